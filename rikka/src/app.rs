@@ -1,53 +1,81 @@
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
 use anyhow::{Context, Result};
 
-use rikka_core::nalgebra::{Matrix4, Vector3, Vector4};
-
-use rikka_core::vk;
+use rikka_core::{projection::Projection, vk};
 use rikka_gpu::{barriers::*, buffer::*, escape::*, gpu::*, image::*, types::*};
 use rikka_graph::graph::Graph;
 
-use rikka_renderer::{loader::asynchronous::AsynchronousLoader, scene_renderer::scene_renderer::*};
+use rikka_renderer::{
+    camera::{Camera, DEFAULT_FOVY},
+    loader::{asynchronous::AsynchronousLoader, native::*, scene::SceneLoadProgress},
+    scene::Aabb,
+    scene_renderer::scene_renderer::*,
+    schedule::ComputeSchedulePolicy,
+    stats::FrameStats,
+};
+
+use crate::config::AppConfig;
 
 pub struct RikkaApp {
     scene_renderer: SceneRenderer,
 
+    /// Shares its request queue with the loader updated by the background thread pool
+    async_loader: AsynchronousLoader,
+
     /// Flag to stop background thread pool
     gpu_transfers_thread_run: Arc<AtomicBool>,
 
     background_thread_pool: threadpool::ThreadPool,
+
+    /// Render graph pass toggled by `toggle_selected_render_pass`
+    selected_render_pass: usize,
+
+    /// glTF models cycled through by `load_next_model`
+    models: Vec<String>,
+    /// None when no scene or a scene from outside the model list is loaded
+    current_model: Option<usize>,
+    scene_file_name: Option<String>,
 }
 
 impl RikkaApp {
-    pub fn new(gpu_desc: GpuDesc, gltf_file_name: &str) -> Result<Self> {
+    pub fn new(gpu_desc: GpuDesc, app_config: &AppConfig) -> Result<Self> {
         let gpu = Gpu::new(gpu_desc)?;
 
         let mut transfer_manager = gpu.new_transfer_manager()?;
         let mut async_loader =
-            AsynchronousLoader::new(transfer_manager.new_image_upload_request_sender());
+            AsynchronousLoader::new(transfer_manager.new_image_upload_request_sender())?;
 
         let scene_renderer_config = Config {
             file_paths_config: FilePathsConfig {
-                render_graph_file_path: String::from("data/graphs/simple_pbr_graph.json"),
-                render_techniques_file_paths: Vec::new(),
-                gtlf_model_file_path: String::from(gltf_file_name),
+                render_graph_file_path: app_config.render_graph_file_path.clone(),
+                render_techniques_file_paths: app_config.render_techniques_file_paths.clone(),
+                gtlf_model_file_path: None,
             },
             gpu,
             async_loader: &mut async_loader,
         };
-        let scene_renderer = SceneRenderer::new_from_config(scene_renderer_config)?;
+        let mut scene_renderer = SceneRenderer::new_from_config(scene_renderer_config)?;
+        scene_renderer.set_texture_budget(app_config.texture_budget_mb * 1024 * 1024);
+        if app_config.async_compute {
+            scene_renderer.set_compute_schedule_policy(ComputeSchedulePolicy::async_compute());
+        }
 
         let background_thread_pool = threadpool::ThreadPool::new(3);
         let gpu_transfers_thread_run = Arc::new(AtomicBool::new(true));
 
         let load_resources = gpu_transfers_thread_run.clone();
+        let mut background_async_loader = async_loader.clone();
         background_thread_pool.execute(move || {
             while load_resources.load(Ordering::Relaxed) {
-                async_loader
+                background_async_loader
                     .update()
                     .expect("Async loader failed to update!");
             }
@@ -65,14 +93,24 @@ impl RikkaApp {
             transfer_manager.destroy();
         });
 
-        Ok(Self {
+        let mut app = Self {
             scene_renderer,
+            async_loader,
             gpu_transfers_thread_run,
             background_thread_pool,
-        })
+            selected_render_pass: 0,
+            models: app_config.models.clone(),
+            current_model: None,
+            scene_file_name: None,
+        };
+        app.load_scene(app_config.model_file_path()?)?;
+
+        Ok(app)
     }
 
     pub fn render(&mut self) -> Result<()> {
+        self.scene_renderer
+            .update_texture_streaming(&mut self.async_loader);
         self.scene_renderer.render()?;
         Ok(())
     }
@@ -82,14 +120,351 @@ impl RikkaApp {
         Ok(())
     }
 
-    pub fn update_view(&mut self, view: &Matrix4<f32>, eye_position: &Vector3<f32>) {
-        self.scene_renderer.scene_uniform_data.view = view.clone();
-        self.scene_renderer.scene_uniform_data.eye_position =
-            Vector4::new(eye_position.x, eye_position.y, eye_position.z, 1.0);
+    pub fn camera(&self) -> &Camera {
+        self.scene_renderer.camera()
+    }
+
+    pub fn camera_mut(&mut self) -> &mut Camera {
+        self.scene_renderer.camera_mut()
+    }
+
+    /// Switches between perspective and orthographic projection, the orthographic view volume keeps the size of the
+    /// scene center on screen
+    pub fn toggle_orthographic_projection(&mut self) {
+        let camera = self.scene_renderer.camera();
+        let projection = match *camera.projection() {
+            Projection::Perspective { fovy, znear, zfar } => {
+                let scene_bounds = self.scene_renderer.scene_bounds();
+                let distance = if scene_bounds.is_empty() {
+                    1.0
+                } else {
+                    (scene_bounds.center() - camera.position()).norm()
+                };
+                let height = 2.0 * (fovy * 0.5).tan() * distance.max(znear);
+                Projection::orthographic(height, znear, zfar)
+            }
+            Projection::Orthographic { znear, zfar, .. } => {
+                Projection::perspective(DEFAULT_FOVY, znear, zfar)
+            }
+        };
+
+        log::info!("Orthographic projection: {}", projection.is_orthographic());
+        self.scene_renderer.camera_mut().set_projection(projection);
+    }
+
+    /// Cycles the displayed image through the render graph attachments and back to the final image
+    pub fn cycle_debug_view(&mut self) -> Result<()> {
+        let names = self.scene_renderer.graph_attachment_names()?;
+
+        let next_index = match self.scene_renderer.debug_view_resource() {
+            Some(current) => names
+                .iter()
+                .position(|name| name == current)
+                .map_or(0, |index| index + 1),
+            None => 0,
+        };
+
+        // Skip resources that cannot be displayed(eg. depth)
+        for name in names.iter().skip(next_index) {
+            if self
+                .scene_renderer
+                .set_debug_view_resource(Some(name))
+                .is_ok()
+            {
+                log::info!("Debug view: {}", name);
+                return Ok(());
+            }
+        }
+
+        self.scene_renderer.set_debug_view_resource(None)?;
+        log::info!("Debug view: final image");
+
+        Ok(())
+    }
+
+    /// Cycles through the debug visualizations of the scene, skipping those the render graph cannot provide
+    pub fn cycle_debug_view_mode(&mut self) -> Result<()> {
+        let current_index = DebugView::ALL
+            .iter()
+            .position(|debug_view| *debug_view == self.scene_renderer.debug_view())
+            .unwrap_or(0);
+
+        for offset in 1..DebugView::ALL.len() {
+            let debug_view = DebugView::ALL[(current_index + offset) % DebugView::ALL.len()];
+            match self.scene_renderer.set_debug_view(debug_view) {
+                Ok(()) => {
+                    log::info!("Debug view mode: {:?}", debug_view);
+                    return Ok(());
+                }
+                Err(err) => log::debug!("Skipping debug view mode {:?}: {}", debug_view, err),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Saves all render graph attachments of the last frame to disk
+    pub fn capture_graph_attachments(&mut self) -> Result<()> {
+        for name in self.scene_renderer.graph_attachment_names()? {
+            let file_name = format!("capture_{}.png", name);
+            if let Err(err) = self
+                .scene_renderer
+                .capture_graph_resource(&name, &file_name)
+            {
+                log::warn!("Failed to capture {}: {}", name, err);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn reload_render_graph(&mut self) -> Result<()> {
+        if let Err(err) = self.scene_renderer.reload_render_graph() {
+            log::error!("{:?}", err);
+        }
+
+        Ok(())
+    }
+
+    /// Selects the next render graph pass and logs the state of all passes
+    pub fn cycle_selected_render_pass(&mut self) -> Result<()> {
+        let states = self.scene_renderer.render_pass_states()?;
+        if states.is_empty() {
+            return Ok(());
+        }
+
+        self.selected_render_pass = (self.selected_render_pass + 1) % states.len();
+
+        for (index, (name, enabled)) in states.iter().enumerate() {
+            log::info!(
+                "{} {}: {}",
+                if index == self.selected_render_pass {
+                    ">"
+                } else {
+                    " "
+                },
+                name,
+                if *enabled { "enabled" } else { "disabled" }
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn toggle_selected_render_pass(&mut self) -> Result<()> {
+        let states = self.scene_renderer.render_pass_states()?;
+        let (name, enabled) = match states.get(self.selected_render_pass) {
+            Some(state) => state.clone(),
+            None => return Ok(()),
+        };
+
+        if let Err(err) = self.scene_renderer.set_render_pass_enabled(&name, !enabled) {
+            log::warn!("{:?}", err);
+            return Ok(());
+        }
+        log::info!("Render pass {}: {}", name, !enabled);
+
+        Ok(())
+    }
+
+    pub fn toggle_ray_traced_shadows(&mut self) -> Result<()> {
+        let enabled = !self.scene_renderer.ray_traced_shadows_enabled();
+        if let Err(err) = self.scene_renderer.set_ray_traced_shadows(enabled) {
+            log::warn!("Failed to enable ray traced shadows: {}", err);
+            return Ok(());
+        }
+        log::info!("Ray traced shadows: {}", enabled);
+
+        Ok(())
+    }
+
+    /// Switches all compute workloads between the graphics and the async compute queue
+    pub fn toggle_async_compute(&mut self) {
+        let policy =
+            if self.scene_renderer.compute_schedule_policy() == ComputeSchedulePolicy::new() {
+                ComputeSchedulePolicy::async_compute()
+            } else {
+                ComputeSchedulePolicy::new()
+            };
+        self.scene_renderer.set_compute_schedule_policy(policy);
+        log::info!(
+            "Compute schedule: {}",
+            self.scene_renderer.resolve_compute_schedule()
+        );
     }
 
-    pub fn update_projection(&mut self, projection: &Matrix4<f32>) {
-        self.scene_renderer.scene_uniform_data.projection = projection.clone();
+    pub fn toggle_indirect_draw(&mut self) -> Result<()> {
+        let enabled = !self.scene_renderer.indirect_draw_enabled();
+        if let Err(err) = self.scene_renderer.set_indirect_draw(enabled) {
+            log::warn!("Failed to enable indirect draws: {}", err);
+            return Ok(());
+        }
+        log::info!("Indirect draws: {}", enabled);
+
+        Ok(())
+    }
+
+    pub fn toggle_wireframe(&mut self) -> Result<()> {
+        let enabled = !self.scene_renderer.wireframe();
+        if let Err(err) = self.scene_renderer.set_wireframe(enabled) {
+            log::warn!("Failed to enable wireframe: {}", err);
+            return Ok(());
+        }
+        log::info!("Wireframe: {}", enabled);
+
+        Ok(())
+    }
+
+    /// Replaces the current scene with one that is loaded in the background, see `update_scene_load`.
+    /// Textures of the previous scene that are still loading are skipped.
+    pub fn load_scene(&mut self, gltf_file_name: &str) -> Result<()> {
+        self.async_loader.cancel_pending_requests();
+        self.current_model = None;
+        self.scene_file_name = None;
+
+        self.scene_renderer.begin_scene_load(gltf_file_name)?;
+
+        self.current_model = self.models.iter().position(|model| model == gltf_file_name);
+        self.scene_file_name = Some(String::from(gltf_file_name));
+
+        Ok(())
+    }
+
+    pub fn unload_scene(&mut self) -> Result<()> {
+        self.async_loader.cancel_pending_requests();
+        self.scene_renderer.unload_scene()?;
+
+        self.current_model = None;
+        self.scene_file_name = None;
+
+        Ok(())
+    }
+
+    /// Loads the model after the current one from the configured model list
+    pub fn load_next_model(&mut self) -> Result<()> {
+        if self.models.is_empty() {
+            return Err(anyhow::anyhow!("No models configured"));
+        }
+
+        let next_model = self
+            .current_model
+            .map_or(0, |current_model| (current_model + 1) % self.models.len());
+        let gltf_file_name = self.models[next_model].clone();
+        log::info!("Loading model {}", gltf_file_name);
+
+        self.load_scene(&gltf_file_name)
+    }
+
+    pub fn scene_file_name(&self) -> Option<&str> {
+        self.scene_file_name.as_deref()
+    }
+
+    /// Whether the loaded scene restored its saved camera
+    pub fn scene_camera_saved(&self) -> bool {
+        self.scene_file_name
+            .as_deref()
+            .map_or(false, is_native_scene_file)
+    }
+
+    /// Saves the current scene next to the file it was loaded from, a loaded native scene is overwritten
+    pub fn save_scene(&self) -> Result<()> {
+        let scene_file_name = self
+            .scene_file_name
+            .as_deref()
+            .context("No scene is loaded")?;
+        let native_file_name = Path::new(scene_file_name)
+            .with_extension(NATIVE_SCENE_EXTENSION)
+            .to_string_lossy()
+            .into_owned();
+
+        self.scene_renderer.save_scene(&native_file_name)
+    }
+
+    /// Returns true on the frame the loading scene gets created, the first animation clip of the scene starts playing
+    pub fn update_scene_load(&mut self) -> Result<bool> {
+        let result = self
+            .scene_renderer
+            .update_scene_load(&mut self.async_loader);
+        match result {
+            Ok(true) if !self.scene_renderer.animator().is_empty() => {
+                self.scene_renderer.animator_mut().play(0)?;
+            }
+            Err(_) => {
+                self.current_model = None;
+                self.scene_file_name = None;
+            }
+            _ => {}
+        }
+
+        result
+    }
+
+    pub fn update_animations(&mut self, delta_time: Duration) -> Result<()> {
+        self.scene_renderer
+            .update_animations(delta_time.as_secs_f32())
+    }
+
+    /// Blocks until the loading scene has been created, its textures may still be streaming in
+    pub fn wait_for_scene_load(&mut self) -> Result<()> {
+        while self.scene_renderer.scene_load_progress().is_some() {
+            if self.update_scene_load()? {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        Ok(())
+    }
+
+    /// None when no scene is being loaded
+    pub fn scene_load_progress(&self) -> Option<&SceneLoadProgress> {
+        self.scene_renderer.scene_load_progress()
+    }
+
+    pub fn scene_bounds(&self) -> Aabb {
+        self.scene_renderer.scene_bounds()
+    }
+
+    pub fn frame_stats(&self) -> &FrameStats {
+        self.scene_renderer.renderer().frame_stats()
+    }
+
+    pub fn invalidate_swapchain(&mut self) {
+        self.scene_renderer.renderer_mut().invalidate_swapchain();
+    }
+
+    pub fn save_output_image(&mut self, file_name: &str) -> Result<()> {
+        self.scene_renderer
+            .renderer_mut()
+            .save_output_image(file_name)
+    }
+
+    /// Cycles between FIFO(vsync), MAILBOX and IMMEDIATE(uncapped) present modes supported by the surface
+    pub fn cycle_present_mode(&mut self) -> Result<()> {
+        const PRESENT_MODES: [vk::PresentModeKHR; 3] = [
+            vk::PresentModeKHR::FIFO,
+            vk::PresentModeKHR::MAILBOX,
+            vk::PresentModeKHR::IMMEDIATE,
+        ];
+
+        let renderer = self.scene_renderer.renderer_mut();
+        let supported_present_modes = renderer.supported_present_modes()?;
+
+        let current_index = PRESENT_MODES
+            .iter()
+            .position(|present_mode| *present_mode == renderer.present_mode())
+            .unwrap_or(0);
+
+        let next_present_mode = (1..PRESENT_MODES.len())
+            .map(|offset| PRESENT_MODES[(current_index + offset) % PRESENT_MODES.len()])
+            .find(|present_mode| supported_present_modes.contains(present_mode));
+
+        if let Some(present_mode) = next_present_mode {
+            renderer.set_present_mode(present_mode)?;
+            log::info!("Present mode set to {:?}", present_mode);
+        }
+
+        Ok(())
     }
 }
 
@@ -0,0 +1,43 @@
+//! Converts glTF files into cooked scenes that load without parsing, mip generation or per buffer view uploads.
+//!
+//! rikka_cook <gltf file> [output file] [--no-optimize]
+
+use std::path::PathBuf;
+
+use rikka_renderer::loader::cooked::{cook_gltf, CookOptions, COOKED_SCENE_EXTENSION};
+
+fn main() {
+    let env = env_logger::Env::default()
+        .filter_or("MY_LOG_LEVEL", "info")
+        .write_style_or("MY_LOG_STYLE", "always");
+    env_logger::init_from_env(env);
+
+    let mut args = std::env::args().collect::<Vec<_>>();
+
+    let mut options = CookOptions::default();
+    if let Some(position) = args.iter().position(|arg| arg == "--no-optimize") {
+        args.remove(position);
+        options.optimize_meshes = false;
+    }
+
+    let gltf_file_name = match args.get(1) {
+        Some(gltf_file_name) => gltf_file_name,
+        None => {
+            log::error!("Usage: rikka_cook <gltf file> [output file] [--no-optimize]");
+            std::process::exit(1);
+        }
+    };
+
+    // Cooked scene is placed next to the glTF file by default
+    let output_file_name = args.get(2).cloned().unwrap_or_else(|| {
+        PathBuf::from(gltf_file_name)
+            .with_extension(COOKED_SCENE_EXTENSION)
+            .to_string_lossy()
+            .into_owned()
+    });
+
+    if let Err(err) = cook_gltf(gltf_file_name, &output_file_name, &options) {
+        log::error!("Failed to cook {}: {:?}", gltf_file_name, err);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,190 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use serde_derive::{Deserialize, Serialize};
+
+use rikka_core::vk;
+
+pub const DEFAULT_CONFIG_FILE_PATH: &str = "data/app_config.json";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PresentMode {
+    /// Vsync
+    Fifo,
+    Mailbox,
+    /// Uncapped
+    Immediate,
+}
+
+impl PresentMode {
+    pub fn vk_present_mode(&self) -> vk::PresentModeKHR {
+        match self {
+            PresentMode::Fifo => vk::PresentModeKHR::FIFO,
+            PresentMode::Mailbox => vk::PresentModeKHR::MAILBOX,
+            PresentMode::Immediate => vk::PresentModeKHR::IMMEDIATE,
+        }
+    }
+
+    fn from_arg(arg: &str) -> Result<Self> {
+        match arg.to_lowercase().as_str() {
+            "fifo" | "vsync" => Ok(PresentMode::Fifo),
+            "mailbox" => Ok(PresentMode::Mailbox),
+            "immediate" => Ok(PresentMode::Immediate),
+            _ => Err(anyhow!("Unknown present mode {}", arg)),
+        }
+    }
+}
+
+/// Startup options, loaded from a JSON file and overridden by command line arguments
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AppConfig {
+    pub window_width: u32,
+    pub window_height: u32,
+    pub fullscreen: bool,
+    /// Exclusive fullscreen with `fullscreen_resolution`, borderless otherwise
+    pub exclusive_fullscreen: bool,
+    /// Index into the available monitors, the primary monitor is used if not set or out of range
+    pub monitor: Option<usize>,
+    /// Closest video mode is used, the monitor's largest one if not set
+    pub fullscreen_resolution: Option<[u32; 2]>,
+    pub present_mode: PresentMode,
+    /// Prefers a HDR10 or scRGB swapchain when the surface supports one
+    pub hdr_output: bool,
+
+    pub render_graph_file_path: String,
+    /// Loaded up front so they are available to the render graph
+    pub render_techniques_file_paths: Vec<String>,
+    /// glTF models, only the first one is loaded
+    pub models: Vec<String>,
+    pub input_bindings_file_path: String,
+
+    /// Units per second, in multiples of the scene radius once the camera is focused on the scene
+    pub camera_speed: f32,
+    pub camera_sensitivity: f32,
+
+    /// Gpu memory for streamed in texture mip levels, in MiB
+    pub texture_budget_mb: usize,
+    /// Requests the async compute queue for skinning, particles and culling, toggled with C
+    pub async_compute: bool,
+
+    /// env_logger filter, `MY_LOG_LEVEL` takes precedence
+    pub log_level: String,
+
+    /// Renders to this image file without a window when set
+    pub headless_output_file_path: Option<String>,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            window_width: 1920,
+            window_height: 1200,
+            fullscreen: false,
+            exclusive_fullscreen: false,
+            monitor: None,
+            fullscreen_resolution: None,
+            present_mode: PresentMode::Fifo,
+            hdr_output: false,
+            render_graph_file_path: String::from("data/graphs/simple_pbr_graph.json"),
+            render_techniques_file_paths: Vec::new(),
+            models: Vec::new(),
+            input_bindings_file_path: String::from("data/input_bindings.json"),
+            camera_speed: 1.0,
+            camera_sensitivity: 0.4,
+            texture_budget_mb: 512,
+            async_compute: false,
+            log_level: String::from("trace"),
+            headless_output_file_path: None,
+        }
+    }
+}
+
+impl AppConfig {
+    pub fn new_from_file(file_path: &str) -> Result<Self> {
+        let file_contents = std::fs::read_to_string(file_path)?;
+        let config = serde_json::from_str(&file_contents)?;
+        Ok(config)
+    }
+
+    /// Loads the config file selected with `--config`, or the default one if it exists, then applies the
+    /// remaining arguments on top:
+    ///
+    /// rikka [gltf file] [--config <file>] [--headless <output image file>] [--width <w>] [--height <h>]
+    ///       [--fullscreen] [--exclusive-fullscreen] [--monitor <index>]
+    ///       [--present-mode <fifo|mailbox|immediate>] [--hdr] [--graph <file>]
+    ///       [--technique <file>]... [--camera-speed <speed>] [--texture-budget <MiB>]
+    ///       [--async-compute] [--log-level <level>]
+    pub fn new_from_args(args: &[String]) -> Result<Self> {
+        let config_file_path = args
+            .iter()
+            .position(|arg| arg == "--config")
+            .map(|index| {
+                args.get(index + 1)
+                    .context("--config requires a file path")
+                    .map(String::as_str)
+            })
+            .transpose()?;
+
+        // The default config file is optional, an explicitly selected one is not
+        let config_file_path = config_file_path.or_else(|| {
+            Path::new(DEFAULT_CONFIG_FILE_PATH)
+                .exists()
+                .then_some(DEFAULT_CONFIG_FILE_PATH)
+        });
+
+        let mut config = match config_file_path {
+            Some(file_path) => Self::new_from_file(file_path)
+                .with_context(|| format!("Failed to load app config {}", file_path))?,
+            None => Self::default(),
+        };
+
+        config.apply_args(args)?;
+        Ok(config)
+    }
+
+    fn apply_args(&mut self, args: &[String]) -> Result<()> {
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .with_context(|| format!("{} requires a value", arg))
+            };
+
+            match arg.as_str() {
+                "--config" => {
+                    value()?;
+                }
+                "--headless" => self.headless_output_file_path = Some(value()?.clone()),
+                "--width" => self.window_width = value()?.parse()?,
+                "--height" => self.window_height = value()?.parse()?,
+                "--fullscreen" => self.fullscreen = true,
+                "--exclusive-fullscreen" => {
+                    self.fullscreen = true;
+                    self.exclusive_fullscreen = true;
+                }
+                "--monitor" => self.monitor = Some(value()?.parse()?),
+                "--present-mode" => self.present_mode = PresentMode::from_arg(value()?)?,
+                "--hdr" => self.hdr_output = true,
+                "--graph" => self.render_graph_file_path = value()?.clone(),
+                "--technique" => self.render_techniques_file_paths.push(value()?.clone()),
+                "--camera-speed" => self.camera_speed = value()?.parse()?,
+                "--texture-budget" => self.texture_budget_mb = value()?.parse()?,
+                "--async-compute" => self.async_compute = true,
+                "--log-level" => self.log_level = value()?.clone(),
+                _ if arg.starts_with("--") => return Err(anyhow!("Unknown argument {}", arg)),
+                // A model given on the command line replaces the configured ones
+                _ => self.models = vec![arg.clone()],
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn model_file_path(&self) -> Result<&str> {
+        self.models
+            .first()
+            .map(String::as_str)
+            .context("No glTF model given in the app config or as an argument")
+    }
+}
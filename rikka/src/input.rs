@@ -0,0 +1,285 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+use gilrs::{Axis, Button, EventType, Gilrs};
+use serde_derive::{Deserialize, Serialize};
+use winit::event::{ElementState, MouseButton, VirtualKeyCode};
+
+/// Analog stick values below this are treated as zero
+const GAMEPAD_DEADZONE: f32 = 0.15;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Action {
+    MoveForward,
+    MoveBackward,
+    MoveLeft,
+    MoveRight,
+    MoveUp,
+    MoveDown,
+    /// Enables mouse look while held
+    Look,
+    LookLeft,
+    LookRight,
+    LookUp,
+    LookDown,
+    /// Grabs and hides the cursor, rotating the camera with relative mouse motion
+    ToggleMouseLook,
+    /// Moves the camera to frame the scene bounds
+    FocusCamera,
+    CyclePresentMode,
+    LoadNextModel,
+    ToggleIndirectDraw,
+    ToggleOrthographicProjection,
+    /// Switches the compute workloads between the graphics and async compute queues
+    ToggleAsyncCompute,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum InputBinding {
+    Key(VirtualKeyCode),
+    Mouse(MouseButton),
+    GamepadButton(Button),
+    /// Only one direction of the axis, `positive` selects which
+    GamepadAxis {
+        axis: Axis,
+        positive: bool,
+    },
+}
+
+/// Bindings file contents, actions that are not listed keep their default bindings
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct InputBindings {
+    #[serde(default)]
+    bindings: HashMap<Action, Vec<InputBinding>>,
+}
+
+/// Maps keyboard, mouse and gamepad input to actions
+pub struct InputMap {
+    bindings: HashMap<Action, Vec<InputBinding>>,
+    /// Current value of every binding that has received input, in [0, 1]
+    values: HashMap<InputBinding, f32>,
+    /// Actions pressed since they were last queried with `take_pressed`
+    pressed_actions: HashSet<Action>,
+    /// XXX: Gamepads are optional, input still works with keyboard and mouse if gilrs fails to initialize
+    gilrs: Option<Gilrs>,
+}
+
+impl InputMap {
+    pub fn new() -> Self {
+        Self::with_bindings(Self::default_bindings())
+    }
+
+    pub fn new_from_file(file_path: &str) -> Result<Self> {
+        let file_contents = std::fs::read_to_string(file_path)?;
+        let input_bindings: InputBindings = serde_json::from_str(&file_contents)?;
+
+        let mut bindings = Self::default_bindings();
+        bindings.extend(input_bindings.bindings);
+
+        Ok(Self::with_bindings(bindings))
+    }
+
+    fn with_bindings(bindings: HashMap<Action, Vec<InputBinding>>) -> Self {
+        let gilrs = match Gilrs::new() {
+            Ok(gilrs) => Some(gilrs),
+            Err(err) => {
+                log::warn!("Gamepad input is not available: {}", err);
+                None
+            }
+        };
+
+        Self {
+            bindings,
+            values: HashMap::new(),
+            pressed_actions: HashSet::new(),
+            gilrs,
+        }
+    }
+
+    pub fn default_bindings() -> HashMap<Action, Vec<InputBinding>> {
+        use InputBinding::*;
+
+        let axis = |axis, positive| GamepadAxis { axis, positive };
+
+        HashMap::from([
+            (
+                Action::MoveForward,
+                vec![
+                    Key(VirtualKeyCode::W),
+                    Key(VirtualKeyCode::Up),
+                    axis(Axis::LeftStickY, true),
+                ],
+            ),
+            (
+                Action::MoveBackward,
+                vec![
+                    Key(VirtualKeyCode::S),
+                    Key(VirtualKeyCode::Down),
+                    axis(Axis::LeftStickY, false),
+                ],
+            ),
+            (
+                Action::MoveLeft,
+                vec![
+                    Key(VirtualKeyCode::A),
+                    Key(VirtualKeyCode::Left),
+                    axis(Axis::LeftStickX, false),
+                ],
+            ),
+            (
+                Action::MoveRight,
+                vec![
+                    Key(VirtualKeyCode::D),
+                    Key(VirtualKeyCode::Right),
+                    axis(Axis::LeftStickX, true),
+                ],
+            ),
+            (
+                Action::MoveUp,
+                vec![
+                    Key(VirtualKeyCode::Space),
+                    GamepadButton(Button::RightTrigger2),
+                ],
+            ),
+            (
+                Action::MoveDown,
+                vec![
+                    Key(VirtualKeyCode::LShift),
+                    GamepadButton(Button::LeftTrigger2),
+                ],
+            ),
+            (Action::Look, vec![Mouse(MouseButton::Left)]),
+            (Action::LookLeft, vec![axis(Axis::RightStickX, false)]),
+            (Action::LookRight, vec![axis(Axis::RightStickX, true)]),
+            (Action::LookUp, vec![axis(Axis::RightStickY, true)]),
+            (Action::LookDown, vec![axis(Axis::RightStickY, false)]),
+            (
+                Action::ToggleMouseLook,
+                vec![Key(VirtualKeyCode::Tab), Mouse(MouseButton::Right)],
+            ),
+            (Action::FocusCamera, vec![Key(VirtualKeyCode::F)]),
+            (Action::CyclePresentMode, vec![Key(VirtualKeyCode::P)]),
+            (Action::LoadNextModel, vec![Key(VirtualKeyCode::N)]),
+            (Action::ToggleIndirectDraw, vec![Key(VirtualKeyCode::I)]),
+            (
+                Action::ToggleOrthographicProjection,
+                vec![Key(VirtualKeyCode::O)],
+            ),
+            (Action::ToggleAsyncCompute, vec![Key(VirtualKeyCode::C)]),
+        ])
+    }
+
+    /// Returns true if the key is bound to an action
+    pub fn process_keyboard(&mut self, key: VirtualKeyCode, state: ElementState) -> bool {
+        self.set_value(InputBinding::Key(key), Self::state_value(state))
+    }
+
+    /// Returns true if the button is bound to an action
+    pub fn process_mouse_button(&mut self, button: MouseButton, state: ElementState) -> bool {
+        self.set_value(InputBinding::Mouse(button), Self::state_value(state))
+    }
+
+    /// Polls pending gamepad events, should be called once per frame
+    pub fn update_gamepads(&mut self) {
+        let mut events = Vec::new();
+        if let Some(gilrs) = &mut self.gilrs {
+            while let Some(event) = gilrs.next_event() {
+                events.push(event.event);
+            }
+        }
+
+        for event in events {
+            match event {
+                EventType::ButtonPressed(button, _) => {
+                    self.set_value(InputBinding::GamepadButton(button), 1.0);
+                }
+                EventType::ButtonReleased(button, _) => {
+                    self.set_value(InputBinding::GamepadButton(button), 0.0);
+                }
+                EventType::ButtonChanged(button, value, _) => {
+                    self.set_value(InputBinding::GamepadButton(button), value);
+                }
+                EventType::AxisChanged(axis, value, _) => {
+                    let value = if value.abs() < GAMEPAD_DEADZONE {
+                        0.0
+                    } else {
+                        value
+                    };
+                    self.set_value(
+                        InputBinding::GamepadAxis {
+                            axis,
+                            positive: true,
+                        },
+                        value.max(0.0),
+                    );
+                    self.set_value(
+                        InputBinding::GamepadAxis {
+                            axis,
+                            positive: false,
+                        },
+                        (-value).max(0.0),
+                    );
+                }
+                EventType::Disconnected => {
+                    self.values.retain(|binding, _| {
+                        matches!(binding, InputBinding::Key(_) | InputBinding::Mouse(_))
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Strongest value of all bindings of `action`, in [0, 1]
+    pub fn value(&self, action: Action) -> f32 {
+        self.bindings
+            .get(&action)
+            .map(|bindings| {
+                bindings
+                    .iter()
+                    .filter_map(|binding| self.values.get(binding))
+                    .fold(0.0_f32, |value, binding_value| value.max(*binding_value))
+            })
+            .unwrap_or(0.0)
+    }
+
+    pub fn pressed(&self, action: Action) -> bool {
+        self.value(action) > 0.5
+    }
+
+    /// Returns true once for every time `action` got pressed
+    pub fn take_pressed(&mut self, action: Action) -> bool {
+        self.pressed_actions.remove(&action)
+    }
+
+    /// Difference between two opposing actions, in [-1, 1]
+    pub fn axis(&self, positive: Action, negative: Action) -> f32 {
+        self.value(positive) - self.value(negative)
+    }
+
+    fn state_value(state: ElementState) -> f32 {
+        if state == ElementState::Pressed {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    fn set_value(&mut self, binding: InputBinding, value: f32) -> bool {
+        let value = value.clamp(0.0, 1.0);
+        let previous_value = self.values.insert(binding, value).unwrap_or(0.0);
+        let newly_pressed = previous_value <= 0.5 && value > 0.5;
+
+        let mut bound = false;
+        for (action, bindings) in &self.bindings {
+            if bindings.contains(&binding) {
+                bound = true;
+                if newly_pressed {
+                    self.pressed_actions.insert(*action);
+                }
+            }
+        }
+
+        bound
+    }
+}
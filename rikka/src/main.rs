@@ -1,64 +1,127 @@
 mod app;
 mod camera;
+mod config;
+mod input;
 
-use std::time::Instant;
+use std::{
+    cmp::Reverse,
+    path::Path,
+    time::{Duration, Instant},
+};
 
 use winit::{
     dpi,
     event::*,
     event_loop::{ControlFlow, EventLoop},
+    monitor::MonitorHandle,
     platform::windows::WindowBuilderExtWindows,
-    window::WindowBuilder,
+    window::{CursorGrabMode, Fullscreen, Window, WindowBuilder},
 };
 
 use rikka_core::nalgebra;
-use rikka_gpu::gpu::GpuDesc;
+use rikka_gpu::{error::GpuError, gpu::GpuDesc};
+use rikka_renderer::loader::{cooked::COOKED_SCENE_EXTENSION, native::NATIVE_SCENE_EXTENSION};
 
 use camera::*;
+use config::AppConfig;
+use input::{Action, InputMap};
 
 fn main() {
+    let args = std::env::args().collect::<Vec<_>>();
+    let app_config = AppConfig::new_from_args(&args);
+
+    let log_level = match &app_config {
+        Ok(app_config) => app_config.log_level.as_str(),
+        Err(_) => "trace",
+    };
     let env = env_logger::Env::default()
-        .filter_or("MY_LOG_LEVEL", "trace")
+        .filter_or("MY_LOG_LEVEL", log_level)
         .write_style_or("MY_LOG_STYLE", "always");
     env_logger::init_from_env(env);
 
-    let args = std::env::args().collect::<Vec<_>>();
-    if args.len() < 2 {
-        log::error!("Argument to gltf file required!");
+    let app_config = match app_config {
+        Ok(app_config) => app_config,
+        Err(err) => {
+            log::error!("Invalid app config: {:?}", err);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(err) = app_config.model_file_path() {
+        log::error!("{}", err);
         std::process::exit(1);
     }
 
+    if let Some(output_file_name) = &app_config.headless_output_file_path {
+        run_headless(&app_config, output_file_name).unwrap();
+        return;
+    }
+
     let event_loop = EventLoop::new();
 
+    let fullscreen = app_config.fullscreen.then(|| {
+        config_fullscreen(
+            &app_config,
+            event_loop.available_monitors().collect(),
+            event_loop.primary_monitor(),
+        )
+    });
+
     let window = WindowBuilder::new()
-        .with_title("Rikka Engine")
-        .with_inner_size(dpi::PhysicalSize::new(1920, 1200))
+        .with_title(WINDOW_TITLE)
+        .with_inner_size(dpi::PhysicalSize::new(
+            app_config.window_width,
+            app_config.window_height,
+        ))
         .with_position(dpi::PhysicalPosition::new(100, 100))
+        .with_fullscreen(fullscreen)
         // .with_resizable(false)
         .build(&event_loop)
         .unwrap();
 
-    let mut rikka_app =
-        app::RikkaApp::new(GpuDesc::new(&window, &window), args[1].as_str()).unwrap();
+    if app_config.fullscreen {
+        update_cursor_grab(&window, true, false);
+    }
+
+    let mut rikka_app = app::RikkaApp::new(
+        GpuDesc::new(&window, &window)
+            .set_present_mode(app_config.present_mode.vk_present_mode())
+            .set_hdr_output(app_config.hdr_output),
+        &app_config,
+    )
+    .unwrap();
 
     rikka_app.prepare().unwrap();
 
     let mut camera_view = View::new(nalgebra::Vector3::new(0.0, 2.5, 2.0), 0.0, 0.0);
-    let camera_projection = Projection::new(
-        window.inner_size().width,
-        window.inner_size().height,
-        45.0_f32.to_radians(),
-        0.1,
-        100.0,
-    );
 
-    let mut camera_controller = FirstPersonCameraController::new(4.0, 0.4);
+    let mut camera_controller =
+        FirstPersonCameraController::new(app_config.camera_speed, app_config.camera_sensitivity);
+
+    let input_bindings_file_path = app_config.input_bindings_file_path.as_str();
+    let mut input_map = match InputMap::new_from_file(input_bindings_file_path) {
+        Ok(input_map) => input_map,
+        Err(err) => {
+            log::info!(
+                "Using default input bindings, failed to load {}: {}",
+                input_bindings_file_path,
+                err
+            );
+            InputMap::new()
+        }
+    };
 
-    rikka_app.update_view(camera_view.matrix(), camera_view.position());
-    rikka_app.update_projection(camera_projection.matrix());
+    // The camera is focused on the scene once it has been loaded
+    update_camera(&mut rikka_app, &camera_view);
 
     let mut last_render_time = Instant::now();
 
+    // Scene name, scene loading progress and frame stats are shown in the window title
+    let mut frame_stats_overlay = false;
+    let mut last_title_update = Instant::now();
+
+    let mut modifiers = ModifiersState::empty();
+
     event_loop.run(move |event, _, control_flow| match event {
         Event::WindowEvent {
             ref event,
@@ -76,27 +139,202 @@ fn main() {
             } => {
                 *control_flow = ControlFlow::Exit;
             }
+            WindowEvent::ModifiersChanged(state) => {
+                modifiers = *state;
+            }
             WindowEvent::KeyboardInput {
                 input:
                     KeyboardInput {
-                        virtual_keycode: Some(key),
-                        state,
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::Return),
+                        ..
+                    },
+                ..
+            } if modifiers.alt() => {
+                let fullscreen = window.fullscreen().is_none();
+                window.set_fullscreen(fullscreen.then(|| {
+                    config_fullscreen(
+                        &app_config,
+                        window.available_monitors().collect(),
+                        window.primary_monitor(),
+                    )
+                }));
+                update_cursor_grab(&window, fullscreen, camera_controller.mouse_look());
+
+                // Not every platform reports the swapchain as out of date after the switch
+                rikka_app.invalidate_swapchain();
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::F5),
+                        ..
+                    },
+                ..
+            } => {
+                rikka_app.reload_render_graph().unwrap();
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::F6),
+                        ..
+                    },
+                ..
+            } => {
+                rikka_app.cycle_selected_render_pass().unwrap();
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::F7),
+                        ..
+                    },
+                ..
+            } => {
+                rikka_app.toggle_selected_render_pass().unwrap();
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::F8),
+                        ..
+                    },
+                ..
+            } => {
+                rikka_app.toggle_wireframe().unwrap();
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::F3),
+                        ..
+                    },
+                ..
+            } => {
+                frame_stats_overlay = !frame_stats_overlay;
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::F9),
                         ..
                     },
                 ..
             } => {
-                camera_controller.process_keyboard(*key, *state);
+                rikka_app.cycle_debug_view_mode().unwrap();
             }
-            WindowEvent::MouseInput {
-                button: MouseButton::Left,
-                state,
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::F10),
+                        ..
+                    },
                 ..
             } => {
-                camera_controller.set_mouse_pressed(*state == ElementState::Pressed);
+                rikka_app.toggle_ray_traced_shadows().unwrap();
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::F11),
+                        ..
+                    },
+                ..
+            } => {
+                rikka_app.cycle_debug_view().unwrap();
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::F12),
+                        ..
+                    },
+                ..
+            } => {
+                rikka_app.capture_graph_attachments().unwrap();
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::Delete),
+                        ..
+                    },
+                ..
+            } => {
+                rikka_app.unload_scene().unwrap();
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::F4),
+                        ..
+                    },
+                ..
+            } => {
+                if let Err(err) = rikka_app.save_scene() {
+                    log::error!("Failed to save scene: {:?}", err);
+                }
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        virtual_keycode: Some(key),
+                        state,
+                        ..
+                    },
+                ..
+            } => {
+                input_map.process_keyboard(*key, *state);
+            }
+            WindowEvent::MouseInput { button, state, .. } => {
+                input_map.process_mouse_button(*button, *state);
+            }
+            WindowEvent::DroppedFile(path) => {
+                let is_gltf = path
+                    .extension()
+                    .and_then(|extension| extension.to_str())
+                    .map(|extension| {
+                        extension.eq_ignore_ascii_case("gltf")
+                            || extension.eq_ignore_ascii_case("glb")
+                            || extension.eq_ignore_ascii_case(COOKED_SCENE_EXTENSION)
+                            || extension.eq_ignore_ascii_case(NATIVE_SCENE_EXTENSION)
+                    })
+                    .unwrap_or(false);
+
+                if !is_gltf {
+                    log::warn!(
+                        "Ignoring dropped file {}, only .gltf, .glb, cooked and native scene files can be loaded",
+                        path.display()
+                    );
+                } else {
+                    if let Err(err) = rikka_app.load_scene(&path.to_string_lossy()) {
+                        log::error!("Failed to load {}: {:?}", path.display(), err);
+                    }
+                }
+            }
+            WindowEvent::Focused(false) => {
+                // Release the cursor when switching to another window
+                set_mouse_look(&window, &mut camera_controller, false);
             }
             WindowEvent::MouseWheel { delta, .. } => {
                 camera_controller.process_scroll(delta);
             }
+            WindowEvent::Resized(_) => {
+                // Swapchain is recreated by the renderer, the camera aspect ratio follows the new extent
+                rikka_app.invalidate_swapchain();
+            }
             _ => {}
         },
         Event::DeviceEvent {
@@ -110,11 +348,244 @@ fn main() {
             let dt = now - last_render_time;
             last_render_time = now;
 
-            camera_controller.update_view(&mut camera_view, dt);
-            rikka_app.update_view(camera_view.matrix(), camera_view.position());
+            match rikka_app.update_scene_load() {
+                Ok(true) if rikka_app.scene_camera_saved() => {
+                    let camera = rikka_app.camera();
+                    camera_view = View::new_looking_along(*camera.position(), camera.forward());
+                    let bounds = rikka_app.scene_bounds();
+                    if !bounds.is_empty() {
+                        camera_controller
+                            .set_speed(app_config.camera_speed * bounds.radius().max(0.001));
+                    }
+                }
+                Ok(true) => {
+                    focus_camera(
+                        &mut rikka_app,
+                        &mut camera_view,
+                        &mut camera_controller,
+                        app_config.camera_speed,
+                    );
+                }
+                Ok(false) => {}
+                Err(err) => match err.downcast_ref::<GpuError>() {
+                    Some(GpuError::BindlessExhausted) => {
+                        log::error!("Failed to load scene, it has more textures than bindless slots")
+                    }
+                    _ => log::error!("Failed to load scene: {:?}", err),
+                },
+            }
 
-            rikka_app.render().unwrap();
+            input_map.update_gamepads();
+            if input_map.take_pressed(Action::ToggleMouseLook) {
+                let mouse_look = !camera_controller.mouse_look();
+                set_mouse_look(&window, &mut camera_controller, mouse_look);
+            }
+            if input_map.take_pressed(Action::FocusCamera) {
+                focus_camera(
+                    &mut rikka_app,
+                    &mut camera_view,
+                    &mut camera_controller,
+                    app_config.camera_speed,
+                );
+            }
+            if input_map.take_pressed(Action::CyclePresentMode) {
+                rikka_app.cycle_present_mode().unwrap();
+            }
+            if input_map.take_pressed(Action::LoadNextModel) {
+                if let Err(err) = rikka_app.load_next_model() {
+                    log::error!("Failed to load next model: {:?}", err);
+                }
+            }
+            if input_map.take_pressed(Action::ToggleIndirectDraw) {
+                rikka_app.toggle_indirect_draw().unwrap();
+            }
+            if input_map.take_pressed(Action::ToggleOrthographicProjection) {
+                rikka_app.toggle_orthographic_projection();
+            }
+            if input_map.take_pressed(Action::ToggleAsyncCompute) {
+                rikka_app.toggle_async_compute();
+            }
+            camera_controller.update_view(&mut camera_view, &input_map, dt);
+            update_camera(&mut rikka_app, &camera_view);
+            rikka_app.update_animations(dt).unwrap();
+
+            if let Err(err) = rikka_app.render() {
+                match err.downcast_ref::<GpuError>() {
+                    // The report of the lost device is logged when the loss is detected
+                    Some(gpu_error) if gpu_error.is_device_lost() => {
+                        log::error!("Exiting after the Gpu device was lost");
+                        *control_flow = ControlFlow::Exit;
+                        return;
+                    }
+                    _ => panic!("Failed to render frame: {:?}", err),
+                }
+            }
+
+            if last_title_update.elapsed() >= TITLE_UPDATE_INTERVAL {
+                let scene_name = rikka_app
+                    .scene_file_name()
+                    .map_or(String::from("no scene"), file_name);
+                let mut title = format!("{} | {}", WINDOW_TITLE, scene_name);
+
+                if let Some(progress) = rikka_app.scene_load_progress() {
+                    title += &format!(" (loading {})", progress);
+                }
+                if frame_stats_overlay {
+                    title += &format!(" | {}", rikka_app.frame_stats().summary());
+                }
+
+                window.set_title(&title);
+                last_title_update = Instant::now();
+            }
         }
         _ => {}
     });
 }
+
+const WINDOW_TITLE: &str = "Rikka Engine";
+const TITLE_UPDATE_INTERVAL: Duration = Duration::from_millis(500);
+
+fn file_name(file_path: &str) -> String {
+    Path::new(file_path)
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| file_path.to_string())
+}
+
+fn update_camera(rikka_app: &mut app::RikkaApp, view: &View) {
+    rikka_app
+        .camera_mut()
+        .set_transform(*view.position(), view.forward());
+}
+
+/// Frames the camera on the scene bounds and scales depth range and movement speed to its size
+fn focus_camera(
+    rikka_app: &mut app::RikkaApp,
+    view: &mut View,
+    controller: &mut FirstPersonCameraController,
+    camera_speed: f32,
+) {
+    let bounds = rikka_app.scene_bounds();
+    if bounds.is_empty() {
+        return;
+    }
+
+    let radius = bounds.radius().max(0.001);
+    let distance = rikka_app.camera().focus_distance(radius);
+
+    view.focus_on(&bounds.center(), distance);
+    update_camera(rikka_app, view);
+    // XXX: Far plane leaves room to move away from the model
+    rikka_app
+        .camera_mut()
+        .set_depth_range(radius * 0.01, (distance + radius) * 4.0);
+    controller.set_speed(camera_speed * radius);
+
+    log::info!(
+        "Focused camera on scene bounds {:?} - {:?}, distance {}",
+        bounds.min,
+        bounds.max,
+        distance
+    );
+}
+
+/// Fullscreen mode on the monitor selected by the app config
+fn config_fullscreen(
+    app_config: &AppConfig,
+    monitors: Vec<MonitorHandle>,
+    primary_monitor: Option<MonitorHandle>,
+) -> Fullscreen {
+    let monitor = app_config
+        .monitor
+        .and_then(|index| monitors.get(index).cloned())
+        .or(primary_monitor)
+        .or_else(|| monitors.first().cloned());
+
+    if app_config.exclusive_fullscreen {
+        // Closest resolution, or the largest one if none is configured, at the highest refresh rate
+        let video_mode = monitor.as_ref().and_then(|monitor| {
+            monitor.video_modes().min_by_key(|video_mode| {
+                let size = video_mode.size();
+                let resolution_difference = match app_config.fullscreen_resolution {
+                    Some([width, height]) => {
+                        width.abs_diff(size.width) as u64 + height.abs_diff(size.height) as u64
+                    }
+                    None => u64::MAX - size.width as u64 * size.height as u64,
+                };
+                (
+                    resolution_difference,
+                    Reverse(video_mode.refresh_rate_millihertz()),
+                )
+            })
+        });
+
+        if let Some(video_mode) = video_mode {
+            log::info!("Exclusive fullscreen video mode: {}", video_mode);
+            return Fullscreen::Exclusive(video_mode);
+        }
+
+        log::warn!("No exclusive fullscreen video mode available, using borderless fullscreen");
+    }
+
+    Fullscreen::Borderless(monitor)
+}
+
+/// Locks and hides the cursor in mouse look mode, otherwise keeps it from escaping to other monitors while
+/// fullscreen
+fn update_cursor_grab(window: &Window, fullscreen: bool, mouse_look: bool) {
+    // XXX: Locked is not supported on Windows and Confined is not supported on macOS
+    let result = if mouse_look {
+        window
+            .set_cursor_grab(CursorGrabMode::Locked)
+            .or_else(|_| window.set_cursor_grab(CursorGrabMode::Confined))
+    } else if fullscreen {
+        window
+            .set_cursor_grab(CursorGrabMode::Confined)
+            .or_else(|_| window.set_cursor_grab(CursorGrabMode::Locked))
+    } else {
+        window.set_cursor_grab(CursorGrabMode::None)
+    };
+
+    if let Err(err) = result {
+        log::warn!("Failed to set cursor grab: {}", err);
+    }
+
+    window.set_cursor_visible(!mouse_look);
+}
+
+fn set_mouse_look(
+    window: &Window,
+    camera_controller: &mut FirstPersonCameraController,
+    mouse_look: bool,
+) {
+    camera_controller.set_mouse_look(mouse_look);
+    update_cursor_grab(window, window.fullscreen().is_some(), mouse_look);
+}
+
+// Gives asynchronously loaded textures time to be uploaded before the output is saved
+const HEADLESS_FRAME_COUNT: u32 = 60;
+
+fn run_headless(app_config: &AppConfig, output_file_name: &str) -> anyhow::Result<()> {
+    let mut rikka_app = app::RikkaApp::new(
+        GpuDesc::new_headless(app_config.window_width, app_config.window_height),
+        app_config,
+    )?;
+
+    rikka_app.prepare()?;
+    rikka_app.wait_for_scene_load()?;
+
+    // Native scenes render from their saved camera
+    if !rikka_app.scene_camera_saved() {
+        let camera_view = View::new(nalgebra::Vector3::new(0.0, 2.5, 2.0), 0.0, 0.0);
+        update_camera(&mut rikka_app, &camera_view);
+    }
+
+    for _ in 0..HEADLESS_FRAME_COUNT {
+        rikka_app.render()?;
+    }
+
+    rikka_app.save_output_image(output_file_name)?;
+    log::info!("Saved headless render to {}", output_file_name);
+
+    Ok(())
+}
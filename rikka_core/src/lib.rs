@@ -1,3 +1,5 @@
 pub use ash::{self, vk};
 pub use nalgebra;
 pub use nalgebra_glm as glm;
+
+pub mod projection;
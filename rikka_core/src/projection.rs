@@ -0,0 +1,184 @@
+use nalgebra::Matrix4;
+use nalgebra_glm as glm;
+use serde_derive::{Deserialize, Serialize};
+
+/// Projection to Vulkan clip space, y points down and depth is in [0, 1]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Projection {
+    Perspective {
+        /// Vertical field of view in radians
+        fovy: f32,
+        znear: f32,
+        zfar: f32,
+    },
+    Orthographic {
+        /// Height of the view volume in world units, the width follows the aspect ratio
+        height: f32,
+        znear: f32,
+        zfar: f32,
+    },
+}
+
+impl Projection {
+    pub fn perspective(fovy: f32, znear: f32, zfar: f32) -> Self {
+        Self::Perspective { fovy, znear, zfar }
+    }
+
+    pub fn orthographic(height: f32, znear: f32, zfar: f32) -> Self {
+        Self::Orthographic {
+            height,
+            znear,
+            zfar,
+        }
+    }
+
+    pub fn is_orthographic(&self) -> bool {
+        matches!(self, Self::Orthographic { .. })
+    }
+
+    pub fn znear(&self) -> f32 {
+        match *self {
+            Self::Perspective { znear, .. } | Self::Orthographic { znear, .. } => znear,
+        }
+    }
+
+    pub fn zfar(&self) -> f32 {
+        match *self {
+            Self::Perspective { zfar, .. } | Self::Orthographic { zfar, .. } => zfar,
+        }
+    }
+
+    pub fn set_depth_range(&mut self, new_znear: f32, new_zfar: f32) {
+        match self {
+            Self::Perspective { znear, zfar, .. } | Self::Orthographic { znear, zfar, .. } => {
+                *znear = new_znear;
+                *zfar = new_zfar;
+            }
+        }
+    }
+
+    pub fn matrix(&self, aspect_ratio: f32) -> Matrix4<f32> {
+        match *self {
+            Self::Perspective { fovy, znear, zfar } => {
+                flip_y(glm::perspective_rh_zo(aspect_ratio, fovy, znear, zfar))
+            }
+            Self::Orthographic {
+                height,
+                znear,
+                zfar,
+            } => {
+                let half_height = height * 0.5;
+                let half_width = half_height * aspect_ratio;
+                orthographic_matrix(
+                    -half_width,
+                    half_width,
+                    -half_height,
+                    half_height,
+                    znear,
+                    zfar,
+                )
+            }
+        }
+    }
+
+    /// Pixels covered by one world unit in an image `image_height` pixels high. Perspective projections are measured
+    /// at a view distance of 1 and scale with the inverse distance, orthographic projections are constant.
+    pub fn pixels_per_unit(&self, image_height: u32) -> f32 {
+        match *self {
+            Self::Perspective { fovy, .. } => image_height as f32 * 0.5 / (fovy * 0.5).tan(),
+            Self::Orthographic { height, .. } => image_height as f32 / height,
+        }
+    }
+
+    /// Distance from the center of a sphere of `radius` at which the sphere fits entirely inside the view volume
+    pub fn focus_distance(&self, radius: f32, aspect_ratio: f32) -> f32 {
+        match *self {
+            Self::Perspective { fovy, .. } => {
+                let fovx = 2.0 * ((fovy * 0.5).tan() * aspect_ratio).atan();
+                radius / (fovy.min(fovx) * 0.5).sin()
+            }
+            // Any distance in front of the sphere, the size on screen depends on the view volume height only
+            Self::Orthographic { .. } => radius * 2.0,
+        }
+    }
+}
+
+/// Off center orthographic projection of a view space box looking down -z
+fn orthographic_matrix(
+    left: f32,
+    right: f32,
+    bottom: f32,
+    top: f32,
+    znear: f32,
+    zfar: f32,
+) -> Matrix4<f32> {
+    flip_y(glm::ortho_rh_zo(left, right, bottom, top, znear, zfar))
+}
+
+/// Vulkan clip space y points down
+fn flip_y(mut matrix: Matrix4<f32>) -> Matrix4<f32> {
+    for column in 0..4 {
+        matrix[(1, column)] = -matrix[(1, column)];
+    }
+    matrix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ASPECT_RATIO: f32 = 16.0 / 9.0;
+
+    fn assert_approx_eq(a: &[f32], b: &[f32]) {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b) {
+            assert!((a - b).abs() < 1e-5, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_perspective_matrix() {
+        let (fovy, znear, zfar) = (45.0_f32.to_radians(), 0.1, 100.0);
+
+        // Matrix of the camera before the shared projection, only the y scale was negated
+        let mut expected = glm::perspective_rh_zo(ASPECT_RATIO, fovy, znear, zfar);
+        expected[(1, 1)] = -expected[(1, 1)];
+
+        let projection = Projection::perspective(fovy, znear, zfar);
+        assert_approx_eq(
+            projection.matrix(ASPECT_RATIO).as_slice(),
+            expected.as_slice(),
+        );
+    }
+
+    #[test]
+    fn test_flip_y() {
+        let matrix = glm::perspective_rh_zo(ASPECT_RATIO, 1.0, 0.1, 100.0);
+        let flipped = flip_y(matrix);
+
+        for row in 0..4 {
+            for column in 0..4 {
+                let expected = if row == 1 {
+                    -matrix[(row, column)]
+                } else {
+                    matrix[(row, column)]
+                };
+                assert_eq!(flipped[(row, column)], expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_orthographic_matrix() {
+        let projection = Projection::orthographic(4.0, 1.0, 11.0);
+        let matrix = projection.matrix(2.0);
+
+        // Top right corner of the near plane, y points down in clip space
+        let near = matrix * glm::vec4(4.0, 2.0, -1.0, 1.0);
+        assert_approx_eq(near.as_slice(), &[1.0, -1.0, 0.0, 1.0]);
+
+        // Bottom left corner of the far plane
+        let far = matrix * glm::vec4(-4.0, -2.0, -11.0, 1.0);
+        assert_approx_eq(far.as_slice(), &[-1.0, 1.0, 1.0, 1.0]);
+    }
+}
@@ -2,7 +2,7 @@ use bitflags::bitflags;
 
 use rikka_core::vk;
 
-use crate::{image::Image, queue::*};
+use crate::{buffer::Buffer, image::Image, queue::*};
 
 bitflags! {
     pub struct ResourceState : u32
@@ -191,15 +191,17 @@ fn determine_pipeline_flags_from_access_flags(
             }
         }
         QueueType::Compute => {
-            if access_flags.contains(
-                vk::AccessFlags2::UNIFORM_READ
-                    | vk::AccessFlags2::SHADER_READ
-                    | vk::AccessFlags2::SHADER_WRITE,
-            ) {
-                flags |= vk::PipelineStageFlags2::RAY_TRACING_SHADER_NV;
+            if access_flags.contains(vk::AccessFlags2::UNIFORM_READ)
+                || access_flags.contains(vk::AccessFlags2::SHADER_READ)
+                || access_flags.contains(vk::AccessFlags2::SHADER_WRITE)
+            {
+                flags |= vk::PipelineStageFlags2::COMPUTE_SHADER;
+            }
+            if access_flags.contains(vk::AccessFlags2::TRANSFER_READ)
+                || access_flags.contains(vk::AccessFlags2::TRANSFER_WRITE)
+            {
+                flags |= vk::PipelineStageFlags2::TRANSFER;
             }
-
-            todo!()
         }
         QueueType::Transfer => {
             if access_flags.contains(vk::AccessFlags2::TRANSFER_READ)
@@ -213,8 +215,31 @@ fn determine_pipeline_flags_from_access_flags(
     flags
 }
 
+/// Graphics queue stage flags that also include the compute shader stage for shader accesses
+fn compute_pipeline_flags(state: ResourceState) -> vk::PipelineStageFlags2 {
+    let mut flags = determine_pipeline_flags_from_access_flags(state.into(), QueueType::Graphics);
+    if state.intersects(ResourceState::SHADER_RESOURCE | ResourceState::SHADER_ACCESS) {
+        flags |= vk::PipelineStageFlags2::COMPUTE_SHADER;
+    }
+    flags
+}
+
+/// Transitions between identical states only need a barrier if the state writes, read after read is unsynchronized
+fn needs_barrier(old_state: ResourceState, new_state: ResourceState) -> bool {
+    old_state != new_state
+        || new_state.intersects(
+            ResourceState::RENDER_TARGET
+                | ResourceState::SHADER_ACCESS
+                | ResourceState::DEPTH_WRITE
+                | ResourceState::COPY_DESTINATION
+                | ResourceState::STREAM_OUT,
+        )
+}
+
+#[derive(Clone)]
 pub struct Barriers {
     image_barriers: Vec<vk::ImageMemoryBarrier2>,
+    buffer_barriers: Vec<vk::BufferMemoryBarrier2>,
     // XXX: Technically need to hold references to images/buffers to make sure they are still valid when pipelining the barrier?
 }
 
@@ -222,6 +247,7 @@ impl Barriers {
     pub fn new() -> Self {
         Self {
             image_barriers: vec![],
+            buffer_barriers: vec![],
         }
     }
 
@@ -243,6 +269,120 @@ impl Barriers {
             vk::QUEUE_FAMILY_IGNORED,
             vk::QUEUE_FAMILY_IGNORED,
         );
+        image.set_subresource_state(&image.subresource_range(), new_state);
+
+        self
+    }
+
+    /// Same as `add_image`, but shader accesses are also synchronized with the ray tracing shader stage.
+    /// Only valid on devices that support ray tracing.
+    pub fn add_ray_tracing_image(
+        mut self,
+        image: &Image,
+        old_state: ResourceState,
+        new_state: ResourceState,
+    ) -> Self {
+        let stage_flags = |state: ResourceState| {
+            let mut flags =
+                determine_pipeline_flags_from_access_flags(state.into(), QueueType::Graphics);
+            if state.intersects(ResourceState::SHADER_RESOURCE | ResourceState::SHADER_ACCESS) {
+                flags |= vk::PipelineStageFlags2::RAY_TRACING_SHADER_KHR;
+            }
+            flags
+        };
+
+        self.add_image_from_vulkan_parameters(
+            old_state.into(),
+            stage_flags(old_state),
+            new_state.into(),
+            stage_flags(new_state),
+            old_state.into(),
+            new_state.into(),
+            image.raw(),
+            image.subresource_range(),
+            vk::QUEUE_FAMILY_IGNORED,
+            vk::QUEUE_FAMILY_IGNORED,
+        );
+        image.set_subresource_state(&image.subresource_range(), new_state);
+
+        self
+    }
+
+    /// Same as `add_image`, but shader accesses are also synchronized with the compute shader stage
+    pub fn add_compute_image(
+        mut self,
+        image: &Image,
+        old_state: ResourceState,
+        new_state: ResourceState,
+    ) -> Self {
+        self.add_image_from_vulkan_parameters(
+            old_state.into(),
+            compute_pipeline_flags(old_state),
+            new_state.into(),
+            compute_pipeline_flags(new_state),
+            old_state.into(),
+            new_state.into(),
+            image.raw(),
+            image.subresource_range(),
+            vk::QUEUE_FAMILY_IGNORED,
+            vk::QUEUE_FAMILY_IGNORED,
+        );
+        image.set_subresource_state(&image.subresource_range(), new_state);
+
+        self
+    }
+
+    /// Whole buffer barrier, shader accesses are synchronized with both the graphics and compute shader stages
+    pub fn add_buffer(
+        mut self,
+        buffer: &Buffer,
+        old_state: ResourceState,
+        new_state: ResourceState,
+    ) -> Self {
+        let buffer_barrier = vk::BufferMemoryBarrier2::builder()
+            .src_access_mask(old_state.into())
+            .src_stage_mask(compute_pipeline_flags(old_state))
+            .dst_access_mask(new_state.into())
+            .dst_stage_mask(compute_pipeline_flags(new_state))
+            .buffer(buffer.raw())
+            .offset(0)
+            .size(vk::WHOLE_SIZE)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED);
+
+        self.buffer_barriers.push(buffer_barrier.build());
+        buffer.set_resource_state(new_state);
+
+        self
+    }
+
+    /// Whole buffer barrier recorded on the async compute queue, states can only contain accesses of compute and
+    /// transfer commands. Work of other queues is synchronized by semaphores instead.
+    pub fn add_compute_queue_buffer(
+        mut self,
+        buffer: &Buffer,
+        old_state: ResourceState,
+        new_state: ResourceState,
+    ) -> Self {
+        let buffer_barrier = vk::BufferMemoryBarrier2::builder()
+            .src_access_mask(old_state.into())
+            .src_stage_mask(determine_pipeline_flags_from_access_flags(
+                old_state.into(),
+                QueueType::Compute,
+            ))
+            .dst_access_mask(new_state.into())
+            .dst_stage_mask(determine_pipeline_flags_from_access_flags(
+                new_state.into(),
+                QueueType::Compute,
+            ))
+            .buffer(buffer.raw())
+            .offset(0)
+            .size(vk::WHOLE_SIZE)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED);
+
+        self.buffer_barriers.push(buffer_barrier.build());
+        buffer.set_resource_state(new_state);
 
         self
     }
@@ -267,6 +407,89 @@ impl Barriers {
             src_queue.family_index(),
             dst_queue.family_index(),
         );
+        image.set_subresource_state(&image.subresource_range(), new_state);
+
+        self
+    }
+
+    /// Records the release half of a queue family ownership transfer, to be executed on `src_queue`.
+    /// The matching `add_image_acquire` needs to be recorded on `dst_queue` with the same states.
+    pub fn add_image_release(
+        mut self,
+        image: &Image,
+        old_state: ResourceState,
+        new_state: ResourceState,
+        src_queue: &Queue,
+        dst_queue: &Queue,
+    ) -> Self {
+        let src_access_mask = old_state.into();
+        let src_stage_mask =
+            determine_pipeline_flags_from_access_flags(src_access_mask, src_queue.queue_type());
+
+        if src_queue.family_index() == dst_queue.family_index() {
+            // No ownership transfer needed, perform the whole transition here
+            self.add_image_from_vulkan_parameters(
+                src_access_mask,
+                src_stage_mask,
+                new_state.into(),
+                vk::PipelineStageFlags2::ALL_COMMANDS,
+                old_state.into(),
+                new_state.into(),
+                image.raw(),
+                image.subresource_range(),
+                vk::QUEUE_FAMILY_IGNORED,
+                vk::QUEUE_FAMILY_IGNORED,
+            );
+        } else {
+            // Destination access masks are ignored for releases
+            self.add_image_from_vulkan_parameters(
+                src_access_mask,
+                src_stage_mask,
+                vk::AccessFlags2::NONE,
+                vk::PipelineStageFlags2::NONE,
+                old_state.into(),
+                new_state.into(),
+                image.raw(),
+                image.subresource_range(),
+                src_queue.family_index(),
+                dst_queue.family_index(),
+            );
+        }
+        image.set_subresource_state(&image.subresource_range(), new_state);
+
+        self
+    }
+
+    /// Records the acquire half of a queue family ownership transfer, to be executed on `dst_queue`.
+    /// Records nothing if both queues belong to the same family.
+    pub fn add_image_acquire(
+        mut self,
+        image: &Image,
+        old_state: ResourceState,
+        new_state: ResourceState,
+        src_queue: &Queue,
+        dst_queue: &Queue,
+    ) -> Self {
+        if src_queue.family_index() == dst_queue.family_index() {
+            return self;
+        }
+
+        let dst_access_mask = new_state.into();
+
+        // Source access masks are ignored for acquires
+        self.add_image_from_vulkan_parameters(
+            vk::AccessFlags2::NONE,
+            vk::PipelineStageFlags2::NONE,
+            dst_access_mask,
+            determine_pipeline_flags_from_access_flags(dst_access_mask, dst_queue.queue_type()),
+            old_state.into(),
+            new_state.into(),
+            image.raw(),
+            image.subresource_range(),
+            src_queue.family_index(),
+            dst_queue.family_index(),
+        );
+        image.set_subresource_state(&image.subresource_range(), new_state);
 
         self
     }
@@ -300,10 +523,58 @@ impl Barriers {
             vk::QUEUE_FAMILY_IGNORED,
             vk::QUEUE_FAMILY_IGNORED,
         );
+        image.set_subresource_state(&subresource_range, new_state);
+
+        self
+    }
+
+    /// Transitions all subresources of the image from their tracked states, shader accesses are synchronized with
+    /// both the graphics and compute shader stages. Subresources already in a read only `new_state` are skipped.
+    // XXX: States are tracked in recording order, command buffers need to be submitted in the order they were recorded
+    pub fn add_image_transition(self, image: &Image, new_state: ResourceState) -> Self {
+        self.add_image_subresource_transition(image, image.subresource_range(), new_state)
+    }
+
+    /// Same as `add_image_transition` for a mip level and array layer range of the image
+    pub fn add_image_subresource_transition(
+        mut self,
+        image: &Image,
+        subresource_range: vk::ImageSubresourceRange,
+        new_state: ResourceState,
+    ) -> Self {
+        for (range, old_state) in image.subresource_state_ranges(&subresource_range) {
+            if !needs_barrier(old_state, new_state) {
+                continue;
+            }
+
+            self.add_image_from_vulkan_parameters(
+                old_state.into(),
+                compute_pipeline_flags(old_state),
+                new_state.into(),
+                compute_pipeline_flags(new_state),
+                old_state.into(),
+                new_state.into(),
+                image.raw(),
+                range,
+                vk::QUEUE_FAMILY_IGNORED,
+                vk::QUEUE_FAMILY_IGNORED,
+            );
+        }
+        image.set_subresource_state(&subresource_range, new_state);
 
         self
     }
 
+    /// Transitions the buffer from its tracked state, see `add_image_transition`
+    pub fn add_buffer_transition(self, buffer: &Buffer, new_state: ResourceState) -> Self {
+        let old_state = buffer.resource_state();
+        if !needs_barrier(old_state, new_state) {
+            return self;
+        }
+
+        self.add_buffer(buffer, old_state, new_state)
+    }
+
     pub fn add_image_from_vulkan_parameters(
         &mut self,
         src_access_mask: vk::AccessFlags2,
@@ -335,4 +606,12 @@ impl Barriers {
     pub fn image_barriers(&self) -> &[vk::ImageMemoryBarrier2] {
         &self.image_barriers
     }
+
+    pub fn buffer_barriers(&self) -> &[vk::BufferMemoryBarrier2] {
+        &self.buffer_barriers
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.image_barriers.is_empty() && self.buffer_barriers.is_empty()
+    }
 }
@@ -3,7 +3,6 @@ use std::{
     sync::Arc,
 };
 
-use anyhow::Result;
 use parking_lot::Mutex;
 
 use gpu_allocator::{
@@ -13,7 +12,13 @@ use gpu_allocator::{
 
 use rikka_core::{ash, vk};
 
-use crate::{factory::DeviceGuard, types::*};
+use crate::{
+    barriers::ResourceState,
+    error::{GpuError, Result},
+    factory::DeviceGuard,
+    queue::QueueType,
+    types::*,
+};
 
 pub enum BufferLocation {
     GpuOnly,
@@ -26,6 +31,10 @@ pub struct BufferDesc {
     pub resource_usage: ResourceUsageType,
     pub size: u32,
     pub device_only: bool,
+    /// Host visible memory optimized for reading back data from the Gpu
+    pub readback: bool,
+    /// Accessed by both the graphics and the async compute queue without ownership transfers
+    pub async_compute: bool,
 }
 
 impl BufferDesc {
@@ -35,6 +44,8 @@ impl BufferDesc {
             resource_usage: ResourceUsageType::Immutable,
             size: 0,
             device_only: true,
+            readback: false,
+            async_compute: false,
         }
     }
 
@@ -57,6 +68,16 @@ impl BufferDesc {
         self.device_only = device_only;
         self
     }
+
+    pub fn set_readback(mut self, readback: bool) -> Self {
+        self.readback = readback;
+        self
+    }
+
+    pub fn set_async_compute(mut self, async_compute: bool) -> Self {
+        self.async_compute = async_compute;
+        self
+    }
 }
 
 pub struct Buffer {
@@ -67,6 +88,8 @@ pub struct Buffer {
     raw: vk::Buffer,
     allocation: Allocation,
     desc: BufferDesc,
+    /// State as of the last recorded barrier
+    state: Mutex<ResourceState>,
     //  XXX: Are these needed?
     // global_offset: u32,
     // usage_flags: vk::BufferUsageFlags,
@@ -81,7 +104,7 @@ impl Buffer {
         allocator: Arc<Mutex<Allocator>>,
         desc: BufferDesc,
     ) -> Result<Self> {
-        let create_info = vk::BufferCreateInfo::builder()
+        let mut create_info = vk::BufferCreateInfo::builder()
             .size(desc.size as u64)
             .usage(
                 desc.usage_flags
@@ -89,11 +112,25 @@ impl Buffer {
                     | vk::BufferUsageFlags::TRANSFER_DST,
             );
 
+        // Buffers of async compute work are shared by both queue families, all others are owned by the family that
+        // first accesses them
+        let queue_family_indices = [
+            device.queue_family(QueueType::Graphics).index(),
+            device.queue_family(QueueType::Compute).index(),
+        ];
+        if desc.async_compute && queue_family_indices[0] != queue_family_indices[1] {
+            create_info = create_info
+                .sharing_mode(vk::SharingMode::CONCURRENT)
+                .queue_family_indices(&queue_family_indices);
+        }
+
         let raw = device.raw().create_buffer(&create_info, None)?;
         let requirements = device.raw().get_buffer_memory_requirements(raw);
 
         let location = {
-            if desc.device_only {
+            if desc.readback {
+                MemoryLocation::GpuToCpu
+            } else if desc.device_only {
                 MemoryLocation::GpuOnly
             } else {
                 MemoryLocation::CpuToGpu
@@ -117,6 +154,7 @@ impl Buffer {
             raw,
             allocation,
             desc,
+            state: Mutex::new(ResourceState::UNDEFINED),
         })
     }
 
@@ -137,6 +175,54 @@ impl Buffer {
         Ok(())
     }
 
+    /// `offset` in bytes
+    pub fn copy_data_to_buffer_with_offset<T: Copy>(
+        &self,
+        data: &[T],
+        offset: usize,
+    ) -> Result<()> {
+        if offset + size_of_val(data) > self.desc.size as usize {
+            return Err(GpuError::invalid_desc(format!(
+                "Copy of {} bytes at offset {} exceeds buffer size {}",
+                size_of_val(data),
+                offset,
+                self.desc.size
+            )));
+        }
+
+        unsafe {
+            let data_ptr = self
+                .allocation
+                .mapped_ptr()
+                .unwrap()
+                .as_ptr()
+                .cast::<u8>()
+                .add(offset)
+                .cast();
+
+            let mut align =
+                ash::util::Align::new(data_ptr, align_of::<T>() as _, size_of_val(data) as _);
+            align.copy_from_slice(data);
+        };
+
+        Ok(())
+    }
+
+    pub fn read_data_from_buffer<T: Copy>(&self, count: usize) -> Result<Vec<T>> {
+        let mapped_ptr = self
+            .allocation
+            .mapped_ptr()
+            .ok_or_else(|| GpuError::invalid_desc("Buffer is not host visible"))?;
+
+        assert!(count * std::mem::size_of::<T>() <= self.desc.size as usize);
+
+        let data = unsafe {
+            std::slice::from_raw_parts(mapped_ptr.as_ptr() as *const T, count).to_vec()
+        };
+
+        Ok(data)
+    }
+
     pub fn get_device_address(&self) -> u64 {
         let addr_info = vk::BufferDeviceAddressInfo::builder().buffer(self.raw);
         unsafe { self.device.raw().get_buffer_device_address(&addr_info) }
@@ -150,7 +236,20 @@ impl Buffer {
         self.desc.size
     }
 
+    pub fn usage_flags(&self) -> vk::BufferUsageFlags {
+        self.desc.usage_flags
+    }
+
     pub fn resource_usage_type(&self) -> ResourceUsageType {
         self.desc.resource_usage
     }
+
+    /// Tracked state of the whole buffer
+    pub fn resource_state(&self) -> ResourceState {
+        *self.state.lock()
+    }
+
+    pub(crate) fn set_resource_state(&self, state: ResourceState) {
+        *self.state.lock() = state;
+    }
 }
@@ -1,12 +1,15 @@
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicU32, AtomicU64, Ordering},
+    Arc,
+};
 
-use anyhow::{anyhow, Result};
 use rikka_core::vk;
 
 use crate::{
-    barriers::*, buffer::*, constants, descriptor_set::DescriptorSet, factory::DeviceGuard,
-    frame::FrameThreadPoolsManager, image::*, mesh_shader::MeshShaderContext, pipeline::*,
-    types::*,
+    barriers::*, buffer::*, compute_pipeline::*, constants, crash_diagnostics::CrashDiagnostics,
+    descriptor_set::DescriptorSet, error::*, event::Event, factory::DeviceGuard,
+    frame::FrameThreadPoolsManager, image::*, mesh_shader::*, pipeline::*, query::*,
+    queue::QueueType, ray_tracing::*, types::*,
 };
 
 // XXX: Use a better typestate system
@@ -56,6 +59,14 @@ impl CommandPool {
         Ok(command_buffers[0])
     }
 
+    pub fn free_command_buffers(&self, command_buffers: &[vk::CommandBuffer]) {
+        unsafe {
+            self.device
+                .raw()
+                .free_command_buffers(self.raw, command_buffers)
+        };
+    }
+
     pub fn reset(&self) {
         unsafe {
             self.device
@@ -96,6 +107,7 @@ impl CommandBufferManager {
     pub fn new(
         device: DeviceGuard,
         frame_thread_pools_manager: &FrameThreadPoolsManager,
+        crash_diagnostics: Arc<CrashDiagnostics>,
     ) -> Result<Self> {
         let num_frames = constants::MAX_FRAMES;
         let num_threads_per_frame = frame_thread_pools_manager.num_threads();
@@ -106,6 +118,10 @@ impl CommandBufferManager {
         let num_used_command_buffers: Vec<u32> = vec![0; num_total_pools as usize];
         let num_used_secondary_command_buffers: Vec<u32> = vec![0; num_total_pools as usize];
 
+        let supports_timestamps = device
+            .queue_family(QueueType::Graphics)
+            .supports_timestamps();
+
         let num_command_buffers = num_total_pools * num_command_buffers_per_thread;
         let mut command_buffers = Vec::<CommandBuffer>::with_capacity(num_command_buffers as usize);
 
@@ -131,12 +147,18 @@ impl CommandBufferManager {
 
                     let command_buffer =
                         command_pool.allocate_command_buffer(vk::CommandBufferLevel::PRIMARY)?;
-                    command_buffers.push(CommandBuffer::new(
-                        device.clone(),
-                        command_buffer,
-                        meta_data,
-                        false,
-                    ));
+                    let mut command_buffer =
+                        CommandBuffer::new(device.clone(), command_buffer, meta_data, false);
+                    if supports_timestamps {
+                        command_buffer.timestamp_query_pool = Some(
+                            frame_thread_pools_manager
+                                .pools_at(frame_index, thread_index)
+                                .timestamp_query_pool
+                                .clone(),
+                        );
+                    }
+                    command_buffer.crash_diagnostics = Some(crash_diagnostics.clone());
+                    command_buffers.push(command_buffer);
                 }
 
                 // Create secondary command buffers.
@@ -222,8 +244,8 @@ impl CommandBufferManager {
         let num_used_buffers = self.num_used_command_buffers[pool_index as usize];
 
         if num_used_buffers > self.num_command_buffers_per_thread {
-            return Err(anyhow!(
-                "All command buffers in current frame thread are already used!"
+            return Err(GpuError::other(
+                "All command buffers in current frame thread are already used!",
             ));
         }
 
@@ -243,8 +265,8 @@ impl CommandBufferManager {
         let num_used_buffers = self.num_used_secondary_command_buffers[pool_index as usize];
 
         if num_used_buffers > constants::NUM_SECONDARY_COMMAND_BUFFERS_PER_THREAD {
-            return Err(anyhow!(
-                "All secondary command buffers in current frame thread are already used!"
+            return Err(GpuError::other(
+                "All secondary command buffers in current frame thread are already used!",
             ));
         }
 
@@ -277,6 +299,104 @@ pub struct CommandBufferMetaData {
     pub thread_index: u32,
 }
 
+/// Accumulates copy regions, regions sharing the same source and destination are executed with a single copy
+/// command by `CommandBuffer::copy_batch`
+pub struct CopyBatch {
+    buffer_copies: Vec<(vk::Buffer, vk::Buffer, Vec<vk::BufferCopy2>)>,
+    buffer_to_image_copies: Vec<(vk::Buffer, vk::Image, Vec<vk::BufferImageCopy2>)>,
+    // XXX: Technically need to hold references to buffers/images to make sure they are still valid when recording the copy?
+}
+
+impl CopyBatch {
+    pub fn new() -> Self {
+        Self {
+            buffer_copies: vec![],
+            buffer_to_image_copies: vec![],
+        }
+    }
+
+    pub fn add_buffer_region(
+        mut self,
+        src: &Buffer,
+        dst: &Buffer,
+        size: u64,
+        src_offset: u64,
+        dst_offset: u64,
+    ) -> Self {
+        let region = vk::BufferCopy2::builder()
+            .size(size)
+            .src_offset(src_offset)
+            .dst_offset(dst_offset)
+            .build();
+
+        match self
+            .buffer_copies
+            .iter_mut()
+            .find(|(copy_src, copy_dst, _)| *copy_src == src.raw() && *copy_dst == dst.raw())
+        {
+            Some((_, _, regions)) => regions.push(region),
+            None => self
+                .buffer_copies
+                .push((src.raw(), dst.raw(), vec![region])),
+        }
+
+        self
+    }
+
+    /// The image needs to be in the TRANSFER_DST_OPTIMAL layout when the batch is executed
+    pub fn add_buffer_to_image_region(
+        mut self,
+        buffer: &Buffer,
+        image: &Image,
+        buffer_offset: u64,
+        mip_level: u32,
+        base_array_layer: u32,
+        layer_count: u32,
+    ) -> Self {
+        let region = vk::BufferImageCopy2::builder()
+            .buffer_offset(buffer_offset)
+            .buffer_row_length(0)
+            .buffer_image_height(0)
+            .image_subresource(
+                vk::ImageSubresourceLayers::builder()
+                    .aspect_mask(image.copy_aspect_mask())
+                    .mip_level(mip_level)
+                    .base_array_layer(base_array_layer)
+                    .layer_count(layer_count)
+                    .build(),
+            )
+            .image_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
+            .image_extent(image.mip_extent(mip_level))
+            .build();
+
+        match self
+            .buffer_to_image_copies
+            .iter_mut()
+            .find(|(copy_buffer, copy_image, _)| {
+                *copy_buffer == buffer.raw() && *copy_image == image.raw()
+            }) {
+            Some((_, _, regions)) => regions.push(region),
+            None => self
+                .buffer_to_image_copies
+                .push((buffer.raw(), image.raw(), vec![region])),
+        }
+
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer_copies.is_empty() && self.buffer_to_image_copies.is_empty()
+    }
+}
+
+/// Draws recorded in a command buffer since it was last begun
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DrawStats {
+    pub draw_calls: u32,
+    /// Assumes triangle lists, draws with Gpu generated parameters are not included
+    pub triangles: u64,
+}
+
 pub struct CommandBuffer {
     device: DeviceGuard,
     raw: vk::CommandBuffer,
@@ -285,9 +405,18 @@ pub struct CommandBuffer {
     pub(crate) is_secondary: bool,
 
     mesh_shader: MeshShaderContext,
+    /// None if the device does not support ray tracing
+    ray_tracing: Option<RayTracingContext>,
 
-    // XXX: This is not used, can remove?
+    /// Frame index is used to record crash markers
     meta_data: CommandBufferMetaData,
+
+    /// Only set for primary frame and async compute command buffers if their queue supports timestamps
+    timestamp_query_pool: Option<Arc<TimestampQueryPool>>,
+    /// Only set for primary frame command buffers
+    crash_diagnostics: Option<Arc<CrashDiagnostics>>,
+    draw_calls: AtomicU32,
+    triangles: AtomicU64,
     // Reference to pipeline?
     // pipeline: vk::Pipeline,
     // mesh_shading_context
@@ -301,13 +430,129 @@ impl CommandBuffer {
         meta_data: CommandBufferMetaData,
         is_secondary: bool,
     ) -> Self {
+        let ray_tracing = if device.ray_tracing_supported() {
+            Some(RayTracingContext::new(device.clone()))
+        } else {
+            None
+        };
+
         Self {
             device: device.clone(),
             mesh_shader: MeshShaderContext::new(device),
+            ray_tracing,
             raw: command_buffer,
             // is_recording: false,
             is_secondary,
             meta_data,
+            timestamp_query_pool: None,
+            crash_diagnostics: None,
+            draw_calls: AtomicU32::new(0),
+            triangles: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn set_timestamp_query_pool(
+        &mut self,
+        timestamp_query_pool: Arc<TimestampQueryPool>,
+    ) {
+        self.timestamp_query_pool = Some(timestamp_query_pool);
+    }
+
+    pub fn draw_stats(&self) -> DrawStats {
+        DrawStats {
+            draw_calls: self.draw_calls.load(Ordering::Relaxed),
+            triangles: self.triangles.load(Ordering::Relaxed),
+        }
+    }
+
+    fn add_draw_stats(&self, draw_calls: u32, triangles: u64) {
+        self.draw_calls.fetch_add(draw_calls, Ordering::Relaxed);
+        self.triangles.fetch_add(triangles, Ordering::Relaxed);
+    }
+
+    /// Starts a named Gpu timing scope, scopes can be nested.
+    /// Timings are resolved when the frame of this command buffer is reused, does nothing if timestamps are not supported.
+    pub fn push_timestamp_scope(&self, name: &str) {
+        if let Some(query_pool) = &self.timestamp_query_pool {
+            match query_pool.push_scope(name) {
+                Some(query) => unsafe {
+                    self.device.raw().cmd_write_timestamp2(
+                        self.raw,
+                        vk::PipelineStageFlags2::TOP_OF_PIPE,
+                        query_pool.raw(),
+                        query,
+                    );
+                },
+                None => log::warn!("Out of timestamp queries for scope {}", name),
+            }
+        }
+    }
+
+    pub fn pop_timestamp_scope(&self) {
+        if let Some(query_pool) = &self.timestamp_query_pool {
+            if let Some(query) = query_pool.pop_scope() {
+                unsafe {
+                    self.device.raw().cmd_write_timestamp2(
+                        self.raw,
+                        vk::PipelineStageFlags2::BOTTOM_OF_PIPE,
+                        query_pool.raw(),
+                        query,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Marks the start of a named section of Gpu work, the sections submitted and reached are reported if the device
+    /// is lost
+    pub fn set_crash_marker(&self, name: &str) {
+        if let Some(crash_diagnostics) = &self.crash_diagnostics {
+            crash_diagnostics.set_marker(self.raw, self.meta_data.frame_index, name);
+        }
+    }
+
+    /// Begins an occlusion or pipeline statistics query, `query` is relative to the current frame of the pool
+    pub fn begin_query(&self, query_pool: &QueryPool, query: u32) {
+        debug_assert!(query_pool.query_type() != QueryType::Timestamp);
+
+        unsafe {
+            self.device.raw().cmd_begin_query(
+                self.raw,
+                query_pool.raw(),
+                query_pool.use_query(query),
+                vk::QueryControlFlags::empty(),
+            );
+        }
+    }
+
+    pub fn end_query(&self, query_pool: &QueryPool, query: u32) {
+        debug_assert!(query_pool.query_type() != QueryType::Timestamp);
+
+        unsafe {
+            self.device.raw().cmd_end_query(
+                self.raw,
+                query_pool.raw(),
+                query_pool.use_query(query),
+            );
+        }
+    }
+
+    /// Writes the timestamp of when all previous commands completed `stage`
+    pub fn write_timestamp(
+        &self,
+        query_pool: &QueryPool,
+        query: u32,
+        stage: vk::PipelineStageFlags2,
+    ) {
+        debug_assert!(query_pool.query_type() == QueryType::Timestamp);
+
+        unsafe {
+            self.device.raw().cmd_write_timestamp2(
+                self.raw,
+                stage,
+                query_pool.raw(),
+                query_pool.use_query(query),
+            );
         }
     }
 
@@ -316,6 +561,9 @@ impl CommandBuffer {
     }
 
     pub fn begin(&self) -> Result<()> {
+        self.draw_calls.store(0, Ordering::Relaxed);
+        self.triangles.store(0, Ordering::Relaxed);
+
         // if !self.is_recording {
         let begin_info = vk::CommandBufferBeginInfo::builder()
             .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
@@ -388,6 +636,31 @@ impl CommandBuffer {
             }
         };
 
+        let stencil_attachment_info = match rendering_state.depth_attachment {
+            Some(attachment) if format_has_stencil(attachment.format) => {
+                vk::RenderingAttachmentInfo::builder()
+                    .image_view(attachment.image_view)
+                    .image_layout(attachment.image_layout)
+                    .resolve_mode(vk::ResolveModeFlags::NONE)
+                    .load_op(attachment.stencil_operation.vk_attachment_load_op())
+                    .store_op(if attachment.store_stencil {
+                        vk::AttachmentStoreOp::STORE
+                    } else {
+                        vk::AttachmentStoreOp::DONT_CARE
+                    })
+                    .clear_value(
+                        if attachment.stencil_operation == RenderPassOperation::Clear {
+                            vk::ClearValue {
+                                depth_stencil: attachment.clear_value,
+                            }
+                        } else {
+                            vk::ClearValue::default()
+                        },
+                    )
+            }
+            _ => vk::RenderingAttachmentInfo::builder(),
+        };
+
         let rendering_info = vk::RenderingInfo::builder()
             .flags(if self.is_secondary {
                 vk::RenderingFlags::CONTENTS_SECONDARY_COMMAND_BUFFERS
@@ -396,6 +669,7 @@ impl CommandBuffer {
             })
             .color_attachments(&color_attachments_info)
             .depth_attachment(&depth_attachment_info)
+            .stencil_attachment(&stencil_attachment_info)
             .render_area(vk::Rect2D {
                 extent: vk::Extent2D {
                     width: rendering_state.width,
@@ -432,14 +706,17 @@ impl CommandBuffer {
         }
     }
 
-    pub fn bind_index_buffer(&self, buffer: &Buffer, offset: u64) {
+    /// `vk::IndexType::UINT8_EXT` requires the `index_type_uint8` device feature
+    pub fn bind_index_buffer(&self, buffer: &Buffer, offset: u64, index_type: vk::IndexType) {
+        debug_assert!(
+            index_type != vk::IndexType::UINT8_EXT || self.device.features().index_type_uint8,
+            "8 bit index buffers are not supported by the device"
+        );
+
         unsafe {
-            self.device.raw().cmd_bind_index_buffer(
-                self.raw,
-                buffer.raw(),
-                offset,
-                vk::IndexType::UINT16,
-            );
+            self.device
+                .raw()
+                .cmd_bind_index_buffer(self.raw, buffer.raw(), offset, index_type);
         }
     }
 
@@ -473,6 +750,28 @@ impl CommandBuffer {
         }
     }
 
+    /// Push constants are written at offset 0
+    pub fn push_constants<T: Copy>(
+        &self,
+        raw_pipeline_layout: vk::PipelineLayout,
+        stage_flags: vk::ShaderStageFlags,
+        data: &T,
+    ) {
+        unsafe {
+            let bytes = std::slice::from_raw_parts(
+                (data as *const T) as *const u8,
+                std::mem::size_of::<T>(),
+            );
+            self.device.raw().cmd_push_constants(
+                self.raw,
+                raw_pipeline_layout,
+                stage_flags,
+                0,
+                bytes,
+            );
+        }
+    }
+
     pub fn bind_descriptor_sets(
         &self,
         descriptor_sets: &[&DescriptorSet],
@@ -502,6 +801,7 @@ impl CommandBuffer {
         first_vertex: u32,
         first_instance: u32,
     ) {
+        self.add_draw_stats(1, (vertex_count / 3) as u64 * instance_count as u64);
         unsafe {
             self.device.raw().cmd_draw(
                 self.raw,
@@ -514,6 +814,7 @@ impl CommandBuffer {
     }
 
     pub fn draw_indirect(&self, buffer: &Buffer, offset: u64, draw_count: u32, stride: u32) {
+        self.add_draw_stats(draw_count, 0);
         unsafe {
             self.device
                 .raw()
@@ -529,6 +830,7 @@ impl CommandBuffer {
         vertex_offset: i32,
         first_instance: u32,
     ) {
+        self.add_draw_stats(1, (index_count / 3) as u64 * instance_count as u64);
         unsafe {
             self.device.raw().cmd_draw_indexed(
                 self.raw,
@@ -550,6 +852,7 @@ impl CommandBuffer {
         max_draw_count: u32,
         stride: u32,
     ) {
+        debug_assert!(self.device.features().draw_indirect_count);
         unsafe {
             self.device.raw().cmd_draw_indirect_count(
                 self.raw,
@@ -570,6 +873,7 @@ impl CommandBuffer {
         draw_count: u32,
         stride: u32,
     ) {
+        self.add_draw_stats(draw_count, 0);
         unsafe {
             self.device.raw().cmd_draw_indexed_indirect(
                 self.raw,
@@ -590,6 +894,7 @@ impl CommandBuffer {
         max_draw_count: u32,
         stride: u32,
     ) {
+        debug_assert!(self.device.features().draw_indirect_count);
         unsafe {
             self.device.raw().cmd_draw_indexed_indirect_count(
                 self.raw,
@@ -604,10 +909,35 @@ impl CommandBuffer {
     }
 
     pub fn draw_mesh_tasks(&self, task_count: u32, first_task: u32) {
+        self.add_draw_stats(1, 0);
         unsafe {
-            self.mesh_shader
-                .functions
-                .cmd_draw_mesh_tasks(self.raw, task_count, first_task);
+            match self.mesh_shader.functions() {
+                MeshShaderFunctions::Ext(functions) => {
+                    // XXX: EXT has no first task, pass it through push constants instead
+                    debug_assert_eq!(first_task, 0);
+                    functions.cmd_draw_mesh_tasks(self.raw, task_count, 1, 1);
+                }
+                MeshShaderFunctions::Nv(functions) => {
+                    functions.cmd_draw_mesh_tasks(self.raw, task_count, first_task);
+                }
+            }
+        }
+    }
+
+    /// Only available with VK_EXT_mesh_shader
+    pub fn draw_mesh_tasks_ext(&self, group_count_x: u32, group_count_y: u32, group_count_z: u32) {
+        unsafe {
+            match self.mesh_shader.functions() {
+                MeshShaderFunctions::Ext(functions) => functions.cmd_draw_mesh_tasks(
+                    self.raw,
+                    group_count_x,
+                    group_count_y,
+                    group_count_z,
+                ),
+                MeshShaderFunctions::Nv(_) => {
+                    panic!("Multidimensional mesh task dispatch requires VK_EXT_mesh_shader!")
+                }
+            }
         }
     }
 
@@ -619,13 +949,22 @@ impl CommandBuffer {
         stride: u32,
     ) {
         unsafe {
-            self.mesh_shader.functions.cmd_draw_mesh_tasks_indirect(
-                self.raw,
-                buffer.raw(),
-                offset,
-                draw_count,
-                stride,
-            )
+            match self.mesh_shader.functions() {
+                MeshShaderFunctions::Ext(functions) => functions.cmd_draw_mesh_tasks_indirect(
+                    self.raw,
+                    buffer.raw(),
+                    offset,
+                    draw_count,
+                    stride,
+                ),
+                MeshShaderFunctions::Nv(functions) => functions.cmd_draw_mesh_tasks_indirect(
+                    self.raw,
+                    buffer.raw(),
+                    offset,
+                    draw_count,
+                    stride,
+                ),
+            }
         }
     }
 
@@ -638,10 +977,20 @@ impl CommandBuffer {
         max_draw_count: u32,
         stride: u32,
     ) {
+        debug_assert!(self.device.features().draw_indirect_count);
         unsafe {
-            self.mesh_shader
-                .functions
-                .cmd_draw_mesh_tasks_indirect_count(
+            match self.mesh_shader.functions() {
+                MeshShaderFunctions::Ext(functions) => functions
+                    .cmd_draw_mesh_tasks_indirect_count(
+                        self.raw,
+                        buffer.raw(),
+                        buffer_offset,
+                        count_buffer.raw(),
+                        count_buffer_offset,
+                        max_draw_count,
+                        stride,
+                    ),
+                MeshShaderFunctions::Nv(functions) => functions.cmd_draw_mesh_tasks_indirect_count(
                     self.raw,
                     buffer.raw(),
                     buffer_offset,
@@ -649,12 +998,165 @@ impl CommandBuffer {
                     count_buffer_offset,
                     max_draw_count,
                     stride,
-                )
+                ),
+            }
+        }
+    }
+
+    pub fn bind_ray_tracing_pipeline(&self, pipeline: &RayTracingPipeline) {
+        unsafe {
+            self.device.raw().cmd_bind_pipeline(
+                self.raw,
+                vk::PipelineBindPoint::RAY_TRACING_KHR,
+                pipeline.raw(),
+            );
+        }
+    }
+
+    pub fn bind_ray_tracing_descriptor_set(
+        &self,
+        descriptor_set: &DescriptorSet,
+        raw_pipeline_layout: vk::PipelineLayout,
+        set_index: u32,
+    ) {
+        unsafe {
+            self.device.raw().cmd_bind_descriptor_sets(
+                self.raw,
+                vk::PipelineBindPoint::RAY_TRACING_KHR,
+                raw_pipeline_layout,
+                set_index,
+                &[descriptor_set.raw()],
+                &[],
+            );
+        }
+    }
+
+    pub fn trace_rays(&self, pipeline: &RayTracingPipeline, width: u32, height: u32, depth: u32) {
+        let shader_binding_table = pipeline.shader_binding_table();
+        unsafe {
+            self.ray_tracing_context()
+                .ray_tracing_pipeline
+                .cmd_trace_rays(
+                    self.raw,
+                    shader_binding_table.ray_generation_region(),
+                    shader_binding_table.miss_region(),
+                    shader_binding_table.hit_region(),
+                    shader_binding_table.callable_region(),
+                    width,
+                    height,
+                    depth,
+                );
+        }
+    }
+
+    pub fn build_acceleration_structure(
+        &self,
+        build_info: &vk::AccelerationStructureBuildGeometryInfoKHR,
+        build_ranges: &[vk::AccelerationStructureBuildRangeInfoKHR],
+    ) {
+        unsafe {
+            self.ray_tracing_context()
+                .acceleration_structure
+                .cmd_build_acceleration_structures(
+                    self.raw,
+                    std::slice::from_ref(build_info),
+                    &[build_ranges],
+                );
+        }
+    }
+
+    fn ray_tracing_context(&self) -> &RayTracingContext {
+        self.ray_tracing
+            .as_ref()
+            .expect("Ray tracing is not supported by the device!")
+    }
+
+    pub fn set_viewport(&self, x: f32, y: f32, width: f32, height: f32) {
+        let viewport = vk::Viewport::builder()
+            .x(x)
+            .y(y)
+            .width(width)
+            .height(height)
+            .min_depth(0.0)
+            .max_depth(1.0)
+            .build();
+        unsafe {
+            self.device
+                .raw()
+                .cmd_set_viewport(self.raw, 0, std::slice::from_ref(&viewport));
+        }
+    }
+
+    pub fn set_scissor(&self, x: i32, y: i32, width: u32, height: u32) {
+        let scissor = vk::Rect2D {
+            offset: vk::Offset2D { x, y },
+            extent: vk::Extent2D { width, height },
+        };
+        unsafe {
+            self.device
+                .raw()
+                .cmd_set_scissor(self.raw, 0, std::slice::from_ref(&scissor));
+        }
+    }
+
+    /// Requires the bound pipeline to have LINE_WIDTH as a dynamic state
+    pub fn set_line_width(&self, line_width: f32) {
+        let line_width = if self.device.features().wide_lines {
+            line_width
+        } else {
+            1.0
+        };
+        unsafe {
+            self.device.raw().cmd_set_line_width(self.raw, line_width);
         }
     }
 
-    pub fn set_viewport(&self) {
-        todo!()
+    /// Requires the bound pipeline to have depth bias enabled and DEPTH_BIAS as a dynamic state
+    pub fn set_depth_bias(&self, constant_factor: f32, clamp: f32, slope_factor: f32) {
+        unsafe {
+            self.device
+                .raw()
+                .cmd_set_depth_bias(self.raw, constant_factor, clamp, slope_factor);
+        }
+    }
+
+    /// Sets the viewport and scissor to cover the whole render area of a rendering state
+    pub fn set_viewport_and_scissor(&self, rendering_state: &RenderingState) {
+        self.set_viewport(
+            0.0,
+            0.0,
+            rendering_state.width as f32,
+            rendering_state.height as f32,
+        );
+        self.set_scissor(0, 0, rendering_state.width, rendering_state.height);
+    }
+
+    pub fn bind_compute_pipeline(&self, pipeline: &ComputePipeline) {
+        unsafe {
+            self.device.raw().cmd_bind_pipeline(
+                self.raw,
+                vk::PipelineBindPoint::COMPUTE,
+                pipeline.raw(),
+            );
+        }
+    }
+
+    pub fn bind_compute_descriptor_set(
+        &self,
+        descriptor_set: &DescriptorSet,
+        raw_pipeline_layout: vk::PipelineLayout,
+        set_index: u32,
+    ) {
+        unsafe {
+            self.device.raw().cmd_bind_descriptor_sets(
+                self.raw,
+                vk::PipelineBindPoint::COMPUTE,
+                raw_pipeline_layout,
+                set_index,
+                &[descriptor_set.raw()],
+                &[],
+            );
+        }
     }
 
     pub fn dispatch(&self, group_count_x: u32, group_count_y: u32, group_count_z: u32) {
@@ -673,24 +1175,301 @@ impl CommandBuffer {
         src_offset: u64,
         dst_offset: u64,
     ) {
-        // XXX: Since BufferCopy2 is used - queue all copy regions and only execute copy once?
-        let region = vk::BufferCopy2::builder()
-            .size(size)
-            .src_offset(src_offset)
-            .dst_offset(dst_offset);
+        self.copy_batch(CopyBatch::new().add_buffer_region(src, dst, size, src_offset, dst_offset));
+    }
+
+    /// Executes the regions of a batch with one copy command per source and destination pair
+    pub fn copy_batch(&self, batch: CopyBatch) {
+        for (src, dst, regions) in &batch.buffer_copies {
+            let info = vk::CopyBufferInfo2::builder()
+                .src_buffer(*src)
+                .dst_buffer(*dst)
+                .regions(regions);
+
+            unsafe {
+                self.device.raw().cmd_copy_buffer2(self.raw, &info);
+            }
+        }
+
+        for (buffer, image, regions) in &batch.buffer_to_image_copies {
+            let info = vk::CopyBufferToImageInfo2::builder()
+                .src_buffer(*buffer)
+                .dst_image(*image)
+                .dst_image_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                .regions(regions);
+
+            unsafe {
+                self.device.raw().cmd_copy_buffer_to_image2(self.raw, &info);
+            }
+        }
+    }
+
+    pub fn copy_buffer_to_image(&self, buffer: &Buffer, image: &Image, buffer_offset: u64) {
+        self.copy_buffer_to_image_mip(buffer, image, buffer_offset, 0);
+    }
+
+    /// Copies all layers of a mip level, the layers are expected to be tightly packed one after another
+    pub fn copy_buffer_to_image_mip(
+        &self,
+        buffer: &Buffer,
+        image: &Image,
+        buffer_offset: u64,
+        mip_level: u32,
+    ) {
+        self.copy_buffer_to_image_layers(
+            buffer,
+            image,
+            buffer_offset,
+            mip_level,
+            0,
+            image.array_layers(),
+        );
+    }
+
+    pub fn copy_buffer_to_image_layers(
+        &self,
+        buffer: &Buffer,
+        image: &Image,
+        buffer_offset: u64,
+        mip_level: u32,
+        base_array_layer: u32,
+        layer_count: u32,
+    ) {
+        self.copy_batch(CopyBatch::new().add_buffer_to_image_region(
+            buffer,
+            image,
+            buffer_offset,
+            mip_level,
+            base_array_layer,
+            layer_count,
+        ));
+    }
 
-        let info = vk::CopyBufferInfo2::builder()
-            .src_buffer(src.raw())
-            .dst_buffer(dst.raw())
+    /// Scaled copy between image subresources, eg. for generating mips. The source needs to be in the
+    /// TRANSFER_SRC_OPTIMAL layout and the destination in the TRANSFER_DST_OPTIMAL layout.
+    pub fn blit_image(
+        &self,
+        src: &Image,
+        src_subresource: ImageCopySubresource,
+        dst: &Image,
+        dst_subresource: ImageCopySubresource,
+        filter: vk::Filter,
+    ) -> Result<()> {
+        src_subresource.validate(src)?;
+        dst_subresource.validate(dst)?;
+
+        if src_subresource.array_layer_count != dst_subresource.array_layer_count {
+            return Err(GpuError::invalid_desc(format!(
+                "Blit source has {} layers but destination has {} layers",
+                src_subresource.array_layer_count, dst_subresource.array_layer_count
+            )));
+        }
+        if format_is_block_compressed(src.format()) || format_is_block_compressed(dst.format()) {
+            return Err(GpuError::invalid_desc(format!(
+                "Cannot blit block compressed formats {:?} and {:?}",
+                src.format(),
+                dst.format()
+            )));
+        }
+        if (src.has_depth() || dst.has_depth())
+            && (src.format() != dst.format() || filter != vk::Filter::NEAREST)
+        {
+            return Err(GpuError::invalid_desc(
+                "Depth images can only be blitted to the same format with nearest filtering",
+            ));
+        }
+        if src.raw() == dst.raw()
+            && src_subresource.mip_level == dst_subresource.mip_level
+            && src_subresource.base_array_layer
+                < dst_subresource.base_array_layer + dst_subresource.array_layer_count
+            && dst_subresource.base_array_layer
+                < src_subresource.base_array_layer + src_subresource.array_layer_count
+        {
+            return Err(GpuError::invalid_desc(
+                "Blit source and destination subresources overlap",
+            ));
+        }
+
+        let mip_offset = |extent: vk::Extent3D| vk::Offset3D {
+            x: extent.width as i32,
+            y: extent.height as i32,
+            z: extent.depth as i32,
+        };
+        let region = vk::ImageBlit2::builder()
+            .src_subresource(src_subresource.vulkan_layers(src))
+            .src_offsets([
+                vk::Offset3D::default(),
+                mip_offset(src.mip_extent(src_subresource.mip_level)),
+            ])
+            .dst_subresource(dst_subresource.vulkan_layers(dst))
+            .dst_offsets([
+                vk::Offset3D::default(),
+                mip_offset(dst.mip_extent(dst_subresource.mip_level)),
+            ]);
+
+        let info = vk::BlitImageInfo2::builder()
+            .src_image(src.raw())
+            .src_image_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .dst_image(dst.raw())
+            .dst_image_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .regions(std::slice::from_ref(&region))
+            .filter(filter);
+
+        unsafe {
+            self.device.raw().cmd_blit_image2(self.raw, &info);
+        }
+
+        Ok(())
+    }
+
+    /// Unscaled copy between image subresources of the same extent and texel or block size. The source needs to be
+    /// in the TRANSFER_SRC_OPTIMAL layout and the destination in the TRANSFER_DST_OPTIMAL layout.
+    pub fn copy_image(
+        &self,
+        src: &Image,
+        src_subresource: ImageCopySubresource,
+        dst: &Image,
+        dst_subresource: ImageCopySubresource,
+    ) -> Result<()> {
+        src_subresource.validate(src)?;
+        dst_subresource.validate(dst)?;
+
+        if src_subresource.array_layer_count != dst_subresource.array_layer_count {
+            return Err(GpuError::invalid_desc(format!(
+                "Copy source has {} layers but destination has {} layers",
+                src_subresource.array_layer_count, dst_subresource.array_layer_count
+            )));
+        }
+
+        let src_extent = src.mip_extent(src_subresource.mip_level);
+        let dst_extent = dst.mip_extent(dst_subresource.mip_level);
+        if src_extent != dst_extent {
+            return Err(GpuError::invalid_desc(format!(
+                "Copy source extent {:?} does not match destination extent {:?}",
+                src_extent, dst_extent
+            )));
+        }
+
+        // Formats are compatible if they have the same texel or block size
+        let format_size = |format| format_image_size(format, 4, 4);
+        let compatible = src.format() == dst.format()
+            || (!src.has_depth()
+                && !dst.has_depth()
+                && format_size(src.format()).is_some()
+                && format_size(src.format()) == format_size(dst.format()));
+        if !compatible {
+            return Err(GpuError::invalid_desc(format!(
+                "Cannot copy between incompatible formats {:?} and {:?}",
+                src.format(),
+                dst.format()
+            )));
+        }
+
+        let region = vk::ImageCopy2::builder()
+            .src_subresource(src_subresource.vulkan_layers(src))
+            .src_offset(vk::Offset3D::default())
+            .dst_subresource(dst_subresource.vulkan_layers(dst))
+            .dst_offset(vk::Offset3D::default())
+            .extent(src_extent);
+
+        let info = vk::CopyImageInfo2::builder()
+            .src_image(src.raw())
+            .src_image_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .dst_image(dst.raw())
+            .dst_image_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
             .regions(std::slice::from_ref(&region));
 
         unsafe {
-            self.device.raw().cmd_copy_buffer2(self.raw, &info);
+            self.device.raw().cmd_copy_image2(self.raw, &info);
         }
+
+        Ok(())
     }
 
-    pub fn copy_buffer_to_image(&self, buffer: &Buffer, image: &Image, buffer_offset: u64) {
-        // XXX: Since BufferToImageCopy2 is used - queue all copy regions and only execute copy once?
+    /// Clears all mips and layers of a color image, the image needs to be in the TRANSFER_DST_OPTIMAL layout
+    pub fn clear_color_image(&self, image: &Image, color: [f32; 4]) -> Result<()> {
+        if image.has_depth() || format_is_block_compressed(image.format()) {
+            return Err(GpuError::invalid_desc(format!(
+                "Cannot clear image of format {:?} with a color",
+                image.format()
+            )));
+        }
+
+        let clear_value = vk::ClearColorValue { float32: color };
+        unsafe {
+            self.device.raw().cmd_clear_color_image(
+                self.raw,
+                image.raw(),
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &clear_value,
+                std::slice::from_ref(&image.subresource_range()),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Clears all mips and layers of a depth(stencil) image, the image needs to be in the TRANSFER_DST_OPTIMAL layout
+    pub fn clear_depth_stencil_image(&self, image: &Image, depth: f32, stencil: u32) -> Result<()> {
+        if !image.has_depth() {
+            return Err(GpuError::invalid_desc(format!(
+                "Cannot clear depth of image with format {:?}",
+                image.format()
+            )));
+        }
+
+        let clear_value = vk::ClearDepthStencilValue { depth, stencil };
+        unsafe {
+            self.device.raw().cmd_clear_depth_stencil_image(
+                self.raw,
+                image.raw(),
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &clear_value,
+                std::slice::from_ref(&image.subresource_range()),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Fills `size` bytes of a buffer with repeated `data`, `size` can be vk::WHOLE_SIZE to fill the rest of the
+    /// buffer. Offset and size need to be multiples of 4.
+    pub fn fill_buffer(&self, buffer: &Buffer, offset: u64, size: u64, data: u32) -> Result<()> {
+        if offset % 4 != 0 || (size != vk::WHOLE_SIZE && size % 4 != 0) {
+            return Err(GpuError::invalid_desc(format!(
+                "Buffer fill offset {} and size {} need to be multiples of 4",
+                offset, size
+            )));
+        }
+
+        unsafe {
+            self.device
+                .raw()
+                .cmd_fill_buffer(self.raw, buffer.raw(), offset, size, data);
+        }
+
+        Ok(())
+    }
+
+    pub fn copy_image_to_buffer(&self, image: &Image, buffer: &Buffer, buffer_offset: u64) {
+        self.copy_image_region_to_buffer(
+            image,
+            buffer,
+            buffer_offset,
+            vk::Offset3D { x: 0, y: 0, z: 0 },
+            image.extent(),
+        );
+    }
+
+    /// Copies a region of the first mip and layer of `image`, texels are tightly packed in `buffer`
+    pub fn copy_image_region_to_buffer(
+        &self,
+        image: &Image,
+        buffer: &Buffer,
+        buffer_offset: u64,
+        image_offset: vk::Offset3D,
+        image_extent: vk::Extent3D,
+    ) {
         let region = vk::BufferImageCopy2::builder()
             .buffer_offset(buffer_offset)
             .buffer_row_length(0)
@@ -698,23 +1477,24 @@ impl CommandBuffer {
             // XXX: Handle subresource copy properly
             .image_subresource(
                 vk::ImageSubresourceLayers::builder()
-                    .aspect_mask(vk::ImageAspectFlags::COLOR)
+                    .aspect_mask(image.copy_aspect_mask())
                     .mip_level(0)
                     .base_array_layer(0)
                     .layer_count(1)
                     .build(),
             )
-            .image_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
-            .image_extent(image.extent());
-
-        let info = vk::CopyBufferToImageInfo2::builder()
-            .src_buffer(buffer.raw())
-            .dst_image(image.raw())
-            .dst_image_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .image_offset(image_offset)
+            .image_extent(image_extent)
+            .build();
+
+        let info = vk::CopyImageToBufferInfo2::builder()
+            .src_image(image.raw())
+            .src_image_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .dst_buffer(buffer.raw())
             .regions(std::slice::from_ref(&region));
 
         unsafe {
-            self.device.raw().cmd_copy_buffer_to_image2(self.raw, &info);
+            self.device.raw().cmd_copy_image_to_buffer2(self.raw, &info);
         }
     }
 
@@ -724,15 +1504,6 @@ impl CommandBuffer {
         staging_buffer: &Buffer,
         data: &[T],
     ) -> Result<()> {
-        // XXX: Remove this!
-        let begin_info = vk::CommandBufferBeginInfo::builder()
-            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
-        unsafe {
-            self.device
-                .raw()
-                .begin_command_buffer(self.raw, &begin_info)?
-        };
-
         staging_buffer.copy_data_to_buffer(data)?;
 
         let barriers = Barriers::new().add_image(
@@ -753,14 +1524,24 @@ impl CommandBuffer {
         );
         self.pipeline_barrier(barriers);
 
-        unsafe { self.device.raw().end_command_buffer(self.raw)? };
-
         Ok(())
     }
 
-    pub fn pipeline_barrier(&self, barriers: Barriers) {
+    /// Barrier covering all memory, eg. to make writes of a batch of copies visible to later submissions
+    pub fn memory_barrier(
+        &self,
+        src_stage_mask: vk::PipelineStageFlags2,
+        src_access_mask: vk::AccessFlags2,
+        dst_stage_mask: vk::PipelineStageFlags2,
+        dst_access_mask: vk::AccessFlags2,
+    ) {
+        let memory_barrier = vk::MemoryBarrier2::builder()
+            .src_stage_mask(src_stage_mask)
+            .src_access_mask(src_access_mask)
+            .dst_stage_mask(dst_stage_mask)
+            .dst_access_mask(dst_access_mask);
         let dependency_info =
-            vk::DependencyInfo::builder().image_memory_barriers(barriers.image_barriers());
+            vk::DependencyInfo::builder().memory_barriers(std::slice::from_ref(&memory_barrier));
 
         unsafe {
             self.device
@@ -768,4 +1549,85 @@ impl CommandBuffer {
                 .cmd_pipeline_barrier2(self.raw, &dependency_info);
         }
     }
+
+    pub fn pipeline_barrier(&self, barriers: Barriers) {
+        let dependency_info = vk::DependencyInfo::builder()
+            .image_memory_barriers(barriers.image_barriers())
+            .buffer_memory_barriers(barriers.buffer_barriers());
+
+        unsafe {
+            self.device
+                .raw()
+                .cmd_pipeline_barrier2(self.raw, &dependency_info);
+        }
+    }
+
+    /// First half of a split barrier, signals the event once the source stages of `barriers` completed.
+    /// `wait_event` needs to be recorded with identical barriers.
+    pub fn set_event(&self, event: &Event, barriers: Barriers) {
+        let dependency_info = vk::DependencyInfo::builder()
+            .image_memory_barriers(barriers.image_barriers())
+            .buffer_memory_barriers(barriers.buffer_barriers());
+
+        unsafe {
+            self.device
+                .raw()
+                .cmd_set_event2(self.raw, event.raw(), &dependency_info);
+        }
+    }
+
+    /// Second half of a split barrier, executes the barriers `event` was set with before the destination stages
+    pub fn wait_event(&self, event: &Event, barriers: Barriers) {
+        let dependency_info = vk::DependencyInfo::builder()
+            .image_memory_barriers(barriers.image_barriers())
+            .buffer_memory_barriers(barriers.buffer_barriers())
+            .build();
+
+        unsafe {
+            self.device
+                .raw()
+                .cmd_wait_events2(self.raw, &[event.raw()], &[dependency_info]);
+        }
+    }
+
+    /// Unsignals the event after `stage_mask` completed, needs to be recorded after the wait before the event is
+    /// set again
+    pub fn reset_event(&self, event: &Event, stage_mask: vk::PipelineStageFlags2) {
+        unsafe {
+            self.device
+                .raw()
+                .cmd_reset_event2(self.raw, event.raw(), stage_mask);
+        }
+    }
+
+    /// Records the barriers from the tracked states of all image subresources to `new_state`, nothing is recorded
+    /// if the image already is in the read only `new_state`
+    pub fn transition_image(&self, image: &Image, new_state: ResourceState) {
+        let barriers = Barriers::new().add_image_transition(image, new_state);
+        if !barriers.is_empty() {
+            self.pipeline_barrier(barriers);
+        }
+    }
+
+    /// Same as `transition_image` for a mip level and array layer range of the image
+    pub fn transition_image_subresource(
+        &self,
+        image: &Image,
+        subresource_range: vk::ImageSubresourceRange,
+        new_state: ResourceState,
+    ) {
+        let barriers =
+            Barriers::new().add_image_subresource_transition(image, subresource_range, new_state);
+        if !barriers.is_empty() {
+            self.pipeline_barrier(barriers);
+        }
+    }
+
+    /// Records a barrier from the tracked state of the buffer to `new_state` if needed
+    pub fn transition_buffer(&self, buffer: &Buffer, new_state: ResourceState) {
+        let barriers = Barriers::new().add_buffer_transition(buffer, new_state);
+        if !barriers.is_empty() {
+            self.pipeline_barrier(barriers);
+        }
+    }
 }
@@ -0,0 +1,141 @@
+use anyhow::{Context, Result};
+use rikka_core::vk;
+
+use crate::{
+    descriptor_set::*, error::GpuError, escape::*, factory::*, pipeline::PipelineLayout,
+    shader_state::*,
+};
+
+pub struct ComputePipelineDesc {
+    pub shader_state: ShaderStateDesc,
+    pub push_constants: Option<PushConstantLayout>,
+}
+
+impl ComputePipelineDesc {
+    pub fn new() -> Self {
+        Self {
+            shader_state: ShaderStateDesc::new(),
+            push_constants: None,
+        }
+    }
+
+    pub fn set_compute_stage(mut self, stage: ShaderStageDesc) -> Self {
+        self.shader_state = ShaderStateDesc::new().add_stage(stage);
+        self
+    }
+
+    pub fn set_push_constant_size(mut self, push_constant_size: u32) -> Self {
+        self.push_constants = Some(PushConstantLayout::from_size(push_constant_size));
+        self
+    }
+
+    /// Push constants of the `#[repr(C)]` struct `T`, validated against the compute shader when the pipeline is created
+    pub fn set_push_constants<T: Copy>(mut self) -> Self {
+        self.push_constants = Some(PushConstantLayout::of::<T>());
+        self
+    }
+}
+
+pub struct ComputePipeline {
+    device: DeviceGuard,
+
+    raw: vk::Pipeline,
+    layout: Handle<PipelineLayout>,
+
+    descriptor_set_layouts: Vec<Handle<DescriptorSetLayout>>,
+}
+
+impl ComputePipeline {
+    pub unsafe fn create(
+        device: DeviceGuard,
+        factory: &Factory,
+        desc: ComputePipelineDesc,
+    ) -> Result<Self> {
+        if desc.shader_state.stages.len() != 1
+            || desc.shader_state.stages[0].shader_type != ShaderStageType::Compute
+        {
+            return Err(GpuError::invalid_desc(
+                "Compute pipeline requires exactly one compute stage!",
+            )
+            .into());
+        }
+
+        let shader_state = factory.get_or_create_shader_state(&desc.shader_state)?;
+
+        // XXX: Bindless set is not supported for compute pipelines yet, the shared layout only has fragment stage visibility
+        let descriptor_set_layouts = shader_state
+            .reflection()
+            .descriptor_sets
+            .iter()
+            .map(|set| {
+                let layout_desc = DescriptorSetLayoutDesc::new()
+                    .set_bindings(set.bindings.clone())
+                    .set_binding_names(set.binding_names.clone())
+                    .set_bindless(false)
+                    .set_dynamic(false);
+                factory.get_or_create_descriptor_set_layout(layout_desc)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let push_constant_ranges = match desc.push_constants {
+            Some(push_constants) => {
+                push_constants
+                    .validate(shader_state.reflection(), vk::ShaderStageFlags::COMPUTE)
+                    .context("Compute pipeline push constants do not match the shader")?;
+                vec![vk::PushConstantRange::builder()
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                    .offset(0)
+                    .size(push_constants.size)
+                    .build()]
+            }
+            None => {
+                validate_undeclared_push_constants(
+                    shader_state.reflection(),
+                    vk::ShaderStageFlags::COMPUTE,
+                )?;
+                vec![]
+            }
+        };
+
+        let layout = factory
+            .get_or_create_pipeline_layout(&descriptor_set_layouts, &push_constant_ranges)?;
+
+        let pipeline_info = vk::ComputePipelineCreateInfo::builder()
+            .stage(shader_state.vulkan_shader_stages()[0])
+            .layout(layout.raw())
+            .build();
+
+        let raw = device
+            .raw()
+            .create_compute_pipelines(
+                vk::PipelineCache::null(),
+                std::slice::from_ref(&pipeline_info),
+                None,
+            )
+            .map_err(|e| e.1)
+            .context("Failed to create vulkan compute pipeline!")?[0];
+
+        Ok(Self {
+            device,
+            raw,
+            layout,
+            descriptor_set_layouts,
+        })
+    }
+
+    pub unsafe fn destroy(self) {
+        self.device.raw().destroy_pipeline(self.raw, None);
+    }
+
+    pub fn raw(&self) -> vk::Pipeline {
+        self.raw
+    }
+
+    pub fn raw_layout(&self) -> vk::PipelineLayout {
+        self.layout.raw()
+    }
+
+    pub fn descriptor_set_layouts(&self) -> &[Handle<DescriptorSetLayout>] {
+        &self.descriptor_set_layouts
+    }
+}
@@ -8,6 +8,8 @@ pub const MAX_NUM_BINDLESS_RESOURCECS: u32 = 1024;
 
 pub const BINDLESS_SET_SAMPLED_IMAGE_INDEX: u32 = 15;
 pub const BINDLESS_SET_STORAGE_IMAGE_INDEX: u32 = 16;
+/// Cube maps share the index space of the sampled images
+pub const BINDLESS_SET_SAMPLED_CUBE_IMAGE_INDEX: u32 = 17;
 
 pub const GLOBAL_DESCRIPTOR_POOL_MAX_SETS: u32 = 2048;
 pub const GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE: u32 = 128;
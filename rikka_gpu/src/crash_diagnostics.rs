@@ -0,0 +1,155 @@
+use std::{ffi::c_void, fmt};
+
+use parking_lot::Mutex;
+use rikka_core::{ash::extensions::nv, vk};
+
+use crate::{constants, factory::DeviceGuard, queue::Queue};
+
+/// Bits of a checkpoint marker holding the marker index within its frame
+const CHECKPOINT_MARKER_INDEX_BITS: u32 = 24;
+
+/// Returned by the Gpu when the device is lost, with the Gpu work that was submitted and executing
+#[derive(Debug)]
+pub struct DeviceLostError {
+    /// Absolute index of the frame being recorded when the loss was detected
+    pub frame_index: u64,
+    /// (absolute frame index, marker name) of the frames in flight, in submission order
+    pub submitted_markers: Vec<(u64, String)>,
+    /// Last markers the Gpu reached with the pipeline stage they were reached at. Empty if the device does not
+    /// support diagnostic checkpoints.
+    pub checkpoints: Vec<(String, vk::PipelineStageFlags)>,
+}
+
+impl fmt::Display for DeviceLostError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Gpu device lost during frame {}", self.frame_index)?;
+
+        writeln!(f, "Submitted markers:")?;
+        for (frame_index, name) in &self.submitted_markers {
+            writeln!(f, "    frame {}: {}", frame_index, name)?;
+        }
+
+        if self.checkpoints.is_empty() {
+            write!(f, "No diagnostic checkpoints available")
+        } else {
+            write!(f, "Last checkpoints reached:")?;
+            for (name, stage) in &self.checkpoints {
+                write!(f, "\n    {} ({:?})", name, stage)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+impl std::error::Error for DeviceLostError {}
+
+#[derive(Default)]
+struct FrameMarkers {
+    absolute_frame_index: u64,
+    names: Vec<String>,
+}
+
+/// Keeps the names of markers recorded in the frames in flight to report what the Gpu was executing when the device
+/// is lost. With `VK_NV_device_diagnostic_checkpoints` markers are also recorded as checkpoints and the driver reports
+/// the last ones reached.
+// XXX: Write markers with `VK_AMD_buffer_marker` on devices without diagnostic checkpoints
+pub(crate) struct CrashDiagnostics {
+    checkpoints: Option<nv::DeviceDiagnosticCheckpoints>,
+    frames: Mutex<Vec<FrameMarkers>>,
+}
+
+impl CrashDiagnostics {
+    pub(crate) fn new(device: &DeviceGuard) -> Self {
+        let checkpoints = device
+            .features()
+            .diagnostic_checkpoints
+            .then(|| nv::DeviceDiagnosticCheckpoints::new(device.instance().raw(), device.raw()));
+
+        Self {
+            checkpoints,
+            frames: Mutex::new(
+                (0..constants::MAX_FRAMES)
+                    .map(|_| FrameMarkers::default())
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Clears the markers of `frame_index`, the previous work of the frame must have completed
+    pub(crate) fn begin_frame(&self, frame_index: u32, absolute_frame_index: u64) {
+        self.frames.lock()[frame_index as usize] = FrameMarkers {
+            absolute_frame_index,
+            names: Vec::new(),
+        };
+    }
+
+    pub(crate) fn set_marker(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        frame_index: u32,
+        name: &str,
+    ) {
+        let mut frames = self.frames.lock();
+        let names = &mut frames[frame_index as usize].names;
+        names.push(name.to_string());
+
+        if let Some(checkpoints) = &self.checkpoints {
+            // The marker is an opaque pointer sized value, 0 is avoided so it is never null
+            let marker = ((frame_index as usize) << CHECKPOINT_MARKER_INDEX_BITS) + names.len();
+            unsafe { checkpoints.cmd_set_checkpoint(command_buffer, marker as *const c_void) };
+        }
+    }
+
+    fn marker_name(&self, frames: &[FrameMarkers], marker: *mut c_void) -> String {
+        let marker = marker as usize;
+        let frame_index = marker >> CHECKPOINT_MARKER_INDEX_BITS;
+        let marker_index = marker & ((1 << CHECKPOINT_MARKER_INDEX_BITS) - 1);
+
+        frames
+            .get(frame_index)
+            .and_then(|frame| frame.names.get(marker_index.wrapping_sub(1)))
+            .cloned()
+            .unwrap_or_else(|| format!("Unknown marker {:#x}", marker))
+    }
+
+    pub(crate) fn report(&self, queue: &Queue, frame_index: u64) -> DeviceLostError {
+        let frames = self.frames.lock();
+
+        let mut frame_order = frames.iter().collect::<Vec<_>>();
+        frame_order.sort_by_key(|frame| frame.absolute_frame_index);
+        let submitted_markers = frame_order
+            .into_iter()
+            .flat_map(|frame| {
+                frame
+                    .names
+                    .iter()
+                    .map(move |name| (frame.absolute_frame_index, name.clone()))
+            })
+            .collect();
+
+        let checkpoints = match &self.checkpoints {
+            Some(checkpoints) => unsafe {
+                let checkpoint_count = checkpoints.get_queue_checkpoint_data_len(queue.raw());
+                let mut checkpoint_data = vec![vk::CheckpointDataNV::default(); checkpoint_count];
+                checkpoints.get_queue_checkpoint_data(queue.raw(), &mut checkpoint_data);
+
+                checkpoint_data
+                    .iter()
+                    .map(|data| {
+                        (
+                            self.marker_name(&frames, data.p_checkpoint_marker),
+                            data.stage,
+                        )
+                    })
+                    .collect()
+            },
+            None => Vec::new(),
+        };
+
+        DeviceLostError {
+            frame_index,
+            submitted_markers,
+            checkpoints,
+        }
+    }
+}
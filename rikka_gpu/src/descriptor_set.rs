@@ -1,11 +1,19 @@
 use std::sync::Arc;
 
-use anyhow::{Context, Result};
+use parking_lot::Mutex;
 
 use rikka_core::vk;
 pub use rikka_shader::types::DescriptorBinding;
 
-use crate::{buffer::Buffer, constants, escape::*, factory::DeviceGuard, image::Image};
+use crate::{
+    buffer::Buffer,
+    constants,
+    error::{GpuError, Result},
+    escape::*,
+    factory::DeviceGuard,
+    image::{Image, ImageView},
+    ray_tracing::AccelerationStructure,
+};
 
 pub struct DescriptorPoolDesc {
     pub pool_sizes: Vec<vk::DescriptorPoolSize>,
@@ -46,6 +54,9 @@ impl DescriptorPoolDesc {
 pub struct DescriptorPool {
     device: DeviceGuard,
     raw: vk::DescriptorPool,
+    flags: vk::DescriptorPoolCreateFlags,
+    /// Sets dropped since the last `free_released_descriptor_sets`, they may still be used by frames in flight
+    released_descriptor_sets: Mutex<Vec<vk::DescriptorSet>>,
 }
 
 impl DescriptorPool {
@@ -55,12 +66,14 @@ impl DescriptorPool {
             .max_sets(desc.max_sets)
             .pool_sizes(&desc.pool_sizes);
 
-        let raw = device
-            .raw()
-            .create_descriptor_pool(&create_info, None)
-            .context("Failed to create vulkan descriptor pool!")?;
+        let raw = device.raw().create_descriptor_pool(&create_info, None)?;
 
-        Ok(Self { device, raw })
+        Ok(Self {
+            device,
+            raw,
+            flags: desc.flags,
+            released_descriptor_sets: Mutex::new(Vec::new()),
+        })
     }
 
     pub(crate) unsafe fn destroy(self) {
@@ -70,14 +83,45 @@ impl DescriptorPool {
     pub fn raw(&self) -> vk::DescriptorPool {
         self.raw
     }
+
+    fn release_descriptor_set(&self, descriptor_set: vk::DescriptorSet) {
+        // Sets of pools without FREE_DESCRIPTOR_SET are only returned when the pool is destroyed
+        if self
+            .flags
+            .contains(vk::DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET)
+        {
+            self.released_descriptor_sets.lock().push(descriptor_set);
+        }
+    }
+
+    /// The caller needs to make sure the Gpu is not using the released sets anymore
+    pub(crate) fn free_released_descriptor_sets(&self) {
+        let mut released_descriptor_sets = self.released_descriptor_sets.lock();
+        if released_descriptor_sets.is_empty() {
+            return;
+        }
+
+        unsafe {
+            if let Err(err) = self
+                .device
+                .raw()
+                .free_descriptor_sets(self.raw, &released_descriptor_sets)
+            {
+                log::error!("Failed to free vulkan descriptor sets: {}", err);
+            }
+        }
+        released_descriptor_sets.clear();
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub struct DescriptorSetLayoutDesc {
     pub bindings: Vec<DescriptorBinding>,
     pub bindless: bool,
     pub dynamic: bool,
     pub flags: vk::DescriptorSetLayoutCreateFlags,
+    /// Shader variable name to binding index, used to resolve named binding resources
+    pub binding_names: Vec<(String, u32)>,
 }
 
 impl DescriptorSetLayoutDesc {
@@ -87,9 +131,35 @@ impl DescriptorSetLayoutDesc {
             bindless: false,
             dynamic: false,
             flags: vk::DescriptorSetLayoutCreateFlags::empty(),
+            binding_names: vec![],
         }
     }
 
+    /// Layout of the bindless set shared by all pipelines: sampled 2D images, storage images and sampled cube maps
+    pub fn new_bindless() -> Self {
+        Self::new()
+            .set_flags(vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL)
+            .set_bindless(true)
+            .add_binding(DescriptorBinding::new(
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                constants::BINDLESS_SET_SAMPLED_IMAGE_INDEX,
+                constants::MAX_NUM_BINDLESS_RESOURCECS,
+                vk::ShaderStageFlags::FRAGMENT,
+            ))
+            .add_binding(DescriptorBinding::new(
+                vk::DescriptorType::STORAGE_IMAGE,
+                constants::BINDLESS_SET_STORAGE_IMAGE_INDEX,
+                constants::MAX_NUM_BINDLESS_RESOURCECS,
+                vk::ShaderStageFlags::FRAGMENT,
+            ))
+            .add_binding(DescriptorBinding::new(
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                constants::BINDLESS_SET_SAMPLED_CUBE_IMAGE_INDEX,
+                constants::MAX_NUM_BINDLESS_RESOURCECS,
+                vk::ShaderStageFlags::FRAGMENT,
+            ))
+    }
+
     pub fn add_binding(mut self, binding: DescriptorBinding) -> Self {
         self.bindings.push(binding);
         self
@@ -114,12 +184,18 @@ impl DescriptorSetLayoutDesc {
         self.flags = flags;
         self
     }
+
+    pub fn set_binding_names(mut self, binding_names: Vec<(String, u32)>) -> Self {
+        self.binding_names = binding_names;
+        self
+    }
 }
 
 pub struct DescriptorSetLayout {
     device: DeviceGuard,
     raw: vk::DescriptorSetLayout,
     bindings: Vec<DescriptorBinding>,
+    binding_names: Vec<(String, u32)>,
     binding_index_to_array_index: [usize; constants::MAX_SHADER_BINDING_INDEX as usize],
     bindless: bool,
     dynamic: bool,
@@ -146,7 +222,9 @@ impl DescriptorSetLayout {
                 |binding| binding.index,
             );
         if max_shader_binding_index > constants::MAX_SHADER_BINDING_INDEX {
-            return Err(anyhow::anyhow!("Maximum shader binding index is invalid"));
+            return Err(GpuError::invalid_desc(
+                "Maximum shader binding index is invalid",
+            ));
         }
 
         let mut binding_index_to_array_index =
@@ -204,13 +282,11 @@ impl DescriptorSetLayout {
 
                 device
                     .raw()
-                    .create_descriptor_set_layout(&create_info, None)
-                    .context("Failed to create vulkan descriptor set layout")?
+                    .create_descriptor_set_layout(&create_info, None)?
             } else {
                 device
                     .raw()
-                    .create_descriptor_set_layout(&create_info, None)
-                    .context("Failed to create vulkan descriptor set layout")?
+                    .create_descriptor_set_layout(&create_info, None)?
             }
         };
 
@@ -218,6 +294,7 @@ impl DescriptorSetLayout {
             device,
             raw,
             bindings: desc.bindings,
+            binding_names: desc.binding_names,
             binding_index_to_array_index,
             bindless: desc.bindless,
             dynamic: desc.dynamic,
@@ -251,6 +328,13 @@ impl DescriptorSetLayout {
         &self.bindings[binding_data_index]
     }
 
+    pub fn binding_index_by_name(&self, name: &str) -> Option<u32> {
+        self.binding_names
+            .iter()
+            .find(|(binding_name, _)| binding_name == name)
+            .map(|(_, index)| *index)
+    }
+
     pub fn is_bindless(&self) -> bool {
         self.bindless
     }
@@ -260,6 +344,7 @@ impl DescriptorSetLayout {
 pub enum DescriptorSetBindingResourceType {
     Buffer,
     ImageSampler,
+    AccelerationStructure,
     // ImageArray,
 }
 
@@ -269,9 +354,14 @@ pub struct DescriptorSetBindingResource {
     // XXX: Need strong references for these?
     pub buffer: Option<Handle<Buffer>>,
     pub image: Option<Handle<Image>>,
+    /// Written instead of the default view of `image`
+    pub image_view: Option<Handle<ImageView>>,
+    pub acceleration_structure: Option<Handle<AccelerationStructure>>,
 
     pub count: u32,
     pub binding_index: u32,
+    /// Shader variable name resolved against the layout when the set is written, overrides `binding_index`
+    pub binding_name: Option<String>,
 }
 
 impl DescriptorSetBindingResource {
@@ -280,8 +370,11 @@ impl DescriptorSetBindingResource {
             resource_type: DescriptorSetBindingResourceType::Buffer,
             buffer: Some(buffer),
             image: None,
+            image_view: None,
+            acceleration_structure: None,
             count: 1,
             binding_index,
+            binding_name: None,
         }
     }
 
@@ -290,14 +383,75 @@ impl DescriptorSetBindingResource {
             resource_type: DescriptorSetBindingResourceType::ImageSampler,
             buffer: None,
             image: Some(image),
+            image_view: None,
+            acceleration_structure: None,
             count: 1,
             binding_index,
+            binding_name: None,
         }
     }
 
+    /// Image views are sampled with the linked sampler of their image
+    pub fn image_view(image_view: Handle<ImageView>, binding_index: u32) -> Self {
+        Self {
+            resource_type: DescriptorSetBindingResourceType::ImageSampler,
+            buffer: None,
+            image: Some(image_view.image().clone()),
+            image_view: Some(image_view),
+            acceleration_structure: None,
+            count: 1,
+            binding_index,
+            binding_name: None,
+        }
+    }
+
+    pub fn acceleration_structure(
+        acceleration_structure: Handle<AccelerationStructure>,
+        binding_index: u32,
+    ) -> Self {
+        Self {
+            resource_type: DescriptorSetBindingResourceType::AccelerationStructure,
+            buffer: None,
+            image: None,
+            image_view: None,
+            acceleration_structure: Some(acceleration_structure),
+            count: 1,
+            binding_index,
+            binding_name: None,
+        }
+    }
+
+    pub fn set_binding_name(mut self, name: &str) -> Self {
+        self.binding_name = Some(name.to_owned());
+        self
+    }
+
     pub fn resource_type(&self) -> DescriptorSetBindingResourceType {
         self.resource_type
     }
+
+    fn raw_image_view(&self) -> vk::ImageView {
+        match &self.image_view {
+            Some(image_view) => image_view.raw(),
+            None => self.image.as_ref().unwrap().raw_view(),
+        }
+    }
+
+    fn matches_descriptor_type(&self, descriptor_type: vk::DescriptorType) -> bool {
+        match self.resource_type {
+            DescriptorSetBindingResourceType::Buffer => matches!(
+                descriptor_type,
+                vk::DescriptorType::UNIFORM_BUFFER | vk::DescriptorType::STORAGE_BUFFER
+            ),
+            DescriptorSetBindingResourceType::ImageSampler => matches!(
+                descriptor_type,
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER | vk::DescriptorType::STORAGE_IMAGE
+            ),
+            DescriptorSetBindingResourceType::AccelerationStructure => {
+                descriptor_type == vk::DescriptorType::ACCELERATION_STRUCTURE_KHR
+            }
+        }
+    }
 }
 
 pub struct DescriptorSetDesc {
@@ -345,6 +499,67 @@ impl DescriptorSetDesc {
         self
     }
 
+    pub fn add_image_view_resource(
+        mut self,
+        image_view: Handle<ImageView>,
+        binding_index: u32,
+    ) -> Self {
+        self.binding_resources
+            .push(DescriptorSetBindingResource::image_view(
+                image_view,
+                binding_index,
+            ));
+        self
+    }
+
+    pub fn add_acceleration_structure_resource(
+        mut self,
+        acceleration_structure: Handle<AccelerationStructure>,
+        binding_index: u32,
+    ) -> Self {
+        self.binding_resources
+            .push(DescriptorSetBindingResource::acceleration_structure(
+                acceleration_structure,
+                binding_index,
+            ));
+        self
+    }
+
+    /// Binds a buffer to the shader variable `name`, the binding index is taken from the layout's reflection data
+    pub fn add_buffer_resource_named(mut self, name: &str, buffer: Handle<Buffer>) -> Self {
+        self.binding_resources
+            .push(DescriptorSetBindingResource::buffer(buffer, 0).set_binding_name(name));
+        self
+    }
+
+    pub fn add_image_resource_named(mut self, name: &str, image: Handle<Image>) -> Self {
+        self.binding_resources
+            .push(DescriptorSetBindingResource::image(image, 0).set_binding_name(name));
+        self
+    }
+
+    pub fn add_image_view_resource_named(
+        mut self,
+        name: &str,
+        image_view: Handle<ImageView>,
+    ) -> Self {
+        self.binding_resources
+            .push(DescriptorSetBindingResource::image_view(image_view, 0).set_binding_name(name));
+        self
+    }
+
+    pub fn add_acceleration_structure_resource_named(
+        mut self,
+        name: &str,
+        acceleration_structure: Handle<AccelerationStructure>,
+    ) -> Self {
+        self.binding_resources.push(
+            DescriptorSetBindingResource::acceleration_structure(acceleration_structure, 0)
+                .set_binding_name(name),
+        );
+        self
+    }
+
     pub fn set_pool(mut self, pool: Handle<DescriptorPool>) -> Self {
         self.pool = Some(pool);
         self
@@ -383,19 +598,9 @@ impl DescriptorSet {
                         .descriptor_counts(&max_bindless_binding);
                 allocate_info = allocate_info.push_next(&mut count_info);
 
-                unsafe {
-                    device
-                        .raw()
-                        .allocate_descriptor_sets(&allocate_info)
-                        .context("Failed to create vulkan descriptor set")?
-                }
+                unsafe { device.raw().allocate_descriptor_sets(&allocate_info)? }
             } else {
-                unsafe {
-                    device
-                        .raw()
-                        .allocate_descriptor_sets(&allocate_info)
-                        .context("Failed to create vulkan descriptor set")?
-                }
+                unsafe { device.raw().allocate_descriptor_sets(&allocate_info)? }
             }
         };
 
@@ -407,8 +612,7 @@ impl DescriptorSet {
             layout: desc.layout,
         };
 
-        set.update(&desc.binding_resources)
-            .context("Failed to update descriptor set")?;
+        set.update(&desc.binding_resources)?;
 
         Ok(set)
     }
@@ -429,15 +633,35 @@ impl DescriptorSet {
         // Image/buffer descriptor write infos need to be valid when calling vkUpdateDescriptorSets
         let mut descriptor_buffer_infos = Vec::<vk::DescriptorBufferInfo>::new();
         let mut descriptor_image_infos = Vec::<vk::DescriptorImageInfo>::new();
+        let mut raw_acceleration_structures =
+            Vec::<vk::AccelerationStructureKHR>::with_capacity(binding_resources.len());
+        let mut descriptor_acceleration_structure_infos =
+            Vec::<vk::WriteDescriptorSetAccelerationStructureKHR>::with_capacity(
+                binding_resources.len(),
+            );
 
         for resource in binding_resources {
-            let binding = self
-                .layout
-                .binding_for_shader_binding_index(resource.binding_index);
-            // XXX: Check that reource type corresponds to binding type etc.
+            let binding_index = match &resource.binding_name {
+                Some(name) => self.layout.binding_index_by_name(name).ok_or_else(|| {
+                    GpuError::invalid_desc(format!(
+                        "Descriptor set layout has no binding named {}",
+                        name
+                    ))
+                })?,
+                None => resource.binding_index,
+            };
+            let binding = self.layout.binding_for_shader_binding_index(binding_index);
 
             // XXX: These should be equal; We actually do not require any info from the layout bindings array other than to verify)
-            assert!(resource.binding_index == binding.index);
+            assert!(binding_index == binding.index);
+
+            if !resource.matches_descriptor_type(binding.descriptor_type) {
+                return Err(GpuError::invalid_desc(format!(
+                    "Binding resource {} does not match descriptor type {:?}",
+                    resource.binding_name.as_deref().unwrap_or("<unnamed>"),
+                    binding.descriptor_type
+                )));
+            }
 
             if self.layout.is_bindless() && can_descriptor_type_be_bindless(binding.descriptor_type)
             {
@@ -450,6 +674,8 @@ impl DescriptorSet {
                 &resource,
                 &mut descriptor_buffer_infos,
                 &mut descriptor_image_infos,
+                &mut raw_acceleration_structures,
+                &mut descriptor_acceleration_structure_infos,
             ));
         }
 
@@ -470,10 +696,14 @@ impl DescriptorSet {
         resource: &DescriptorSetBindingResource,
         buffer_descriptors: &mut Vec<vk::DescriptorBufferInfo>,
         image_descriptors: &mut Vec<vk::DescriptorImageInfo>,
+        raw_acceleration_structures: &mut Vec<vk::AccelerationStructureKHR>,
+        acceleration_structure_descriptors: &mut Vec<
+            vk::WriteDescriptorSetAccelerationStructureKHR,
+        >,
     ) -> vk::WriteDescriptorSet {
         let mut write_descriptor = vk::WriteDescriptorSet::builder()
             .dst_set(descriptor_set)
-            .dst_binding(resource.binding_index)
+            .dst_binding(binding.index)
             .dst_array_element(0)
             .descriptor_type(binding.descriptor_type);
         // XXX: ash bug or intentional?
@@ -486,7 +716,7 @@ impl DescriptorSet {
                     let image = resource.image.clone().unwrap();
                     let sampler = image.linked_sampler().unwrap();
                     let image_descriptor = vk::DescriptorImageInfo::builder()
-                        .image_view(image.raw_view())
+                        .image_view(resource.raw_image_view())
                         .sampler(sampler.raw())
                         .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
                         .build();
@@ -499,9 +729,8 @@ impl DescriptorSet {
                 }
             }
             vk::DescriptorType::STORAGE_IMAGE => {
-                let image = resource.image.clone().unwrap();
                 let image_descriptor = vk::DescriptorImageInfo::builder()
-                    .image_view(image.raw_view())
+                    .image_view(resource.raw_image_view())
                     .image_layout(vk::ImageLayout::GENERAL)
                     .build();
 
@@ -538,6 +767,21 @@ impl DescriptorSet {
                 write_descriptor = write_descriptor
                     .buffer_info(std::slice::from_ref(buffer_descriptors.last().unwrap()));
             }
+            vk::DescriptorType::ACCELERATION_STRUCTURE_KHR => {
+                let acceleration_structure = resource.acceleration_structure.clone().unwrap();
+                raw_acceleration_structures.push(acceleration_structure.raw());
+
+                // Acceleration structure writes are chained through pNext instead of the regular info arrays
+                let acceleration_structure_descriptor =
+                    vk::WriteDescriptorSetAccelerationStructureKHR::builder()
+                        .acceleration_structures(std::slice::from_ref(
+                            raw_acceleration_structures.last().unwrap(),
+                        ))
+                        .build();
+                acceleration_structure_descriptors.push(acceleration_structure_descriptor);
+                write_descriptor = write_descriptor
+                    .push_next(acceleration_structure_descriptors.last_mut().unwrap());
+            }
             _ => todo!(
                 "Vulkan write descriptor for type {:?} not yet supported",
                 binding.descriptor_type
@@ -547,3 +791,9 @@ impl DescriptorSet {
         write_descriptor.build()
     }
 }
+
+impl Drop for DescriptorSet {
+    fn drop(&mut self) {
+        self.pool.release_descriptor_set(self.raw);
+    }
+}
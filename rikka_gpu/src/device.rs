@@ -9,24 +9,35 @@ use parking_lot::Mutex;
 
 use rikka_core::{ash, vk};
 
-use crate::{instance::Instance, physical_device::PhysicalDevice, queue::*, surface::Surface};
+use crate::{
+    instance::Instance,
+    physical_device::*,
+    queue::*,
+    surface::Surface,
+    types::{DeviceFeatures, MeshShaderExtension},
+};
 
 /// Device wrapper that acts as a lifeguard for the Gpu resources and the Vulkan instance.
 pub struct Device {
     // XXX: Remove Arc<>
     allocator: ManuallyDrop<Arc<Mutex<Allocator>>>,
     queue_family_indices: QueueFamilyIndices,
+    // One lock per queue family, every family is created with a single queue.
+    queue_locks: Vec<Arc<Mutex<()>>>,
     raw: ash::Device,
     physical_device: PhysicalDevice,
-    surface: Surface,
+    surface: Option<Surface>,
     instance: Instance,
 }
 
 impl Device {
-    pub fn new(instance: Instance, surface: Surface) -> Result<Self> {
-        let physical_devices = instance.get_physical_devices(&surface)?;
+    /// A device created without a surface is headless and cannot present. Compute only devices submit all work to
+    /// a compute family and do not require a graphics family.
+    pub fn new(instance: Instance, surface: Option<Surface>, compute_only: bool) -> Result<Self> {
+        let physical_devices = instance.get_physical_devices(surface.as_ref())?;
         let physical_device = select_suitable_physical_device(&physical_devices)?;
-        let queue_family_indices = select_queue_family_indices(&physical_device);
+        let queue_family_indices =
+            select_queue_family_indices(&physical_device, surface.is_some(), compute_only)?;
 
         log::info!("Gpu name: {}", physical_device.name);
         log::info!("Graphics family: {}", queue_family_indices.graphics.index());
@@ -34,9 +45,12 @@ impl Device {
         log::info!("Compute family: {}", queue_family_indices.compute.index());
         log::info!("Transfer family: {}", queue_family_indices.transfer.index());
 
+        log::info!("Gpu features: {:?}", physical_device.features);
+
         let raw = Self::new_vulkan_device(
             &instance,
             &physical_device,
+            surface.is_some(),
             &[
                 queue_family_indices.graphics,
                 queue_family_indices.compute,
@@ -54,13 +68,18 @@ impl Device {
                 log_leaks_on_shutdown: true,
                 ..Default::default()
             },
-            buffer_device_address: true,
+            buffer_device_address: physical_device.features.buffer_device_address,
         })?;
         let allocator = Arc::new(Mutex::new(allocator));
 
+        let queue_locks = (0..physical_device.queue_families.len())
+            .map(|_| Arc::new(Mutex::new(())))
+            .collect();
+
         Ok(Self {
             allocator: ManuallyDrop::new(allocator),
             queue_family_indices,
+            queue_locks,
             raw,
             physical_device,
             surface,
@@ -71,6 +90,7 @@ impl Device {
     fn new_vulkan_device(
         instance: &Instance,
         physical_device: &PhysicalDevice,
+        presentable: bool,
         queue_family_indices: &[QueueFamily],
     ) -> Result<ash::Device> {
         let queue_priorities = [1.0f32];
@@ -95,7 +115,26 @@ impl Device {
                 .collect::<Vec<_>>()
         };
 
-        let device_extension_strs = ["VK_KHR_swapchain", "VK_NV_mesh_shader"];
+        let features = physical_device.features;
+
+        let mut device_extension_strs = vec![];
+        if presentable {
+            device_extension_strs.push("VK_KHR_swapchain");
+        }
+        match features.mesh_shader {
+            Some(MeshShaderExtension::Ext) => device_extension_strs.push(MESH_SHADER_EXT_EXTENSION),
+            Some(MeshShaderExtension::Nv) => device_extension_strs.push(MESH_SHADER_NV_EXTENSION),
+            None => {}
+        }
+        if features.ray_tracing {
+            device_extension_strs.extend_from_slice(&RAY_TRACING_EXTENSIONS);
+        }
+        if features.index_type_uint8 {
+            device_extension_strs.push(INDEX_TYPE_UINT8_EXTENSION);
+        }
+        if features.diagnostic_checkpoints {
+            device_extension_strs.push(DIAGNOSTIC_CHECKPOINTS_EXTENSION);
+        }
         let device_extension_strs = device_extension_strs
             .iter()
             .map(|str| CString::new(*str))
@@ -105,8 +144,7 @@ impl Device {
             .map(|ext| ext.as_ptr())
             .collect::<Vec<_>>();
 
-        // XXX: Properly check that these features are supported by the Gpu(done in physical device creation)
-
+        // Required features are checked when selecting the physical device
         let mut vulkan11_features =
             vk::PhysicalDeviceVulkan11Features::builder().shader_draw_parameters(true);
         let mut vulkan12_features = vk::PhysicalDeviceVulkan12Features::builder()
@@ -117,15 +155,28 @@ impl Device {
             .descriptor_binding_sampled_image_update_after_bind(true)
             .descriptor_binding_storage_image_update_after_bind(true)
             .timeline_semaphore(true)
+            .host_query_reset(true)
             .shader_sampled_image_array_non_uniform_indexing(true)
-            .buffer_device_address(true);
+            .draw_indirect_count(features.draw_indirect_count)
+            .buffer_device_address(features.buffer_device_address);
         let mut vulkan13_features = vk::PhysicalDeviceVulkan13Features::builder()
             .dynamic_rendering(true)
             .synchronization2(true);
 
-        let mut mesh_shader_features = vk::PhysicalDeviceMeshShaderFeaturesNV::builder()
+        let mut mesh_shader_ext_features = vk::PhysicalDeviceMeshShaderFeaturesEXT::builder()
             .mesh_shader(true)
             .task_shader(true);
+        let mut mesh_shader_nv_features = vk::PhysicalDeviceMeshShaderFeaturesNV::builder()
+            .mesh_shader(true)
+            .task_shader(true);
+
+        let mut acceleration_structure_features =
+            vk::PhysicalDeviceAccelerationStructureFeaturesKHR::builder()
+                .acceleration_structure(true);
+        let mut ray_tracing_pipeline_features =
+            vk::PhysicalDeviceRayTracingPipelineFeaturesKHR::builder().ray_tracing_pipeline(true);
+        let mut index_type_uint8_features =
+            vk::PhysicalDeviceIndexTypeUint8FeaturesEXT::builder().index_type_uint8(true);
 
         // PhysicalDeviceFeatures 2 reports ALL of Gpu's device features capabilies. Pass this along pNext chain to enable all.
         let mut device_features2 = vk::PhysicalDeviceFeatures2::builder();
@@ -139,8 +190,24 @@ impl Device {
         device_features2 = device_features2
             .push_next(&mut vulkan11_features)
             .push_next(&mut vulkan12_features)
-            .push_next(&mut vulkan13_features)
-            .push_next(&mut mesh_shader_features);
+            .push_next(&mut vulkan13_features);
+        match features.mesh_shader {
+            Some(MeshShaderExtension::Ext) => {
+                device_features2 = device_features2.push_next(&mut mesh_shader_ext_features)
+            }
+            Some(MeshShaderExtension::Nv) => {
+                device_features2 = device_features2.push_next(&mut mesh_shader_nv_features)
+            }
+            None => {}
+        }
+        if features.ray_tracing {
+            device_features2 = device_features2
+                .push_next(&mut acceleration_structure_features)
+                .push_next(&mut ray_tracing_pipeline_features);
+        }
+        if features.index_type_uint8 {
+            device_features2 = device_features2.push_next(&mut index_type_uint8_features);
+        }
 
         let device_create_info = vk::DeviceCreateInfo::builder()
             .queue_create_infos(&queue_create_infos)
@@ -172,7 +239,16 @@ impl Device {
     pub fn get_queue(&self, queue_type: QueueType, queue_index: u32) -> Queue {
         let queue_family = self.queue_family(queue_type);
         let raw = unsafe { self.raw.get_device_queue(queue_family.index(), queue_index) };
-        unsafe { Queue::new(self.raw.clone(), raw, queue_family.index()) }
+        let lock = self.queue_locks[queue_family.index() as usize].clone();
+        unsafe {
+            Queue::new(
+                self.raw.clone(),
+                raw,
+                queue_family.index(),
+                queue_type,
+                lock,
+            )
+        }
     }
 
     pub fn instance(&self) -> &Instance {
@@ -183,8 +259,21 @@ impl Device {
         &self.physical_device
     }
 
-    pub fn surface(&self) -> &Surface {
-        &self.surface
+    /// Returns None for headless devices
+    pub fn surface(&self) -> Option<&Surface> {
+        self.surface.as_ref()
+    }
+
+    pub fn features(&self) -> &DeviceFeatures {
+        &self.physical_device.features
+    }
+
+    pub fn ray_tracing_supported(&self) -> bool {
+        self.physical_device.features.ray_tracing
+    }
+
+    pub fn is_headless(&self) -> bool {
+        self.surface.is_none()
     }
 
     pub fn allocator(&self) -> &Arc<Mutex<Allocator>> {
@@ -205,41 +294,93 @@ impl Drop for Device {
 }
 
 fn select_suitable_physical_device(devices: &[PhysicalDevice]) -> Result<PhysicalDevice> {
-    let device = devices
+    for device in devices
         .iter()
+        .filter(|device| !device.missing_required_features.is_empty())
+    {
+        log::warn!(
+            "Gpu {} is missing required features: {:?}",
+            device.name,
+            device.missing_required_features
+        );
+    }
+
+    let suitable_devices = devices
+        .iter()
+        .filter(|device| device.missing_required_features.is_empty());
+
+    // Prefer discrete Gpus
+    let device = suitable_devices
+        .clone()
         .find(|device| device.device_type == vk::PhysicalDeviceType::DISCRETE_GPU)
+        .or_else(|| suitable_devices.clone().next())
         .ok_or_else(|| anyhow::anyhow!("Could not find suitable Gpu!"))?;
 
     Ok(device.clone())
 }
 
-fn select_queue_family_indices(device: &PhysicalDevice) -> QueueFamilyIndices {
-    let mut graphics = None;
-    let mut present = None;
-    let mut compute = None;
-    let mut transfer = None;
-
-    // 1 graphics + present family, 1 compute family and 1 transfer only family
-    for family in device
+fn select_queue_family_indices(
+    device: &PhysicalDevice,
+    presentable: bool,
+    compute_only: bool,
+) -> Result<QueueFamilyIndices> {
+    let families = device
         .queue_families
         .iter()
         .filter(|family| family.queue_count() > 0)
-    {
-        if family.supports_graphics() && graphics.is_none() {
-            graphics = Some(*family);
-            assert!(family.supports_present());
-            present = Some(*family);
-        } else if family.supports_compute() && compute.is_none() {
-            compute = Some(*family);
-        } else if family.supports_transfer() && !family.supports_compute() && transfer.is_none() {
-            transfer = Some(*family);
-        }
-    }
+        .copied()
+        .collect::<Vec<_>>();
 
-    QueueFamilyIndices {
-        graphics: graphics.unwrap(),
-        present: present.unwrap(),
-        compute: compute.unwrap(),
-        transfer: transfer.unwrap(),
+    // 1 graphics + present family, 1 compute family and 1 transfer only family
+    let graphics = families
+        .iter()
+        .find(|family| family.supports_graphics() && (!presentable || family.supports_present()))
+        .copied();
+    let compute = families
+        .iter()
+        .find(|family| {
+            family.supports_compute()
+                && graphics.map_or(true, |graphics| graphics.index() != family.index())
+        })
+        .copied();
+    let transfer = families
+        .iter()
+        .find(|family| {
+            family.supports_transfer() && !family.supports_compute() && !family.supports_graphics()
+        })
+        .copied();
+
+    if compute_only {
+        // The compute family is the primary queue, the graphics family is only used if it is the only one
+        let compute = compute.or(graphics.filter(|graphics| graphics.supports_compute()));
+        let compute = compute
+            .ok_or_else(|| anyhow::anyhow!("Gpu {} has no compute queue family", device.name))?;
+
+        return Ok(QueueFamilyIndices {
+            graphics: compute,
+            present: compute,
+            compute,
+            transfer: transfer.unwrap_or(compute),
+        });
     }
+
+    let graphics = graphics.ok_or_else(|| {
+        if presentable {
+            anyhow::anyhow!(
+                "Gpu {} has no graphics queue family that can present to the surface",
+                device.name
+            )
+        } else {
+            anyhow::anyhow!("Gpu {} has no graphics queue family", device.name)
+        }
+    })?;
+
+    // Devices without dedicated families, eg. software rasterizers on CI machines, share the graphics family. The
+    // queues of collapsed families are the same VkQueue, submissions to it are serialized by the per family queue lock.
+    Ok(QueueFamilyIndices {
+        graphics,
+        present: graphics,
+        compute: compute.unwrap_or(graphics),
+        transfer: transfer.or(compute).unwrap_or(graphics),
+    })
 }
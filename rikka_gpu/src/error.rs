@@ -0,0 +1,129 @@
+use std::fmt;
+
+use gpu_allocator::AllocationError;
+use rikka_core::vk;
+
+use crate::crash_diagnostics::DeviceLostError;
+
+pub type Result<T, E = GpuError> = std::result::Result<T, E>;
+
+/// Errors of the public Gpu API. Internal failures of the factory and device setup are kept as `anyhow` errors and
+/// classified when they cross the API boundary.
+#[derive(Debug)]
+pub enum GpuError {
+    /// Host or device memory is exhausted
+    OutOfMemory,
+    /// Report is None if the loss was not detected while submitting or presenting a frame
+    DeviceLost(Option<DeviceLostError>),
+    /// The swapchain has to be recreated before presenting again
+    SwapchainOutOfDate,
+    ShaderCompile(String),
+    InvalidDesc {
+        reason: String,
+    },
+    /// Unsupported features or resources are requested
+    Unsupported(String),
+    /// All slots of a bindless array are in use
+    BindlessExhausted,
+    Vulkan(vk::Result),
+    /// Failures without a kind callers can handle, with the context they were reported with
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl GpuError {
+    pub fn invalid_desc(reason: impl Into<String>) -> Self {
+        Self::InvalidDesc {
+            reason: reason.into(),
+        }
+    }
+
+    pub fn other(message: impl Into<String>) -> Self {
+        Self::Other(message.into().into())
+    }
+
+    pub fn is_device_lost(&self) -> bool {
+        matches!(self, Self::DeviceLost(_))
+    }
+}
+
+impl fmt::Display for GpuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfMemory => write!(f, "Out of Gpu or host memory"),
+            Self::DeviceLost(Some(report)) => write!(f, "{}", report),
+            Self::DeviceLost(None) => write!(f, "Gpu device lost"),
+            Self::SwapchainOutOfDate => write!(f, "Swapchain is out of date"),
+            Self::ShaderCompile(message) => write!(f, "Failed to compile shader: {}", message),
+            Self::InvalidDesc { reason } => write!(f, "Invalid description: {}", reason),
+            Self::Unsupported(message) => write!(f, "Unsupported by the device: {}", message),
+            Self::BindlessExhausted => write!(f, "All bindless slots are in use"),
+            Self::Vulkan(result) => write!(f, "Vulkan error: {}", result),
+            Self::Other(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for GpuError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::DeviceLost(Some(report)) => Some(report),
+            Self::Other(error) => error.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<vk::Result> for GpuError {
+    fn from(result: vk::Result) -> Self {
+        match result {
+            vk::Result::ERROR_OUT_OF_HOST_MEMORY
+            | vk::Result::ERROR_OUT_OF_DEVICE_MEMORY
+            | vk::Result::ERROR_OUT_OF_POOL_MEMORY => Self::OutOfMemory,
+            vk::Result::ERROR_DEVICE_LOST => Self::DeviceLost(None),
+            vk::Result::ERROR_OUT_OF_DATE_KHR => Self::SwapchainOutOfDate,
+            result => Self::Vulkan(result),
+        }
+    }
+}
+
+impl From<AllocationError> for GpuError {
+    fn from(error: AllocationError) -> Self {
+        match error {
+            AllocationError::OutOfMemory => Self::OutOfMemory,
+            error => Self::Other(Box::new(error)),
+        }
+    }
+}
+
+/// Recovers the kind of an internal error, the context of errors that are not `Other` is dropped
+impl From<anyhow::Error> for GpuError {
+    fn from(error: anyhow::Error) -> Self {
+        let error = match error.downcast::<GpuError>() {
+            Ok(gpu_error) => return gpu_error,
+            Err(error) => error,
+        };
+        let error = match error.downcast::<DeviceLostError>() {
+            Ok(report) => return Self::DeviceLost(Some(report)),
+            Err(error) => error,
+        };
+
+        if error.chain().any(|cause| {
+            matches!(
+                cause.downcast_ref::<AllocationError>(),
+                Some(AllocationError::OutOfMemory)
+            )
+        }) {
+            return Self::OutOfMemory;
+        }
+
+        let vulkan_error = error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<vk::Result>().copied())
+            .map(Self::from);
+        match vulkan_error {
+            // The alternate format keeps the context chain in the message
+            Some(Self::Vulkan(_)) | None => Self::other(format!("{:#}", error)),
+            Some(gpu_error) => gpu_error,
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use anyhow::Result;
+use rikka_core::vk;
+
+use crate::factory::DeviceGuard;
+
+/// Splits a barrier into `CommandBuffer::set_event` after the producing work and `CommandBuffer::wait_event` before
+/// the consuming work, work recorded in between can overlap with the transition
+pub struct Event {
+    device: DeviceGuard,
+    raw: vk::Event,
+}
+
+impl Event {
+    pub(crate) unsafe fn create(device: DeviceGuard) -> Result<Self> {
+        // Events are only set and waited on by command buffers
+        let event_info = vk::EventCreateInfo::builder().flags(vk::EventCreateFlags::DEVICE_ONLY);
+        let raw = device.raw().create_event(&event_info, None)?;
+
+        Ok(Self { device, raw })
+    }
+
+    pub(crate) unsafe fn destroy(self) {
+        self.device.raw().destroy_event(self.raw, None);
+    }
+
+    pub fn raw(&self) -> vk::Event {
+        self.raw
+    }
+}
@@ -1,20 +1,30 @@
-use std::{ops::Deref, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    ops::Deref,
+    sync::Arc,
+};
 
 use anyhow::Result;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
+use rikka_core::vk;
 
 use crate::{
-    buffer::*, descriptor_set::*, device::*, escape::*, image::*, pipeline::*, sampler::*,
+    buffer::*, compute_pipeline::*, constants::INVALID_BINDLESS_TEXTURE_INDEX, descriptor_set::*,
+    device::*, escape::*, event::*, image::*, pipeline::*, ray_tracing::*, sampler::*,
+    shader_state::*,
 };
 
 struct ResourceTracker<T> {
     terminal: Terminal<T>,
+    /// Dropped resources with the absolute index of the last frame that may still use them
+    pending: VecDeque<(u64, T)>,
 }
 
 impl<T> ResourceTracker<T> {
     fn new() -> Self {
         Self {
             terminal: Terminal::new(),
+            pending: VecDeque::new(),
         }
     }
 
@@ -25,18 +35,39 @@ impl<T> ResourceTracker<T> {
         Escape::escape(resource, &self.terminal)
     }
 
-    fn destroy(&mut self, destroy: impl FnMut(T)) {
-        self.terminal.drain().for_each(destroy);
+    /// Queues resources dropped since the last call with `frame`, then destroys the queued resources whose frame
+    /// completed. `completed_frames` is the number of frames whose Gpu work has finished.
+    fn destroy(&mut self, frame: u64, completed_frames: u64, mut destroy: impl FnMut(T)) {
+        let pending = &mut self.pending;
+        pending.extend(self.terminal.drain().map(|resource| (frame, resource)));
+
+        while let Some((dropped_frame, _)) = pending.front() {
+            if *dropped_frame >= completed_frames {
+                break;
+            }
+            destroy(pending.pop_front().unwrap().1);
+        }
     }
 }
 
 struct ResourceHub {
     buffers: ResourceTracker<Buffer>,
     images: ResourceTracker<Image>,
+    image_views: ResourceTracker<ImageView>,
     samplers: ResourceTracker<Sampler>,
     graphics_pipelines: ResourceTracker<GraphicsPipeline>,
+    compute_pipelines: ResourceTracker<ComputePipeline>,
+    ray_tracing_pipelines: ResourceTracker<RayTracingPipeline>,
+    acceleration_structures: ResourceTracker<AccelerationStructure>,
+    pipeline_layouts: ResourceTracker<PipelineLayout>,
     descriptor_set_layouts: ResourceTracker<DescriptorSetLayout>,
     descriptor_pools: ResourceTracker<DescriptorPool>,
+    events: ResourceTracker<Event>,
+
+    /// Bindless indices of destroyed images, reused for new images
+    returned_bindless_image_indices: Vec<u32>,
+    /// Bindless storage image indices of destroyed images
+    returned_storage_bindless_image_indices: Vec<u32>,
 }
 
 impl ResourceHub {
@@ -44,26 +75,69 @@ impl ResourceHub {
         Self {
             buffers: ResourceTracker::new(),
             images: ResourceTracker::new(),
+            image_views: ResourceTracker::new(),
             samplers: ResourceTracker::new(),
             graphics_pipelines: ResourceTracker::new(),
+            compute_pipelines: ResourceTracker::new(),
+            ray_tracing_pipelines: ResourceTracker::new(),
+            acceleration_structures: ResourceTracker::new(),
+            pipeline_layouts: ResourceTracker::new(),
             descriptor_set_layouts: ResourceTracker::new(),
             descriptor_pools: ResourceTracker::new(),
+            events: ResourceTracker::new(),
+            returned_bindless_image_indices: Vec::new(),
+            returned_storage_bindless_image_indices: Vec::new(),
         }
     }
 
-    unsafe fn cleanup(&mut self) {
-        self.buffers.destroy(|b| b.destroy());
-        self.images.destroy(|i| i.destroy());
-        self.samplers.destroy(|s| s.destroy());
-        self.graphics_pipelines.destroy(|p| p.destroy());
-        self.descriptor_set_layouts.destroy(|l| l.destroy());
-        self.descriptor_pools.destroy(|p| p.destroy());
+    unsafe fn cleanup(&mut self, frame: u64, completed_frames: u64) {
+        self.acceleration_structures
+            .destroy(frame, completed_frames, |a| a.destroy());
+        self.buffers
+            .destroy(frame, completed_frames, |b| b.destroy());
+        // Views release their image handle when dropped, drop them before the images are destroyed
+        self.image_views.destroy(frame, completed_frames, drop);
+        let returned_bindless_image_indices = &mut self.returned_bindless_image_indices;
+        let returned_storage_bindless_image_indices =
+            &mut self.returned_storage_bindless_image_indices;
+        self.images.destroy(frame, completed_frames, |i| {
+            if i.owns_bindless_index() {
+                returned_bindless_image_indices.push(i.bindless_index());
+                if i.has_srgb_view() {
+                    returned_bindless_image_indices.push(i.srgb_bindless_index());
+                }
+            }
+            if i.storage_bindless_index() != INVALID_BINDLESS_TEXTURE_INDEX {
+                returned_storage_bindless_image_indices.push(i.storage_bindless_index());
+            }
+            i.destroy()
+        });
+        self.samplers
+            .destroy(frame, completed_frames, |s| s.destroy());
+        self.graphics_pipelines
+            .destroy(frame, completed_frames, |p| p.destroy());
+        self.compute_pipelines
+            .destroy(frame, completed_frames, |p| p.destroy());
+        self.ray_tracing_pipelines
+            .destroy(frame, completed_frames, |p| p.destroy());
+        self.pipeline_layouts
+            .destroy(frame, completed_frames, |l| l.destroy());
+        self.descriptor_set_layouts
+            .destroy(frame, completed_frames, |l| l.destroy());
+        self.descriptor_pools
+            .destroy(frame, completed_frames, |p| p.destroy());
+        self.events
+            .destroy(frame, completed_frames, |e| e.destroy());
+    }
+
+    unsafe fn cleanup_all(&mut self) {
+        self.cleanup(0, u64::MAX);
     }
 }
 
 impl Drop for ResourceHub {
     fn drop(&mut self) {
-        unsafe { self.cleanup() }
+        unsafe { self.cleanup_all() }
     }
 }
 
@@ -118,9 +192,26 @@ impl Drop for DeviceGuard {
     }
 }
 
+#[derive(Hash, PartialEq, Eq)]
+struct PipelineLayoutKey {
+    descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
+    /// Stage flags, offset and size of each range
+    push_constant_ranges: Vec<(vk::ShaderStageFlags, u32, u32)>,
+}
+
+/// Layouts shared by all pipelines created through the factory, entries live until `clear_layout_caches`
+#[derive(Default)]
+struct LayoutCache {
+    descriptor_set_layouts: HashMap<DescriptorSetLayoutDesc, Handle<DescriptorSetLayout>>,
+    pipeline_layouts: HashMap<PipelineLayoutKey, Handle<PipelineLayout>>,
+}
+
 pub struct Factory {
     device: DeviceGuard,
     resource_hub: HubGuard,
+    layout_cache: Mutex<LayoutCache>,
+    /// Shader modules shared by pipeline variants, entries live until they are invalidated
+    shader_state_cache: Mutex<HashMap<ShaderStateDesc, Arc<ShaderState>>>,
 }
 
 impl Factory {
@@ -128,6 +219,8 @@ impl Factory {
         Self {
             device,
             resource_hub,
+            layout_cache: Mutex::new(LayoutCache::default()),
+            shader_state_cache: Mutex::new(HashMap::new()),
         }
     }
 
@@ -143,6 +236,11 @@ impl Factory {
         Ok(self.resource_hub.hub.read().images.escape(image))
     }
 
+    pub fn create_image_view(&self, desc: ImageViewDesc) -> Result<Escape<ImageView>> {
+        let image_view = ImageView::create(desc)?;
+        Ok(self.resource_hub.hub.read().image_views.escape(image_view))
+    }
+
     pub fn create_sampler(&self, desc: SamplerDesc) -> Result<Escape<Sampler>> {
         let sampler = unsafe { Sampler::create(self.device.clone(), desc)? };
         Ok(self.resource_hub.hub.read().samplers.escape(sampler))
@@ -162,6 +260,50 @@ impl Factory {
             .escape(graphics_pipeline))
     }
 
+    pub fn create_compute_pipeline(
+        &self,
+        desc: ComputePipelineDesc,
+    ) -> Result<Escape<ComputePipeline>> {
+        let compute_pipeline = unsafe { ComputePipeline::create(self.device.clone(), self, desc)? };
+        Ok(self
+            .resource_hub
+            .hub
+            .read()
+            .compute_pipelines
+            .escape(compute_pipeline))
+    }
+
+    pub fn create_ray_tracing_pipeline(
+        &self,
+        desc: RayTracingPipelineDesc,
+    ) -> Result<Escape<RayTracingPipeline>> {
+        let ray_tracing_pipeline =
+            unsafe { RayTracingPipeline::create(self.device.clone(), self, desc)? };
+        Ok(self
+            .resource_hub
+            .hub
+            .read()
+            .ray_tracing_pipelines
+            .escape(ray_tracing_pipeline))
+    }
+
+    pub fn create_acceleration_structure(
+        &self,
+        level: vk::AccelerationStructureTypeKHR,
+        buffer: Handle<Buffer>,
+        bottom_levels: Vec<Handle<AccelerationStructure>>,
+    ) -> Result<Escape<AccelerationStructure>> {
+        let acceleration_structure = unsafe {
+            AccelerationStructure::create(self.device.clone(), level, buffer, bottom_levels)?
+        };
+        Ok(self
+            .resource_hub
+            .hub
+            .read()
+            .acceleration_structures
+            .escape(acceleration_structure))
+    }
+
     pub fn create_descriptor_set_layout(
         &self,
         desc: DescriptorSetLayoutDesc,
@@ -175,6 +317,99 @@ impl Factory {
             .escape(layout))
     }
 
+    /// Returns the cached layout created from an identical desc, or creates and caches a new one
+    pub fn get_or_create_descriptor_set_layout(
+        &self,
+        desc: DescriptorSetLayoutDesc,
+    ) -> Result<Handle<DescriptorSetLayout>> {
+        let mut layout_cache = self.layout_cache.lock();
+        if let Some(layout) = layout_cache.descriptor_set_layouts.get(&desc) {
+            return Ok(layout.clone());
+        }
+
+        let layout = Handle::new(
+            self.create_descriptor_set_layout(desc.clone())?,
+            self.resource_hub.clone(),
+        );
+        layout_cache
+            .descriptor_set_layouts
+            .insert(desc, layout.clone());
+
+        Ok(layout)
+    }
+
+    /// Returns the cached pipeline layout with the same set layouts and push constant ranges, or creates and caches a
+    /// new one
+    pub fn get_or_create_pipeline_layout(
+        &self,
+        descriptor_set_layouts: &[Handle<DescriptorSetLayout>],
+        push_constant_ranges: &[vk::PushConstantRange],
+    ) -> Result<Handle<PipelineLayout>> {
+        let key = PipelineLayoutKey {
+            descriptor_set_layouts: descriptor_set_layouts
+                .iter()
+                .map(|layout| layout.raw())
+                .collect(),
+            push_constant_ranges: push_constant_ranges
+                .iter()
+                .map(|range| (range.stage_flags, range.offset, range.size))
+                .collect(),
+        };
+
+        let mut layout_cache = self.layout_cache.lock();
+        if let Some(layout) = layout_cache.pipeline_layouts.get(&key) {
+            return Ok(layout.clone());
+        }
+
+        let layout = unsafe {
+            PipelineLayout::create(
+                self.device.clone(),
+                descriptor_set_layouts.to_vec(),
+                push_constant_ranges,
+            )?
+        };
+        let layout = Handle::new(
+            self.resource_hub.hub.read().pipeline_layouts.escape(layout),
+            self.resource_hub.clone(),
+        );
+        layout_cache.pipeline_layouts.insert(key, layout.clone());
+
+        Ok(layout)
+    }
+
+    /// Releases the cached layouts, they are destroyed once no pipeline uses them anymore
+    pub fn clear_layout_caches(&self) {
+        let mut layout_cache = self.layout_cache.lock();
+        layout_cache.pipeline_layouts.clear();
+        layout_cache.descriptor_set_layouts.clear();
+    }
+
+    /// Returns the cached shader state created from an identical desc, or compiles and caches a new one
+    pub fn get_or_create_shader_state(&self, desc: &ShaderStateDesc) -> Result<Arc<ShaderState>> {
+        if let Some(shader_state) = self.shader_state_cache.lock().get(desc) {
+            return Ok(shader_state.clone());
+        }
+
+        // Compile without holding the lock, shader compilation can be slow
+        let shader_state = Arc::new(ShaderState::new(self.device.clone(), desc.clone())?);
+        self.shader_state_cache
+            .lock()
+            .insert(desc.clone(), shader_state.clone());
+
+        Ok(shader_state)
+    }
+
+    /// Drops cached shader states that read any of the files so their shaders are recompiled on next use
+    pub fn invalidate_shader_states(&self, file_names: &[String]) {
+        self.shader_state_cache
+            .lock()
+            .retain(|desc, _| !desc.uses_files(file_names));
+    }
+
+    pub fn clear_shader_state_cache(&self) {
+        self.shader_state_cache.lock().clear();
+    }
+
     pub fn create_descriptor_pool(
         &self,
         desc: DescriptorPoolDesc,
@@ -183,9 +418,43 @@ impl Factory {
         Ok(self.resource_hub.hub.read().descriptor_pools.escape(pool))
     }
 
-    pub fn cleanup_resources(&self) {
+    pub fn create_event(&self) -> Result<Escape<Event>> {
+        let event = unsafe { Event::create(self.device.clone())? };
+        Ok(self.resource_hub.hub.read().events.escape(event))
+    }
+
+    pub fn pop_returned_bindless_image_index(&self) -> Option<u32> {
+        self.resource_hub
+            .hub
+            .write()
+            .returned_bindless_image_indices
+            .pop()
+    }
+
+    pub fn pop_returned_storage_bindless_image_index(&self) -> Option<u32> {
+        self.resource_hub
+            .hub
+            .write()
+            .returned_storage_bindless_image_indices
+            .pop()
+    }
+
+    /// Tags resources dropped since the last cleanup with `frame`, the absolute index of the last submitted frame.
+    /// Resources are destroyed once the Gpu completed their frame, `completed_frames` being the graphics timeline
+    /// semaphore value.
+    pub fn cleanup_resources(&self, frame: u64, completed_frames: u64) {
+        unsafe {
+            self.resource_hub
+                .hub
+                .write()
+                .cleanup(frame, completed_frames);
+        }
+    }
+
+    /// Destroys all dropped resources, the caller needs to make sure the Gpu is idle
+    pub fn cleanup_all_resources(&self) {
         unsafe {
-            self.resource_hub.hub.write().cleanup();
+            self.resource_hub.hub.write().cleanup_all();
         }
     }
 
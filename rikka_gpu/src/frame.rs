@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use anyhow::Result;
 use parking_lot::Mutex;
 
@@ -7,15 +9,14 @@ use crate::{
     command_buffer::*,
     constants,
     factory::DeviceGuard,
-    query::{PipelineStatsQueryPool, TimestampQueryPool},
+    query::{GpuTimestamp, TimestampQueryPool},
     queue::*,
     synchronization::*,
 };
 
 pub struct FrameThreadPools {
     pub command_pool: CommandPool,
-    pub timestamp_query_pool: TimestampQueryPool,
-    pub pipeline_stats_query_pool: PipelineStatsQueryPool,
+    pub timestamp_query_pool: Arc<TimestampQueryPool>,
 }
 
 pub struct FrameThreadPoolsDesc {
@@ -42,14 +43,14 @@ impl FrameThreadPoolsManager {
 
         for _ in 0..num_pools {
             let command_pool = CommandPool::new(device.clone(), desc.graphics_queue_family_index)?;
-            let timestamp_query_pool =
-                TimestampQueryPool::new(device.clone(), desc.time_queries_per_frame)?;
-            let pipeline_stats_query_pool = PipelineStatsQueryPool::new(device.clone())?;
+            let timestamp_query_pool = Arc::new(TimestampQueryPool::new(
+                device.clone(),
+                desc.time_queries_per_frame,
+            )?);
 
             frame_thread_pools.push(FrameThreadPools {
                 command_pool,
                 timestamp_query_pool,
-                pipeline_stats_query_pool,
             });
         }
 
@@ -85,6 +86,23 @@ impl FrameThreadPoolsManager {
     pub fn num_threads(&self) -> u32 {
         self.num_threads
     }
+
+    /// Reads back the timestamp scopes of all threads of a frame, the frame's Gpu work must have completed
+    pub fn resolve_timestamps(
+        &self,
+        frame_index: u32,
+        timestamp_period: f32,
+    ) -> Result<Vec<GpuTimestamp>> {
+        let mut timestamps = Vec::new();
+        for thread_index in 0..self.num_threads {
+            timestamps.extend(
+                self.pools_at(frame_index, thread_index)
+                    .timestamp_query_pool
+                    .resolve(timestamp_period)?,
+            );
+        }
+        Ok(timestamps)
+    }
 }
 
 pub struct FrameIndexData {
@@ -106,10 +124,12 @@ pub struct FrameSynchronizationManager {
     // transfer_work_semaphore: Semaphore,
     last_compute_semaphore_value: u64,
     has_async_work: bool,
+    /// No swapchain image acquisition/presentation to synchronize with
+    headless: bool,
 }
 
 impl FrameSynchronizationManager {
-    pub(crate) fn new(device: DeviceGuard) -> Result<Self> {
+    pub(crate) fn new(device: DeviceGuard, headless: bool) -> Result<Self> {
         let mut render_complete_semaphores =
             Vec::<Semaphore>::with_capacity(constants::MAX_FRAMES as usize);
         for _ in 0..constants::MAX_FRAMES as usize {
@@ -137,6 +157,7 @@ impl FrameSynchronizationManager {
             compute_work_semaphore,
             last_compute_semaphore_value: 0,
             has_async_work: false,
+            headless,
         })
     }
 
@@ -186,6 +207,7 @@ impl FrameSynchronizationManager {
         self.frame_index_data.current =
             (self.frame_index_data.current + 1) % (constants::MAX_FRAMES as u64);
         self.frame_index_data.absolute += 1;
+        self.has_async_work = false;
     }
 
     pub fn wait_graphics_compute_semaphores(&self) -> Result<()> {
@@ -225,11 +247,13 @@ impl FrameSynchronizationManager {
         let mut wait_semaphores = Vec::<SemaphoreSubmitInfo>::with_capacity(3);
 
         // Wait for image acquired semaphore.
-        wait_semaphores.push(SemaphoreSubmitInfo {
-            semaphore: &self.swapchain_image_acquired_semaphore,
-            stage_mask: vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT,
-            value: None,
-        });
+        if !self.headless {
+            wait_semaphores.push(SemaphoreSubmitInfo {
+                semaphore: &self.swapchain_image_acquired_semaphore,
+                stage_mask: vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT,
+                value: None,
+            });
+        }
 
         // Wait for graphics semaphore.
         // XXX: Do we need these? since we can wait directly in wait_graphics_compute_semaphores()?
@@ -243,37 +267,63 @@ impl FrameSynchronizationManager {
             wait_semaphores.push(graphics_wait_info);
         }
 
-        // Wait for compute semaphore.
-        // XXX: Do we need these? since we can wait directly in wait_graphics_compute_semaphores()?
-        // if self.has_async_work && self.last_compute_semaphore_value > 0 {
-        //     let compute_wait_info = SemaphoreSubmitInfo {
-        //         semaphore: &self.compute_work_semaphore,
-        //         stage_mask: vk::PipelineStageFlags2::VERTEX_ATTRIBUTE_INPUT,
-        //         value: Some(self.last_compute_semaphore_value),
-        //     };
+        // Wait for the async compute work of this frame, its outputs are consumed as draw arguments and vertices.
+        // XXX: Task and mesh shader reads are not waited on
+        if self.has_async_work && self.last_compute_semaphore_value > 0 {
+            let compute_wait_info = SemaphoreSubmitInfo {
+                semaphore: &self.compute_work_semaphore,
+                stage_mask: vk::PipelineStageFlags2::DRAW_INDIRECT
+                    | vk::PipelineStageFlags2::VERTEX_ATTRIBUTE_INPUT
+                    | vk::PipelineStageFlags2::VERTEX_SHADER,
+                value: Some(self.last_compute_semaphore_value),
+            };
 
-        //     wait_semaphores.push(compute_wait_info);
-        // }
+            wait_semaphores.push(compute_wait_info);
+        }
 
         // Signal present/render complete semaphore and new graphics timeline value.
-        let signal_semaphores = [
-            SemaphoreSubmitInfo {
+        let mut signal_semaphores = Vec::<SemaphoreSubmitInfo>::with_capacity(2);
+        if !self.headless {
+            signal_semaphores.push(SemaphoreSubmitInfo {
                 semaphore: self.current_render_complete_semaphore(),
                 stage_mask: vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT,
                 value: None,
-            },
-            SemaphoreSubmitInfo {
-                semaphore: &self.graphics_work_semaphore,
-                stage_mask: vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT,
-                value: Some(self.frame_index_data.absolute + 1),
-            },
-        ];
+            });
+        }
+        signal_semaphores.push(SemaphoreSubmitInfo {
+            semaphore: &self.graphics_work_semaphore,
+            stage_mask: vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT,
+            value: Some(self.frame_index_data.absolute + 1),
+        });
 
         queue.submit(command_buffers, &wait_semaphores, &signal_semaphores)?;
 
         Ok(())
     }
 
+    /// Submits async compute work of the current frame, needs to be called before the graphics work of the frame is
+    /// submitted. The previous use of the frame's compute command buffers has completed once the graphics work of
+    /// that frame was waited on.
+    pub fn submit_compute_command_buffers(
+        &mut self,
+        command_buffers: &[&CommandBuffer],
+        queue: &Queue,
+    ) -> Result<()> {
+        let signal_value = self.frame_index_data.absolute + 1;
+        let signal_semaphores = [SemaphoreSubmitInfo {
+            semaphore: &self.compute_work_semaphore,
+            stage_mask: vk::PipelineStageFlags2::ALL_COMMANDS,
+            value: Some(signal_value),
+        }];
+
+        queue.submit(command_buffers, &[], &signal_semaphores)?;
+
+        self.last_compute_semaphore_value = signal_value;
+        self.has_async_work = true;
+
+        Ok(())
+    }
+
     fn graphics_semaphore_wait_value(&self) -> u64 {
         self.frame_index_data.absolute - (constants::MAX_FRAMES as u64 - 1)
     }
@@ -1,9 +1,13 @@
-use std::sync::{
-    atomic::{AtomicU32, Ordering},
-    Arc,
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Weak,
+    },
+    time::Duration,
 };
 
-use anyhow::{Context, Result};
+use anyhow::Context;
 use crossbeam_channel::{Receiver, Sender};
 
 use raw_window_handle::{HasRawDisplayHandle, HasRawWindowHandle};
@@ -13,23 +17,33 @@ use crate::{
     barriers::*,
     buffer::*,
     command_buffer::*,
+    compute_pipeline::*,
     constants::{self, INVALID_BINDLESS_TEXTURE_INDEX},
+    crash_diagnostics::*,
     descriptor_set::*,
     device::Device,
+    error::*,
     escape::*,
+    event::Event,
     factory::*,
     frame::*,
     image::ImageDesc,
     image::*,
     instance::Instance,
     pipeline::*,
+    profiling::{self, GpuProfiler},
+    query::{GpuTimestamp, QueryPool, QueryPoolDesc, QueryResults, TimestampQueryPool},
     queue::{Queue, QueueType},
+    ray_tracing::*,
     sampler::*,
     shader_state::*,
     surface::Surface,
-    swapchain::{Swapchain, SwapchainDesc},
+    swapchain::{
+        Swapchain, SwapchainDesc, SwapchainStatus, DEFAULT_SURFACE_FORMAT, HDR_SURFACE_FORMATS,
+    },
     transfer::TransferManager,
-    types::ImageResourceUpdate,
+    types::{DeviceFeatures, ImageResourceUpdate, TransferFunction},
+    upload::UploadContext,
 };
 
 // XXX: There needs to be a "shared" object reference of this object passed around internally as well
@@ -47,30 +61,52 @@ pub struct Gpu {
 
     // XXX: Have an asynchronous transfer handler
     transfer_command_pool: CommandPool,
+    /// Batches immediate uploads and layout transitions
+    upload_context: UploadContext,
 
     // XXX: Use escape/terminals for this?
     global_descriptor_pool: Handle<DescriptorPool>,
 
     // XXX: Use channel for this?
     bindless_images_to_update: Vec<ImageResourceUpdate>,
+    /// Image last written to each bindless slot, checked for linked sampler changes
+    bindless_slot_images: HashMap<u32, Weak<Escape<Image>>>,
 
-    // XXX: Handle image destruction for bindless images
-    // bindless_image_returned_indices: Vec<u32>,
+    /// Indices of destroyed images are reused before new ones are allocated
     bindless_image_new_index: AtomicU32,
 
+    /// Storage images written to the bindless storage image array at the start of the next frame
+    bindless_storage_images_to_update: Vec<Handle<Image>>,
+    bindless_storage_image_new_index: AtomicU32,
+
     bindless_descriptor_set: Arc<DescriptorSet>,
     bindless_descriptor_set_layout: Handle<DescriptorSetLayout>,
     bindless_descriptor_pool: Handle<DescriptorPool>,
 
     default_sampler: Handle<Sampler>,
 
-    swapchain: Swapchain,
+    /// None when running headless
+    swapchain: Option<Swapchain>,
+    /// Render target used instead of the swapchain when running headless, None for compute only Gpus
+    offscreen_image: Option<Handle<Image>>,
+    /// Set when the swapchain needs to be recreated before the next image acquisition
+    swapchain_out_of_date: bool,
 
     queued_command_buffers: Vec<Arc<CommandBuffer>>,
+    /// Per frame command pools and buffers of the async compute queue, empty without a dedicated compute queue family
+    compute_command_pools: Vec<CommandPool>,
+    compute_command_buffers: Vec<Arc<CommandBuffer>>,
+    /// Empty if the compute queue family does not support timestamps
+    compute_timestamp_query_pools: Vec<Arc<TimestampQueryPool>>,
+    queued_compute_command_buffers: Vec<Arc<CommandBuffer>>,
+    /// Timestamp scopes of the most recent frame whose Gpu work completed
+    gpu_timestamps: Vec<GpuTimestamp>,
+    gpu_profiler: GpuProfiler,
 
     command_buffer_manager: CommandBufferManager,
     frame_thread_pools_manager: FrameThreadPoolsManager,
     frame_synchronization_manager: FrameSynchronizationManager,
+    crash_diagnostics: Arc<CrashDiagnostics>,
 
     graphics_queue: Queue,
     transfer_queue: Queue,
@@ -83,28 +119,91 @@ pub struct Gpu {
 }
 
 pub struct GpuDesc<'a> {
-    window_handle: &'a dyn HasRawWindowHandle,
-    display_handle: &'a dyn HasRawDisplayHandle,
+    window_handle: Option<&'a dyn HasRawWindowHandle>,
+    display_handle: Option<&'a dyn HasRawDisplayHandle>,
+    preferred_surface_formats: Vec<vk::SurfaceFormatKHR>,
+    /// Falls back to FIFO if not supported by the surface
+    present_mode: vk::PresentModeKHR,
+    /// Extent of the offscreen render target when running headless
+    headless_extent: vk::Extent2D,
+    /// No offscreen render target is created
+    compute_only: bool,
 }
 
+const HEADLESS_OUTPUT_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+
 impl<'a> GpuDesc<'a> {
     pub fn new(
         window_handle: &'a dyn HasRawWindowHandle,
         display_handle: &'a dyn HasRawDisplayHandle,
     ) -> Self {
         Self {
-            window_handle,
-            display_handle,
+            window_handle: Some(window_handle),
+            display_handle: Some(display_handle),
+            preferred_surface_formats: vec![DEFAULT_SURFACE_FORMAT],
+            present_mode: vk::PresentModeKHR::FIFO,
+            headless_extent: vk::Extent2D::default(),
+            compute_only: false,
+        }
+    }
+
+    /// Creates a Gpu without a surface/swapchain that renders to an offscreen image instead
+    pub fn new_headless(width: u32, height: u32) -> Self {
+        Self {
+            window_handle: None,
+            display_handle: None,
+            preferred_surface_formats: Vec::new(),
+            present_mode: vk::PresentModeKHR::FIFO,
+            headless_extent: vk::Extent2D { width, height },
+            compute_only: false,
+        }
+    }
+
+    fn new_compute_only() -> Self {
+        Self {
+            compute_only: true,
+            ..Self::new_headless(0, 0)
         }
     }
+
+    /// Surface formats in order of preference, falls back to the first format supported by the surface.
+    pub fn set_preferred_surface_formats(
+        mut self,
+        preferred_surface_formats: Vec<vk::SurfaceFormatKHR>,
+    ) -> Self {
+        self.preferred_surface_formats = preferred_surface_formats;
+        self
+    }
+
+    pub fn set_present_mode(mut self, present_mode: vk::PresentModeKHR) -> Self {
+        self.present_mode = present_mode;
+        self
+    }
+
+    /// Prefers HDR10/scRGB swapchain formats over the default SDR format when available.
+    pub fn set_hdr_output(mut self, hdr_output: bool) -> Self {
+        self.preferred_surface_formats = if hdr_output {
+            let mut formats = HDR_SURFACE_FORMATS.to_vec();
+            formats.push(DEFAULT_SURFACE_FORMAT);
+            formats
+        } else {
+            vec![DEFAULT_SURFACE_FORMAT]
+        };
+        self
+    }
 }
 
 impl Gpu {
     pub fn new(desc: GpuDesc) -> Result<Self> {
         // Core vulkan objects
-        let instance = Instance::new(&desc.display_handle)?;
-        let surface = Surface::new(&instance, desc.window_handle, desc.display_handle)?;
-        let device = Device::new(instance, surface)?;
+        let instance = Instance::new(desc.display_handle)?;
+        let surface = match (desc.window_handle, desc.display_handle) {
+            (Some(window_handle), Some(display_handle)) => {
+                Some(Surface::new(&instance, window_handle, display_handle)?)
+            }
+            _ => None,
+        };
+        let device = Device::new(instance, surface, desc.compute_only)?;
 
         // Resource guards/wrappers
         let device = DeviceGuard::new(device);
@@ -116,18 +215,47 @@ impl Gpu {
         let compute_queue = device.get_queue(QueueType::Compute, 0);
         let present_queue = device.get_queue(QueueType::Graphics, 0);
 
-        let swapchain = Swapchain::new(
-            device.instance(),
-            device.surface(),
-            device.physical_device(),
-            device.clone(),
-            SwapchainDesc::new(
-                u32::MAX, // Set dimensions based on information obtained from surface
-                u32::MAX,
-                device.queue_family(QueueType::Graphics).index(),
-                device.queue_family(QueueType::Graphics).index(),
-            ),
-        )?;
+        let (swapchain, offscreen_image) = match device.surface() {
+            Some(surface) => {
+                let swapchain = Swapchain::new(
+                    device.instance(),
+                    surface,
+                    device.physical_device(),
+                    device.clone(),
+                    SwapchainDesc::new(
+                        u32::MAX, // Set dimensions based on information obtained from surface
+                        u32::MAX,
+                        device.queue_family(QueueType::Graphics).index(),
+                        device.queue_family(QueueType::Graphics).index(),
+                    )
+                    .set_preferred_surface_formats(desc.preferred_surface_formats)
+                    .set_present_mode(desc.present_mode),
+                )?;
+
+                (Some(swapchain), None)
+            }
+            None if desc.compute_only => {
+                log::info!("Running compute only");
+                (None, None)
+            }
+            None => {
+                let offscreen_image = factory.create_image(
+                    ImageDesc::new(desc.headless_extent.width, desc.headless_extent.height, 1)
+                        .set_format(HEADLESS_OUTPUT_FORMAT)
+                        .set_usage_flags(vk::ImageUsageFlags::COLOR_ATTACHMENT),
+                )?;
+
+                log::info!(
+                    "Running headless with output extent: {:?}",
+                    desc.headless_extent
+                );
+
+                (
+                    None,
+                    Some(Handle::new(offscreen_image, resource_hub.clone())),
+                )
+            }
+        };
 
         let frame_thread_pools_manager = FrameThreadPoolsManager::new(
             device.clone(),
@@ -139,59 +267,71 @@ impl Gpu {
             },
         )?;
 
-        let command_buffer_manager =
-            CommandBufferManager::new(device.clone(), &frame_thread_pools_manager)?;
+        let crash_diagnostics = Arc::new(CrashDiagnostics::new(&device));
+        let command_buffer_manager = CommandBufferManager::new(
+            device.clone(),
+            &frame_thread_pools_manager,
+            crash_diagnostics.clone(),
+        )?;
+        let timestamp_period = device.physical_device().limits.timestamp_period;
 
-        let frame_synchronization_manager = FrameSynchronizationManager::new(device.clone())?;
+        let frame_synchronization_manager =
+            FrameSynchronizationManager::new(device.clone(), device.is_headless())?;
 
-        let global_descriptor_pool = factory.create_descriptor_pool(
-            DescriptorPoolDesc::new()
-                .set_max_sets(constants::GLOBAL_DESCRIPTOR_POOL_MAX_SETS)
-                .add_pool_size(
-                    vk::DescriptorType::SAMPLER,
-                    constants::GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE,
-                )
-                .add_pool_size(
-                    vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-                    constants::GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE,
-                )
-                .add_pool_size(
-                    vk::DescriptorType::SAMPLED_IMAGE,
-                    constants::GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE,
-                )
-                .add_pool_size(
-                    vk::DescriptorType::STORAGE_IMAGE,
-                    constants::GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE,
-                )
-                .add_pool_size(
-                    vk::DescriptorType::UNIFORM_BUFFER,
-                    constants::GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE,
-                )
-                .add_pool_size(
-                    vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC,
-                    constants::GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE,
-                )
-                .add_pool_size(
-                    vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
-                    constants::GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE,
-                )
-                .add_pool_size(
-                    vk::DescriptorType::STORAGE_BUFFER,
-                    constants::GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE,
-                )
-                .add_pool_size(
-                    vk::DescriptorType::STORAGE_BUFFER_DYNAMIC,
-                    constants::GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE,
-                )
-                .add_pool_size(
-                    vk::DescriptorType::STORAGE_TEXEL_BUFFER,
-                    constants::GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE,
-                )
-                .add_pool_size(
-                    vk::DescriptorType::INPUT_ATTACHMENT,
-                    constants::GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE,
-                ),
-        )?;
+        let mut global_descriptor_pool_desc = DescriptorPoolDesc::new()
+            .set_flags(vk::DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET)
+            .set_max_sets(constants::GLOBAL_DESCRIPTOR_POOL_MAX_SETS)
+            .add_pool_size(
+                vk::DescriptorType::SAMPLER,
+                constants::GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE,
+            )
+            .add_pool_size(
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                constants::GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE,
+            )
+            .add_pool_size(
+                vk::DescriptorType::SAMPLED_IMAGE,
+                constants::GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE,
+            )
+            .add_pool_size(
+                vk::DescriptorType::STORAGE_IMAGE,
+                constants::GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE,
+            )
+            .add_pool_size(
+                vk::DescriptorType::UNIFORM_BUFFER,
+                constants::GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE,
+            )
+            .add_pool_size(
+                vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC,
+                constants::GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE,
+            )
+            .add_pool_size(
+                vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
+                constants::GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE,
+            )
+            .add_pool_size(
+                vk::DescriptorType::STORAGE_BUFFER,
+                constants::GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE,
+            )
+            .add_pool_size(
+                vk::DescriptorType::STORAGE_BUFFER_DYNAMIC,
+                constants::GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE,
+            )
+            .add_pool_size(
+                vk::DescriptorType::STORAGE_TEXEL_BUFFER,
+                constants::GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE,
+            )
+            .add_pool_size(
+                vk::DescriptorType::INPUT_ATTACHMENT,
+                constants::GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE,
+            );
+        if device.ray_tracing_supported() {
+            global_descriptor_pool_desc = global_descriptor_pool_desc.add_pool_size(
+                vk::DescriptorType::ACCELERATION_STRUCTURE_KHR,
+                constants::GLOBAL_DESCRIPTOR_POOL_ELEMENT_SIZE,
+            );
+        }
+        let global_descriptor_pool = factory.create_descriptor_pool(global_descriptor_pool_desc)?;
         let global_descriptor_pool = Handle::new(global_descriptor_pool, resource_hub.clone());
 
         let bindless_descriptor_pool = factory.create_descriptor_pool(
@@ -202,7 +342,7 @@ impl Gpu {
                 .set_max_sets(constants::MAX_NUM_BINDLESS_RESOURCECS * 2)
                 .add_pool_size(
                     vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-                    constants::MAX_NUM_BINDLESS_RESOURCECS,
+                    constants::MAX_NUM_BINDLESS_RESOURCECS * 2,
                 )
                 .add_pool_size(
                     vk::DescriptorType::STORAGE_IMAGE,
@@ -211,21 +351,7 @@ impl Gpu {
         )?;
         let bindless_descriptor_pool = Handle::new(bindless_descriptor_pool, resource_hub.clone());
 
-        let bindless_descriptor_set_layout_desc = DescriptorSetLayoutDesc::new()
-            .set_flags(vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL)
-            .set_bindless(true)
-            .add_binding(DescriptorBinding::new(
-                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-                constants::BINDLESS_SET_SAMPLED_IMAGE_INDEX,
-                constants::MAX_NUM_BINDLESS_RESOURCECS,
-                vk::ShaderStageFlags::FRAGMENT,
-            ))
-            .add_binding(DescriptorBinding::new(
-                vk::DescriptorType::STORAGE_IMAGE,
-                constants::BINDLESS_SET_STORAGE_IMAGE_INDEX,
-                constants::MAX_NUM_BINDLESS_RESOURCECS,
-                vk::ShaderStageFlags::FRAGMENT,
-            ));
+        let bindless_descriptor_set_layout_desc = DescriptorSetLayoutDesc::new_bindless();
 
         let bindless_descriptor_set_layout = Handle::new(
             factory.create_descriptor_set_layout(bindless_descriptor_set_layout_desc)?,
@@ -247,9 +373,42 @@ impl Gpu {
         // XXX: Actually use transfer command queue for this, currently use graphics since need different queues for resource state transitions
         let transfer_command_pool =
             CommandPool::new(device.clone(), graphics_queue.family_index())?;
+        let upload_context = UploadContext::new(device.clone(), graphics_queue.family_index())?;
 
         let (shader_read_image_sender, shader_read_image_receiver) = crossbeam_channel::unbounded();
 
+        let mut compute_command_pools = Vec::new();
+        let mut compute_command_buffers = Vec::new();
+        let mut compute_timestamp_query_pools = Vec::new();
+        if compute_queue.family_index() != graphics_queue.family_index() {
+            let supports_timestamps = device
+                .queue_family(QueueType::Compute)
+                .supports_timestamps();
+
+            for frame_index in 0..constants::MAX_FRAMES {
+                let command_pool = CommandPool::new(device.clone(), compute_queue.family_index())?;
+                let mut command_buffer = CommandBuffer::new(
+                    device.clone(),
+                    command_pool.allocate_command_buffer(vk::CommandBufferLevel::PRIMARY)?,
+                    CommandBufferMetaData {
+                        array_index: frame_index,
+                        frame_index,
+                        thread_index: 0,
+                    },
+                    false,
+                );
+                if supports_timestamps {
+                    let timestamp_query_pool =
+                        Arc::new(TimestampQueryPool::new(device.clone(), 8)?);
+                    command_buffer.set_timestamp_query_pool(timestamp_query_pool.clone());
+                    compute_timestamp_query_pools.push(timestamp_query_pool);
+                }
+
+                compute_command_pools.push(command_pool);
+                compute_command_buffers.push(Arc::new(command_buffer));
+            }
+        }
+
         // let transfer_manager = TransferManager::new(
         //     device.clone(),
         //     transfer_queue,
@@ -268,11 +427,20 @@ impl Gpu {
             transfer_queue,
 
             swapchain,
+            offscreen_image,
+            swapchain_out_of_date: false,
 
             queued_command_buffers: Vec::new(),
+            compute_command_pools,
+            compute_command_buffers,
+            compute_timestamp_query_pools,
+            queued_compute_command_buffers: Vec::new(),
+            gpu_timestamps: Vec::new(),
+            gpu_profiler: GpuProfiler::new(timestamp_period),
             command_buffer_manager,
             frame_thread_pools_manager,
             frame_synchronization_manager,
+            crash_diagnostics,
 
             global_descriptor_pool,
 
@@ -281,12 +449,16 @@ impl Gpu {
             bindless_descriptor_set,
 
             bindless_images_to_update: Vec::new(),
+            bindless_slot_images: HashMap::new(),
 
             transfer_command_pool,
+            upload_context,
 
             default_sampler,
 
             bindless_image_new_index: AtomicU32::new(0),
+            bindless_storage_images_to_update: Vec::new(),
+            bindless_storage_image_new_index: AtomicU32::new(0),
 
             shader_read_image_sender,
             shader_read_image_receiver,
@@ -296,6 +468,13 @@ impl Gpu {
         })
     }
 
+    /// Creates a Gpu without a surface, swapchain or output image for offline processing and tests on machines
+    /// without a display. Frames are recorded with `new_frame`, submitted with `submit_graphics_command_buffer` and
+    /// finished with `end_frame` instead of `present`.
+    pub fn new_compute_only() -> Result<Self> {
+        Self::new(GpuDesc::new_compute_only())
+    }
+
     pub fn create_buffer(&self, desc: BufferDesc) -> Result<Handle<Buffer>> {
         let buffer = self.factory.create_buffer(desc)?;
         Ok(Handle::new(buffer, self.resource_hub.clone()))
@@ -303,14 +482,91 @@ impl Gpu {
 
     pub fn create_image(&mut self, desc: ImageDesc) -> Result<Handle<Image>> {
         let mut image = self.factory.create_image(desc)?;
-        image.set_bindless_index(
-            self.bindless_image_new_index
-                .fetch_add(1, Ordering::Relaxed),
-        );
+        let bindless_index = self.allocate_bindless_image_index();
+        image.set_bindless_index(bindless_index);
+        let srgb_bindless_index = if image.has_srgb_view() {
+            let srgb_bindless_index = self.allocate_bindless_image_index();
+            image.set_srgb_bindless_index(srgb_bindless_index);
+            srgb_bindless_index
+        } else {
+            bindless_index
+        };
 
         // XXX: Add image bindless image descriptor update here
 
-        Ok(Handle::new(image, self.resource_hub.clone()))
+        // Returns the indices to the factory if they are out of range
+        let image = Handle::new(image, self.resource_hub.clone());
+        if bindless_index.max(srgb_bindless_index) >= constants::MAX_NUM_BINDLESS_RESOURCECS {
+            return Err(GpuError::BindlessExhausted);
+        }
+
+        Ok(image)
+    }
+
+    fn allocate_bindless_image_index(&self) -> u32 {
+        self.factory
+            .pop_returned_bindless_image_index()
+            .unwrap_or_else(|| {
+                self.bindless_image_new_index
+                    .fetch_add(1, Ordering::Relaxed)
+            })
+    }
+
+    /// Creates an image that compute shaders write through the bindless storage image array. The image is
+    /// transitioned to the GENERAL layout and stays in it, its bindless storage slot is written at the start of the
+    /// next frame.
+    pub fn create_storage_image(&mut self, desc: ImageDesc) -> Result<Handle<Image>> {
+        let usage_flags = desc.usage_flags | vk::ImageUsageFlags::STORAGE;
+        let mut image = self
+            .factory
+            .create_image(desc.set_usage_flags(usage_flags))?;
+
+        // XXX: Storage images stay in the GENERAL layout, they do not get a sampled bindless slot
+        let storage_bindless_index = self
+            .factory
+            .pop_returned_storage_bindless_image_index()
+            .unwrap_or_else(|| {
+                self.bindless_storage_image_new_index
+                    .fetch_add(1, Ordering::Relaxed)
+            });
+        image.set_storage_bindless_index(storage_bindless_index);
+
+        // Returns the index to the factory if anything below fails
+        let image = Handle::new(image, self.resource_hub.clone());
+
+        if storage_bindless_index >= constants::MAX_NUM_BINDLESS_RESOURCECS {
+            return Err(GpuError::BindlessExhausted);
+        }
+
+        self.transition_image_layout(
+            &image,
+            ResourceState::UNDEFINED,
+            ResourceState::SHADER_ACCESS,
+        )?;
+        self.bindless_storage_images_to_update.push(image.clone());
+
+        Ok(image)
+    }
+
+    /// Creates an image that replaces `image` in its bindless slots, eg. a streamed texture with more mips.
+    /// The slots stay owned by `image`.
+    pub fn create_image_with_bindless_index(
+        &mut self,
+        desc: ImageDesc,
+        image: &Image,
+    ) -> Result<Handle<Image>> {
+        let mut replacement = self.factory.create_image(desc)?;
+        replacement.set_shared_bindless_index(image.bindless_index());
+        if replacement.has_srgb_view() {
+            replacement.set_srgb_bindless_index(image.srgb_bindless_index());
+        }
+
+        Ok(Handle::new(replacement, self.resource_hub.clone()))
+    }
+
+    pub fn create_image_view(&self, desc: ImageViewDesc) -> Result<Handle<ImageView>> {
+        let image_view = self.factory.create_image_view(desc)?;
+        Ok(Handle::new(image_view, self.resource_hub.clone()))
     }
 
     pub fn create_sampler(&self, desc: SamplerDesc) -> Result<Handle<Sampler>> {
@@ -320,7 +576,7 @@ impl Gpu {
 
     // XXX: Should we expose this?
     pub fn create_shader_state(&self, desc: ShaderStateDesc) -> Result<ShaderState> {
-        ShaderState::new(self.device.clone(), desc)
+        Ok(ShaderState::new(self.device.clone(), desc)?)
     }
 
     pub fn create_graphics_pipeline(
@@ -331,6 +587,219 @@ impl Gpu {
         Ok(Handle::new(pipeline, self.resource_hub.clone()))
     }
 
+    pub fn create_compute_pipeline(
+        &self,
+        desc: ComputePipelineDesc,
+    ) -> Result<Handle<ComputePipeline>> {
+        let pipeline = self.factory.create_compute_pipeline(desc)?;
+        Ok(Handle::new(pipeline, self.resource_hub.clone()))
+    }
+
+    pub fn create_event(&self) -> Result<Handle<Event>> {
+        let event = self.factory.create_event()?;
+        Ok(Handle::new(event, self.resource_hub.clone()))
+    }
+
+    pub fn create_query_pool(&self, desc: QueryPoolDesc) -> Result<QueryPool> {
+        Ok(QueryPool::new(self.device.clone(), desc)?)
+    }
+
+    /// Selects the queries of the current frame in flight and returns the results last written to them, None if no
+    /// query was written. The results do not stall, that frame was waited for when the current frame began.
+    /// Needs to be called once per frame before the queries of the pool are recorded.
+    pub fn begin_query_pool_frame(&self, query_pool: &QueryPool) -> Result<Option<QueryResults>> {
+        Ok(query_pool.begin_frame(
+            self.current_frame_index() as usize,
+            self.completed_value()?,
+            self.signal_value_after_submit(),
+        )?)
+    }
+
+    pub fn default_sampler(&self) -> &Handle<Sampler> {
+        &self.default_sampler
+    }
+
+    pub fn ray_tracing_supported(&self) -> bool {
+        self.device.ray_tracing_supported()
+    }
+
+    /// Only formats listed in `physical_device::COMPRESSED_TEXTURE_FORMATS` are queried
+    pub fn supports_compressed_format(&self, format: vk::Format) -> bool {
+        self.device
+            .physical_device()
+            .supports_compressed_format(format)
+    }
+
+    pub fn device_features(&self) -> DeviceFeatures {
+        *self.device.features()
+    }
+
+    pub fn create_ray_tracing_pipeline(
+        &self,
+        desc: RayTracingPipelineDesc,
+    ) -> Result<Handle<RayTracingPipeline>> {
+        if !self.ray_tracing_supported() {
+            return Err(GpuError::Unsupported("Ray tracing".to_string()));
+        }
+        let pipeline = self.factory.create_ray_tracing_pipeline(desc)?;
+        Ok(Handle::new(pipeline, self.resource_hub.clone()))
+    }
+
+    /// Builds a bottom level acceleration structure, blocking until the build has completed
+    pub fn create_bottom_level_acceleration_structure(
+        &mut self,
+        desc: BottomLevelAccelerationStructureDesc,
+    ) -> Result<Handle<AccelerationStructure>> {
+        let geometries = desc.vulkan_geometries();
+        let build_ranges = desc.vulkan_build_ranges();
+
+        self.build_acceleration_structure(
+            vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL,
+            desc.flags,
+            &geometries,
+            &build_ranges,
+            vec![],
+        )
+    }
+
+    /// Builds a top level acceleration structure, blocking until the build has completed
+    pub fn create_top_level_acceleration_structure(
+        &mut self,
+        desc: TopLevelAccelerationStructureDesc,
+    ) -> Result<Handle<AccelerationStructure>> {
+        let instances = desc.vulkan_instances();
+
+        let instance_buffer = self.create_buffer(
+            BufferDesc::new()
+                .set_usage_flags(
+                    vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR
+                        | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+                )
+                .set_size(
+                    (std::mem::size_of::<vk::AccelerationStructureInstanceKHR>()
+                        * instances.len().max(1)) as u32,
+                )
+                .set_device_only(false),
+        )?;
+        instance_buffer.copy_data_to_buffer(&instances)?;
+
+        let instances_data = vk::AccelerationStructureGeometryInstancesDataKHR::builder()
+            .array_of_pointers(false)
+            .data(vk::DeviceOrHostAddressConstKHR {
+                device_address: instance_buffer.get_device_address(),
+            })
+            .build();
+        let geometry = vk::AccelerationStructureGeometryKHR::builder()
+            .geometry_type(vk::GeometryTypeKHR::INSTANCES)
+            .geometry(vk::AccelerationStructureGeometryDataKHR {
+                instances: instances_data,
+            })
+            .build();
+        let build_range = vk::AccelerationStructureBuildRangeInfoKHR::builder()
+            .primitive_count(instances.len() as u32)
+            .build();
+
+        self.build_acceleration_structure(
+            vk::AccelerationStructureTypeKHR::TOP_LEVEL,
+            desc.flags,
+            std::slice::from_ref(&geometry),
+            std::slice::from_ref(&build_range),
+            desc.bottom_levels(),
+        )
+    }
+
+    fn build_acceleration_structure(
+        &mut self,
+        level: vk::AccelerationStructureTypeKHR,
+        flags: vk::BuildAccelerationStructureFlagsKHR,
+        geometries: &[vk::AccelerationStructureGeometryKHR],
+        build_ranges: &[vk::AccelerationStructureBuildRangeInfoKHR],
+        bottom_levels: Vec<Handle<AccelerationStructure>>,
+    ) -> Result<Handle<AccelerationStructure>> {
+        if !self.ray_tracing_supported() {
+            return Err(GpuError::Unsupported("Ray tracing".to_string()));
+        }
+
+        let context = RayTracingContext::new(self.device.clone());
+
+        let mut build_info = vk::AccelerationStructureBuildGeometryInfoKHR::builder()
+            .ty(level)
+            .flags(flags)
+            .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
+            .geometries(geometries)
+            .build();
+
+        let primitive_counts = build_ranges
+            .iter()
+            .map(|range| range.primitive_count)
+            .collect::<Vec<_>>();
+        let build_sizes = unsafe {
+            context
+                .acceleration_structure
+                .get_acceleration_structure_build_sizes(
+                    vk::AccelerationStructureBuildTypeKHR::DEVICE,
+                    &build_info,
+                    &primitive_counts,
+                )
+        };
+
+        let buffer = self.create_buffer(
+            BufferDesc::new()
+                .set_usage_flags(
+                    vk::BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR
+                        | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+                )
+                .set_size(build_sizes.acceleration_structure_size as u32)
+                .set_device_only(true),
+        )?;
+        // XXX: Scratch address needs to be aligned to `min_acceleration_structure_scratch_offset_alignment`
+        let scratch_buffer = self.create_buffer(
+            BufferDesc::new()
+                .set_usage_flags(
+                    vk::BufferUsageFlags::STORAGE_BUFFER
+                        | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+                )
+                .set_size(build_sizes.build_scratch_size as u32)
+                .set_device_only(true),
+        )?;
+
+        let acceleration_structure = Handle::new(
+            self.factory
+                .create_acceleration_structure(level, buffer, bottom_levels)?,
+            self.resource_hub.clone(),
+        );
+
+        build_info.dst_acceleration_structure = acceleration_structure.raw();
+        build_info.scratch_data = vk::DeviceOrHostAddressKHR {
+            device_address: scratch_buffer.get_device_address(),
+        };
+
+        let command_buffer = self
+            .transfer_command_pool
+            .allocate_command_buffer(vk::CommandBufferLevel::PRIMARY)?;
+        let command_buffer = CommandBuffer::new(
+            self.device.clone(),
+            command_buffer,
+            CommandBufferMetaData {
+                array_index: 0,
+                frame_index: 0,
+                thread_index: 0,
+            },
+            false,
+        );
+
+        command_buffer.begin()?;
+        command_buffer.build_acceleration_structure(&build_info, build_ranges);
+        command_buffer.end()?;
+        // Geometry buffers may still be in the upload batch
+        self.flush_uploads()?;
+        self.graphics_queue.submit(&[&command_buffer], &[], &[])?;
+
+        self.wait_idle();
+
+        Ok(acceleration_structure)
+    }
+
     pub fn create_descriptor_set_layout(
         &self,
         desc: DescriptorSetLayoutDesc,
@@ -342,26 +811,48 @@ impl Gpu {
     pub fn create_descriptor_set(&self, desc: DescriptorSetDesc) -> Result<DescriptorSet> {
         // XXX: Always use internal global descriptor pool for now
         let desc = desc.set_pool(self.global_descriptor_pool.clone());
-        DescriptorSet::new(self.device.clone(), desc)
+        Ok(DescriptorSet::new(self.device.clone(), desc)?)
     }
 
     pub fn new_frame(&mut self) -> Result<()> {
-        self.frame_synchronization_manager
-            .wait_graphics_compute_semaphores()?;
+        let _span = profiling::span("Gpu::new_frame");
 
-        self.command_buffer_manager.reset_pools(
-            &self.frame_thread_pools_manager,
-            self.frame_synchronization_manager.current_frame_index() as u32,
-        )?;
+        let wait_result = self
+            .frame_synchronization_manager
+            .wait_graphics_compute_semaphores();
+        self.check_device_lost(wait_result)?;
+
+        let frame_index = self.frame_synchronization_manager.current_frame_index() as u32;
+        self.crash_diagnostics
+            .begin_frame(frame_index, self.absolute_frame_index());
+
+        self.command_buffer_manager
+            .reset_pools(&self.frame_thread_pools_manager, frame_index)?;
+        if let Some(command_pool) = self.compute_command_pools.get(frame_index as usize) {
+            command_pool.reset();
+        }
 
         // XXX: Update descriptor sets.
 
-        // XXX: Reset queries.
+        // Work of the previous use of this frame's pools has completed
+        let timestamp_period = self.device.physical_device().limits.timestamp_period;
+        let mut gpu_timestamps = self
+            .frame_thread_pools_manager
+            .resolve_timestamps(frame_index, timestamp_period)?;
+        // Ticks of the async compute scopes are not comparable to those of the graphics queue, only their durations
+        if let Some(query_pool) = self.compute_timestamp_query_pools.get(frame_index as usize) {
+            gpu_timestamps.extend(query_pool.resolve(timestamp_period)?);
+        }
+        if !gpu_timestamps.is_empty() {
+            self.gpu_profiler.submit(&gpu_timestamps);
+            self.gpu_timestamps = gpu_timestamps;
+        }
 
         Ok(())
     }
 
     pub fn submit_graphics_command_buffer(&self, command_buffer: &CommandBuffer) -> Result<()> {
+        self.flush_uploads()?;
         self.frame_synchronization_manager
             .submit_graphics_command_buffers(&[command_buffer], &self.graphics_queue)?;
 
@@ -372,91 +863,402 @@ impl Gpu {
         self.queued_command_buffers.push(command_buffer);
     }
 
+    /// Needs to be called after recording the async compute work of the frame, the queued graphics work waits on it
+    pub fn queue_compute_command_buffer(&mut self, command_buffer: Arc<CommandBuffer>) {
+        self.queued_compute_command_buffers.push(command_buffer);
+    }
+
+    /// Submits the queued async compute work before the queued graphics work of the frame
     pub fn submit_queued_graphics_command_buffers(&mut self) -> Result<()> {
+        let _span = profiling::span("Gpu::submit");
+
+        self.flush_uploads()?;
+
+        if !self.queued_compute_command_buffers.is_empty() {
+            let command_buffers = self
+                .queued_compute_command_buffers
+                .iter()
+                .map(|command_buffer| command_buffer.as_ref())
+                .collect::<Vec<_>>();
+            let submit_result = self
+                .frame_synchronization_manager
+                .submit_compute_command_buffers(&command_buffers, &self.compute_queue);
+            self.check_device_lost(submit_result)?;
+            self.queued_compute_command_buffers.clear();
+        }
+
         let command_buffers = self
             .queued_command_buffers
             .iter()
             .map(|command_buffer| command_buffer.as_ref())
             .collect::<Vec<_>>();
-        self.frame_synchronization_manager
-            .submit_graphics_command_buffers(&command_buffers, &self.graphics_queue)?;
+        let submit_result = self
+            .frame_synchronization_manager
+            .submit_graphics_command_buffers(&command_buffers, &self.graphics_queue);
+        self.check_device_lost(submit_result)?;
         self.queued_command_buffers.clear();
         Ok(())
     }
 
     // XXX: Do not expose this? queue command buffer and call this during present before submitting queued command buffers.
+    /// Acquires the next swapchain image, recreating the swapchain if it is out of date.
+    /// Returns false if no image could be acquired (eg. the window is minimized) and the frame should be skipped.
     pub fn swapchain_acquire_next_image(&mut self) -> Result<bool> {
-        // XXX: Handle this in FrameSynchronizationManager?
-        let acquire_result = self.swapchain.acquire_next_image(
-            self.frame_synchronization_manager
-                .swapchain_image_acquired_semaphore(),
-        )?;
+        if self.is_headless() {
+            return Ok(true);
+        }
+
+        if self.swapchain_out_of_date && !self.try_recreate_swapchain()? {
+            return Ok(false);
+        }
 
-        Ok(acquire_result)
+        // Retry once with the recreated swapchain
+        for _ in 0..2 {
+            // XXX: Handle this in FrameSynchronizationManager?
+            let status = self
+                .swapchain
+                .as_mut()
+                .context("Gpu has no swapchain")?
+                .acquire_next_image(
+                    self.frame_synchronization_manager
+                        .swapchain_image_acquired_semaphore(),
+                )?;
+
+            match status {
+                SwapchainStatus::Optimal => return Ok(true),
+                SwapchainStatus::Suboptimal => {
+                    // The image is still acquired and has to be presented, recreate after presentation
+                    self.swapchain_out_of_date = true;
+                    return Ok(true);
+                }
+                SwapchainStatus::OutOfDate => {
+                    if !self.try_recreate_swapchain()? {
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+
+        Ok(false)
     }
 
     pub fn recreate_swapchain(&mut self) -> Result<()> {
-        self.swapchain = self
+        if self.is_headless() {
+            return Ok(());
+        }
+
+        self.wait_device_idle()?;
+
+        let swapchain = self
             .swapchain
+            .as_mut()
+            .context("Gpu has no swapchain")?
             .recreate(
                 self.device.instance(),
-                self.device.surface(),
+                self.device.surface().context("Gpu has no surface")?,
                 self.device.physical_device(),
                 self.device.clone(),
             )
             .with_context(|| format!("recreate_swapchain: Failed to create new swapchain!"))?;
+        self.swapchain = Some(swapchain);
+        self.swapchain_out_of_date = false;
 
         log::info!(
             "Swapchain recreated with extent: {:?}",
-            self.swapchain().extent()
+            self.swapchain_extent()
         );
 
         Ok(())
     }
 
-    pub fn set_present_mode(&mut self, present_mode: vk::PresentModeKHR) -> Result<()> {
-        self.swapchain = self.swapchain.recreate_present_mode(
-            self.device.instance(),
-            self.device.surface(),
+    /// Recreates the swapchain if the window surface has a non-zero extent.
+    /// Returns false if recreation was deferred.
+    fn try_recreate_swapchain(&mut self) -> Result<bool> {
+        let surface_extent = Swapchain::surface_extent(
+            self.device.surface().context("Gpu has no surface")?,
             self.device.physical_device(),
-            self.device.clone(),
-            present_mode,
         )?;
+
+        if surface_extent.width == 0 || surface_extent.height == 0 {
+            self.swapchain_out_of_date = true;
+            return Ok(false);
+        }
+
+        self.recreate_swapchain()?;
+
+        Ok(true)
+    }
+
+    /// Recreates the swapchain before the next image acquisition, eg. after the window changed fullscreen state
+    /// which is not always reported as out of date by the surface.
+    pub fn invalidate_swapchain(&mut self) {
+        if !self.is_headless() {
+            self.swapchain_out_of_date = true;
+        }
+    }
+
+    pub fn set_present_mode(&mut self, present_mode: vk::PresentModeKHR) -> Result<()> {
+        self.wait_device_idle()?;
+
+        let swapchain = self
+            .swapchain
+            .as_mut()
+            .context("Present mode cannot be set when running headless")?
+            .recreate_present_mode(
+                self.device.instance(),
+                self.device.surface().context("Gpu has no surface")?,
+                self.device.physical_device(),
+                self.device.clone(),
+                present_mode,
+            )?;
+        self.swapchain = Some(swapchain);
         Ok(())
     }
 
+    /// Extent of the swapchain, or of the offscreen output image when running headless. Zero for compute only Gpus.
     pub fn swapchain_extent(&self) -> vk::Extent2D {
-        self.swapchain.extent()
+        match &self.swapchain {
+            Some(swapchain) => swapchain.extent(),
+            None => match &self.offscreen_image {
+                Some(offscreen_image) => {
+                    let extent = offscreen_image.extent();
+                    vk::Extent2D {
+                        width: extent.width,
+                        height: extent.height,
+                    }
+                }
+                None => vk::Extent2D::default(),
+            },
+        }
+    }
+
+    pub fn present_mode(&self) -> vk::PresentModeKHR {
+        match &self.swapchain {
+            Some(swapchain) => swapchain.present_mode(),
+            // Nothing throttles headless rendering
+            None => vk::PresentModeKHR::IMMEDIATE,
+        }
+    }
+
+    pub fn supported_present_modes(&self) -> Result<Vec<vk::PresentModeKHR>> {
+        match self.device.surface() {
+            Some(surface) => Ok(Swapchain::supported_present_modes(
+                surface,
+                self.device.physical_device(),
+            )?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn swapchain_format(&self) -> vk::Format {
+        match &self.swapchain {
+            Some(swapchain) => swapchain.format(),
+            None => HEADLESS_OUTPUT_FORMAT,
+        }
+    }
+
+    /// Transfer function the final image needs to be encoded with for the current swapchain color space.
+    pub fn swapchain_transfer_function(&self) -> TransferFunction {
+        match &self.swapchain {
+            Some(swapchain) => swapchain.transfer_function(),
+            None => TransferFunction::Srgb,
+        }
+    }
+
+    pub fn is_headless(&self) -> bool {
+        self.swapchain.is_none()
+    }
+
+    /// Compute only Gpus are headless without an output image
+    pub fn is_compute_only(&self) -> bool {
+        self.is_headless() && self.offscreen_image.is_none()
+    }
+
+    /// Image the final frame is rendered to, either the current swapchain image or the offscreen image.
+    pub fn output_image(&self) -> &Image {
+        match &self.swapchain {
+            Some(swapchain) => swapchain.current_image(),
+            None => self
+                .offscreen_image
+                .as_ref()
+                .expect("Compute only Gpu has no output image"),
+        }
+    }
+
+    /// State the output image needs to be in at the end of the frame.
+    pub fn output_image_final_state(&self) -> ResourceState {
+        if self.is_headless() {
+            ResourceState::COPY_SOURCE
+        } else {
+            ResourceState::PRESENT
+        }
+    }
+
+    /// Reads back the headless output image to the CPU as tightly packed RGBA8 texels.
+    /// Needs to be called after the frame is submitted.
+    pub fn read_output_image(&mut self) -> Result<Vec<u8>> {
+        let offscreen_image = self
+            .offscreen_image
+            .clone()
+            .context("Output image can only be read back when running headless")?;
+
+        self.read_image(&offscreen_image, ResourceState::COPY_SOURCE)
     }
 
+    /// Reads back the first mip of an image to the CPU as tightly packed texels.
+    /// `state` is the state the image was left in by previously submitted work, the image is returned to this state.
+    pub fn read_image(&mut self, image: &Image, state: ResourceState) -> Result<Vec<u8>> {
+        let texel_size = format_texel_size(image.format())
+            .with_context(|| format!("Readback of format {:?} is not supported", image.format()))?;
+
+        let extent = image.extent();
+        let size = extent.width * extent.height * extent.depth * texel_size;
+
+        let readback_buffer = self.create_buffer(
+            BufferDesc::new()
+                .set_size(size)
+                .set_device_only(false)
+                .set_readback(true),
+        )?;
+
+        self.upload_context
+            .record(&[&readback_buffer], &[], |command_buffer| {
+                if state != ResourceState::COPY_SOURCE {
+                    command_buffer.pipeline_barrier(Barriers::new().add_image(
+                        image,
+                        state,
+                        ResourceState::COPY_SOURCE,
+                    ));
+                }
+                command_buffer.copy_image_to_buffer(image, &readback_buffer, 0);
+                if state != ResourceState::COPY_SOURCE {
+                    command_buffer.pipeline_barrier(Barriers::new().add_image(
+                        image,
+                        ResourceState::COPY_SOURCE,
+                        state,
+                    ));
+                }
+                Ok(())
+            })?;
+
+        // Frame work is submitted on the same queue, so the copy is ordered after the frame
+        let value = self.flush_uploads()?;
+        if !self.wait_for_uploads(value, Duration::new(10, 0))? {
+            return Err(GpuError::other(format!(
+                "Timed out waiting for the readback of a {}x{} image",
+                extent.width, extent.height
+            )));
+        }
+
+        Ok(readback_buffer.read_data_from_buffer::<u8>(size as usize)?)
+    }
+
+    /// Returns false if the swapchain was out of date or suboptimal and had to be recreated.
     pub fn present(&mut self) -> Result<bool> {
-        let wait_semaphores = [self
-            .frame_synchronization_manager
-            .current_render_complete_semaphore()];
+        let _span = profiling::span("Gpu::present");
 
-        let present_result = self
-            .swapchain
-            .queue_present(&wait_semaphores, &self.graphics_queue)
-            .with_context(|| (format!("Failed swapchain presentation!")))?;
+        if let Some(swapchain) = &self.swapchain {
+            let wait_semaphores = [self
+                .frame_synchronization_manager
+                .current_render_complete_semaphore()];
 
-        // XXX: Properly handle failed presentation case.
-        // assert!(present_result);
+            let present_result = swapchain
+                .queue_present(&wait_semaphores, &self.graphics_queue)
+                .with_context(|| (format!("Failed swapchain presentation!")));
+            let present_status = self.check_device_lost(present_result)?;
 
+            if present_status != SwapchainStatus::Optimal {
+                self.swapchain_out_of_date = true;
+            }
+        }
+
+        self.end_frame()?;
+
+        if self.swapchain_out_of_date {
+            self.try_recreate_swapchain()?;
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// Advances to the next frame and destroys dropped resources no longer used by completed frames.
+    /// Called by `present`, compute only Gpus call this directly at the end of each frame.
+    pub fn end_frame(&mut self) -> Result<()> {
         self.frame_synchronization_manager.advance_frame_counters();
+        profiling::frame_mark();
 
         self.update_bindless_images();
 
-        // XXX: Technically it MAY not be safe to destroy resource here. Need a proper resource tracker management system(don't wanna write GL though ugh!);
-        //      A very common example is that images used on the transfer queue may be destroyed already
-        self.factory.cleanup_resources();
+        // Dropped resources are destroyed once the frames that may use them completed, the graphics timeline is
+        // signaled with the absolute frame index + 1 when a frame's work finishes.
+        // XXX: Work on the transfer queue is not tracked by the graphics timeline
+        let submitted_frame = self.frame_synchronization_manager.absolute_frame_index() - 1;
+        let completed_frames = self.check_device_lost(
+            self.frame_synchronization_manager
+                .graphics_work_semaphore()
+                .counter_value(),
+        )?;
+        self.upload_context.retire_completed()?;
+        self.global_descriptor_pool.free_released_descriptor_sets();
+        self.factory
+            .cleanup_resources(submitted_frame, completed_frames);
 
-        Ok(present_result)
+        Ok(())
     }
 
     pub fn current_frame_index(&self) -> u64 {
         self.frame_synchronization_manager.current_frame_index()
     }
 
+    pub fn absolute_frame_index(&self) -> u64 {
+        self.frame_synchronization_manager.absolute_frame_index()
+    }
+
+    /// Graphics timeline value signaled once the work of the current frame completes, work recorded this frame is
+    /// finished when `completed_value` reaches it
+    pub fn signal_value_after_submit(&self) -> u64 {
+        self.frame_synchronization_manager.absolute_frame_index() + 1
+    }
+
+    /// Adds a report of the Gpu work that was submitted and executing to device lost errors
+    fn check_device_lost<T, E: Into<GpuError>>(&self, result: Result<T, E>) -> Result<T> {
+        result.map_err(|error| match error.into() {
+            GpuError::DeviceLost(_) => {
+                let device_lost_error = self
+                    .crash_diagnostics
+                    .report(&self.graphics_queue, self.absolute_frame_index());
+                log::error!("{}", device_lost_error);
+                GpuError::DeviceLost(Some(device_lost_error))
+            }
+            error => error,
+        })
+    }
+
+    /// Last graphics timeline value completed by the Gpu
+    pub fn completed_value(&self) -> Result<u64> {
+        Ok(self
+            .frame_synchronization_manager
+            .graphics_work_semaphore()
+            .counter_value()?)
+    }
+
+    /// Waits for the graphics timeline to reach `value`, returns false on timeout.
+    /// Values of frames that were not submitted yet block until the timeout.
+    pub fn wait_for_value(&self, value: u64, timeout: Duration) -> Result<bool> {
+        let _span = profiling::span("Gpu::wait_for_value");
+
+        Ok(self
+            .frame_synchronization_manager
+            .graphics_work_semaphore()
+            .wait_for_value_timeout(value, timeout)?)
+    }
+
+    /// Timestamp scopes of the most recent frame whose Gpu work completed, lags the current frame by the number of frames in flight
+    pub fn gpu_timestamps(&self) -> &[GpuTimestamp] {
+        &self.gpu_timestamps
+    }
+
     // XXX: Do not need to return a strong ref here...
     pub fn current_command_buffer(&mut self, thread_index: u32) -> Result<Arc<CommandBuffer>> {
         let command_buffer = self.command_buffer_manager.command_buffer(
@@ -467,22 +1269,44 @@ impl Gpu {
         Ok(command_buffer)
     }
 
+    /// Command buffer of the current frame submitted to the async compute queue, fails if async compute is not
+    /// supported
+    pub fn current_compute_command_buffer(&self) -> Result<Arc<CommandBuffer>> {
+        let frame_index = self.frame_synchronization_manager.current_frame_index() as usize;
+        let command_buffer = self
+            .compute_command_buffers
+            .get(frame_index)
+            .cloned()
+            .context("Gpu does not support async compute")?;
+
+        Ok(command_buffer)
+    }
+
+    /// Work submitted to the compute queue can overlap graphics work, the compute queue is from a different queue
+    /// family than the graphics queue
+    pub fn async_compute_supported(&self) -> bool {
+        !self.compute_command_buffers.is_empty()
+    }
+
     // XXX: Remove this
-    pub fn swapchain(&self) -> &Swapchain {
-        &self.swapchain
+    pub fn swapchain(&self) -> Option<&Swapchain> {
+        self.swapchain.as_ref()
     }
 
     pub fn advance_frame_counters(&mut self) {
         self.frame_synchronization_manager.advance_frame_counters();
     }
 
+    fn wait_device_idle(&self) -> Result<()> {
+        unsafe { self.device.raw().device_wait_idle()? };
+        Ok(())
+    }
+
+    /// Also waits for all recorded uploads
     pub fn wait_idle(&self) {
-        unsafe {
-            self.device
-                .raw()
-                .queue_wait_idle(self.graphics_queue.raw())
-                .unwrap();
-        };
+        self.flush_uploads().unwrap();
+        self.graphics_queue.wait_idle().unwrap();
+        self.upload_context.retire_completed().unwrap();
     }
 
     // XXX: Remove these, ideally handled somewhere else
@@ -502,59 +1326,42 @@ impl Gpu {
         Ok(())
     }
 
-    pub fn copy_buffer(&mut self, src: &Buffer, dst: &Buffer) -> Result<()> {
-        let command_buffer = self
-            .transfer_command_pool
-            .allocate_command_buffer(vk::CommandBufferLevel::PRIMARY)?;
-        let command_buffer = CommandBuffer::new(
-            self.device.clone(),
-            command_buffer,
-            // XXX: Implement trait default for this
-            CommandBufferMetaData {
-                array_index: 0,
-                frame_index: 0,
-                thread_index: 0,
-            },
-            false,
-        );
+    /// Records a copy of the whole `src` buffer into the current upload batch, see `flush_uploads`
+    pub fn copy_buffer(&self, src: &Handle<Buffer>, dst: &Handle<Buffer>) -> Result<()> {
+        self.upload_context
+            .record(&[src, dst], &[], |command_buffer| {
+                command_buffer.copy_buffer(src, dst, src.size() as u64, 0, 0);
+                Ok(())
+            })?;
 
-        // command_buffer.upload_data_to_image(image.as_ref(), staging_buffer, data)?;
-        command_buffer.begin()?;
-        command_buffer.copy_buffer(src, dst, src.size() as u64, 0, 0);
-        command_buffer.end()?;
-        self.graphics_queue.submit(&[&command_buffer], &[], &[])?;
+        Ok(())
+    }
 
-        self.wait_idle();
+    /// Records the regions of `batch` into the current upload batch, `buffers` are the buffers used by the regions and
+    /// are kept alive until the copies completed
+    pub fn copy_batch(&self, batch: CopyBatch, buffers: &[&Handle<Buffer>]) -> Result<()> {
+        self.upload_context.record(buffers, &[], |command_buffer| {
+            command_buffer.copy_batch(batch);
+            Ok(())
+        })?;
 
         Ok(())
     }
 
+    /// Fills `staging_buffer` with `data` and records its upload to the first mip of `image` into the current
+    /// upload batch. The image is registered in the bindless array at the start of the next frame.
     pub fn copy_data_to_image<T: Copy>(
-        // For command buffer manager mut access
         &mut self,
         image: Handle<Image>,
-        staging_buffer: &Buffer,
+        staging_buffer: &Handle<Buffer>,
         data: &[T],
     ) -> Result<()> {
-        let command_buffer = self
-            .transfer_command_pool
-            .allocate_command_buffer(vk::CommandBufferLevel::PRIMARY)?;
-        let command_buffer = CommandBuffer::new(
-            self.device.clone(),
-            command_buffer,
-            // XXX: Implement trait default for this
-            CommandBufferMetaData {
-                array_index: 0,
-                frame_index: 0,
-                thread_index: 0,
-            },
-            false,
-        );
+        let _span = profiling::span("Gpu::copy_data_to_image");
 
-        command_buffer.upload_data_to_image(&image, staging_buffer, data)?;
-        self.graphics_queue.submit(&[&command_buffer], &[], &[])?;
-
-        self.wait_idle();
+        self.upload_context
+            .record(&[staging_buffer], &[&image], |command_buffer| {
+                command_buffer.upload_data_to_image(&image, staging_buffer, data)
+            })?;
 
         let update = ImageResourceUpdate {
             frame: self.frame_synchronization_manager.current_frame_index(),
@@ -566,39 +1373,185 @@ impl Gpu {
         Ok(())
     }
 
+    /// Creates an image from tightly packed data of its first mip level, uploads it and registers it in the
+    /// bindless array. The upload is part of the current upload batch.
+    pub fn create_image_from_data<T: Copy>(
+        &mut self,
+        desc: ImageDesc,
+        data: &[T],
+    ) -> Result<Handle<Image>> {
+        let data_size = std::mem::size_of_val(data);
+        if let Some(image_size) = format_image_size(desc.format, desc.width, desc.height) {
+            let expected_size = image_size * (desc.array_layer_count * desc.depth) as usize;
+            if data_size != expected_size {
+                return Err(GpuError::invalid_desc(format!(
+                    "Image data is {} bytes, expected {} bytes",
+                    data_size, expected_size
+                )));
+            }
+        }
+
+        let staging_buffer = self.create_buffer(
+            BufferDesc::new()
+                .set_size(data_size as u32)
+                .set_usage_flags(vk::BufferUsageFlags::TRANSFER_SRC)
+                .set_device_only(false),
+        )?;
+        let usage_flags = desc.usage_flags | vk::ImageUsageFlags::SAMPLED;
+        let image = self.create_image(desc.set_usage_flags(usage_flags))?;
+        self.copy_data_to_image(image.clone(), &staging_buffer, data)?;
+
+        Ok(image)
+    }
+
+    /// Creates a sampled image from the data of all of its mip levels, tightly packed starting with the most detailed
+    /// one. Every mip level stores all array layers consecutively.
+    pub fn create_image_with_mips_from_data<T: Copy>(
+        &mut self,
+        desc: ImageDesc,
+        data: &[T],
+    ) -> Result<Handle<Image>> {
+        let usage_flags = desc.usage_flags | vk::ImageUsageFlags::SAMPLED;
+        let image = self.create_image(desc.set_usage_flags(usage_flags))?;
+
+        self.upload_image_mips(&image, data, ResourceState::UNDEFINED)?;
+
+        self.bindless_images_to_update.push(ImageResourceUpdate {
+            frame: self.frame_synchronization_manager.current_frame_index(),
+            image: Some(image.clone()),
+            sampler: None,
+        });
+
+        Ok(image)
+    }
+
+    /// Replaces the data of all mip levels of an image created by `create_image_with_mips_from_data`, packed the same
+    /// way. The image keeps its bindless slot, draws recorded before the upload still sample the old data.
+    pub fn update_image_with_mips_from_data<T: Copy>(
+        &self,
+        image: &Handle<Image>,
+        data: &[T],
+    ) -> Result<()> {
+        self.upload_image_mips(image, data, ResourceState::SHADER_RESOURCE)
+    }
+
+    fn upload_image_mips<T: Copy>(
+        &self,
+        image: &Handle<Image>,
+        data: &[T],
+        old_state: ResourceState,
+    ) -> Result<()> {
+        let layer_count = image.array_layers() * image.depth();
+        let mut mip_offsets = Vec::with_capacity(image.mip_levels() as usize);
+        let mut expected_size = 0;
+        for mip_level in 0..image.mip_levels() {
+            let width = (image.width() >> mip_level).max(1);
+            let height = (image.height() >> mip_level).max(1);
+            let mip_size = format_image_size(image.format(), width, height).with_context(|| {
+                format!(
+                    "Image data upload of format {:?} is not supported",
+                    image.format()
+                )
+            })?;
+            mip_offsets.push(expected_size as u64);
+            expected_size += mip_size * layer_count as usize;
+        }
+
+        let data_size = std::mem::size_of_val(data);
+        if data_size != expected_size {
+            return Err(GpuError::invalid_desc(format!(
+                "Image data is {} bytes, expected {} bytes",
+                data_size, expected_size
+            )));
+        }
+
+        let staging_buffer = self.create_buffer(
+            BufferDesc::new()
+                .set_size(data_size as u32)
+                .set_usage_flags(vk::BufferUsageFlags::TRANSFER_SRC)
+                .set_device_only(false),
+        )?;
+        staging_buffer.copy_data_to_buffer(data)?;
+
+        self.upload_context
+            .record(&[&staging_buffer], &[image], |command_buffer| {
+                command_buffer.pipeline_barrier(Barriers::new().add_image(
+                    image,
+                    old_state,
+                    ResourceState::COPY_DESTINATION,
+                ));
+
+                let mut copy_batch = CopyBatch::new();
+                for (mip_level, offset) in mip_offsets.iter().enumerate() {
+                    copy_batch = copy_batch.add_buffer_to_image_region(
+                        &staging_buffer,
+                        image,
+                        *offset,
+                        mip_level as u32,
+                        0,
+                        image.array_layers(),
+                    );
+                }
+                command_buffer.copy_batch(copy_batch);
+
+                command_buffer.pipeline_barrier(Barriers::new().add_image(
+                    image,
+                    ResourceState::COPY_DESTINATION,
+                    ResourceState::SHADER_RESOURCE,
+                ));
+                Ok(())
+            })?;
+
+        Ok(())
+    }
+
+    /// Records a layout transition into the current upload batch, it is executed before the next frame. The image is
+    /// kept alive until the batch completed.
     pub fn transition_image_layout(
         &self,
-        image: &Image,
+        image: &Handle<Image>,
         old_state: ResourceState,
         new_state: ResourceState,
     ) -> Result<()> {
-        let command_buffer = self
-            .transfer_command_pool
-            .allocate_command_buffer(vk::CommandBufferLevel::PRIMARY)?;
-        let command_buffer = CommandBuffer::new(
-            self.device.clone(),
-            command_buffer,
-            // XXX: Implement trait default for this
-            CommandBufferMetaData {
-                array_index: 0,
-                frame_index: 0,
-                thread_index: 0,
-            },
-            false,
-        );
-
-        let barriers = Barriers::new().add_image(image, old_state, new_state);
+        self.upload_context
+            .record(&[], &[image], |command_buffer| {
+                command_buffer
+                    .pipeline_barrier(Barriers::new().add_image(image, old_state, new_state));
+                Ok(())
+            })?;
 
-        command_buffer.begin()?;
-        command_buffer.pipeline_barrier(barriers);
-        command_buffer.end()?;
+        Ok(())
+    }
 
-        self.graphics_queue.submit(&[&command_buffer], &[], &[])?;
-        self.wait_idle();
+    /// Same as `transition_image_layout`, but transitions from the tracked state of the image
+    pub fn transition_image(&self, image: &Handle<Image>, new_state: ResourceState) -> Result<()> {
+        self.upload_context
+            .record(&[], &[image], |command_buffer| {
+                command_buffer.transition_image(image, new_state);
+                Ok(())
+            })?;
 
         Ok(())
     }
 
+    /// Submits the uploads and transitions recorded since the last flush. Returns the upload timeline value
+    /// signaled once they completed, see `wait_for_uploads`.
+    /// Called before every submission on the graphics queue, so recorded uploads are always executed before work
+    /// submitted afterwards.
+    pub fn flush_uploads(&self) -> Result<u64> {
+        let _span = profiling::span("Gpu::flush_uploads");
+        Ok(self.upload_context.flush(&self.graphics_queue)?)
+    }
+
+    /// Waits for the upload timeline to reach `value` returned by `flush_uploads`, returns false on timeout
+    pub fn wait_for_uploads(&self, value: u64, timeout: Duration) -> Result<bool> {
+        Ok(self.upload_context.wait_for_value(value, timeout)?)
+    }
+
+    pub fn completed_upload_value(&self) -> Result<u64> {
+        Ok(self.upload_context.completed_value()?)
+    }
+
     // XXX: Properly integrate this somewhere internally
     pub fn bindless_descriptor_set_layout(&self) -> &Handle<DescriptorSetLayout> {
         &self.bindless_descriptor_set_layout
@@ -613,7 +1566,34 @@ impl Gpu {
         self.bindless_images_to_update.push(update);
     }
 
+    /// Queues bindless slot rewrites for images whose linked sampler changed after they were registered
+    fn queue_bindless_sampler_updates(&mut self) {
+        let frame = self.frame_synchronization_manager.current_frame_index();
+        let resource_hub = self.resource_hub.clone();
+        let bindless_images_to_update = &mut self.bindless_images_to_update;
+
+        // Slots of destroyed images are dropped
+        self.bindless_slot_images
+            .retain(|_, image| match image.upgrade() {
+                Some(image) => {
+                    if image.take_sampler_changed() {
+                        bindless_images_to_update.push(ImageResourceUpdate {
+                            frame,
+                            image: Some(Handle::new_from_arc(image, resource_hub.clone())),
+                            sampler: None,
+                        });
+                    }
+                    true
+                }
+                None => false,
+            });
+    }
+
+    /// Writes the queued bindless image updates, updates without a sampler use the image's linked sampler and fall
+    /// back to the default sampler. Called at the start of every frame.
     pub fn update_bindless_images(&mut self) {
+        self.queue_bindless_sampler_updates();
+
         // let mut write_descriptors = Vec::new();
 
         // Need this here to store image descriptors
@@ -624,22 +1604,37 @@ impl Gpu {
             if let Some(image) = update.image {
                 assert!(image.bindless_index() != INVALID_BINDLESS_TEXTURE_INDEX);
 
-                let mut image_descriptor = vk::DescriptorImageInfo::builder()
+                // Cube maps are written to their own array at the same index
+                let binding = match image.view_type() {
+                    vk::ImageViewType::TYPE_2D => constants::BINDLESS_SET_SAMPLED_IMAGE_INDEX,
+                    vk::ImageViewType::CUBE => constants::BINDLESS_SET_SAMPLED_CUBE_IMAGE_INDEX,
+                    view_type => {
+                        log::warn!(
+                            "Skipping bindless update of image with view type {:?}",
+                            view_type
+                        );
+                        continue;
+                    }
+                };
+
+                // The write below covers the current linked sampler
+                image.take_sampler_changed();
+                let sampler = update
+                    .sampler
+                    .or_else(|| image.linked_sampler())
+                    .map_or_else(|| self.default_sampler.raw(), |sampler| sampler.raw());
+
+                let image_descriptor = vk::DescriptorImageInfo::builder()
                     .image_view(image.raw_view())
-                    .sampler(self.default_sampler.raw())
+                    .sampler(sampler)
                     .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
-                if let Some(sampler) = update.sampler {
-                    image_descriptor = image_descriptor.sampler(sampler.raw());
-                } else {
-                    image_descriptor = image_descriptor.sampler(self.default_sampler.raw());
-                }
                 image_descriptors.push(image_descriptor);
 
                 let write_descriptor = vk::WriteDescriptorSet::builder()
                     .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER) //
                     .dst_array_element(image.bindless_index())
                     .dst_set(self.bindless_descriptor_set.raw())
-                    .dst_binding(constants::BINDLESS_SET_SAMPLED_IMAGE_INDEX)
+                    .dst_binding(binding)
                     // XXX: This is dangerous, change this!
                     .image_info(std::slice::from_ref(image_descriptors.last().unwrap()));
                 // write_descriptors.push(write_descriptor.build());
@@ -649,6 +1644,55 @@ impl Gpu {
                         .raw()
                         .update_descriptor_sets(std::slice::from_ref(&write_descriptor), &[]);
                 }
+
+                if let Some(srgb_view) = image.srgb_view() {
+                    let srgb_image_descriptor = vk::DescriptorImageInfo::builder()
+                        .image_view(srgb_view)
+                        .sampler(sampler)
+                        .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+                    let write_descriptor = vk::WriteDescriptorSet::builder()
+                        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                        .dst_array_element(image.srgb_bindless_index())
+                        .dst_set(self.bindless_descriptor_set.raw())
+                        .dst_binding(binding)
+                        .image_info(std::slice::from_ref(&srgb_image_descriptor));
+
+                    unsafe {
+                        self.device
+                            .raw()
+                            .update_descriptor_sets(std::slice::from_ref(&write_descriptor), &[]);
+                    }
+                }
+
+                self.bindless_slot_images
+                    .insert(image.bindless_index(), Arc::downgrade(&image.inner));
+            }
+        }
+
+        for image in self.bindless_storage_images_to_update.drain(..) {
+            if image.view_type() != vk::ImageViewType::TYPE_2D {
+                log::warn!(
+                    "Skipping bindless storage update of image with view type {:?}",
+                    image.view_type()
+                );
+                continue;
+            }
+
+            let image_descriptor = vk::DescriptorImageInfo::builder()
+                .image_view(image.raw_view())
+                .image_layout(vk::ImageLayout::GENERAL);
+
+            let write_descriptor = vk::WriteDescriptorSet::builder()
+                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                .dst_array_element(image.storage_bindless_index())
+                .dst_set(self.bindless_descriptor_set.raw())
+                .dst_binding(constants::BINDLESS_SET_STORAGE_IMAGE_INDEX)
+                .image_info(std::slice::from_ref(&image_descriptor));
+
+            unsafe {
+                self.device
+                    .raw()
+                    .update_descriptor_sets(std::slice::from_ref(&write_descriptor), &[]);
             }
         }
 
@@ -665,13 +1709,22 @@ impl Gpu {
         self.shader_read_image_sender.clone()
     }
 
+    /// Images whose transfer queue upload got acquired by the graphics queue in the last `update_image_transitions`
+    pub fn uploaded_images(&self) -> &[Handle<Image>] {
+        &self.cached_images_to_transition_0
+    }
+
     pub fn update_image_transitions(&mut self, thread_index: u32) -> Result<()> {
         self.cached_images_to_transition_1 = self.cached_images_to_transition_0.clone();
 
         let mut images_to_transition = Vec::new();
 
         while !self.shader_read_image_receiver.is_empty() {
-            images_to_transition.push(self.shader_read_image_receiver.recv()?);
+            images_to_transition.push(
+                self.shader_read_image_receiver
+                    .recv()
+                    .context("Image transition channel is disconnected")?,
+            );
         }
 
         if !images_to_transition.is_empty() {
@@ -679,12 +1732,17 @@ impl Gpu {
 
             command_buffer.begin()?;
 
+            // Acquire ownership of the images released by the transfer manager
+            let (old_state, new_state) = TransferManager::uploaded_image_states();
+
             let mut barriers = Barriers::new();
             for image in &images_to_transition {
-                barriers = barriers.add_image(
+                barriers = barriers.add_image_acquire(
                     &image,
-                    ResourceState::COPY_DESTINATION,
-                    ResourceState::SHADER_RESOURCE,
+                    old_state,
+                    new_state,
+                    &self.transfer_queue,
+                    &self.graphics_queue,
                 );
             }
             command_buffer.pipeline_barrier(barriers);
@@ -705,29 +1763,33 @@ impl Gpu {
     // }
 
     pub fn new_transfer_manager(&self) -> Result<TransferManager> {
-        TransferManager::new(
+        Ok(TransferManager::new(
             self.device.clone(),
             &self.factory,
             self.transfer_queue.clone(),
             self.graphics_queue.clone(),
             self.shader_read_image_sender.clone(),
-        )
+        )?)
     }
 
+    /// Shaders read from the files are recompiled by the next pipeline created with them
+    pub fn invalidate_shader_states(&self, file_names: &[String]) {
+        self.factory.invalidate_shader_states(file_names);
+    }
+
+    /// Destroys all dropped resources, the caller needs to make sure the Gpu is idle
     pub fn force_cleanup(&self) {
-        self.factory.cleanup_resources();
+        self.global_descriptor_pool.free_released_descriptor_sets();
+        self.factory.cleanup_all_resources();
     }
 }
 
 impl Drop for Gpu {
     fn drop(&mut self) {
-        unsafe {
-            self.device
-                .raw()
-                .queue_wait_idle(self.graphics_queue.raw())
-                .unwrap();
-        }
+        self.graphics_queue.wait_idle().unwrap();
 
+        self.factory.clear_shader_state_cache();
+        self.factory.clear_layout_caches();
         self.force_cleanup();
 
         log::info!("Gpu dropped");
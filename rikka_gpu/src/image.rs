@@ -1,8 +1,14 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    ops::Range,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 use parking_lot::{Mutex, RwLock};
 
-use anyhow::{Context, Result};
 use gpu_allocator::{
     vulkan::{Allocation, AllocationCreateDesc, Allocator},
     MemoryLocation,
@@ -10,10 +16,11 @@ use gpu_allocator::{
 use rikka_core::vk;
 
 use crate::{
-    barriers::ResourceState, constants::INVALID_BINDLESS_TEXTURE_INDEX, device::Device,
+    barriers::ResourceState, constants::INVALID_BINDLESS_TEXTURE_INDEX, device::Device, error::*,
     escape::Handle, factory::DeviceGuard, sampler::Sampler, swapchain::Swapchain,
 };
 
+#[derive(Clone)]
 pub struct ImageDesc {
     pub width: u32,
     pub height: u32,
@@ -25,6 +32,7 @@ pub struct ImageDesc {
     pub format: vk::Format,
     pub image_type: vk::ImageType,
     pub usage_flags: vk::ImageUsageFlags,
+    pub flags: vk::ImageCreateFlags,
     memory_location: MemoryLocation,
 }
 
@@ -39,6 +47,7 @@ impl ImageDesc {
             format: vk::Format::UNDEFINED,
             image_type: vk::ImageType::TYPE_2D,
             usage_flags: vk::ImageUsageFlags::empty(),
+            flags: vk::ImageCreateFlags::empty(),
             memory_location: MemoryLocation::GpuOnly,
         }
     }
@@ -57,24 +66,271 @@ impl ImageDesc {
         self.image_type = image_type;
         self
     }
+
+    pub fn set_mip_level_count(mut self, mip_level_count: u32) -> Self {
+        self.mip_level_count = mip_level_count;
+        self
+    }
+
+    /// Images with more than one layer get an array view
+    pub fn set_array_layer_count(mut self, array_layer_count: u32) -> Self {
+        self.array_layer_count = array_layer_count;
+        self
+    }
+
+    pub fn set_flags(mut self, flags: vk::ImageCreateFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Creates `cube_count` cube maps with 6 layers each, multiple cubes get a cube array view
+    pub fn set_cube_map(mut self, cube_count: u32) -> Self {
+        self.array_layer_count = cube_count * 6;
+        self.flags |= vk::ImageCreateFlags::CUBE_COMPATIBLE;
+        self
+    }
+
+    pub fn is_cube_map(&self) -> bool {
+        self.flags.contains(vk::ImageCreateFlags::CUBE_COMPATIBLE)
+    }
 }
 
+/// Custom view of an image, defaults to the view type, format and full subresource range of the image
 pub struct ImageViewDesc {
-    pub image: vk::Image,
+    pub image: Handle<Image>,
     pub view_type: vk::ImageViewType,
     pub format: vk::Format,
     pub subresource_range: vk::ImageSubresourceRange,
 }
 
-fn vulkan_image_type_to_view_type(image_type: vk::ImageType) -> vk::ImageViewType {
+impl ImageViewDesc {
+    pub fn new(image: Handle<Image>) -> Self {
+        Self {
+            view_type: image.view_type(),
+            format: image.format(),
+            subresource_range: image.subresource_range(),
+            image,
+        }
+    }
+
+    pub fn set_view_type(mut self, view_type: vk::ImageViewType) -> Self {
+        self.view_type = view_type;
+        self
+    }
+
+    /// Formats other than the image format require the image to be created with MUTABLE_FORMAT
+    pub fn set_format(mut self, format: vk::Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn set_mip_levels(mut self, base_mip_level: u32, mip_level_count: u32) -> Self {
+        self.subresource_range.base_mip_level = base_mip_level;
+        self.subresource_range.level_count = mip_level_count;
+        self
+    }
+
+    pub fn set_array_layers(mut self, base_array_layer: u32, array_layer_count: u32) -> Self {
+        self.subresource_range.base_array_layer = base_array_layer;
+        self.subresource_range.layer_count = array_layer_count;
+        self
+    }
+
+    pub fn set_aspect_mask(mut self, aspect_mask: vk::ImageAspectFlags) -> Self {
+        self.subresource_range.aspect_mask = aspect_mask;
+        self
+    }
+}
+
+/// `Image::subresource_state_ranges` for the states of all subresources stored layer by layer, `mips` and `layers`
+/// are clamped to the image
+fn subresource_state_ranges(
+    subresource_states: &[ResourceState],
+    mip_levels: u32,
+    mips: Range<u32>,
+    layers: Range<u32>,
+    subresource_range: &vk::ImageSubresourceRange,
+) -> Vec<(vk::ImageSubresourceRange, ResourceState)> {
+    let state = |mip_level: u32, array_layer: u32| {
+        subresource_states[(array_layer * mip_levels + mip_level) as usize]
+    };
+
+    if mips.is_empty() || layers.is_empty() {
+        return Vec::new();
+    }
+
+    let first_state = state(mips.start, layers.start);
+    if layers
+        .clone()
+        .all(|layer| mips.clone().all(|mip| state(mip, layer) == first_state))
+    {
+        return vec![(
+            vk::ImageSubresourceRange {
+                base_mip_level: mips.start,
+                level_count: mips.len() as u32,
+                base_array_layer: layers.start,
+                layer_count: layers.len() as u32,
+                ..*subresource_range
+            },
+            first_state,
+        )];
+    }
+
+    let mut ranges = Vec::new();
+    for layer in layers {
+        let mut base_mip_level = mips.start;
+        for mip in mips.clone() {
+            let run_state = state(base_mip_level, layer);
+            if mip + 1 == mips.end || state(mip + 1, layer) != run_state {
+                ranges.push((
+                    vk::ImageSubresourceRange {
+                        base_mip_level,
+                        level_count: mip + 1 - base_mip_level,
+                        base_array_layer: layer,
+                        layer_count: 1,
+                        ..*subresource_range
+                    },
+                    run_state,
+                ));
+                base_mip_level = mip + 1;
+            }
+        }
+    }
+
+    ranges
+}
+
+unsafe fn create_vulkan_image_view(
+    device: &Device,
+    image: vk::Image,
+    view_type: vk::ImageViewType,
+    format: vk::Format,
+    subresource_range: vk::ImageSubresourceRange,
+) -> Result<vk::ImageView> {
+    let create_info = vk::ImageViewCreateInfo::builder()
+        .image(image)
+        .view_type(view_type)
+        .format(format)
+        .subresource_range(subresource_range);
+
+    let image_view = device.raw().create_image_view(&create_info, None)?;
+
+    Ok(image_view)
+}
+
+/// View type covering all layers of an image
+fn default_view_type(
+    image_type: vk::ImageType,
+    array_layer_count: u32,
+    cube_compatible: bool,
+) -> vk::ImageViewType {
     match image_type {
+        vk::ImageType::TYPE_2D if cube_compatible && array_layer_count > 6 => {
+            vk::ImageViewType::CUBE_ARRAY
+        }
+        vk::ImageType::TYPE_2D if cube_compatible => vk::ImageViewType::CUBE,
+        vk::ImageType::TYPE_2D if array_layer_count > 1 => vk::ImageViewType::TYPE_2D_ARRAY,
         vk::ImageType::TYPE_2D => vk::ImageViewType::TYPE_2D,
+        vk::ImageType::TYPE_3D => vk::ImageViewType::TYPE_3D,
+        vk::ImageType::TYPE_1D if array_layer_count > 1 => vk::ImageViewType::TYPE_1D_ARRAY,
+        vk::ImageType::TYPE_1D => vk::ImageViewType::TYPE_1D,
         _ => {
             todo!()
         }
     }
 }
 
+/// Mip and layer range of an additional image view, eg. a single cube face or mip level
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct ImageSubresourceViewDesc {
+    pub view_type: vk::ImageViewType,
+    pub base_mip_level: u32,
+    pub mip_level_count: u32,
+    pub base_array_layer: u32,
+    pub array_layer_count: u32,
+}
+
+impl ImageSubresourceViewDesc {
+    /// 2D view of all mips of a single layer
+    pub fn layer(layer: u32, mip_level_count: u32) -> Self {
+        Self {
+            view_type: vk::ImageViewType::TYPE_2D,
+            base_mip_level: 0,
+            mip_level_count,
+            base_array_layer: layer,
+            array_layer_count: 1,
+        }
+    }
+
+    /// 2D view of a single mip of a single layer, eg. for rendering into a cube face
+    pub fn layer_mip(layer: u32, mip_level: u32) -> Self {
+        Self {
+            view_type: vk::ImageViewType::TYPE_2D,
+            base_mip_level: mip_level,
+            mip_level_count: 1,
+            base_array_layer: layer,
+            array_layer_count: 1,
+        }
+    }
+}
+
+/// Key of the views cached by an image
+#[derive(Clone, Copy, Hash, PartialEq, Eq)]
+struct CachedViewKey {
+    desc: ImageSubresourceViewDesc,
+    format: vk::Format,
+    aspect_mask: vk::ImageAspectFlags,
+}
+
+/// Mip level and array layers of an image copy or blit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageCopySubresource {
+    pub mip_level: u32,
+    pub base_array_layer: u32,
+    pub array_layer_count: u32,
+}
+
+impl ImageCopySubresource {
+    /// First layer of a mip level
+    pub fn mip(mip_level: u32) -> Self {
+        Self::layers(mip_level, 0, 1)
+    }
+
+    pub fn layers(mip_level: u32, base_array_layer: u32, array_layer_count: u32) -> Self {
+        Self {
+            mip_level,
+            base_array_layer,
+            array_layer_count,
+        }
+    }
+
+    /// Checks the subresource is within the mips and layers of `image`
+    pub fn validate(&self, image: &Image) -> Result<()> {
+        if self.mip_level >= image.mip_levels()
+            || self.array_layer_count == 0
+            || self.base_array_layer + self.array_layer_count > image.array_layers()
+        {
+            return Err(GpuError::invalid_desc(format!(
+                "Subresource {:?} is out of range of image with {} mips and {} layers",
+                self,
+                image.mip_levels(),
+                image.array_layers()
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn vulkan_layers(&self, image: &Image) -> vk::ImageSubresourceLayers {
+        vk::ImageSubresourceLayers::builder()
+            .aspect_mask(image.copy_aspect_mask())
+            .mip_level(self.mip_level)
+            .base_array_layer(self.base_array_layer)
+            .layer_count(self.array_layer_count)
+            .build()
+    }
+}
+
 pub fn format_has_depth(format: vk::Format) -> bool {
     match format {
         vk::Format::D32_SFLOAT_S8_UINT
@@ -85,16 +341,109 @@ pub fn format_has_depth(format: vk::Format) -> bool {
     }
 }
 
-fn format_has_stencil(format: vk::Format) -> bool {
+pub fn format_has_stencil(format: vk::Format) -> bool {
     match format {
         vk::Format::D32_SFLOAT_S8_UINT
         | vk::Format::D24_UNORM_S8_UINT
-        | vk::Format::D16_UNORM_S8_UINT => true,
+        | vk::Format::D16_UNORM_S8_UINT
+        | vk::Format::S8_UINT => true,
+        _ => false,
+    }
+}
+
+/// sRGB encoded counterpart of a UNORM format, returns None if the format has no sRGB variant
+pub fn format_to_srgb(format: vk::Format) -> Option<vk::Format> {
+    match format {
+        vk::Format::R8_UNORM => Some(vk::Format::R8_SRGB),
+        vk::Format::R8G8B8A8_UNORM => Some(vk::Format::R8G8B8A8_SRGB),
+        vk::Format::B8G8R8A8_UNORM => Some(vk::Format::B8G8R8A8_SRGB),
+        vk::Format::BC1_RGBA_UNORM_BLOCK => Some(vk::Format::BC1_RGBA_SRGB_BLOCK),
+        vk::Format::BC2_UNORM_BLOCK => Some(vk::Format::BC2_SRGB_BLOCK),
+        vk::Format::BC3_UNORM_BLOCK => Some(vk::Format::BC3_SRGB_BLOCK),
+        vk::Format::BC7_UNORM_BLOCK => Some(vk::Format::BC7_SRGB_BLOCK),
+        vk::Format::ASTC_4X4_UNORM_BLOCK => Some(vk::Format::ASTC_4X4_SRGB_BLOCK),
+        vk::Format::ETC2_R8G8B8A8_UNORM_BLOCK => Some(vk::Format::ETC2_R8G8B8A8_SRGB_BLOCK),
+        _ => None,
+    }
+}
+
+pub fn format_is_srgb(format: vk::Format) -> bool {
+    match format {
+        vk::Format::R8_SRGB
+        | vk::Format::R8G8B8A8_SRGB
+        | vk::Format::B8G8R8A8_SRGB
+        | vk::Format::BC1_RGBA_SRGB_BLOCK
+        | vk::Format::BC2_SRGB_BLOCK
+        | vk::Format::BC3_SRGB_BLOCK
+        | vk::Format::BC7_SRGB_BLOCK
+        | vk::Format::ASTC_4X4_SRGB_BLOCK
+        | vk::Format::ETC2_R8G8B8A8_SRGB_BLOCK => true,
         _ => false,
     }
 }
 
-// XXX: Need a first-class ImageView type as well. Can be useful for example the use cases of different image views for the same image
+/// Size in bytes of a single texel, returns None for compressed/unhandled formats
+pub fn format_texel_size(format: vk::Format) -> Option<u32> {
+    match format {
+        vk::Format::R8_UNORM | vk::Format::R8_SRGB => Some(1),
+        vk::Format::R8G8_UNORM | vk::Format::R16_SFLOAT | vk::Format::D16_UNORM => Some(2),
+        vk::Format::R8G8B8A8_UNORM
+        | vk::Format::R8G8B8A8_SRGB
+        | vk::Format::B8G8R8A8_UNORM
+        | vk::Format::B8G8R8A8_SRGB
+        | vk::Format::A2B10G10R10_UNORM_PACK32
+        | vk::Format::R16G16_SFLOAT
+        | vk::Format::R32_SFLOAT
+        | vk::Format::D32_SFLOAT => Some(4),
+        vk::Format::R16G16B16A16_SFLOAT | vk::Format::R32G32_SFLOAT => Some(8),
+        vk::Format::R32G32B32A32_SFLOAT => Some(16),
+        _ => None,
+    }
+}
+
+/// Size in bytes of a tightly packed 2D image or mip level, returns None for unhandled formats
+pub fn format_image_size(format: vk::Format, width: u32, height: u32) -> Option<usize> {
+    let block_size = match format_block_size(format) {
+        Some(block_size) => block_size,
+        None => {
+            let texel_size = format_texel_size(format)?;
+            return Some(width as usize * height as usize * texel_size as usize);
+        }
+    };
+
+    // Block compressed formats store 4x4 texel blocks
+    let blocks_x = ((width + 3) / 4).max(1) as usize;
+    let blocks_y = ((height + 3) / 4).max(1) as usize;
+    Some(blocks_x * blocks_y * block_size)
+}
+
+pub fn format_is_block_compressed(format: vk::Format) -> bool {
+    format_block_size(format).is_some()
+}
+
+/// Size in bytes of a 4x4 texel block of block compressed formats
+fn format_block_size(format: vk::Format) -> Option<usize> {
+    let block_size = match format {
+        vk::Format::BC1_RGBA_UNORM_BLOCK
+        | vk::Format::BC1_RGBA_SRGB_BLOCK
+        | vk::Format::BC4_UNORM_BLOCK => 8,
+        vk::Format::BC2_UNORM_BLOCK
+        | vk::Format::BC2_SRGB_BLOCK
+        | vk::Format::BC3_UNORM_BLOCK
+        | vk::Format::BC3_SRGB_BLOCK
+        | vk::Format::BC5_UNORM_BLOCK
+        | vk::Format::BC6H_UFLOAT_BLOCK
+        | vk::Format::BC7_UNORM_BLOCK
+        | vk::Format::BC7_SRGB_BLOCK
+        | vk::Format::ASTC_4X4_UNORM_BLOCK
+        | vk::Format::ASTC_4X4_SRGB_BLOCK
+        | vk::Format::ETC2_R8G8B8A8_UNORM_BLOCK
+        | vk::Format::ETC2_R8G8B8A8_SRGB_BLOCK => 16,
+        _ => return None,
+    };
+    Some(block_size)
+}
+
 pub struct Image {
     device: DeviceGuard,
     allocator: Option<Arc<Mutex<Allocator>>>,
@@ -102,10 +451,16 @@ pub struct Image {
 
     raw: vk::Image,
     raw_view: vk::ImageView,
+    /// View with the sRGB variant of the format for images created with MUTABLE_FORMAT, null otherwise
+    srgb_view: vk::ImageView,
 
-    // XXX: We do not actually track this and the images are imutable
-    resource_state: ResourceState,
+    /// State of every mip level and array layer as of the last recorded barrier, indexed by
+    /// `array_layer * mip_levels + mip_level`
+    // XXX: Barriers built from raw Vulkan parameters are not tracked
+    subresource_states: Mutex<Vec<ResourceState>>,
     sampler: RwLock<Option<Handle<Sampler>>>,
+    /// Set when the linked sampler changes, the Gpu rewrites the bindless slot of the image at frame start
+    sampler_changed: AtomicBool,
 
     // XXX: This struct contains to much stuff...move/remove some of these?
     format: vk::Format,
@@ -113,11 +468,21 @@ pub struct Image {
     mip_levels: u32,
     array_layers: u32,
     image_type: vk::ImageType,
+    flags: vk::ImageCreateFlags,
+    view_type: vk::ImageViewType,
 
     subresource_range: vk::ImageSubresourceRange,
+    /// Views created with `subresource_view` and `ImageView`s, destroyed with the image
+    subresource_views: Mutex<HashMap<CachedViewKey, vk::ImageView>>,
 
     owning: bool,
     bindless_index: u32,
+    /// Images sharing the bindless index of another image do not return it when destroyed
+    owns_bindless_index: bool,
+    /// Index of the sRGB view in the bindless image array, only allocated for images with an sRGB view
+    srgb_bindless_index: u32,
+    /// Index into the bindless storage image array, only allocated for images created as storage images
+    storage_bindless_index: u32,
 }
 
 impl Image {
@@ -135,7 +500,24 @@ impl Image {
             depth: desc.depth,
         };
 
+        if desc.is_cube_map() && (desc.width != desc.height || desc.array_layer_count % 6 != 0) {
+            return Err(GpuError::invalid_desc(
+                "Cube map images need square faces and a multiple of 6 layers",
+            ));
+        }
+        if desc.image_type == vk::ImageType::TYPE_3D
+            && (desc.array_layer_count != 1 || desc.is_cube_map())
+        {
+            return Err(GpuError::invalid_desc("3D images cannot have array layers"));
+        }
+        if desc.image_type != vk::ImageType::TYPE_3D && desc.depth != 1 {
+            return Err(GpuError::invalid_desc(
+                "Only 3D images can have a depth other than 1",
+            ));
+        }
+
         let create_info = vk::ImageCreateInfo::builder()
+            .flags(desc.flags)
             .image_type(desc.image_type)
             .format(desc.format)
             .extent(extent)
@@ -147,10 +529,7 @@ impl Image {
             .sharing_mode(vk::SharingMode::EXCLUSIVE)
             .initial_layout(vk::ImageLayout::UNDEFINED);
 
-        let raw = device
-            .raw()
-            .create_image(&create_info, None)
-            .context("Failed to create vulkan image")?;
+        let raw = device.raw().create_image(&create_info, None)?;
         let requirements = device.raw().get_image_memory_requirements(raw);
 
         // XXX: Always Gpu only (and use staging buffer to copy)?
@@ -167,10 +546,16 @@ impl Image {
             .raw()
             .bind_image_memory(raw, allocation.memory(), allocation.offset())?;
 
+        // Layout transitions of combined depth stencil formats need to include both aspects
+        // XXX: Views with both aspects cannot be sampled, create a separate depth only view for sampling
         let mut aspect_flags = vk::ImageAspectFlags::empty();
         if format_has_depth(desc.format) {
             aspect_flags |= vk::ImageAspectFlags::DEPTH;
-        } else {
+        }
+        if format_has_stencil(desc.format) {
+            aspect_flags |= vk::ImageAspectFlags::STENCIL;
+        }
+        if aspect_flags.is_empty() {
             aspect_flags |= vk::ImageAspectFlags::COLOR;
         }
 
@@ -182,36 +567,59 @@ impl Image {
             .layer_count(desc.array_layer_count)
             .build();
 
-        let raw_view = Self::create_vulkan_image_view(
-            &device,
-            ImageViewDesc {
-                image: raw,
-                view_type: vulkan_image_type_to_view_type(desc.image_type),
-                format: desc.format,
-                subresource_range,
-            },
-        )?;
+        let view_type =
+            default_view_type(desc.image_type, desc.array_layer_count, desc.is_cube_map());
+        let raw_view =
+            create_vulkan_image_view(&device, raw, view_type, desc.format, subresource_range)?;
+
+        // Textures sampled as both linear and sRGB data get a second view to sample the sRGB data with
+        let srgb_view = match format_to_srgb(desc.format) {
+            Some(srgb_format)
+                if desc.flags.contains(vk::ImageCreateFlags::MUTABLE_FORMAT)
+                    && desc.usage_flags.contains(vk::ImageUsageFlags::SAMPLED)
+                    && view_type == vk::ImageViewType::TYPE_2D =>
+            {
+                create_vulkan_image_view(&device, raw, view_type, srgb_format, subresource_range)?
+            }
+            _ => vk::ImageView::null(),
+        };
 
         Ok(Self {
             device,
             raw,
             raw_view,
+            srgb_view,
             allocator: Some(allocator),
             allocation: Some(allocation),
-            resource_state: ResourceState::UNDEFINED,
+            subresource_states: Mutex::new(vec![
+                ResourceState::UNDEFINED;
+                (desc.mip_level_count * desc.array_layer_count)
+                    as usize
+            ]),
             format: desc.format,
             extent,
             mip_levels: desc.mip_level_count,
             array_layers: desc.array_layer_count,
             subresource_range,
+            subresource_views: Mutex::new(HashMap::new()),
             image_type: desc.image_type,
+            flags: desc.flags,
+            view_type,
             sampler: RwLock::new(None),
+            sampler_changed: AtomicBool::new(false),
             owning: true,
             bindless_index: u32::MAX,
+            owns_bindless_index: false,
+            srgb_bindless_index: INVALID_BINDLESS_TEXTURE_INDEX,
+            storage_bindless_index: INVALID_BINDLESS_TEXTURE_INDEX,
         })
     }
 
     pub(crate) unsafe fn destroy(mut self) {
+        for (_, view) in self.subresource_views.get_mut().drain() {
+            self.device.raw().destroy_image_view(view, None);
+        }
+
         if self.owning {
             self.allocator
                 .clone()
@@ -222,6 +630,9 @@ impl Image {
 
             self.device.raw().destroy_image(self.raw, None);
             self.device.raw().destroy_image_view(self.raw_view, None);
+            if self.srgb_view != vk::ImageView::null() {
+                self.device.raw().destroy_image_view(self.srgb_view, None);
+            }
         }
     }
 
@@ -235,9 +646,10 @@ impl Image {
             device: swapchain.device().clone(),
             raw,
             raw_view,
+            srgb_view: vk::ImageView::null(),
             allocator: None,
             allocation: None,
-            resource_state: ResourceState::UNDEFINED,
+            subresource_states: Mutex::new(vec![ResourceState::UNDEFINED]),
             format: swapchain.format(),
             extent: vk::Extent3D {
                 width: swapchain.extent().width,
@@ -253,37 +665,62 @@ impl Image {
                 .base_array_layer(0)
                 .layer_count(1)
                 .build(),
+            subresource_views: Mutex::new(HashMap::new()),
             image_type: vk::ImageType::TYPE_2D,
+            flags: vk::ImageCreateFlags::empty(),
+            view_type: vk::ImageViewType::TYPE_2D,
             sampler: RwLock::new(None),
+            sampler_changed: AtomicBool::new(false),
             owning: false,
             bindless_index: INVALID_BINDLESS_TEXTURE_INDEX,
+            owns_bindless_index: false,
+            srgb_bindless_index: INVALID_BINDLESS_TEXTURE_INDEX,
+            storage_bindless_index: INVALID_BINDLESS_TEXTURE_INDEX,
         }
     }
 
     pub(crate) fn set_bindless_index(&mut self, index: u32) {
         self.bindless_index = index;
+        self.owns_bindless_index = true;
+    }
+
+    pub(crate) fn set_shared_bindless_index(&mut self, index: u32) {
+        self.bindless_index = index;
+        self.owns_bindless_index = false;
+    }
+
+    pub(crate) fn owns_bindless_index(&self) -> bool {
+        self.owns_bindless_index
     }
 
     pub fn bindless_index(&self) -> u32 {
         self.bindless_index
     }
 
-    unsafe fn create_vulkan_image_view(
-        device: &Device,
-        desc: ImageViewDesc,
-    ) -> Result<vk::ImageView> {
-        let create_info = vk::ImageViewCreateInfo::builder()
-            .image(desc.image)
-            .view_type(desc.view_type)
-            .format(desc.format)
-            .subresource_range(desc.subresource_range);
+    pub(crate) fn set_srgb_bindless_index(&mut self, index: u32) {
+        self.srgb_bindless_index = index;
+    }
 
-        let image_view = device
-            .raw()
-            .create_image_view(&create_info, None)
-            .context("Failed to create vulkan image view")?;
+    /// Bindless index to sample the image as sRGB data with, the linear bindless index for images without an sRGB view
+    pub fn srgb_bindless_index(&self) -> u32 {
+        if self.has_srgb_view() {
+            self.srgb_bindless_index
+        } else {
+            self.bindless_index
+        }
+    }
+
+    pub fn has_srgb_view(&self) -> bool {
+        self.srgb_view != vk::ImageView::null()
+    }
 
-        Ok(image_view)
+    pub(crate) fn set_storage_bindless_index(&mut self, index: u32) {
+        self.storage_bindless_index = index;
+    }
+
+    /// INVALID_BINDLESS_TEXTURE_INDEX if the image was not created as a bindless storage image
+    pub fn storage_bindless_index(&self) -> u32 {
+        self.storage_bindless_index
     }
 
     pub fn raw(&self) -> vk::Image {
@@ -294,6 +731,85 @@ impl Image {
         self.raw_view
     }
 
+    pub fn srgb_view(&self) -> Option<vk::ImageView> {
+        self.has_srgb_view().then_some(self.srgb_view)
+    }
+
+    /// Returns a view of a mip/layer range of the image, views are created on first use and live as long as the
+    /// image
+    pub fn subresource_view(&self, desc: ImageSubresourceViewDesc) -> Result<vk::ImageView> {
+        self.cached_view(desc, self.format, self.subresource_range.aspect_mask)
+    }
+
+    /// `subresource_view` with a custom format and aspect mask
+    fn cached_view(
+        &self,
+        desc: ImageSubresourceViewDesc,
+        format: vk::Format,
+        aspect_mask: vk::ImageAspectFlags,
+    ) -> Result<vk::ImageView> {
+        if desc.base_mip_level + desc.mip_level_count > self.mip_levels
+            || desc.base_array_layer + desc.array_layer_count > self.array_layers
+        {
+            return Err(GpuError::invalid_desc(format!(
+                "Image subresource view {:?} is out of range",
+                desc
+            )));
+        }
+        if format != self.format && !self.flags.contains(vk::ImageCreateFlags::MUTABLE_FORMAT) {
+            return Err(GpuError::invalid_desc(format!(
+                "Image view format {:?} differs from image format {:?} without MUTABLE_FORMAT",
+                format, self.format
+            )));
+        }
+
+        let key = CachedViewKey {
+            desc,
+            format,
+            aspect_mask,
+        };
+        let mut subresource_views = self.subresource_views.lock();
+        if let Some(view) = subresource_views.get(&key) {
+            return Ok(*view);
+        }
+
+        let subresource_range = vk::ImageSubresourceRange {
+            aspect_mask,
+            base_mip_level: desc.base_mip_level,
+            level_count: desc.mip_level_count,
+            base_array_layer: desc.base_array_layer,
+            layer_count: desc.array_layer_count,
+        };
+        let view = unsafe {
+            create_vulkan_image_view(
+                &self.device,
+                self.raw,
+                desc.view_type,
+                format,
+                subresource_range,
+            )?
+        };
+        subresource_views.insert(key, view);
+
+        Ok(view)
+    }
+
+    /// 2D view of all mips of a single layer, eg. a cube face
+    pub fn layer_view(&self, layer: u32) -> Result<vk::ImageView> {
+        self.subresource_view(ImageSubresourceViewDesc::layer(layer, self.mip_levels))
+    }
+
+    /// View of a single mip level covering all layers
+    pub fn mip_view(&self, mip_level: u32) -> Result<vk::ImageView> {
+        self.subresource_view(ImageSubresourceViewDesc {
+            view_type: self.view_type,
+            base_mip_level: mip_level,
+            mip_level_count: 1,
+            base_array_layer: 0,
+            array_layer_count: self.array_layers,
+        })
+    }
+
     pub fn has_linked_sampler(&self) -> bool {
         self.sampler.read().is_some()
     }
@@ -307,6 +823,12 @@ impl Image {
         unsafe {
             *self.sampler.write() = Some(Handle::new_no_guard_from_arc(sampler.inner));
         }
+        self.sampler_changed.store(true, Ordering::Release);
+    }
+
+    /// Returns whether the linked sampler changed since the last call
+    pub(crate) fn take_sampler_changed(&self) -> bool {
+        self.sampler_changed.swap(false, Ordering::AcqRel)
     }
 
     pub fn width(&self) -> u32 {
@@ -321,6 +843,18 @@ impl Image {
         self.extent
     }
 
+    pub fn mip_extent(&self, mip_level: u32) -> vk::Extent3D {
+        vk::Extent3D {
+            width: (self.extent.width >> mip_level).max(1),
+            height: (self.extent.height >> mip_level).max(1),
+            depth: (self.extent.depth >> mip_level).max(1),
+        }
+    }
+
+    pub fn format(&self) -> vk::Format {
+        self.format
+    }
+
     pub fn has_depth(&self) -> bool {
         format_has_depth(self.format)
     }
@@ -337,7 +871,252 @@ impl Image {
         self.subresource_range.level_count
     }
 
+    pub fn array_layers(&self) -> u32 {
+        self.array_layers
+    }
+
+    /// Tracked state of a single mip level and array layer
+    pub fn subresource_state(&self, mip_level: u32, array_layer: u32) -> ResourceState {
+        self.subresource_states.lock()[(array_layer * self.mip_levels + mip_level) as usize]
+    }
+
+    /// Tracked state shared by all subresources, None if they are in different states
+    pub fn resource_state(&self) -> Option<ResourceState> {
+        let subresource_states = self.subresource_states.lock();
+        let state = subresource_states[0];
+        subresource_states
+            .iter()
+            .all(|subresource_state| *subresource_state == state)
+            .then_some(state)
+    }
+
+    /// Mip levels and array layers of `subresource_range` clamped to the image, resolves `REMAINING_*` counts
+    fn subresource_range_bounds(
+        &self,
+        subresource_range: &vk::ImageSubresourceRange,
+    ) -> (Range<u32>, Range<u32>) {
+        let mip_end = subresource_range.base_mip_level
+            + subresource_range.level_count.min(
+                self.mip_levels
+                    .saturating_sub(subresource_range.base_mip_level),
+            );
+        let layer_end = subresource_range.base_array_layer
+            + subresource_range.layer_count.min(
+                self.array_layers
+                    .saturating_sub(subresource_range.base_array_layer),
+            );
+
+        (
+            subresource_range.base_mip_level..mip_end,
+            subresource_range.base_array_layer..layer_end,
+        )
+    }
+
+    /// Splits `subresource_range` into ranges of subresources in the same tracked state. The whole range is returned
+    /// if all subresources share a state, otherwise consecutive mip levels of every layer are merged.
+    pub(crate) fn subresource_state_ranges(
+        &self,
+        subresource_range: &vk::ImageSubresourceRange,
+    ) -> Vec<(vk::ImageSubresourceRange, ResourceState)> {
+        let (mips, layers) = self.subresource_range_bounds(subresource_range);
+        subresource_state_ranges(
+            &self.subresource_states.lock(),
+            self.mip_levels,
+            mips,
+            layers,
+            subresource_range,
+        )
+    }
+
+    pub(crate) fn set_subresource_state(
+        &self,
+        subresource_range: &vk::ImageSubresourceRange,
+        state: ResourceState,
+    ) {
+        let (mips, layers) = self.subresource_range_bounds(subresource_range);
+        let mut subresource_states = self.subresource_states.lock();
+        for layer in layers {
+            for mip in mips.clone() {
+                subresource_states[(layer * self.mip_levels + mip) as usize] = state;
+            }
+        }
+    }
+
+    pub fn is_cube_map(&self) -> bool {
+        self.flags.contains(vk::ImageCreateFlags::CUBE_COMPATIBLE)
+    }
+
+    pub fn view_type(&self) -> vk::ImageViewType {
+        self.view_type
+    }
+
+    pub fn image_type(&self) -> vk::ImageType {
+        self.image_type
+    }
+
+    pub fn flags(&self) -> vk::ImageCreateFlags {
+        self.flags
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.extent.depth
+    }
+
     pub fn aspect_mask(&self) -> vk::ImageAspectFlags {
         self.subresource_range.aspect_mask
     }
+
+    /// Buffer/image copies can only address a single aspect, depth takes priority over stencil
+    pub fn copy_aspect_mask(&self) -> vk::ImageAspectFlags {
+        let aspect_mask = self.aspect_mask();
+        if aspect_mask.contains(vk::ImageAspectFlags::DEPTH) {
+            vk::ImageAspectFlags::DEPTH
+        } else {
+            aspect_mask
+        }
+    }
+
+    pub fn has_stencil(&self) -> bool {
+        self.aspect_mask().contains(vk::ImageAspectFlags::STENCIL)
+    }
+}
+
+/// View of an image with a custom format, mip range or layer slice. The view keeps its image alive, the raw view is
+/// cached by the image like the views of `Image::subresource_view`.
+pub struct ImageView {
+    raw: vk::ImageView,
+    image: Handle<Image>,
+    view_type: vk::ImageViewType,
+    format: vk::Format,
+    subresource_range: vk::ImageSubresourceRange,
+}
+
+impl ImageView {
+    pub(crate) fn create(desc: ImageViewDesc) -> Result<Self> {
+        let image = desc.image;
+        let range = desc.subresource_range;
+
+        let raw = image.cached_view(
+            ImageSubresourceViewDesc {
+                view_type: desc.view_type,
+                base_mip_level: range.base_mip_level,
+                mip_level_count: range.level_count,
+                base_array_layer: range.base_array_layer,
+                array_layer_count: range.layer_count,
+            },
+            desc.format,
+            range.aspect_mask,
+        )?;
+
+        Ok(Self {
+            raw,
+            image,
+            view_type: desc.view_type,
+            format: desc.format,
+            subresource_range: range,
+        })
+    }
+
+    pub fn raw(&self) -> vk::ImageView {
+        self.raw
+    }
+
+    pub fn image(&self) -> &Handle<Image> {
+        &self.image
+    }
+
+    pub fn view_type(&self) -> vk::ImageViewType {
+        self.view_type
+    }
+
+    pub fn format(&self) -> vk::Format {
+        self.format
+    }
+
+    pub fn subresource_range(&self) -> vk::ImageSubresourceRange {
+        self.subresource_range
+    }
+
+    /// Extent of the first mip level of the view
+    pub fn extent(&self) -> vk::Extent3D {
+        self.image.mip_extent(self.subresource_range.base_mip_level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn color_range(mips: Range<u32>, layers: Range<u32>) -> vk::ImageSubresourceRange {
+        vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: mips.start,
+            level_count: mips.len() as u32,
+            base_array_layer: layers.start,
+            layer_count: layers.len() as u32,
+        }
+    }
+
+    #[test]
+    fn test_subresource_state_ranges_uniform() {
+        let states = [ResourceState::SHADER_RESOURCE; 6];
+        let range = color_range(0..3, 0..2);
+
+        let ranges = subresource_state_ranges(&states, 3, 0..3, 0..2, &range);
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0].0, range);
+        assert_eq!(ranges[0].1, ResourceState::SHADER_RESOURCE);
+    }
+
+    #[test]
+    fn test_subresource_state_ranges_merges_mips_per_layer() {
+        // Layer 0: mips 0 and 1 copied to, mip 2 sampled. Layer 1: all sampled.
+        let states = [
+            ResourceState::COPY_DESTINATION,
+            ResourceState::COPY_DESTINATION,
+            ResourceState::SHADER_RESOURCE,
+            ResourceState::SHADER_RESOURCE,
+            ResourceState::SHADER_RESOURCE,
+            ResourceState::SHADER_RESOURCE,
+        ];
+        let range = color_range(0..3, 0..2);
+
+        let ranges = subresource_state_ranges(&states, 3, 0..3, 0..2, &range);
+        assert_eq!(
+            ranges,
+            vec![
+                (color_range(0..2, 0..1), ResourceState::COPY_DESTINATION),
+                (color_range(2..3, 0..1), ResourceState::SHADER_RESOURCE),
+                (color_range(0..3, 1..2), ResourceState::SHADER_RESOURCE),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_subresource_state_ranges_partial_range() {
+        let states = [
+            ResourceState::UNDEFINED,
+            ResourceState::COPY_DESTINATION,
+            ResourceState::SHADER_RESOURCE,
+            ResourceState::SHADER_RESOURCE,
+        ];
+        let range = color_range(1..4, 0..1);
+
+        let ranges = subresource_state_ranges(&states, 4, 1..4, 0..1, &range);
+        assert_eq!(
+            ranges,
+            vec![
+                (color_range(1..2, 0..1), ResourceState::COPY_DESTINATION),
+                (color_range(2..4, 0..1), ResourceState::SHADER_RESOURCE),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_subresource_state_ranges_empty() {
+        let states = [ResourceState::UNDEFINED; 2];
+        let range = color_range(0..0, 0..1);
+
+        assert!(subresource_state_ranges(&states, 2, 0..0, 0..1, &range).is_empty());
+    }
 }
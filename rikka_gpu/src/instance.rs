@@ -14,10 +14,12 @@ pub struct Instance {
     debug_utils: DebugUtils,
     debug_utils_messenger: vk::DebugUtilsMessengerEXT,
     entry: ash::Entry,
+    swapchain_colorspace_supported: bool,
 }
 
 impl Instance {
-    pub fn new(display_handle: &dyn HasRawDisplayHandle) -> Result<Self> {
+    /// Surface extensions are only enabled when a display handle is provided.
+    pub fn new(display_handle: Option<&dyn HasRawDisplayHandle>) -> Result<Self> {
         let entry = unsafe { ash::Entry::load()? };
 
         // Create vulkan instance.
@@ -26,11 +28,28 @@ impl Instance {
             .application_name(app_name.as_c_str())
             .api_version(vk::API_VERSION_1_3);
 
-        let mut extension_names =
-            ash_window::enumerate_required_extensions(display_handle.raw_display_handle())?
-                .to_vec();
+        let mut extension_names = match display_handle {
+            Some(display_handle) => {
+                ash_window::enumerate_required_extensions(display_handle.raw_display_handle())?
+                    .to_vec()
+            }
+            None => Vec::new(),
+        };
         extension_names.push(DebugUtils::name().as_ptr());
 
+        // Required for HDR/wide gamut swapchain color spaces
+        let swapchain_colorspace_supported = display_handle.is_some()
+            && entry
+                .enumerate_instance_extension_properties(None)?
+                .iter()
+                .any(|extension| {
+                    let name = unsafe { CStr::from_ptr(extension.extension_name.as_ptr()) };
+                    name == vk::ExtSwapchainColorspaceFn::name()
+                });
+        if swapchain_colorspace_supported {
+            extension_names.push(vk::ExtSwapchainColorspaceFn::name().as_ptr());
+        }
+
         let layer_strings = vec![CString::new("VK_LAYER_KHRONOS_validation").unwrap()];
         let layer_names: Vec<*const i8> =
             layer_strings.iter().map(|c_str| c_str.as_ptr()).collect();
@@ -76,6 +95,7 @@ impl Instance {
             instance,
             debug_utils,
             debug_utils_messenger,
+            swapchain_colorspace_supported,
         })
     }
 
@@ -87,13 +107,17 @@ impl Instance {
         &self.entry
     }
 
-    pub fn get_physical_devices(&self, surface: &Surface) -> Result<Vec<PhysicalDevice>> {
+    pub fn swapchain_colorspace_supported(&self) -> bool {
+        self.swapchain_colorspace_supported
+    }
+
+    pub fn get_physical_devices(&self, surface: Option<&Surface>) -> Result<Vec<PhysicalDevice>> {
         let physical_devices = unsafe { self.instance.enumerate_physical_devices()? };
 
         let physical_devices = physical_devices
             .into_iter()
             .map(|phys_device| {
-                PhysicalDevice::new_from_vulkan_handle(&self.instance, &surface, phys_device)
+                PhysicalDevice::new_from_vulkan_handle(&self.instance, surface, phys_device)
             })
             .collect::<Result<Vec<_>>>()?;
 
@@ -3,11 +3,18 @@ pub use rikka_shader;
 pub mod barriers;
 pub mod buffer;
 pub mod command_buffer;
+pub mod compute_pipeline;
+pub mod crash_diagnostics;
 pub mod descriptor_set;
+pub mod error;
 pub mod escape;
+pub mod event;
 pub mod gpu;
 pub mod image;
 pub mod pipeline;
+pub mod profiling;
+pub mod query;
+pub mod ray_tracing;
 pub mod sampler;
 pub mod shader_state;
 pub mod types;
@@ -22,8 +29,8 @@ mod frame;
 mod instance;
 mod mesh_shader;
 mod physical_device;
-mod query;
 mod queue;
 mod surface;
 mod swapchain;
 mod synchronization;
+mod upload;
@@ -1,17 +1,37 @@
-use rikka_core::ash::extensions::nv::MeshShader;
+use rikka_core::ash::extensions::{ext, nv};
 
-use crate::factory::DeviceGuard;
+use crate::{factory::DeviceGuard, types::MeshShaderExtension};
+
+pub enum MeshShaderFunctions {
+    Ext(ext::MeshShader),
+    Nv(nv::MeshShader),
+}
 
 pub struct MeshShaderContext {
-    pub functions: MeshShader,
+    /// None if the device does not support mesh shaders
+    pub functions: Option<MeshShaderFunctions>,
     pub device: DeviceGuard,
 }
 
 impl MeshShaderContext {
     pub fn new(device: DeviceGuard) -> Self {
-        Self {
-            functions: MeshShader::new(device.instance().raw(), device.raw()),
-            device,
-        }
+        let functions =
+            match device.features().mesh_shader {
+                Some(MeshShaderExtension::Ext) => Some(MeshShaderFunctions::Ext(
+                    ext::MeshShader::new(device.instance().raw(), device.raw()),
+                )),
+                Some(MeshShaderExtension::Nv) => Some(MeshShaderFunctions::Nv(
+                    nv::MeshShader::new(device.instance().raw(), device.raw()),
+                )),
+                None => None,
+            };
+
+        Self { functions, device }
+    }
+
+    pub fn functions(&self) -> &MeshShaderFunctions {
+        self.functions
+            .as_ref()
+            .expect("Mesh shaders are not supported by the device!")
     }
 }
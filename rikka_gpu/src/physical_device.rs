@@ -6,7 +6,31 @@ use rikka_core::{
     vk::{self, QUEUE_FAMILY_EXTERNAL},
 };
 
-use crate::{queue::QueueFamily, surface::Surface};
+use crate::{
+    queue::QueueFamily,
+    surface::Surface,
+    types::{DeviceFeatures, MeshShaderExtension},
+};
+
+pub const MESH_SHADER_EXT_EXTENSION: &str = "VK_EXT_mesh_shader";
+pub const MESH_SHADER_NV_EXTENSION: &str = "VK_NV_mesh_shader";
+pub const INDEX_TYPE_UINT8_EXTENSION: &str = "VK_EXT_index_type_uint8";
+pub const DIAGNOSTIC_CHECKPOINTS_EXTENSION: &str = "VK_NV_device_diagnostic_checkpoints";
+pub const RAY_TRACING_EXTENSIONS: [&str; 3] = [
+    "VK_KHR_acceleration_structure",
+    "VK_KHR_ray_tracing_pipeline",
+    "VK_KHR_deferred_host_operations",
+];
+
+/// Block compressed formats textures may be transcoded to, support varies between vendors and platforms
+pub const COMPRESSED_TEXTURE_FORMATS: [vk::Format; 6] = [
+    vk::Format::BC7_UNORM_BLOCK,
+    vk::Format::BC7_SRGB_BLOCK,
+    vk::Format::ASTC_4X4_UNORM_BLOCK,
+    vk::Format::ASTC_4X4_SRGB_BLOCK,
+    vk::Format::ETC2_R8G8B8A8_UNORM_BLOCK,
+    vk::Format::ETC2_R8G8B8A8_SRGB_BLOCK,
+];
 
 #[derive(Debug, Clone)]
 pub struct PhysicalDevice {
@@ -20,12 +44,17 @@ pub struct PhysicalDevice {
     pub supported_extensions: Vec<String>,
     pub supported_surface_formats: Vec<vk::SurfaceFormatKHR>,
     pub supported_present_modes: Vec<vk::PresentModeKHR>,
+    /// Formats of `COMPRESSED_TEXTURE_FORMATS` that can be sampled with optimal tiling
+    pub supported_compressed_formats: Vec<vk::Format>,
+    pub features: DeviceFeatures,
+    /// Names of required features that are not supported, the device is unusable if not empty
+    pub missing_required_features: Vec<&'static str>,
 }
 
 impl PhysicalDevice {
     pub fn new_from_vulkan_handle(
         instance: &ash::Instance,
-        surface: &Surface,
+        surface: Option<&Surface>,
         physical_device: vk::PhysicalDevice,
     ) -> Result<Self> {
         let properties = unsafe { instance.get_physical_device_properties(physical_device) };
@@ -44,12 +73,15 @@ impl PhysicalDevice {
             .into_iter()
             .enumerate()
             .map(|(index, prop)| {
-                let present_support = unsafe {
-                    surface.raw().get_physical_device_surface_support(
-                        physical_device,
-                        index as _,
-                        surface.raw_vulkan(),
-                    )?
+                let present_support = match surface {
+                    Some(surface) => unsafe {
+                        surface.raw().get_physical_device_surface_support(
+                            physical_device,
+                            index as _,
+                            surface.raw_vulkan(),
+                        )?
+                    },
+                    None => false,
                 };
                 Ok(QueueFamily::new(index as _, prop, present_support))
             })
@@ -63,18 +95,38 @@ impl PhysicalDevice {
                 let name = unsafe { CStr::from_ptr(prop.extension_name.as_ptr()) };
                 name.to_str().unwrap().to_owned()
             })
-            .collect();
+            .collect::<Vec<_>>();
 
-        let supported_surface_formats = unsafe {
-            surface
-                .raw()
-                .get_physical_device_surface_formats(physical_device, surface.raw_vulkan())?
-        };
+        let (features, missing_required_features) =
+            query_device_features(instance, physical_device, &supported_extensions);
 
-        let supported_present_modes = unsafe {
-            surface
-                .raw()
-                .get_physical_device_surface_present_modes(physical_device, surface.raw_vulkan())?
+        let supported_compressed_formats = COMPRESSED_TEXTURE_FORMATS
+            .into_iter()
+            .filter(|format| {
+                let format_properties = unsafe {
+                    instance.get_physical_device_format_properties(physical_device, *format)
+                };
+                format_properties
+                    .optimal_tiling_features
+                    .contains(vk::FormatFeatureFlags::SAMPLED_IMAGE)
+            })
+            .collect();
+
+        let (supported_surface_formats, supported_present_modes) = match surface {
+            Some(surface) => unsafe {
+                (
+                    surface.raw().get_physical_device_surface_formats(
+                        physical_device,
+                        surface.raw_vulkan(),
+                    )?,
+                    surface.raw().get_physical_device_surface_present_modes(
+                        physical_device,
+                        surface.raw_vulkan(),
+                    )?,
+                )
+            },
+            // Headless
+            None => (Vec::new(), Vec::new()),
         };
 
         Ok(Self {
@@ -87,6 +139,9 @@ impl PhysicalDevice {
             supported_extensions,
             supported_surface_formats,
             supported_present_modes,
+            supported_compressed_formats,
+            features,
+            missing_required_features,
         })
     }
 
@@ -102,7 +157,119 @@ impl PhysicalDevice {
             .all(|ext| supported_extensions.contains(ext))
     }
 
+    pub fn supports_compressed_format(&self, format: vk::Format) -> bool {
+        self.supported_compressed_formats.contains(&format)
+    }
+
     pub fn raw(&self) -> vk::PhysicalDevice {
         self.physical_device
     }
 }
+
+fn query_device_features(
+    instance: &ash::Instance,
+    physical_device: vk::PhysicalDevice,
+    supported_extensions: &[String],
+) -> (DeviceFeatures, Vec<&'static str>) {
+    let supports = |extension: &str| supported_extensions.iter().any(|ext| ext == extension);
+
+    let has_mesh_shader_ext = supports(MESH_SHADER_EXT_EXTENSION);
+    let has_mesh_shader_nv = supports(MESH_SHADER_NV_EXTENSION);
+    let has_index_type_uint8 = supports(INDEX_TYPE_UINT8_EXTENSION);
+
+    let mut vulkan11_features = vk::PhysicalDeviceVulkan11Features::default();
+    let mut vulkan12_features = vk::PhysicalDeviceVulkan12Features::default();
+    let mut vulkan13_features = vk::PhysicalDeviceVulkan13Features::default();
+
+    // Extension feature structs can only be chained if the extension is supported
+    let mut mesh_shader_ext_features = vk::PhysicalDeviceMeshShaderFeaturesEXT::default();
+    let mut mesh_shader_nv_features = vk::PhysicalDeviceMeshShaderFeaturesNV::default();
+    let mut index_type_uint8_features = vk::PhysicalDeviceIndexTypeUint8FeaturesEXT::default();
+    {
+        let mut features2 = vk::PhysicalDeviceFeatures2::builder()
+            .push_next(&mut vulkan11_features)
+            .push_next(&mut vulkan12_features)
+            .push_next(&mut vulkan13_features);
+        if has_mesh_shader_ext {
+            features2 = features2.push_next(&mut mesh_shader_ext_features);
+        }
+        if has_mesh_shader_nv {
+            features2 = features2.push_next(&mut mesh_shader_nv_features);
+        }
+        if has_index_type_uint8 {
+            features2 = features2.push_next(&mut index_type_uint8_features);
+        }
+        unsafe { instance.get_physical_device_features2(physical_device, &mut features2) };
+    }
+    let core_features = unsafe { instance.get_physical_device_features(physical_device) };
+
+    let mesh_shader = if has_mesh_shader_ext
+        && mesh_shader_ext_features.mesh_shader == vk::TRUE
+        && mesh_shader_ext_features.task_shader == vk::TRUE
+    {
+        Some(MeshShaderExtension::Ext)
+    } else if has_mesh_shader_nv
+        && mesh_shader_nv_features.mesh_shader == vk::TRUE
+        && mesh_shader_nv_features.task_shader == vk::TRUE
+    {
+        Some(MeshShaderExtension::Nv)
+    } else {
+        None
+    };
+
+    let buffer_device_address = vulkan12_features.buffer_device_address == vk::TRUE;
+    let bindless = vulkan12_features.descriptor_indexing == vk::TRUE
+        && vulkan12_features.runtime_descriptor_array == vk::TRUE
+        && vulkan12_features.descriptor_binding_partially_bound == vk::TRUE
+        && vulkan12_features.descriptor_binding_variable_descriptor_count == vk::TRUE
+        && vulkan12_features.descriptor_binding_sampled_image_update_after_bind == vk::TRUE
+        && vulkan12_features.descriptor_binding_storage_image_update_after_bind == vk::TRUE
+        && vulkan12_features.shader_sampled_image_array_non_uniform_indexing == vk::TRUE;
+
+    let features = DeviceFeatures {
+        mesh_shader,
+        ray_tracing: buffer_device_address
+            && RAY_TRACING_EXTENSIONS.iter().all(|ext| supports(ext)),
+        buffer_device_address,
+        draw_indirect_count: vulkan12_features.draw_indirect_count == vk::TRUE,
+        bindless,
+        wide_lines: core_features.wide_lines == vk::TRUE,
+        fill_mode_non_solid: core_features.fill_mode_non_solid == vk::TRUE,
+        index_type_uint8: has_index_type_uint8
+            && index_type_uint8_features.index_type_uint8 == vk::TRUE,
+        pipeline_statistics_query: core_features.pipeline_statistics_query == vk::TRUE,
+        diagnostic_checkpoints: supports(DIAGNOSTIC_CHECKPOINTS_EXTENSION),
+    };
+
+    // XXX: Bindless is required until there is a non bindless path for material textures
+    let required_features = [
+        (
+            "shader_draw_parameters",
+            vulkan11_features.shader_draw_parameters == vk::TRUE,
+        ),
+        (
+            "timeline_semaphore",
+            vulkan12_features.timeline_semaphore == vk::TRUE,
+        ),
+        (
+            "host_query_reset",
+            vulkan12_features.host_query_reset == vk::TRUE,
+        ),
+        (
+            "dynamic_rendering",
+            vulkan13_features.dynamic_rendering == vk::TRUE,
+        ),
+        (
+            "synchronization2",
+            vulkan13_features.synchronization2 == vk::TRUE,
+        ),
+        ("bindless", bindless),
+    ];
+    let missing_required_features = required_features
+        .into_iter()
+        .filter(|(_, supported)| !supported)
+        .map(|(name, _)| name)
+        .collect();
+
+    (features, missing_required_features)
+}
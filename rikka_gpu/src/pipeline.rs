@@ -3,28 +3,26 @@ use rikka_core::vk;
 
 use crate::{constants, descriptor_set::*, escape::*, factory::*, shader_state::*, types::*};
 
+#[derive(Clone)]
 pub struct GraphicsPipelineDesc {
     pub vertex_input_state: VertexInputState,
     pub rasterization_state: RasterizationState,
     pub depth_stencil_state: DepthStencilState,
     pub blend_states: Vec<BlendState>,
     pub primitive_topology: vk::PrimitiveTopology,
+    pub dynamic_states: Vec<vk::DynamicState>,
 
     // XXX: Is this required?
     pub rendering_state: RenderingState,
 
-    pub vertex_const_size: Option<u32>,
-    pub fragment_const_size: Option<u32>,
+    pub vertex_constants: Option<PushConstantLayout>,
+    pub fragment_constants: Option<PushConstantLayout>,
 
     // pub shader_stages: Vec<vk::PipelineShaderStageCreateInfo>,
     pub shader_state: ShaderStateDesc,
-
     // XXX: Need a handle to the primary type `DescriptorSetLayout` here?
     // pub descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
 
-    // XXX: Handle to viewport state required?
-    pub width: u32,
-    pub height: u32,
     // XXX: pipeline cache somewhere? or handle this completely internally?
 }
 
@@ -36,15 +34,13 @@ impl GraphicsPipelineDesc {
             depth_stencil_state: DepthStencilState::new(),
             blend_states: vec![],
             primitive_topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            dynamic_states: vec![],
             // XXX: Only need formats for this, maybe use a simpler version of this structure?
             rendering_state: RenderingState::new_dimensionless(),
-            vertex_const_size: None,
-            fragment_const_size: None,
+            vertex_constants: None,
+            fragment_constants: None,
             // shader_stages: vec![],
             // descriptor_set_layouts: vec![],
-            width: 1,
-            height: 1,
-
             shader_state: ShaderStateDesc::new(),
         }
     }
@@ -59,12 +55,6 @@ impl GraphicsPipelineDesc {
         self
     }
 
-    pub fn set_extent(mut self, width: u32, height: u32) -> Self {
-        self.width = width;
-        self.height = height;
-        self
-    }
-
     pub fn set_rasterization_state(mut self, rasterization_state: RasterizationState) -> Self {
         self.rasterization_state = rasterization_state;
         self
@@ -80,6 +70,47 @@ impl GraphicsPipelineDesc {
         self
     }
 
+    /// Either none or one per color attachment
+    pub fn add_blend_state(mut self, blend_state: BlendState) -> Self {
+        self.blend_states.push(blend_state);
+        self
+    }
+
+    pub fn set_primitive_topology(mut self, primitive_topology: vk::PrimitiveTopology) -> Self {
+        self.primitive_topology = primitive_topology;
+        self
+    }
+
+    pub fn set_vertex_const_size(mut self, vertex_const_size: u32) -> Self {
+        self.vertex_constants = Some(PushConstantLayout::from_size(vertex_const_size));
+        self
+    }
+
+    pub fn set_fragment_const_size(mut self, fragment_const_size: u32) -> Self {
+        self.fragment_constants = Some(PushConstantLayout::from_size(fragment_const_size));
+        self
+    }
+
+    /// Vertex push constants of the `#[repr(C)]` struct `T`, validated against the vertex shader when the pipeline is
+    /// created
+    pub fn set_vertex_constants<T: Copy>(mut self) -> Self {
+        self.vertex_constants = Some(PushConstantLayout::of::<T>());
+        self
+    }
+
+    /// Fragment push constants of the `#[repr(C)]` struct `T`, validated against the fragment shader when the pipeline
+    /// is created
+    pub fn set_fragment_constants<T: Copy>(mut self) -> Self {
+        self.fragment_constants = Some(PushConstantLayout::of::<T>());
+        self
+    }
+
+    /// Viewport and scissor are always dynamic
+    pub fn add_dynamic_state(mut self, dynamic_state: vk::DynamicState) -> Self {
+        self.dynamic_states.push(dynamic_state);
+        self
+    }
+
     // Not used as shader and descriptor layout information is obtained through shader reflection.
     // pub fn set_shader_stages(
     //     mut self,
@@ -106,11 +137,59 @@ impl GraphicsPipelineDesc {
     // }
 }
 
+/// Shared by pipelines with identical set layouts and push constant ranges through the `Factory` layout cache
+pub struct PipelineLayout {
+    device: DeviceGuard,
+    raw: vk::PipelineLayout,
+    // Kept alive so the raw set layout handles in the cache key are not reused
+    descriptor_set_layouts: Vec<Handle<DescriptorSetLayout>>,
+}
+
+impl PipelineLayout {
+    pub(crate) unsafe fn create(
+        device: DeviceGuard,
+        descriptor_set_layouts: Vec<Handle<DescriptorSetLayout>>,
+        push_constant_ranges: &[vk::PushConstantRange],
+    ) -> Result<Self> {
+        let vulkan_descriptor_set_layouts = descriptor_set_layouts
+            .iter()
+            .map(|layout| layout.raw())
+            .collect::<Vec<_>>();
+
+        let pipeline_layout_info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&vulkan_descriptor_set_layouts)
+            .push_constant_ranges(push_constant_ranges);
+
+        let raw = device
+            .raw()
+            .create_pipeline_layout(&pipeline_layout_info, None)
+            .context("Failed to create vulkan pipeline layout!")?;
+
+        Ok(Self {
+            device,
+            raw,
+            descriptor_set_layouts,
+        })
+    }
+
+    pub(crate) unsafe fn destroy(self) {
+        self.device.raw().destroy_pipeline_layout(self.raw, None);
+    }
+
+    pub fn raw(&self) -> vk::PipelineLayout {
+        self.raw
+    }
+
+    pub fn descriptor_set_layouts(&self) -> &[Handle<DescriptorSetLayout>] {
+        &self.descriptor_set_layouts
+    }
+}
+
 pub struct GraphicsPipeline {
     device: DeviceGuard,
 
     raw: vk::Pipeline,
-    raw_layout: vk::PipelineLayout,
+    layout: Handle<PipelineLayout>,
 
     // XXX: Do we need this?
     desc: GraphicsPipelineDesc,
@@ -125,7 +204,7 @@ impl GraphicsPipeline {
         desc: GraphicsPipelineDesc,
     ) -> Result<Self> {
         // Create shader modules
-        let shader_state = ShaderState::new(device.clone(), desc.shader_state.clone())?;
+        let shader_state = factory.get_or_create_shader_state(&desc.shader_state)?;
 
         // Create descriptor set layouts
         let descriptor_sets = &shader_state.reflection().descriptor_sets;
@@ -135,27 +214,13 @@ impl GraphicsPipeline {
             // XXX: Make this bindless texture array check nicer
             //      Need Gpu class for this to work... use shared bindless texture layout for all pipelines
             if set.bindings[0].index == constants::BINDLESS_SET_SAMPLED_IMAGE_INDEX {
-                let bindless_descriptor_set_layout_desc = DescriptorSetLayoutDesc::new()
-                    .set_flags(vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL)
-                    .set_bindless(true)
-                    .add_binding(DescriptorBinding::new(
-                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-                        constants::BINDLESS_SET_SAMPLED_IMAGE_INDEX,
-                        constants::MAX_NUM_BINDLESS_RESOURCECS,
-                        vk::ShaderStageFlags::FRAGMENT,
-                    ))
-                    .add_binding(DescriptorBinding::new(
-                        vk::DescriptorType::STORAGE_IMAGE,
-                        constants::BINDLESS_SET_STORAGE_IMAGE_INDEX,
-                        constants::MAX_NUM_BINDLESS_RESOURCECS,
-                        vk::ShaderStageFlags::FRAGMENT,
-                    ));
-                layout_descs.push(bindless_descriptor_set_layout_desc);
+                layout_descs.push(DescriptorSetLayoutDesc::new_bindless());
                 continue;
             }
 
             let layout_desc = DescriptorSetLayoutDesc::new()
                 .set_bindings(set.bindings.clone())
+                .set_binding_names(set.binding_names.clone())
                 .set_bindless(false)
                 .set_dynamic(false);
             layout_descs.push(layout_desc);
@@ -163,52 +228,39 @@ impl GraphicsPipeline {
 
         let descriptor_set_layouts = layout_descs
             .into_iter()
-            .map(|desc| {
-                Ok(Handle::new_no_guard(
-                    factory.create_descriptor_set_layout(desc)?,
-                ))
-            })
+            .map(|desc| factory.get_or_create_descriptor_set_layout(desc))
             .collect::<Result<Vec<_>>>()?;
 
-        let vulkan_descriptor_set_layouts = descriptor_set_layouts
-            .iter()
-            .map(|layout| layout.raw())
-            .collect::<Vec<_>>();
-
-        // XXX: Read layout from cache
-
         let push_constant_ranges = {
             let mut push_constant_ranges = Vec::<vk::PushConstantRange>::new();
-            if desc.vertex_const_size.is_some() {
-                push_constant_ranges.push(
-                    vk::PushConstantRange::builder()
-                        .stage_flags(vk::ShaderStageFlags::VERTEX)
-                        .offset(0)
-                        .size(desc.vertex_const_size.unwrap())
-                        .build(),
-                );
-            }
-            if desc.fragment_const_size.is_some() {
-                push_constant_ranges.push(
-                    vk::PushConstantRange::builder()
-                        .stage_flags(vk::ShaderStageFlags::FRAGMENT)
-                        .offset(0)
-                        .size(desc.fragment_const_size.unwrap())
-                        .build(),
-                );
+            let mut undeclared_stages = vk::ShaderStageFlags::empty();
+            for (stage, push_constants) in [
+                (vk::ShaderStageFlags::VERTEX, desc.vertex_constants),
+                (vk::ShaderStageFlags::FRAGMENT, desc.fragment_constants),
+            ] {
+                match push_constants {
+                    Some(push_constants) => {
+                        push_constants
+                            .validate(shader_state.reflection(), stage)
+                            .context("Graphics pipeline push constants do not match the shader")?;
+                        push_constant_ranges.push(
+                            vk::PushConstantRange::builder()
+                                .stage_flags(stage)
+                                .offset(0)
+                                .size(push_constants.size)
+                                .build(),
+                        );
+                    }
+                    None => undeclared_stages |= stage,
+                }
             }
+            validate_undeclared_push_constants(shader_state.reflection(), undeclared_stages)?;
 
             push_constant_ranges
         };
 
-        let pipeline_layout_info = vk::PipelineLayoutCreateInfo::builder()
-            .set_layouts(&vulkan_descriptor_set_layouts)
-            .push_constant_ranges(&push_constant_ranges);
-
-        let pipeline_layout = device
-            .raw()
-            .create_pipeline_layout(&pipeline_layout_info, None)
-            .context("Failed to create vulkan pipeline layout!")?;
+        let layout = factory
+            .get_or_create_pipeline_layout(&descriptor_set_layouts, &push_constant_ranges)?;
 
         // Create vulkan pipeline
 
@@ -244,26 +296,10 @@ impl GraphicsPipeline {
             .topology(desc.primitive_topology)
             .primitive_restart_enable(false);
 
-        let viewports = [vk::Viewport::builder()
-            .x(0.0)
-            .y(0.0)
-            .width(desc.width as f32)
-            .height(desc.height as f32)
-            .min_depth(0.0)
-            .max_depth(1.0)
-            .build()];
-        let scissors = [vk::Rect2D::builder()
-            .offset(vk::Offset2D { x: 0, y: 0 })
-            .extent(vk::Extent2D {
-                width: desc.width,
-                height: desc.height,
-            })
-            .build()];
-
-        // XXX: Add dynamic viewport(to handle window resizing)
+        // Viewport and scissor are set when recording, pipelines do not need to be recreated on resize
         let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
-            .viewports(&viewports)
-            .scissors(&scissors);
+            .viewport_count(1)
+            .scissor_count(1);
 
         let color_blend_attachments = {
             if !desc.blend_states.is_empty() {
@@ -321,6 +357,9 @@ impl GraphicsPipeline {
             .depth_test_enable(desc.depth_stencil_state.depth_test_enable)
             .depth_write_enable(desc.depth_stencil_state.depth_write_enable)
             .depth_compare_op(desc.depth_stencil_state.depth_compare)
+            .stencil_test_enable(desc.depth_stencil_state.stencil_test_enable)
+            .front(desc.depth_stencil_state.stencil_front.vk_stencil_op_state())
+            .back(desc.depth_stencil_state.stencil_back.vk_stencil_op_state())
             .depth_bounds_test_enable(false)
             .min_depth_bounds(0.0)
             .max_depth_bounds(0.0);
@@ -330,17 +369,40 @@ impl GraphicsPipeline {
             .sample_shading_enable(false)
             .min_sample_shading(1.0);
 
-        let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+        let line_width =
+            if desc.rasterization_state.line_width != 1.0 && !device.features().wide_lines {
+                log::warn!(
+                    "Line width {} requested but wide lines are not supported, using 1.0",
+                    desc.rasterization_state.line_width
+                );
+                1.0
+            } else {
+                desc.rasterization_state.line_width
+            };
+
+        let mut rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
             .polygon_mode(desc.rasterization_state.polygon_mode)
             .cull_mode(desc.rasterization_state.cull_mode)
             .front_face(desc.rasterization_state.front_face)
-            .line_width(1.0)
+            .line_width(line_width)
             .depth_bias_enable(false)
             .depth_clamp_enable(false);
+        if let Some(depth_bias) = desc.rasterization_state.depth_bias {
+            rasterization_state = rasterization_state
+                .depth_bias_enable(true)
+                .depth_bias_constant_factor(depth_bias.constant_factor)
+                .depth_bias_slope_factor(depth_bias.slope_factor)
+                .depth_bias_clamp(depth_bias.clamp);
+        }
 
-        // let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
-        // let dynamic_state =
-        //     vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+        let mut dynamic_states = vec![vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        for dynamic_state in &desc.dynamic_states {
+            if !dynamic_states.contains(dynamic_state) {
+                dynamic_states.push(*dynamic_state);
+            }
+        }
+        let dynamic_state =
+            vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
 
         // XXX: Tesselation state?
 
@@ -357,7 +419,12 @@ impl GraphicsPipeline {
                 Some(depth_attachment) => depth_attachment.format,
                 None => vk::Format::UNDEFINED,
             })
-            .stencil_attachment_format(vk::Format::UNDEFINED);
+            .stencil_attachment_format(match desc.rendering_state.depth_attachment {
+                Some(depth_attachment) if format_has_stencil(depth_attachment.format) => {
+                    depth_attachment.format
+                }
+                _ => vk::Format::UNDEFINED,
+            });
 
         let pipeline_info = vk::GraphicsPipelineCreateInfo::builder()
             .stages(&shader_state.vulkan_shader_stages())
@@ -368,8 +435,8 @@ impl GraphicsPipeline {
             .depth_stencil_state(&depth_stencil_state)
             .multisample_state(&multisample_state)
             .rasterization_state(&rasterization_state)
-            // .dynamic_state(&dynamic_state)
-            .layout(pipeline_layout)
+            .dynamic_state(&dynamic_state)
+            .layout(layout.raw())
             .push_next(&mut pipeline_rendering_info)
             .build();
 
@@ -384,7 +451,7 @@ impl GraphicsPipeline {
 
         Ok(Self {
             raw,
-            raw_layout: pipeline_layout,
+            layout,
             desc,
             device,
             descriptor_set_layouts,
@@ -393,9 +460,6 @@ impl GraphicsPipeline {
 
     pub unsafe fn destroy(self) {
         self.device.raw().destroy_pipeline(self.raw, None);
-        self.device
-            .raw()
-            .destroy_pipeline_layout(self.raw_layout, None);
     }
 
     pub fn raw(&self) -> vk::Pipeline {
@@ -403,7 +467,7 @@ impl GraphicsPipeline {
     }
 
     pub fn raw_layout(&self) -> vk::PipelineLayout {
-        self.raw_layout
+        self.layout.raw()
     }
 
     pub fn descriptor_set_layouts(&self) -> &[Handle<DescriptorSetLayout>] {
@@ -0,0 +1,101 @@
+//! Optional Tracy instrumentation, everything compiles to no-ops without the `profiling` feature.
+
+use crate::query::GpuTimestamp;
+
+/// Cpu zone that ends when dropped
+pub struct ProfileSpan {
+    #[cfg(feature = "profiling")]
+    _span: tracy_client::Span,
+}
+
+#[track_caller]
+#[inline]
+pub fn span(name: &str) -> ProfileSpan {
+    #[cfg(feature = "profiling")]
+    {
+        let location = std::panic::Location::caller();
+        ProfileSpan {
+            _span: tracy_client::Client::start().span_alloc(
+                Some(name),
+                "",
+                location.file(),
+                location.line(),
+                0,
+            ),
+        }
+    }
+    #[cfg(not(feature = "profiling"))]
+    {
+        let _ = name;
+        ProfileSpan {}
+    }
+}
+
+#[inline]
+pub fn frame_mark() {
+    #[cfg(feature = "profiling")]
+    tracy_client::Client::start().frame_mark();
+}
+
+/// Forwards resolved timestamp scopes to Tracy as Gpu zones
+pub(crate) struct GpuProfiler {
+    #[cfg(feature = "profiling")]
+    context: Option<tracy_client::GpuContext>,
+}
+
+impl GpuProfiler {
+    pub(crate) fn new(timestamp_period: f32) -> Self {
+        #[cfg(feature = "profiling")]
+        {
+            // XXX: The Gpu timeline is not calibrated against the Cpu clock, query an initial Gpu timestamp
+            let context = tracy_client::Client::start()
+                .new_gpu_context(
+                    Some("graphics"),
+                    tracy_client::GpuContextType::Vulkan,
+                    0,
+                    timestamp_period,
+                )
+                .map_err(|err| log::warn!("Failed to create Tracy Gpu context: {:?}", err))
+                .ok();
+            Self { context }
+        }
+        #[cfg(not(feature = "profiling"))]
+        {
+            let _ = timestamp_period;
+            Self {}
+        }
+    }
+
+    /// `timestamps` need to be in the order their scopes were started
+    pub(crate) fn submit(&self, timestamps: &[GpuTimestamp]) {
+        #[cfg(feature = "profiling")]
+        if let Some(context) = &self.context {
+            // Zones are ended after their nested zones
+            let mut open_spans: Vec<(tracy_client::GpuSpan, &GpuTimestamp)> = Vec::new();
+            for timestamp in timestamps {
+                while open_spans
+                    .last()
+                    .map_or(false, |(_, open)| open.depth >= timestamp.depth)
+                {
+                    let (span, open) = open_spans.pop().unwrap();
+                    Self::end_span(span, open);
+                }
+
+                if let Ok(span) = context.span_alloc(&timestamp.name, "", file!(), line!()) {
+                    open_spans.push((span, timestamp));
+                }
+            }
+            while let Some((span, open)) = open_spans.pop() {
+                Self::end_span(span, open);
+            }
+        }
+        #[cfg(not(feature = "profiling"))]
+        let _ = timestamps;
+    }
+
+    #[cfg(feature = "profiling")]
+    fn end_span(mut span: tracy_client::GpuSpan, timestamp: &GpuTimestamp) {
+        span.end_zone();
+        span.upload_timestamp(timestamp.start_ticks as i64, timestamp.end_ticks as i64);
+    }
+}
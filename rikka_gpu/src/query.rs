@@ -1,7 +1,35 @@
 use anyhow::Result;
+use parking_lot::Mutex;
 use rikka_core::vk;
 
-use crate::factory::DeviceGuard;
+use crate::{constants, error::GpuError, factory::DeviceGuard};
+
+/// Resolved Gpu time of a timestamp scope
+#[derive(Clone, Debug)]
+pub struct GpuTimestamp {
+    pub name: String,
+    /// Nesting level of the scope, 0 for top level scopes
+    pub depth: u32,
+    pub duration_ms: f32,
+    /// Raw timestamp values, in units of the device timestamp period
+    pub start_ticks: u64,
+    pub end_ticks: u64,
+}
+
+struct TimestampScope {
+    name: String,
+    depth: u32,
+    start_query: u32,
+    end_query: Option<u32>,
+}
+
+#[derive(Default)]
+struct TimestampScopes {
+    scopes: Vec<TimestampScope>,
+    /// Indices of scopes that are not yet closed
+    open_scopes: Vec<usize>,
+    next_query: u32,
+}
 
 pub struct TimestampQueryPool {
     device: DeviceGuard,
@@ -11,6 +39,8 @@ pub struct TimestampQueryPool {
     // Are these needed?
     time_queries_per_frame: u32,
     total_query_count: u32,
+
+    scopes: Mutex<TimestampScopes>,
 }
 
 impl TimestampQueryPool {
@@ -20,14 +50,104 @@ impl TimestampQueryPool {
             .query_count(time_queries_per_frame * 2);
 
         let query_pool = unsafe { device.raw().create_query_pool(&pool_info, None)? };
+        unsafe {
+            device
+                .raw()
+                .reset_query_pool(query_pool, 0, time_queries_per_frame * 2)
+        };
 
         Ok(Self {
             device,
             query_pool,
             time_queries_per_frame,
             total_query_count: time_queries_per_frame * 2,
+            scopes: Mutex::new(TimestampScopes::default()),
         })
     }
+
+    pub fn raw(&self) -> vk::QueryPool {
+        self.query_pool
+    }
+
+    /// Returns the query index to write the start timestamp to, None if the pool is full
+    pub(crate) fn push_scope(&self, name: &str) -> Option<u32> {
+        let mut scopes = self.scopes.lock();
+        if scopes.next_query + 2 > self.total_query_count {
+            return None;
+        }
+
+        let start_query = scopes.next_query;
+        scopes.next_query += 2;
+
+        let depth = scopes.open_scopes.len() as u32;
+        let index = scopes.scopes.len();
+        scopes.scopes.push(TimestampScope {
+            name: name.to_string(),
+            depth,
+            start_query,
+            end_query: None,
+        });
+        scopes.open_scopes.push(index);
+
+        Some(start_query)
+    }
+
+    /// Returns the query index to write the end timestamp to, None if the scope was not started
+    pub(crate) fn pop_scope(&self) -> Option<u32> {
+        let mut scopes = self.scopes.lock();
+        let index = scopes.open_scopes.pop()?;
+
+        let scope = &mut scopes.scopes[index];
+        let end_query = scope.start_query + 1;
+        scope.end_query = Some(end_query);
+
+        Some(end_query)
+    }
+
+    /// Reads back the timestamps of all closed scopes and resets the pool.
+    /// Must only be called once the Gpu work that wrote the timestamps has completed.
+    pub(crate) fn resolve(&self, timestamp_period: f32) -> Result<Vec<GpuTimestamp>> {
+        let mut scopes = self.scopes.lock();
+        if scopes.next_query == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut results = vec![0u64; scopes.next_query as usize];
+        unsafe {
+            self.device.raw().get_query_pool_results(
+                self.query_pool,
+                0,
+                scopes.next_query,
+                &mut results,
+                vk::QueryResultFlags::TYPE_64,
+            )?;
+            self.device
+                .raw()
+                .reset_query_pool(self.query_pool, 0, scopes.next_query);
+        }
+
+        let timestamps = scopes
+            .scopes
+            .iter()
+            .filter_map(|scope| {
+                let end_query = scope.end_query?;
+                let start_ticks = results[scope.start_query as usize];
+                let end_ticks = results[end_query as usize];
+                Some(GpuTimestamp {
+                    name: scope.name.clone(),
+                    depth: scope.depth,
+                    duration_ms: end_ticks.saturating_sub(start_ticks) as f32 * timestamp_period
+                        / 1_000_000.0,
+                    start_ticks,
+                    end_ticks,
+                })
+            })
+            .collect();
+
+        *scopes = TimestampScopes::default();
+
+        Ok(timestamps)
+    }
 }
 
 impl Drop for TimestampQueryPool {
@@ -36,40 +156,281 @@ impl Drop for TimestampQueryPool {
     }
 }
 
-pub struct PipelineStatsQueryPool {
+/// Pipeline statistics counted by `QueryType::PipelineStatistics` pools, in the order the results are written
+const PIPELINE_STATISTICS_FLAGS: vk::QueryPipelineStatisticFlags =
+    vk::QueryPipelineStatisticFlags::from_raw(
+        vk::QueryPipelineStatisticFlags::INPUT_ASSEMBLY_VERTICES.as_raw()
+            | vk::QueryPipelineStatisticFlags::INPUT_ASSEMBLY_PRIMITIVES.as_raw()
+            | vk::QueryPipelineStatisticFlags::VERTEX_SHADER_INVOCATIONS.as_raw()
+            | vk::QueryPipelineStatisticFlags::CLIPPING_INVOCATIONS.as_raw()
+            | vk::QueryPipelineStatisticFlags::CLIPPING_PRIMITIVES.as_raw()
+            | vk::QueryPipelineStatisticFlags::FRAGMENT_SHADER_INVOCATIONS.as_raw()
+            | vk::QueryPipelineStatisticFlags::COMPUTE_SHADER_INVOCATIONS.as_raw(),
+    );
+const PIPELINE_STATISTICS_COUNT: usize = 7;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryType {
+    /// Samples passing the depth and stencil tests between `begin_query` and `end_query`
+    Occlusion,
+    /// Written with `write_timestamp`, in units of the device timestamp period
+    Timestamp,
+    /// Requires `DeviceFeatures::pipeline_statistics_query`
+    PipelineStatistics,
+}
+
+impl QueryType {
+    fn to_vk(self) -> vk::QueryType {
+        match self {
+            QueryType::Occlusion => vk::QueryType::OCCLUSION,
+            QueryType::Timestamp => vk::QueryType::TIMESTAMP,
+            QueryType::PipelineStatistics => vk::QueryType::PIPELINE_STATISTICS,
+        }
+    }
+
+    fn values_per_query(self) -> usize {
+        match self {
+            QueryType::PipelineStatistics => PIPELINE_STATISTICS_COUNT,
+            _ => 1,
+        }
+    }
+}
+
+pub struct QueryPoolDesc {
+    pub query_type: QueryType,
+    /// Queries available per frame
+    pub query_count: u32,
+}
+
+impl QueryPoolDesc {
+    pub fn new(query_type: QueryType) -> Self {
+        Self {
+            query_type,
+            query_count: 1,
+        }
+    }
+
+    pub fn set_query_count(mut self, query_count: u32) -> Self {
+        self.query_count = query_count;
+        self
+    }
+}
+
+/// Counters of a pipeline statistics query
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PipelineStatistics {
+    pub input_assembly_vertices: u64,
+    pub input_assembly_primitives: u64,
+    pub vertex_shader_invocations: u64,
+    pub clipping_invocations: u64,
+    pub clipping_primitives: u64,
+    pub fragment_shader_invocations: u64,
+    pub compute_shader_invocations: u64,
+}
+
+/// Query values read back from a completed frame
+#[derive(Clone, Debug)]
+pub struct QueryResults {
+    query_type: QueryType,
+    values: Vec<u64>,
+}
+
+impl QueryResults {
+    pub fn query_type(&self) -> QueryType {
+        self.query_type
+    }
+
+    /// Number of queries written in the frame
+    pub fn query_count(&self) -> u32 {
+        (self.values.len() / self.query_type.values_per_query()) as u32
+    }
+
+    /// Sample count of an occlusion query, ticks of a timestamp or the counters of a pipeline statistics query
+    pub fn query(&self, query: u32) -> &[u64] {
+        let values_per_query = self.query_type.values_per_query();
+        let start = query as usize * values_per_query;
+        &self.values[start..start + values_per_query]
+    }
+
+    pub fn pipeline_statistics(&self, query: u32) -> Option<PipelineStatistics> {
+        if self.query_type != QueryType::PipelineStatistics {
+            return None;
+        }
+
+        let values = self.query(query);
+        Some(PipelineStatistics {
+            input_assembly_vertices: values[0],
+            input_assembly_primitives: values[1],
+            vertex_shader_invocations: values[2],
+            clipping_invocations: values[3],
+            clipping_primitives: values[4],
+            fragment_shader_invocations: values[5],
+            compute_shader_invocations: values[6],
+        })
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct QueryFrame {
+    /// Queries below this were written, all of them need to be written before the frame is read back
+    written_count: u32,
+    /// Graphics timeline value signaled once the frame's queries are available
+    signal_value: u64,
+}
+
+struct QueryFrames {
+    frames: Vec<QueryFrame>,
+    current: usize,
+}
+
+/// Queries of a type with a range of `query_count` queries for every frame in flight. Query indices recorded on a
+/// command buffer are relative to the range of the current frame, selected by `Gpu::begin_query_pool_frame`.
+pub struct QueryPool {
     device: DeviceGuard,
-    query_pool: vk::QueryPool,
+    raw: vk::QueryPool,
+    query_type: QueryType,
+    query_count: u32,
+    frames: Mutex<QueryFrames>,
 }
 
-impl PipelineStatsQueryPool {
-    pub fn new(device: DeviceGuard) -> Result<Self> {
-        let pipeline_stats_flags = {
-            use vk::QueryPipelineStatisticFlags as flags;
+impl QueryPool {
+    pub(crate) fn new(device: DeviceGuard, desc: QueryPoolDesc) -> Result<Self> {
+        if desc.query_type == QueryType::PipelineStatistics
+            && !device.features().pipeline_statistics_query
+        {
+            return Err(GpuError::Unsupported("Pipeline statistics queries".to_string()).into());
+        }
 
-            let query_flags = flags::INPUT_ASSEMBLY_VERTICES
-                | flags::INPUT_ASSEMBLY_PRIMITIVES
-                | flags::VERTEX_SHADER_INVOCATIONS
-                | flags::CLIPPING_INVOCATIONS
-                | flags::CLIPPING_PRIMITIVES
-                | flags::FRAGMENT_SHADER_INVOCATIONS
-                | flags::COMPUTE_SHADER_INVOCATIONS;
+        let total_query_count = desc.query_count * constants::MAX_FRAMES;
+        let mut pool_info = vk::QueryPoolCreateInfo::builder()
+            .query_type(desc.query_type.to_vk())
+            .query_count(total_query_count);
+        if desc.query_type == QueryType::PipelineStatistics {
+            pool_info = pool_info.pipeline_statistics(PIPELINE_STATISTICS_FLAGS);
+        }
 
-            query_flags
+        let raw = unsafe { device.raw().create_query_pool(&pool_info, None)? };
+        unsafe { device.raw().reset_query_pool(raw, 0, total_query_count) };
+
+        Ok(Self {
+            device,
+            raw,
+            query_type: desc.query_type,
+            query_count: desc.query_count,
+            frames: Mutex::new(QueryFrames {
+                frames: vec![QueryFrame::default(); constants::MAX_FRAMES as usize],
+                current: 0,
+            }),
+        })
+    }
+
+    pub fn raw(&self) -> vk::QueryPool {
+        self.raw
+    }
+
+    pub fn query_type(&self) -> QueryType {
+        self.query_type
+    }
+
+    /// Queries available per frame
+    pub fn query_count(&self) -> u32 {
+        self.query_count
+    }
+
+    /// Returns the pool index of a query of the current frame and marks it as written
+    pub(crate) fn use_query(&self, query: u32) -> u32 {
+        assert!(
+            query < self.query_count,
+            "Query {} is out of range of {} queries",
+            query,
+            self.query_count
+        );
+
+        let mut frames = self.frames.lock();
+        let current = frames.current;
+        let frame = &mut frames.frames[current];
+        frame.written_count = frame.written_count.max(query + 1);
+
+        current as u32 * self.query_count + query
+    }
+
+    /// Reads back the queries previously written to the range of `frame_index` and selects the range for the queries
+    /// of the frame completing at `signal_value`. Does not wait, the previous queries need to have completed.
+    pub(crate) fn begin_frame(
+        &self,
+        frame_index: usize,
+        completed_value: u64,
+        signal_value: u64,
+    ) -> Result<Option<QueryResults>> {
+        let mut frames = self.frames.lock();
+        let frame = frames.frames[frame_index];
+
+        let results = if frame.written_count > 0 {
+            if frame.signal_value > completed_value {
+                return Err(anyhow::anyhow!(
+                    "Queries of frame {} are read back before the frame completed",
+                    frame_index
+                ));
+            }
+            Some(self.read_results(frame_index as u32, frame.written_count)?)
+        } else {
+            None
         };
 
-        let pool_info = vk::QueryPoolCreateInfo::builder()
-            .query_type(vk::QueryType::PIPELINE_STATISTICS)
-            .query_count(7)
-            .pipeline_statistics(pipeline_stats_flags);
+        frames.frames[frame_index] = QueryFrame {
+            written_count: 0,
+            signal_value,
+        };
+        frames.current = frame_index;
 
-        let query_pool = unsafe { device.raw().create_query_pool(&pool_info, None)? };
+        Ok(results)
+    }
 
-        Ok(Self { device, query_pool })
+    fn read_results(&self, frame_index: u32, written_count: u32) -> Result<QueryResults> {
+        let first_query = frame_index * self.query_count;
+
+        let values = unsafe {
+            let values = match self.query_type {
+                QueryType::PipelineStatistics => {
+                    let mut results =
+                        vec![[0u64; PIPELINE_STATISTICS_COUNT]; written_count as usize];
+                    self.device.raw().get_query_pool_results(
+                        self.raw,
+                        first_query,
+                        written_count,
+                        &mut results,
+                        vk::QueryResultFlags::TYPE_64,
+                    )?;
+                    results.into_iter().flatten().collect()
+                }
+                _ => {
+                    let mut results = vec![0u64; written_count as usize];
+                    self.device.raw().get_query_pool_results(
+                        self.raw,
+                        first_query,
+                        written_count,
+                        &mut results,
+                        vk::QueryResultFlags::TYPE_64,
+                    )?;
+                    results
+                }
+            };
+            self.device
+                .raw()
+                .reset_query_pool(self.raw, first_query, written_count);
+            values
+        };
+
+        Ok(QueryResults {
+            query_type: self.query_type,
+            values,
+        })
     }
 }
 
-impl Drop for PipelineStatsQueryPool {
+impl Drop for QueryPool {
     fn drop(&mut self) {
-        unsafe { self.device.raw().destroy_query_pool(self.query_pool, None) }
+        // XXX: Not deferred destroyed, the pool must not be in use by the Gpu when dropped
+        unsafe { self.device.raw().destroy_query_pool(self.raw, None) }
     }
 }
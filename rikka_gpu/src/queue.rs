@@ -1,4 +1,7 @@
+use std::sync::Arc;
+
 use anyhow::Result;
+use parking_lot::{Mutex, MutexGuard};
 use rikka_core::{ash, vk};
 
 use crate::{
@@ -70,7 +73,7 @@ pub struct SemaphoreSubmitInfo<'a> {
     pub value: Option<u64>,
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum QueueType {
     Graphics,
     Compute,
@@ -82,17 +85,33 @@ pub struct Queue {
     device: ash::Device,
     raw: vk::Queue,
     family_index: u32,
+    queue_type: QueueType,
+    // Queue types whose families collapsed into the same family share the VkQueue, and with it this lock.
+    lock: Arc<Mutex<()>>,
 }
 
 impl Queue {
-    pub unsafe fn new(device: ash::Device, raw: vk::Queue, family_index: u32) -> Self {
+    pub unsafe fn new(
+        device: ash::Device,
+        raw: vk::Queue,
+        family_index: u32,
+        queue_type: QueueType,
+        lock: Arc<Mutex<()>>,
+    ) -> Self {
         Self {
             device,
             raw,
             family_index,
+            queue_type,
+            lock,
         }
     }
 
+    /// Externally synchronizes the VkQueue, must be held by anything that submits to or presents on the raw queue.
+    pub fn lock(&self) -> MutexGuard<()> {
+        self.lock.lock()
+    }
+
     pub fn submit(
         &self,
         command_buffers: &[&CommandBuffer],
@@ -155,6 +174,7 @@ impl Queue {
             .command_buffer_infos(&command_buffer_submit_infos[..])
             .build();
 
+        let _lock = self.lock();
         unsafe {
             self.device.queue_submit2(
                 self.raw,
@@ -166,6 +186,12 @@ impl Queue {
         Ok(())
     }
 
+    pub fn wait_idle(&self) -> Result<()> {
+        let _lock = self.lock();
+        unsafe { self.device.queue_wait_idle(self.raw)? };
+        Ok(())
+    }
+
     pub fn raw(&self) -> vk::Queue {
         self.raw.clone()
     }
@@ -173,4 +199,8 @@ impl Queue {
     pub fn family_index(&self) -> u32 {
         self.family_index
     }
+
+    pub fn queue_type(&self) -> QueueType {
+        self.queue_type
+    }
 }
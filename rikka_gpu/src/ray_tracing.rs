@@ -0,0 +1,695 @@
+use std::mem::size_of;
+
+use anyhow::{anyhow, Context, Result};
+use rikka_core::{ash::extensions::khr, nalgebra::Matrix4, vk};
+
+use crate::{
+    buffer::*, descriptor_set::*, error::GpuError, escape::*, factory::*, pipeline::PipelineLayout,
+    shader_state::*, types::ResourceUsageType,
+};
+
+pub struct RayTracingContext {
+    pub acceleration_structure: khr::AccelerationStructure,
+    pub ray_tracing_pipeline: khr::RayTracingPipeline,
+    pub device: DeviceGuard,
+}
+
+impl RayTracingContext {
+    pub fn new(device: DeviceGuard) -> Self {
+        Self {
+            acceleration_structure: khr::AccelerationStructure::new(
+                device.instance().raw(),
+                device.raw(),
+            ),
+            ray_tracing_pipeline: khr::RayTracingPipeline::new(
+                device.instance().raw(),
+                device.raw(),
+            ),
+            device,
+        }
+    }
+
+    pub fn pipeline_properties(&self) -> vk::PhysicalDeviceRayTracingPipelinePropertiesKHR {
+        unsafe {
+            khr::RayTracingPipeline::get_properties(
+                self.device.instance().raw(),
+                self.device.physical_device().raw(),
+            )
+        }
+    }
+}
+
+fn align_up(value: u32, alignment: u32) -> u32 {
+    (value + alignment - 1) & !(alignment - 1)
+}
+
+/// Indexed triangle geometry of a bottom level acceleration structure
+#[derive(Clone)]
+pub struct TriangleGeometry {
+    pub vertex_buffer: Handle<Buffer>,
+    pub vertex_offset: u64,
+    pub vertex_stride: u64,
+    pub vertex_format: vk::Format,
+    pub vertex_count: u32,
+
+    pub index_buffer: Handle<Buffer>,
+    pub index_offset: u64,
+    pub index_type: vk::IndexType,
+    pub index_count: u32,
+
+    pub opaque: bool,
+}
+
+impl TriangleGeometry {
+    pub fn new(
+        vertex_buffer: Handle<Buffer>,
+        vertex_count: u32,
+        index_buffer: Handle<Buffer>,
+        index_count: u32,
+    ) -> Self {
+        Self {
+            vertex_buffer,
+            vertex_offset: 0,
+            vertex_stride: (size_of::<f32>() * 3) as u64,
+            vertex_format: vk::Format::R32G32B32_SFLOAT,
+            vertex_count,
+            index_buffer,
+            index_offset: 0,
+            index_type: vk::IndexType::UINT16,
+            index_count,
+            opaque: true,
+        }
+    }
+
+    pub fn set_vertex_layout(mut self, offset: u64, stride: u64, format: vk::Format) -> Self {
+        self.vertex_offset = offset;
+        self.vertex_stride = stride;
+        self.vertex_format = format;
+        self
+    }
+
+    pub fn set_index_layout(mut self, offset: u64, index_type: vk::IndexType) -> Self {
+        self.index_offset = offset;
+        self.index_type = index_type;
+        self
+    }
+
+    pub fn set_opaque(mut self, opaque: bool) -> Self {
+        self.opaque = opaque;
+        self
+    }
+
+    fn vulkan_geometry(&self) -> vk::AccelerationStructureGeometryKHR {
+        let triangles = vk::AccelerationStructureGeometryTrianglesDataKHR::builder()
+            .vertex_format(self.vertex_format)
+            .vertex_data(vk::DeviceOrHostAddressConstKHR {
+                device_address: self.vertex_buffer.get_device_address() + self.vertex_offset,
+            })
+            .vertex_stride(self.vertex_stride)
+            .max_vertex(self.vertex_count.saturating_sub(1))
+            .index_type(self.index_type)
+            .index_data(vk::DeviceOrHostAddressConstKHR {
+                device_address: self.index_buffer.get_device_address() + self.index_offset,
+            })
+            .build();
+
+        vk::AccelerationStructureGeometryKHR::builder()
+            .geometry_type(vk::GeometryTypeKHR::TRIANGLES)
+            .geometry(vk::AccelerationStructureGeometryDataKHR { triangles })
+            .flags(if self.opaque {
+                vk::GeometryFlagsKHR::OPAQUE
+            } else {
+                vk::GeometryFlagsKHR::empty()
+            })
+            .build()
+    }
+
+    fn vulkan_build_range(&self) -> vk::AccelerationStructureBuildRangeInfoKHR {
+        vk::AccelerationStructureBuildRangeInfoKHR::builder()
+            .primitive_count(self.index_count / 3)
+            .primitive_offset(0)
+            .first_vertex(0)
+            .transform_offset(0)
+            .build()
+    }
+}
+
+pub struct BottomLevelAccelerationStructureDesc {
+    pub geometries: Vec<TriangleGeometry>,
+    pub flags: vk::BuildAccelerationStructureFlagsKHR,
+}
+
+impl BottomLevelAccelerationStructureDesc {
+    pub fn new() -> Self {
+        Self {
+            geometries: vec![],
+            flags: vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE,
+        }
+    }
+
+    pub fn add_geometry(mut self, geometry: TriangleGeometry) -> Self {
+        self.geometries.push(geometry);
+        self
+    }
+
+    pub fn set_flags(mut self, flags: vk::BuildAccelerationStructureFlagsKHR) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub(crate) fn vulkan_geometries(&self) -> Vec<vk::AccelerationStructureGeometryKHR> {
+        self.geometries
+            .iter()
+            .map(|geometry| geometry.vulkan_geometry())
+            .collect()
+    }
+
+    pub(crate) fn vulkan_build_ranges(&self) -> Vec<vk::AccelerationStructureBuildRangeInfoKHR> {
+        self.geometries
+            .iter()
+            .map(|geometry| geometry.vulkan_build_range())
+            .collect()
+    }
+}
+
+#[derive(Clone)]
+pub struct AccelerationStructureInstance {
+    pub bottom_level: Handle<AccelerationStructure>,
+    pub transform: Matrix4<f32>,
+    pub custom_index: u32,
+    pub mask: u8,
+    pub hit_group_offset: u32,
+}
+
+impl AccelerationStructureInstance {
+    pub fn new(bottom_level: Handle<AccelerationStructure>, transform: Matrix4<f32>) -> Self {
+        Self {
+            bottom_level,
+            transform,
+            custom_index: 0,
+            mask: 0xFF,
+            hit_group_offset: 0,
+        }
+    }
+
+    pub fn set_custom_index(mut self, custom_index: u32) -> Self {
+        self.custom_index = custom_index;
+        self
+    }
+
+    pub fn set_mask(mut self, mask: u8) -> Self {
+        self.mask = mask;
+        self
+    }
+
+    pub fn set_hit_group_offset(mut self, hit_group_offset: u32) -> Self {
+        self.hit_group_offset = hit_group_offset;
+        self
+    }
+
+    fn vulkan_instance(&self) -> vk::AccelerationStructureInstanceKHR {
+        // Vulkan expects a row major 3x4 matrix
+        let mut matrix = [0.0f32; 12];
+        for row in 0..3 {
+            for column in 0..4 {
+                matrix[row * 4 + column] = self.transform[(row, column)];
+            }
+        }
+
+        vk::AccelerationStructureInstanceKHR {
+            transform: vk::TransformMatrixKHR { matrix },
+            instance_custom_index_and_mask: vk::Packed24_8::new(self.custom_index, self.mask),
+            instance_shader_binding_table_record_offset_and_flags: vk::Packed24_8::new(
+                self.hit_group_offset,
+                vk::GeometryInstanceFlagsKHR::TRIANGLE_FACING_CULL_DISABLE.as_raw() as u8,
+            ),
+            acceleration_structure_reference: vk::AccelerationStructureReferenceKHR {
+                device_handle: self.bottom_level.device_address(),
+            },
+        }
+    }
+}
+
+pub struct TopLevelAccelerationStructureDesc {
+    pub instances: Vec<AccelerationStructureInstance>,
+    pub flags: vk::BuildAccelerationStructureFlagsKHR,
+}
+
+impl TopLevelAccelerationStructureDesc {
+    pub fn new() -> Self {
+        Self {
+            instances: vec![],
+            flags: vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE,
+        }
+    }
+
+    pub fn add_instance(mut self, instance: AccelerationStructureInstance) -> Self {
+        self.instances.push(instance);
+        self
+    }
+
+    pub fn set_flags(mut self, flags: vk::BuildAccelerationStructureFlagsKHR) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub(crate) fn vulkan_instances(&self) -> Vec<vk::AccelerationStructureInstanceKHR> {
+        self.instances
+            .iter()
+            .map(|instance| instance.vulkan_instance())
+            .collect()
+    }
+
+    pub(crate) fn bottom_levels(&self) -> Vec<Handle<AccelerationStructure>> {
+        self.instances
+            .iter()
+            .map(|instance| instance.bottom_level.clone())
+            .collect()
+    }
+}
+
+pub struct AccelerationStructure {
+    context: RayTracingContext,
+    raw: vk::AccelerationStructureKHR,
+    level: vk::AccelerationStructureTypeKHR,
+    device_address: u64,
+
+    buffer: Handle<Buffer>,
+    // Top level structures keep the bottom levels they reference alive
+    bottom_levels: Vec<Handle<AccelerationStructure>>,
+}
+
+impl AccelerationStructure {
+    pub(crate) unsafe fn create(
+        device: DeviceGuard,
+        level: vk::AccelerationStructureTypeKHR,
+        buffer: Handle<Buffer>,
+        bottom_levels: Vec<Handle<AccelerationStructure>>,
+    ) -> Result<Self> {
+        let context = RayTracingContext::new(device);
+
+        let create_info = vk::AccelerationStructureCreateInfoKHR::builder()
+            .ty(level)
+            .buffer(buffer.raw())
+            .offset(0)
+            .size(buffer.size() as u64);
+
+        let raw = context
+            .acceleration_structure
+            .create_acceleration_structure(&create_info, None)
+            .context("Failed to create vulkan acceleration structure!")?;
+
+        let address_info =
+            vk::AccelerationStructureDeviceAddressInfoKHR::builder().acceleration_structure(raw);
+        let device_address = context
+            .acceleration_structure
+            .get_acceleration_structure_device_address(&address_info);
+
+        Ok(Self {
+            context,
+            raw,
+            level,
+            device_address,
+            buffer,
+            bottom_levels,
+        })
+    }
+
+    pub(crate) unsafe fn destroy(self) {
+        self.context
+            .acceleration_structure
+            .destroy_acceleration_structure(self.raw, None);
+    }
+
+    pub fn raw(&self) -> vk::AccelerationStructureKHR {
+        self.raw
+    }
+
+    pub fn level(&self) -> vk::AccelerationStructureTypeKHR {
+        self.level
+    }
+
+    pub fn device_address(&self) -> u64 {
+        self.device_address
+    }
+
+    pub fn buffer(&self) -> &Handle<Buffer> {
+        &self.buffer
+    }
+
+    pub fn bottom_levels(&self) -> &[Handle<AccelerationStructure>] {
+        &self.bottom_levels
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RayTracingShaderGroupType {
+    RayGeneration,
+    Miss,
+    HitGroup,
+}
+
+/// Shader group with indices into the pipeline's shader stages
+#[derive(Clone, Copy, Debug)]
+pub struct RayTracingShaderGroup {
+    pub group_type: RayTracingShaderGroupType,
+    pub general: Option<u32>,
+    pub closest_hit: Option<u32>,
+    pub any_hit: Option<u32>,
+}
+
+impl RayTracingShaderGroup {
+    fn vulkan_shader_group(&self) -> vk::RayTracingShaderGroupCreateInfoKHR {
+        let group_type = match self.group_type {
+            RayTracingShaderGroupType::HitGroup => {
+                vk::RayTracingShaderGroupTypeKHR::TRIANGLES_HIT_GROUP
+            }
+            _ => vk::RayTracingShaderGroupTypeKHR::GENERAL,
+        };
+
+        vk::RayTracingShaderGroupCreateInfoKHR::builder()
+            .ty(group_type)
+            .general_shader(self.general.unwrap_or(vk::SHADER_UNUSED_KHR))
+            .closest_hit_shader(self.closest_hit.unwrap_or(vk::SHADER_UNUSED_KHR))
+            .any_hit_shader(self.any_hit.unwrap_or(vk::SHADER_UNUSED_KHR))
+            .intersection_shader(vk::SHADER_UNUSED_KHR)
+            .build()
+    }
+}
+
+pub struct RayTracingPipelineDesc {
+    pub shader_state: ShaderStateDesc,
+    pub shader_groups: Vec<RayTracingShaderGroup>,
+    pub max_recursion_depth: u32,
+    pub push_constant_size: Option<u32>,
+}
+
+impl RayTracingPipelineDesc {
+    pub fn new() -> Self {
+        Self {
+            shader_state: ShaderStateDesc::new(),
+            shader_groups: vec![],
+            max_recursion_depth: 1,
+            push_constant_size: None,
+        }
+    }
+
+    fn add_stage(&mut self, stage: ShaderStageDesc) -> u32 {
+        self.shader_state.stages.push(stage);
+        self.shader_state.stages.len() as u32 - 1
+    }
+
+    pub fn set_ray_generation_stage(mut self, stage: ShaderStageDesc) -> Self {
+        let general = self.add_stage(stage);
+        self.shader_groups
+            .retain(|group| group.group_type != RayTracingShaderGroupType::RayGeneration);
+        self.shader_groups.push(RayTracingShaderGroup {
+            group_type: RayTracingShaderGroupType::RayGeneration,
+            general: Some(general),
+            closest_hit: None,
+            any_hit: None,
+        });
+        self
+    }
+
+    pub fn add_miss_stage(mut self, stage: ShaderStageDesc) -> Self {
+        let general = self.add_stage(stage);
+        self.shader_groups.push(RayTracingShaderGroup {
+            group_type: RayTracingShaderGroupType::Miss,
+            general: Some(general),
+            closest_hit: None,
+            any_hit: None,
+        });
+        self
+    }
+
+    pub fn add_hit_group(
+        mut self,
+        closest_hit: Option<ShaderStageDesc>,
+        any_hit: Option<ShaderStageDesc>,
+    ) -> Self {
+        let closest_hit = closest_hit.map(|stage| self.add_stage(stage));
+        let any_hit = any_hit.map(|stage| self.add_stage(stage));
+        self.shader_groups.push(RayTracingShaderGroup {
+            group_type: RayTracingShaderGroupType::HitGroup,
+            general: None,
+            closest_hit,
+            any_hit,
+        });
+        self
+    }
+
+    pub fn set_max_recursion_depth(mut self, max_recursion_depth: u32) -> Self {
+        self.max_recursion_depth = max_recursion_depth;
+        self
+    }
+
+    pub fn set_push_constant_size(mut self, push_constant_size: u32) -> Self {
+        self.push_constant_size = Some(push_constant_size);
+        self
+    }
+
+    /// Shader groups ordered by ray generation, miss and hit groups, matching the shader binding table layout
+    fn ordered_shader_groups(&self) -> Vec<RayTracingShaderGroup> {
+        let mut groups = self.shader_groups.clone();
+        groups.sort_by_key(|group| group.group_type as u32);
+        groups
+    }
+
+    fn count_shader_groups(&self, group_type: RayTracingShaderGroupType) -> u32 {
+        self.shader_groups
+            .iter()
+            .filter(|group| group.group_type == group_type)
+            .count() as u32
+    }
+}
+
+pub struct ShaderBindingTable {
+    buffer: Handle<Buffer>,
+    ray_generation_region: vk::StridedDeviceAddressRegionKHR,
+    miss_region: vk::StridedDeviceAddressRegionKHR,
+    hit_region: vk::StridedDeviceAddressRegionKHR,
+    callable_region: vk::StridedDeviceAddressRegionKHR,
+}
+
+impl ShaderBindingTable {
+    unsafe fn create(
+        context: &RayTracingContext,
+        factory: &Factory,
+        pipeline: vk::Pipeline,
+        desc: &RayTracingPipelineDesc,
+    ) -> Result<Self> {
+        let properties = context.pipeline_properties();
+        let handle_size = properties.shader_group_handle_size;
+        let handle_size_aligned = align_up(handle_size, properties.shader_group_handle_alignment);
+        let base_alignment = properties.shader_group_base_alignment;
+
+        let ray_generation_count =
+            desc.count_shader_groups(RayTracingShaderGroupType::RayGeneration);
+        let miss_count = desc.count_shader_groups(RayTracingShaderGroupType::Miss);
+        let hit_count = desc.count_shader_groups(RayTracingShaderGroupType::HitGroup);
+        let group_count = ray_generation_count + miss_count + hit_count;
+
+        // Ray generation region size must be equal to its stride
+        let ray_generation_size = align_up(handle_size_aligned, base_alignment);
+        let miss_size = align_up(miss_count * handle_size_aligned, base_alignment);
+        let hit_size = align_up(hit_count * handle_size_aligned, base_alignment);
+
+        let handles = context
+            .ray_tracing_pipeline
+            .get_ray_tracing_shader_group_handles(
+                pipeline,
+                0,
+                group_count,
+                (group_count * handle_size) as usize,
+            )
+            .context("Failed to get ray tracing shader group handles!")?;
+
+        let mut data = vec![0u8; (ray_generation_size + miss_size + hit_size) as usize];
+        let mut copy_handles = |region_offset: u32, first_group: u32, count: u32| {
+            for i in 0..count {
+                let src = ((first_group + i) * handle_size) as usize;
+                let dst = (region_offset + i * handle_size_aligned) as usize;
+                data[dst..dst + handle_size as usize]
+                    .copy_from_slice(&handles[src..src + handle_size as usize]);
+            }
+        };
+        copy_handles(0, 0, ray_generation_count);
+        copy_handles(ray_generation_size, ray_generation_count, miss_count);
+        copy_handles(
+            ray_generation_size + miss_size,
+            ray_generation_count + miss_count,
+            hit_count,
+        );
+
+        // XXX: Buffer address needs to be aligned to `shader_group_base_alignment`, we currently rely on the allocator alignment
+        let buffer = Handle::new(
+            factory.create_buffer(
+                BufferDesc::new()
+                    .set_usage_flags(
+                        vk::BufferUsageFlags::SHADER_BINDING_TABLE_KHR
+                            | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+                    )
+                    .set_resource_usage(ResourceUsageType::Immutable)
+                    .set_size(data.len() as u32)
+                    .set_device_only(false),
+            )?,
+            factory.hub_guard(),
+        );
+        buffer.copy_data_to_buffer(&data)?;
+
+        let address = buffer.get_device_address();
+        let region = |offset: u32, stride: u32, size: u32| {
+            vk::StridedDeviceAddressRegionKHR::builder()
+                .device_address(if size > 0 { address + offset as u64 } else { 0 })
+                .stride(stride as u64)
+                .size(size as u64)
+                .build()
+        };
+
+        Ok(Self {
+            ray_generation_region: region(0, ray_generation_size, ray_generation_size),
+            miss_region: region(ray_generation_size, handle_size_aligned, miss_size),
+            hit_region: region(
+                ray_generation_size + miss_size,
+                handle_size_aligned,
+                hit_size,
+            ),
+            callable_region: vk::StridedDeviceAddressRegionKHR::default(),
+            buffer,
+        })
+    }
+
+    pub fn buffer(&self) -> &Handle<Buffer> {
+        &self.buffer
+    }
+
+    pub fn ray_generation_region(&self) -> &vk::StridedDeviceAddressRegionKHR {
+        &self.ray_generation_region
+    }
+
+    pub fn miss_region(&self) -> &vk::StridedDeviceAddressRegionKHR {
+        &self.miss_region
+    }
+
+    pub fn hit_region(&self) -> &vk::StridedDeviceAddressRegionKHR {
+        &self.hit_region
+    }
+
+    pub fn callable_region(&self) -> &vk::StridedDeviceAddressRegionKHR {
+        &self.callable_region
+    }
+}
+
+pub struct RayTracingPipeline {
+    context: RayTracingContext,
+
+    raw: vk::Pipeline,
+    layout: Handle<PipelineLayout>,
+
+    descriptor_set_layouts: Vec<Handle<DescriptorSetLayout>>,
+    shader_binding_table: ShaderBindingTable,
+}
+
+impl RayTracingPipeline {
+    pub unsafe fn create(
+        device: DeviceGuard,
+        factory: &Factory,
+        desc: RayTracingPipelineDesc,
+    ) -> Result<Self> {
+        if desc.count_shader_groups(RayTracingShaderGroupType::RayGeneration) != 1 {
+            return Err(GpuError::invalid_desc(
+                "Ray tracing pipeline requires exactly one ray generation stage!",
+            )
+            .into());
+        }
+
+        let context = RayTracingContext::new(device.clone());
+        let shader_state = factory.get_or_create_shader_state(&desc.shader_state)?;
+
+        // XXX: Bindless set is not supported for ray tracing pipelines yet
+        let descriptor_set_layouts = shader_state
+            .reflection()
+            .descriptor_sets
+            .iter()
+            .map(|set| {
+                let layout_desc = DescriptorSetLayoutDesc::new()
+                    .set_bindings(set.bindings.clone())
+                    .set_binding_names(set.binding_names.clone())
+                    .set_bindless(false)
+                    .set_dynamic(false);
+                factory.get_or_create_descriptor_set_layout(layout_desc)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let push_constant_ranges = match desc.push_constant_size {
+            Some(size) => vec![vk::PushConstantRange::builder()
+                .stage_flags(
+                    vk::ShaderStageFlags::RAYGEN_KHR
+                        | vk::ShaderStageFlags::MISS_KHR
+                        | vk::ShaderStageFlags::CLOSEST_HIT_KHR
+                        | vk::ShaderStageFlags::ANY_HIT_KHR,
+                )
+                .offset(0)
+                .size(size)
+                .build()],
+            None => vec![],
+        };
+
+        let layout = factory
+            .get_or_create_pipeline_layout(&descriptor_set_layouts, &push_constant_ranges)?;
+
+        let shader_groups = desc
+            .ordered_shader_groups()
+            .iter()
+            .map(|group| group.vulkan_shader_group())
+            .collect::<Vec<_>>();
+
+        let pipeline_info = vk::RayTracingPipelineCreateInfoKHR::builder()
+            .stages(shader_state.vulkan_shader_stages())
+            .groups(&shader_groups)
+            .max_pipeline_ray_recursion_depth(desc.max_recursion_depth)
+            .layout(layout.raw())
+            .build();
+
+        let raw = context
+            .ray_tracing_pipeline
+            .create_ray_tracing_pipelines(
+                vk::DeferredOperationKHR::null(),
+                vk::PipelineCache::null(),
+                std::slice::from_ref(&pipeline_info),
+                None,
+            )
+            .context("Failed to create vulkan ray tracing pipeline!")?[0];
+
+        let shader_binding_table = ShaderBindingTable::create(&context, factory, raw, &desc)?;
+
+        Ok(Self {
+            context,
+            raw,
+            layout,
+            descriptor_set_layouts,
+            shader_binding_table,
+        })
+    }
+
+    pub unsafe fn destroy(self) {
+        self.context.device.raw().destroy_pipeline(self.raw, None);
+    }
+
+    pub fn raw(&self) -> vk::Pipeline {
+        self.raw
+    }
+
+    pub fn raw_layout(&self) -> vk::PipelineLayout {
+        self.layout.raw()
+    }
+
+    pub fn descriptor_set_layouts(&self) -> &[Handle<DescriptorSetLayout>] {
+        &self.descriptor_set_layouts
+    }
+
+    pub fn shader_binding_table(&self) -> &ShaderBindingTable {
+        &self.shader_binding_table
+    }
+}
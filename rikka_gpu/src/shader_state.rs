@@ -1,13 +1,17 @@
-use std::{ffi::CString, str::FromStr};
+use std::{
+    ffi::CString,
+    mem::{align_of, size_of},
+    str::FromStr,
+};
 
-use anyhow::{Context, Result};
+use anyhow::Result;
 
 use rikka_core::{ash, vk};
 use rikka_shader::{compiler, reflect::*, types::*};
 
-use crate::{device::Device, factory::DeviceGuard};
+use crate::{device::Device, error::GpuError, factory::DeviceGuard};
 
-pub use rikka_shader::types::ShaderStageType;
+pub use rikka_shader::types::{ShaderDefine, ShaderStageType};
 
 pub fn shader_stage_type_to_vk_flags(shader_type: ShaderStageType) -> vk::ShaderStageFlags {
     match shader_type {
@@ -17,10 +21,108 @@ pub fn shader_stage_type_to_vk_flags(shader_type: ShaderStageType) -> vk::Shader
         ShaderStageType::Compute => vk::ShaderStageFlags::COMPUTE,
         ShaderStageType::Mesh => vk::ShaderStageFlags::MESH_NV,
         ShaderStageType::Task => vk::ShaderStageFlags::TASK_NV,
+        ShaderStageType::RayGeneration => vk::ShaderStageFlags::RAYGEN_KHR,
+        ShaderStageType::Miss => vk::ShaderStageFlags::MISS_KHR,
+        ShaderStageType::ClosestHit => vk::ShaderStageFlags::CLOSEST_HIT_KHR,
+        ShaderStageType::AnyHit => vk::ShaderStageFlags::ANY_HIT_KHR,
+        ShaderStageType::Intersection => vk::ShaderStageFlags::INTERSECTION_KHR,
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+/// Push constant range of a pipeline stage. Typed layouts of a `#[repr(C)]` struct have to match the reflected block
+/// exactly, untyped layouts only need to cover it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PushConstantLayout {
+    pub size: u32,
+    pub alignment: u32,
+    pub type_name: Option<&'static str>,
+}
+
+impl PushConstantLayout {
+    pub fn of<T: Copy>() -> Self {
+        Self {
+            size: size_of::<T>() as u32,
+            alignment: align_of::<T>() as u32,
+            type_name: Some(std::any::type_name::<T>()),
+        }
+    }
+
+    pub fn from_size(size: u32) -> Self {
+        Self {
+            size,
+            alignment: 4,
+            type_name: None,
+        }
+    }
+
+    /// Fails if the shader `stage` reads push constants that are not covered by this layout
+    pub fn validate(
+        &self,
+        reflection: &ShaderReflection,
+        stage: vk::ShaderStageFlags,
+    ) -> Result<()> {
+        let type_name = self.type_name.unwrap_or("untyped push constants");
+
+        if self.size % 4 != 0 {
+            return Err(anyhow::anyhow!(
+                "Push constant size {} of {} is not a multiple of 4",
+                self.size,
+                type_name
+            ));
+        }
+
+        let block = match reflection.push_constant_block(stage) {
+            Some(block) => block,
+            None => {
+                log::warn!(
+                    "{:?} stage does not use the {} declared for it",
+                    stage,
+                    type_name
+                );
+                return Ok(());
+            }
+        };
+
+        // The struct is padded to its alignment, the reflected block ends at its last member
+        let block_size = (block.size + self.alignment - 1) / self.alignment * self.alignment;
+        let matches = match self.type_name {
+            Some(_) => self.size == block_size,
+            None => self.size >= block.size,
+        };
+        if !matches {
+            return Err(anyhow::anyhow!(
+                "{} of size {} and alignment {} does not match the {} byte push constant block of the {:?} stage",
+                type_name,
+                self.size,
+                self.alignment,
+                block.size,
+                stage
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Fails if a stage of `stages` uses push constants without a declared range
+pub fn validate_undeclared_push_constants(
+    reflection: &ShaderReflection,
+    stages: vk::ShaderStageFlags,
+) -> Result<()> {
+    for block in &reflection.push_constant_blocks {
+        if block.shader_stages.intersects(stages) {
+            return Err(anyhow::anyhow!(
+                "{:?} stages use {} bytes of push constants but no push constant range is declared",
+                block.shader_stages & stages,
+                block.size
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
 pub enum ShaderStageDataReadType {
     Bytes,
     BytesFromFile,
@@ -28,7 +130,7 @@ pub enum ShaderStageDataReadType {
     SourceFromFile,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub struct ShaderStageDesc {
     // XXX: Make this private
     pub read_type: ShaderStageDataReadType,
@@ -36,6 +138,7 @@ pub struct ShaderStageDesc {
     pub source: Option<String>,
     pub bytes: Option<Vec<u8>>,
     pub shader_type: ShaderStageType,
+    pub defines: Vec<ShaderDefine>,
 }
 
 impl ShaderStageDesc {
@@ -46,11 +149,17 @@ impl ShaderStageDesc {
             source: None,
             bytes: None,
             shader_type,
+            defines: vec![],
         }
     }
+
+    pub fn add_define(mut self, name: &str, value: Option<&str>) -> Self {
+        self.defines.push(ShaderDefine::new(name, value));
+        self
+    }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub struct ShaderStateDesc {
     pub stages: Vec<ShaderStageDesc>,
 }
@@ -64,8 +173,19 @@ impl ShaderStateDesc {
         self.stages.push(stage);
         self
     }
+
+    /// Whether any stage is read from one of the files
+    pub fn uses_files(&self, file_names: &[String]) -> bool {
+        self.stages.iter().any(|stage| {
+            stage
+                .file_name
+                .as_ref()
+                .map_or(false, |file_name| file_names.contains(file_name))
+        })
+    }
 }
 
+/// Shared by pipelines created from the same `ShaderStateDesc` through the `Factory` shader state cache
 pub struct ShaderState {
     device: DeviceGuard,
     raw_stages: Vec<vk::PipelineShaderStageCreateInfo>,
@@ -132,12 +252,15 @@ impl ShaderState {
                 ShaderStageDataReadType::SourceFromFile => {
                     let source_file_name = desc.file_name.as_ref().unwrap();
                     let destination_file_name = source_file_name.to_owned() + ".spv";
-                    let shader_data = compiler::compile_shader_through_glslangvalidator_cli(
-                        source_file_name,
-                        destination_file_name.as_str(),
-                        desc.shader_type,
-                    )
-                    .context("Failed to compile shader through glslangvalidator cli!")?;
+                    let shader_data = compiler::ShaderCache::default()
+                        .compile(
+                            source_file_name,
+                            destination_file_name.as_str(),
+                            desc.shader_type,
+                            &desc.defines,
+                            compiler::ShaderCompilerBackend::default(),
+                        )
+                        .map_err(|error| GpuError::ShaderCompile(format!("{:#}", error)))?;
                     shader_data.bytes
                 }
                 ShaderStageDataReadType::SourceFromString => {
@@ -179,3 +302,129 @@ impl Drop for ShaderState {
         unsafe { Self::destroy_shader_modules(&self.device, &self.raw_stages) };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy)]
+    #[repr(C)]
+    struct TransformConstants {
+        offset: [f32; 3],
+        index: u32,
+        scale: f32,
+    }
+
+    fn reflection(blocks: &[(vk::ShaderStageFlags, u32)]) -> ShaderReflection {
+        ShaderReflection {
+            descriptor_sets: Vec::new(),
+            push_constant_blocks: blocks
+                .iter()
+                .map(|(shader_stages, size)| PushConstantBlock {
+                    shader_stages: *shader_stages,
+                    size: *size,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_typed_layout_matches_padded_block() {
+        // 20 bytes of members padded to the 4 byte alignment of the struct
+        let reflection = reflection(&[(vk::ShaderStageFlags::VERTEX, 20)]);
+        let layout = PushConstantLayout::of::<TransformConstants>();
+
+        assert!(layout
+            .validate(&reflection, vk::ShaderStageFlags::VERTEX)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_typed_layout_size_mismatch() {
+        let layout = PushConstantLayout::of::<TransformConstants>();
+
+        let larger_block = reflection(&[(vk::ShaderStageFlags::VERTEX, 32)]);
+        assert!(layout
+            .validate(&larger_block, vk::ShaderStageFlags::VERTEX)
+            .is_err());
+
+        // Typed layouts have to match exactly, a struct larger than the block is an error too
+        let smaller_block = reflection(&[(vk::ShaderStageFlags::VERTEX, 16)]);
+        assert!(layout
+            .validate(&smaller_block, vk::ShaderStageFlags::VERTEX)
+            .is_err());
+    }
+
+    #[test]
+    fn test_untyped_layout_covers_block() {
+        let reflection = reflection(&[(vk::ShaderStageFlags::COMPUTE, 20)]);
+
+        assert!(PushConstantLayout::from_size(20)
+            .validate(&reflection, vk::ShaderStageFlags::COMPUTE)
+            .is_ok());
+        assert!(PushConstantLayout::from_size(64)
+            .validate(&reflection, vk::ShaderStageFlags::COMPUTE)
+            .is_ok());
+        assert!(PushConstantLayout::from_size(16)
+            .validate(&reflection, vk::ShaderStageFlags::COMPUTE)
+            .is_err());
+    }
+
+    #[test]
+    fn test_size_not_multiple_of_four() {
+        let reflection = reflection(&[(vk::ShaderStageFlags::COMPUTE, 20)]);
+
+        assert!(PushConstantLayout::from_size(22)
+            .validate(&reflection, vk::ShaderStageFlags::COMPUTE)
+            .is_err());
+        // Checked before the reflected block is looked up
+        assert!(PushConstantLayout::from_size(6)
+            .validate(&reflection, vk::ShaderStageFlags::VERTEX)
+            .is_err());
+    }
+
+    #[test]
+    fn test_stage_without_push_constants() {
+        let reflection = reflection(&[(vk::ShaderStageFlags::VERTEX, 20)]);
+
+        assert!(PushConstantLayout::of::<TransformConstants>()
+            .validate(&reflection, vk::ShaderStageFlags::FRAGMENT)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_block_shared_by_overlapping_stages() {
+        let reflection = reflection(&[(
+            vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+            20,
+        )]);
+        let layout = PushConstantLayout::of::<TransformConstants>();
+
+        assert!(layout
+            .validate(&reflection, vk::ShaderStageFlags::VERTEX)
+            .is_ok());
+        assert!(layout
+            .validate(&reflection, vk::ShaderStageFlags::FRAGMENT)
+            .is_ok());
+        assert!(PushConstantLayout::from_size(16)
+            .validate(&reflection, vk::ShaderStageFlags::FRAGMENT)
+            .is_err());
+    }
+
+    #[test]
+    fn test_undeclared_push_constants_overlapping_stages() {
+        let reflection = reflection(&[(
+            vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+            20,
+        )]);
+
+        assert!(
+            validate_undeclared_push_constants(&reflection, vk::ShaderStageFlags::COMPUTE).is_ok()
+        );
+        assert!(validate_undeclared_push_constants(
+            &reflection,
+            vk::ShaderStageFlags::FRAGMENT | vk::ShaderStageFlags::COMPUTE
+        )
+        .is_err());
+    }
+}
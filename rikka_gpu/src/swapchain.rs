@@ -1,11 +1,21 @@
-use anyhow::{Context, Result};
 use rikka_core::{ash::extensions::khr, vk};
 
 use crate::{
-    device::Device, escape::Escape, factory::*, image::Image, instance::Instance,
+    device::Device, error::Result, escape::Escape, factory::*, image::Image, instance::Instance,
     physical_device::PhysicalDevice, queue::Queue, surface::Surface, synchronization::Semaphore,
+    types::TransferFunction,
 };
 
+/// Result of acquiring or presenting a swapchain image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SwapchainStatus {
+    Optimal,
+    /// The swapchain can still be used but should be recreated.
+    Suboptimal,
+    /// The swapchain can no longer be used and needs to be recreated.
+    OutOfDate,
+}
+
 pub struct Swapchain {
     device: DeviceGuard,
     ash_swapchain: khr::Swapchain,
@@ -19,6 +29,8 @@ pub struct Swapchain {
     color_space: vk::ColorSpaceKHR,
     present_mode: vk::PresentModeKHR,
 
+    preferred_surface_formats: Vec<vk::SurfaceFormatKHR>,
+
     image_count: u32,
     images: Vec<vk::Image>,
     image_views: Vec<vk::ImageView>,
@@ -28,6 +40,23 @@ pub struct Swapchain {
     vulkan_image_index: u32,
 }
 
+pub const DEFAULT_SURFACE_FORMAT: vk::SurfaceFormatKHR = vk::SurfaceFormatKHR {
+    format: vk::Format::B8G8R8A8_UNORM,
+    color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+};
+
+/// HDR10 and scRGB surface formats, these require VK_EXT_swapchain_colorspace.
+pub const HDR_SURFACE_FORMATS: [vk::SurfaceFormatKHR; 2] = [
+    vk::SurfaceFormatKHR {
+        format: vk::Format::A2B10G10R10_UNORM_PACK32,
+        color_space: vk::ColorSpaceKHR::HDR10_ST2084_EXT,
+    },
+    vk::SurfaceFormatKHR {
+        format: vk::Format::R16G16B16A16_SFLOAT,
+        color_space: vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT,
+    },
+];
+
 pub struct SwapchainDesc {
     pub width: u32,
     pub height: u32,
@@ -36,6 +65,9 @@ pub struct SwapchainDesc {
     pub present_queue_family_index: u32,
 
     pub present_mode: vk::PresentModeKHR,
+
+    /// Surface formats in order of preference, the first one supported by the surface is used.
+    pub preferred_surface_formats: Vec<vk::SurfaceFormatKHR>,
 }
 
 impl SwapchainDesc {
@@ -51,6 +83,7 @@ impl SwapchainDesc {
             graphics_queue_family_index,
             present_queue_family_index,
             present_mode: vk::PresentModeKHR::FIFO,
+            preferred_surface_formats: vec![DEFAULT_SURFACE_FORMAT],
         }
     }
 
@@ -58,6 +91,14 @@ impl SwapchainDesc {
         self.present_mode = present_mode;
         self
     }
+
+    pub fn set_preferred_surface_formats(
+        mut self,
+        preferred_surface_formats: Vec<vk::SurfaceFormatKHR>,
+    ) -> Self {
+        self.preferred_surface_formats = preferred_surface_formats;
+        self
+    }
 }
 
 impl Swapchain {
@@ -77,21 +118,32 @@ impl Swapchain {
             };
 
             if formats.len() == 1 && formats[0].format == vk::Format::UNDEFINED {
-                vk::SurfaceFormatKHR {
-                    format: vk::Format::B8G8R8A8_UNORM,
-                    color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
-                }
+                DEFAULT_SURFACE_FORMAT
             } else {
-                *formats
+                *swapchain_desc
+                    .preferred_surface_formats
                     .iter()
-                    .find(|format| {
-                        format.format == vk::Format::B8G8R8A8_UNORM
-                            && format.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR
+                    .filter(|preferred| {
+                        // Non sRGB color spaces are only available with the extension
+                        preferred.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR
+                            || instance.swapchain_colorspace_supported()
+                    })
+                    .find(|preferred| {
+                        formats.iter().any(|format| {
+                            format.format == preferred.format
+                                && format.color_space == preferred.color_space
+                        })
                     })
                     .unwrap_or(&formats[0])
             }
         };
 
+        log::info!(
+            "Swapchain surface format: {:?}, color space: {:?}",
+            surface_format.format,
+            surface_format.color_space
+        );
+
         let present_mode = {
             let present_modes = unsafe {
                 surface.raw().get_physical_device_surface_present_modes(
@@ -103,10 +155,17 @@ impl Swapchain {
             if present_modes.contains(&swapchain_desc.present_mode) {
                 swapchain_desc.present_mode
             } else {
-                return Err(anyhow::anyhow!("Present mode not supported"));
+                // FIFO is required to be supported
+                log::warn!(
+                    "Present mode {:?} not supported, falling back to FIFO",
+                    swapchain_desc.present_mode
+                );
+                vk::PresentModeKHR::FIFO
             }
         };
 
+        log::info!("Swapchain present mode: {:?}", present_mode);
+
         // Get surface capabilities.
         let capabilities = unsafe {
             surface.raw().get_physical_device_surface_capabilities(
@@ -156,9 +215,7 @@ impl Swapchain {
                 )
                 .pre_transform(capabilities.current_transform)
                 .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
-                // XXX: Query supported present modes and make sure requested present mode is supported
-                // .present_mode(present_mode);
-                .present_mode(vk::PresentModeKHR::FIFO);
+                .present_mode(present_mode);
 
             if swapchain_desc.graphics_queue_family_index
                 == swapchain_desc.present_queue_family_index
@@ -189,6 +246,8 @@ impl Swapchain {
             present_mode,
             extent,
 
+            preferred_surface_formats: swapchain_desc.preferred_surface_formats,
+
             image_count,
             vulkan_image_index: 0,
 
@@ -197,30 +256,42 @@ impl Swapchain {
             image_handles: Vec::with_capacity(image_count as _),
         };
 
-        swapchain
-            .init_images()
-            .with_context(|| (format!("Failed to initialize swapchain images!")))?;
+        swapchain.init_images()?;
 
         Ok(swapchain)
     }
 
-    pub fn acquire_next_image(&mut self, signal_semaphore: &Semaphore) -> Result<bool> {
-        let (image_index, is_suboptimal) = unsafe {
+    pub fn acquire_next_image(&mut self, signal_semaphore: &Semaphore) -> Result<SwapchainStatus> {
+        let acquire_result = unsafe {
             self.ash_swapchain.acquire_next_image(
                 self.vulkan_swapchain,
                 // XXX: Investigate validation error
                 u64::MAX - 1,
                 signal_semaphore.raw(),
                 vk::Fence::null(),
-            )?
+            )
         };
 
-        self.vulkan_image_index = image_index;
+        match acquire_result {
+            Ok((image_index, is_suboptimal)) => {
+                self.vulkan_image_index = image_index;
 
-        Ok(!is_suboptimal)
+                if is_suboptimal {
+                    Ok(SwapchainStatus::Suboptimal)
+                } else {
+                    Ok(SwapchainStatus::Optimal)
+                }
+            }
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => Ok(SwapchainStatus::OutOfDate),
+            Err(err) => Err(err.into()),
+        }
     }
 
-    pub fn queue_present(&self, wait_semaphores: &[&Semaphore], queue: &Queue) -> Result<bool> {
+    pub fn queue_present(
+        &self,
+        wait_semaphores: &[&Semaphore],
+        queue: &Queue,
+    ) -> Result<SwapchainStatus> {
         let swapchains = [self.vulkan_swapchain];
         let image_indices = [self.vulkan_image_index];
         let wait_semaphores = wait_semaphores
@@ -233,18 +304,56 @@ impl Swapchain {
             .swapchains(&swapchains)
             .image_indices(&image_indices);
 
-        let result = unsafe {
-            self.ash_swapchain
-                .queue_present(queue.raw(), &present_info)?
+        let present_result = {
+            let _lock = queue.lock();
+            unsafe { self.ash_swapchain.queue_present(queue.raw(), &present_info) }
         };
 
-        Ok(result)
+        match present_result {
+            Ok(false) => Ok(SwapchainStatus::Optimal),
+            Ok(true) => Ok(SwapchainStatus::Suboptimal),
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => Ok(SwapchainStatus::OutOfDate),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Queries the current extent of the window surface. The extent is zero sized when the window is minimized.
+    pub fn surface_extent(
+        surface: &Surface,
+        physical_device: &PhysicalDevice,
+    ) -> Result<vk::Extent2D> {
+        let capabilities = unsafe {
+            surface.raw().get_physical_device_surface_capabilities(
+                physical_device.raw(),
+                surface.raw_vulkan(),
+            )?
+        };
+
+        Ok(capabilities.current_extent)
     }
 
     pub fn set_present_mode(&mut self, present_mode: vk::PresentModeKHR) {
         self.present_mode = present_mode;
     }
 
+    pub fn present_mode(&self) -> vk::PresentModeKHR {
+        self.present_mode
+    }
+
+    pub fn supported_present_modes(
+        surface: &Surface,
+        physical_device: &PhysicalDevice,
+    ) -> Result<Vec<vk::PresentModeKHR>> {
+        let present_modes = unsafe {
+            surface.raw().get_physical_device_surface_present_modes(
+                physical_device.raw(),
+                surface.raw_vulkan(),
+            )?
+        };
+
+        Ok(present_modes)
+    }
+
     pub fn vulkan_image_index(&self) -> u32 {
         self.vulkan_image_index
     }
@@ -261,6 +370,10 @@ impl Swapchain {
         self.color_space
     }
 
+    pub fn transfer_function(&self) -> TransferFunction {
+        TransferFunction::from_color_space(self.color_space)
+    }
+
     pub fn current_vulkan_image(&self) -> vk::Image {
         self.images[self.vulkan_image_index as usize]
     }
@@ -303,7 +416,8 @@ impl Swapchain {
             self.graphics_queue_family_index,
             self.present_queue_family_index,
         )
-        .set_present_mode(present_mode);
+        .set_present_mode(present_mode)
+        .set_preferred_surface_formats(self.preferred_surface_formats.clone());
         self.recreate_from_desc(instance, surface, physical_device, device, desc)
     }
 
@@ -321,7 +435,8 @@ impl Swapchain {
             self.graphics_queue_family_index,
             self.present_queue_family_index,
         )
-        .set_present_mode(self.present_mode);
+        .set_present_mode(self.present_mode)
+        .set_preferred_surface_formats(self.preferred_surface_formats.clone());
         self.recreate_from_desc(instance, surface, physical_device, device, desc)
     }
 
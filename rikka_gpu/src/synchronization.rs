@@ -1,9 +1,11 @@
 use std::time::Duration;
 
-use anyhow::Result;
 use rikka_core::vk;
 
-use crate::factory::DeviceGuard;
+use crate::{
+    error::{GpuError, Result},
+    factory::DeviceGuard,
+};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum SemaphoreType {
@@ -50,10 +52,33 @@ impl Semaphore {
         self.semaphore_type
     }
 
+    /// Current value of a timeline semaphore
+    pub fn counter_value(&self) -> Result<u64> {
+        if self.semaphore_type != SemaphoreType::Timeline {
+            return Err(GpuError::invalid_desc(
+                "Cannot get counter value of non-timeline semaphore",
+            ));
+        }
+
+        Ok(unsafe { self.device.raw().get_semaphore_counter_value(self.raw)? })
+    }
+
     pub fn wait_for_value(&self, value: u64) -> Result<()> {
+        if !self.wait_for_value_timeout(value, Duration::new(10, 0))? {
+            return Err(GpuError::other(format!(
+                "Timed out waiting for semaphore value {}",
+                value
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Returns false if the timeline did not reach `value` within `timeout`
+    pub fn wait_for_value_timeout(&self, value: u64, timeout: Duration) -> Result<bool> {
         if self.semaphore_type != SemaphoreType::Timeline {
-            return Err(anyhow::anyhow!(
-                "Cannot call wait for value for non-timeline semaphore"
+            return Err(GpuError::invalid_desc(
+                "Cannot call wait for value for non-timeline semaphore",
             ));
         }
 
@@ -66,13 +91,17 @@ impl Semaphore {
             .semaphores(&semaphores)
             .values(&values);
 
-        unsafe {
+        let result = unsafe {
             self.device
                 .raw()
-                .wait_semaphores(&wait_info, Duration::new(10, 0).as_nanos() as u64)?;
-        }
+                .wait_semaphores(&wait_info, timeout.as_nanos() as u64)
+        };
 
-        Ok(())
+        match result {
+            Ok(()) => Ok(true),
+            Err(vk::Result::TIMEOUT) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
     }
 }
 
@@ -1,7 +1,4 @@
-use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc,
-};
+use std::collections::VecDeque;
 
 use crossbeam_channel::{Receiver, Sender};
 
@@ -9,12 +6,13 @@ use anyhow::Result;
 use rikka_core::vk;
 
 use crate::{
-    barriers::*, buffer::*, command_buffer::*, constants, escape::*, factory::*, image::Image,
-    queue::*, synchronization::*,
+    barriers::*, buffer::*, command_buffer::*, constants, escape::*, factory::*, image::*,
+    profiling, queue::*, synchronization::*,
 };
 
 pub struct ImageUploadRequest {
     pub image: Handle<Image>,
+    /// All mip levels of the image, tightly packed starting with the most detailed one
     pub data: Vec<u8>,
     // XXX: Have a mechanism to signal upon completion?
 }
@@ -33,9 +31,8 @@ pub struct TransferManager {
 
     // XXX: This needs to be a persistently mapped buffer
     staging_buffer: Handle<Buffer>,
-    staging_buffer_offset: AtomicUsize,
 
-    image_upload_requests: Vec<ImageUploadRequest>,
+    image_upload_requests: VecDeque<ImageUploadRequest>,
     completed_images: Vec<Handle<Image>>,
 
     image_upload_request_sender: Sender<ImageUploadRequest>,
@@ -45,6 +42,12 @@ pub struct TransferManager {
 }
 
 const STAGING_BUFFER_SIZE: u32 = 64 * 1024 * 1024;
+/// Offset alignment of images in the staging buffer, covers texel and compressed block sizes
+const STAGING_BUFFER_ALIGNMENT: usize = 16;
+
+fn align_offset(offset: usize) -> usize {
+    (offset + STAGING_BUFFER_ALIGNMENT - 1) & !(STAGING_BUFFER_ALIGNMENT - 1)
+}
 
 impl TransferManager {
     pub fn new(
@@ -62,7 +65,6 @@ impl TransferManager {
             )?,
             factory.hub_guard(),
         );
-        let staging_buffer_offset = AtomicUsize::new(0);
 
         let mut command_pools = Vec::with_capacity(constants::MAX_FRAMES as usize);
         let mut command_buffers = Vec::with_capacity(constants::MAX_FRAMES as usize);
@@ -100,8 +102,7 @@ impl TransferManager {
             submission_semaphore,
             submission_index,
             staging_buffer,
-            staging_buffer_offset,
-            image_upload_requests: Vec::new(),
+            image_upload_requests: VecDeque::new(),
             completed_images: Vec::new(),
 
             image_upload_request_sender,
@@ -112,6 +113,8 @@ impl TransferManager {
 
     /// Called periodically to perform asynchronous transfers
     pub fn perform_transfers(&mut self) -> Result<()> {
+        let _span = profiling::span("TransferManager::perform_transfers");
+
         // XXX: Technically we can have two in flight transfer_queue submissions running at once
         //      Implement that one day...
         if !self.completed_images.is_empty()
@@ -133,48 +136,80 @@ impl TransferManager {
 
         self.receive_image_upload_requests();
 
-        if !self.image_upload_requests.is_empty() {
-            // XXX: Handle multiple image uploads
-            let image_request = self.image_upload_requests.pop().unwrap();
+        // Batch as many uploads as fit into the staging buffer into one submission
+        let mut batch = Vec::new();
+        let mut staging_buffer_offset = 0;
+        while let Some(image_request) = self.image_upload_requests.front() {
+            let offset = align_offset(staging_buffer_offset);
+            let end = offset + image_request.data.len();
+
+            if end > STAGING_BUFFER_SIZE as usize {
+                if batch.is_empty() {
+                    // XXX: Split large images across multiple submissions
+                    let image_request = self.image_upload_requests.pop_front().unwrap();
+                    log::error!(
+                        "Skipping image upload of {} bytes, larger than the staging buffer",
+                        image_request.data.len()
+                    );
+                    continue;
+                }
+                break;
+            }
+
+            batch.push((self.image_upload_requests.pop_front().unwrap(), offset));
+            staging_buffer_offset = end;
+        }
 
+        if !batch.is_empty() {
             let command_buffer = &self.command_buffers[current_frame];
             command_buffer.begin()?;
 
-            // XXX: Query proper number of channels from image format.
-            // let num_channels = 4;
-            // XXX: Handle proper alignment when number of channels is not guaranteed to be multiple of 4.
-            // let image_alignment = 4;
-            // let aligned_image_size =
-            //     image_request.image.width() * image_request.image.height() * num_channels;
-            // let current_offset = self
-            //     .staging_buffer_offset
-            //     .fetch_add(aligned_image_size as usize, Ordering::Relaxed);
-
-            self.staging_buffer
-                .copy_data_to_buffer(&image_request.data)?;
-
-            let barriers = Barriers::new().add_image(
-                &image_request.image,
-                ResourceState::UNDEFINED,
-                ResourceState::COPY_DESTINATION,
-            );
-            command_buffer.pipeline_barrier(barriers);
+            let mut barriers = Barriers::new();
+            for (image_request, offset) in &batch {
+                self.staging_buffer
+                    .copy_data_to_buffer_with_offset(&image_request.data, *offset)?;
 
-            command_buffer.copy_buffer_to_image(&self.staging_buffer, &image_request.image, 0);
+                barriers = barriers.add_image(
+                    &image_request.image,
+                    ResourceState::UNDEFINED,
+                    ResourceState::COPY_DESTINATION,
+                );
+            }
+            command_buffer.pipeline_barrier(barriers);
 
-            // log::info!(
-            //     "Transfer index {}, graphics index {}",
-            //     self.transfer_queue.family_index(),
-            //     self.graphics_queue.family_index()
-            // );
+            // All mips of an image are copied with a single command
+            let mut copy_batch = CopyBatch::new();
+            for (image_request, offset) in &batch {
+                let mut buffer_offset = *offset;
+                for mip_level in 0..image_request.image.mip_levels() {
+                    copy_batch = copy_batch.add_buffer_to_image_region(
+                        &self.staging_buffer,
+                        &image_request.image,
+                        buffer_offset as u64,
+                        mip_level,
+                        0,
+                        image_request.image.array_layers(),
+                    );
+
+                    let mip_extent = image_request.image.mip_extent(mip_level);
+                    buffer_offset += format_image_size(
+                        image_request.image.format(),
+                        mip_extent.width,
+                        mip_extent.height,
+                    )
+                    .map_or(image_request.data.len(), |size| {
+                        // Slices of 3D images are packed like array layers
+                        size * (image_request.image.array_layers() * mip_extent.depth) as usize
+                    });
+                }
+            }
+            command_buffer.copy_batch(copy_batch);
 
-            let barriers = Barriers::new().add_image_with_queue_transfer(
-                &image_request.image,
-                ResourceState::COPY_DESTINATION,
-                ResourceState::COPY_DESTINATION,
-                &self.transfer_queue,
-                &self.graphics_queue,
-            );
+            // Release ownership to the graphics queue, which records the matching acquire
+            let mut barriers = Barriers::new();
+            for (image_request, _) in &batch {
+                barriers = self.release_to_graphics_queue(barriers, &image_request.image);
+            }
             command_buffer.pipeline_barrier(barriers);
 
             command_buffer.end()?;
@@ -188,12 +223,11 @@ impl TransferManager {
                 .submit(&[command_buffer], &[], &[signal_semaphores])?;
             self.submission_index += 1;
 
-            self.completed_images.push(image_request.image);
-
-            // log::info!(
-            //     "Submitted transfer commands for submission index {}",
-            //     self.submission_index
-            // );
+            self.completed_images.extend(
+                batch
+                    .into_iter()
+                    .map(|(image_request, _)| image_request.image),
+            );
         }
 
         Ok(())
@@ -207,11 +241,30 @@ impl TransferManager {
         log::info!("Destroyed Gpu transfer manager");
     }
 
+    /// Returns the states of an uploaded image before and after the transfer to the graphics queue
+    pub fn uploaded_image_states() -> (ResourceState, ResourceState) {
+        (
+            ResourceState::COPY_DESTINATION,
+            ResourceState::SHADER_RESOURCE,
+        )
+    }
+
+    fn release_to_graphics_queue(&self, barriers: Barriers, image: &Image) -> Barriers {
+        let (old_state, new_state) = Self::uploaded_image_states();
+        barriers.add_image_release(
+            image,
+            old_state,
+            new_state,
+            &self.transfer_queue,
+            &self.graphics_queue,
+        )
+    }
+
     /// Receives image upload requests from the channel
     fn receive_image_upload_requests(&mut self) {
         while !self.image_upload_request_receiver.is_empty() {
             self.image_upload_requests
-                .push(self.image_upload_request_receiver.recv().unwrap());
+                .push_back(self.image_upload_request_receiver.recv().unwrap());
         }
     }
 }
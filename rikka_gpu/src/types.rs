@@ -2,7 +2,11 @@ use serde_derive::{Deserialize, Serialize};
 
 use rikka_core::vk;
 
-use crate::{escape::Handle, image::Image, sampler::Sampler};
+use crate::{
+    escape::Handle,
+    image::{Image, ImageView},
+    sampler::Sampler,
+};
 
 pub enum PipelineStage {
     DrawIndirect,
@@ -14,6 +18,66 @@ pub enum PipelineStage {
     Transfer,
 }
 
+/// Transfer function expected by the swapchain color space, used to determine how the final image is tonemapped/encoded.
+/// The discriminant is passed to the tonemapping shader.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u32)]
+pub enum TransferFunction {
+    /// sRGB curve, standard dynamic range.
+    Srgb = 0,
+    /// Linear extended sRGB(scRGB), values can go above 1.0.
+    Linear = 1,
+    /// SMPTE ST 2084 perceptual quantizer(HDR10).
+    Pq = 2,
+}
+
+impl TransferFunction {
+    pub fn from_color_space(color_space: vk::ColorSpaceKHR) -> Self {
+        match color_space {
+            vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT
+            | vk::ColorSpaceKHR::BT709_LINEAR_EXT
+            | vk::ColorSpaceKHR::DISPLAY_P3_LINEAR_EXT => TransferFunction::Linear,
+            vk::ColorSpaceKHR::HDR10_ST2084_EXT => TransferFunction::Pq,
+            _ => TransferFunction::Srgb,
+        }
+    }
+
+    pub fn is_hdr(&self) -> bool {
+        *self != TransferFunction::Srgb
+    }
+}
+
+/// Vendor extension used for mesh/task shaders.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MeshShaderExtension {
+    /// VK_EXT_mesh_shader, preferred when available.
+    Ext,
+    /// VK_NV_mesh_shader.
+    Nv,
+}
+
+/// Optional device features detected from the physical device, only supported features are enabled.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DeviceFeatures {
+    pub mesh_shader: Option<MeshShaderExtension>,
+    /// Requires `buffer_device_address`.
+    pub ray_tracing: bool,
+    pub buffer_device_address: bool,
+    pub draw_indirect_count: bool,
+    /// Descriptor indexing features used by the bindless descriptor set.
+    pub bindless: bool,
+    /// Line widths other than 1.0.
+    pub wide_lines: bool,
+    /// LINE and POINT polygon modes.
+    pub fill_mode_non_solid: bool,
+    /// 8 bit index buffers with `vk::IndexType::UINT8_EXT`.
+    pub index_type_uint8: bool,
+    /// `QueryType::PipelineStatistics` query pools.
+    pub pipeline_statistics_query: bool,
+    /// `VK_NV_device_diagnostic_checkpoints` for crash markers.
+    pub diagnostic_checkpoints: bool,
+}
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum ResourceUsageType {
     Immutable,
@@ -39,8 +103,48 @@ pub struct BlendState {
 }
 
 impl BlendState {
+    /// Blending disabled, enabling it as is gives standard alpha blending
     pub fn new() -> Self {
-        todo!()
+        Self {
+            source_color: vk::BlendFactor::SRC_ALPHA,
+            destination_color: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+            color_operation: vk::BlendOp::ADD,
+            source_alpha: vk::BlendFactor::ONE,
+            destination_alpha: vk::BlendFactor::ZERO,
+            alpha_operation: vk::BlendOp::ADD,
+            enable: false,
+            separate_alpha: false,
+        }
+    }
+
+    pub fn set_enable(mut self, enable: bool) -> Self {
+        self.enable = enable;
+        self
+    }
+
+    pub fn set_color(
+        mut self,
+        source_color: vk::BlendFactor,
+        destination_color: vk::BlendFactor,
+        color_operation: vk::BlendOp,
+    ) -> Self {
+        self.source_color = source_color;
+        self.destination_color = destination_color;
+        self.color_operation = color_operation;
+        self
+    }
+
+    pub fn set_alpha(
+        mut self,
+        source_alpha: vk::BlendFactor,
+        destination_alpha: vk::BlendFactor,
+        alpha_operation: vk::BlendOp,
+    ) -> Self {
+        self.source_alpha = source_alpha;
+        self.destination_alpha = destination_alpha;
+        self.alpha_operation = alpha_operation;
+        self.separate_alpha = true;
+        self
     }
 }
 
@@ -109,6 +213,8 @@ pub struct RasterizationState {
     pub cull_mode: vk::CullModeFlags,
     pub front_face: vk::FrontFace,
     pub polygon_mode: vk::PolygonMode,
+    pub depth_bias: Option<DepthBias>,
+    pub line_width: f32,
 }
 
 impl RasterizationState {
@@ -117,9 +223,23 @@ impl RasterizationState {
             cull_mode: vk::CullModeFlags::NONE,
             front_face: vk::FrontFace::COUNTER_CLOCKWISE,
             polygon_mode: vk::PolygonMode::FILL,
+            depth_bias: None,
+            line_width: 1.0,
         }
     }
 
+    /// Widths other than 1.0 require the wide lines device feature
+    pub fn set_line_width(mut self, line_width: f32) -> Self {
+        self.line_width = line_width;
+        self
+    }
+
+    /// Enables depth bias, the values are ignored if depth bias is set as a dynamic state
+    pub fn set_depth_bias(mut self, depth_bias: DepthBias) -> Self {
+        self.depth_bias = Some(depth_bias);
+        self
+    }
+
     pub fn set_cull_mode(mut self, cull_mode: vk::CullModeFlags) -> Self {
         self.cull_mode = cull_mode;
         self
@@ -136,12 +256,109 @@ impl RasterizationState {
     }
 }
 
+#[derive(Clone, Copy)]
+pub struct DepthBias {
+    pub constant_factor: f32,
+    pub slope_factor: f32,
+    /// Non zero values require the depthBiasClamp device feature
+    pub clamp: f32,
+}
+
+impl DepthBias {
+    pub fn new(constant_factor: f32, slope_factor: f32) -> Self {
+        Self {
+            constant_factor,
+            slope_factor,
+            clamp: 0.0,
+        }
+    }
+
+    pub fn set_clamp(mut self, clamp: f32) -> Self {
+        self.clamp = clamp;
+        self
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct StencilOpState {
+    pub fail_op: vk::StencilOp,
+    pub pass_op: vk::StencilOp,
+    pub depth_fail_op: vk::StencilOp,
+    pub compare_op: vk::CompareOp,
+    pub compare_mask: u32,
+    pub write_mask: u32,
+    pub reference: u32,
+}
+
+impl StencilOpState {
+    pub fn new() -> Self {
+        Self {
+            fail_op: vk::StencilOp::KEEP,
+            pass_op: vk::StencilOp::KEEP,
+            depth_fail_op: vk::StencilOp::KEEP,
+            compare_op: vk::CompareOp::ALWAYS,
+            compare_mask: 0xff,
+            write_mask: 0xff,
+            reference: 0,
+        }
+    }
+
+    pub fn set_fail_op(mut self, fail_op: vk::StencilOp) -> Self {
+        self.fail_op = fail_op;
+        self
+    }
+
+    pub fn set_pass_op(mut self, pass_op: vk::StencilOp) -> Self {
+        self.pass_op = pass_op;
+        self
+    }
+
+    pub fn set_depth_fail_op(mut self, depth_fail_op: vk::StencilOp) -> Self {
+        self.depth_fail_op = depth_fail_op;
+        self
+    }
+
+    pub fn set_compare_op(mut self, compare_op: vk::CompareOp) -> Self {
+        self.compare_op = compare_op;
+        self
+    }
+
+    pub fn set_compare_mask(mut self, compare_mask: u32) -> Self {
+        self.compare_mask = compare_mask;
+        self
+    }
+
+    pub fn set_write_mask(mut self, write_mask: u32) -> Self {
+        self.write_mask = write_mask;
+        self
+    }
+
+    pub fn set_reference(mut self, reference: u32) -> Self {
+        self.reference = reference;
+        self
+    }
+
+    pub fn vk_stencil_op_state(&self) -> vk::StencilOpState {
+        vk::StencilOpState {
+            fail_op: self.fail_op,
+            pass_op: self.pass_op,
+            depth_fail_op: self.depth_fail_op,
+            compare_op: self.compare_op,
+            compare_mask: self.compare_mask,
+            write_mask: self.write_mask,
+            reference: self.reference,
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct DepthStencilState {
     pub depth_test_enable: bool,
     pub depth_write_enable: bool,
     pub depth_compare: vk::CompareOp,
-    // XXX: Add stencil states
+    pub stencil_test_enable: bool,
+    pub stencil_front: StencilOpState,
+    pub stencil_back: StencilOpState,
 }
 
 impl DepthStencilState {
@@ -150,9 +367,27 @@ impl DepthStencilState {
             depth_test_enable: true,
             depth_write_enable: true,
             depth_compare: vk::CompareOp::LESS_OR_EQUAL,
+            stencil_test_enable: false,
+            stencil_front: StencilOpState::new(),
+            stencil_back: StencilOpState::new(),
         }
     }
 
+    pub fn set_stencil_test(mut self, enable: bool) -> Self {
+        self.stencil_test_enable = enable;
+        self
+    }
+
+    pub fn set_stencil_front(mut self, stencil_front: StencilOpState) -> Self {
+        self.stencil_front = stencil_front;
+        self
+    }
+
+    pub fn set_stencil_back(mut self, stencil_back: StencilOpState) -> Self {
+        self.stencil_back = stencil_back;
+        self
+    }
+
     pub fn set_depth_test(mut self, enable: bool) -> Self {
         self.depth_test_enable = enable;
         self
@@ -234,6 +469,13 @@ impl RenderColorAttachment {
         self.image_view = image_view;
         self
     }
+
+    /// Renders into `view`, eg. a single layer or mip level of an image
+    pub fn set_view(mut self, view: &ImageView) -> Self {
+        self.image_view = view.raw();
+        self.format = view.format();
+        self
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -243,7 +485,9 @@ pub struct RenderDepthStencilAttachment {
 
     pub image_layout: vk::ImageLayout,
     pub depth_operation: RenderPassOperation,
+    /// Only used if the format has a stencil aspect
     pub stencil_operation: RenderPassOperation,
+    pub store_stencil: bool,
     pub clear_value: vk::ClearDepthStencilValue,
 
     // XXX: Need struct for non-owning view?
@@ -257,6 +501,7 @@ impl RenderDepthStencilAttachment {
             image_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
             depth_operation: RenderPassOperation::DontCare,
             stencil_operation: RenderPassOperation::DontCare,
+            store_stencil: true,
             clear_value: vk::ClearDepthStencilValue::default(),
             image_view: vk::ImageView::null(),
         }
@@ -282,6 +527,11 @@ impl RenderDepthStencilAttachment {
         self
     }
 
+    pub fn set_store_stencil(mut self, store_stencil: bool) -> Self {
+        self.store_stencil = store_stencil;
+        self
+    }
+
     pub fn set_clear_value(mut self, clear_value: vk::ClearDepthStencilValue) -> Self {
         self.clear_value = clear_value;
         self
@@ -291,6 +541,13 @@ impl RenderDepthStencilAttachment {
         self.image_view = image_view;
         self
     }
+
+    /// Renders into `view`, eg. a single layer or mip level of an image
+    pub fn set_view(mut self, view: &ImageView) -> Self {
+        self.image_view = view.raw();
+        self.format = view.format();
+        self
+    }
 }
 
 #[derive(Clone)]
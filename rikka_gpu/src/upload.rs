@@ -0,0 +1,175 @@
+use std::{collections::VecDeque, time::Duration};
+
+use parking_lot::Mutex;
+
+use rikka_core::vk;
+
+use crate::{
+    buffer::Buffer,
+    command_buffer::*,
+    error::Result,
+    escape::Handle,
+    factory::DeviceGuard,
+    image::Image,
+    queue::{Queue, SemaphoreSubmitInfo},
+    synchronization::*,
+};
+
+/// Batch that is still being recorded, buffers and images used by the commands are kept alive until the batch
+/// completes
+struct RecordingBatch {
+    command_buffer: CommandBuffer,
+    buffers: Vec<Handle<Buffer>>,
+    images: Vec<Handle<Image>>,
+}
+
+struct SubmittedBatch {
+    /// Upload timeline value signaled when the batch completes
+    value: u64,
+    command_buffer: CommandBuffer,
+    _buffers: Vec<Handle<Buffer>>,
+    _images: Vec<Handle<Image>>,
+}
+
+#[derive(Default)]
+struct UploadBatches {
+    recording: Option<RecordingBatch>,
+    submitted: VecDeque<SubmittedBatch>,
+    last_submitted_value: u64,
+}
+
+/// Records immediate uploads and layout transitions into a single command buffer that is submitted on the
+/// graphics queue when flushed. Every flush signals a new value of the upload timeline instead of waiting for the
+/// queue to be idle, so uploads overlap with each other and with frame work.
+pub(crate) struct UploadContext {
+    device: DeviceGuard,
+    command_pool: CommandPool,
+    semaphore: Semaphore,
+    batches: Mutex<UploadBatches>,
+}
+
+impl UploadContext {
+    pub fn new(device: DeviceGuard, queue_family_index: u32) -> Result<Self> {
+        let command_pool = CommandPool::new(device.clone(), queue_family_index)?;
+        let semaphore = Semaphore::new(device.clone(), SemaphoreType::Timeline)?;
+
+        Ok(Self {
+            device,
+            command_pool,
+            semaphore,
+            batches: Mutex::new(UploadBatches::default()),
+        })
+    }
+
+    /// Records commands into the current batch, `buffers` and `images` are released once the batch completed on the
+    /// Gpu
+    pub fn record(
+        &self,
+        buffers: &[&Handle<Buffer>],
+        images: &[&Handle<Image>],
+        record: impl FnOnce(&CommandBuffer) -> Result<()>,
+    ) -> Result<()> {
+        let mut batches = self.batches.lock();
+
+        if batches.recording.is_none() {
+            let command_buffer = CommandBuffer::new(
+                self.device.clone(),
+                self.command_pool
+                    .allocate_command_buffer(vk::CommandBufferLevel::PRIMARY)?,
+                // XXX: Implement trait default for this
+                CommandBufferMetaData {
+                    array_index: 0,
+                    frame_index: 0,
+                    thread_index: 0,
+                },
+                false,
+            );
+            command_buffer.begin()?;
+
+            batches.recording = Some(RecordingBatch {
+                command_buffer,
+                buffers: Vec::new(),
+                images: Vec::new(),
+            });
+        }
+
+        let batch = batches.recording.as_mut().unwrap();
+        record(&batch.command_buffer)?;
+        batch
+            .buffers
+            .extend(buffers.iter().map(|buffer| (*buffer).clone()));
+        batch
+            .images
+            .extend(images.iter().map(|image| (*image).clone()));
+
+        Ok(())
+    }
+
+    /// Submits the recorded batch, returns the upload timeline value that is signaled once all uploads recorded so
+    /// far completed
+    pub fn flush(&self, queue: &Queue) -> Result<u64> {
+        let mut batches = self.batches.lock();
+
+        let batch = match batches.recording.take() {
+            Some(batch) => batch,
+            None => return Ok(batches.last_submitted_value),
+        };
+
+        // Make the uploads visible to all work submitted afterwards
+        batch.command_buffer.memory_barrier(
+            vk::PipelineStageFlags2::ALL_COMMANDS,
+            vk::AccessFlags2::MEMORY_WRITE,
+            vk::PipelineStageFlags2::ALL_COMMANDS,
+            vk::AccessFlags2::MEMORY_READ | vk::AccessFlags2::MEMORY_WRITE,
+        );
+        batch.command_buffer.end()?;
+
+        let value = batches.last_submitted_value + 1;
+        queue.submit(
+            &[&batch.command_buffer],
+            &[],
+            &[SemaphoreSubmitInfo {
+                semaphore: &self.semaphore,
+                stage_mask: vk::PipelineStageFlags2::ALL_COMMANDS,
+                value: Some(value),
+            }],
+        )?;
+
+        batches.last_submitted_value = value;
+        batches.submitted.push_back(SubmittedBatch {
+            value,
+            command_buffer: batch.command_buffer,
+            _buffers: batch.buffers,
+            _images: batch.images,
+        });
+
+        Ok(value)
+    }
+
+    pub fn completed_value(&self) -> Result<u64> {
+        self.semaphore.counter_value()
+    }
+
+    /// Returns false if the upload timeline did not reach `value` within `timeout`
+    pub fn wait_for_value(&self, value: u64, timeout: Duration) -> Result<bool> {
+        self.semaphore.wait_for_value_timeout(value, timeout)
+    }
+
+    /// Frees the command buffers and releases the buffers and images of completed batches
+    pub fn retire_completed(&self) -> Result<()> {
+        let completed_value = self.completed_value()?;
+        let mut batches = self.batches.lock();
+
+        while batches
+            .submitted
+            .front()
+            .map_or(false, |batch| batch.value <= completed_value)
+        {
+            let batch = batches.submitted.pop_front().unwrap();
+            self.command_pool
+                .free_command_buffers(&[batch.command_buffer.raw()]);
+        }
+
+        Ok(())
+    }
+}
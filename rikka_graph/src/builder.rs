@@ -45,6 +45,7 @@ impl Builder {
             .set_producer(ResourceHandle::invalid())
             .set_output(ResourceHandle::invalid())
             .set_ref_count(0);
+        resource.history = desc.history;
 
         resource_handle
     }
@@ -79,7 +80,9 @@ impl Builder {
 
         self.access_node_mut(node_handle)
             .set_name(desc.name.clone())
-            .set_enable(desc.enabled);
+            .set_enable(desc.enabled)
+            .set_type(desc.node_type)
+            .set_clear_values(desc.clear_values);
 
         self.node_cache
             .node_map
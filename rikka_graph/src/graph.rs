@@ -1,39 +1,216 @@
+use std::collections::HashMap;
+
 use anyhow::Result;
 
 use rikka_core::vk;
 use rikka_gpu::{
     barriers::{Barriers, ResourceState},
+    buffer::Buffer,
     command_buffer::CommandBuffer,
+    constants::MAX_FRAMES,
+    escape::Handle,
+    event::Event,
     gpu::Gpu,
     image::*,
+    profiling,
     types::*,
 };
 
 use crate::{builder::*, types::*};
 
+/// Changes applied by `Graph::reload`
+#[derive(Debug, Default)]
+pub struct GraphReloadDiff {
+    pub added_nodes: Vec<String>,
+    pub removed_nodes: Vec<String>,
+    /// Attachments whose Gpu images were (re)created, all other attachments keep their previous images
+    pub recreated_attachments: Vec<String>,
+}
+
+impl GraphReloadDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added_nodes.is_empty()
+            && self.removed_nodes.is_empty()
+            && self.recreated_attachments.is_empty()
+    }
+}
+
+/// Transition of a texture whose only consumer runs at least one pass after the producer. The event is set after the
+/// producer and waited on by the consumer, so the passes in between overlap with the transition.
+struct SplitBarrier {
+    producer: NodeHandle,
+    consumer: NodeHandle,
+    /// Output of the producer
+    resource: ResourceHandle,
+    /// Per frame in flight, reset after the wait
+    events: Vec<Handle<Event>>,
+}
+
 pub struct Graph {
     // pub(crate) builder: Builder,
     // pub(crate) nodes: Vec<NodeHandle>,
     pub builder: Builder,
     pub nodes: Vec<NodeHandle>,
+    source_file_name: Option<String>,
+    /// Extent relative attachment resolutions are based on, the swapchain extent if not set
+    extent: Option<vk::Extent2D>,
+    /// Recomputed for the enabled nodes on compile
+    split_barriers: Vec<SplitBarrier>,
+    /// Frame in flight set by `prepare`, selects the split barrier events
+    frame_index: usize,
 }
 
 impl Graph {
     pub fn new(builder: Builder, nodes: Vec<NodeHandle>) -> Self {
-        Self { builder, nodes }
+        Self {
+            builder,
+            nodes,
+            source_file_name: None,
+            extent: None,
+            split_barriers: Vec::new(),
+            frame_index: 0,
+        }
+    }
+
+    pub fn set_source_file_name(&mut self, file_name: &str) {
+        self.source_file_name = Some(file_name.to_string());
+    }
+
+    /// File the graph was parsed from
+    pub fn source_file_name(&self) -> Option<&str> {
+        self.source_file_name.as_deref()
+    }
+
+    /// Re-parses the graph from its source file and applies it with `reload`
+    pub fn reload_from_file(&mut self, gpu: &mut Gpu) -> Result<GraphReloadDiff> {
+        let file_name = self
+            .source_file_name
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("Render graph was not loaded from a file"))?;
+        let new_graph = crate::parser::parse_from_file(&file_name)?;
+
+        self.reload(gpu, new_graph)
+    }
+
+    /// Replaces this graph with a new (uncompiled) graph. Attachments with unchanged descriptions keep their Gpu images
+    /// and render passes are re-registered to nodes of the same name.
+    /// The graph is left untouched if the new graph fails to compile.
+    /// The caller needs to make sure the Gpu is not using any graph resources.
+    pub fn reload(&mut self, gpu: &mut Gpu, mut new_graph: Graph) -> Result<GraphReloadDiff> {
+        let mut diff = GraphReloadDiff::default();
+
+        new_graph.extent = self.extent;
+        new_graph.resolve_relative_resolutions(new_graph.reference_extent(gpu))?;
+
+        let old_node_names = self.node_names()?;
+        let new_node_names = new_graph.node_names()?;
+        diff.added_nodes = new_node_names
+            .iter()
+            .filter(|name| !old_node_names.contains(name))
+            .cloned()
+            .collect();
+        diff.removed_nodes = old_node_names
+            .iter()
+            .filter(|name| !new_node_names.contains(name))
+            .cloned()
+            .collect();
+
+        // Reuse Gpu images of attachments that did not change, external images stay bound
+        for node_handle in new_graph.nodes.clone() {
+            let outputs = new_graph
+                .builder
+                .access_node_by_handle(&node_handle)?
+                .outputs
+                .clone();
+
+            for output_handle in outputs {
+                let resource = new_graph
+                    .builder
+                    .access_resource_mut_by_handle(&output_handle)?;
+                if resource.resource_type != ResourceType::Attachment {
+                    continue;
+                }
+
+                let old_image = self
+                    .builder
+                    .access_resource_by_name(&resource.name)
+                    .ok()
+                    .filter(|old_resource| old_resource.resource_type == ResourceType::Attachment)
+                    .and_then(|old_resource| old_resource.info.image.as_ref())
+                    .filter(|old_image_info| {
+                        resource
+                            .info
+                            .image
+                            .as_ref()
+                            .map_or(false, |image_info| image_info.matches(old_image_info))
+                    })
+                    .cloned();
+
+                match old_image.filter(|old_image_info| old_image_info.image.is_some()) {
+                    Some(old_image_info) => {
+                        let image_info = resource.info.image.as_mut().unwrap();
+                        image_info.image = old_image_info.image;
+                        image_info.history_image = old_image_info.history_image;
+                        image_info.history_valid = old_image_info.history_valid;
+                    }
+                    None if !resource.info.external => {
+                        diff.recreated_attachments.push(resource.name.clone())
+                    }
+                    None => {}
+                }
+            }
+        }
+
+        new_graph.compile(gpu)?;
+
+        // Move registered render passes
+        for node_handle in &new_graph.nodes {
+            let name = new_graph
+                .builder
+                .access_node_by_handle(node_handle)?
+                .name
+                .clone();
+            if let Ok(old_node) = self.builder.access_node_mut_by_name(&name) {
+                let render_pass = old_node.render_pass.take();
+                new_graph
+                    .builder
+                    .access_node_mut_by_handle(node_handle)?
+                    .render_pass = render_pass;
+            }
+        }
+
+        new_graph.source_file_name = self.source_file_name.take();
+        *self = new_graph;
+
+        log::info!("Render graph reloaded: {:?}", diff);
+
+        Ok(diff)
+    }
+
+    fn node_names(&self) -> Result<Vec<String>> {
+        self.nodes
+            .iter()
+            .map(|node_handle| {
+                Ok(self
+                    .builder
+                    .access_node_by_handle(node_handle)?
+                    .name
+                    .clone())
+            })
+            .collect()
     }
 
     pub fn reset(&mut self) {
         todo!()
     }
 
-    /// Enable render pass node
+    /// Enable render pass node, the graph needs to be recompiled afterwards
     pub fn enable_render_pass(&mut self, name: &str) -> Result<()> {
         self.builder.access_node_mut_by_name(name)?.set_enable(true);
         Ok(())
     }
 
-    /// Disable render pass node
+    /// Disable render pass node, the graph needs to be recompiled afterwards
     pub fn disable_render_pass(&mut self, name: &str) -> Result<()> {
         self.builder
             .access_node_mut_by_name(name)?
@@ -41,13 +218,339 @@ impl Graph {
         Ok(())
     }
 
+    /// Enables or disables a render pass node and recompiles the graph.
+    /// The previous state is restored if the graph is not valid with the change(eg. an enabled node consumes an output of the node).
+    /// The caller needs to make sure the Gpu is not using any graph resources.
+    pub fn set_render_pass_enabled(
+        &mut self,
+        gpu: &mut Gpu,
+        name: &str,
+        enabled: bool,
+    ) -> Result<()> {
+        let previous = self.render_pass_enabled(name)?;
+        if previous == enabled {
+            return Ok(());
+        }
+
+        self.builder
+            .access_node_mut_by_name(name)?
+            .set_enable(enabled);
+
+        if let Err(err) = self.compile(gpu) {
+            self.builder
+                .access_node_mut_by_name(name)?
+                .set_enable(previous);
+            self.compile(gpu)?;
+            return Err(err.context(format!(
+                "Failed to {} render pass {}",
+                if enabled { "enable" } else { "disable" },
+                name
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub fn render_pass_enabled(&self, name: &str) -> Result<bool> {
+        Ok(self.builder.access_node_by_name(name)?.enabled)
+    }
+
+    /// Names of all render pass nodes with their enabled state, in execution order
+    pub fn render_pass_states(&self) -> Result<Vec<(String, bool)>> {
+        self.nodes
+            .iter()
+            .map(|node_handle| {
+                let node = self.builder.access_node_by_handle(node_handle)?;
+                Ok((node.name.clone(), node.enabled))
+            })
+            .collect()
+    }
+
+    /// Checks that the graph is well formed, all problems found are listed in the returned error
+    pub fn validate(&self) -> Result<()> {
+        let mut errors = Vec::new();
+
+        let mut output_producers = HashMap::<&str, &str>::new();
+        for node_handle in &self.nodes {
+            let node = self.builder.access_node_by_handle(node_handle)?;
+
+            let mut depth_attachment_count = 0;
+            let mut resolution = None;
+
+            for output_handle in &node.outputs {
+                let output = self.builder.access_resource_by_handle(output_handle)?;
+                if output.resource_type == ResourceType::Reference {
+                    continue;
+                }
+
+                if let Some(producer) = output_producers.insert(&output.name, &node.name) {
+                    errors.push(format!(
+                        "Resource {} is produced by both {} and {}",
+                        output.name, producer, node.name
+                    ));
+                }
+
+                if output.resource_type == ResourceType::Attachment {
+                    let image_info = match output.info.image.as_ref() {
+                        Some(image_info) => image_info,
+                        None => {
+                            errors.push(format!(
+                                "Attachment {} of node {} does not have an image description",
+                                output.name, node.name
+                            ));
+                            continue;
+                        }
+                    };
+
+                    if output.info.external && node.enabled {
+                        match image_info.image.as_ref() {
+                            None => errors.push(format!(
+                                "External attachment {} of node {} does not have a bound image",
+                                output.name, node.name
+                            )),
+                            Some(image)
+                                if (image.width(), image.height())
+                                    != (image_info.width, image_info.height) =>
+                            {
+                                errors.push(format!(
+                                    "External attachment {} of node {} is declared as {}x{}, the bound image is {}x{}",
+                                    output.name,
+                                    node.name,
+                                    image_info.width,
+                                    image_info.height,
+                                    image.width(),
+                                    image.height()
+                                ))
+                            }
+                            _ => {}
+                        }
+                    }
+
+                    if format_has_depth(image_info.format) {
+                        depth_attachment_count += 1;
+
+                        if node.is_compute() {
+                            errors.push(format!(
+                                "Compute node {} cannot write depth attachment {}",
+                                node.name, output.name
+                            ));
+                        }
+                    }
+
+                    match resolution {
+                        Some((width, height))
+                            if (width, height) != (image_info.width, image_info.height) =>
+                        {
+                            errors.push(format!(
+                                "Attachment {} of node {} has resolution {}x{}, other attachments of the node are {}x{}",
+                                output.name,
+                                node.name,
+                                image_info.width,
+                                image_info.height,
+                                width,
+                                height
+                            ));
+                        }
+                        _ => resolution = Some((image_info.width, image_info.height)),
+                    }
+                }
+            }
+
+            if depth_attachment_count > 1 && node.is_graphics() {
+                errors.push(format!(
+                    "Node {} has {} depth attachments, only one is supported",
+                    node.name, depth_attachment_count
+                ));
+            }
+        }
+
+        // Producer -> consumer edges of enabled nodes
+        let mut edges = HashMap::<usize, Vec<usize>>::new();
+        for node_handle in &self.nodes {
+            let node = self.builder.access_node_by_handle(node_handle)?;
+            if !node.enabled {
+                continue;
+            }
+
+            for input_handle in &node.inputs {
+                let input = self.builder.access_resource_by_handle(input_handle)?;
+
+                let output = match self.builder.access_resource_by_name(&input.name) {
+                    Ok(output) => output,
+                    Err(_) => {
+                        errors.push(format!(
+                            "Input {} of node {} is not produced by any node",
+                            input.name, node.name
+                        ));
+                        continue;
+                    }
+                };
+
+                let compatible = match input.resource_type {
+                    ResourceType::Buffer => output.resource_type == ResourceType::Buffer,
+                    ResourceType::Texture | ResourceType::Attachment => {
+                        output.resource_type == ResourceType::Attachment
+                            || output.resource_type == ResourceType::Texture
+                    }
+                    ResourceType::Reference => true,
+                };
+                if !compatible {
+                    errors.push(format!(
+                        "Input {} of node {} is a {:?} but is produced as a {:?}",
+                        input.name, node.name, input.resource_type, output.resource_type
+                    ));
+                }
+
+                // History inputs read the previous frame and do not depend on the producer
+                if input.history {
+                    if !output
+                        .info
+                        .image
+                        .as_ref()
+                        .map_or(false, |image| image.history)
+                    {
+                        errors.push(format!(
+                            "History input {} of node {} is not produced as a history resource",
+                            input.name, node.name
+                        ));
+                    }
+                    continue;
+                }
+
+                if output.info.external {
+                    continue;
+                }
+                match self.builder.access_node_by_handle(&output.producer) {
+                    Ok(producer) if !producer.enabled => errors.push(format!(
+                        "Input {} of node {} is produced by disabled node {}",
+                        input.name, node.name, producer.name
+                    )),
+                    Ok(_) => edges
+                        .entry(output.producer.index)
+                        .or_default()
+                        .push(node_handle.index),
+                    Err(_) => {}
+                }
+            }
+        }
+
+        if let Some(cycle) = self.find_cycle(&edges) {
+            let cycle_names = cycle
+                .iter()
+                .map(|index| {
+                    self.builder
+                        .access_node_by_handle(&NodeHandle::new(*index))
+                        .map_or("?", |node| node.name.as_str())
+                })
+                .collect::<Vec<_>>();
+            errors.push(format!(
+                "Cyclic dependency between nodes: {}",
+                cycle_names.join(" -> ")
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "Invalid render graph:\n{}",
+                errors.join("\n")
+            ))
+        }
+    }
+
+    /// Returns the node indices of a dependency cycle, with the first node repeated at the end
+    fn find_cycle(&self, edges: &HashMap<usize, Vec<usize>>) -> Option<Vec<usize>> {
+        // 1: On the current path
+        // 2: Done
+        let mut visited = HashMap::<usize, u8>::new();
+        let mut path = Vec::new();
+
+        fn visit(
+            node: usize,
+            edges: &HashMap<usize, Vec<usize>>,
+            visited: &mut HashMap<usize, u8>,
+            path: &mut Vec<usize>,
+        ) -> Option<Vec<usize>> {
+            visited.insert(node, 1);
+            path.push(node);
+
+            for next in edges.get(&node).into_iter().flatten() {
+                match visited.get(next) {
+                    Some(1) => {
+                        let start = path.iter().position(|index| index == next).unwrap();
+                        let mut cycle = path[start..].to_vec();
+                        cycle.push(*next);
+                        return Some(cycle);
+                    }
+                    Some(_) => {}
+                    None => {
+                        if let Some(cycle) = visit(*next, edges, visited, path) {
+                            return Some(cycle);
+                        }
+                    }
+                }
+            }
+
+            path.pop();
+            visited.insert(node, 2);
+            None
+        }
+
+        for node_handle in &self.nodes {
+            if !visited.contains_key(&node_handle.index) {
+                if let Some(cycle) = visit(node_handle.index, edges, &mut visited, &mut path) {
+                    return Some(cycle);
+                }
+            }
+        }
+
+        None
+    }
+
+    fn reference_extent(&self, gpu: &Gpu) -> vk::Extent2D {
+        self.extent.unwrap_or_else(|| gpu.swapchain_extent())
+    }
+
+    /// Returns the names of relative attachments whose size changed
+    fn resolve_relative_resolutions(&mut self, extent: vk::Extent2D) -> Result<Vec<String>> {
+        let mut resized = Vec::new();
+
+        for node_handle in self.nodes.clone() {
+            let outputs = self
+                .builder
+                .access_node_by_handle(&node_handle)?
+                .outputs
+                .clone();
+            for output_handle in outputs {
+                let resource = self.builder.access_resource_mut_by_handle(&output_handle)?;
+                if let Some(image_info) = resource.info.image.as_mut() {
+                    if image_info.resolve_relative_resolution(extent.width, extent.height) {
+                        resized.push(resource.name.clone());
+                    }
+                }
+            }
+        }
+
+        Ok(resized)
+    }
+
     pub fn compile(&mut self, gpu: &mut Gpu) -> Result<()> {
-        // Clear all node edges
+        self.resolve_relative_resolutions(self.reference_extent(gpu))?;
+        self.validate()?;
+
+        // Clear all node edges and ref counts, these are recomputed for the currently enabled nodes
         for node_handle in &self.nodes {
-            self.builder
-                .access_node_mut_by_handle(node_handle)?
-                .edges
-                .clear();
+            let outputs = {
+                let node = self.builder.access_node_mut_by_handle(node_handle)?;
+                node.edges.clear();
+                node.outputs.clone()
+            };
+            for output_handle in outputs {
+                self.builder
+                    .access_resource_mut_by_handle(&output_handle)?
+                    .set_ref_count(0);
+            }
         }
 
         // Compute edges for all nodes
@@ -62,7 +565,10 @@ impl Graph {
                     .clone();
 
                 for input in &node_inputs {
-                    let resource_name = self.builder.access_resource_by_handle(input)?.name.clone();
+                    let (resource_name, history) = {
+                        let input_resource = self.builder.access_resource_by_handle(input)?;
+                        (input_resource.name.clone(), input_resource.history)
+                    };
 
                     // `output_resource` -> `input_resource`
                     // XXX: name String is also cloned here, have a work around so this can be avoided?
@@ -77,6 +583,10 @@ impl Graph {
                         input_resource.output = output_resource.output;
                     }
 
+                    if history {
+                        continue;
+                    }
+
                     if let Ok(parent_node) = self
                         .builder
                         .access_node_mut_by_handle(&output_resource.producer)
@@ -88,6 +598,7 @@ impl Graph {
         }
 
         let mut sorted_nodes = Vec::new();
+        let mut disabled_nodes = Vec::new();
         let mut node_stack = Vec::new();
 
         // 1: Visited
@@ -122,11 +633,15 @@ impl Graph {
                         }
                     }
                 }
+            } else {
+                disabled_nodes.push(*node_handle);
             }
         }
 
-        assert!(self.nodes.len() == sorted_nodes.len());
+        assert!(self.nodes.len() == sorted_nodes.len() + disabled_nodes.len());
         sorted_nodes.reverse();
+        // Disabled nodes are kept so they can be enabled again later
+        sorted_nodes.append(&mut disabled_nodes);
         self.nodes = sorted_nodes;
 
         log::info!("Render graph render pass order:");
@@ -152,10 +667,14 @@ impl Graph {
                     .inputs
                     .clone();
                 for input_handle in &inputs {
-                    let output_handle = self
-                        .builder
-                        .access_resource_by_handle(&input_handle)?
-                        .output;
+                    let (output_handle, history) = {
+                        let input = self.builder.access_resource_by_handle(&input_handle)?;
+                        (input.output, input.history)
+                    };
+                    // History inputs are not aliased, both images stay alive
+                    if history {
+                        continue;
+                    }
 
                     // Increase ref count of outputs that are used as inputs
                     self.builder
@@ -173,9 +692,9 @@ impl Graph {
                 continue;
             }
 
-            let (outputs, inputs) = {
+            let (outputs, inputs, is_compute) = {
                 let node = self.builder.access_node_by_handle(&node_handle)?;
-                (node.outputs.clone(), node.inputs.clone())
+                (node.outputs.clone(), node.inputs.clone(), node.is_compute())
             };
 
             for output_handle in outputs {
@@ -191,7 +710,10 @@ impl Graph {
                 if !resource_info.external {
                     if resource_type == ResourceType::Attachment {
                         let image_info = &resource_info.image.unwrap();
-                        if !image_free_list.is_empty() {
+                        if image_info.image.is_some() {
+                            // Image is kept from a previous compilation
+                            continue;
+                        } else if !image_free_list.is_empty() {
                             // XXX: Reuse free images
                             todo!()
                         } else {
@@ -201,13 +723,26 @@ impl Graph {
                                 image_info.depth,
                             )
                             .set_format(image_info.format)
-                            .set_image_type(vk::ImageType::TYPE_2D)
+                            .set_image_type(if image_info.depth > 1 {
+                                vk::ImageType::TYPE_3D
+                            } else {
+                                vk::ImageType::TYPE_2D
+                            })
                             .set_usage_flags(image_info.usage_flags);
 
-                            if !format_has_depth(image_info.format) {
+                            // XXX: Views of combined depth stencil images cannot be sampled
+                            if !format_has_stencil(image_info.format) || image_info.history {
                                 image_desc.usage_flags |= vk::ImageUsageFlags::SAMPLED;
                             }
+                            if is_compute {
+                                image_desc.usage_flags |= vk::ImageUsageFlags::STORAGE;
+                            }
 
+                            let history_image = if image_info.history {
+                                Some(gpu.create_image(image_desc.clone())?)
+                            } else {
+                                None
+                            };
                             let image = gpu.create_image(image_desc)?;
                             log::trace!(
                                 "Created Gpu image for node output {} with bindless index {}",
@@ -222,6 +757,18 @@ impl Graph {
                                 .as_mut()
                                 .unwrap()
                                 .image = Some(image);
+
+                            if history_image.is_some() {
+                                let image_info = self
+                                    .builder
+                                    .access_resource_mut_by_handle(&output_handle)?
+                                    .info
+                                    .image
+                                    .as_mut()
+                                    .unwrap();
+                                image_info.history_image = history_image;
+                                image_info.history_valid = false;
+                            }
                         }
                     }
                 }
@@ -230,8 +777,13 @@ impl Graph {
             // Reuse images if it is not referenced anymore/image aliasing
             for node_handle in inputs {
                 // Resource handle this input originates from
-                let origin_resource_handle =
-                    self.builder.access_resource_by_handle(&node_handle)?.output;
+                let (origin_resource_handle, history) = {
+                    let input = self.builder.access_resource_by_handle(&node_handle)?;
+                    (input.output, input.history)
+                };
+                if history {
+                    continue;
+                }
 
                 // XXX: Re-design to work around the borrow checker nicely
                 let (resource_info, resource_type, ref_count) = {
@@ -265,7 +817,7 @@ impl Graph {
             let mut rendering_state = None;
             {
                 let node = self.builder.access_node_by_handle(&node_handle)?;
-                if !node.enabled {
+                if !node.enabled || !node.is_graphics() {
                     continue;
                 }
 
@@ -281,9 +833,124 @@ impl Graph {
             }
         }
 
+        self.split_barriers = self.create_split_barriers(gpu)?;
+
+        Ok(())
+    }
+
+    /// Split barriers for texture outputs with a single consumer that does not directly follow the producer
+    fn create_split_barriers(&self, gpu: &Gpu) -> Result<Vec<SplitBarrier>> {
+        let mut enabled_nodes = Vec::new();
+        for node_handle in &self.nodes {
+            if self.builder.access_node_by_handle(node_handle)?.enabled {
+                enabled_nodes.push(*node_handle);
+            }
+        }
+
+        // Positions of the nodes reading an output in the current frame and whether they read it as a texture. Outputs
+        // that are also loaded as attachments or referenced by another node are transitioned by regular barriers.
+        let mut consumers = HashMap::<usize, Vec<(usize, bool)>>::new();
+        for (position, node_handle) in enabled_nodes.iter().enumerate() {
+            for input_handle in &self.builder.access_node_by_handle(node_handle)?.inputs {
+                let input = self.builder.access_resource_by_handle(input_handle)?;
+                if !input.history {
+                    consumers
+                        .entry(input.output.index)
+                        .or_default()
+                        .push((position, input.resource_type == ResourceType::Texture));
+                }
+            }
+        }
+
+        let mut split_barriers = Vec::new();
+        for (producer_position, node_handle) in enabled_nodes.iter().enumerate() {
+            for output_handle in &self.builder.access_node_by_handle(node_handle)?.outputs {
+                let consumer_position = match consumers.get(&output_handle.index).map(Vec::as_slice)
+                {
+                    Some(&[(consumer_position, true)])
+                        if consumer_position > producer_position + 1 =>
+                    {
+                        consumer_position
+                    }
+                    _ => continue,
+                };
+
+                let events = (0..MAX_FRAMES)
+                    .map(|_| gpu.create_event())
+                    .collect::<Result<Vec<_>, _>>()?;
+                split_barriers.push(SplitBarrier {
+                    producer: *node_handle,
+                    consumer: enabled_nodes[consumer_position],
+                    resource: *output_handle,
+                    events,
+                });
+            }
+        }
+
+        log::info!("Render graph uses {} split barriers", split_barriers.len());
+
+        Ok(split_barriers)
+    }
+
+    /// Transition of a split barrier from the producer write state to the consumer read state
+    fn split_barrier_barriers(&self, split_barrier: &SplitBarrier) -> Result<Barriers> {
+        let resource = self
+            .builder
+            .access_resource_by_handle(&split_barrier.resource)?;
+
+        Ok(Barriers::new().add_compute_image(
+            &resource.gpu_image()?,
+            self.output_write_state(resource)?,
+            ResourceState::SHADER_RESOURCE,
+        ))
+    }
+
+    /// Sets the events of the split barriers produced by the node, needs to be recorded after the node rendered
+    fn set_split_barrier_events(
+        &self,
+        command_buffer: &CommandBuffer,
+        node_handle: &NodeHandle,
+    ) -> Result<()> {
+        for split_barrier in &self.split_barriers {
+            if split_barrier.producer.index == node_handle.index {
+                command_buffer.set_event(
+                    &split_barrier.events[self.frame_index],
+                    self.split_barrier_barriers(split_barrier)?,
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Waits on the events of the split barriers consumed by the node and resets them for the next use
+    fn wait_split_barrier_events(
+        &self,
+        command_buffer: &CommandBuffer,
+        node_handle: &NodeHandle,
+    ) -> Result<()> {
+        for split_barrier in &self.split_barriers {
+            if split_barrier.consumer.index == node_handle.index {
+                let event = &split_barrier.events[self.frame_index];
+                command_buffer.wait_event(event, self.split_barrier_barriers(split_barrier)?);
+                command_buffer.reset_event(event, vk::PipelineStageFlags2::ALL_COMMANDS);
+            }
+        }
+
         Ok(())
     }
 
+    fn is_split_barrier_input(
+        &self,
+        node_handle: &NodeHandle,
+        output_handle: &ResourceHandle,
+    ) -> bool {
+        self.split_barriers.iter().any(|split_barrier| {
+            split_barrier.consumer.index == node_handle.index
+                && split_barrier.resource.index == output_handle.index
+        })
+    }
+
     fn create_rendering_state(&self, node: &Node) -> Result<RenderingState> {
         let mut rendering_state = RenderingState::new_dimensionless();
         let mut width = 0;
@@ -308,6 +975,11 @@ impl Graph {
                                 stencil: 0,
                             })
                             .set_depth_operation(RenderPassOperation::Clear)
+                            .set_stencil_operation(if format_has_stencil(image_info.format) {
+                                RenderPassOperation::Clear
+                            } else {
+                                RenderPassOperation::DontCare
+                            })
                             .set_image_view(image_info.image.as_ref().unwrap().raw_view()),
                     );
                 } else {
@@ -340,6 +1012,36 @@ impl Graph {
         Ok(rendering_state)
     }
 
+    /// Calls `RenderPass::prepare` of the enabled nodes, needs to be called before `render`
+    pub fn prepare(&mut self, frame_context: &FrameContext) -> Result<()> {
+        self.frame_index = frame_context.frame_index as usize;
+
+        for node_handle in self.nodes.clone() {
+            let node = self.builder.access_node_mut_by_handle(&node_handle)?;
+            if !node.enabled {
+                continue;
+            }
+
+            if let Some(render_pass) = node.render_pass.as_mut() {
+                render_pass.prepare(frame_context)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Notifies all registered render passes that a render technique was rebuilt
+    pub fn on_technique_reloaded(&mut self, file_name: &str) -> Result<()> {
+        for node_handle in self.nodes.clone() {
+            let node = self.builder.access_node_mut_by_handle(&node_handle)?;
+            if let Some(render_pass) = node.render_pass.as_mut() {
+                render_pass.on_technique_reloaded(file_name)?;
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn render(&self, command_buffer: &CommandBuffer) -> Result<()> {
         for node_handle in &self.nodes {
             let node = self.builder.access_node_by_handle(&node_handle)?;
@@ -347,28 +1049,67 @@ impl Graph {
                 continue;
             }
 
+            let _span = profiling::span(&node.name);
+            command_buffer.set_crash_marker(&node.name);
+
+            if node.is_clear() {
+                command_buffer.push_timestamp_scope(&node.name);
+                self.clear_outputs(command_buffer, node)?;
+                command_buffer.pop_timestamp_scope();
+                self.set_split_barrier_events(command_buffer, node_handle)?;
+                continue;
+            }
+
+            self.wait_split_barrier_events(command_buffer, node_handle)?;
+
+            // Resources are transitioned from their tracked states, which also cover accesses recorded outside of the
+            // graph
             let mut barriers = Barriers::new();
 
-            // Transition image barriers
             for input_handle in &node.inputs {
                 let input_resource = self.builder.access_resource_by_handle(&input_handle)?;
                 match input_resource.resource_type {
+                    ResourceType::Texture if input_resource.history => {
+                        let output_resource = self
+                            .builder
+                            .access_resource_by_handle(&input_resource.output)?;
+                        let image_info = output_resource.info.image.as_ref().unwrap();
+
+                        barriers = barriers.add_image_transition(
+                            image_info.history_image.as_ref().unwrap(),
+                            ResourceState::SHADER_RESOURCE,
+                        );
+                    }
+                    // Transitioned by the wait on the split barrier event
+                    ResourceType::Texture
+                        if self.is_split_barrier_input(node_handle, &input_resource.output) => {}
                     ResourceType::Texture => {
-                        let image = input_resource
-                            .info
-                            .image
-                            .as_ref()
-                            .unwrap()
-                            .image
-                            .as_ref()
-                            .unwrap();
+                        // Inputs copy their info before the output images are created, use the originating output
+                        let output_resource = self
+                            .builder
+                            .access_resource_by_handle(&input_resource.output)?;
 
-                        barriers = barriers.add_image(
-                            image,
-                            ResourceState::RENDER_TARGET,
+                        barriers = barriers.add_image_transition(
+                            &output_resource.gpu_image()?,
                             ResourceState::SHADER_RESOURCE,
                         );
                     }
+                    ResourceType::Buffer => {
+                        let output_resource = self
+                            .builder
+                            .access_resource_by_handle(&input_resource.output)?;
+                        if let Some(buffer) = output_resource
+                            .info
+                            .buffer
+                            .as_ref()
+                            .and_then(|buffer_info| buffer_info.buffer.as_ref())
+                        {
+                            barriers = barriers.add_buffer_transition(
+                                buffer,
+                                ResourceState::SHADER_RESOURCE | ResourceState::INDIRECT_ARGUMENT,
+                            );
+                        }
+                    }
                     _ => {}
                 }
             }
@@ -379,18 +1120,21 @@ impl Graph {
                     ResourceType::Attachment => {
                         let image_info = output_resource.info.image.as_ref().unwrap();
 
-                        if format_has_depth(image_info.format) {
-                            barriers = barriers.add_image(
-                                image_info.image.as_ref().unwrap(),
-                                ResourceState::UNDEFINED,
-                                ResourceState::DEPTH_WRITE,
-                            );
-                        } else {
-                            barriers = barriers.add_image(
-                                image_info.image.as_ref().unwrap(),
-                                ResourceState::UNDEFINED,
-                                ResourceState::RENDER_TARGET,
-                            );
+                        barriers = barriers.add_image_transition(
+                            image_info.image.as_ref().unwrap(),
+                            self.output_write_state(output_resource)?,
+                        );
+                    }
+                    ResourceType::Buffer if node.is_compute() => {
+                        // Previous reads need to finish before the buffer is written again
+                        if let Some(buffer) = output_resource
+                            .info
+                            .buffer
+                            .as_ref()
+                            .and_then(|buffer_info| buffer_info.buffer.as_ref())
+                        {
+                            barriers = barriers
+                                .add_buffer_transition(buffer, ResourceState::SHADER_ACCESS);
                         }
                     }
                     _ => {}
@@ -399,23 +1143,222 @@ impl Graph {
 
             command_buffer.pipeline_barrier(barriers);
 
-            // XXX: set viewport
+            if node.is_compute() {
+                // Compute passes bind their pipeline and dispatch themselves
+                if let Some(render_pass) = &node.render_pass {
+                    command_buffer.push_timestamp_scope(&node.name);
+                    render_pass.render(command_buffer)?;
+                    render_pass.post_render(command_buffer, self)?;
+                    command_buffer.pop_timestamp_scope();
+                }
+                self.set_split_barrier_events(command_buffer, node_handle)?;
+                continue;
+            }
 
             if let Some(render_pass) = &node.render_pass {
+                let rendering_state = node.rendering_state.as_ref().unwrap();
+
+                command_buffer.push_timestamp_scope(&node.name);
+
                 // render_pass.pre_render(command_buffer)?;
-                command_buffer.begin_rendering(node.rendering_state.as_ref().unwrap().clone());
+                command_buffer.begin_rendering(rendering_state.clone());
+                command_buffer.set_viewport_and_scissor(rendering_state);
 
                 render_pass.render(command_buffer)?;
 
                 command_buffer.end_rendering();
 
                 render_pass.post_render(command_buffer, self)?;
+
+                command_buffer.pop_timestamp_scope();
+            }
+            self.set_split_barrier_events(command_buffer, node_handle)?;
+        }
+
+        Ok(())
+    }
+
+    /// Clears the image outputs and fills the buffer outputs of a clear node, outputs are left in the shader access
+    /// state like outputs of compute nodes
+    fn clear_outputs(&self, command_buffer: &CommandBuffer, node: &Node) -> Result<()> {
+        let mut images = Vec::new();
+        let mut buffers = Vec::new();
+        for output_handle in &node.outputs {
+            let output_resource = self.builder.access_resource_by_handle(output_handle)?;
+            match output_resource.resource_type {
+                ResourceType::Attachment | ResourceType::Texture => {
+                    images.push(output_resource.gpu_image()?)
+                }
+                ResourceType::Buffer => {
+                    if let Some(buffer) = output_resource
+                        .info
+                        .buffer
+                        .as_ref()
+                        .and_then(|buffer_info| buffer_info.buffer.clone())
+                    {
+                        buffers.push(buffer);
+                    }
+                }
+                ResourceType::Reference => {}
+            }
+        }
+
+        let mut barriers = Barriers::new();
+        for image in &images {
+            barriers = barriers.add_image_transition(image, ResourceState::COPY_DESTINATION);
+        }
+        for buffer in &buffers {
+            barriers = barriers.add_buffer_transition(buffer, ResourceState::COPY_DESTINATION);
+        }
+        command_buffer.pipeline_barrier(barriers);
+
+        let clear_values = &node.clear_values;
+        for image in &images {
+            if image.has_depth() {
+                command_buffer.clear_depth_stencil_image(
+                    image,
+                    clear_values.depth,
+                    clear_values.stencil,
+                )?;
+            } else {
+                command_buffer.clear_color_image(image, clear_values.color)?;
+            }
+        }
+        for buffer in &buffers {
+            command_buffer.fill_buffer(buffer, 0, vk::WHOLE_SIZE, clear_values.buffer)?;
+        }
+
+        let mut barriers = Barriers::new();
+        for image in &images {
+            barriers = barriers.add_image_transition(image, ResourceState::SHADER_ACCESS);
+        }
+        for buffer in &buffers {
+            barriers = barriers.add_buffer_transition(buffer, ResourceState::SHADER_ACCESS);
+        }
+        command_buffer.pipeline_barrier(barriers);
+
+        Ok(())
+    }
+
+    /// Swaps the images of history resources, the contents rendered this frame become the history of the next frame.
+    /// Needs to be called once per frame after the graph is rendered.
+    // XXX: Only safe with a single frame in flight, the history images need to be buffered per frame otherwise
+    pub fn advance_history(&mut self) -> Result<()> {
+        for node_handle in self.nodes.clone() {
+            let (outputs, enabled) = {
+                let node = self.builder.access_node_by_handle(&node_handle)?;
+                (node.outputs.clone(), node.enabled)
+            };
+
+            let mut swapped = false;
+            for output_handle in outputs {
+                let resource = self.builder.access_resource_mut_by_handle(&output_handle)?;
+                let image_info = match resource.info.image.as_mut() {
+                    Some(image_info) if image_info.history => image_info,
+                    _ => continue,
+                };
+
+                if enabled {
+                    std::mem::swap(&mut image_info.image, &mut image_info.history_image);
+                    swapped = true;
+                }
+                image_info.history_valid = enabled;
+            }
+
+            // Rendering state references the previous image view
+            if swapped
+                && self
+                    .builder
+                    .access_node_by_handle(&node_handle)?
+                    .is_graphics()
+            {
+                let rendering_state = {
+                    let node = self.builder.access_node_by_handle(&node_handle)?;
+                    self.create_rendering_state(node)?
+                };
+                self.builder
+                    .access_node_mut_by_handle(&node_handle)?
+                    .rendering_state = Some(rendering_state);
             }
         }
 
         Ok(())
     }
 
+    /// Binds an externally created image(eg. a swapchain image) to an external attachment.
+    /// Images need to be bound before the graph is compiled, the image can be rebound at any time after that.
+    /// The caller needs to make sure the Gpu is not using the previously bound image.
+    pub fn bind_external_image(&mut self, name: &str, image: Handle<Image>) -> Result<()> {
+        let resource = self.builder.access_resource_mut_by_name(name)?;
+        if !resource.info.external {
+            return Err(anyhow::anyhow!("Resource {} is not external", name));
+        }
+
+        let image_info = resource
+            .info
+            .image
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("External resource {} is not an image", name))?;
+        if image.format() != image_info.format {
+            return Err(anyhow::anyhow!(
+                "External image {} has format {:?}, the graph declares {:?}",
+                name,
+                image.format(),
+                image_info.format
+            ));
+        }
+        // Relative resolutions are not resolved before the graph is compiled, these are checked when validating
+        if image_info.width != 0
+            && image_info.height != 0
+            && (image.width(), image.height()) != (image_info.width, image_info.height)
+        {
+            return Err(anyhow::anyhow!(
+                "External image {} is {}x{}, the graph declares {}x{}",
+                name,
+                image.width(),
+                image.height(),
+                image_info.width,
+                image_info.height
+            ));
+        }
+
+        image_info.image = Some(image);
+
+        // Rendering state of the producer references the previous image view
+        let producer = resource.producer;
+        let rendering_state = {
+            let node = self.builder.access_node_by_handle(&producer)?;
+            if node.rendering_state.is_some() {
+                Some(self.create_rendering_state(node)?)
+            } else {
+                None
+            }
+        };
+        if rendering_state.is_some() {
+            self.builder
+                .access_node_mut_by_handle(&producer)?
+                .rendering_state = rendering_state;
+        }
+
+        Ok(())
+    }
+
+    /// Binds an externally created buffer(eg. an indirect draw argument buffer written by a compute node) to a buffer resource
+    pub fn bind_buffer(&mut self, name: &str, buffer: Handle<Buffer>) -> Result<()> {
+        let resource = self.builder.access_resource_mut_by_name(name)?;
+        if resource.resource_type != ResourceType::Buffer {
+            return Err(anyhow::anyhow!("Resource {} is not a buffer", name));
+        }
+
+        resource.info.buffer = Some(BufferInfo {
+            size: buffer.size(),
+            usage_flags: buffer.usage_flags(),
+            buffer: Some(buffer),
+        });
+
+        Ok(())
+    }
+
     pub fn register_render_pass(
         &mut self,
         name: &str,
@@ -426,8 +1369,59 @@ impl Graph {
         Ok(())
     }
 
-    pub fn on_resize(&mut self, width: u32, height: u32) {
-        todo!()
+    /// Removes the render pass of a node, the node keeps its attachments but records no commands
+    pub fn unregister_render_pass(&mut self, name: &str) -> Result<()> {
+        let node = self.builder.access_node_mut_by_name(name)?;
+        node.render_pass = None;
+        Ok(())
+    }
+
+    /// Recreates relative resolution attachments for the new extent, recompiles the graph and calls
+    /// `RenderPass::on_resize` of all registered passes.
+    /// Returns the names of the recreated attachments, render passes referencing them need to be updated.
+    /// The caller needs to make sure the Gpu is not using any graph resources.
+    pub fn on_resize(&mut self, gpu: &mut Gpu, width: u32, height: u32) -> Result<Vec<String>> {
+        let extent = vk::Extent2D { width, height };
+        self.extent = Some(extent);
+
+        let resized = self.resolve_relative_resolutions(extent)?;
+        if !resized.is_empty() {
+            self.recreate_resized_attachments(gpu, &resized)?;
+        }
+
+        for node_handle in self.nodes.clone() {
+            let node = self.builder.access_node_mut_by_handle(&node_handle)?;
+            if let Some(render_pass) = node.render_pass.as_mut() {
+                render_pass.on_resize(gpu, width, height)?;
+            }
+        }
+
+        Ok(resized)
+    }
+
+    fn recreate_resized_attachments(&mut self, gpu: &mut Gpu, resized: &[String]) -> Result<()> {
+        for name in resized {
+            if let Some(image_info) = self
+                .builder
+                .access_resource_mut_by_name(name)?
+                .info
+                .image
+                .as_mut()
+            {
+                image_info.image = None;
+                image_info.history_image = None;
+                image_info.history_valid = false;
+            }
+        }
+
+        // Rendering states reference the previous image views
+        for node_handle in self.nodes.clone() {
+            self.builder
+                .access_node_mut_by_handle(&node_handle)?
+                .rendering_state = None;
+        }
+
+        self.compile(gpu)
     }
 
     pub fn access_resource_by_handle(&self, handle: ResourceHandle) -> Result<&Resource> {
@@ -442,6 +1436,190 @@ impl Graph {
         self.builder.access_node_by_name(name)
     }
 
+    /// Names of all image attachments produced by enabled nodes
+    pub fn attachment_names(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+
+        for node_handle in &self.nodes {
+            let node = self.builder.access_node_by_handle(node_handle)?;
+            if !node.enabled {
+                continue;
+            }
+
+            for output_handle in &node.outputs {
+                let resource = self.builder.access_resource_by_handle(output_handle)?;
+                if resource.resource_type == ResourceType::Attachment {
+                    names.push(resource.name.clone());
+                }
+            }
+        }
+
+        Ok(names)
+    }
+
+    /// State an image resource is left in after the graph is rendered
+    pub fn resource_final_state(&self, name: &str) -> Result<ResourceState> {
+        let resource = self.builder.access_resource_by_name(name)?;
+
+        for node_handle in &self.nodes {
+            let node = self.builder.access_node_by_handle(node_handle)?;
+            if !node.enabled {
+                continue;
+            }
+
+            for input_handle in &node.inputs {
+                let input = self.builder.access_resource_by_handle(input_handle)?;
+                if input.resource_type == ResourceType::Texture
+                    && !input.history
+                    && input.name == name
+                {
+                    return Ok(ResourceState::SHADER_RESOURCE);
+                }
+            }
+        }
+
+        self.output_write_state(resource)
+    }
+
+    /// State an output image is written in by its producer
+    fn output_write_state(&self, resource: &Resource) -> Result<ResourceState> {
+        let image = resource.gpu_image()?;
+        let producer = self.builder.access_node_by_handle(&resource.producer)?;
+
+        if producer.is_compute() || producer.is_clear() {
+            Ok(ResourceState::SHADER_ACCESS)
+        } else if image.has_depth() {
+            Ok(ResourceState::DEPTH_WRITE)
+        } else {
+            Ok(ResourceState::RENDER_TARGET)
+        }
+    }
+
+    /// Reads back a named image resource to the CPU. Needs to be called after the frame that rendered the graph is submitted.
+    pub fn capture_resource(&self, gpu: &mut Gpu, name: &str) -> Result<ImageCapture> {
+        let image = self.builder.access_resource_by_name(name)?.gpu_image()?;
+        let state = self.resource_final_state(name)?;
+
+        let data = gpu.read_image(&image, state)?;
+
+        Ok(ImageCapture {
+            name: name.to_string(),
+            width: image.width(),
+            height: image.height(),
+            format: image.format(),
+            data,
+        })
+    }
+
+    /// GraphViz DOT description of the nodes, resources and edges, nodes are listed in execution order.
+    /// Resources that share the same Gpu image are grouped into alias clusters.
+    pub fn export_dot(&self) -> Result<String> {
+        let escape = |name: &str| name.replace('"', "\\\"");
+
+        let mut dot = String::from("digraph render_graph {\n    rankdir=LR;\n");
+
+        // Nodes
+        for (order, node_handle) in self.nodes.iter().enumerate() {
+            let node = self.builder.access_node_by_handle(node_handle)?;
+            dot.push_str(&format!(
+                "    \"node_{}\" [shape=box, style={}, label=\"{}: {}\"];\n",
+                escape(&node.name),
+                if node.enabled { "solid" } else { "dashed" },
+                order,
+                escape(&node.name)
+            ));
+        }
+
+        // Resources, grouped by the Gpu image they use
+        let mut alias_groups = HashMap::<vk::Image, Vec<String>>::new();
+        for node_handle in &self.nodes {
+            let node = self.builder.access_node_by_handle(node_handle)?;
+            for output_handle in &node.outputs {
+                let resource = self.builder.access_resource_by_handle(output_handle)?;
+                if resource.resource_type == ResourceType::Reference {
+                    continue;
+                }
+
+                let mut label =
+                    format!("{}\\n{:?}", escape(&resource.name), resource.resource_type);
+                if let Some(image_info) = resource.info.image.as_ref() {
+                    label.push_str(&format!(
+                        "\\n{:?}\\n{}x{}x{}",
+                        image_info.format, image_info.width, image_info.height, image_info.depth
+                    ));
+                    if let Some(image) = image_info.image.as_ref() {
+                        alias_groups
+                            .entry(image.raw())
+                            .or_default()
+                            .push(resource.name.clone());
+                    }
+                }
+                if resource.info.external {
+                    label.push_str("\\nexternal");
+                }
+
+                dot.push_str(&format!(
+                    "    \"resource_{}\" [shape=ellipse, label=\"{}\"];\n",
+                    escape(&resource.name),
+                    label
+                ));
+            }
+        }
+
+        let mut alias_groups = alias_groups
+            .into_values()
+            .filter(|names| names.len() > 1)
+            .collect::<Vec<_>>();
+        alias_groups.sort();
+        for (index, names) in alias_groups.iter().enumerate() {
+            dot.push_str(&format!(
+                "    subgraph cluster_alias_{} {{\n        label=\"alias group {}\";\n        style=dotted;\n",
+                index, index
+            ));
+            for name in names {
+                dot.push_str(&format!("        \"resource_{}\";\n", escape(name)));
+            }
+            dot.push_str("    }\n");
+        }
+
+        // Edges
+        for node_handle in &self.nodes {
+            let node = self.builder.access_node_by_handle(node_handle)?;
+
+            for output_handle in &node.outputs {
+                let resource = self.builder.access_resource_by_handle(output_handle)?;
+                if resource.resource_type == ResourceType::Reference {
+                    continue;
+                }
+                dot.push_str(&format!(
+                    "    \"node_{}\" -> \"resource_{}\";\n",
+                    escape(&node.name),
+                    escape(&resource.name)
+                ));
+            }
+
+            for input_handle in &node.inputs {
+                let resource = self.builder.access_resource_by_handle(input_handle)?;
+                dot.push_str(&format!(
+                    "    \"resource_{}\" -> \"node_{}\" [label=\"{:?}{}\"{}];\n",
+                    escape(&resource.name),
+                    escape(&node.name),
+                    resource.resource_type,
+                    if resource.history { " (history)" } else { "" },
+                    if resource.history {
+                        ", style=dashed"
+                    } else {
+                        ""
+                    }
+                ));
+            }
+        }
+
+        dot.push_str("}\n");
+
+        Ok(dot)
+    }
+
     pub fn add_node(&mut self, desc: NodeDesc) {
         todo!()
     }
@@ -15,12 +15,17 @@ mod tests {
         let input = parser::Input {
             resource_type: ResourceType::Attachment,
             name: String::from("gbuffer_colour"),
+            history: false,
         };
 
         let image = parser::ImageDesc {
             format: 32,
             resolution: [1280, 800],
+            resolution_scale: None,
+            depth: None,
             load_op: RenderPassOperation::Load,
+            external: false,
+            history: false,
         };
 
         let output = parser::Output {
@@ -31,6 +36,8 @@ mod tests {
 
         let main_pass = parser::Pass {
             name: String::from("gbuffer_pass"),
+            node_type: NodeType::Graphics,
+            clear_values: ClearValues::default(),
             inputs: vec![input],
             outputs: vec![output],
         };
@@ -38,6 +45,7 @@ mod tests {
         let graph = parser::Graph {
             name: String::from("main_graph"),
             passes: vec![main_pass],
+            subgraphs: Vec::new(),
         };
 
         let graph_json = serde_json::to_string_pretty(&graph).unwrap();
@@ -52,4 +60,105 @@ mod tests {
         // );
         // }
     }
+
+    fn buffer_node_desc(name: &str, inputs: &[&str], outputs: &[&str]) -> NodeDesc {
+        NodeDesc {
+            inputs: inputs
+                .iter()
+                .map(|name| InputDesc {
+                    resource_type: ResourceType::Buffer,
+                    name: name.to_string(),
+                    history: false,
+                })
+                .collect(),
+            outputs: outputs
+                .iter()
+                .map(|name| OutputDesc {
+                    resource_type: ResourceType::Buffer,
+                    name: name.to_string(),
+                    info: ResourceInfo::default(),
+                })
+                .collect(),
+            enabled: true,
+            name: name.to_string(),
+            node_type: NodeType::Graphics,
+            clear_values: ClearValues::default(),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut builder = builder::Builder::new();
+        let nodes = vec![
+            builder.create_node(buffer_node_desc("a", &["y"], &["x"])),
+            builder.create_node(buffer_node_desc("b", &["x", "missing"], &["y"])),
+        ];
+        let graph = builder.build(nodes);
+
+        let error = graph.validate().unwrap_err().to_string();
+        assert!(error.contains("Cyclic dependency between nodes: a -> b -> a"));
+        assert!(error.contains("Input missing of node b is not produced by any node"));
+
+        let mut builder = builder::Builder::new();
+        let nodes = vec![
+            builder.create_node(buffer_node_desc("a", &[], &["x"])),
+            builder.create_node(buffer_node_desc("b", &["x"], &["y"])),
+        ];
+        assert!(builder.build(nodes).validate().is_ok());
+    }
+
+    #[test]
+    fn test_subgraph_prefix() {
+        let bloom_graph: parser::Graph = serde_json::from_str(
+            r#"{
+                "name": "bloom",
+                "passes": [
+                    {
+                        "name": "downsample",
+                        "inputs": [{ "resource_type": "Texture", "name": "source" }],
+                        "outputs": [{ "resource_type": "Buffer", "name": "mips", "image": null }]
+                    },
+                    {
+                        "name": "composite",
+                        "inputs": [
+                            { "resource_type": "Texture", "name": "mips" },
+                            { "resource_type": "Texture", "name": "depth" }
+                        ],
+                        "outputs": [{ "resource_type": "Buffer", "name": "output", "image": null }]
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let subgraph = parser::Subgraph {
+            name: String::from("bloom"),
+            file: String::from("bloom_graph.json"),
+            inputs: [(String::from("source"), String::from("lighting"))]
+                .into_iter()
+                .collect(),
+        };
+        let passes = parser::prefix_subgraph_passes(&subgraph, bloom_graph);
+
+        assert_eq!(passes[0].name, "bloom_downsample");
+        assert_eq!(passes[0].inputs[0].name, "lighting");
+        assert_eq!(passes[0].outputs[0].name, "bloom_mips");
+        assert_eq!(passes[1].inputs[0].name, "bloom_mips");
+        assert_eq!(passes[1].inputs[1].name, "depth");
+        assert_eq!(passes[1].outputs[0].name, "bloom_output");
+    }
+
+    #[test]
+    fn test_export_dot() {
+        let mut builder = builder::Builder::new();
+        let nodes = vec![
+            builder.create_node(buffer_node_desc("a", &[], &["x"])),
+            builder.create_node(buffer_node_desc("b", &["x"], &["y"])),
+        ];
+        let dot = builder.build(nodes).export_dot().unwrap();
+
+        assert!(dot.starts_with("digraph render_graph {"));
+        assert!(dot.contains("\"node_a\" -> \"resource_x\";"));
+        assert!(dot.contains("\"resource_x\" -> \"node_b\" [label=\"Buffer\"];"));
+    }
 }
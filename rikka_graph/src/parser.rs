@@ -1,3 +1,8 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
 use rikka_gpu::{image::format_has_depth, types::RenderPassOperation};
 use serde::{Deserialize, Serialize};
 
@@ -12,6 +17,9 @@ use crate::{builder::*, graph, types::*};
 pub struct Input {
     pub resource_type: ResourceType,
     pub name: String,
+    /// Reads the previous frame contents of a history attachment
+    #[serde(default)]
+    pub history: bool,
 }
 
 impl Into<InputDesc> for Input {
@@ -19,6 +27,7 @@ impl Into<InputDesc> for Input {
         InputDesc {
             resource_type: self.resource_type,
             name: self.name,
+            history: self.history,
         }
     }
 }
@@ -27,8 +36,22 @@ impl Into<InputDesc> for Input {
 pub struct ImageDesc {
     // XXX: Change this to the actual VkFormat enum
     pub format: i32,
+    /// Absolute resolution, ignored if `resolution_scale` is set
+    #[serde(default)]
     pub resolution: [u32; 2],
+    /// Resolution relative to the swapchain, eg. 0.5 for half resolution
+    #[serde(default)]
+    pub resolution_scale: Option<f32>,
+    /// Depth of 3D images, eg. froxel volumes written by compute nodes
+    #[serde(default)]
+    pub depth: Option<u32>,
     pub load_op: RenderPassOperation,
+    /// Image is created outside the graph and bound with `Graph::bind_external_image`
+    #[serde(default)]
+    pub external: bool,
+    /// Keeps the previous frame contents available to history inputs
+    #[serde(default)]
+    pub history: bool,
 }
 
 impl Into<ImageInfo> for ImageDesc {
@@ -44,10 +67,14 @@ impl Into<ImageInfo> for ImageDesc {
             image: None,
             width: self.resolution[0],
             height: self.resolution[1],
-            depth: 1,
+            depth: self.depth.unwrap_or(1),
             format,
             usage_flags,
             load_op: self.load_op,
+            resolution_scale: self.resolution_scale,
+            history: self.history,
+            history_image: None,
+            history_valid: false,
         }
     }
 }
@@ -56,8 +83,8 @@ impl Into<ResourceInfo> for ImageDesc {
     fn into(self) -> ResourceInfo {
         ResourceInfo {
             buffer: None,
+            external: self.external,
             image: Some(self.into()),
-            external: false,
         }
     }
 }
@@ -86,6 +113,11 @@ impl Into<OutputDesc> for Output {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Pass {
     pub name: String,
+    #[serde(default)]
+    pub node_type: NodeType,
+    /// Only used by clear nodes
+    #[serde(default)]
+    pub clear_values: ClearValues,
     pub inputs: Vec<Input>,
     pub outputs: Vec<Output>,
 }
@@ -97,17 +129,120 @@ impl Into<NodeDesc> for Pass {
             outputs: self.outputs.into_iter().map(Into::into).collect::<Vec<_>>(),
             enabled: true,
             name: self.name,
+            node_type: self.node_type,
+            clear_values: self.clear_values,
         }
     }
 }
 
+/// Graph file included into another graph.
+/// Passes and resources produced by the subgraph are prefixed with `<name>_`,
+/// resources consumed but not produced by the subgraph keep their names unless bound in `inputs`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Subgraph {
+    pub name: String,
+    /// Path relative to the including graph file
+    pub file: String,
+    /// Subgraph input resource name -> resource name in the including graph
+    #[serde(default)]
+    pub inputs: HashMap<String, String>,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Graph {
     pub name: String,
     pub passes: Vec<Pass>,
+    #[serde(default)]
+    pub subgraphs: Vec<Subgraph>,
+}
+
+// XXX: Guards against include cycles
+const MAX_SUBGRAPH_DEPTH: usize = 16;
+
+/// Returns the passes of `graph`(with its own subgraphs already expanded) renamed to be included as `subgraph`
+pub fn prefix_subgraph_passes(subgraph: &Subgraph, graph: Graph) -> Vec<Pass> {
+    let prefixed_name = |name: &str| format!("{}_{}", subgraph.name, name);
+
+    let produced = graph
+        .passes
+        .iter()
+        .flat_map(|pass| pass.outputs.iter())
+        .filter(|output| output.resource_type != ResourceType::Reference)
+        .map(|output| output.name.clone())
+        .collect::<Vec<_>>();
+
+    let rename_resource = |name: &str| {
+        if produced.iter().any(|produced_name| produced_name == name) {
+            prefixed_name(name)
+        } else if let Some(bound_name) = subgraph.inputs.get(name) {
+            bound_name.clone()
+        } else {
+            name.to_string()
+        }
+    };
+
+    graph
+        .passes
+        .into_iter()
+        .map(|mut pass| {
+            pass.name = prefixed_name(&pass.name);
+            for input in &mut pass.inputs {
+                input.name = rename_resource(&input.name);
+            }
+            for output in &mut pass.outputs {
+                output.name = rename_resource(&output.name);
+            }
+            pass
+        })
+        .collect()
 }
 
+fn expand_subgraphs(mut graph: Graph, base_dir: &Path, depth: usize) -> Result<Graph> {
+    if graph.subgraphs.is_empty() {
+        return Ok(graph);
+    }
+    if depth >= MAX_SUBGRAPH_DEPTH {
+        return Err(anyhow::anyhow!(
+            "Render graph {} exceeds the maximum subgraph depth, subgraphs might include each other",
+            graph.name
+        ));
+    }
+
+    for subgraph in std::mem::take(&mut graph.subgraphs) {
+        let file_path = base_dir.join(&subgraph.file);
+        let included_graph = load_graph(&file_path, depth + 1).map_err(|err| {
+            err.context(format!(
+                "Failed to load subgraph {} from {}",
+                subgraph.name,
+                file_path.display()
+            ))
+        })?;
+
+        graph
+            .passes
+            .append(&mut prefix_subgraph_passes(&subgraph, included_graph));
+    }
+
+    Ok(graph)
+}
+
+fn load_graph(file_path: &Path, depth: usize) -> Result<Graph> {
+    let file_contents = std::fs::read_to_string(file_path)?;
+    let graph = serde_json::from_str(&file_contents)?;
+    let base_dir = file_path.parent().map_or(PathBuf::new(), Path::to_path_buf);
+
+    expand_subgraphs(graph, &base_dir, depth)
+}
+
+/// Subgraphs need to be expanded before
 pub fn parse(graph: Graph) -> Result<graph::Graph> {
+    if !graph.subgraphs.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Render graph {} has unexpanded subgraphs",
+            graph.name
+        ));
+    }
+
     let mut builder = Builder::new();
     let mut nodes = Vec::new();
 
@@ -118,11 +253,17 @@ pub fn parse(graph: Graph) -> Result<graph::Graph> {
     Ok(builder.build(nodes))
 }
 
+/// Subgraph files are relative to the current directory
 pub fn parse_from_string(string: &str) -> Result<graph::Graph> {
-    parse(serde_json::from_str(string)?)
+    parse(expand_subgraphs(
+        serde_json::from_str(string)?,
+        Path::new(""),
+        0,
+    )?)
 }
 
 pub fn parse_from_file(file_name: &str) -> Result<graph::Graph> {
-    let file_contents = std::fs::read_to_string(file_name)?;
-    parse_from_string(&file_contents)
+    let mut graph = parse(load_graph(Path::new(file_name), 0)?)?;
+    graph.set_source_file_name(file_name);
+    Ok(graph)
 }
@@ -5,7 +5,7 @@ use serde_derive::{Deserialize, Serialize};
 
 use rikka_core::vk;
 use rikka_gpu::{
-    buffer::Buffer, command_buffer::CommandBuffer, escape::Handle, image::Image, types::*,
+    buffer::Buffer, command_buffer::CommandBuffer, escape::Handle, gpu::Gpu, image::Image, types::*,
 };
 
 use crate::graph::Graph;
@@ -20,6 +20,39 @@ pub enum ResourceType {
     Reference,
 }
 
+/// Graphics nodes render to their attachments, compute nodes write their outputs as storage resources.
+/// Clear nodes clear their image outputs and fill their buffer outputs with their `ClearValues`, eg. to reset
+/// indirect count buffers or history images.
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, Default)]
+pub enum NodeType {
+    #[default]
+    Graphics,
+    Compute,
+    Clear,
+}
+
+/// Values written by clear nodes
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(default)]
+pub struct ClearValues {
+    pub color: [f32; 4],
+    pub depth: f32,
+    pub stencil: u32,
+    /// Repeated over the whole buffer
+    pub buffer: u32,
+}
+
+impl Default for ClearValues {
+    fn default() -> Self {
+        Self {
+            color: [0.0; 4],
+            depth: 1.0,
+            stencil: 0,
+            buffer: 0,
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct ResourceHandle {
     pub index: usize,
@@ -59,6 +92,43 @@ pub struct ImageInfo {
     pub format: vk::Format,
     pub usage_flags: vk::ImageUsageFlags,
     pub load_op: RenderPassOperation,
+    /// Size relative to the graph reference extent, overrides width and height when set
+    pub resolution_scale: Option<f32>,
+    /// Double buffered across frames, the previous frame contents are read with history inputs
+    pub history: bool,
+    /// Image written in the previous frame
+    pub history_image: Option<Handle<Image>>,
+    /// False until the history image has been rendered to once
+    pub history_valid: bool,
+}
+
+impl ImageInfo {
+    /// Returns true if the size changed
+    pub fn resolve_relative_resolution(&mut self, width: u32, height: u32) -> bool {
+        let scale = match self.resolution_scale {
+            Some(scale) => scale,
+            None => return false,
+        };
+
+        let scaled_width = ((width as f32 * scale) as u32).max(1);
+        let scaled_height = ((height as f32 * scale) as u32).max(1);
+        let changed = (self.width, self.height) != (scaled_width, scaled_height);
+
+        self.width = scaled_width;
+        self.height = scaled_height;
+
+        changed
+    }
+
+    /// Whether both describe the same Gpu image, ignoring the image itself
+    pub fn matches(&self, other: &ImageInfo) -> bool {
+        self.width == other.width
+            && self.height == other.height
+            && self.depth == other.depth
+            && self.format == other.format
+            && self.usage_flags == other.usage_flags
+            && self.history == other.history
+    }
 }
 
 #[derive(Clone)]
@@ -86,6 +156,8 @@ pub struct Resource {
     pub output: ResourceHandle,
     pub ref_count: i32,
     pub name: String,
+    /// Input reads the previous frame contents of a history resource
+    pub history: bool,
 }
 
 impl Resource {
@@ -159,14 +231,27 @@ impl Default for Resource {
             output: ResourceHandle::invalid(),
             name: String::new(),
             ref_count: 0,
+            history: false,
         }
     }
 }
 
+/// CPU copy of a graph image resource
+pub struct ImageCapture {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    pub format: vk::Format,
+    /// Tightly packed texels
+    pub data: Vec<u8>,
+}
+
 pub struct InputDesc {
     pub resource_type: ResourceType,
     /// Name of the output resource this input originates from
     pub name: String,
+    /// Read the previous frame contents of a history resource, does not create a dependency on the producer
+    pub history: bool,
 }
 
 pub struct OutputDesc {
@@ -180,20 +265,49 @@ pub struct NodeDesc {
     pub outputs: Vec<OutputDesc>,
     pub enabled: bool,
     pub name: String,
+    pub node_type: NodeType,
+    /// Only used by clear nodes
+    pub clear_values: ClearValues,
+}
+
+/// Frame information passed to `RenderPass::prepare`
+#[derive(Clone, Copy, Debug)]
+pub struct FrameContext {
+    /// Index of the frame in flight
+    pub frame_index: u64,
+    pub absolute_frame_index: u64,
+    pub extent: vk::Extent2D,
 }
 
 pub trait RenderPass {
+    /// Called every frame for enabled passes before any commands are recorded
+    fn prepare(&mut self, _frame_context: &FrameContext) -> Result<()> {
+        Ok(())
+    }
+
     // fn pre_render(&self, command_buffer: &CommandBuffer) -> Result<()>;
     fn render(&self, command_buffer: &CommandBuffer) -> Result<()>;
     fn post_render(&self, command_buffer: &CommandBuffer, graph: &Graph) -> Result<()>;
 
-    // fn resize(&self, width: u32, height: u32) -> Result<()>;
+    /// Called after the graph was resized and its attachments recreated, the Gpu is not using any graph resources
+    fn on_resize(&mut self, _gpu: &mut Gpu, _width: u32, _height: u32) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called after the render technique loaded from `file_name` was rebuilt
+    fn on_technique_reloaded(&mut self, _file_name: &str) -> Result<()> {
+        Ok(())
+    }
 
     fn name(&self) -> &str;
 }
 
 pub struct Node {
+    pub node_type: NodeType,
+    /// Only used by graphics nodes
     pub rendering_state: Option<RenderingState>,
+    /// Only used by clear nodes
+    pub clear_values: ClearValues,
     pub inputs: Vec<ResourceHandle>,
     pub outputs: Vec<ResourceHandle>,
     pub edges: Vec<NodeHandle>,
@@ -236,12 +350,36 @@ impl Node {
         self.name = name;
         self
     }
+
+    pub fn set_type(&mut self, node_type: NodeType) -> &mut Self {
+        self.node_type = node_type;
+        self
+    }
+
+    pub fn set_clear_values(&mut self, clear_values: ClearValues) -> &mut Self {
+        self.clear_values = clear_values;
+        self
+    }
+
+    pub fn is_graphics(&self) -> bool {
+        self.node_type == NodeType::Graphics
+    }
+
+    pub fn is_compute(&self) -> bool {
+        self.node_type == NodeType::Compute
+    }
+
+    pub fn is_clear(&self) -> bool {
+        self.node_type == NodeType::Clear
+    }
 }
 
 impl Default for Node {
     fn default() -> Self {
         Self {
+            node_type: NodeType::Graphics,
             rendering_state: None,
+            clear_values: ClearValues::default(),
             inputs: Vec::new(),
             outputs: Vec::new(),
             edges: Vec::new(),
@@ -0,0 +1,662 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+
+use rikka_core::nalgebra::{
+    Matrix3, Matrix4, Quaternion, SVector, UnitQuaternion, Vector3, Vector4,
+};
+
+use crate::scene;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelProperty {
+    Translation,
+    Rotation,
+    Scale,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    Step,
+    Linear,
+    CubicSpline,
+}
+
+/// Keyframes of one transform property of a scene graph node
+#[derive(Clone, Debug)]
+pub struct AnimationChannel {
+    pub node: usize,
+    pub property: ChannelProperty,
+    pub interpolation: Interpolation,
+    /// Seconds in ascending order, never empty
+    pub times: Vec<f32>,
+    /// Rotations are xyzw quaternions, translations and scales leave w unused. Cubic spline keyframes are stored as
+    /// (in tangent, value, out tangent) triplets.
+    pub values: Vec<Vector4<f32>>,
+}
+
+impl AnimationChannel {
+    fn keyframe_value(&self, index: usize) -> Vector4<f32> {
+        match self.interpolation {
+            Interpolation::CubicSpline => self.values[index * 3 + 1],
+            _ => self.values[index],
+        }
+    }
+
+    /// Value at `time`, clamped to the first and last keyframes
+    pub fn sample(&self, time: f32) -> Vector4<f32> {
+        let last = self.times.len() - 1;
+        if time <= self.times[0] {
+            return self.keyframe_value(0);
+        }
+        if time >= self.times[last] {
+            return self.keyframe_value(last);
+        }
+
+        let next = self
+            .times
+            .partition_point(|&keyframe_time| keyframe_time <= time);
+        let previous = next - 1;
+        let delta = self.times[next] - self.times[previous];
+        let t = (time - self.times[previous]) / delta;
+
+        match self.interpolation {
+            Interpolation::Step => self.keyframe_value(previous),
+            Interpolation::Linear if self.property == ChannelProperty::Rotation => {
+                let from = UnitQuaternion::new_normalize(Quaternion::from(self.values[previous]));
+                let to = UnitQuaternion::new_normalize(Quaternion::from(self.values[next]));
+                from.slerp(&to, t).into_inner().coords
+            }
+            Interpolation::Linear => self.values[previous].lerp(&self.values[next], t),
+            Interpolation::CubicSpline => {
+                let t2 = t * t;
+                let t3 = t2 * t;
+
+                let value_0 = self.values[previous * 3 + 1];
+                let out_tangent_0 = self.values[previous * 3 + 2] * delta;
+                let value_1 = self.values[next * 3 + 1];
+                let in_tangent_1 = self.values[next * 3] * delta;
+
+                value_0 * (2.0 * t3 - 3.0 * t2 + 1.0)
+                    + out_tangent_0 * (t3 - 2.0 * t2 + t)
+                    + value_1 * (-2.0 * t3 + 3.0 * t2)
+                    + in_tangent_1 * (t3 - t2)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AnimationClip {
+    pub name: Option<String>,
+    pub channels: Vec<AnimationChannel>,
+    /// Time of the last keyframe in seconds
+    pub duration: f32,
+}
+
+impl AnimationClip {
+    pub fn new(name: Option<String>, channels: Vec<AnimationChannel>) -> Self {
+        let duration = channels
+            .iter()
+            .filter_map(|channel| channel.times.last().copied())
+            .fold(0.0, f32::max);
+
+        Self {
+            name,
+            channels,
+            duration,
+        }
+    }
+}
+
+/// Joints deforming the vertices of skinned meshes
+#[derive(Clone, Debug)]
+pub struct Skin {
+    pub name: Option<String>,
+    /// Scene graph nodes of the joints, indexed by the joint indices of the vertices
+    pub joints: Vec<usize>,
+    /// Transform from mesh space to the bind pose space of each joint
+    pub inverse_bind_matrices: Vec<Matrix4<f32>>,
+}
+
+impl Skin {
+    /// Joint matrices of a mesh attached to `mesh_node`, relative to the node so the mesh world matrix still applies.
+    /// Requires global matrices computed by `scene::Graph::calculate_transforms`.
+    pub fn joint_matrices(
+        &self,
+        scene_graph: &scene::Graph,
+        mesh_node: usize,
+    ) -> Vec<Matrix4<f32>> {
+        let inverse_mesh_matrix = scene_graph.global_matrices[mesh_node]
+            .try_inverse()
+            .unwrap_or_else(Matrix4::identity);
+
+        self.joints
+            .iter()
+            .zip(&self.inverse_bind_matrices)
+            .map(|(joint, inverse_bind_matrix)| {
+                inverse_mesh_matrix * scene_graph.global_matrices[*joint] * inverse_bind_matrix
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoopMode {
+    /// Stops at the end of the clip, or at the start when played backwards
+    Once,
+    Repeat,
+    /// Alternates between playing forwards and backwards
+    PingPong,
+}
+
+/// Playback state of a clip
+#[derive(Clone, Copy, Debug)]
+pub struct ClipPlayback {
+    pub playing: bool,
+    /// Seconds in [0, duration]
+    pub time: f32,
+    /// Negative speeds play backwards
+    pub speed: f32,
+    pub loop_mode: LoopMode,
+    /// Blend weight of the clip, clips with a weight of 0 do not affect the scene
+    pub weight: f32,
+    /// Ping pong playback is on its backward half
+    reversed: bool,
+}
+
+impl Default for ClipPlayback {
+    fn default() -> Self {
+        Self {
+            playing: false,
+            time: 0.0,
+            speed: 1.0,
+            loop_mode: LoopMode::Repeat,
+            weight: 0.0,
+            reversed: false,
+        }
+    }
+}
+
+impl ClipPlayback {
+    fn advance(&mut self, delta_time: f32, duration: f32) {
+        if !self.playing {
+            return;
+        }
+        if duration <= 0.0 {
+            self.time = 0.0;
+            return;
+        }
+
+        let delta = delta_time * self.speed;
+        match self.loop_mode {
+            LoopMode::Once => {
+                self.time = (self.time + delta).clamp(0.0, duration);
+                if (delta > 0.0 && self.time >= duration) || (delta < 0.0 && self.time <= 0.0) {
+                    self.playing = false;
+                }
+            }
+            LoopMode::Repeat => {
+                self.time = (self.time + delta).rem_euclid(duration);
+            }
+            LoopMode::PingPong => {
+                // Position along a forward and backward cycle of twice the duration
+                let cycle_time = if self.reversed {
+                    2.0 * duration - self.time
+                } else {
+                    self.time
+                };
+                let cycle_time = (cycle_time + delta).rem_euclid(2.0 * duration);
+
+                self.reversed = cycle_time > duration;
+                self.time = if self.reversed {
+                    2.0 * duration - cycle_time
+                } else {
+                    cycle_time
+                };
+            }
+        }
+    }
+}
+
+/// Transform of an animated node in its rest pose
+#[derive(Clone, Copy)]
+struct NodePose {
+    node: usize,
+    /// Restored unchanged once no clip animates the node anymore
+    matrix: Matrix4<f32>,
+    translation: Vector3<f32>,
+    rotation: UnitQuaternion<f32>,
+    scale: Vector3<f32>,
+}
+
+impl NodePose {
+    fn from_matrix(node: usize, matrix: &Matrix4<f32>) -> Self {
+        let scale = Vector3::from_fn(|column, _| matrix.fixed_view::<3, 1>(0, column).norm());
+        let rotation_matrix =
+            Matrix3::from_fn(|row, column| matrix[(row, column)] / scale[column].max(f32::EPSILON));
+
+        Self {
+            node,
+            matrix: *matrix,
+            translation: matrix.fixed_view::<3, 1>(0, 3).into_owned(),
+            rotation: UnitQuaternion::from_matrix(&rotation_matrix),
+            scale,
+        }
+    }
+}
+
+/// Weighted sum of the sampled channels of a node
+#[derive(Clone, Copy, Default)]
+struct BlendedPose {
+    translation: Vector3<f32>,
+    translation_weight: f32,
+    rotation: Vector4<f32>,
+    rotation_weight: f32,
+    scale: Vector3<f32>,
+    scale_weight: f32,
+}
+
+/// Plays the animation clips of a scene and writes the blended node transforms to its scene graph. Nodes take the
+/// weighted average of all clips animating them, the rest pose fills up total weights below 1.
+#[derive(Default)]
+pub struct Animator {
+    clips: Vec<AnimationClip>,
+    playbacks: Vec<ClipPlayback>,
+
+    rest_poses: Vec<NodePose>,
+    /// Scene graph node to its index in `rest_poses`
+    node_pose_indices: HashMap<usize, usize>,
+    /// Rest poses the last `apply` replaced with an animated pose
+    animated_poses: Vec<bool>,
+    /// Playback changed since the last `apply`
+    dirty: bool,
+}
+
+impl Animator {
+    /// The rest pose of animated nodes is taken from the current local matrices of `scene_graph`
+    pub fn new(clips: Vec<AnimationClip>, scene_graph: &scene::Graph) -> Self {
+        let mut rest_poses = Vec::new();
+        let mut node_pose_indices = HashMap::new();
+        for channel in clips.iter().flat_map(|clip| &clip.channels) {
+            node_pose_indices.entry(channel.node).or_insert_with(|| {
+                rest_poses.push(NodePose::from_matrix(
+                    channel.node,
+                    &scene_graph.local_matrices[channel.node],
+                ));
+                rest_poses.len() - 1
+            });
+        }
+
+        Self {
+            playbacks: vec![ClipPlayback::default(); clips.len()],
+            clips,
+            animated_poses: vec![false; rest_poses.len()],
+            rest_poses,
+            node_pose_indices,
+            dirty: false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clips.is_empty()
+    }
+
+    pub fn clips(&self) -> &[AnimationClip] {
+        &self.clips
+    }
+
+    /// First clip named `name`
+    pub fn find_clip(&self, name: &str) -> Option<usize> {
+        self.clips
+            .iter()
+            .position(|clip| clip.name.as_deref() == Some(name))
+    }
+
+    pub fn playback(&self, clip: usize) -> Option<&ClipPlayback> {
+        self.playbacks.get(clip)
+    }
+
+    /// Resumes playback from the current time, a clip without weight is given full weight
+    pub fn play(&mut self, clip: usize) -> Result<()> {
+        let playback = self.playback_mut(clip)?;
+        playback.playing = true;
+        if playback.weight <= 0.0 {
+            playback.weight = 1.0;
+        }
+
+        Ok(())
+    }
+
+    /// Keeps the clip at its current time, paused clips still affect the scene
+    pub fn pause(&mut self, clip: usize) -> Result<()> {
+        self.playback_mut(clip)?.playing = false;
+        Ok(())
+    }
+
+    /// Pauses and rewinds the clip and removes its weight
+    pub fn stop(&mut self, clip: usize) -> Result<()> {
+        let playback = self.playback_mut(clip)?;
+        *playback = ClipPlayback {
+            loop_mode: playback.loop_mode,
+            speed: playback.speed,
+            ..Default::default()
+        };
+        Ok(())
+    }
+
+    /// Clamped to the clip duration, ping pong playback continues forwards
+    pub fn seek(&mut self, clip: usize, time: f32) -> Result<()> {
+        let duration = self.duration(clip)?;
+        let playback = self.playback_mut(clip)?;
+        playback.time = time.clamp(0.0, duration);
+        playback.reversed = false;
+        Ok(())
+    }
+
+    pub fn set_speed(&mut self, clip: usize, speed: f32) -> Result<()> {
+        self.playback_mut(clip)?.speed = speed;
+        Ok(())
+    }
+
+    pub fn set_loop_mode(&mut self, clip: usize, loop_mode: LoopMode) -> Result<()> {
+        self.playback_mut(clip)?.loop_mode = loop_mode;
+        Ok(())
+    }
+
+    pub fn set_weight(&mut self, clip: usize, weight: f32) -> Result<()> {
+        self.playback_mut(clip)?.weight = weight.max(0.0);
+        Ok(())
+    }
+
+    pub fn duration(&self, clip: usize) -> Result<f32> {
+        self.clips
+            .get(clip)
+            .map(|clip| clip.duration)
+            .ok_or_else(|| anyhow!("Animation clip {} does not exist", clip))
+    }
+
+    /// Advances the playing clips by `delta_time` seconds scaled by their speed
+    pub fn update(&mut self, delta_time: f32) {
+        for (clip, playback) in self.clips.iter().zip(&mut self.playbacks) {
+            if playback.playing {
+                playback.advance(delta_time, clip.duration);
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// Writes the blended local matrices of the animated nodes if the playback changed since the last call, returns
+    /// whether any were written. Nodes without weight in any clip are left alone, except for being reset to their rest
+    /// pose once after their last clip stopped.
+    pub fn apply(&mut self, scene_graph: &mut scene::Graph) -> bool {
+        if !self.dirty {
+            return false;
+        }
+        self.dirty = false;
+
+        let mut blended_poses = vec![BlendedPose::default(); self.rest_poses.len()];
+
+        for (clip, playback) in self.clips.iter().zip(&self.playbacks) {
+            let weight = playback.weight;
+            if weight <= 0.0 {
+                continue;
+            }
+
+            for channel in &clip.channels {
+                let pose_index = self.node_pose_indices[&channel.node];
+                let blended_pose = &mut blended_poses[pose_index];
+                let value = channel.sample(playback.time);
+
+                match channel.property {
+                    ChannelProperty::Translation => {
+                        blended_pose.translation += value.xyz() * weight;
+                        blended_pose.translation_weight += weight;
+                    }
+                    ChannelProperty::Rotation => {
+                        // q and -q are the same rotation, keep all summed quaternions in one hemisphere
+                        let rest_rotation = self.rest_poses[pose_index].rotation.coords;
+                        let value = if value.dot(&rest_rotation) < 0.0 {
+                            -value
+                        } else {
+                            value
+                        };
+                        blended_pose.rotation += value * weight;
+                        blended_pose.rotation_weight += weight;
+                    }
+                    ChannelProperty::Scale => {
+                        blended_pose.scale += value.xyz() * weight;
+                        blended_pose.scale_weight += weight;
+                    }
+                }
+            }
+        }
+
+        for ((rest_pose, blended_pose), animated) in self
+            .rest_poses
+            .iter()
+            .zip(&blended_poses)
+            .zip(&mut self.animated_poses)
+        {
+            if blended_pose.translation_weight
+                + blended_pose.rotation_weight
+                + blended_pose.scale_weight
+                <= 0.0
+            {
+                if *animated {
+                    scene_graph.set_local_matrix(rest_pose.node, rest_pose.matrix);
+                    *animated = false;
+                }
+                continue;
+            }
+            *animated = true;
+
+            let translation = blend_with_rest(
+                blended_pose.translation,
+                blended_pose.translation_weight,
+                rest_pose.translation,
+            );
+            let rotation = UnitQuaternion::new_normalize(Quaternion::from(blend_with_rest(
+                blended_pose.rotation,
+                blended_pose.rotation_weight,
+                rest_pose.rotation.coords,
+            )));
+            let scale = blend_with_rest(
+                blended_pose.scale,
+                blended_pose.scale_weight,
+                rest_pose.scale,
+            );
+
+            scene_graph.set_local_matrix(
+                rest_pose.node,
+                Matrix4::new_translation(&translation)
+                    * rotation.to_homogeneous()
+                    * Matrix4::new_nonuniform_scaling(&scale),
+            );
+        }
+
+        true
+    }
+
+    fn playback_mut(&mut self, clip: usize) -> Result<&mut ClipPlayback> {
+        self.dirty = true;
+        self.playbacks
+            .get_mut(clip)
+            .ok_or_else(|| anyhow!("Animation clip {} does not exist", clip))
+    }
+}
+
+/// Normalizes total weights above 1, lower weights are filled up with the rest pose
+fn blend_with_rest<const D: usize>(
+    value: SVector<f32, D>,
+    weight: f32,
+    rest_value: SVector<f32, D>,
+) -> SVector<f32, D> {
+    if weight >= 1.0 {
+        value / weight
+    } else {
+        value + rest_value * (1.0 - weight)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(
+        property: ChannelProperty,
+        interpolation: Interpolation,
+        times: Vec<f32>,
+        values: Vec<Vector4<f32>>,
+    ) -> AnimationChannel {
+        AnimationChannel {
+            node: 0,
+            property,
+            interpolation,
+            times,
+            values,
+        }
+    }
+
+    fn playing(loop_mode: LoopMode, speed: f32) -> ClipPlayback {
+        ClipPlayback {
+            playing: true,
+            speed,
+            loop_mode,
+            weight: 1.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_sample_clamps_to_keyframes() {
+        let channel = channel(
+            ChannelProperty::Translation,
+            Interpolation::Linear,
+            vec![1.0, 2.0],
+            vec![
+                Vector4::new(1.0, 0.0, 0.0, 0.0),
+                Vector4::new(3.0, 0.0, 0.0, 0.0),
+            ],
+        );
+
+        assert_eq!(channel.sample(0.0).x, 1.0);
+        assert_eq!(channel.sample(5.0).x, 3.0);
+    }
+
+    #[test]
+    fn test_sample_step_and_linear() {
+        let values = vec![
+            Vector4::new(0.0, 0.0, 0.0, 0.0),
+            Vector4::new(4.0, 2.0, 0.0, 0.0),
+        ];
+        let step = channel(
+            ChannelProperty::Translation,
+            Interpolation::Step,
+            vec![0.0, 1.0],
+            values.clone(),
+        );
+        let linear = channel(
+            ChannelProperty::Translation,
+            Interpolation::Linear,
+            vec![0.0, 1.0],
+            values,
+        );
+
+        assert_eq!(step.sample(0.75).x, 0.0);
+        let value = linear.sample(0.25);
+        assert!((value.x - 1.0).abs() < 1e-6);
+        assert!((value.y - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_sample_linear_rotation_slerps() {
+        let to = UnitQuaternion::from_axis_angle(&Vector3::y_axis(), std::f32::consts::FRAC_PI_2);
+        let rotation = channel(
+            ChannelProperty::Rotation,
+            Interpolation::Linear,
+            vec![0.0, 1.0],
+            vec![UnitQuaternion::identity().coords, to.coords],
+        );
+
+        let value = UnitQuaternion::new_normalize(Quaternion::from(rotation.sample(0.5)));
+        assert!((value.angle() - std::f32::consts::FRAC_PI_4).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_sample_cubic_spline_hits_keyframes() {
+        let tangent = Vector4::new(1.0, 0.0, 0.0, 0.0);
+        let spline = channel(
+            ChannelProperty::Translation,
+            Interpolation::CubicSpline,
+            vec![0.0, 1.0],
+            vec![
+                tangent,
+                Vector4::new(0.0, 0.0, 0.0, 0.0),
+                tangent,
+                tangent,
+                Vector4::new(1.0, 0.0, 0.0, 0.0),
+                tangent,
+            ],
+        );
+
+        assert_eq!(spline.sample(0.0).x, 0.0);
+        assert_eq!(spline.sample(1.0).x, 1.0);
+        // Tangents matching the slope between the keyframes interpolate linearly
+        assert!((spline.sample(0.5).x - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_advance_once_stops_at_end() {
+        let mut playback = playing(LoopMode::Once, 1.0);
+        playback.advance(1.5, 2.0);
+        assert_eq!(playback.time, 1.5);
+        assert!(playback.playing);
+
+        playback.advance(1.0, 2.0);
+        assert_eq!(playback.time, 2.0);
+        assert!(!playback.playing);
+    }
+
+    #[test]
+    fn test_advance_once_backwards_stops_at_start() {
+        let mut playback = playing(LoopMode::Once, -2.0);
+        playback.time = 1.0;
+        playback.advance(1.0, 2.0);
+        assert_eq!(playback.time, 0.0);
+        assert!(!playback.playing);
+    }
+
+    #[test]
+    fn test_advance_repeat_wraps() {
+        let mut playback = playing(LoopMode::Repeat, 1.0);
+        playback.advance(2.5, 2.0);
+        assert!((playback.time - 0.5).abs() < 1e-6);
+
+        let mut playback = playing(LoopMode::Repeat, -1.0);
+        playback.advance(0.5, 2.0);
+        assert!((playback.time - 1.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_advance_ping_pong_reverses() {
+        let mut playback = playing(LoopMode::PingPong, 1.0);
+        playback.advance(2.5, 2.0);
+        assert!((playback.time - 1.5).abs() < 1e-6);
+        assert!(playback.reversed);
+
+        playback.advance(2.0, 2.0);
+        assert!((playback.time - 0.5).abs() < 1e-6);
+        assert!(!playback.reversed);
+    }
+
+    #[test]
+    fn test_advance_paused_keeps_time() {
+        let mut playback = ClipPlayback {
+            time: 1.0,
+            ..Default::default()
+        };
+        playback.advance(1.0, 2.0);
+        assert_eq!(playback.time, 1.0);
+    }
+}
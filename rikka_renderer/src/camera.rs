@@ -0,0 +1,189 @@
+use rikka_core::{
+    nalgebra::{Matrix4, Vector2, Vector3, Vector4},
+    projection::Projection,
+};
+
+const UP_VECTOR: Vector3<f32> = Vector3::new(0.0, 1.0, 0.0);
+
+pub const DEFAULT_FOVY: f32 = std::f32::consts::FRAC_PI_4;
+
+/// World space frustum planes of a Vulkan `view_projection` with a [0, 1] depth range, normals point inwards.
+/// Order is left, right, bottom, top, near, far.
+pub fn frustum_planes(view_projection: &Matrix4<f32>) -> [Vector4<f32>; 6] {
+    let row = |index: usize| view_projection.row(index).transpose();
+
+    [
+        row(3) + row(0),
+        row(3) - row(0),
+        row(3) + row(1),
+        row(3) - row(1),
+        row(2),
+        row(3) - row(2),
+    ]
+    .map(|plane| plane / plane.xyz().norm().max(f32::EPSILON))
+}
+
+/// Camera of the scene. The app drives its transform, the scene renderer keeps the aspect ratio in sync
+/// with the extent of the main view.
+#[derive(Clone, Debug)]
+pub struct Camera {
+    position: Vector3<f32>,
+    forward: Vector3<f32>,
+
+    projection: Projection,
+    width: u32,
+    height: u32,
+    /// Subpixel offset of the projection in pixels
+    jitter: Vector2<f32>,
+
+    /// Linear scale of the final image before tonemapping, overridden while auto exposure is enabled
+    exposure: f32,
+
+    view: Matrix4<f32>,
+    projection_matrix: Matrix4<f32>,
+    jittered_projection_matrix: Matrix4<f32>,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self::new(Projection::perspective(DEFAULT_FOVY, 0.1, 100.0))
+    }
+}
+
+impl Camera {
+    pub fn new(projection: Projection) -> Self {
+        let mut camera = Self {
+            position: Vector3::zeros(),
+            forward: Vector3::new(0.0, 0.0, -1.0),
+            projection,
+            width: 1,
+            height: 1,
+            jitter: Vector2::zeros(),
+            exposure: 1.0,
+            view: Matrix4::identity(),
+            projection_matrix: Matrix4::identity(),
+            jittered_projection_matrix: Matrix4::identity(),
+        };
+        camera.calculate_view();
+        camera.calculate_projection();
+        camera
+    }
+
+    /// `forward` does not need to be normalized
+    pub fn set_transform(&mut self, position: Vector3<f32>, forward: Vector3<f32>) {
+        self.position = position;
+        self.forward = forward.normalize();
+        self.calculate_view();
+    }
+
+    pub fn position(&self) -> &Vector3<f32> {
+        &self.position
+    }
+
+    pub fn forward(&self) -> &Vector3<f32> {
+        &self.forward
+    }
+
+    pub fn set_projection(&mut self, projection: Projection) {
+        self.projection = projection;
+        self.calculate_projection();
+    }
+
+    pub fn projection(&self) -> &Projection {
+        &self.projection
+    }
+
+    pub fn set_depth_range(&mut self, znear: f32, zfar: f32) {
+        self.projection.set_depth_range(znear, zfar);
+        self.calculate_projection();
+    }
+
+    /// Extent of the image rendered with the camera, empty extents are ignored
+    pub fn set_extent(&mut self, width: u32, height: u32) {
+        if width == 0 || height == 0 || (width == self.width && height == self.height) {
+            return;
+        }
+
+        self.width = width;
+        self.height = height;
+        self.calculate_projection();
+    }
+
+    pub fn aspect_ratio(&self) -> f32 {
+        self.width as f32 / self.height as f32
+    }
+
+    /// Offsets the projection by a fraction of a pixel, eg. for temporal antialiasing. Zero disables jittering.
+    pub fn set_jitter(&mut self, jitter: Vector2<f32>) {
+        self.jitter = jitter;
+        self.calculate_projection();
+    }
+
+    pub fn jitter(&self) -> &Vector2<f32> {
+        &self.jitter
+    }
+
+    pub fn set_exposure(&mut self, exposure: f32) {
+        self.exposure = exposure;
+    }
+
+    pub fn exposure(&self) -> f32 {
+        self.exposure
+    }
+
+    pub fn view(&self) -> &Matrix4<f32> {
+        &self.view
+    }
+
+    /// Jittered projection used for rendering
+    pub fn projection_matrix(&self) -> &Matrix4<f32> {
+        &self.jittered_projection_matrix
+    }
+
+    /// Stable projection for culling and LOD selection
+    pub fn unjittered_projection_matrix(&self) -> &Matrix4<f32> {
+        &self.projection_matrix
+    }
+
+    pub fn view_projection(&self) -> Matrix4<f32> {
+        self.projection_matrix * self.view
+    }
+
+    /// World space frustum planes of the unjittered projection, see `frustum_planes`
+    pub fn frustum_planes(&self) -> [Vector4<f32>; 6] {
+        frustum_planes(&self.view_projection())
+    }
+
+    /// Screen space pixels covered by one world unit, see `Projection::pixels_per_unit`
+    pub fn pixels_per_unit(&self) -> f32 {
+        self.projection.pixels_per_unit(self.height)
+    }
+
+    /// Distance at which a sphere of `radius` fits entirely inside the view volume
+    pub fn focus_distance(&self, radius: f32) -> f32 {
+        self.projection.focus_distance(radius, self.aspect_ratio())
+    }
+
+    fn calculate_view(&mut self) {
+        self.view = Matrix4::look_at_rh(
+            &self.position.into(),
+            &(self.position + self.forward).into(),
+            &UP_VECTOR,
+        );
+    }
+
+    fn calculate_projection(&mut self) {
+        self.projection_matrix = self.projection.matrix(self.aspect_ratio());
+
+        // Offsets clip space x/y by `jitter` pixels in NDC for both perspective (w = -z) and orthographic (w = 1)
+        // projections
+        let offset_x = self.jitter.x * 2.0 / self.width as f32;
+        let offset_y = self.jitter.y * 2.0 / self.height as f32;
+        let w_row = self.projection_matrix.row(3).clone_owned();
+        self.jittered_projection_matrix = self.projection_matrix;
+        self.jittered_projection_matrix
+            .set_row(0, &(self.projection_matrix.row(0) + w_row * offset_x));
+        self.jittered_projection_matrix
+            .set_row(1, &(self.projection_matrix.row(1) + w_row * offset_y));
+    }
+}
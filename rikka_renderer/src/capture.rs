@@ -0,0 +1,98 @@
+use anyhow::{Context, Result};
+
+use rikka_core::vk;
+use rikka_graph::types::ImageCapture;
+
+/// Converts a captured graph image to RGBA8 and saves it to disk, the file format is deduced from the file extension.
+/// Single channel float/depth formats are normalized to grayscale.
+pub fn save_image_capture(capture: &ImageCapture, file_name: &str) -> Result<()> {
+    let rgba = capture_to_rgba8(capture)?;
+
+    image::save_buffer(
+        file_name,
+        &rgba,
+        capture.width,
+        capture.height,
+        image::ColorType::Rgba8,
+    )
+    .with_context(|| format!("Failed to save capture of {}", capture.name))?;
+
+    log::info!("Saved capture of {} to {}", capture.name, file_name);
+
+    Ok(())
+}
+
+fn capture_to_rgba8(capture: &ImageCapture) -> Result<Vec<u8>> {
+    let data = &capture.data;
+
+    let rgba = match capture.format {
+        vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => data.clone(),
+        vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => data
+            .chunks_exact(4)
+            .flat_map(|texel| [texel[2], texel[1], texel[0], texel[3]])
+            .collect(),
+        vk::Format::R16G16B16A16_SFLOAT => data
+            .chunks_exact(2)
+            .map(|half| unorm_to_u8(f16_to_f32(u16::from_le_bytes([half[0], half[1]]))))
+            .collect(),
+        vk::Format::R32G32B32A32_SFLOAT => data
+            .chunks_exact(4)
+            .map(|float| unorm_to_u8(f32::from_le_bytes(float.try_into().unwrap())))
+            .collect(),
+        vk::Format::R32_SFLOAT | vk::Format::D32_SFLOAT => {
+            let values = data
+                .chunks_exact(4)
+                .map(|float| f32::from_le_bytes(float.try_into().unwrap()))
+                .collect::<Vec<_>>();
+            grayscale_to_rgba8(&values)
+        }
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Capture of format {:?} is not supported",
+                capture.format
+            ))
+        }
+    };
+
+    Ok(rgba)
+}
+
+/// Normalizes values to the [min, max] range so depth buffers are visible
+fn grayscale_to_rgba8(values: &[f32]) -> Vec<u8> {
+    let (min, max) = values
+        .iter()
+        .fold((f32::MAX, f32::MIN), |(min, max), value| {
+            (min.min(*value), max.max(*value))
+        });
+    let range = (max - min).max(f32::EPSILON);
+
+    values
+        .iter()
+        .flat_map(|value| {
+            let gray = unorm_to_u8((value - min) / range);
+            [gray, gray, gray, u8::MAX]
+        })
+        .collect()
+}
+
+fn unorm_to_u8(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0 + 0.5) as u8
+}
+
+fn f16_to_f32(half: u16) -> f32 {
+    let sign = if half & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((half >> 10) & 0x1f) as i32;
+    let mantissa = (half & 0x3ff) as f32;
+
+    match exponent {
+        0 => sign * mantissa * 2.0_f32.powi(-24),
+        0x1f => {
+            if mantissa == 0.0 {
+                sign * f32::INFINITY
+            } else {
+                f32::NAN
+            }
+        }
+        _ => sign * (1.0 + mantissa / 1024.0) * 2.0_f32.powi(exponent - 15),
+    }
+}
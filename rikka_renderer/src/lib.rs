@@ -1,8 +1,15 @@
+pub mod animation;
+pub mod camera;
+pub mod capture;
 pub mod loader;
 pub mod pass;
+pub mod procedural;
 pub mod renderer;
 pub mod scene;
 pub mod scene_renderer;
+pub mod schedule;
+pub mod sky;
+pub mod stats;
 
 #[cfg(test)]
 mod tests {
@@ -1,69 +1,228 @@
-use anyhow::Result;
-use crossbeam_channel::Sender;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
 
-use rikka_gpu::{escape::Handle, image::Image, transfer::ImageUploadRequest};
+use anyhow::{Context, Result};
+use crossbeam_channel::{Receiver, Sender};
+use image::{imageops::FilterType, RgbaImage};
+
+use rikka_gpu::{escape::Handle, image::*, transfer::ImageUploadRequest};
+
+use crate::loader::{basis, dds, hdr};
 
 struct ImageFileLoadRequest {
     file_name: String,
     image: Handle<Image>,
+    /// Mip level of the file that becomes the image's first mip level
+    first_mip_level: u32,
+    /// Streaming requests are not counted as pending loads
+    streamed: bool,
 }
 
+/// Clones share the same request queue and decode threads, requests can be made from one thread while another
+/// one calls `update`
+#[derive(Clone)]
 pub struct AsynchronousLoader {
-    image_file_load_requests: Vec<ImageFileLoadRequest>,
+    image_file_load_request_sender: Sender<ImageFileLoadRequest>,
+    image_file_load_request_receiver: Receiver<ImageFileLoadRequest>,
+    /// Requests that have not been loaded yet
+    pending_image_file_loads: Arc<AtomicUsize>,
     /// Sender to send loaded images
     image_file_load_complete_sender: Sender<ImageUploadRequest>,
+
+    decode_thread_pool: Arc<rayon::ThreadPool>,
+    /// Requests taken from the queue that are still being decoded
+    in_flight_decodes: Arc<AtomicUsize>,
 }
 
-fn load_image_data(file_name: &str) -> Result<Vec<u8>> {
+/// Limits the decoded image data waiting for the transfer manager
+const MAX_IN_FLIGHT_DECODES_PER_THREAD: usize = 2;
+
+/// Number of mip levels of a full mip chain
+pub fn mip_chain_length(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
+/// Loads the mip levels of `gpu_image` from the file, starting at the file's `first_mip_level`
+fn load_image_data(file_name: &str, gpu_image: &Image, first_mip_level: u32) -> Result<Vec<u8>> {
     let data = std::fs::read(file_name)?;
 
-    if let Ok(dds) = ddsfile::Dds::read(&mut std::io::Cursor::new(&data)) {
-        Ok(dds.get_data(0)?.to_vec())
+    if basis::is_basis_file(file_name) {
+        basis::transcode_mips(
+            &data,
+            gpu_image.format(),
+            first_mip_level,
+            gpu_image.mip_levels(),
+        )
+    } else if hdr::is_hdr_file(file_name) {
+        hdr::load_mips(
+            &data,
+            gpu_image.format(),
+            first_mip_level,
+            gpu_image.mip_levels(),
+        )
+    } else if let Ok(dds) = ddsfile::Dds::read(&mut std::io::Cursor::new(&data)) {
+        dds::load_mips(
+            &dds,
+            gpu_image.format(),
+            first_mip_level,
+            gpu_image.mip_levels(),
+        )
     } else {
         let dynamic_image = image::load_from_memory(&data)?;
         // XXX: How expensive/slow is this? Maybe this conversion should be preemptively done elsewhere
-        let texture_rgba8 = dynamic_image.clone().into_rgba8();
+        let mut mip = dynamic_image.into_rgba8();
 
-        // log::info!(
-        //     "Loaded image {} with size {}",
-        //     file_name,
-        //     texture_rgba8.as_raw().len()
-        // );
+        // XXX: Generate mips offline, every streaming request downsamples from the full image again
+        let mut image_data = Vec::new();
+        for mip_level in 0..first_mip_level + gpu_image.mip_levels() {
+            if mip_level > 0 {
+                mip = downsample(&mip);
+            }
+            if mip_level >= first_mip_level {
+                image_data.extend_from_slice(mip.as_raw());
+            }
+        }
 
-        Ok(texture_rgba8.as_raw().clone())
+        Ok(image_data)
     }
 }
 
+pub(crate) fn downsample(mip: &RgbaImage) -> RgbaImage {
+    image::imageops::resize(
+        mip,
+        (mip.width() / 2).max(1),
+        (mip.height() / 2).max(1),
+        FilterType::Triangle,
+    )
+}
+
 impl AsynchronousLoader {
-    pub fn new(image_file_load_complete_sender: Sender<ImageUploadRequest>) -> Self {
-        AsynchronousLoader {
-            image_file_load_requests: Vec::new(),
+    pub fn new(image_file_load_complete_sender: Sender<ImageUploadRequest>) -> Result<Self> {
+        let (image_file_load_request_sender, image_file_load_request_receiver) =
+            crossbeam_channel::unbounded();
+
+        // Leave a core for the render thread
+        let decode_thread_count = std::thread::available_parallelism()
+            .map_or(1, |count| count.get().saturating_sub(1))
+            .max(1);
+        let decode_thread_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(decode_thread_count)
+            .thread_name(|index| format!("image_decode_{}", index))
+            .build()?;
+
+        Ok(AsynchronousLoader {
+            image_file_load_request_sender,
+            image_file_load_request_receiver,
+            pending_image_file_loads: Arc::new(AtomicUsize::new(0)),
             image_file_load_complete_sender,
-        }
+            decode_thread_pool: Arc::new(decode_thread_pool),
+            in_flight_decodes: Arc::new(AtomicUsize::new(0)),
+        })
     }
 
-    // XXX: Use a channel to request
-    pub fn request_image_file_load(&mut self, file_name: &str, image: Handle<Image>) {
-        self.image_file_load_requests.push(ImageFileLoadRequest {
-            file_name: file_name.to_string(),
-            image,
-        })
+    /// Loads all mip levels of `image`, the file's `first_mip_level` is loaded into the image's first mip level
+    pub fn request_image_file_load(
+        &mut self,
+        file_name: &str,
+        image: Handle<Image>,
+        first_mip_level: u32,
+    ) {
+        self.pending_image_file_loads
+            .fetch_add(1, Ordering::Relaxed);
+        self.send_request(file_name, image, first_mip_level, false);
     }
 
-    /// Called periodically
+    /// Same as `request_image_file_load` for more detailed mip levels of an already loaded texture
+    pub fn request_image_stream_load(
+        &mut self,
+        file_name: &str,
+        image: Handle<Image>,
+        first_mip_level: u32,
+    ) {
+        self.send_request(file_name, image, first_mip_level, true);
+    }
+
+    fn send_request(
+        &mut self,
+        file_name: &str,
+        image: Handle<Image>,
+        first_mip_level: u32,
+        streamed: bool,
+    ) {
+        self.image_file_load_request_sender
+            .send(ImageFileLoadRequest {
+                file_name: file_name.to_string(),
+                image,
+                first_mip_level,
+                streamed,
+            })
+            .expect("Image file load request receiver disconnected");
+    }
+
+    pub fn pending_image_file_loads(&self) -> usize {
+        self.pending_image_file_loads.load(Ordering::Relaxed)
+    }
+
+    /// Drops all requests that have not been loaded yet, eg. when their scene is unloaded
+    pub fn cancel_pending_requests(&self) {
+        while let Ok(image_request) = self.image_file_load_request_receiver.try_recv() {
+            if !image_request.streamed {
+                self.pending_image_file_loads
+                    .fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Called periodically, hands queued requests to the decode threads. Decoded images are sent to the
+    /// transfer manager as soon as they are ready so decoding overlaps with uploads.
     pub fn update(&mut self) -> Result<()> {
-        if let Some(image_request) = self.image_file_load_requests.pop() {
-            let image_data = load_image_data(image_request.file_name.as_str())?;
-            self.image_file_load_complete_sender
-                .send(ImageUploadRequest {
-                    image: image_request.image,
-                    data: image_data,
-                })?;
-
-            // log::info!(
-            //     "Successfully loaded image {}",
-            //     image_request.file_name.as_str()
-            // );
+        let max_in_flight_decodes =
+            self.decode_thread_pool.current_num_threads() * MAX_IN_FLIGHT_DECODES_PER_THREAD;
+
+        while self.in_flight_decodes.load(Ordering::Relaxed) < max_in_flight_decodes {
+            let image_request = match self.image_file_load_request_receiver.try_recv() {
+                Ok(image_request) => image_request,
+                Err(_) => break,
+            };
+
+            self.in_flight_decodes.fetch_add(1, Ordering::Relaxed);
+
+            let pending_image_file_loads = self.pending_image_file_loads.clone();
+            let in_flight_decodes = self.in_flight_decodes.clone();
+            let image_file_load_complete_sender = self.image_file_load_complete_sender.clone();
+
+            self.decode_thread_pool.spawn(move || {
+                match load_image_data(
+                    &image_request.file_name,
+                    &image_request.image,
+                    image_request.first_mip_level,
+                ) {
+                    Ok(data) => {
+                        let upload_request = ImageUploadRequest {
+                            image: image_request.image,
+                            data,
+                        };
+                        if image_file_load_complete_sender
+                            .send(upload_request)
+                            .is_err()
+                        {
+                            log::warn!("Image upload request receiver disconnected");
+                        }
+                    }
+                    Err(err) => log::error!(
+                        "Failed to load image {}: {:?}",
+                        image_request.file_name,
+                        err
+                    ),
+                }
+
+                if !image_request.streamed {
+                    pending_image_file_loads.fetch_sub(1, Ordering::Relaxed);
+                }
+                in_flight_decodes.fetch_sub(1, Ordering::Relaxed);
+            });
         }
 
         Ok(())
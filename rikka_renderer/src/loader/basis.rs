@@ -0,0 +1,123 @@
+use std::{path::Path, sync::Once};
+
+use anyhow::{anyhow, Context, Result};
+use basis_universal::{TranscodeParameters, Transcoder, TranscoderTextureFormat};
+
+use rikka_core::vk;
+use rikka_gpu::{
+    gpu::Gpu,
+    image::{format_to_srgb, ImageDesc},
+};
+
+/// Block formats in order of preference, uncompressed RGBA is used if none of them are supported
+const TRANSCODE_FORMATS: [(TranscoderTextureFormat, vk::Format); 3] = [
+    (
+        TranscoderTextureFormat::BC7_RGBA,
+        vk::Format::BC7_UNORM_BLOCK,
+    ),
+    (
+        TranscoderTextureFormat::ASTC_4x4_RGBA,
+        vk::Format::ASTC_4X4_UNORM_BLOCK,
+    ),
+    (
+        TranscoderTextureFormat::ETC2_RGBA,
+        vk::Format::ETC2_R8G8B8A8_UNORM_BLOCK,
+    ),
+];
+
+const FALLBACK_TRANSCODE_FORMAT: (TranscoderTextureFormat, vk::Format) =
+    (TranscoderTextureFormat::RGBA32, vk::Format::R8G8B8A8_UNORM);
+
+static TRANSCODER_INIT: Once = Once::new();
+
+pub fn is_basis_file(file_name: &str) -> bool {
+    Path::new(file_name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map_or(false, |extension| extension.eq_ignore_ascii_case("basis"))
+}
+
+/// Best format supported by the Gpu that basis textures are transcoded to
+pub fn transcode_format(gpu: &Gpu) -> vk::Format {
+    TRANSCODE_FORMATS
+        .iter()
+        .find(|(_, format)| gpu.supports_compressed_format(*format))
+        .unwrap_or(&FALLBACK_TRANSCODE_FORMAT)
+        .1
+}
+
+/// Describes the image of the first texture in the file with all of its mip levels
+pub fn image_desc(data: &[u8], format: vk::Format) -> Result<ImageDesc> {
+    let transcoder = new_transcoder();
+    if !transcoder.validate_header(data) {
+        return Err(anyhow!("Invalid basis file header"));
+    }
+
+    let level_description = transcoder
+        .image_level_description(data, 0, 0)
+        .context("Basis file contains no images")?;
+    let mip_level_count = transcoder.image_level_count(data, 0);
+
+    Ok(ImageDesc::new(
+        level_description.original_width,
+        level_description.original_height,
+        1,
+    )
+    .set_format(format)
+    .set_usage_flags(vk::ImageUsageFlags::SAMPLED)
+    .set_mip_level_count(mip_level_count.max(1)))
+}
+
+/// Transcodes mip levels of the first texture in the file to `format`, tightly packed
+pub fn transcode_mips(
+    data: &[u8],
+    format: vk::Format,
+    first_mip_level: u32,
+    mip_level_count: u32,
+) -> Result<Vec<u8>> {
+    let transcode_format = TRANSCODE_FORMATS
+        .iter()
+        .chain(std::iter::once(&FALLBACK_TRANSCODE_FORMAT))
+        // sRGB textures are transcoded to the same block format
+        .find(|(_, transcode_vk_format)| {
+            *transcode_vk_format == format || format_to_srgb(*transcode_vk_format) == Some(format)
+        })
+        .with_context(|| format!("Basis files cannot be transcoded to {:?}", format))?
+        .0;
+
+    let mut transcoder = new_transcoder();
+    transcoder
+        .prepare_transcoding(data)
+        .map_err(|_| anyhow!("Failed to prepare basis transcoding"))?;
+
+    let mut image_data = Vec::new();
+    for mip_level in first_mip_level..first_mip_level + mip_level_count {
+        let mip_data = transcoder
+            .transcode_image_level(
+                data,
+                transcode_format,
+                TranscodeParameters {
+                    image_index: 0,
+                    level_index: mip_level,
+                    ..Default::default()
+                },
+            )
+            .map_err(|err| {
+                anyhow!(
+                    "Failed to transcode basis mip level {}: {:?}",
+                    mip_level,
+                    err
+                )
+            })?;
+        image_data.extend_from_slice(&mip_data);
+    }
+
+    transcoder.end_transcoding();
+
+    Ok(image_data)
+}
+
+fn new_transcoder() -> Transcoder {
+    TRANSCODER_INIT.call_once(basis_universal::transcoder_init);
+    Transcoder::new()
+}
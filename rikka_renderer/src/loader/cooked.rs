@@ -0,0 +1,656 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use ddsfile::{D3D10ResourceDimension, Dds, DxgiFormat, NewDxgiParams};
+use gltf::material::AlphaMode;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    loader::{asynchronous::*, basis, scene::SceneLoadProgress},
+    scene_renderer::{
+        gltf::*, material::*, mesh_optimizer::*, meshlet, tangent::generate_tangents,
+    },
+};
+
+pub const COOKED_SCENE_EXTENSION: &str = "rkscene";
+
+const COOKED_SCENE_MAGIC: [u8; 4] = *b"RKSC";
+/// Bumped on every layout change, old cooked scenes need to be cooked again
+const COOKED_SCENE_VERSION: u32 = 4;
+
+/// Vertex data offsets are aligned so attributes can be fetched directly from the merged buffer
+const COOKED_BUFFER_ALIGNMENT: usize = 16;
+
+pub const INVALID_COOKED_INDEX: u32 = u32::MAX;
+
+/// Relative vertex cache miss ratio increase allowed when reordering triangles for overdraw
+const OVERDRAW_CLUSTER_THRESHOLD: f32 = 1.05;
+
+pub struct CookOptions {
+    /// Reorders triangles and vertices for vertex cache, overdraw and vertex fetch efficiency
+    pub optimize_meshes: bool,
+}
+
+impl Default for CookOptions {
+    fn default() -> Self {
+        Self {
+            optimize_meshes: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CookedTexture {
+    /// Relative to the cooked scene file
+    pub file_name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CookedSampler {
+    pub min_filter: i32,
+    pub mag_filter: i32,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct CookedTextureRef {
+    pub texture_index: u32,
+    /// `INVALID_COOKED_INDEX` if the texture uses the default sampler
+    pub sampler_index: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CookedMaterial {
+    pub draw_flags: u32,
+    pub alpha_cutoff: f32,
+    pub base_color_factor: [f32; 4],
+    pub metallic_roughness_occlusion_factor: [f32; 4],
+
+    pub diffuse_texture: Option<CookedTextureRef>,
+    pub metallic_roughness_texture: Option<CookedTextureRef>,
+    pub normal_texture: Option<CookedTextureRef>,
+    pub occlusion_texture: Option<CookedTextureRef>,
+}
+
+/// Offsets are in bytes into the merged scene buffer
+#[derive(Serialize, Deserialize)]
+pub struct CookedPrimitive {
+    pub material_index: u32,
+
+    pub vertex_count: u32,
+    pub index_count: u32,
+
+    pub position_offset: u32,
+    pub tex_coords_offset: u32,
+    pub normal_offset: u32,
+    /// `INVALID_COOKED_INDEX` if the primitive has no tangents
+    pub tangent_offset: u32,
+    /// `INVALID_COOKED_INDEX` if the primitive has no second texture coordinate set
+    pub tex_coords_1_offset: u32,
+    /// RGBA float colors, `INVALID_COOKED_INDEX` if the primitive has no vertex colors
+    pub color_offset: u32,
+    pub index_offset: u32,
+    /// Indices are stored as u16 if the primitive has at most 65536 vertices, as u32 otherwise
+    pub index_size: u32,
+
+    pub bounds_min: [f32; 3],
+    pub bounds_max: [f32; 3],
+
+    pub meshlet_offset: u32,
+    pub meshlet_count: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CookedMesh {
+    pub primitives: Vec<CookedPrimitive>,
+}
+
+/// Nodes are stored in level order, parents always come before their children
+#[derive(Serialize, Deserialize)]
+pub struct CookedNode {
+    pub name: Option<String>,
+    pub parent: u32,
+    pub level: u32,
+    /// Column major
+    pub local_matrix: [f32; 16],
+    pub mesh_index: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CookedMeshlet {
+    pub vertex_offset: u32,
+    pub triangle_offset: u32,
+    pub vertex_count: u32,
+    pub triangle_count: u32,
+    pub center: [f32; 3],
+    pub radius: f32,
+}
+
+/// glTF scene converted into ready to upload data, see `cook_gltf`
+#[derive(Serialize, Deserialize)]
+pub struct CookedScene {
+    pub nodes: Vec<CookedNode>,
+    pub meshes: Vec<CookedMesh>,
+    pub materials: Vec<CookedMaterial>,
+    pub textures: Vec<CookedTexture>,
+    pub samplers: Vec<CookedSampler>,
+
+    pub meshlets: Vec<CookedMeshlet>,
+    pub meshlet_vertices: Vec<u32>,
+    pub meshlet_triangles: Vec<u8>,
+
+    /// Vertex and index data of all primitives
+    pub buffer: Vec<u8>,
+}
+
+/// Cpu side cooked scene data, loaded without Gpu access so it can be done on a background thread
+pub struct CookedSceneData {
+    pub root_path_buf: PathBuf,
+    pub scene: CookedScene,
+}
+
+pub fn is_cooked_scene_file(file_name: &str) -> bool {
+    Path::new(file_name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map_or(false, |extension| {
+            extension.eq_ignore_ascii_case(COOKED_SCENE_EXTENSION)
+        })
+}
+
+impl CookedSceneData {
+    pub fn new_from_file(
+        file_name: &str,
+        mut progress: impl FnMut(SceneLoadProgress),
+    ) -> Result<Self> {
+        let mut root_path_buf = PathBuf::from(file_name);
+        root_path_buf.pop();
+
+        progress(SceneLoadProgress::Parsing);
+        let mut reader = BufReader::new(
+            File::open(file_name)
+                .with_context(|| format!("Failed to open cooked scene {}", file_name))?,
+        );
+
+        let mut magic = [0u8; 4];
+        let mut version = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        reader.read_exact(&mut version)?;
+        if magic != COOKED_SCENE_MAGIC {
+            return Err(anyhow!("{} is not a cooked scene", file_name));
+        }
+        let version = u32::from_le_bytes(version);
+        if version != COOKED_SCENE_VERSION {
+            return Err(anyhow!(
+                "Cooked scene {} has version {}, expected {}. Cook the scene again",
+                file_name,
+                version,
+                COOKED_SCENE_VERSION
+            ));
+        }
+
+        let scene: CookedScene = bincode::deserialize_from(reader)
+            .with_context(|| format!("Failed to read cooked scene {}", file_name))?;
+
+        Ok(Self {
+            root_path_buf,
+            scene,
+        })
+    }
+}
+
+impl CookedScene {
+    pub fn write_to_file(&self, file_name: &str) -> Result<()> {
+        let mut writer = BufWriter::new(
+            File::create(file_name)
+                .with_context(|| format!("Failed to create cooked scene {}", file_name))?,
+        );
+        writer.write_all(&COOKED_SCENE_MAGIC)?;
+        writer.write_all(&COOKED_SCENE_VERSION.to_le_bytes())?;
+        bincode::serialize_into(&mut writer, self)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    fn push_buffer_data<T: Copy>(&mut self, data: &[T]) -> u32 {
+        let padding = (COOKED_BUFFER_ALIGNMENT - self.buffer.len() % COOKED_BUFFER_ALIGNMENT)
+            % COOKED_BUFFER_ALIGNMENT;
+        self.buffer.resize(self.buffer.len() + padding, 0);
+
+        let offset = self.buffer.len();
+        let bytes = unsafe {
+            std::slice::from_raw_parts(data.as_ptr() as *const u8, std::mem::size_of_val(data))
+        };
+        self.buffer.extend_from_slice(bytes);
+
+        offset as u32
+    }
+}
+
+/// Converts a glTF file into a cooked scene at `output_file_name`. Textures are written with their full mip chain
+/// into a directory next to the cooked scene.
+pub fn cook_gltf(
+    gltf_file_name: &str,
+    output_file_name: &str,
+    options: &CookOptions,
+) -> Result<()> {
+    let scene_data = GltfSceneData::new_from_file(gltf_file_name, |_| {})?;
+    let gltf_file = scene_data.gltf_file();
+    let buffers_data = scene_data.buffers_data();
+
+    let output_path = Path::new(output_file_name);
+    let output_dir = output_path.parent().unwrap_or(Path::new(""));
+    let textures_dir_name = format!(
+        "{}_textures",
+        output_path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .context("Invalid cooked scene file name")?
+    );
+    std::fs::create_dir_all(output_dir.join(&textures_dir_name))?;
+
+    let mut scene = CookedScene {
+        nodes: Vec::new(),
+        meshes: Vec::new(),
+        materials: Vec::new(),
+        textures: Vec::new(),
+        samplers: Vec::new(),
+        meshlets: Vec::new(),
+        meshlet_vertices: Vec::new(),
+        meshlet_triangles: Vec::new(),
+        buffer: Vec::new(),
+    };
+
+    for image in gltf_file.images() {
+        let uri = match image.source() {
+            gltf::image::Source::Uri { uri, .. } => uri,
+            gltf::image::Source::View { .. } => {
+                return Err(anyhow!("glTF image loading from view not implemented!"));
+            }
+        };
+
+        let source_path = scene_data.root_path_buf().join(uri);
+        let file_name = Path::new(&textures_dir_name).join(cook_texture_file_name(&source_path)?);
+        cook_texture(&source_path, &output_dir.join(&file_name))
+            .with_context(|| format!("Failed to cook texture {}", source_path.display()))?;
+
+        log::info!("Cooked texture {}", file_name.display());
+        scene.textures.push(CookedTexture {
+            file_name: file_name.to_string_lossy().into_owned(),
+        });
+    }
+
+    for sampler in gltf_file.samplers() {
+        scene.samplers.push(CookedSampler {
+            min_filter: gltf_min_filter_to_vulkan_filter(
+                sampler
+                    .min_filter()
+                    .unwrap_or(gltf::texture::MinFilter::Linear),
+            )
+            .as_raw(),
+            mag_filter: gltf_mag_filter_to_vulkan_filter(
+                sampler
+                    .mag_filter()
+                    .unwrap_or(gltf::texture::MagFilter::Linear),
+            )
+            .as_raw(),
+        });
+    }
+
+    // The glTF default material is stored after the file's materials
+    for material in gltf_file.materials() {
+        scene.materials.push(cook_material(&material));
+    }
+    let default_material_index = scene.materials.len() as u32;
+    scene.materials.push(default_cooked_material());
+
+    for mesh in gltf_file.meshes() {
+        let mut primitives = Vec::new();
+        for primitive in mesh.primitives() {
+            primitives.push(
+                cook_primitive(
+                    &mut scene,
+                    &primitive,
+                    buffers_data,
+                    default_material_index,
+                    options,
+                )
+                    .with_context(|| {
+                        format!(
+                            "Failed to cook mesh {} primitive {}",
+                            mesh.index(),
+                            primitive.index()
+                        )
+                    })?,
+            );
+        }
+        scene.meshes.push(CookedMesh { primitives });
+    }
+
+    // Store reachable nodes of the default scene in level order
+    let root_scene = gltf_file
+        .default_scene()
+        .or_else(|| gltf_file.scenes().next())
+        .context("glTF file does not contain a scene")?;
+
+    let mut nodes_to_visit = root_scene
+        .nodes()
+        .map(|node| (node, INVALID_COOKED_INDEX, 0))
+        .collect::<VecDeque<_>>();
+    while let Some((node, parent, level)) = nodes_to_visit.pop_front() {
+        let node_index = scene.nodes.len() as u32;
+        let local_matrix = node.transform().matrix();
+
+        scene.nodes.push(CookedNode {
+            name: node.name().map(String::from),
+            parent,
+            level,
+            local_matrix: std::array::from_fn(|index| local_matrix[index / 4][index % 4]),
+            mesh_index: node
+                .mesh()
+                .map_or(INVALID_COOKED_INDEX, |mesh| mesh.index() as u32),
+        });
+
+        for child in node.children() {
+            nodes_to_visit.push_back((child, node_index, level + 1));
+        }
+    }
+
+    scene.write_to_file(output_file_name)?;
+
+    log::info!(
+        "Cooked {} into {}: {} nodes, {} meshes, {} meshlets, {} textures, {} bytes of geometry",
+        gltf_file_name,
+        output_file_name,
+        scene.nodes.len(),
+        scene.meshes.len(),
+        scene.meshlets.len(),
+        scene.textures.len(),
+        scene.buffer.len()
+    );
+
+    Ok(())
+}
+
+fn cook_texture_ref(info_texture: gltf::Texture) -> CookedTextureRef {
+    CookedTextureRef {
+        texture_index: info_texture.source().index() as u32,
+        sampler_index: info_texture
+            .sampler()
+            .index()
+            .map_or(INVALID_COOKED_INDEX, |index| index as u32),
+    }
+}
+
+fn cook_material(gltf_material: &gltf::Material) -> CookedMaterial {
+    let mut draw_flags = DrawFlags::NONE;
+    match gltf_material.alpha_mode() {
+        AlphaMode::Mask => draw_flags |= DrawFlags::ALPHA_MASK,
+        AlphaMode::Blend => draw_flags |= DrawFlags::TRANSPARENT,
+        AlphaMode::Opaque => {}
+    }
+    if gltf_material.double_sided() {
+        draw_flags |= DrawFlags::DOUBLE_SIDED;
+    }
+
+    let gltf_pbr_material = gltf_material.pbr_metallic_roughness();
+    let occlusion_texture = gltf_material.occlusion_texture();
+    if occlusion_texture
+        .as_ref()
+        .map_or(false, |info| info.tex_coord() == 1)
+    {
+        draw_flags |= DrawFlags::OCCLUSION_TEXCOORDS_1;
+    }
+
+    CookedMaterial {
+        draw_flags: draw_flags.bits(),
+        alpha_cutoff: gltf_material
+            .alpha_cutoff()
+            .unwrap_or(INVALID_FLOAT_VALUE),
+        base_color_factor: gltf_pbr_material.base_color_factor(),
+        metallic_roughness_occlusion_factor: [
+            gltf_pbr_material.metallic_factor(),
+            gltf_pbr_material.roughness_factor(),
+            occlusion_texture
+                .as_ref()
+                .map_or(INVALID_FLOAT_VALUE, |info| info.strength()),
+            0.0,
+        ],
+        diffuse_texture: gltf_pbr_material
+            .base_color_texture()
+            .map(|info| cook_texture_ref(info.texture())),
+        metallic_roughness_texture: gltf_pbr_material
+            .metallic_roughness_texture()
+            .map(|info| cook_texture_ref(info.texture())),
+        normal_texture: gltf_material
+            .normal_texture()
+            .map(|info| cook_texture_ref(info.texture())),
+        occlusion_texture: occlusion_texture.map(|info| cook_texture_ref(info.texture())),
+    }
+}
+
+/// glTF default material, a fully metallic and rough white surface
+fn default_cooked_material() -> CookedMaterial {
+    CookedMaterial {
+        draw_flags: DrawFlags::NONE.bits(),
+        alpha_cutoff: INVALID_FLOAT_VALUE,
+        base_color_factor: [1.0; 4],
+        metallic_roughness_occlusion_factor: [1.0, 1.0, INVALID_FLOAT_VALUE, 0.0],
+        diffuse_texture: None,
+        metallic_roughness_texture: None,
+        normal_texture: None,
+        occlusion_texture: None,
+    }
+}
+
+fn cook_primitive(
+    scene: &mut CookedScene,
+    primitive: &gltf::Primitive,
+    buffers_data: &[Vec<u8>],
+    default_material_index: u32,
+    options: &CookOptions,
+) -> Result<CookedPrimitive> {
+    // XXX: Cooked meshes are optimized and split into meshlets as triangle lists, other modes are only supported by
+    // the glTF loader
+    if primitive.mode() != gltf::mesh::Mode::Triangles {
+        return Err(anyhow!(
+            "glTF primitive mode {:?} cannot be cooked, only TRIANGLES is supported",
+            primitive.mode()
+        ));
+    }
+
+    let reader = primitive.reader(|buffer| buffers_data.get(buffer.index()).map(Vec::as_slice));
+
+    let mut positions = reader
+        .read_positions()
+        .context("glTF positions accessor does not exist!")?
+        .collect::<Vec<_>>();
+    let mut vertex_count = positions.len();
+
+    let mut normals = reader
+        .read_normals()
+        .context("glTF normals accessor does not exist!")?
+        .collect::<Vec<_>>();
+    let mut tex_coords = match reader.read_tex_coords(0) {
+        Some(tex_coords) => tex_coords.into_f32().collect::<Vec<_>>(),
+        None => vec![[0.0; 2]; vertex_count],
+    };
+    let mut tangents = reader
+        .read_tangents()
+        .map(|tangents| tangents.collect::<Vec<_>>());
+    let mut tex_coords_1 = reader
+        .read_tex_coords(1)
+        .map(|tex_coords| tex_coords.into_f32().collect::<Vec<_>>());
+    let mut colors = reader
+        .read_colors(0)
+        .map(|colors| colors.into_rgba_f32().collect::<Vec<_>>());
+    let mut indices = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect::<Vec<_>>(),
+        None => (0..vertex_count as u32).collect(),
+    };
+
+    let has_normal_texture = primitive.material().normal_texture().is_some();
+    if tangents.is_none() && has_normal_texture && reader.read_tex_coords(0).is_some() {
+        tangents = generate_tangents(&indices, &positions, &normals, &tex_coords);
+    }
+
+    if options.optimize_meshes {
+        let acmr = average_cache_miss_ratio(&indices, vertex_count);
+
+        indices = optimize_vertex_cache(&indices, vertex_count);
+        let optimized_acmr = average_cache_miss_ratio(&indices, vertex_count);
+        indices = optimize_overdraw(&indices, &positions, OVERDRAW_CLUSTER_THRESHOLD);
+        let overdraw_acmr = average_cache_miss_ratio(&indices, vertex_count);
+
+        // Unreferenced vertices are dropped by the remap
+        let (remapped_vertex_count, remap) = optimize_vertex_fetch_remap(&mut indices, vertex_count);
+        positions = remap_vertex_attribute(&positions, &remap, remapped_vertex_count);
+        normals = remap_vertex_attribute(&normals, &remap, remapped_vertex_count);
+        tex_coords = remap_vertex_attribute(&tex_coords, &remap, remapped_vertex_count);
+        tangents = tangents
+            .map(|tangents| remap_vertex_attribute(&tangents, &remap, remapped_vertex_count));
+        tex_coords_1 = tex_coords_1
+            .map(|tex_coords| remap_vertex_attribute(&tex_coords, &remap, remapped_vertex_count));
+        colors =
+            colors.map(|colors| remap_vertex_attribute(&colors, &remap, remapped_vertex_count));
+
+        log::info!(
+            "Optimized primitive {}: ACMR {:.3} -> {:.3} (vertex cache), {:.3} (overdraw), vertices {} -> {}",
+            primitive.index(),
+            acmr,
+            optimized_acmr,
+            overdraw_acmr,
+            vertex_count,
+            remapped_vertex_count
+        );
+        vertex_count = remapped_vertex_count;
+    }
+
+    let position_offset = scene.push_buffer_data(&positions);
+    let tex_coords_offset = scene.push_buffer_data(&tex_coords);
+    let normal_offset = scene.push_buffer_data(&normals);
+    let tangent_offset = tangents.as_ref().map_or(INVALID_COOKED_INDEX, |tangents| {
+        scene.push_buffer_data(tangents)
+    });
+    let tex_coords_1_offset = tex_coords_1
+        .as_ref()
+        .map_or(INVALID_COOKED_INDEX, |tex_coords| {
+            scene.push_buffer_data(tex_coords)
+        });
+    let color_offset = colors.as_ref().map_or(INVALID_COOKED_INDEX, |colors| {
+        scene.push_buffer_data(colors)
+    });
+    let (index_offset, index_size) = if vertex_count <= u16::MAX as usize + 1 {
+        let indices = indices
+            .iter()
+            .map(|index| *index as u16)
+            .collect::<Vec<_>>();
+        (scene.push_buffer_data(&indices), 2)
+    } else {
+        (scene.push_buffer_data(&indices), 4)
+    };
+
+    let meshlets = meshlet::build_meshlets(&indices, &positions);
+    let meshlet_offset = scene.meshlets.len() as u32;
+    let meshlet_count = meshlets.meshlets.len() as u32;
+    let vertex_base = scene.meshlet_vertices.len() as u32;
+    let triangle_base = scene.meshlet_triangles.len() as u32;
+    scene
+        .meshlets
+        .extend(meshlets.meshlets.iter().map(|meshlet| CookedMeshlet {
+            vertex_offset: vertex_base + meshlet.vertex_offset,
+            triangle_offset: triangle_base + meshlet.triangle_offset,
+            vertex_count: meshlet.vertex_count,
+            triangle_count: meshlet.triangle_count,
+            center: meshlet.center.into(),
+            radius: meshlet.radius,
+        }));
+    scene.meshlet_vertices.extend(meshlets.vertices);
+    scene.meshlet_triangles.extend(meshlets.triangles);
+
+    let bounding_box = primitive.bounding_box();
+
+    Ok(CookedPrimitive {
+        material_index: primitive
+            .material()
+            .index()
+            .map_or(default_material_index, |index| index as u32),
+        vertex_count: vertex_count as u32,
+        index_count: indices.len() as u32,
+        position_offset,
+        tex_coords_offset,
+        normal_offset,
+        tangent_offset,
+        tex_coords_1_offset,
+        color_offset,
+        index_offset,
+        index_size,
+        bounds_min: bounding_box.min,
+        bounds_max: bounding_box.max,
+        meshlet_offset,
+        meshlet_count,
+    })
+}
+
+/// Compressed textures are kept as is, everything else is converted to a mipped dds
+fn cook_texture_file_name(source_path: &Path) -> Result<PathBuf> {
+    let file_name = source_path
+        .file_name()
+        .context("Texture path has no file name")?;
+
+    if basis::is_basis_file(&source_path.to_string_lossy()) || is_dds_file(source_path) {
+        Ok(PathBuf::from(file_name))
+    } else {
+        Ok(PathBuf::from(file_name).with_extension("dds"))
+    }
+}
+
+fn is_dds_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .map_or(false, |extension| extension.eq_ignore_ascii_case("dds"))
+}
+
+fn cook_texture(source_path: &Path, output_path: &Path) -> Result<()> {
+    if basis::is_basis_file(&source_path.to_string_lossy()) || is_dds_file(source_path) {
+        std::fs::copy(source_path, output_path)?;
+        return Ok(());
+    }
+
+    // XXX: Block compress the texture, the cooked texture is only pre-mipped
+    let mut mip = image::open(source_path)?.into_rgba8();
+    let (width, height) = mip.dimensions();
+    let mip_level_count = mip_chain_length(width, height);
+
+    let mut data = Vec::new();
+    for mip_level in 0..mip_level_count {
+        if mip_level > 0 {
+            mip = downsample(&mip);
+        }
+        data.extend_from_slice(mip.as_raw());
+    }
+
+    let mut dds = Dds::new_dxgi(NewDxgiParams {
+        height,
+        width,
+        depth: None,
+        format: DxgiFormat::R8G8B8A8_UNorm,
+        mipmap_levels: Some(mip_level_count),
+        array_layers: None,
+        caps2: None,
+        is_cubemap: false,
+        resource_dimension: D3D10ResourceDimension::Texture2D,
+        alpha_mode: ddsfile::AlphaMode::Unknown,
+    })?;
+    dds.data = data;
+
+    let mut writer = BufWriter::new(File::create(output_path)?);
+    dds.write(&mut writer)?;
+
+    Ok(())
+}
@@ -0,0 +1,161 @@
+use anyhow::{anyhow, Context, Result};
+use ddsfile::{Caps2, D3DFormat, Dds, DxgiFormat, MiscFlag};
+
+use rikka_core::vk;
+use rikka_gpu::image::{format_image_size, ImageDesc};
+
+fn dxgi_format_to_vulkan_format(dxgi_format: DxgiFormat) -> Option<vk::Format> {
+    match dxgi_format {
+        DxgiFormat::BC1_UNorm => Some(vk::Format::BC1_RGBA_UNORM_BLOCK),
+        DxgiFormat::BC1_UNorm_sRGB => Some(vk::Format::BC1_RGBA_SRGB_BLOCK),
+        DxgiFormat::BC2_UNorm => Some(vk::Format::BC2_UNORM_BLOCK),
+        DxgiFormat::BC2_UNorm_sRGB => Some(vk::Format::BC2_SRGB_BLOCK),
+        DxgiFormat::BC3_UNorm => Some(vk::Format::BC3_UNORM_BLOCK),
+        DxgiFormat::BC3_UNorm_sRGB => Some(vk::Format::BC3_SRGB_BLOCK),
+        DxgiFormat::BC4_UNorm => Some(vk::Format::BC4_UNORM_BLOCK),
+        DxgiFormat::BC5_UNorm => Some(vk::Format::BC5_UNORM_BLOCK),
+        DxgiFormat::BC6H_UF16 => Some(vk::Format::BC6H_UFLOAT_BLOCK),
+        DxgiFormat::BC7_UNorm => Some(vk::Format::BC7_UNORM_BLOCK),
+        DxgiFormat::BC7_UNorm_sRGB => Some(vk::Format::BC7_SRGB_BLOCK),
+        // Written by the scene cooker
+        DxgiFormat::R8G8B8A8_UNorm => Some(vk::Format::R8G8B8A8_UNORM),
+        DxgiFormat::R8G8B8A8_UNorm_sRGB => Some(vk::Format::R8G8B8A8_SRGB),
+        DxgiFormat::B8G8R8A8_UNorm => Some(vk::Format::B8G8R8A8_UNORM),
+        DxgiFormat::B8G8R8A8_UNorm_sRGB => Some(vk::Format::B8G8R8A8_SRGB),
+        DxgiFormat::R16G16B16A16_Float => Some(vk::Format::R16G16B16A16_SFLOAT),
+        DxgiFormat::R32G32B32A32_Float => Some(vk::Format::R32G32B32A32_SFLOAT),
+        DxgiFormat::R32_Float => Some(vk::Format::R32_SFLOAT),
+        _ => None,
+    }
+}
+
+/// Legacy D3D formats, premultiplied alpha variants are treated as their straight alpha counterparts
+fn d3d_format_to_vulkan_format(d3d_format: D3DFormat) -> Option<vk::Format> {
+    match d3d_format {
+        D3DFormat::DXT1 => Some(vk::Format::BC1_RGBA_UNORM_BLOCK),
+        D3DFormat::DXT2 | D3DFormat::DXT3 => Some(vk::Format::BC2_UNORM_BLOCK),
+        D3DFormat::DXT4 | D3DFormat::DXT5 => Some(vk::Format::BC3_UNORM_BLOCK),
+        D3DFormat::A8R8G8B8 => Some(vk::Format::B8G8R8A8_UNORM),
+        D3DFormat::A8B8G8R8 => Some(vk::Format::R8G8B8A8_UNORM),
+        D3DFormat::A16B16G16R16F => Some(vk::Format::R16G16B16A16_SFLOAT),
+        D3DFormat::A32B32G32R32F => Some(vk::Format::R32G32B32A32_SFLOAT),
+        D3DFormat::R32F => Some(vk::Format::R32_SFLOAT),
+        _ => None,
+    }
+}
+
+/// Block compressed formats that only have a FourCC code and no D3D format
+fn fourcc_to_vulkan_format(fourcc: u32) -> Option<vk::Format> {
+    match &fourcc.to_le_bytes() {
+        b"ATI1" | b"BC4U" => Some(vk::Format::BC4_UNORM_BLOCK),
+        b"ATI2" | b"BC5U" => Some(vk::Format::BC5_UNORM_BLOCK),
+        _ => None,
+    }
+}
+
+pub fn format(dds: &Dds) -> Result<vk::Format> {
+    if let Some(dxgi_format) = dds.get_dxgi_format() {
+        return dxgi_format_to_vulkan_format(dxgi_format)
+            .ok_or_else(|| anyhow!("Unsupported dds dxgi format {:?}", dxgi_format));
+    }
+
+    dds.get_d3d_format()
+        .and_then(d3d_format_to_vulkan_format)
+        .or_else(|| {
+            dds.header
+                .spf
+                .fourcc
+                .as_ref()
+                .and_then(|fourcc| fourcc_to_vulkan_format(fourcc.0))
+        })
+        .context("Unsupported dds format")
+}
+
+fn is_cube_map(dds: &Dds) -> bool {
+    dds.header.caps2.contains(Caps2::CUBEMAP)
+        || dds.header10.as_ref().map_or(false, |header10| {
+            header10.misc_flag.contains(MiscFlag::TEXTURECUBE)
+        })
+}
+
+/// Number of image layers including cube faces, the array size of cube map arrays counts cubes
+fn array_layer_count(dds: &Dds) -> u32 {
+    let array_size = dds
+        .header10
+        .as_ref()
+        .map_or(1, |header10| header10.array_size.max(1));
+
+    // XXX: Legacy cube maps with missing faces are not handled
+    if is_cube_map(dds) {
+        array_size * 6
+    } else {
+        array_size
+    }
+}
+
+fn mip_size(format: vk::Format, dds: &Dds, mip_level: u32) -> Result<usize> {
+    let width = (dds.get_width() >> mip_level).max(1);
+    let height = (dds.get_height() >> mip_level).max(1);
+    let depth = (dds.get_depth() >> mip_level).max(1);
+
+    Ok(format_image_size(format, width, height).context("Unhandled dds format")? * depth as usize)
+}
+
+/// Describes the image with all of its mip levels, layers and cube faces
+pub fn image_desc(dds: &Dds) -> Result<ImageDesc> {
+    let depth = dds.get_depth().max(1);
+    let array_layer_count = array_layer_count(dds);
+
+    let image_desc = ImageDesc::new(dds.get_width(), dds.get_height(), depth)
+        .set_format(format(dds)?)
+        .set_usage_flags(vk::ImageUsageFlags::SAMPLED)
+        .set_mip_level_count(dds.get_num_mipmap_levels().max(1));
+
+    Ok(if depth > 1 {
+        image_desc.set_image_type(vk::ImageType::TYPE_3D)
+    } else if is_cube_map(dds) {
+        image_desc.set_cube_map(array_layer_count / 6)
+    } else {
+        image_desc.set_array_layer_count(array_layer_count)
+    })
+}
+
+/// Copies `mip_level_count` mip levels starting at `first_mip_level` of all layers. DDS files store all mips of a
+/// layer after each other, the returned data stores all layers of a mip level after each other.
+pub fn load_mips(
+    dds: &Dds,
+    format: vk::Format,
+    first_mip_level: u32,
+    mip_level_count: u32,
+) -> Result<Vec<u8>> {
+    let file_mip_level_count = dds.get_num_mipmap_levels().max(1);
+    if first_mip_level + mip_level_count > file_mip_level_count {
+        return Err(anyhow!(
+            "Dds file has {} mip levels, requested {} starting at {}",
+            file_mip_level_count,
+            mip_level_count,
+            first_mip_level
+        ));
+    }
+
+    let mip_sizes = (0..file_mip_level_count)
+        .map(|mip_level| mip_size(format, dds, mip_level))
+        .collect::<Result<Vec<_>>>()?;
+    let layer_size: usize = mip_sizes.iter().sum();
+    let array_layer_count = array_layer_count(dds) as usize;
+
+    let mut data = Vec::new();
+    for mip_level in first_mip_level as usize..(first_mip_level + mip_level_count) as usize {
+        let mip_offset: usize = mip_sizes[..mip_level].iter().sum();
+        for layer in 0..array_layer_count {
+            let start = layer * layer_size + mip_offset;
+            data.extend_from_slice(
+                dds.data
+                    .get(start..start + mip_sizes[mip_level])
+                    .context("Dds file does not contain the requested mip levels")?,
+            );
+        }
+    }
+
+    Ok(data)
+}
@@ -0,0 +1,105 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use image::{imageops::FilterType, Rgba32FImage};
+
+use rikka_core::vk;
+use rikka_gpu::image::ImageDesc;
+
+use crate::loader::asynchronous::mip_chain_length;
+
+fn file_extension(file_name: &str) -> Option<String> {
+    Path::new(file_name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase())
+}
+
+/// Radiance HDR and OpenEXR files
+pub fn is_hdr_file(file_name: &str) -> bool {
+    matches!(file_extension(file_name).as_deref(), Some("hdr" | "exr"))
+}
+
+/// Radiance HDR files only store 8 bit mantissas and fit into half floats, OpenEXR files keep full precision
+fn file_format(file_name: &str) -> vk::Format {
+    match file_extension(file_name).as_deref() {
+        Some("exr") => vk::Format::R32G32B32A32_SFLOAT,
+        _ => vk::Format::R16G16B16A16_SFLOAT,
+    }
+}
+
+/// Describes the image with a full mip chain, mips are generated by the asynchronous loader
+pub fn image_desc(file_name: &str) -> Result<ImageDesc> {
+    let (width, height) = image::io::Reader::open(file_name)?
+        .with_guessed_format()?
+        .into_dimensions()?;
+
+    Ok(ImageDesc::new(width, height, 1)
+        .set_format(file_format(file_name))
+        .set_usage_flags(vk::ImageUsageFlags::SAMPLED)
+        .set_mip_level_count(mip_chain_length(width, height)))
+}
+
+/// Round to nearest conversion, values outside of the half float range are clamped to the largest finite value
+fn f32_to_f16_bits(value: f32) -> u16 {
+    const MAX_HALF: f32 = 65504.0;
+
+    let value = value.clamp(-MAX_HALF, MAX_HALF);
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+
+    if exponent <= 0 {
+        // Subnormal halfs
+        let subnormal = (value.abs() * 2.0_f32.powi(24)).round() as u16;
+        return sign | subnormal;
+    }
+
+    let half = ((exponent as u32) << 10) | ((bits & 0x7f_ffff) >> 13);
+    let round = (bits >> 12) & 1;
+    sign | (half + round).min(0x7bff) as u16
+}
+
+fn downsample(mip: &Rgba32FImage) -> Rgba32FImage {
+    image::imageops::resize(
+        mip,
+        (mip.width() / 2).max(1),
+        (mip.height() / 2).max(1),
+        FilterType::Triangle,
+    )
+}
+
+/// Decodes the file and generates `mip_level_count` mip levels starting at `first_mip_level`, tightly packed in
+/// `format`
+pub fn load_mips(
+    data: &[u8],
+    format: vk::Format,
+    first_mip_level: u32,
+    mip_level_count: u32,
+) -> Result<Vec<u8>> {
+    let mut mip = image::load_from_memory(data)?.into_rgba32f();
+
+    let mut image_data = Vec::new();
+    for mip_level in 0..first_mip_level + mip_level_count {
+        if mip_level > 0 {
+            mip = downsample(&mip);
+        }
+        if mip_level < first_mip_level {
+            continue;
+        }
+
+        match format {
+            vk::Format::R16G16B16A16_SFLOAT => image_data.extend(
+                mip.as_raw()
+                    .iter()
+                    .flat_map(|value| f32_to_f16_bits(*value).to_le_bytes()),
+            ),
+            vk::Format::R32G32B32A32_SFLOAT => {
+                image_data.extend(mip.as_raw().iter().flat_map(|value| value.to_le_bytes()))
+            }
+            _ => return Err(anyhow!("HDR images cannot be loaded as {:?}", format)),
+        }
+    }
+
+    Ok(image_data)
+}
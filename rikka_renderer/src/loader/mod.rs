@@ -1,2 +1,9 @@
 pub mod asynchronous;
+pub mod basis;
+pub mod cooked;
+pub mod dds;
+pub mod hdr;
+pub mod native;
+pub mod scene;
+pub mod streaming;
 pub mod technique;
@@ -0,0 +1,245 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde_derive::{Deserialize, Serialize};
+
+use rikka_core::projection::Projection;
+
+pub const NATIVE_SCENE_EXTENSION: &str = "rkworld";
+
+/// Bumped on every layout change
+pub(crate) const NATIVE_SCENE_VERSION: u32 = 1;
+
+pub const INVALID_NATIVE_INDEX: u32 = u32::MAX;
+
+/// Node of the source scene graph, the hierarchy is checked against the source scene when loading
+#[derive(Serialize, Deserialize)]
+pub struct NativeNode {
+    /// `INVALID_NATIVE_INDEX` for root nodes
+    pub parent: u32,
+    pub level: u32,
+    /// Column major
+    pub local_matrix: [f32; 16],
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub hidden: bool,
+}
+
+/// Material parameters changed at runtime, texture changes are not saved
+#[derive(Serialize, Deserialize)]
+pub struct NativeMaterial {
+    pub base_color_factor: [f32; 4],
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+}
+
+/// Mesh of the source scene, in the order the source scene creates them
+#[derive(Serialize, Deserialize)]
+pub struct NativeMesh {
+    pub node: u32,
+    /// None if the mesh uses the material of the source scene
+    pub material: Option<NativeMaterial>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NativeLight {
+    pub position: [f32; 3],
+    pub range: f32,
+    pub intensity: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NativeCamera {
+    pub position: [f32; 3],
+    pub forward: [f32; 3],
+    pub projection: Projection,
+    pub exposure: f32,
+}
+
+/// Scene composed from a glTF or cooked scene file with the changes made at runtime, stored as JSON. Meshes, materials
+/// and textures are loaded from the source scene file.
+#[derive(Serialize, Deserialize)]
+pub struct NativeScene {
+    pub version: u32,
+    /// Relative to the working directory like the paths of the app config
+    pub source_file_name: String,
+
+    pub nodes: Vec<NativeNode>,
+    pub meshes: Vec<NativeMesh>,
+    pub light: NativeLight,
+    pub camera: NativeCamera,
+}
+
+pub fn is_native_scene_file(file_name: &str) -> bool {
+    Path::new(file_name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map_or(false, |extension| {
+            extension.eq_ignore_ascii_case(NATIVE_SCENE_EXTENSION)
+        })
+}
+
+impl NativeScene {
+    pub fn new_from_file(file_name: &str) -> Result<Self> {
+        let reader = BufReader::new(
+            File::open(file_name)
+                .with_context(|| format!("Failed to open native scene {}", file_name))?,
+        );
+
+        let scene: NativeScene = serde_json::from_reader(reader)
+            .with_context(|| format!("Failed to read native scene {}", file_name))?;
+        if scene.version != NATIVE_SCENE_VERSION {
+            return Err(anyhow!(
+                "Native scene {} has version {}, expected {}",
+                file_name,
+                scene.version,
+                NATIVE_SCENE_VERSION
+            ));
+        }
+
+        Ok(scene)
+    }
+
+    pub fn write_to_file(&self, file_name: &str) -> Result<()> {
+        let mut writer = BufWriter::new(
+            File::create(file_name)
+                .with_context(|| format!("Failed to create native scene {}", file_name))?,
+        );
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_scene() -> NativeScene {
+        let mut local_matrix = [0.0; 16];
+        for (index, value) in local_matrix.iter_mut().enumerate() {
+            *value = index as f32;
+        }
+
+        NativeScene {
+            version: NATIVE_SCENE_VERSION,
+            source_file_name: String::from("data/scene.gltf"),
+            nodes: vec![
+                NativeNode {
+                    parent: INVALID_NATIVE_INDEX,
+                    level: 0,
+                    local_matrix,
+                    tags: vec![String::from("root")],
+                    hidden: false,
+                },
+                NativeNode {
+                    parent: 0,
+                    level: 1,
+                    local_matrix,
+                    tags: Vec::new(),
+                    hidden: true,
+                },
+            ],
+            meshes: vec![
+                NativeMesh {
+                    node: 1,
+                    material: Some(NativeMaterial {
+                        base_color_factor: [0.25, 0.5, 0.75, 1.0],
+                        metallic_factor: 0.5,
+                        roughness_factor: 0.25,
+                    }),
+                },
+                NativeMesh {
+                    node: 1,
+                    material: None,
+                },
+            ],
+            light: NativeLight {
+                position: [1.0, 2.0, 3.0],
+                range: 10.0,
+                intensity: 2.0,
+            },
+            camera: NativeCamera {
+                position: [0.0, 2.5, 2.0],
+                forward: [0.0, 0.0, -1.0],
+                projection: Projection::orthographic(4.0, 0.1, 100.0),
+                exposure: 1.5,
+            },
+        }
+    }
+
+    fn temp_file_name(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!(
+                "rikka_{}_{}.{}",
+                name,
+                std::process::id(),
+                NATIVE_SCENE_EXTENSION
+            ))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_native_scene_round_trip() {
+        let file_name = temp_file_name("round_trip");
+        let scene = test_scene();
+        scene.write_to_file(&file_name).unwrap();
+        let loaded = NativeScene::new_from_file(&file_name);
+        std::fs::remove_file(&file_name).unwrap();
+        let loaded = loaded.unwrap();
+
+        assert!(is_native_scene_file(&file_name));
+        assert_eq!(loaded.source_file_name, scene.source_file_name);
+
+        assert_eq!(loaded.nodes.len(), scene.nodes.len());
+        for (loaded, node) in loaded.nodes.iter().zip(&scene.nodes) {
+            assert_eq!(loaded.parent, node.parent);
+            assert_eq!(loaded.level, node.level);
+            assert_eq!(loaded.local_matrix, node.local_matrix);
+            assert_eq!(loaded.tags, node.tags);
+            assert_eq!(loaded.hidden, node.hidden);
+        }
+
+        assert_eq!(loaded.meshes.len(), scene.meshes.len());
+        for (loaded, mesh) in loaded.meshes.iter().zip(&scene.meshes) {
+            assert_eq!(loaded.node, mesh.node);
+            match (&loaded.material, &mesh.material) {
+                (Some(loaded), Some(material)) => {
+                    assert_eq!(loaded.base_color_factor, material.base_color_factor);
+                    assert_eq!(loaded.metallic_factor, material.metallic_factor);
+                    assert_eq!(loaded.roughness_factor, material.roughness_factor);
+                }
+                (None, None) => {}
+                _ => panic!("Material override of mesh {} was not restored", mesh.node),
+            }
+        }
+
+        assert_eq!(loaded.light.position, scene.light.position);
+        assert_eq!(loaded.light.range, scene.light.range);
+        assert_eq!(loaded.light.intensity, scene.light.intensity);
+
+        assert_eq!(loaded.camera.position, scene.camera.position);
+        assert_eq!(loaded.camera.forward, scene.camera.forward);
+        assert_eq!(loaded.camera.projection, scene.camera.projection);
+        assert_eq!(loaded.camera.exposure, scene.camera.exposure);
+    }
+
+    #[test]
+    fn test_native_scene_version_mismatch() {
+        let file_name = temp_file_name("version");
+        let mut scene = test_scene();
+        scene.version = NATIVE_SCENE_VERSION + 1;
+        scene.write_to_file(&file_name).unwrap();
+        let loaded = NativeScene::new_from_file(&file_name);
+        std::fs::remove_file(&file_name).unwrap();
+
+        assert!(loaded.is_err());
+    }
+}
@@ -0,0 +1,114 @@
+use std::fmt;
+
+use anyhow::Result;
+use crossbeam_channel::Receiver;
+
+use crate::{loader::cooked::*, scene_renderer::gltf::GltfSceneData};
+
+/// Stage of a scene load. Finished loads have no progress, failed loads return their error from
+/// `SceneRenderer::update_scene_load`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SceneLoadProgress {
+    Parsing,
+    Buffers {
+        loaded: usize,
+        total: usize,
+    },
+    /// Gpu resources are created and textures are streamed in
+    Textures {
+        loaded: usize,
+        total: usize,
+    },
+}
+
+impl fmt::Display for SceneLoadProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneLoadProgress::Parsing => write!(f, "parsing"),
+            SceneLoadProgress::Buffers { loaded, total } => {
+                write!(f, "buffers {}/{}", loaded, total)
+            }
+            SceneLoadProgress::Textures { loaded, total } => {
+                write!(f, "textures {}/{}", loaded, total)
+            }
+        }
+    }
+}
+
+/// Cpu side data of a scene file
+pub enum SceneData {
+    Gltf(GltfSceneData),
+    Cooked(CookedSceneData),
+}
+
+impl SceneData {
+    /// Cooked scenes are detected by their file extension, everything else is loaded as glTF
+    pub fn new_from_file(file_name: &str, progress: impl FnMut(SceneLoadProgress)) -> Result<Self> {
+        if is_cooked_scene_file(file_name) {
+            Ok(Self::Cooked(CookedSceneData::new_from_file(
+                file_name, progress,
+            )?))
+        } else {
+            Ok(Self::Gltf(GltfSceneData::new_from_file(
+                file_name, progress,
+            )?))
+        }
+    }
+}
+
+/// Scene data being loaded on a background thread
+pub struct SceneLoad {
+    file_name: String,
+    progress: SceneLoadProgress,
+    progress_receiver: Receiver<SceneLoadProgress>,
+    scene_data_receiver: Receiver<Result<SceneData>>,
+}
+
+impl SceneLoad {
+    pub fn new(file_name: &str) -> Result<Self> {
+        let (progress_sender, progress_receiver) = crossbeam_channel::unbounded();
+        let (scene_data_sender, scene_data_receiver) = crossbeam_channel::bounded(1);
+
+        let thread_file_name = file_name.to_string();
+        std::thread::Builder::new()
+            .name(String::from("scene_load"))
+            .spawn(move || {
+                let scene_data = SceneData::new_from_file(&thread_file_name, |progress| {
+                    // The load may have been abandoned
+                    let _ = progress_sender.send(progress);
+                });
+                let _ = scene_data_sender.send(scene_data);
+            })?;
+
+        Ok(Self {
+            file_name: file_name.to_string(),
+            progress: SceneLoadProgress::Parsing,
+            progress_receiver,
+            scene_data_receiver,
+        })
+    }
+
+    pub fn file_name(&self) -> &str {
+        &self.file_name
+    }
+
+    pub fn progress(&self) -> &SceneLoadProgress {
+        &self.progress
+    }
+
+    pub fn set_progress(&mut self, progress: SceneLoadProgress) {
+        self.progress = progress;
+    }
+
+    /// Returns the scene data once it has been loaded, load errors are returned instead of being reported as progress
+    pub fn poll(&mut self) -> Option<Result<SceneData>> {
+        self.poll_progress();
+        self.scene_data_receiver.try_recv().ok()
+    }
+
+    fn poll_progress(&mut self) {
+        while let Ok(progress) = self.progress_receiver.try_recv() {
+            self.progress = progress;
+        }
+    }
+}
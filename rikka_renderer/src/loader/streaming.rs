@@ -0,0 +1,393 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+use anyhow::Result;
+
+use rikka_core::nalgebra::{Matrix4, Vector3};
+use rikka_gpu::{
+    constants::MAX_FRAMES, escape::Handle, gpu::Gpu, image::*, types::ImageResourceUpdate,
+};
+
+use crate::{
+    loader::asynchronous::{mip_chain_length, AsynchronousLoader},
+    scene_renderer::mesh::Mesh,
+};
+
+/// Mip levels up to this size are always resident and loaded first
+const RESIDENT_MIP_SIZE: u32 = 64;
+
+/// Limits the number of streaming requests so the most important ones are loaded first
+const MAX_PENDING_STREAM_LOADS: usize = 4;
+
+pub const DEFAULT_TEXTURE_BUDGET: usize = 512 * 1024 * 1024;
+
+/// Camera parameters used to select the mip levels of visible textures
+pub struct StreamingView {
+    pub eye_position: Vector3<f32>,
+    /// Screen pixels covered by one world unit at a distance of one world unit
+    pub pixels_per_unit: f32,
+}
+
+struct StreamedTexture {
+    file_name: String,
+    desc: ImageDesc,
+
+    /// Low mip levels starting at `resident_mip_level`, never evicted
+    resident_image: Handle<Image>,
+    resident_mip_level: u32,
+
+    /// More detailed mip levels, bound instead of the resident image once uploaded
+    streamed_image: Option<Handle<Image>>,
+    streamed_mip_level: u32,
+    /// Image that is still being loaded and uploaded
+    pending_image: Option<Handle<Image>>,
+    pending_mip_level: u32,
+
+    desired_mip_level: u32,
+    /// Frame in which the streamed mip levels were last needed
+    last_used_frame: u64,
+    distance: f32,
+}
+
+impl StreamedTexture {
+    fn bound_mip_level(&self) -> u32 {
+        if self.streamed_image.is_some() {
+            self.streamed_mip_level
+        } else {
+            self.resident_mip_level
+        }
+    }
+
+    fn mips_size(&self, first_mip_level: u32) -> usize {
+        (first_mip_level..self.desc.mip_level_count)
+            .map(|mip_level| {
+                let layer_size = format_image_size(
+                    self.desc.format,
+                    (self.desc.width >> mip_level).max(1),
+                    (self.desc.height >> mip_level).max(1),
+                )
+                .unwrap_or(0);
+                let layer_count =
+                    self.desc.array_layer_count * (self.desc.depth >> mip_level).max(1);
+                layer_size * layer_count as usize
+            })
+            .sum()
+    }
+}
+
+/// Streams texture mip levels in and out based on their distance to the camera, within a Gpu memory budget.
+/// Only the low mip levels of a texture are loaded with the scene, more detailed mip levels are loaded into a
+/// new image that replaces the bindless descriptor of the texture once uploaded.
+pub struct TextureStreamer {
+    /// Keyed by the bindless index of the resident image
+    textures: HashMap<u32, StreamedTexture>,
+    /// Replaced images that may still be used by frames in flight
+    retired_images: VecDeque<(u64, Handle<Image>)>,
+
+    budget: usize,
+    /// Size of all streamed and pending images
+    streamed_size: usize,
+    frame: u64,
+}
+
+impl TextureStreamer {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            textures: HashMap::new(),
+            retired_images: VecDeque::new(),
+            budget,
+            streamed_size: 0,
+            frame: 0,
+        }
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+    }
+
+    pub fn streamed_size(&self) -> usize {
+        self.streamed_size
+    }
+
+    /// Creates the image of a texture with only its low mip levels and requests their load.
+    /// `desc` describes the full texture including all of its mip levels.
+    pub fn add_texture(
+        &mut self,
+        gpu: &mut Gpu,
+        async_loader: &mut AsynchronousLoader,
+        file_name: &str,
+        desc: ImageDesc,
+    ) -> Result<Handle<Image>> {
+        let resident_mip_level = (0..desc.mip_level_count)
+            .find(|mip_level| {
+                (desc.width >> mip_level).max(desc.height >> mip_level) <= RESIDENT_MIP_SIZE
+            })
+            .unwrap_or(desc.mip_level_count - 1);
+
+        let resident_image = gpu.create_image(Self::mips_desc(&desc, resident_mip_level))?;
+        // XXX: Do this internally in the Gpu
+        gpu.add_bindless_image_update(ImageResourceUpdate {
+            frame: 0,
+            image: Some(resident_image.clone()),
+            sampler: None,
+        });
+        async_loader.request_image_file_load(file_name, resident_image.clone(), resident_mip_level);
+
+        self.textures.insert(
+            resident_image.bindless_index(),
+            StreamedTexture {
+                file_name: file_name.to_string(),
+                desc,
+                resident_image: resident_image.clone(),
+                resident_mip_level,
+                streamed_image: None,
+                streamed_mip_level: resident_mip_level,
+                pending_image: None,
+                pending_mip_level: resident_mip_level,
+                desired_mip_level: resident_mip_level,
+                last_used_frame: 0,
+                distance: f32::MAX,
+            },
+        );
+
+        Ok(resident_image)
+    }
+
+    /// Drops all textures, their images are destroyed once the caller's references are gone
+    pub fn clear(&mut self) {
+        self.textures.clear();
+        self.retired_images.clear();
+        self.streamed_size = 0;
+    }
+
+    /// Called once per frame, before the frame is rendered
+    pub fn update(
+        &mut self,
+        gpu: &mut Gpu,
+        async_loader: &mut AsynchronousLoader,
+        meshes: &[Arc<Mesh>],
+        global_matrices: &[Matrix4<f32>],
+        view: &StreamingView,
+    ) {
+        self.frame += 1;
+        while self
+            .retired_images
+            .front()
+            .map_or(false, |(frame, _)| frame + (MAX_FRAMES as u64) < self.frame)
+        {
+            self.retired_images.pop_front();
+        }
+
+        self.bind_uploaded_images(gpu);
+        self.update_desired_mip_levels(meshes, global_matrices, view);
+        self.request_stream_loads(gpu, async_loader);
+    }
+
+    fn bind_uploaded_images(&mut self, gpu: &mut Gpu) {
+        let uploaded_images = gpu
+            .uploaded_images()
+            .iter()
+            .map(|image| image.raw())
+            .collect::<Vec<_>>();
+
+        for texture in self.textures.values_mut() {
+            let uploaded = texture
+                .pending_image
+                .as_ref()
+                .map_or(false, |image| uploaded_images.contains(&image.raw()));
+            if !uploaded {
+                continue;
+            }
+
+            let image = texture.pending_image.take().unwrap();
+            gpu.add_bindless_image_update(ImageResourceUpdate {
+                frame: 0,
+                image: Some(image.clone()),
+                sampler: None,
+            });
+
+            if let Some(streamed_image) = texture.streamed_image.replace(image) {
+                self.streamed_size -= texture.mips_size(texture.streamed_mip_level);
+                self.retired_images.push_back((self.frame, streamed_image));
+            }
+            texture.streamed_mip_level = texture.pending_mip_level;
+        }
+    }
+
+    fn update_desired_mip_levels(
+        &mut self,
+        meshes: &[Arc<Mesh>],
+        global_matrices: &[Matrix4<f32>],
+        view: &StreamingView,
+    ) {
+        for texture in self.textures.values_mut() {
+            texture.desired_mip_level = texture.resident_mip_level;
+            texture.distance = f32::MAX;
+        }
+
+        for mesh in meshes {
+            let bounds = mesh
+                .bounds
+                .transform(&global_matrices[mesh.scene_graph_node_index]);
+            let distance = bounds.distance_to_point(&view.eye_position);
+            // Assumes the texture is mapped once over the whole mesh
+            let screen_size = if distance > 0.0 {
+                bounds.radius() * 2.0 * view.pixels_per_unit / distance
+            } else {
+                f32::MAX
+            };
+
+            let material = &mesh.pbr_material;
+            let images = [
+                &material.diffuse_image,
+                &material.metallic_roughness_image,
+                &material.normal_image,
+                &material.occlusion_image,
+            ];
+
+            for image in images.into_iter().flatten() {
+                let texture = match self.textures.get_mut(&image.bindless_index()) {
+                    Some(texture) => texture,
+                    None => continue,
+                };
+
+                let texture_size = texture.desc.width.max(texture.desc.height) as f32;
+                let mip_level = (texture_size / screen_size.max(1.0))
+                    .log2()
+                    .floor()
+                    .max(0.0) as u32;
+
+                texture.desired_mip_level = texture.desired_mip_level.min(mip_level);
+                texture.distance = texture.distance.min(distance);
+            }
+        }
+
+        for texture in self.textures.values_mut() {
+            if texture.desired_mip_level < texture.resident_mip_level {
+                texture.last_used_frame = self.frame;
+            }
+        }
+    }
+
+    fn request_stream_loads(&mut self, gpu: &mut Gpu, async_loader: &mut AsynchronousLoader) {
+        let pending_loads = self
+            .textures
+            .values()
+            .filter(|texture| texture.pending_image.is_some())
+            .count();
+        if pending_loads >= MAX_PENDING_STREAM_LOADS {
+            return;
+        }
+
+        // Textures missing the most mip levels first, closer textures first otherwise
+        let mut requests = self
+            .textures
+            .iter()
+            .filter(|(_, texture)| {
+                texture.pending_image.is_none()
+                    && texture.desired_mip_level < texture.bound_mip_level()
+            })
+            .map(|(bindless_index, texture)| {
+                (
+                    *bindless_index,
+                    texture.bound_mip_level() - texture.desired_mip_level,
+                    texture.distance,
+                )
+            })
+            .collect::<Vec<_>>();
+        requests.sort_by(|a, b| b.1.cmp(&a.1).then(a.2.total_cmp(&b.2)));
+
+        for (bindless_index, _, _) in requests
+            .into_iter()
+            .take(MAX_PENDING_STREAM_LOADS - pending_loads)
+        {
+            let texture = &self.textures[&bindless_index];
+            let mip_level = texture.desired_mip_level;
+            let size = texture.mips_size(mip_level);
+
+            if self.streamed_size + size > self.budget && !self.evict(gpu, size, bindless_index) {
+                continue;
+            }
+
+            let texture = self.textures.get_mut(&bindless_index).unwrap();
+            let image = match gpu.create_image_with_bindless_index(
+                Self::mips_desc(&texture.desc, mip_level),
+                &texture.resident_image,
+            ) {
+                Ok(image) => image,
+                Err(err) => {
+                    log::warn!("Failed to create streamed texture image: {}", err);
+                    continue;
+                }
+            };
+            if let Some(sampler) = texture.resident_image.linked_sampler() {
+                image.set_linked_sampler(sampler);
+            }
+            async_loader.request_image_stream_load(&texture.file_name, image.clone(), mip_level);
+
+            texture.pending_image = Some(image);
+            texture.pending_mip_level = mip_level;
+            self.streamed_size += size;
+        }
+    }
+
+    /// Evicts the streamed mip levels of the least recently used textures until `size` fits into the budget.
+    /// Textures that are needed by the current frame are not evicted.
+    fn evict(&mut self, gpu: &mut Gpu, size: usize, requesting_bindless_index: u32) -> bool {
+        let mut candidates = self
+            .textures
+            .iter()
+            .filter(|(bindless_index, texture)| {
+                **bindless_index != requesting_bindless_index
+                    && texture.streamed_image.is_some()
+                    && texture.last_used_frame < self.frame
+            })
+            .map(|(bindless_index, texture)| (*bindless_index, texture.last_used_frame))
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|(_, last_used_frame)| *last_used_frame);
+
+        for (bindless_index, _) in candidates {
+            if self.streamed_size + size <= self.budget {
+                break;
+            }
+
+            let texture = self.textures.get_mut(&bindless_index).unwrap();
+            let streamed_image = texture.streamed_image.take().unwrap();
+            self.streamed_size -= texture.mips_size(texture.streamed_mip_level);
+            self.retired_images.push_back((self.frame, streamed_image));
+            texture.streamed_mip_level = texture.resident_mip_level;
+
+            // XXX: Descriptor updates are applied at the end of the frame, the retired image is kept alive until
+            //      frames in flight are done with it
+            gpu.add_bindless_image_update(ImageResourceUpdate {
+                frame: 0,
+                image: Some(texture.resident_image.clone()),
+                sampler: None,
+            });
+        }
+
+        self.streamed_size + size <= self.budget
+    }
+
+    fn mips_desc(desc: &ImageDesc, first_mip_level: u32) -> ImageDesc {
+        let width = (desc.width >> first_mip_level).max(1);
+        let height = (desc.height >> first_mip_level).max(1);
+        let depth = (desc.depth >> first_mip_level).max(1);
+
+        ImageDesc::new(width, height, depth)
+            .set_format(desc.format)
+            .set_image_type(desc.image_type)
+            .set_array_layer_count(desc.array_layer_count)
+            .set_usage_flags(desc.usage_flags)
+            .set_flags(desc.flags)
+            .set_mip_level_count(
+                (desc.mip_level_count - first_mip_level).min(mip_chain_length(width, height)),
+            )
+    }
+}
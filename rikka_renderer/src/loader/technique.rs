@@ -1,3 +1,8 @@
+use std::{
+    sync::{Arc, Weak},
+    time::SystemTime,
+};
+
 use anyhow::{Context, Result};
 use serde_derive::{Deserialize, Serialize};
 
@@ -64,20 +69,85 @@ impl Into<vk::CompareOp> for CompareOp {
     }
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum StencilOp {
+    Keep,
+    Zero,
+    Replace,
+    IncrementAndClamp,
+    DecrementAndClamp,
+    Invert,
+    IncrementAndWrap,
+    DecrementAndWrap,
+}
+
+impl Into<vk::StencilOp> for StencilOp {
+    fn into(self) -> vk::StencilOp {
+        match self {
+            Self::Keep => vk::StencilOp::KEEP,
+            Self::Zero => vk::StencilOp::ZERO,
+            Self::Replace => vk::StencilOp::REPLACE,
+            Self::IncrementAndClamp => vk::StencilOp::INCREMENT_AND_CLAMP,
+            Self::DecrementAndClamp => vk::StencilOp::DECREMENT_AND_CLAMP,
+            Self::Invert => vk::StencilOp::INVERT,
+            Self::IncrementAndWrap => vk::StencilOp::INCREMENT_AND_WRAP,
+            Self::DecrementAndWrap => vk::StencilOp::DECREMENT_AND_WRAP,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StencilFaceState {
+    pub fail_op: StencilOp,
+    pub pass_op: StencilOp,
+    pub depth_fail_op: StencilOp,
+    pub compare_op: CompareOp,
+    pub compare_mask: u32,
+    pub write_mask: u32,
+    pub reference: u32,
+}
+
+impl Into<gpu_types::StencilOpState> for StencilFaceState {
+    fn into(self) -> gpu_types::StencilOpState {
+        gpu_types::StencilOpState::new()
+            .set_fail_op(self.fail_op.into())
+            .set_pass_op(self.pass_op.into())
+            .set_depth_fail_op(self.depth_fail_op.into())
+            .set_compare_op(self.compare_op.into())
+            .set_compare_mask(self.compare_mask)
+            .set_write_mask(self.write_mask)
+            .set_reference(self.reference)
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DepthState {
     pub write_enable: bool,
     pub test_enable: bool,
     pub compare_op: CompareOp,
+    /// Stencil test is enabled if a front face state is specified, back face uses the front state if unspecified
+    #[serde(default)]
+    pub stencil_front: Option<StencilFaceState>,
+    #[serde(default)]
+    pub stencil_back: Option<StencilFaceState>,
 }
 
 impl Into<gpu_types::DepthStencilState> for DepthState {
     fn into(self) -> gpu_types::DepthStencilState {
-        gpu_types::DepthStencilState {
-            depth_test_enable: self.write_enable,
-            depth_write_enable: self.write_enable,
-            depth_compare: self.compare_op.into(),
+        let mut depth_stencil_state = gpu_types::DepthStencilState::new();
+        depth_stencil_state.depth_test_enable = self.test_enable;
+        depth_stencil_state.depth_write_enable = self.write_enable;
+        depth_stencil_state.depth_compare = self.compare_op.into();
+
+        if let Some(stencil_front) = self.stencil_front {
+            let stencil_back = self.stencil_back.unwrap_or_else(|| stencil_front.clone());
+            depth_stencil_state = depth_stencil_state
+                .set_stencil_test(true)
+                .set_stencil_front(stencil_front.into())
+                .set_stencil_back(stencil_back.into());
         }
+
+        depth_stencil_state
     }
 }
 
@@ -145,6 +215,23 @@ pub struct RasterizationState {
     pub cull_mode: CullMode,
     pub front_face: FrontFace,
     pub polygon_mode: PolygonMode,
+    #[serde(default)]
+    pub depth_bias: Option<DepthBias>,
+    /// Only used with line topologies or line polygon mode
+    #[serde(default = "default_line_width")]
+    pub line_width: f32,
+}
+
+fn default_line_width() -> f32 {
+    1.0
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DepthBias {
+    pub constant_factor: f32,
+    pub slope_factor: f32,
+    #[serde(default)]
+    pub clamp: f32,
 }
 
 impl Into<gpu_types::RasterizationState> for RasterizationState {
@@ -153,10 +240,161 @@ impl Into<gpu_types::RasterizationState> for RasterizationState {
             cull_mode: self.cull_mode.into(),
             front_face: self.front_face.into(),
             polygon_mode: self.polygon_mode.into(),
+            depth_bias: self.depth_bias.map(|depth_bias| {
+                gpu_types::DepthBias::new(depth_bias.constant_factor, depth_bias.slope_factor)
+                    .set_clamp(depth_bias.clamp)
+            }),
+            line_width: self.line_width,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum BlendFactor {
+    Zero,
+    One,
+    SrcColor,
+    OneMinusSrcColor,
+    DstColor,
+    OneMinusDstColor,
+    SrcAlpha,
+    OneMinusSrcAlpha,
+    DstAlpha,
+    OneMinusDstAlpha,
+}
+
+impl Into<vk::BlendFactor> for BlendFactor {
+    fn into(self) -> vk::BlendFactor {
+        match self {
+            Self::Zero => vk::BlendFactor::ZERO,
+            Self::One => vk::BlendFactor::ONE,
+            Self::SrcColor => vk::BlendFactor::SRC_COLOR,
+            Self::OneMinusSrcColor => vk::BlendFactor::ONE_MINUS_SRC_COLOR,
+            Self::DstColor => vk::BlendFactor::DST_COLOR,
+            Self::OneMinusDstColor => vk::BlendFactor::ONE_MINUS_DST_COLOR,
+            Self::SrcAlpha => vk::BlendFactor::SRC_ALPHA,
+            Self::OneMinusSrcAlpha => vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+            Self::DstAlpha => vk::BlendFactor::DST_ALPHA,
+            Self::OneMinusDstAlpha => vk::BlendFactor::ONE_MINUS_DST_ALPHA,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum BlendOp {
+    Add,
+    Subtract,
+    ReverseSubtract,
+    Min,
+    Max,
+}
+
+impl Into<vk::BlendOp> for BlendOp {
+    fn into(self) -> vk::BlendOp {
+        match self {
+            Self::Add => vk::BlendOp::ADD,
+            Self::Subtract => vk::BlendOp::SUBTRACT,
+            Self::ReverseSubtract => vk::BlendOp::REVERSE_SUBTRACT,
+            Self::Min => vk::BlendOp::MIN,
+            Self::Max => vk::BlendOp::MAX,
         }
     }
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BlendEquation {
+    pub source: BlendFactor,
+    pub destination: BlendFactor,
+    pub operation: BlendOp,
+}
+
+/// Blend state of a single color attachment
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BlendState {
+    pub enable: bool,
+    pub color: Option<BlendEquation>,
+    /// Uses the color equation if not specified
+    pub alpha: Option<BlendEquation>,
+}
+
+impl Into<gpu_types::BlendState> for BlendState {
+    fn into(self) -> gpu_types::BlendState {
+        let mut blend_state = gpu_types::BlendState::new().set_enable(self.enable);
+        if let Some(color) = self.color {
+            blend_state = blend_state.set_color(
+                color.source.into(),
+                color.destination.into(),
+                color.operation.into(),
+            );
+        }
+        if let Some(alpha) = self.alpha {
+            blend_state = blend_state.set_alpha(
+                alpha.source.into(),
+                alpha.destination.into(),
+                alpha.operation.into(),
+            );
+        }
+        blend_state
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum PrimitiveTopology {
+    PointList,
+    LineList,
+    LineStrip,
+    TriangleList,
+    TriangleStrip,
+    TriangleFan,
+}
+
+impl Into<vk::PrimitiveTopology> for PrimitiveTopology {
+    fn into(self) -> vk::PrimitiveTopology {
+        match self {
+            Self::PointList => vk::PrimitiveTopology::POINT_LIST,
+            Self::LineList => vk::PrimitiveTopology::LINE_LIST,
+            Self::LineStrip => vk::PrimitiveTopology::LINE_STRIP,
+            Self::TriangleList => vk::PrimitiveTopology::TRIANGLE_LIST,
+            Self::TriangleStrip => vk::PrimitiveTopology::TRIANGLE_STRIP,
+            Self::TriangleFan => vk::PrimitiveTopology::TRIANGLE_FAN,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum DynamicState {
+    Viewport,
+    Scissor,
+    LineWidth,
+    DepthBias,
+    BlendConstants,
+    CullMode,
+    FrontFace,
+    DepthTestEnable,
+    DepthWriteEnable,
+    DepthCompareOp,
+}
+
+impl Into<vk::DynamicState> for DynamicState {
+    fn into(self) -> vk::DynamicState {
+        match self {
+            Self::Viewport => vk::DynamicState::VIEWPORT,
+            Self::Scissor => vk::DynamicState::SCISSOR,
+            Self::LineWidth => vk::DynamicState::LINE_WIDTH,
+            Self::DepthBias => vk::DynamicState::DEPTH_BIAS,
+            Self::BlendConstants => vk::DynamicState::BLEND_CONSTANTS,
+            Self::CullMode => vk::DynamicState::CULL_MODE,
+            Self::FrontFace => vk::DynamicState::FRONT_FACE,
+            Self::DepthTestEnable => vk::DynamicState::DEPTH_TEST_ENABLE,
+            Self::DepthWriteEnable => vk::DynamicState::DEPTH_WRITE_ENABLE,
+            Self::DepthCompareOp => vk::DynamicState::DEPTH_COMPARE_OP,
+        }
+    }
+}
+
+/// Upper bound on permutation defines per pipeline, every combination is compiled
+const MAX_PERMUTATION_DEFINES: usize = 6;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Pipeline {
     pub name: String,
@@ -165,24 +403,69 @@ pub struct Pipeline {
     pub vertex_inputs: Vec<VertexInput>,
     pub depth_state: Option<DepthState>,
     pub rasterization_state: Option<RasterizationState>,
-    // pub blend_state: Vec<BlendState>,
+    /// Either empty(blending disabled) or one per color attachment of the render pass
+    #[serde(default)]
+    pub blend_states: Vec<BlendState>,
+    pub primitive_topology: Option<PrimitiveTopology>,
+    #[serde(default)]
+    pub dynamic_states: Vec<DynamicState>,
+    /// Defines passed to all shaders of this pipeline
+    #[serde(default)]
+    pub defines: Vec<ShaderDefine>,
+    /// Defines that are toggled per draw, a pipeline variant is created for every combination
+    #[serde(default)]
+    pub permutation_defines: Vec<String>,
+    /// Push constant sizes in bytes
+    #[serde(default)]
+    pub vertex_const_size: Option<u32>,
+    #[serde(default)]
+    pub fragment_const_size: Option<u32>,
 }
 
 impl Pipeline {
+    /// Returns one description per permutation, indexed by the bitmask of enabled `permutation_defines`
+    pub fn into_graphics_pipeline_permutation_descs(
+        self,
+        renderer: &Renderer,
+        render_graph: &Graph,
+    ) -> Result<Vec<GraphicsPipelineDesc>> {
+        if self.permutation_defines.len() > MAX_PERMUTATION_DEFINES {
+            return Err(anyhow::anyhow!(
+                "Pipeline {} has {} permutation defines, maximum is {}",
+                self.name,
+                self.permutation_defines.len(),
+                MAX_PERMUTATION_DEFINES
+            ));
+        }
+
+        (0..1_u32 << self.permutation_defines.len())
+            .map(|permutation| {
+                let mut pipeline = self.clone();
+                for (index, define) in self.permutation_defines.iter().enumerate() {
+                    if permutation & (1 << index) != 0 {
+                        pipeline.defines.push(ShaderDefine::new(define, None));
+                    }
+                }
+                pipeline.into_graphics_pipeline_desc(renderer, render_graph)
+            })
+            .collect()
+    }
+
     pub fn into_graphics_pipeline_desc(
         self,
         renderer: &Renderer,
         render_graph: &Graph,
     ) -> Result<GraphicsPipelineDesc> {
-        let mut desc = GraphicsPipelineDesc::new()
-            .set_extent(renderer.extent().width, renderer.extent().height);
+        let mut desc = GraphicsPipelineDesc::new();
 
         let mut shader_state = ShaderStateDesc::new();
         for shader in self.shaders {
-            shader_state = shader_state.add_stage(ShaderStageDesc::new_from_source_file(
+            let mut stage = ShaderStageDesc::new_from_source_file(
                 shader.file_name.as_str(),
                 shader.shader_type,
-            ));
+            );
+            stage.defines = self.defines.clone();
+            shader_state = shader_state.add_stage(stage);
         }
         desc = desc.set_shader_state(shader_state);
 
@@ -208,7 +491,7 @@ impl Pipeline {
                 gpu_types::RenderingState::new_dimensionless()
                     .add_color_attachment(
                         gpu_types::RenderColorAttachment::new()
-                            .set_format(renderer.gpu().swapchain().format()),
+                            .set_format(renderer.gpu().swapchain_format()),
                     )
                     // XXX: Swapchain does not generally have depth attahcment
                     //      Removs this hardcoded depth attachment
@@ -236,6 +519,35 @@ impl Pipeline {
             desc = desc.set_rasterization_state(rasterization_state.into());
         }
 
+        if !self.blend_states.is_empty()
+            && self.blend_states.len() != desc.rendering_state.color_attachments.len()
+        {
+            return Err(anyhow::anyhow!(
+                "Pipeline {} has {} blend states but {} color attachments",
+                self.name,
+                self.blend_states.len(),
+                desc.rendering_state.color_attachments.len()
+            ));
+        }
+        for blend_state in self.blend_states {
+            desc = desc.add_blend_state(blend_state.into());
+        }
+
+        if let Some(primitive_topology) = self.primitive_topology {
+            desc = desc.set_primitive_topology(primitive_topology.into());
+        }
+
+        for dynamic_state in self.dynamic_states {
+            desc = desc.add_dynamic_state(dynamic_state.into());
+        }
+
+        if let Some(vertex_const_size) = self.vertex_const_size {
+            desc = desc.set_vertex_const_size(vertex_const_size);
+        }
+        if let Some(fragment_const_size) = self.fragment_const_size {
+            desc = desc.set_fragment_const_size(fragment_const_size);
+        }
+
         Ok(desc)
     }
 }
@@ -256,9 +568,17 @@ impl Technique {
         let mut desc = RenderTechniqueDesc::new(self.name);
 
         for pipeline in self.pipelines {
-            desc = desc.add_graphics_pipeline(
-                pipeline.into_graphics_pipeline_desc(renderer, render_graph)?,
-            );
+            if pipeline.permutation_defines.is_empty() {
+                desc = desc.add_graphics_pipeline(
+                    pipeline.into_graphics_pipeline_desc(renderer, render_graph)?,
+                );
+            } else {
+                let permutation_defines = pipeline.permutation_defines.clone();
+                desc = desc.add_graphics_pipeline_permutations(
+                    permutation_defines,
+                    pipeline.into_graphics_pipeline_permutation_descs(renderer, render_graph)?,
+                );
+            }
         }
 
         Ok(desc)
@@ -282,3 +602,53 @@ pub fn parse_from_file(
     let file_contents = std::fs::read_to_string(file_name)?;
     parse_from_string(&file_contents, renderer, render_graph)
 }
+
+struct WatchedTechniqueFile {
+    file_name: String,
+    modified: Option<SystemTime>,
+    technique: Weak<RenderTechnique>,
+}
+
+/// Polls technique files for modifications
+pub struct TechniqueFileWatcher {
+    files: Vec<WatchedTechniqueFile>,
+}
+
+impl TechniqueFileWatcher {
+    pub fn new() -> Self {
+        Self { files: Vec::new() }
+    }
+
+    pub fn watch(&mut self, file_name: &str, technique: &Arc<RenderTechnique>) {
+        self.files.retain(|file| file.file_name != file_name);
+        self.files.push(WatchedTechniqueFile {
+            file_name: file_name.to_owned(),
+            modified: Self::file_modified_time(file_name),
+            technique: Arc::downgrade(technique),
+        });
+    }
+
+    /// Returns techniques whose files changed since the last poll, techniques that were dropped are no longer watched
+    pub fn poll_modified(&mut self) -> Vec<(String, Arc<RenderTechnique>)> {
+        self.files.retain(|file| file.technique.strong_count() > 0);
+
+        let mut modified_techniques = Vec::new();
+        for file in &mut self.files {
+            let modified = Self::file_modified_time(&file.file_name);
+            if modified.is_some() && modified != file.modified {
+                file.modified = modified;
+                if let Some(technique) = file.technique.upgrade() {
+                    modified_techniques.push((file.file_name.clone(), technique));
+                }
+            }
+        }
+
+        modified_techniques
+    }
+
+    fn file_modified_time(file_name: &str) -> Option<SystemTime> {
+        std::fs::metadata(file_name)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+}
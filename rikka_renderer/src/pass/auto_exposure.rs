@@ -0,0 +1,315 @@
+use std::{mem::size_of, sync::Arc, time::Instant};
+
+use anyhow::Result;
+
+use rikka_core::vk;
+use rikka_gpu::{
+    barriers::*, buffer::*, command_buffer::CommandBuffer, compute_pipeline::*,
+    constants::MAX_FRAMES, descriptor_set::*, image::*, shader_state::*,
+};
+
+use crate::renderer::*;
+
+/// Bins the log luminance of the HDR scene color. Descriptor set 0 bindings: 0 - HDR scene color,
+/// 1 - histogram storage buffer of `LUMINANCE_HISTOGRAM_BIN_COUNT` u32 counters.
+const LUMINANCE_HISTOGRAM_SHADER_FILE_PATH: &str = "shaders/luminance_histogram.comp";
+
+/// Bin 0 counts pixels darker than the minimum luminance, the other bins split the log luminance range evenly
+pub const LUMINANCE_HISTOGRAM_BIN_COUNT: usize = 256;
+
+const LUMINANCE_HISTOGRAM_WORKGROUP_SIZE: u32 = 16;
+
+/// Frame time used for the adaptation is clamped so stalls do not produce sudden exposure jumps
+const MAX_ADAPTATION_DELTA_TIME: f32 = 0.1;
+
+#[derive(Clone, Copy)]
+pub struct AutoExposureDesc {
+    /// Log2 luminance range covered by the histogram, luminance outside of it is clamped
+    pub min_log_luminance: f32,
+    pub max_log_luminance: f32,
+    /// Fractions of the darkest and brightest pixels ignored when averaging the luminance
+    pub low_percentile: f32,
+    pub high_percentile: f32,
+    /// Middle grey the average luminance is exposed to
+    pub key_value: f32,
+    /// Adaptation rates in 1/s when the scene gets brighter and darker, dark adaptation is usually slower
+    pub brightening_rate: f32,
+    pub darkening_rate: f32,
+    /// Exposure bias in stops
+    pub exposure_compensation: f32,
+}
+
+impl AutoExposureDesc {
+    pub fn new() -> Self {
+        Self {
+            min_log_luminance: -10.0,
+            max_log_luminance: 6.0,
+            low_percentile: 0.5,
+            high_percentile: 0.95,
+            key_value: 0.18,
+            brightening_rate: 3.0,
+            darkening_rate: 1.0,
+            exposure_compensation: 0.0,
+        }
+    }
+
+    pub fn set_log_luminance_range(mut self, min: f32, max: f32) -> Self {
+        self.min_log_luminance = min;
+        self.max_log_luminance = max;
+        self
+    }
+
+    pub fn set_percentiles(mut self, low: f32, high: f32) -> Self {
+        self.low_percentile = low;
+        self.high_percentile = high;
+        self
+    }
+
+    pub fn set_key_value(mut self, key_value: f32) -> Self {
+        self.key_value = key_value;
+        self
+    }
+
+    pub fn set_adaptation_rates(mut self, brightening: f32, darkening: f32) -> Self {
+        self.brightening_rate = brightening;
+        self.darkening_rate = darkening;
+        self
+    }
+
+    pub fn set_exposure_compensation(mut self, stops: f32) -> Self {
+        self.exposure_compensation = stops;
+        self
+    }
+
+    fn log_luminance_range(&self) -> f32 {
+        (self.max_log_luminance - self.min_log_luminance).max(f32::EPSILON)
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GpuLuminanceHistogramConstants {
+    min_log_luminance: f32,
+    inverse_log_luminance_range: f32,
+    width: u32,
+    height: u32,
+}
+
+/// Eye adaptation from a luminance histogram of the HDR scene color. The histogram is built on the Gpu every frame
+/// and read back once the frame completed, the exposure is adapted on the Cpu and used by the tonemapping pass.
+pub struct AutoExposure {
+    desc: AutoExposureDesc,
+
+    pipeline: Handle<ComputePipeline>,
+    histogram_buffers: [Handle<Buffer>; MAX_FRAMES as usize],
+    descriptor_sets: Vec<Arc<DescriptorSet>>,
+    /// Set when the histogram of the frame was recorded and not read back yet
+    histogram_recorded: [bool; MAX_FRAMES as usize],
+
+    hdr_image: Handle<Image>,
+
+    /// Temporally adapted scene luminance, None until the first histogram was read back
+    adapted_luminance: Option<f32>,
+    last_adaptation: Option<Instant>,
+}
+
+impl AutoExposure {
+    pub fn new(
+        renderer: &Renderer,
+        hdr_image: Handle<Image>,
+        desc: AutoExposureDesc,
+    ) -> Result<Self> {
+        let pipeline = renderer.gpu().create_compute_pipeline(
+            ComputePipelineDesc::new()
+                .set_compute_stage(ShaderStageDesc::new_from_source_file(
+                    LUMINANCE_HISTOGRAM_SHADER_FILE_PATH,
+                    ShaderStageType::Compute,
+                ))
+                .set_push_constants::<GpuLuminanceHistogramConstants>(),
+        )?;
+
+        let create_histogram_buffer = || {
+            renderer.create_buffer(
+                BufferDesc::new()
+                    .set_size((LUMINANCE_HISTOGRAM_BIN_COUNT * size_of::<u32>()) as _)
+                    .set_usage_flags(
+                        vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+                    )
+                    .set_device_only(false)
+                    .set_readback(true),
+            )
+        };
+        let histogram_buffers = [create_histogram_buffer()?, create_histogram_buffer()?];
+
+        let mut auto_exposure = Self {
+            desc,
+            pipeline,
+            histogram_buffers,
+            descriptor_sets: Vec::new(),
+            histogram_recorded: [false; MAX_FRAMES as usize],
+            hdr_image: hdr_image.clone(),
+            adapted_luminance: None,
+            last_adaptation: None,
+        };
+        auto_exposure.set_hdr_image(renderer, hdr_image)?;
+
+        Ok(auto_exposure)
+    }
+
+    /// Needs to be called when the HDR scene color is recreated, the Gpu must not use the previous image
+    pub fn set_hdr_image(&mut self, renderer: &Renderer, hdr_image: Handle<Image>) -> Result<()> {
+        if !hdr_image.has_linked_sampler() {
+            hdr_image.set_linked_sampler(renderer.gpu().default_sampler().clone());
+        }
+
+        self.descriptor_sets = self
+            .histogram_buffers
+            .iter()
+            .map(|histogram_buffer| {
+                renderer.create_descriptor_set(
+                    DescriptorSetDesc::new(self.pipeline.descriptor_set_layouts()[0].clone())
+                        .add_image_resource(hdr_image.clone(), 0)
+                        .add_buffer_resource(histogram_buffer.clone(), 1),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.hdr_image = hdr_image;
+
+        Ok(())
+    }
+
+    pub fn desc(&self) -> &AutoExposureDesc {
+        &self.desc
+    }
+
+    /// The adapted luminance is kept, only the parameters of the following adaptation change
+    pub fn set_desc(&mut self, desc: AutoExposureDesc) {
+        self.desc = desc;
+    }
+
+    /// Linear scale applied to the HDR scene color before tonemapping
+    pub fn exposure(&self) -> f32 {
+        let luminance = self.adapted_luminance.unwrap_or(self.desc.key_value);
+        self.desc.key_value / luminance.max(f32::EPSILON) * self.desc.exposure_compensation.exp2()
+    }
+
+    /// Adapts the exposure to the histogram recorded by the last use of `frame_index`.
+    /// The Gpu must have finished that frame, e.g. after the frame fence was waited on.
+    pub fn update(&mut self, frame_index: usize) -> Result<()> {
+        if !self.histogram_recorded[frame_index] {
+            return Ok(());
+        }
+        self.histogram_recorded[frame_index] = false;
+
+        let histogram = self.histogram_buffers[frame_index]
+            .read_data_from_buffer::<u32>(LUMINANCE_HISTOGRAM_BIN_COUNT)?;
+        let target_luminance = match self.average_luminance(&histogram) {
+            Some(luminance) => luminance,
+            None => return Ok(()),
+        };
+
+        let now = Instant::now();
+        let delta_time = self
+            .last_adaptation
+            .map(|last| (now - last).as_secs_f32().min(MAX_ADAPTATION_DELTA_TIME))
+            .unwrap_or(0.0);
+        self.last_adaptation = Some(now);
+
+        let adapted_luminance = match self.adapted_luminance {
+            Some(adapted_luminance) => {
+                let rate = if target_luminance > adapted_luminance {
+                    self.desc.brightening_rate
+                } else {
+                    self.desc.darkening_rate
+                };
+                adapted_luminance
+                    + (target_luminance - adapted_luminance) * (1.0 - (-delta_time * rate).exp())
+            }
+            None => target_luminance,
+        };
+        self.adapted_luminance = Some(adapted_luminance);
+
+        Ok(())
+    }
+
+    /// Average of the histogram bins between the low and high percentiles, None for an empty histogram
+    fn average_luminance(&self, histogram: &[u32]) -> Option<f32> {
+        let pixel_count = histogram.iter().map(|count| *count as u64).sum::<u64>();
+        if pixel_count == 0 {
+            return None;
+        }
+
+        let low = (pixel_count as f32 * self.desc.low_percentile.clamp(0.0, 1.0)) as u64;
+        let high = (pixel_count as f32 * self.desc.high_percentile.clamp(0.0, 1.0)) as u64;
+        let bin_width =
+            self.desc.log_luminance_range() / (LUMINANCE_HISTOGRAM_BIN_COUNT - 1) as f32;
+
+        let mut counted = 0;
+        let mut log_luminance_sum = 0.0;
+        let mut weight_sum = 0;
+        for (bin, count) in histogram.iter().enumerate() {
+            let bin_start = counted;
+            counted += *count as u64;
+
+            // Part of the bin inside of the percentile range
+            let weight = counted.min(high).saturating_sub(bin_start.max(low));
+            if weight == 0 {
+                continue;
+            }
+
+            let log_luminance = if bin == 0 {
+                self.desc.min_log_luminance
+            } else {
+                self.desc.min_log_luminance + (bin as f32 - 0.5) * bin_width
+            };
+            log_luminance_sum += log_luminance * weight as f32;
+            weight_sum += weight;
+        }
+
+        if weight_sum == 0 {
+            return None;
+        }
+        Some((log_luminance_sum / weight_sum as f32).exp2())
+    }
+
+    /// Records the histogram of the HDR scene color, which has to be in the `SHADER_RESOURCE` state
+    pub fn record(&mut self, command_buffer: &CommandBuffer, frame_index: usize) -> Result<()> {
+        let histogram_buffer = &self.histogram_buffers[frame_index];
+
+        command_buffer.fill_buffer(histogram_buffer, 0, vk::WHOLE_SIZE, 0)?;
+        command_buffer.pipeline_barrier(Barriers::new().add_buffer(
+            histogram_buffer,
+            ResourceState::COPY_DESTINATION,
+            ResourceState::SHADER_ACCESS,
+        ));
+
+        let width = self.hdr_image.width();
+        let height = self.hdr_image.height();
+
+        command_buffer.bind_compute_pipeline(&self.pipeline);
+        command_buffer.bind_compute_descriptor_set(
+            &self.descriptor_sets[frame_index],
+            self.pipeline.raw_layout(),
+            0,
+        );
+        command_buffer.push_constants(
+            self.pipeline.raw_layout(),
+            vk::ShaderStageFlags::COMPUTE,
+            &GpuLuminanceHistogramConstants {
+                min_log_luminance: self.desc.min_log_luminance,
+                inverse_log_luminance_range: 1.0 / self.desc.log_luminance_range(),
+                width,
+                height,
+            },
+        );
+        command_buffer.dispatch(
+            (width + LUMINANCE_HISTOGRAM_WORKGROUP_SIZE - 1) / LUMINANCE_HISTOGRAM_WORKGROUP_SIZE,
+            (height + LUMINANCE_HISTOGRAM_WORKGROUP_SIZE - 1) / LUMINANCE_HISTOGRAM_WORKGROUP_SIZE,
+            1,
+        );
+
+        self.histogram_recorded[frame_index] = true;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,268 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use parking_lot::RwLock;
+
+use rikka_core::vk;
+use rikka_gpu::{buffer::Buffer, command_buffer::CommandBuffer, descriptor_set::*};
+use rikka_graph::{graph::Graph, types::*};
+
+use crate::renderer::*;
+
+/// Technique blurring the scene color with a scatter-as-gather disk kernel, every tap is weighted by its own circle
+/// of confusion so out of focus foreground bleeds over sharp background. Descriptor set 0 bindings: "scene_constants"
+/// uniform buffer, "scene_color" and "scene_depth" images. `GpuDepthOfFieldConstants` are pushed to the fragment stage.
+pub const DEPTH_OF_FIELD_TECHNIQUE_FILE_PATH: &str = "data/depth_of_field.json";
+
+/// Render graph node the pass is registered to. Inputs: 0 - HDR scene color, 1 - scene depth.
+/// Output 0 replaces the scene color as the input of the tonemapping.
+pub const DEPTH_OF_FIELD_PASS_NAME: &str = "depth_of_field_pass";
+
+/// Thin lens camera parameters, distances are in world units
+#[derive(Clone, Copy, Debug)]
+pub struct DepthOfFieldSettings {
+    /// Distance to the plane in focus
+    pub focus_distance: f32,
+    pub focal_length: f32,
+    /// Ratio of the focal length to the aperture diameter
+    pub f_number: f32,
+    /// Height of the sensor the image is projected on, 35mm film by default
+    pub sensor_height: f32,
+    /// Blur radius in pixels is clamped to this
+    pub max_coc_radius: f32,
+    /// Taps of the gather kernel
+    pub sample_count: u32,
+}
+
+impl DepthOfFieldSettings {
+    pub fn new() -> Self {
+        Self {
+            focus_distance: 5.0,
+            focal_length: 0.05,
+            f_number: 2.8,
+            sensor_height: 0.024,
+            max_coc_radius: 16.0,
+            sample_count: 48,
+        }
+    }
+
+    pub fn set_focus_distance(mut self, focus_distance: f32) -> Self {
+        self.focus_distance = focus_distance;
+        self
+    }
+
+    pub fn set_focal_length(mut self, focal_length: f32) -> Self {
+        self.focal_length = focal_length;
+        self
+    }
+
+    pub fn set_f_number(mut self, f_number: f32) -> Self {
+        self.f_number = f_number;
+        self
+    }
+
+    pub fn set_sensor_height(mut self, sensor_height: f32) -> Self {
+        self.sensor_height = sensor_height;
+        self
+    }
+
+    pub fn set_max_coc_radius(mut self, max_coc_radius: f32) -> Self {
+        self.max_coc_radius = max_coc_radius;
+        self
+    }
+
+    pub fn set_sample_count(mut self, sample_count: u32) -> Self {
+        self.sample_count = sample_count;
+        self
+    }
+
+    /// Circle of confusion radius in pixels of a point at `distance` is `coc_scale * |distance - focus| / distance`
+    fn coc_scale(&self, image_height: u32) -> f32 {
+        let focus_distance = self.focus_distance.max(self.focal_length + f32::EPSILON);
+        let aperture_diameter = self.focal_length / self.f_number.max(f32::EPSILON);
+        let coc_diameter =
+            aperture_diameter * self.focal_length / (focus_distance - self.focal_length);
+
+        0.5 * coc_diameter / self.sensor_height.max(f32::EPSILON) * image_height as f32
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GpuDepthOfFieldConstants {
+    focus_distance: f32,
+    coc_scale: f32,
+    max_coc_radius: f32,
+    sample_count: u32,
+}
+
+impl GpuDepthOfFieldConstants {
+    fn new(settings: &DepthOfFieldSettings, image_height: u32) -> Self {
+        Self {
+            focus_distance: settings.focus_distance,
+            coc_scale: settings.coc_scale(image_height),
+            max_coc_radius: settings.max_coc_radius,
+            sample_count: settings.sample_count.max(1),
+        }
+    }
+}
+
+/// Thin lens depth of field applied to the HDR scene color before tonemapping
+pub struct DepthOfFieldPass {
+    technique: Arc<RenderTechnique>,
+    scene_uniform_buffer: Handle<Buffer>,
+    settings: DepthOfFieldSettings,
+    image_height: u32,
+
+    /// Shared with the render pass registered to the graph
+    descriptor_set: Arc<RwLock<Arc<DescriptorSet>>>,
+    constants: Arc<RwLock<GpuDepthOfFieldConstants>>,
+}
+
+impl DepthOfFieldPass {
+    pub fn new(
+        renderer: &Renderer,
+        render_graph: &Graph,
+        settings: DepthOfFieldSettings,
+        scene_uniform_buffer: Handle<Buffer>,
+    ) -> Result<Self> {
+        let technique = renderer
+            .create_technique_from_file(DEPTH_OF_FIELD_TECHNIQUE_FILE_PATH, render_graph)
+            .context("Failed to load depth of field technique")?;
+
+        let (descriptor_set, image_height) = Self::create_descriptor_set(
+            renderer,
+            render_graph,
+            &technique,
+            scene_uniform_buffer.clone(),
+        )?;
+
+        Ok(Self {
+            technique,
+            scene_uniform_buffer,
+            settings,
+            image_height,
+            descriptor_set: Arc::new(RwLock::new(descriptor_set)),
+            constants: Arc::new(RwLock::new(GpuDepthOfFieldConstants::new(
+                &settings,
+                image_height,
+            ))),
+        })
+    }
+
+    /// Returns the descriptor set with the current node inputs and the height of the scene color
+    fn create_descriptor_set(
+        renderer: &Renderer,
+        render_graph: &Graph,
+        technique: &RenderTechnique,
+        scene_uniform_buffer: Handle<Buffer>,
+    ) -> Result<(Arc<DescriptorSet>, u32)> {
+        let node = render_graph
+            .access_node_by_name(DEPTH_OF_FIELD_PASS_NAME)
+            .context("Render graph does not contain a depth of field pass")?;
+        let (color_input, depth_input) = match node.inputs[..] {
+            [color_input, depth_input, ..] => (color_input, depth_input),
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Render graph pass {} needs the scene color and depth as inputs",
+                    DEPTH_OF_FIELD_PASS_NAME
+                ))
+            }
+        };
+        let color_image = render_graph
+            .access_resource_by_handle(color_input)?
+            .gpu_image()?;
+        let depth_image = render_graph
+            .access_resource_by_handle(depth_input)?
+            .gpu_image()?;
+
+        for image in [&color_image, &depth_image] {
+            if !image.has_linked_sampler() {
+                image.set_linked_sampler(renderer.gpu().default_sampler().clone());
+            }
+        }
+
+        let descriptor_set = renderer.create_descriptor_set(
+            DescriptorSetDesc::new(
+                technique.pass(0).graphics_pipeline.descriptor_set_layouts()[0].clone(),
+            )
+            .add_buffer_resource_named("scene_constants", scene_uniform_buffer)
+            .add_image_resource_named("scene_color", color_image.clone())
+            .add_image_resource_named("scene_depth", depth_image),
+        )?;
+
+        Ok((descriptor_set, color_image.height()))
+    }
+
+    /// Needs to be called after the graph attachments were recreated
+    pub fn update_graph_resources(
+        &mut self,
+        renderer: &Renderer,
+        render_graph: &Graph,
+    ) -> Result<()> {
+        let (descriptor_set, image_height) = Self::create_descriptor_set(
+            renderer,
+            render_graph,
+            &self.technique,
+            self.scene_uniform_buffer.clone(),
+        )?;
+
+        *self.descriptor_set.write() = descriptor_set;
+        self.image_height = image_height;
+        *self.constants.write() = GpuDepthOfFieldConstants::new(&self.settings, image_height);
+
+        Ok(())
+    }
+
+    pub fn settings(&self) -> &DepthOfFieldSettings {
+        &self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: DepthOfFieldSettings) {
+        self.settings = settings;
+        *self.constants.write() = GpuDepthOfFieldConstants::new(&settings, self.image_height);
+    }
+
+    pub fn create_render_pass(&self) -> Box<dyn RenderPass> {
+        Box::new(DepthOfFieldRenderPass {
+            technique: self.technique.clone(),
+            descriptor_set: self.descriptor_set.clone(),
+            constants: self.constants.clone(),
+        })
+    }
+}
+
+struct DepthOfFieldRenderPass {
+    technique: Arc<RenderTechnique>,
+    descriptor_set: Arc<RwLock<Arc<DescriptorSet>>>,
+    constants: Arc<RwLock<GpuDepthOfFieldConstants>>,
+}
+
+impl RenderPass for DepthOfFieldRenderPass {
+    fn render(&self, command_buffer: &CommandBuffer) -> Result<()> {
+        let graphics_pipeline = self.technique.pass(0).graphics_pipeline;
+
+        command_buffer.bind_graphics_pipeline(&graphics_pipeline);
+        command_buffer.bind_descriptor_set(
+            &self.descriptor_set.read(),
+            graphics_pipeline.raw_layout(),
+            0,
+        );
+        command_buffer.push_constants(
+            graphics_pipeline.raw_layout(),
+            vk::ShaderStageFlags::FRAGMENT,
+            &*self.constants.read(),
+        );
+        command_buffer.draw(3, 1, 0, 0);
+
+        Ok(())
+    }
+
+    fn post_render(&self, _command_buffer: &CommandBuffer, _graph: &Graph) -> Result<()> {
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "Depth of field render pass"
+    }
+}
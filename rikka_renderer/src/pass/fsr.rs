@@ -0,0 +1,260 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use parking_lot::RwLock;
+
+use rikka_core::vk;
+use rikka_gpu::{command_buffer::CommandBuffer, descriptor_set::*, image::Image};
+use rikka_graph::{graph::Graph, types::*};
+
+use crate::renderer::*;
+
+/// Technique with the two FidelityFX Super Resolution 1 passes, both drawn with a fullscreen triangle.
+/// Pass 0 (EASU) upscales, pass 1 (RCAS) sharpens. Descriptor set 0 binding of both: "input_image".
+/// `GpuEasuConstants` and `GpuRcasConstants` are pushed to the fragment stage.
+pub const FSR_TECHNIQUE_FILE_PATH: &str = "data/fsr.json";
+
+/// Render graph node upscaling its reduced resolution input 0 to the full resolution output 0
+pub const FSR_EASU_PASS_NAME: &str = "fsr_easu_pass";
+/// Render graph node sharpening the upscaled input 0 into output 0, which replaces the scene color as the input of
+/// the tonemapping
+pub const FSR_RCAS_PASS_NAME: &str = "fsr_rcas_pass";
+
+/// Sharpening reduction in stops, 0 is the sharpest
+pub const DEFAULT_FSR_SHARPNESS: f32 = 0.2;
+
+/// Upscaling kernel constants, `FsrEasuCon` of the FSR 1 reference
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GpuEasuConstants {
+    con0: [f32; 4],
+    con1: [f32; 4],
+    con2: [f32; 4],
+    con3: [f32; 4],
+}
+
+impl GpuEasuConstants {
+    /// The whole input image is upscaled to the whole output image
+    fn new(input: vk::Extent2D, output: vk::Extent2D) -> Self {
+        let input_width = input.width as f32;
+        let input_height = input.height as f32;
+        let output_width = output.width as f32;
+        let output_height = output.height as f32;
+
+        Self {
+            con0: [
+                input_width / output_width,
+                input_height / output_height,
+                0.5 * input_width / output_width - 0.5,
+                0.5 * input_height / output_height - 0.5,
+            ],
+            con1: [
+                1.0 / input_width,
+                1.0 / input_height,
+                1.0 / input_width,
+                -1.0 / input_height,
+            ],
+            con2: [
+                -1.0 / input_width,
+                2.0 / input_height,
+                1.0 / input_width,
+                2.0 / input_height,
+            ],
+            con3: [0.0, 4.0 / input_height, 0.0, 0.0],
+        }
+    }
+}
+
+/// Sharpening constants, `FsrRcasCon` of the FSR 1 reference
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GpuRcasConstants {
+    sharpness: f32,
+}
+
+impl GpuRcasConstants {
+    fn new(sharpness: f32) -> Self {
+        Self {
+            sharpness: (-sharpness.max(0.0)).exp2(),
+        }
+    }
+}
+
+/// Shared with the render passes registered to the graph
+struct FsrState {
+    easu_descriptor_set: Arc<DescriptorSet>,
+    rcas_descriptor_set: Arc<DescriptorSet>,
+    easu_constants: GpuEasuConstants,
+    rcas_constants: GpuRcasConstants,
+}
+
+/// Spatial FidelityFX Super Resolution 1 upscaling of reduced resolution graph attachments.
+// XXX: EASU expects perceptual input, upscaling the HDR scene color can ring around very bright pixels
+pub struct FsrUpscalePass {
+    technique: Arc<RenderTechnique>,
+    sharpness: f32,
+    state: Arc<RwLock<FsrState>>,
+}
+
+impl FsrUpscalePass {
+    pub fn new(renderer: &Renderer, render_graph: &Graph, sharpness: f32) -> Result<Self> {
+        let technique = renderer
+            .create_technique_from_file(FSR_TECHNIQUE_FILE_PATH, render_graph)
+            .context("Failed to load FSR technique")?;
+
+        let state = Self::create_state(renderer, render_graph, &technique, sharpness)?;
+
+        Ok(Self {
+            technique,
+            sharpness,
+            state: Arc::new(RwLock::new(state)),
+        })
+    }
+
+    /// Input and output image of a node
+    fn node_images(render_graph: &Graph, name: &str) -> Result<(Handle<Image>, Handle<Image>)> {
+        let node = render_graph
+            .access_node_by_name(name)
+            .with_context(|| format!("Render graph does not contain pass {}", name))?;
+        let input = render_graph
+            .access_resource_by_handle(node.inputs[0])?
+            .gpu_image()?;
+        let output = render_graph
+            .access_resource_by_handle(node.outputs[0])?
+            .gpu_image()?;
+
+        Ok((input, output))
+    }
+
+    fn create_state(
+        renderer: &Renderer,
+        render_graph: &Graph,
+        technique: &RenderTechnique,
+        sharpness: f32,
+    ) -> Result<FsrState> {
+        let (easu_input, easu_output) = Self::node_images(render_graph, FSR_EASU_PASS_NAME)?;
+        let (rcas_input, _) = Self::node_images(render_graph, FSR_RCAS_PASS_NAME)?;
+
+        let create_descriptor_set = |pass_index: usize, input: Handle<Image>| {
+            if !input.has_linked_sampler() {
+                input.set_linked_sampler(renderer.gpu().default_sampler().clone());
+            }
+            renderer.create_descriptor_set(
+                DescriptorSetDesc::new(
+                    technique
+                        .pass(pass_index)
+                        .graphics_pipeline
+                        .descriptor_set_layouts()[0]
+                        .clone(),
+                )
+                .add_image_resource_named("input_image", input),
+            )
+        };
+
+        let extent = |image: &Image| vk::Extent2D {
+            width: image.width(),
+            height: image.height(),
+        };
+        let easu_constants = GpuEasuConstants::new(extent(&easu_input), extent(&easu_output));
+
+        Ok(FsrState {
+            easu_descriptor_set: create_descriptor_set(0, easu_input)?,
+            rcas_descriptor_set: create_descriptor_set(1, rcas_input)?,
+            easu_constants,
+            rcas_constants: GpuRcasConstants::new(sharpness),
+        })
+    }
+
+    /// Needs to be called after the graph attachments were recreated, e.g. when the resolution scale changed
+    pub fn update_graph_resources(
+        &mut self,
+        renderer: &Renderer,
+        render_graph: &Graph,
+    ) -> Result<()> {
+        *self.state.write() =
+            Self::create_state(renderer, render_graph, &self.technique, self.sharpness)?;
+        Ok(())
+    }
+
+    pub fn sharpness(&self) -> f32 {
+        self.sharpness
+    }
+
+    pub fn set_sharpness(&mut self, sharpness: f32) {
+        self.sharpness = sharpness;
+        self.state.write().rcas_constants = GpuRcasConstants::new(sharpness);
+    }
+
+    pub fn create_easu_render_pass(&self) -> Box<dyn RenderPass> {
+        Box::new(FsrRenderPass {
+            technique: self.technique.clone(),
+            state: self.state.clone(),
+            sharpen: false,
+        })
+    }
+
+    pub fn create_rcas_render_pass(&self) -> Box<dyn RenderPass> {
+        Box::new(FsrRenderPass {
+            technique: self.technique.clone(),
+            state: self.state.clone(),
+            sharpen: true,
+        })
+    }
+}
+
+struct FsrRenderPass {
+    technique: Arc<RenderTechnique>,
+    state: Arc<RwLock<FsrState>>,
+    /// RCAS instead of EASU
+    sharpen: bool,
+}
+
+impl RenderPass for FsrRenderPass {
+    fn render(&self, command_buffer: &CommandBuffer) -> Result<()> {
+        let graphics_pipeline = self
+            .technique
+            .pass(if self.sharpen { 1 } else { 0 })
+            .graphics_pipeline;
+        let state = self.state.read();
+
+        command_buffer.bind_graphics_pipeline(&graphics_pipeline);
+        if self.sharpen {
+            command_buffer.bind_descriptor_set(
+                &state.rcas_descriptor_set,
+                graphics_pipeline.raw_layout(),
+                0,
+            );
+            command_buffer.push_constants(
+                graphics_pipeline.raw_layout(),
+                vk::ShaderStageFlags::FRAGMENT,
+                &state.rcas_constants,
+            );
+        } else {
+            command_buffer.bind_descriptor_set(
+                &state.easu_descriptor_set,
+                graphics_pipeline.raw_layout(),
+                0,
+            );
+            command_buffer.push_constants(
+                graphics_pipeline.raw_layout(),
+                vk::ShaderStageFlags::FRAGMENT,
+                &state.easu_constants,
+            );
+        }
+        command_buffer.draw(3, 1, 0, 0);
+
+        Ok(())
+    }
+
+    fn post_render(&self, _command_buffer: &CommandBuffer, _graph: &Graph) -> Result<()> {
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        if self.sharpen {
+            "FSR RCAS render pass"
+        } else {
+            "FSR EASU render pass"
+        }
+    }
+}
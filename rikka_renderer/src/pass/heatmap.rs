@@ -0,0 +1,193 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use rikka_core::vk;
+use rikka_gpu::{
+    barriers::*, command_buffer::CommandBuffer, compute_pipeline::*, descriptor_set::*, image::*,
+    shader_state::*, types::ImageResourceUpdate,
+};
+
+use crate::renderer::*;
+
+/// Maps a per-pixel value to a blue to red heatmap. Descriptor set 0 bindings: 0 - overdraw counts storage image,
+/// 1 - scene color, 2 - heatmap storage image.
+const HEATMAP_SHADER_FILE_PATH: &str = "shaders/debug_heatmap.comp";
+
+const HEATMAP_WORKGROUP_SIZE: u32 = 16;
+
+/// Overdraw count shown as the hottest color
+pub const DEFAULT_OVERDRAW_HEATMAP_MAX: f32 = 8.0;
+/// Log2 luminance range shown by the luminance heatmap, centered on middle grey
+pub const DEFAULT_LUMINANCE_HEATMAP_STOPS: f32 = 8.0;
+
+/// Value a heatmap is computed from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum HeatmapSource {
+    /// Fragments shaded per pixel, counted by the scene fragment shaders
+    Overdraw = 0,
+    /// Luminance of the HDR scene color
+    Luminance = 1,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GpuHeatmapConstants {
+    source: u32,
+    /// Value mapped to the hottest color, stops above and below middle grey for the luminance
+    max_value: f32,
+    width: u32,
+    height: u32,
+}
+
+/// Debug heatmaps of fill rate and exposure. Scene fragment shaders atomically increment the overdraw count of their
+/// pixel through the bindless storage image array, a compute pass converts the counts or the scene color luminance
+/// into a sampled heatmap shown by the debug view.
+pub struct HeatmapPass {
+    pipeline: Handle<ComputePipeline>,
+    descriptor_set: Arc<DescriptorSet>,
+
+    scene_color: Handle<Image>,
+    overdraw_counts: Handle<Image>,
+    heatmap: Handle<Image>,
+}
+
+impl HeatmapPass {
+    /// Heatmaps have the resolution of `scene_color`
+    pub fn new(renderer: &mut Renderer, scene_color: Handle<Image>) -> Result<Self> {
+        let width = scene_color.width();
+        let height = scene_color.height();
+
+        let overdraw_counts = renderer.create_storage_image(
+            ImageDesc::new(width, height, 1)
+                .set_format(vk::Format::R32_UINT)
+                .set_usage_flags(vk::ImageUsageFlags::TRANSFER_DST),
+        )?;
+
+        let heatmap = renderer.create_image(
+            ImageDesc::new(width, height, 1)
+                .set_format(vk::Format::R8G8B8A8_UNORM)
+                .set_usage_flags(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED),
+        )?;
+        renderer.gpu().transition_image_layout(
+            &heatmap,
+            ResourceState::UNDEFINED,
+            ResourceState::SHADER_RESOURCE,
+        )?;
+        renderer
+            .gpu_mut()
+            .add_bindless_image_update(ImageResourceUpdate {
+                frame: 0,
+                image: Some(heatmap.clone()),
+                sampler: None,
+            });
+
+        if !scene_color.has_linked_sampler() {
+            scene_color.set_linked_sampler(renderer.gpu().default_sampler().clone());
+        }
+
+        let pipeline = renderer.gpu().create_compute_pipeline(
+            ComputePipelineDesc::new()
+                .set_compute_stage(ShaderStageDesc::new_from_source_file(
+                    HEATMAP_SHADER_FILE_PATH,
+                    ShaderStageType::Compute,
+                ))
+                .set_push_constants::<GpuHeatmapConstants>(),
+        )?;
+        let descriptor_set = renderer.create_descriptor_set(
+            DescriptorSetDesc::new(pipeline.descriptor_set_layouts()[0].clone())
+                .add_image_resource(overdraw_counts.clone(), 0)
+                .add_image_resource(scene_color.clone(), 1)
+                .add_image_resource(heatmap.clone(), 2),
+        )?;
+
+        Ok(Self {
+            pipeline,
+            descriptor_set,
+            scene_color,
+            overdraw_counts,
+            heatmap,
+        })
+    }
+
+    pub fn scene_color(&self) -> &Handle<Image> {
+        &self.scene_color
+    }
+
+    /// Sampled heatmap, registered in the bindless array
+    pub fn heatmap_image(&self) -> &Handle<Image> {
+        &self.heatmap
+    }
+
+    /// Bindless storage index the scene fragment shaders increment the overdraw counts through
+    pub fn overdraw_storage_index(&self) -> u32 {
+        self.overdraw_counts.storage_bindless_index()
+    }
+
+    /// Resets the overdraw counts, needs to be recorded before the scene is drawn
+    pub fn clear_overdraw(&self, command_buffer: &CommandBuffer) -> Result<()> {
+        command_buffer.pipeline_barrier(Barriers::new().add_image(
+            &self.overdraw_counts,
+            ResourceState::SHADER_ACCESS,
+            ResourceState::COPY_DESTINATION,
+        ));
+        // Zero has the same bits as a float and an unsigned integer
+        command_buffer.clear_color_image(&self.overdraw_counts, [0.0; 4])?;
+        command_buffer.pipeline_barrier(Barriers::new().add_image(
+            &self.overdraw_counts,
+            ResourceState::COPY_DESTINATION,
+            ResourceState::SHADER_ACCESS,
+        ));
+
+        Ok(())
+    }
+
+    /// Computes the heatmap, the scene color has to be in the `SHADER_RESOURCE` state
+    pub fn record(&self, command_buffer: &CommandBuffer, source: HeatmapSource, max_value: f32) {
+        command_buffer.pipeline_barrier(
+            Barriers::new()
+                .add_compute_image(
+                    &self.overdraw_counts,
+                    ResourceState::SHADER_ACCESS,
+                    ResourceState::SHADER_ACCESS,
+                )
+                .add_compute_image(
+                    &self.heatmap,
+                    ResourceState::SHADER_RESOURCE,
+                    ResourceState::SHADER_ACCESS,
+                ),
+        );
+
+        let width = self.heatmap.width();
+        let height = self.heatmap.height();
+
+        command_buffer.bind_compute_pipeline(&self.pipeline);
+        command_buffer.bind_compute_descriptor_set(
+            &self.descriptor_set,
+            self.pipeline.raw_layout(),
+            0,
+        );
+        command_buffer.push_constants(
+            self.pipeline.raw_layout(),
+            vk::ShaderStageFlags::COMPUTE,
+            &GpuHeatmapConstants {
+                source: source as u32,
+                max_value,
+                width,
+                height,
+            },
+        );
+        command_buffer.dispatch(
+            (width + HEATMAP_WORKGROUP_SIZE - 1) / HEATMAP_WORKGROUP_SIZE,
+            (height + HEATMAP_WORKGROUP_SIZE - 1) / HEATMAP_WORKGROUP_SIZE,
+            1,
+        );
+
+        command_buffer.pipeline_barrier(Barriers::new().add_compute_image(
+            &self.heatmap,
+            ResourceState::SHADER_ACCESS,
+            ResourceState::SHADER_RESOURCE,
+        ));
+    }
+}
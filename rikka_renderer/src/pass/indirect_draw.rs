@@ -0,0 +1,403 @@
+use std::{mem::size_of, sync::Arc};
+
+use anyhow::{Context, Result};
+use parking_lot::RwLock;
+
+use rikka_core::{nalgebra::Vector4, vk};
+use rikka_gpu::{
+    barriers::*, buffer::*, command_buffer::CommandBuffer, compute_pipeline::*,
+    constants::MAX_FRAMES, descriptor_set::*, shader_state::*,
+};
+use rikka_graph::{graph::Graph, types::*};
+
+use crate::{
+    camera::Camera,
+    renderer::*,
+    scene_renderer::{
+        gpu_types::*, material_storage::MaterialStorage, mesh::Mesh, meshlet::*,
+        picking::object_id_from_node_index,
+    },
+    schedule::ComputeQueue,
+};
+
+/// Culls the mesh instances against the view frustum and appends a `GpuMeshDrawCommand` with both the indexed and
+/// the mesh tasks command of every visible instance, counted by `opaque_mesh_visible_count`. The commands draw the
+/// cull mesh of the LOD level selected for the instance.
+/// Descriptor set 0 bindings: 0 - mesh instances, 1 - cull meshes, 2 - draw commands, 3 - draw counts.
+const MESH_CULLING_SHADER_FILE_PATH: &str = "shaders/mesh_culling.comp";
+
+const MESH_CULLING_WORKGROUP_SIZE: u32 = 64;
+
+/// Technique drawing the culled instances with a single multi-draw, vertices are pulled from the meshlet vertex storage
+/// buffers and the instance is looked up through the draw command at `gl_DrawID`. Descriptor set 0 bindings:
+/// "scene_constants" uniform buffer, "materials", "mesh_instances", "draw_commands", "vertex_positions" and
+/// "vertex_data" storage buffers.
+pub const INDIRECT_DRAW_TECHNIQUE_FILE_PATH: &str = "data/indirect_draw.json";
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GpuMeshCullingConstants {
+    frustum_planes: [Vector4<f32>; 6],
+    instance_count: u32,
+
+    _pad0: [u32; 3],
+}
+
+/// Draws the opaque scene meshes without mesh shaders. A compute pass culls the mesh instances and writes standard
+/// indexed indirect commands, the geometry pass issues one `vkCmdDrawIndexedIndirectCount` over the scene meshlet
+/// index buffer. Selected by default on Gpus without mesh shaders, see `SceneRenderer::set_indirect_draw`.
+// XXX: The mesh tasks commands written by the culling pass are not drawn, scenes have no mesh shader path yet
+pub struct IndirectDrawPass {
+    technique: Arc<RenderTechnique>,
+    /// Meshes drawn by this pass, indexed by the draw id
+    meshes: Vec<Arc<Mesh>>,
+    /// Cull mesh of the full detail level of every mesh
+    cull_mesh_offsets: Vec<u32>,
+
+    culling_pipeline: Handle<ComputePipeline>,
+    culling_descriptor_sets: Vec<Arc<DescriptorSet>>,
+
+    mesh_instance_buffers: [Handle<Buffer>; MAX_FRAMES as usize],
+    draw_command_buffers: [Handle<Buffer>; MAX_FRAMES as usize],
+    draw_count_buffers: [Handle<Buffer>; MAX_FRAMES as usize],
+    index_buffer: Handle<Buffer>,
+
+    descriptor_sets: Vec<Arc<DescriptorSet>>,
+    bindless_descriptor_set: Arc<DescriptorSet>,
+
+    /// Shared with the render pass registered to the graph
+    frame_index: Arc<RwLock<usize>>,
+}
+
+impl IndirectDrawPass {
+    /// Fails if the Gpu does not support `drawIndirectCount`
+    pub fn new(
+        renderer: &Renderer,
+        render_graph: &Graph,
+        meshes: &[Arc<Mesh>],
+        meshlet_storage_buffers: &MeshletStorageBuffers,
+        material_storage: &MaterialStorage,
+        scene_uniform_buffer: Handle<Buffer>,
+    ) -> Result<Self> {
+        if !renderer.gpu().device_features().draw_indirect_count {
+            return Err(anyhow::anyhow!(
+                "Indirect draws require the drawIndirectCount device feature"
+            ));
+        }
+
+        let technique = renderer
+            .create_technique_from_file(INDIRECT_DRAW_TECHNIQUE_FILE_PATH, render_graph)
+            .context("Failed to load indirect draw technique")?;
+
+        // XXX: Transparent meshes need sorting and other topologies need their own pipelines, both are not drawn yet
+        let meshes = meshes
+            .iter()
+            .filter(|mesh| {
+                !mesh.transparent()
+                    && mesh.topology == vk::PrimitiveTopology::TRIANGLE_LIST
+                    && mesh.meshlet_index_count > 0
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // One cull mesh per LOD level, the levels of a mesh follow its first cull mesh
+        let mut cull_mesh_offsets = Vec::with_capacity(meshes.len());
+        let mut cull_meshes = Vec::new();
+        for mesh in &meshes {
+            cull_mesh_offsets.push(cull_meshes.len() as u32);
+            cull_meshes.extend((0..mesh.lod_count()).map(|lod| mesh.gpu_cull_mesh(lod)));
+        }
+        let cull_mesh_buffer = renderer.create_buffer(
+            BufferDesc::new()
+                .set_size((cull_meshes.len().max(1) * size_of::<GpuCullMesh>()) as _)
+                .set_usage_flags(vk::BufferUsageFlags::STORAGE_BUFFER)
+                .set_device_only(false)
+                .set_async_compute(true),
+        )?;
+        cull_mesh_buffer.copy_data_to_buffer(&cull_meshes)?;
+
+        // Culling can run on the async compute queue
+        let create_buffer = |size: usize, usage_flags: vk::BufferUsageFlags, device_only: bool| {
+            renderer.create_buffer(
+                BufferDesc::new()
+                    .set_size((meshes.len().max(1) * size) as _)
+                    .set_usage_flags(vk::BufferUsageFlags::STORAGE_BUFFER | usage_flags)
+                    .set_device_only(device_only)
+                    .set_async_compute(true),
+            )
+        };
+        let create_mesh_instance_buffer = || {
+            create_buffer(
+                size_of::<GpuMeshInstanceData>(),
+                vk::BufferUsageFlags::empty(),
+                false,
+            )
+        };
+        let create_draw_command_buffer = || {
+            create_buffer(
+                size_of::<GpuMeshDrawCommand>(),
+                vk::BufferUsageFlags::INDIRECT_BUFFER,
+                true,
+            )
+        };
+        let create_draw_count_buffer = || {
+            renderer.create_buffer(
+                BufferDesc::new()
+                    .set_size(size_of::<GpuMeshDrawCounts>() as _)
+                    .set_usage_flags(
+                        vk::BufferUsageFlags::STORAGE_BUFFER
+                            | vk::BufferUsageFlags::INDIRECT_BUFFER
+                            | vk::BufferUsageFlags::TRANSFER_DST,
+                    )
+                    .set_device_only(true)
+                    .set_async_compute(true),
+            )
+        };
+        let mesh_instance_buffers = [
+            create_mesh_instance_buffer()?,
+            create_mesh_instance_buffer()?,
+        ];
+        let draw_command_buffers = [create_draw_command_buffer()?, create_draw_command_buffer()?];
+        let draw_count_buffers = [create_draw_count_buffer()?, create_draw_count_buffer()?];
+
+        let culling_pipeline = renderer.gpu().create_compute_pipeline(
+            ComputePipelineDesc::new()
+                .set_compute_stage(ShaderStageDesc::new_from_source_file(
+                    MESH_CULLING_SHADER_FILE_PATH,
+                    ShaderStageType::Compute,
+                ))
+                .set_push_constants::<GpuMeshCullingConstants>(),
+        )?;
+
+        let mut culling_descriptor_sets = Vec::new();
+        let mut descriptor_sets = Vec::new();
+        for frame_index in 0..MAX_FRAMES as usize {
+            culling_descriptor_sets.push(
+                renderer.create_descriptor_set(
+                    DescriptorSetDesc::new(culling_pipeline.descriptor_set_layouts()[0].clone())
+                        .add_buffer_resource(mesh_instance_buffers[frame_index].clone(), 0)
+                        .add_buffer_resource(cull_mesh_buffer.clone(), 1)
+                        .add_buffer_resource(draw_command_buffers[frame_index].clone(), 2)
+                        .add_buffer_resource(draw_count_buffers[frame_index].clone(), 3),
+                )?,
+            );
+
+            descriptor_sets.push(
+                renderer.create_descriptor_set(
+                    DescriptorSetDesc::new(
+                        technique.pass(0).graphics_pipeline.descriptor_set_layouts()[0].clone(),
+                    )
+                    .add_buffer_resource_named("scene_constants", scene_uniform_buffer.clone())
+                    .add_buffer_resource_named("materials", material_storage.buffer().clone())
+                    .add_buffer_resource_named(
+                        "mesh_instances",
+                        mesh_instance_buffers[frame_index].clone(),
+                    )
+                    .add_buffer_resource_named(
+                        "draw_commands",
+                        draw_command_buffers[frame_index].clone(),
+                    )
+                    .add_buffer_resource_named(
+                        "vertex_positions",
+                        meshlet_storage_buffers.vertex_positions.clone(),
+                    )
+                    .add_buffer_resource_named(
+                        "vertex_data",
+                        meshlet_storage_buffers.vertex_data.clone(),
+                    ),
+                )?,
+            );
+        }
+
+        Ok(Self {
+            technique,
+            meshes,
+            cull_mesh_offsets,
+            culling_pipeline,
+            culling_descriptor_sets,
+            mesh_instance_buffers,
+            draw_command_buffers,
+            draw_count_buffers,
+            index_buffer: meshlet_storage_buffers.indices.clone(),
+            descriptor_sets,
+            bindless_descriptor_set: renderer.gpu().bindless_descriptor_set().clone(),
+            frame_index: Arc::new(RwLock::new(0)),
+        })
+    }
+
+    /// Uploads the mesh instances of the frame and records the culling, needs to be recorded before the render graph.
+    /// The draw commands are in the `INDIRECT_ARGUMENT` state afterwards, async compute work is synchronized with the
+    /// graphics queue by semaphores instead of barriers.
+    pub fn record_culling(
+        &self,
+        command_buffer: &CommandBuffer,
+        frame_index: usize,
+        camera: &Camera,
+        queue: ComputeQueue,
+    ) -> Result<()> {
+        *self.frame_index.write() = frame_index;
+
+        // Material indices change when material instances are overridden and LOD levels are selected every frame,
+        // hidden meshes are left out of the culling
+        let mesh_instances = self
+            .meshes
+            .iter()
+            .zip(&self.cull_mesh_offsets)
+            .enumerate()
+            .filter(|(_, (mesh, _))| mesh.visible())
+            .map(|(mesh_index, (mesh, cull_mesh_offset))| {
+                GpuMeshInstanceData::new(
+                    mesh.global_model(),
+                    mesh_index as u32,
+                    mesh.pbr_material.material_index(),
+                    object_id_from_node_index(mesh.scene_graph_node_index),
+                    cull_mesh_offset + mesh.selected_lod() as u32,
+                )
+            })
+            .collect::<Vec<_>>();
+        self.mesh_instance_buffers[frame_index].copy_data_to_buffer(&mesh_instances)?;
+
+        let draw_commands = &self.draw_command_buffers[frame_index];
+        let draw_counts = &self.draw_count_buffers[frame_index];
+
+        let async_compute = queue == ComputeQueue::AsyncCompute;
+        if async_compute {
+            // Draws of the previous use of the frame completed before the async compute work is submitted
+            command_buffer.fill_buffer(draw_counts, 0, vk::WHOLE_SIZE, 0)?;
+            command_buffer.pipeline_barrier(Barriers::new().add_compute_queue_buffer(
+                draw_counts,
+                ResourceState::COPY_DESTINATION,
+                ResourceState::SHADER_ACCESS,
+            ));
+        } else {
+            command_buffer.pipeline_barrier(Barriers::new().add_buffer(
+                draw_counts,
+                ResourceState::INDIRECT_ARGUMENT,
+                ResourceState::COPY_DESTINATION,
+            ));
+            command_buffer.fill_buffer(draw_counts, 0, vk::WHOLE_SIZE, 0)?;
+            command_buffer.pipeline_barrier(
+                Barriers::new()
+                    .add_buffer(
+                        draw_counts,
+                        ResourceState::COPY_DESTINATION,
+                        ResourceState::SHADER_ACCESS,
+                    )
+                    .add_buffer(
+                        draw_commands,
+                        ResourceState::INDIRECT_ARGUMENT,
+                        ResourceState::SHADER_ACCESS,
+                    ),
+            );
+        }
+
+        let instance_count = mesh_instances.len() as u32;
+
+        command_buffer.bind_compute_pipeline(&self.culling_pipeline);
+        command_buffer.bind_compute_descriptor_set(
+            &self.culling_descriptor_sets[frame_index],
+            self.culling_pipeline.raw_layout(),
+            0,
+        );
+        command_buffer.push_constants(
+            self.culling_pipeline.raw_layout(),
+            vk::ShaderStageFlags::COMPUTE,
+            &GpuMeshCullingConstants {
+                frustum_planes: camera.frustum_planes(),
+                instance_count,
+                _pad0: [0; 3],
+            },
+        );
+        command_buffer.dispatch(
+            (instance_count + MESH_CULLING_WORKGROUP_SIZE - 1) / MESH_CULLING_WORKGROUP_SIZE,
+            1,
+            1,
+        );
+
+        if !async_compute {
+            command_buffer.pipeline_barrier(
+                Barriers::new()
+                    .add_buffer(
+                        draw_counts,
+                        ResourceState::SHADER_ACCESS,
+                        ResourceState::INDIRECT_ARGUMENT,
+                    )
+                    .add_buffer(
+                        draw_commands,
+                        ResourceState::SHADER_ACCESS,
+                        ResourceState::INDIRECT_ARGUMENT,
+                    ),
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn create_render_pass(&self) -> Box<dyn RenderPass> {
+        Box::new(IndirectDrawRenderPass {
+            technique: self.technique.clone(),
+            max_draw_count: self.meshes.len() as u32,
+            draw_command_buffers: self.draw_command_buffers.clone(),
+            draw_count_buffers: self.draw_count_buffers.clone(),
+            index_buffer: self.index_buffer.clone(),
+            descriptor_sets: self.descriptor_sets.clone(),
+            bindless_descriptor_set: self.bindless_descriptor_set.clone(),
+            frame_index: self.frame_index.clone(),
+        })
+    }
+}
+
+struct IndirectDrawRenderPass {
+    technique: Arc<RenderTechnique>,
+    max_draw_count: u32,
+    draw_command_buffers: [Handle<Buffer>; MAX_FRAMES as usize],
+    draw_count_buffers: [Handle<Buffer>; MAX_FRAMES as usize],
+    index_buffer: Handle<Buffer>,
+    descriptor_sets: Vec<Arc<DescriptorSet>>,
+    bindless_descriptor_set: Arc<DescriptorSet>,
+    frame_index: Arc<RwLock<usize>>,
+}
+
+impl RenderPass for IndirectDrawRenderPass {
+    fn render(&self, command_buffer: &CommandBuffer) -> Result<()> {
+        if self.max_draw_count == 0 {
+            return Ok(());
+        }
+
+        let frame_index = *self.frame_index.read();
+        let graphics_pipeline = self.technique.pass(0).graphics_pipeline;
+
+        command_buffer.bind_graphics_pipeline(&graphics_pipeline);
+        command_buffer.bind_descriptor_set(
+            &self.descriptor_sets[frame_index],
+            graphics_pipeline.raw_layout(),
+            0,
+        );
+        command_buffer.bind_descriptor_set(
+            &self.bindless_descriptor_set,
+            graphics_pipeline.raw_layout(),
+            1,
+        );
+        command_buffer.bind_index_buffer(&self.index_buffer, 0, vk::IndexType::UINT32);
+
+        // The indexed command follows the draw id of every `GpuMeshDrawCommand`
+        command_buffer.draw_indexed_indirect_count(
+            &self.draw_command_buffers[frame_index],
+            size_of::<u32>() as u64,
+            &self.draw_count_buffers[frame_index],
+            0,
+            self.max_draw_count,
+            size_of::<GpuMeshDrawCommand>() as u32,
+        );
+
+        Ok(())
+    }
+
+    fn post_render(&self, _command_buffer: &CommandBuffer, _graph: &Graph) -> Result<()> {
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "Indirect draw render pass"
+    }
+}
@@ -1,3 +1,14 @@
+pub mod auto_exposure;
+pub mod depth_of_field;
+pub mod fsr;
 pub mod gbuffer_mesh_shading;
+pub mod heatmap;
+pub mod indirect_draw;
+pub mod overlay;
+pub mod particles;
 pub mod pbr_lighting;
+pub mod ray_traced_shadows;
 pub mod simple_pbr;
+pub mod skinning;
+pub mod sky;
+pub mod terrain;
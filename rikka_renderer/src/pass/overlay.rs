@@ -0,0 +1,331 @@
+use std::{mem::size_of, sync::Arc};
+
+use anyhow::{anyhow, Context, Result};
+
+use rikka_core::vk;
+use rikka_gpu::{
+    buffer::*, command_buffer::CommandBuffer, constants::*, descriptor_set::*, image::*,
+};
+use rikka_graph::graph::Graph;
+
+use crate::renderer::*;
+
+/// Technique drawing the overlay quads on top of the output image with alpha blending, uses the bindless set 0.
+/// Vertex binding 0 contains `GpuOverlayVertex` data.
+pub const OVERLAY_TECHNIQUE_FILE_PATH: &str = "data/overlay.json";
+
+/// Quads drawn without a texture sample white
+const UNTEXTURED: u32 = INVALID_BINDLESS_TEXTURE_INDEX;
+
+/// Vertex buffers are grown in steps of this many quads
+const OVERLAY_QUAD_CAPACITY_STEP: usize = 1024;
+
+bitflags::bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct OverlayQuadFlags : u32 {
+        /// Texture alpha is a signed distance to the glyph edge instead of coverage
+        const SIGNED_DISTANCE_FIELD = 0x1;
+        /// Only the red channel is sampled and used as alpha, e.g. bitmap font atlases
+        const ALPHA_MASK = 0x2;
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GpuOverlayVertex {
+    /// Screen space position in pixels, origin at the top left
+    position: [f32; 2],
+    tex_coords: [f32; 2],
+    color: [f32; 4],
+    texture_index: u32,
+    flags: u32,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GpuOverlayConstants {
+    /// Converts pixel positions to normalized device coordinates
+    inverse_screen_size: [f32; 2],
+}
+
+/// Monospaced font atlas with glyphs laid out in a grid, row by row starting at `first_character`.
+pub struct FontAtlas {
+    image: Handle<Image>,
+    glyph_width: u32,
+    glyph_height: u32,
+    columns: u32,
+    rows: u32,
+    first_character: char,
+    flags: OverlayQuadFlags,
+}
+
+impl FontAtlas {
+    /// Loads a bitmap atlas, glyph coverage is read from the red channel.
+    /// `signed_distance_field` atlases store the distance to the glyph edge instead, remapped to [0, 1].
+    pub fn load_from_file(
+        renderer: &mut Renderer,
+        file_name: &str,
+        glyph_width: u32,
+        glyph_height: u32,
+        first_character: char,
+        signed_distance_field: bool,
+    ) -> Result<Self> {
+        let atlas = image::open(file_name)
+            .with_context(|| format!("Failed to load font atlas {}", file_name))?
+            .into_luma8();
+
+        let columns = atlas.width() / glyph_width.max(1);
+        let rows = atlas.height() / glyph_height.max(1);
+        if columns == 0 || rows == 0 {
+            return Err(anyhow!(
+                "Font atlas {} of {}x{} is smaller than a {}x{} glyph",
+                file_name,
+                atlas.width(),
+                atlas.height(),
+                glyph_width,
+                glyph_height
+            ));
+        }
+
+        let image = renderer.create_image_from_data(
+            ImageDesc::new(atlas.width(), atlas.height(), 1).set_format(vk::Format::R8_UNORM),
+            atlas.as_raw(),
+        )?;
+
+        let flags = if signed_distance_field {
+            OverlayQuadFlags::ALPHA_MASK | OverlayQuadFlags::SIGNED_DISTANCE_FIELD
+        } else {
+            OverlayQuadFlags::ALPHA_MASK
+        };
+
+        Ok(Self {
+            image,
+            glyph_width,
+            glyph_height,
+            columns,
+            rows,
+            first_character,
+            flags,
+        })
+    }
+
+    pub fn glyph_width(&self) -> u32 {
+        self.glyph_width
+    }
+
+    pub fn glyph_height(&self) -> u32 {
+        self.glyph_height
+    }
+
+    /// Normalized atlas rectangle of a character as (min u, min v, max u, max v), None if it is not in the atlas
+    fn glyph_tex_coords(&self, character: char) -> Option<[f32; 4]> {
+        let index = (character as u32).checked_sub(self.first_character as u32)?;
+        if index >= self.columns * self.rows {
+            return None;
+        }
+
+        let column = index % self.columns;
+        let row = index / self.columns;
+        let width = self.image.width() as f32;
+        let height = self.image.height() as f32;
+
+        Some([
+            (column * self.glyph_width) as f32 / width,
+            (row * self.glyph_height) as f32 / height,
+            ((column + 1) * self.glyph_width) as f32 / width,
+            ((row + 1) * self.glyph_height) as f32 / height,
+        ])
+    }
+}
+
+/// Immediate mode 2D overlay in screen space pixel coordinates. Quads added during a frame are batched into a
+/// per-frame vertex buffer and drawn on top of the output image, the quad list is cleared after every frame.
+pub struct Overlay {
+    technique: Arc<RenderTechnique>,
+    vertex_buffers: Vec<Handle<Buffer>>,
+    vertices: Vec<GpuOverlayVertex>,
+    font: Option<FontAtlas>,
+}
+
+impl Overlay {
+    pub fn new(renderer: &Renderer, render_graph: &Graph) -> Result<Self> {
+        let technique = renderer
+            .create_technique_from_file(OVERLAY_TECHNIQUE_FILE_PATH, render_graph)
+            .context("Failed to load overlay technique")?;
+
+        let vertex_buffers = (0..MAX_FRAMES)
+            .map(|_| Self::create_vertex_buffer(renderer, OVERLAY_QUAD_CAPACITY_STEP))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            technique,
+            vertex_buffers,
+            vertices: Vec::new(),
+            font: None,
+        })
+    }
+
+    fn create_vertex_buffer(renderer: &Renderer, quad_capacity: usize) -> Result<Handle<Buffer>> {
+        renderer.create_buffer(
+            BufferDesc::new()
+                .set_size((quad_capacity * 6 * size_of::<GpuOverlayVertex>()) as _)
+                .set_usage_flags(vk::BufferUsageFlags::VERTEX_BUFFER)
+                .set_device_only(false),
+        )
+    }
+
+    pub fn set_font(&mut self, font: FontAtlas) {
+        self.font = Some(font);
+    }
+
+    pub fn font(&self) -> Option<&FontAtlas> {
+        self.font.as_ref()
+    }
+
+    fn add_quad(
+        &mut self,
+        rect: [f32; 4],
+        tex_coords: [f32; 4],
+        color: [f32; 4],
+        texture_index: u32,
+        flags: OverlayQuadFlags,
+    ) {
+        let [x0, y0, x1, y1] = rect;
+        let [u0, v0, u1, v1] = tex_coords;
+        let vertex = |x: f32, y: f32, u: f32, v: f32| GpuOverlayVertex {
+            position: [x, y],
+            tex_coords: [u, v],
+            color,
+            texture_index,
+            flags: flags.bits(),
+        };
+
+        self.vertices.extend_from_slice(&[
+            vertex(x0, y0, u0, v0),
+            vertex(x0, y1, u0, v1),
+            vertex(x1, y0, u1, v0),
+            vertex(x1, y0, u1, v0),
+            vertex(x0, y1, u0, v1),
+            vertex(x1, y1, u1, v1),
+        ]);
+    }
+
+    /// Solid color rectangle with its top left corner at (x, y)
+    pub fn draw_rect(&mut self, x: f32, y: f32, width: f32, height: f32, color: [f32; 4]) {
+        self.add_quad(
+            [x, y, x + width, y + height],
+            [0.0, 0.0, 1.0, 1.0],
+            color,
+            UNTEXTURED,
+            OverlayQuadFlags::empty(),
+        );
+    }
+
+    /// Draws a bindless image tinted by `color`, the image needs to be registered as a bindless texture
+    pub fn draw_image(
+        &mut self,
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+        image: &Image,
+        color: [f32; 4],
+    ) {
+        self.add_quad(
+            [x, y, x + width, y + height],
+            [0.0, 0.0, 1.0, 1.0],
+            color,
+            image.bindless_index(),
+            OverlayQuadFlags::empty(),
+        );
+    }
+
+    /// Draws a single line of text with the glyph cell scaled by `scale`, characters missing from the atlas are skipped.
+    /// Returns the width of the text in pixels.
+    pub fn draw_text(
+        &mut self,
+        x: f32,
+        y: f32,
+        text: &str,
+        scale: f32,
+        color: [f32; 4],
+    ) -> Result<f32> {
+        let font = self
+            .font
+            .as_ref()
+            .context("Overlay text requires a font atlas")?;
+        let glyph_width = font.glyph_width as f32 * scale;
+        let glyph_height = font.glyph_height as f32 * scale;
+        let texture_index = font.image.bindless_index();
+        let flags = font.flags;
+
+        let glyphs = text
+            .chars()
+            .map(|character| font.glyph_tex_coords(character))
+            .collect::<Vec<_>>();
+
+        let mut cursor = x;
+        for tex_coords in glyphs {
+            if let Some(tex_coords) = tex_coords {
+                self.add_quad(
+                    [cursor, y, cursor + glyph_width, y + glyph_height],
+                    tex_coords,
+                    color,
+                    texture_index,
+                    flags,
+                );
+            }
+            cursor += glyph_width;
+        }
+
+        Ok(cursor - x)
+    }
+
+    pub fn quad_count(&self) -> usize {
+        self.vertices.len() / 6
+    }
+
+    /// Draws the quads added since the last call inside the active rendering of the output image and clears them.
+    /// The vertex buffer of `frame_index` must no longer be in use by the Gpu.
+    pub fn render(
+        &mut self,
+        renderer: &Renderer,
+        command_buffer: &CommandBuffer,
+        frame_index: usize,
+        extent: vk::Extent2D,
+    ) -> Result<()> {
+        if self.vertices.is_empty() {
+            return Ok(());
+        }
+
+        let required_size = self.vertices.len() * size_of::<GpuOverlayVertex>();
+        if required_size > self.vertex_buffers[frame_index].size() as usize {
+            let quad_capacity =
+                (self.quad_count() / OVERLAY_QUAD_CAPACITY_STEP + 1) * OVERLAY_QUAD_CAPACITY_STEP;
+            self.vertex_buffers[frame_index] = Self::create_vertex_buffer(renderer, quad_capacity)?;
+        }
+        let vertex_buffer = &self.vertex_buffers[frame_index];
+        vertex_buffer.copy_data_to_buffer(&self.vertices)?;
+
+        let graphics_pipeline = self.technique.pass(0).graphics_pipeline;
+        command_buffer.bind_graphics_pipeline(&graphics_pipeline);
+        command_buffer.bind_descriptor_set(
+            renderer.gpu().bindless_descriptor_set().as_ref(),
+            graphics_pipeline.raw_layout(),
+            0,
+        );
+        command_buffer.push_constants(
+            graphics_pipeline.raw_layout(),
+            vk::ShaderStageFlags::VERTEX,
+            &GpuOverlayConstants {
+                inverse_screen_size: [1.0 / extent.width as f32, 1.0 / extent.height as f32],
+            },
+        );
+        command_buffer.bind_vertex_buffer(vertex_buffer, 0, 0);
+        command_buffer.draw(self.vertices.len() as u32, 1, 0, 0);
+
+        self.vertices.clear();
+
+        Ok(())
+    }
+}
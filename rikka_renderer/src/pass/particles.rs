@@ -0,0 +1,659 @@
+use std::{mem::size_of, sync::Arc, time::Instant};
+
+use anyhow::{anyhow, Context, Result};
+use parking_lot::RwLock;
+
+use rikka_core::{
+    nalgebra::{Vector3, Vector4},
+    vk,
+};
+use rikka_gpu::{
+    barriers::*, buffer::*, command_buffer::CommandBuffer, compute_pipeline::*,
+    constants::MAX_FRAMES, descriptor_set::*, shader_state::*,
+};
+use rikka_graph::{graph::Graph, types::*};
+
+use crate::{renderer::*, schedule::ComputeQueue};
+
+struct ParticleShaderFilePaths;
+
+impl ParticleShaderFilePaths {
+    const SIMULATE: &str = "shaders/particles_simulate.comp";
+    const SORT: &str = "shaders/particles_sort.comp";
+}
+
+/// Technique drawing the sorted particles as camera facing billboards without vertex buffers, descriptor set 0
+/// bindings: "scene_constants" uniform buffer, "particles" and "sort_keys" storage buffers.
+pub const PARTICLES_TECHNIQUE_FILE_PATH: &str = "data/particles.json";
+
+/// Render graph graphics node drawing the particles, they are simulated before the render graph is recorded
+pub const PARTICLE_RENDER_PASS_NAME: &str = "particle_render_pass";
+
+const PARTICLE_WORKGROUP_SIZE: u32 = 64;
+
+/// Byte offset of `vk::DrawIndirectCommand::instance_count`
+const INDIRECT_INSTANCE_COUNT_OFFSET: u64 = 4;
+
+/// Frame time used for the simulation is clamped so stalls do not produce large jumps
+const MAX_PARTICLE_DELTA_TIME: f32 = 0.1;
+
+#[derive(Clone)]
+pub struct EmitterDesc {
+    pub position: Vector3<f32>,
+    /// Particles spawned per second
+    pub emission_rate: f32,
+    /// Seconds a particle stays alive
+    pub lifetime: f32,
+    pub initial_velocity: Vector3<f32>,
+    /// Maximum random offset added to every component of the initial velocity
+    pub velocity_randomness: f32,
+    pub gravity: Vector3<f32>,
+    /// World space size of the billboards
+    pub size: f32,
+    /// Particle colors are interpolated from start to end over their lifetime
+    pub color_start: Vector4<f32>,
+    pub color_end: Vector4<f32>,
+    /// Particles of the emitter alive at the same time
+    pub max_particles: u32,
+}
+
+impl EmitterDesc {
+    pub fn new() -> Self {
+        Self {
+            position: Vector3::zeros(),
+            emission_rate: 100.0,
+            lifetime: 2.0,
+            initial_velocity: Vector3::new(0.0, 2.0, 0.0),
+            velocity_randomness: 0.5,
+            gravity: Vector3::new(0.0, -9.81, 0.0),
+            size: 0.05,
+            color_start: Vector4::new(1.0, 1.0, 1.0, 1.0),
+            color_end: Vector4::new(1.0, 1.0, 1.0, 0.0),
+            max_particles: 1024,
+        }
+    }
+
+    pub fn set_position(mut self, position: Vector3<f32>) -> Self {
+        self.position = position;
+        self
+    }
+
+    pub fn set_emission_rate(mut self, emission_rate: f32) -> Self {
+        self.emission_rate = emission_rate;
+        self
+    }
+
+    pub fn set_lifetime(mut self, lifetime: f32) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    pub fn set_initial_velocity(mut self, initial_velocity: Vector3<f32>) -> Self {
+        self.initial_velocity = initial_velocity;
+        self
+    }
+
+    pub fn set_velocity_randomness(mut self, velocity_randomness: f32) -> Self {
+        self.velocity_randomness = velocity_randomness;
+        self
+    }
+
+    pub fn set_gravity(mut self, gravity: Vector3<f32>) -> Self {
+        self.gravity = gravity;
+        self
+    }
+
+    pub fn set_size(mut self, size: f32) -> Self {
+        self.size = size;
+        self
+    }
+
+    pub fn set_colors(mut self, color_start: Vector4<f32>, color_end: Vector4<f32>) -> Self {
+        self.color_start = color_start;
+        self.color_end = color_end;
+        self
+    }
+
+    pub fn set_max_particles(mut self, max_particles: u32) -> Self {
+        self.max_particles = max_particles;
+        self
+    }
+}
+
+/// Particles are dead when their age reaches their lifetime, zeroed particles are dead
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct GpuParticle {
+    /// w: age in seconds
+    position_age: Vector4<f32>,
+    /// w: lifetime in seconds
+    velocity_lifetime: Vector4<f32>,
+    color: Vector4<f32>,
+    size: f32,
+    emitter_index: u32,
+    _pad0: u32,
+    _pad1: u32,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GpuEmitter {
+    /// w: particle size
+    position_size: Vector4<f32>,
+    /// w: velocity randomness
+    velocity_randomness: Vector4<f32>,
+    /// w: particle lifetime
+    gravity_lifetime: Vector4<f32>,
+    color_start: Vector4<f32>,
+    color_end: Vector4<f32>,
+    particle_offset: u32,
+    particle_count: u32,
+    /// Particles to spawn this frame
+    spawn_count: u32,
+    /// Atomically incremented by the simulation for every spawned particle
+    spawned_count: u32,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GpuParticleSimulationConstants {
+    delta_time: f32,
+    emitter_count: u32,
+    particle_count: u32,
+    random_seed: u32,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GpuParticleSortConstants {
+    /// Size of the bitonic sequences being merged
+    sequence_size: u32,
+    /// Distance between the compared keys
+    compare_distance: u32,
+    sort_key_count: u32,
+}
+
+/// Buffers of one frame in flight, shared by the simulation and the render pass
+#[derive(Clone)]
+struct ParticleBuffers {
+    /// Copied from the particles of the previous frame before they are simulated
+    particles: Handle<Buffer>,
+    /// Pairs of (distance key, particle index), alive particles are sorted back to front
+    sort_keys: Handle<Buffer>,
+    /// Single `vk::DrawIndirectCommand`, the instance count is the number of alive particles
+    indirect_draw: Handle<Buffer>,
+    /// Written by the CPU every frame
+    emitters: Handle<Buffer>,
+}
+
+/// Compute driven particles. Emitters spawn into fixed ranges of a particle storage buffer, the simulation compacts the
+/// alive particles into a bitonic sorted key buffer that the billboards are drawn from with a single indirect draw.
+/// All buffers are per frame in flight so the simulation can overlap the draw of the previous frame on the async
+/// compute queue.
+pub struct ParticleSystem {
+    emitters: RwLock<Vec<EmitterDesc>>,
+
+    frame_buffers: [ParticleBuffers; MAX_FRAMES as usize],
+    simulate_pipeline: Handle<ComputePipeline>,
+    simulate_descriptor_sets: [Arc<DescriptorSet>; MAX_FRAMES as usize],
+    sort_pipeline: Handle<ComputePipeline>,
+    sort_descriptor_sets: [Arc<DescriptorSet>; MAX_FRAMES as usize],
+
+    technique: Arc<RenderTechnique>,
+    render_descriptor_sets: [Arc<DescriptorSet>; MAX_FRAMES as usize],
+
+    particle_count: u32,
+    sort_key_count: u32,
+
+    /// Fractional particles carried over to the next frame
+    emission_accumulators: Vec<f32>,
+    constants: GpuParticleSimulationConstants,
+    last_update: Option<Instant>,
+
+    /// Shared with the render pass registered to the graph
+    frame_index: Arc<RwLock<usize>>,
+}
+
+impl ParticleSystem {
+    pub fn new(
+        renderer: &mut Renderer,
+        render_graph: &Graph,
+        emitters: Vec<EmitterDesc>,
+        scene_uniform_buffer: Handle<Buffer>,
+    ) -> Result<Self> {
+        if emitters.is_empty() {
+            return Err(anyhow!("Particle system needs at least one emitter"));
+        }
+
+        let particle_count = emitters
+            .iter()
+            .map(|emitter| emitter.max_particles)
+            .sum::<u32>();
+        if particle_count == 0 {
+            return Err(anyhow!("Particle emitters do not allow any particles"));
+        }
+        let sort_key_count = particle_count.next_power_of_two().max(2);
+
+        // The simulation can run on the async compute queue
+        let create_frame_buffers = || -> Result<ParticleBuffers> {
+            Ok(ParticleBuffers {
+                particles: renderer.create_buffer_from_data(
+                    BufferDesc::new()
+                        .set_usage_flags(vk::BufferUsageFlags::STORAGE_BUFFER)
+                        .set_async_compute(true),
+                    &vec![GpuParticle::default(); particle_count as usize],
+                )?,
+                sort_keys: renderer.create_buffer(
+                    BufferDesc::new()
+                        .set_size(sort_key_count * size_of::<[u32; 2]>() as u32)
+                        .set_usage_flags(vk::BufferUsageFlags::STORAGE_BUFFER)
+                        .set_device_only(true)
+                        .set_async_compute(true),
+                )?,
+                indirect_draw: renderer.create_buffer_from_data(
+                    BufferDesc::new()
+                        .set_usage_flags(
+                            vk::BufferUsageFlags::STORAGE_BUFFER
+                                | vk::BufferUsageFlags::INDIRECT_BUFFER,
+                        )
+                        .set_async_compute(true),
+                    &[vk::DrawIndirectCommand {
+                        vertex_count: 6,
+                        instance_count: 0,
+                        first_vertex: 0,
+                        first_instance: 0,
+                    }],
+                )?,
+                emitters: renderer.create_buffer(
+                    BufferDesc::new()
+                        .set_size((emitters.len() * size_of::<GpuEmitter>()) as _)
+                        .set_usage_flags(vk::BufferUsageFlags::STORAGE_BUFFER)
+                        .set_device_only(false)
+                        .set_async_compute(true),
+                )?,
+            })
+        };
+        let frame_buffers = [create_frame_buffers()?, create_frame_buffers()?];
+
+        let simulate_pipeline = renderer.gpu().create_compute_pipeline(
+            ComputePipelineDesc::new()
+                .set_compute_stage(ShaderStageDesc::new_from_source_file(
+                    ParticleShaderFilePaths::SIMULATE,
+                    ShaderStageType::Compute,
+                ))
+                .set_push_constants::<GpuParticleSimulationConstants>(),
+        )?;
+        let create_simulate_descriptor_set = |buffers: &ParticleBuffers| {
+            renderer.create_descriptor_set(
+                DescriptorSetDesc::new(simulate_pipeline.descriptor_set_layouts()[0].clone())
+                    .add_buffer_resource(buffers.particles.clone(), 0)
+                    .add_buffer_resource(buffers.emitters.clone(), 1)
+                    .add_buffer_resource(buffers.sort_keys.clone(), 2)
+                    .add_buffer_resource(buffers.indirect_draw.clone(), 3)
+                    .add_buffer_resource(scene_uniform_buffer.clone(), 4),
+            )
+        };
+        let simulate_descriptor_sets = [
+            create_simulate_descriptor_set(&frame_buffers[0])?,
+            create_simulate_descriptor_set(&frame_buffers[1])?,
+        ];
+
+        let sort_pipeline = renderer.gpu().create_compute_pipeline(
+            ComputePipelineDesc::new()
+                .set_compute_stage(ShaderStageDesc::new_from_source_file(
+                    ParticleShaderFilePaths::SORT,
+                    ShaderStageType::Compute,
+                ))
+                .set_push_constants::<GpuParticleSortConstants>(),
+        )?;
+        let create_sort_descriptor_set = |buffers: &ParticleBuffers| {
+            renderer.create_descriptor_set(
+                DescriptorSetDesc::new(sort_pipeline.descriptor_set_layouts()[0].clone())
+                    .add_buffer_resource(buffers.sort_keys.clone(), 0),
+            )
+        };
+        let sort_descriptor_sets = [
+            create_sort_descriptor_set(&frame_buffers[0])?,
+            create_sort_descriptor_set(&frame_buffers[1])?,
+        ];
+
+        let technique = renderer
+            .create_technique_from_file(PARTICLES_TECHNIQUE_FILE_PATH, render_graph)
+            .context("Failed to load particles technique")?;
+        let create_render_descriptor_set = |buffers: &ParticleBuffers| {
+            renderer.create_descriptor_set(
+                DescriptorSetDesc::new(
+                    technique.pass(0).graphics_pipeline.descriptor_set_layouts()[0].clone(),
+                )
+                .add_buffer_resource_named("scene_constants", scene_uniform_buffer.clone())
+                .add_buffer_resource_named("particles", buffers.particles.clone())
+                .add_buffer_resource_named("sort_keys", buffers.sort_keys.clone()),
+            )
+        };
+        let render_descriptor_sets = [
+            create_render_descriptor_set(&frame_buffers[0])?,
+            create_render_descriptor_set(&frame_buffers[1])?,
+        ];
+
+        log::info!(
+            "Created particle system with {} emitters and {} particles",
+            emitters.len(),
+            particle_count
+        );
+
+        let emitter_count = emitters.len();
+        Ok(Self {
+            emitters: RwLock::new(emitters),
+            frame_buffers,
+            simulate_pipeline,
+            simulate_descriptor_sets,
+            sort_pipeline,
+            sort_descriptor_sets,
+            technique,
+            render_descriptor_sets,
+            particle_count,
+            sort_key_count,
+            emission_accumulators: vec![0.0; emitter_count],
+            constants: GpuParticleSimulationConstants {
+                delta_time: 0.0,
+                emitter_count: emitter_count as u32,
+                particle_count,
+                random_seed: 0,
+            },
+            last_update: None,
+            frame_index: Arc::new(RwLock::new(0)),
+        })
+    }
+
+    pub fn particle_count(&self) -> u32 {
+        self.particle_count
+    }
+
+    pub fn emitter_count(&self) -> usize {
+        self.emitters.read().len()
+    }
+
+    pub fn set_emitter_position(&self, emitter_index: usize, position: Vector3<f32>) -> Result<()> {
+        let mut emitters = self.emitters.write();
+        let emitter = emitters
+            .get_mut(emitter_index)
+            .with_context(|| format!("Particle emitter {} does not exist", emitter_index))?;
+        emitter.position = position;
+        Ok(())
+    }
+
+    /// Stops spawning new particles when zero, alive particles finish their lifetime
+    pub fn set_emitter_emission_rate(
+        &self,
+        emitter_index: usize,
+        emission_rate: f32,
+    ) -> Result<()> {
+        let mut emitters = self.emitters.write();
+        let emitter = emitters
+            .get_mut(emitter_index)
+            .with_context(|| format!("Particle emitter {} does not exist", emitter_index))?;
+        emitter.emission_rate = emission_rate.max(0.0);
+        Ok(())
+    }
+
+    /// Writes the emitters spawning into the particles of `frame_context`. The Gpu must have finished the last use of
+    /// the frame, e.g. after the frame fence was waited on.
+    pub fn update(&mut self, frame_context: &FrameContext) -> Result<()> {
+        let now = Instant::now();
+        let delta_time = self.last_update.map_or(0.0, |last_update| {
+            (now - last_update)
+                .as_secs_f32()
+                .min(MAX_PARTICLE_DELTA_TIME)
+        });
+        self.last_update = Some(now);
+
+        let frame_index = frame_context.frame_index as usize;
+        *self.frame_index.write() = frame_index;
+        self.constants.delta_time = delta_time;
+        self.constants.random_seed = frame_context.absolute_frame_index as u32;
+
+        let emitters = self.emitters.read();
+
+        let mut particle_offset = 0;
+        let mut gpu_emitters = Vec::with_capacity(emitters.len());
+        for (emitter, accumulator) in emitters.iter().zip(self.emission_accumulators.iter_mut()) {
+            *accumulator += emitter.emission_rate * delta_time;
+            let spawn_count = accumulator.floor();
+            *accumulator -= spawn_count;
+
+            gpu_emitters.push(GpuEmitter {
+                position_size: emitter.position.push(emitter.size),
+                velocity_randomness: emitter.initial_velocity.push(emitter.velocity_randomness),
+                gravity_lifetime: emitter.gravity.push(emitter.lifetime),
+                color_start: emitter.color_start,
+                color_end: emitter.color_end,
+                particle_offset,
+                particle_count: emitter.max_particles,
+                spawn_count: (spawn_count as u32).min(emitter.max_particles),
+                spawned_count: 0,
+            });
+            particle_offset += emitter.max_particles;
+        }
+
+        Ok(self.frame_buffers[frame_index]
+            .emitters
+            .copy_data_to_buffer(&gpu_emitters)?)
+    }
+
+    /// Simulates and sorts the particles of the frame last passed to `update`, needs to be recorded before the render
+    /// graph. The particles of the previous frame are only read, so async compute work can overlap its draw. Async
+    /// compute work is synchronized with the graphics queue by semaphores instead of barriers.
+    pub fn record_simulation(
+        &self,
+        command_buffer: &CommandBuffer,
+        queue: ComputeQueue,
+    ) -> Result<()> {
+        let frame_index = *self.frame_index.read();
+        let previous_particles = &self.frame_buffers
+            [(frame_index + MAX_FRAMES as usize - 1) % MAX_FRAMES as usize]
+            .particles;
+        let buffers = &self.frame_buffers[frame_index];
+
+        let async_compute = queue == ComputeQueue::AsyncCompute;
+        let add_buffer = |barriers: Barriers,
+                          buffer: &Handle<Buffer>,
+                          old_state: ResourceState,
+                          new_state: ResourceState| {
+            if async_compute {
+                barriers.add_compute_queue_buffer(buffer, old_state, new_state)
+            } else {
+                barriers.add_buffer(buffer, old_state, new_state)
+            }
+        };
+
+        // Buffers of this frame were last read by the previous use of the frame's draw, which completed before async
+        // compute work is submitted
+        if !async_compute {
+            command_buffer.pipeline_barrier(
+                Barriers::new()
+                    .add_buffer(
+                        previous_particles,
+                        ResourceState::SHADER_RESOURCE,
+                        ResourceState::COPY_SOURCE,
+                    )
+                    .add_buffer(
+                        &buffers.particles,
+                        ResourceState::SHADER_RESOURCE,
+                        ResourceState::COPY_DESTINATION,
+                    )
+                    .add_buffer(
+                        &buffers.sort_keys,
+                        ResourceState::SHADER_RESOURCE,
+                        ResourceState::COPY_DESTINATION,
+                    )
+                    .add_buffer(
+                        &buffers.indirect_draw,
+                        ResourceState::INDIRECT_ARGUMENT,
+                        ResourceState::COPY_DESTINATION,
+                    ),
+            );
+        }
+
+        command_buffer.copy_buffer(
+            previous_particles,
+            &buffers.particles,
+            previous_particles.size() as u64,
+            0,
+            0,
+        );
+        // Unused keys sort behind the alive particles
+        command_buffer.fill_buffer(&buffers.sort_keys, 0, vk::WHOLE_SIZE, u32::MAX)?;
+        command_buffer.fill_buffer(
+            &buffers.indirect_draw,
+            INDIRECT_INSTANCE_COUNT_OFFSET,
+            size_of::<u32>() as u64,
+            0,
+        )?;
+
+        let mut barriers = Barriers::new();
+        for buffer in [
+            &buffers.particles,
+            &buffers.sort_keys,
+            &buffers.indirect_draw,
+        ] {
+            barriers = add_buffer(
+                barriers,
+                buffer,
+                ResourceState::COPY_DESTINATION,
+                ResourceState::SHADER_ACCESS,
+            );
+        }
+        if !async_compute {
+            barriers = barriers.add_buffer(
+                previous_particles,
+                ResourceState::COPY_SOURCE,
+                ResourceState::SHADER_RESOURCE,
+            );
+        }
+        command_buffer.pipeline_barrier(barriers);
+
+        command_buffer.bind_compute_pipeline(&self.simulate_pipeline);
+        command_buffer.bind_compute_descriptor_set(
+            &self.simulate_descriptor_sets[frame_index],
+            self.simulate_pipeline.raw_layout(),
+            0,
+        );
+        command_buffer.push_constants(
+            self.simulate_pipeline.raw_layout(),
+            vk::ShaderStageFlags::COMPUTE,
+            &self.constants,
+        );
+        command_buffer.dispatch(
+            (self.particle_count + PARTICLE_WORKGROUP_SIZE - 1) / PARTICLE_WORKGROUP_SIZE,
+            1,
+            1,
+        );
+
+        // Bitonic sort, every dispatch compares and swaps half of the keys
+        command_buffer.bind_compute_pipeline(&self.sort_pipeline);
+        command_buffer.bind_compute_descriptor_set(
+            &self.sort_descriptor_sets[frame_index],
+            self.sort_pipeline.raw_layout(),
+            0,
+        );
+        let group_count =
+            (self.sort_key_count / 2 + PARTICLE_WORKGROUP_SIZE - 1) / PARTICLE_WORKGROUP_SIZE;
+        let mut sequence_size = 2;
+        while sequence_size <= self.sort_key_count {
+            let mut compare_distance = sequence_size / 2;
+            while compare_distance > 0 {
+                command_buffer.pipeline_barrier(add_buffer(
+                    Barriers::new(),
+                    &buffers.sort_keys,
+                    ResourceState::SHADER_ACCESS,
+                    ResourceState::SHADER_ACCESS,
+                ));
+                command_buffer.push_constants(
+                    self.sort_pipeline.raw_layout(),
+                    vk::ShaderStageFlags::COMPUTE,
+                    &GpuParticleSortConstants {
+                        sequence_size,
+                        compare_distance,
+                        sort_key_count: self.sort_key_count,
+                    },
+                );
+                command_buffer.dispatch(group_count, 1, 1);
+
+                compare_distance /= 2;
+            }
+            sequence_size *= 2;
+        }
+
+        if !async_compute {
+            command_buffer.pipeline_barrier(
+                Barriers::new()
+                    .add_buffer(
+                        &buffers.particles,
+                        ResourceState::SHADER_ACCESS,
+                        ResourceState::SHADER_RESOURCE,
+                    )
+                    .add_buffer(
+                        &buffers.sort_keys,
+                        ResourceState::SHADER_ACCESS,
+                        ResourceState::SHADER_RESOURCE,
+                    )
+                    .add_buffer(
+                        &buffers.indirect_draw,
+                        ResourceState::SHADER_ACCESS,
+                        ResourceState::INDIRECT_ARGUMENT,
+                    ),
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn create_render_pass(&self) -> Box<dyn RenderPass> {
+        Box::new(ParticleRenderPass {
+            technique: self.technique.clone(),
+            descriptor_sets: self.render_descriptor_sets.clone(),
+            indirect_draw: [
+                self.frame_buffers[0].indirect_draw.clone(),
+                self.frame_buffers[1].indirect_draw.clone(),
+            ],
+            frame_index: self.frame_index.clone(),
+        })
+    }
+}
+
+struct ParticleRenderPass {
+    technique: Arc<RenderTechnique>,
+    descriptor_sets: [Arc<DescriptorSet>; MAX_FRAMES as usize],
+    indirect_draw: [Handle<Buffer>; MAX_FRAMES as usize],
+    frame_index: Arc<RwLock<usize>>,
+}
+
+impl RenderPass for ParticleRenderPass {
+    fn render(&self, command_buffer: &CommandBuffer) -> Result<()> {
+        let frame_index = *self.frame_index.read();
+        let graphics_pipeline = self.technique.pass(0).graphics_pipeline;
+
+        command_buffer.bind_graphics_pipeline(&graphics_pipeline);
+        command_buffer.bind_descriptor_set(
+            &self.descriptor_sets[frame_index],
+            graphics_pipeline.raw_layout(),
+            0,
+        );
+        command_buffer.draw_indirect(
+            &self.indirect_draw[frame_index],
+            0,
+            1,
+            size_of::<vk::DrawIndirectCommand>() as u32,
+        );
+
+        Ok(())
+    }
+
+    fn post_render(&self, _command_buffer: &CommandBuffer, _graph: &Graph) -> Result<()> {
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "Particle render pass"
+    }
+}
@@ -8,12 +8,14 @@ use rikka_graph::{graph::Graph, types::*};
 
 use crate::{
     renderer::*,
-    scene_renderer::{material::*, mesh::*},
+    scene_renderer::{material::*, material_storage::MaterialStorage, mesh::*},
 };
 
 pub struct PBRLightingPass {
     /// Fullscreen mesh
     mesh: Mesh,
+    material_buffer: Handle<Buffer>,
+    descriptor_set: Arc<DescriptorSet>,
 }
 
 impl PBRLightingPass {
@@ -28,30 +30,22 @@ impl PBRLightingPass {
             MaterialDesc::new(0, render_technique.clone(), String::from("pbr_lighting"));
         let material = renderer.create_material(material_desc)?;
 
-        // XXX: Use dynamic uniform buffer
-        let material_buffer_desc = BufferDesc::new()
-            .set_usage_flags(vk::BufferUsageFlags::UNIFORM_TEXEL_BUFFER)
-            .set_size(size_of::<GpuMeshData> as u32)
-            .set_device_only(false);
-        let material_buffer = renderer.create_buffer(material_buffer_desc)?;
-
-        // XXX: Use accessprs fpr a lot of the structs instead of public mbembers
-        let descriptor_set_layout = render_technique.passes[0]
-            .graphics_pipeline
-            .descriptor_set_layouts()[0]
-            .clone();
-        let descriptor_set_desc = DescriptorSetDesc::new(descriptor_set_layout)
-            .add_buffer_resource(material_buffer.clone(), 0)
-            .add_buffer_resource(scene_uniform_buffer, 0);
-        let descriptor_set = renderer.create_descriptor_set(descriptor_set_desc)?;
-
-        let mut mesh = Mesh::new_with_pbr_material(PBRMaterial::new(
-            material,
-            material_buffer,
-            descriptor_set,
-        ));
-
-        // XXX: Set mesh position buffer?
+        // Lighting is drawn with a single material, bound the same way as the scene materials
+        let material_buffer = renderer.create_buffer(
+            BufferDesc::new()
+                .set_usage_flags(vk::BufferUsageFlags::STORAGE_BUFFER)
+                .set_size(size_of::<GpuMaterialData>() as u32)
+                .set_device_only(false),
+        )?;
+        let descriptor_set = MaterialStorage::create_descriptor_set(
+            renderer,
+            render_technique,
+            scene_uniform_buffer,
+            &material_buffer,
+        )?;
+
+        let mut pbr_material = PBRMaterial::new(material);
+        pbr_material.set_material_index(0);
 
         let node = render_graph.access_node_by_name("pbr_lighting_pass")?;
 
@@ -60,38 +54,44 @@ impl PBRLightingPass {
         let roughness_texture_resource = render_graph.access_resource_by_handle(node.inputs[2])?;
         let position_texture_resource = render_graph.access_resource_by_handle(node.inputs[3])?;
 
-        mesh.pbr_material.diffuse_image = Some(diffuse_texture_resource.gpu_image()?.clone());
-        mesh.pbr_material.normal_image = Some(normal_texture_resource.gpu_image()?);
-        mesh.pbr_material.metallic_roughness_image = Some(roughness_texture_resource.gpu_image()?);
+        pbr_material.diffuse_image = Some(diffuse_texture_resource.gpu_image()?.clone());
+        pbr_material.normal_image = Some(normal_texture_resource.gpu_image()?);
+        pbr_material.metallic_roughness_image = Some(roughness_texture_resource.gpu_image()?);
         // Store position texture on occlusion image
-        mesh.pbr_material.occlusion_image = Some(position_texture_resource.gpu_image()?);
+        pbr_material.occlusion_image = Some(position_texture_resource.gpu_image()?);
+
+        // XXX: Set mesh position buffer?
+        let mesh = Mesh::new_with_pbr_material(Arc::new(pbr_material));
 
-        Ok(Self { mesh })
+        Ok(Self {
+            mesh,
+            material_buffer,
+            descriptor_set,
+        })
     }
 
-    /// Copies mesh data to the Gpu buffer
+    /// Copies material data to the Gpu buffer
     pub fn upload_data_to_gpu(&self) -> Result<()> {
-        self.mesh
-            .pbr_material
+        Ok(self
             .material_buffer
-            .copy_data_to_buffer(&[self.mesh.create_gpu_data()])
+            .copy_data_to_buffer(&[self.mesh.pbr_material.create_gpu_data()])?)
     }
 }
 
 impl RenderPass for PBRLightingPass {
     fn render(&self, command_buffer: &CommandBuffer) -> Result<()> {
         let material_pass_index = 0;
-        let graphics_pipeline = &self.mesh.pbr_material.material.render_technique.passes
-            [material_pass_index]
+        let graphics_pipeline = self
+            .mesh
+            .pbr_material
+            .material
+            .render_technique
+            .pass(material_pass_index)
             .graphics_pipeline;
 
         command_buffer.bind_graphics_pipeline(&graphics_pipeline);
         command_buffer.bind_vertex_buffer(self.mesh.position_buffer.as_ref().unwrap(), 0, 0);
-        command_buffer.bind_descriptor_set(
-            &self.mesh.pbr_material.descriptor_set,
-            graphics_pipeline.raw_layout(),
-            0,
-        );
+        command_buffer.bind_descriptor_set(&self.descriptor_set, graphics_pipeline.raw_layout(), 0);
         command_buffer.draw(3, 1, 0, 0);
 
         Ok(())
@@ -0,0 +1,219 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+
+use rikka_core::vk;
+use rikka_gpu::{
+    barriers::*, buffer::*, command_buffer::CommandBuffer, descriptor_set::*, image::*,
+    ray_tracing::*, shader_state::*, types::ImageResourceUpdate,
+};
+
+use crate::{renderer::*, scene, scene_renderer::mesh::*};
+
+/// Render graph attachment the shadow rays are traced from
+pub const SCENE_DEPTH_RESOURCE_NAME: &str = "depth";
+
+struct RayTracedShadowsShaderFilePaths;
+
+impl RayTracedShadowsShaderFilePaths {
+    const RAY_GENERATION: &str = "shaders/ray_traced_shadows.rgen";
+    const MISS: &str = "shaders/ray_traced_shadows.rmiss";
+}
+
+/// Traces shadow rays from the scene depth towards the light into a shadow mask.
+/// Descriptor set 0 bindings: 0 - top level acceleration structure, 1 - shadow mask storage image,
+/// 2 - scene depth, 3 - scene uniform buffer.
+pub struct RayTracedShadowsPass {
+    pipeline: Handle<RayTracingPipeline>,
+    descriptor_set: Arc<DescriptorSet>,
+
+    top_level_acceleration_structure: Handle<AccelerationStructure>,
+
+    depth_image: Handle<Image>,
+    shadow_mask_image: Handle<Image>,
+}
+
+impl RayTracedShadowsPass {
+    pub const SHADOW_MASK_FORMAT: vk::Format = vk::Format::R8_UNORM;
+
+    pub fn new(
+        renderer: &mut Renderer,
+        meshes: &[Arc<Mesh>],
+        scene_graph: &scene::Graph,
+        depth_image: Handle<Image>,
+        scene_uniform_buffer: Handle<Buffer>,
+    ) -> Result<Self> {
+        if !renderer.gpu().ray_tracing_supported() {
+            return Err(anyhow!(
+                "Ray traced shadows require a ray tracing capable Gpu"
+            ));
+        }
+
+        let top_level_acceleration_structure =
+            Self::build_acceleration_structures(renderer, meshes, scene_graph)?;
+
+        let shadow_mask_image = renderer.create_image(
+            ImageDesc::new(depth_image.width(), depth_image.height(), 1)
+                .set_format(Self::SHADOW_MASK_FORMAT)
+                .set_usage_flags(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED),
+        )?;
+
+        if !depth_image.has_linked_sampler() {
+            depth_image.set_linked_sampler(renderer.gpu().default_sampler().clone());
+        }
+
+        let pipeline = renderer.gpu().create_ray_tracing_pipeline(
+            RayTracingPipelineDesc::new()
+                .set_ray_generation_stage(ShaderStageDesc::new_from_source_file(
+                    RayTracedShadowsShaderFilePaths::RAY_GENERATION,
+                    ShaderStageType::RayGeneration,
+                ))
+                .add_miss_stage(ShaderStageDesc::new_from_source_file(
+                    RayTracedShadowsShaderFilePaths::MISS,
+                    ShaderStageType::Miss,
+                )),
+        )?;
+
+        let descriptor_set_desc =
+            DescriptorSetDesc::new(pipeline.descriptor_set_layouts()[0].clone())
+                .add_acceleration_structure_resource(top_level_acceleration_structure.clone(), 0)
+                .add_image_resource(shadow_mask_image.clone(), 1)
+                .add_image_resource(depth_image.clone(), 2)
+                .add_buffer_resource(scene_uniform_buffer, 3);
+        let descriptor_set = renderer.create_descriptor_set(descriptor_set_desc)?;
+
+        // Shadow mask is kept readable between frames, the lighting samples it through the bindless array
+        renderer.gpu().transition_image_layout(
+            &shadow_mask_image,
+            ResourceState::UNDEFINED,
+            ResourceState::SHADER_RESOURCE,
+        )?;
+        renderer
+            .gpu_mut()
+            .add_bindless_image_update(ImageResourceUpdate {
+                frame: 0,
+                image: Some(shadow_mask_image.clone()),
+                sampler: None,
+            });
+
+        Ok(Self {
+            pipeline,
+            descriptor_set,
+            top_level_acceleration_structure,
+            depth_image,
+            shadow_mask_image,
+        })
+    }
+
+    fn build_acceleration_structures(
+        renderer: &mut Renderer,
+        meshes: &[Arc<Mesh>],
+        scene_graph: &scene::Graph,
+    ) -> Result<Handle<AccelerationStructure>> {
+        let mut top_level_desc = TopLevelAccelerationStructureDesc::new();
+
+        for (mesh_index, mesh) in meshes.iter().enumerate() {
+            // Points and lines do not cast shadows
+            // XXX: Convert triangle strips and fans into lists
+            if mesh.topology != vk::PrimitiveTopology::TRIANGLE_LIST {
+                continue;
+            }
+
+            let (position_buffer, index_buffer) = match (&mesh.position_buffer, &mesh.index_buffer)
+            {
+                (Some(position_buffer), Some(index_buffer)) => {
+                    (position_buffer.clone(), index_buffer.clone())
+                }
+                _ => {
+                    log::warn!(
+                        "Mesh {} without position or index buffer is skipped",
+                        mesh_index
+                    );
+                    continue;
+                }
+            };
+
+            let geometry = TriangleGeometry::new(
+                position_buffer,
+                mesh.vertex_count,
+                index_buffer,
+                mesh.primitive_count,
+            )
+            .set_vertex_layout(
+                mesh.position_offset as _,
+                mesh.position_stride as _,
+                vk::Format::R32G32B32_SFLOAT,
+            )
+            .set_index_layout(mesh.index_offset as _, mesh.index_type)
+            .set_opaque(!mesh.transparent());
+
+            let bottom_level = renderer
+                .gpu_mut()
+                .create_bottom_level_acceleration_structure(
+                    BottomLevelAccelerationStructureDesc::new().add_geometry(geometry),
+                )?;
+
+            top_level_desc = top_level_desc.add_instance(
+                AccelerationStructureInstance::new(
+                    bottom_level,
+                    scene_graph.global_matrices[mesh.scene_graph_node_index],
+                )
+                .set_custom_index(mesh_index as u32),
+            );
+        }
+
+        Ok(renderer
+            .gpu_mut()
+            .create_top_level_acceleration_structure(top_level_desc)?)
+    }
+
+    /// Expects the depth image to be in the `DEPTH_WRITE` state
+    pub fn render(&self, command_buffer: &CommandBuffer) {
+        let barriers = Barriers::new()
+            .add_ray_tracing_image(
+                &self.depth_image,
+                ResourceState::DEPTH_WRITE,
+                ResourceState::SHADER_RESOURCE,
+            )
+            .add_ray_tracing_image(
+                &self.shadow_mask_image,
+                ResourceState::SHADER_RESOURCE,
+                ResourceState::SHADER_ACCESS,
+            );
+        command_buffer.pipeline_barrier(barriers);
+
+        command_buffer.bind_ray_tracing_pipeline(&self.pipeline);
+        command_buffer.bind_ray_tracing_descriptor_set(
+            &self.descriptor_set,
+            self.pipeline.raw_layout(),
+            0,
+        );
+        command_buffer.trace_rays(
+            &self.pipeline,
+            self.shadow_mask_image.width(),
+            self.shadow_mask_image.height(),
+            1,
+        );
+
+        let barriers = Barriers::new()
+            .add_ray_tracing_image(
+                &self.depth_image,
+                ResourceState::SHADER_RESOURCE,
+                ResourceState::DEPTH_WRITE,
+            )
+            .add_ray_tracing_image(
+                &self.shadow_mask_image,
+                ResourceState::SHADER_ACCESS,
+                ResourceState::SHADER_RESOURCE,
+            );
+        command_buffer.pipeline_barrier(barriers);
+    }
+
+    pub fn shadow_mask_image(&self) -> &Handle<Image> {
+        &self.shadow_mask_image
+    }
+
+    pub fn top_level_acceleration_structure(&self) -> &Handle<AccelerationStructure> {
+        &self.top_level_acceleration_structure
+    }
+}
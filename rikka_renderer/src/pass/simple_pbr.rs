@@ -1,17 +1,29 @@
 use std::{mem::size_of, sync::Arc};
 
 use anyhow::Result;
+use parking_lot::RwLock;
 
-use rikka_core::{nalgebra::Vector4, vk};
+use rikka_core::{
+    nalgebra::{Vector3, Vector4},
+    vk,
+};
 use rikka_gpu::{buffer::*, command_buffer::CommandBuffer, descriptor_set::*};
 use rikka_graph::{graph::Graph, types::*};
 
-use crate::{renderer::*, scene_renderer::mesh::*};
+use crate::{
+    renderer::*,
+    scene_renderer::{draw_list::*, mesh::*},
+};
 
 pub struct SimplePbrPass {
     mesh_instances: Vec<MeshInstance>,
     zero_buffer: Handle<Buffer>,
     bindless_descriptor_set: Arc<DescriptorSet>,
+    material_descriptor_set: Option<Arc<DescriptorSet>>,
+
+    /// Shared with the render pass registered to the graph
+    view_position: Arc<RwLock<Vector3<f32>>>,
+    draw_list_stats: Arc<RwLock<DrawListStats>>,
 }
 
 impl SimplePbrPass {
@@ -20,6 +32,8 @@ impl SimplePbrPass {
         render_graph: &Graph,
         meshes: &[Arc<Mesh>],
         bindless_descriptor_set: Arc<DescriptorSet>,
+        // Descriptor set 0 of the material storage, None without meshes
+        material_descriptor_set: Option<Arc<DescriptorSet>>,
     ) -> Result<Self> {
         let mesh_instances = meshes
             .into_iter()
@@ -39,14 +53,30 @@ impl SimplePbrPass {
             mesh_instances,
             zero_buffer,
             bindless_descriptor_set,
+            material_descriptor_set,
+            view_position: Arc::new(RwLock::new(Vector3::zeros())),
+            draw_list_stats: Arc::new(RwLock::new(DrawListStats::default())),
         })
     }
 
+    /// Opaque meshes are drawn front to back from this position
+    pub fn set_view_position(&self, view_position: Vector3<f32>) {
+        *self.view_position.write() = view_position;
+    }
+
+    /// State changes of the last recorded frame
+    pub fn draw_list_stats(&self) -> DrawListStats {
+        *self.draw_list_stats.read()
+    }
+
     pub fn create_render_pass(&self) -> Box<dyn RenderPass> {
         Box::new(SimplePbrRenderPass {
             mesh_instances: self.mesh_instances.clone(),
             zero_buffer: self.zero_buffer.clone(),
             bindless_descriptor_set: self.bindless_descriptor_set.clone(),
+            material_descriptor_set: self.material_descriptor_set.clone(),
+            view_position: self.view_position.clone(),
+            draw_list_stats: self.draw_list_stats.clone(),
         })
     }
 }
@@ -55,32 +85,45 @@ struct SimplePbrRenderPass {
     mesh_instances: Vec<MeshInstance>,
     zero_buffer: Handle<Buffer>,
     bindless_descriptor_set: Arc<DescriptorSet>,
+    material_descriptor_set: Option<Arc<DescriptorSet>>,
+    view_position: Arc<RwLock<Vector3<f32>>>,
+    draw_list_stats: Arc<RwLock<DrawListStats>>,
 }
 
 impl RenderPass for SimplePbrRenderPass {
     fn render(&self, command_buffer: &CommandBuffer) -> Result<()> {
+        let material_descriptor_set = match &self.material_descriptor_set {
+            Some(material_descriptor_set) => material_descriptor_set,
+            None => return Ok(()),
+        };
+
+        let mut draw_list_builder = DrawListBuilder::new(*self.view_position.read());
         for mesh_instance in &self.mesh_instances {
             let mesh = &mesh_instance.mesh;
 
-            if mesh.transparent() {
+            if mesh.transparent() || !mesh.visible() {
                 continue;
             }
-            let graphics_pipeline = &mesh.pbr_material.material.render_technique.passes
-                [mesh_instance.material_pass_index]
-                .graphics_pipeline;
-
-            // XXX: Do not bind pipeline ber draw, sort based on material and bind sparringly
-            // XXX FIXME: The process of obtaining the pipeline from the mesh and material
-            command_buffer.bind_graphics_pipeline(graphics_pipeline);
-            command_buffer.bind_descriptor_set(
-                &self.bindless_descriptor_set,
-                graphics_pipeline.raw_layout(),
-                1,
-            );
-
-            mesh.draw(command_buffer, graphics_pipeline, &self.zero_buffer);
+            // Pipelines are looked up every frame as techniques can be reloaded
+            let technique_pass = mesh
+                .pbr_material
+                .material
+                .render_technique
+                .pass_with_topology(mesh_instance.material_pass_index, mesh.topology);
+            let graphics_pipeline =
+                technique_pass.graphics_pipeline_variant(&mesh.permutation_defines());
+
+            draw_list_builder.add_mesh(mesh, graphics_pipeline.clone());
         }
 
+        let draw_list = draw_list_builder.build();
+        *self.draw_list_stats.write() = draw_list.record(
+            command_buffer,
+            material_descriptor_set,
+            &self.bindless_descriptor_set,
+            &self.zero_buffer,
+        );
+
         Ok(())
     }
 
@@ -0,0 +1,294 @@
+use std::{mem::size_of, sync::Arc};
+
+use anyhow::{anyhow, Result};
+
+use rikka_core::{nalgebra::Matrix4, vk};
+use rikka_gpu::{
+    barriers::*, buffer::*, command_buffer::CommandBuffer, compute_pipeline::*,
+    constants::MAX_FRAMES, descriptor_set::*, shader_state::*,
+};
+
+use crate::{
+    animation::Skin,
+    renderer::*,
+    scene,
+    scene_renderer::{mesh::Mesh, meshlet::MeshletStorageBuffers},
+    schedule::ComputeQueue,
+};
+
+/// Skins the vertices of one mesh per dispatch. Descriptor set 0 bindings: 0 - `GpuSkinVertex` bind pose vertices,
+/// 1 - joint matrices, 2 - skinned positions, 3 - skinned normals, 4 - meshlet vertex positions, 5 - meshlet vertex
+/// data. Meshlet vertices are only written when `meshlet_vertex_offset` is valid.
+const SKINNING_SHADER_FILE_PATH: &str = "shaders/skinning.comp";
+
+const SKINNING_WORKGROUP_SIZE: u32 = 64;
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GpuSkinningConstants {
+    vertex_count: u32,
+    /// First joint matrix of the mesh
+    joint_offset: u32,
+    /// `u32::MAX` for meshes without meshlets
+    meshlet_vertex_offset: u32,
+
+    _pad0: u32,
+}
+
+struct SkinnedMesh {
+    mesh: Arc<Mesh>,
+    joint_offset: u32,
+    descriptor_sets: [Arc<DescriptorSet>; MAX_FRAMES as usize],
+}
+
+/// Compute pre-pass skinning the skinned meshes once per frame. Positions and normals are written to per frame vertex
+/// buffers drawn by every geometry pass and to the meshlet vertex buffers read by the meshlet and indirect draw paths,
+/// so no vertex shader needs to skin.
+// XXX: Tangents are not skinned and meshlet bounds and cones are computed from the bind pose, skinned meshlets can be
+// culled wrongly
+// XXX: Acceleration structures of ray traced shadows and ray casts use the bind pose
+pub struct SkinningPass {
+    pipeline: Handle<ComputePipeline>,
+    skinned_meshes: Vec<SkinnedMesh>,
+
+    /// Joint matrices of all skinned meshes, written by the Cpu every frame
+    joint_matrix_buffers: [Handle<Buffer>; MAX_FRAMES as usize],
+    /// Meshlet vertex buffers, or a placeholder buffer if the scene has no meshlets
+    meshlet_vertex_positions: Handle<Buffer>,
+    meshlet_vertex_data: Handle<Buffer>,
+}
+
+impl SkinningPass {
+    /// Fails if none of `meshes` is skinned
+    pub fn new(
+        renderer: &Renderer,
+        meshes: &[Arc<Mesh>],
+        skins: &[Skin],
+        meshlet_storage_buffers: Option<&MeshletStorageBuffers>,
+    ) -> Result<Self> {
+        let pipeline = renderer.gpu().create_compute_pipeline(
+            ComputePipelineDesc::new()
+                .set_compute_stage(ShaderStageDesc::new_from_source_file(
+                    SKINNING_SHADER_FILE_PATH,
+                    ShaderStageType::Compute,
+                ))
+                .set_push_constants::<GpuSkinningConstants>(),
+        )?;
+
+        let mut joint_count = 0;
+        let mut skinned_meshes = Vec::new();
+        let (meshlet_vertex_positions, meshlet_vertex_data) = match meshlet_storage_buffers {
+            Some(meshlet_storage_buffers) => (
+                meshlet_storage_buffers.vertex_positions.clone(),
+                meshlet_storage_buffers.vertex_data.clone(),
+            ),
+            None => {
+                let placeholder_buffer = renderer.create_buffer(
+                    BufferDesc::new()
+                        .set_size(size_of::<[f32; 4]>() as _)
+                        .set_usage_flags(vk::BufferUsageFlags::STORAGE_BUFFER)
+                        .set_device_only(true)
+                        .set_async_compute(true),
+                )?;
+                (placeholder_buffer.clone(), placeholder_buffer)
+            }
+        };
+
+        let mesh_skins = meshes
+            .iter()
+            .filter_map(|mesh| mesh.skin.as_ref().map(|mesh_skin| (mesh, mesh_skin)))
+            .collect::<Vec<_>>();
+        if mesh_skins.is_empty() {
+            return Err(anyhow!("Skinning pass needs at least one skinned mesh"));
+        }
+
+        let joint_offsets = mesh_skins
+            .iter()
+            .map(|(_, mesh_skin)| {
+                let skin = skins
+                    .get(mesh_skin.skin_index)
+                    .ok_or_else(|| anyhow!("Skin {} does not exist", mesh_skin.skin_index))?;
+                let joint_offset = joint_count;
+                joint_count += skin.joints.len();
+                Ok(joint_offset as u32)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let create_joint_matrix_buffer = || {
+            renderer.create_buffer(
+                BufferDesc::new()
+                    .set_size((joint_count.max(1) * size_of::<Matrix4<f32>>()) as _)
+                    .set_usage_flags(vk::BufferUsageFlags::STORAGE_BUFFER)
+                    .set_device_only(false)
+                    .set_async_compute(true),
+            )
+        };
+        let joint_matrix_buffers = [create_joint_matrix_buffer()?, create_joint_matrix_buffer()?];
+
+        for ((mesh, mesh_skin), joint_offset) in mesh_skins.into_iter().zip(joint_offsets) {
+            let create_descriptor_set = |frame: usize| {
+                renderer.create_descriptor_set(
+                    DescriptorSetDesc::new(pipeline.descriptor_set_layouts()[0].clone())
+                        .add_buffer_resource(mesh_skin.vertex_buffer.clone(), 0)
+                        .add_buffer_resource(joint_matrix_buffers[frame].clone(), 1)
+                        .add_buffer_resource(mesh_skin.skinned_position_buffers[frame].clone(), 2)
+                        .add_buffer_resource(mesh_skin.skinned_normal_buffers[frame].clone(), 3)
+                        .add_buffer_resource(meshlet_vertex_positions.clone(), 4)
+                        .add_buffer_resource(meshlet_vertex_data.clone(), 5),
+                )
+            };
+
+            skinned_meshes.push(SkinnedMesh {
+                mesh: mesh.clone(),
+                joint_offset,
+                descriptor_sets: [create_descriptor_set(0)?, create_descriptor_set(1)?],
+            });
+        }
+
+        log::info!(
+            "Created skinning pass for {} meshes with {} joints",
+            skinned_meshes.len(),
+            joint_count
+        );
+
+        Ok(Self {
+            pipeline,
+            skinned_meshes,
+            joint_matrix_buffers,
+            meshlet_vertex_positions,
+            meshlet_vertex_data,
+        })
+    }
+
+    pub fn skinned_mesh_count(&self) -> usize {
+        self.skinned_meshes.len()
+    }
+
+    /// Writes the joint matrices of the current scene graph pose. The Gpu must have finished the last use of
+    /// `frame_index`, e.g. after the frame fence was waited on.
+    pub fn update(
+        &self,
+        frame_index: usize,
+        skins: &[Skin],
+        scene_graph: &scene::Graph,
+    ) -> Result<()> {
+        let joint_matrices = self
+            .skinned_meshes
+            .iter()
+            .flat_map(|skinned_mesh| {
+                let mesh = &skinned_mesh.mesh;
+                let skin_index = mesh.skin.as_ref().unwrap().skin_index;
+                skins[skin_index].joint_matrices(scene_graph, mesh.scene_graph_node_index)
+            })
+            .collect::<Vec<_>>();
+
+        Ok(self.joint_matrix_buffers[frame_index].copy_data_to_buffer(&joint_matrices)?)
+    }
+
+    /// Meshlet vertices are shared by all frames, skinning them cannot overlap the previous frame on the async compute
+    /// queue
+    pub fn writes_meshlet_vertices(&self) -> bool {
+        self.skinned_meshes
+            .iter()
+            .any(|skinned_mesh| skinned_mesh.mesh.meshlet_vertex_offset != u32::MAX)
+    }
+
+    /// Skins the visible meshes, needs to be recorded before any pass drawing the scene meshes. Async compute work
+    /// is synchronized with the graphics queue by semaphores instead of barriers, `writes_meshlet_vertices` must be
+    /// false.
+    pub fn record(&self, command_buffer: &CommandBuffer, frame_index: usize, queue: ComputeQueue) {
+        let visible_meshes = self
+            .skinned_meshes
+            .iter()
+            .filter(|skinned_mesh| skinned_mesh.mesh.visible())
+            .collect::<Vec<_>>();
+        let async_compute = queue == ComputeQueue::AsyncCompute;
+
+        // Skinned vertices of this frame were last drawn by the previous use of the frame, the meshlet vertices by the
+        // previous frame
+        let mut barriers = Barriers::new()
+            .add_buffer(
+                &self.meshlet_vertex_positions,
+                ResourceState::SHADER_RESOURCE,
+                ResourceState::SHADER_ACCESS,
+            )
+            .add_buffer(
+                &self.meshlet_vertex_data,
+                ResourceState::SHADER_RESOURCE,
+                ResourceState::SHADER_ACCESS,
+            );
+        for skinned_mesh in &visible_meshes {
+            let mesh_skin = skinned_mesh.mesh.skin.as_ref().unwrap();
+            barriers = barriers
+                .add_buffer(
+                    &mesh_skin.skinned_position_buffers[frame_index],
+                    ResourceState::VERTEX_AND_UNIFORM_BUFFER,
+                    ResourceState::SHADER_ACCESS,
+                )
+                .add_buffer(
+                    &mesh_skin.skinned_normal_buffers[frame_index],
+                    ResourceState::VERTEX_AND_UNIFORM_BUFFER,
+                    ResourceState::SHADER_ACCESS,
+                );
+        }
+        if !async_compute {
+            command_buffer.pipeline_barrier(barriers);
+        }
+
+        command_buffer.bind_compute_pipeline(&self.pipeline);
+        for skinned_mesh in &visible_meshes {
+            let mesh = &skinned_mesh.mesh;
+
+            command_buffer.bind_compute_descriptor_set(
+                &skinned_mesh.descriptor_sets[frame_index],
+                self.pipeline.raw_layout(),
+                0,
+            );
+            command_buffer.push_constants(
+                self.pipeline.raw_layout(),
+                vk::ShaderStageFlags::COMPUTE,
+                &GpuSkinningConstants {
+                    vertex_count: mesh.vertex_count,
+                    joint_offset: skinned_mesh.joint_offset,
+                    meshlet_vertex_offset: mesh.meshlet_vertex_offset,
+                    _pad0: 0,
+                },
+            );
+            command_buffer.dispatch(
+                (mesh.vertex_count + SKINNING_WORKGROUP_SIZE - 1) / SKINNING_WORKGROUP_SIZE,
+                1,
+                1,
+            );
+        }
+
+        let mut barriers = Barriers::new()
+            .add_buffer(
+                &self.meshlet_vertex_positions,
+                ResourceState::SHADER_ACCESS,
+                ResourceState::SHADER_RESOURCE,
+            )
+            .add_buffer(
+                &self.meshlet_vertex_data,
+                ResourceState::SHADER_ACCESS,
+                ResourceState::SHADER_RESOURCE,
+            );
+        for skinned_mesh in &visible_meshes {
+            let mesh_skin = skinned_mesh.mesh.skin.as_ref().unwrap();
+            barriers = barriers
+                .add_buffer(
+                    &mesh_skin.skinned_position_buffers[frame_index],
+                    ResourceState::SHADER_ACCESS,
+                    ResourceState::VERTEX_AND_UNIFORM_BUFFER,
+                )
+                .add_buffer(
+                    &mesh_skin.skinned_normal_buffers[frame_index],
+                    ResourceState::SHADER_ACCESS,
+                    ResourceState::VERTEX_AND_UNIFORM_BUFFER,
+                );
+            mesh_skin.set_skinned_frame(Some(frame_index));
+        }
+        if !async_compute {
+            command_buffer.pipeline_barrier(barriers);
+        }
+    }
+}
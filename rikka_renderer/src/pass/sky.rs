@@ -0,0 +1,239 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use anyhow::{Context, Result};
+use parking_lot::RwLock;
+
+use rikka_core::{
+    nalgebra::{Vector3, Vector4},
+    vk,
+};
+use rikka_gpu::{buffer::Buffer, command_buffer::CommandBuffer, descriptor_set::*, image::*};
+use rikka_graph::{graph::Graph, types::*};
+
+use crate::{renderer::*, sky::*};
+
+/// Technique drawing the sky behind the scene with a fullscreen triangle, descriptor set 0 binding: "scene_constants"
+/// uniform buffer. The model parameters are pushed as `GpuSkyConstants` to the fragment stage.
+pub const SKY_TECHNIQUE_FILE_PATH: &str = "data/sky.json";
+
+/// Render graph node the sky pass is registered to
+pub const SKY_PASS_NAME: &str = "sky_pass";
+
+/// Texels per side of the generated environment cube map
+const SKY_ENVIRONMENT_SIZE: u32 = 32;
+
+/// Generated environments kept around for sun positions that are revisited
+const SKY_ENVIRONMENT_CACHE_SIZE: usize = 8;
+
+/// Bit patterns of the sun direction and turbidity a cached environment was generated for
+type SkyEnvironmentKey = ([u32; 3], u32);
+
+fn sky_environment_key(sky: &PreethamSky) -> SkyEnvironmentKey {
+    let sun_direction = sky.sun_direction();
+    (
+        [
+            sun_direction.x.to_bits(),
+            sun_direction.y.to_bits(),
+            sun_direction.z.to_bits(),
+        ],
+        sky.turbidity().to_bits(),
+    )
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GpuSkyConstants {
+    /// Perez coefficients A to E, xyz contain the luminance Y and the chromaticities x, y
+    perez: [Vector4<f32>; 5],
+    /// xyz contain the zenith luminance and chromaticities, w is the luminance scale
+    zenith: Vector4<f32>,
+    /// xyz contain the normalized direction towards the sun
+    sun_direction: Vector4<f32>,
+    /// xyz contain the Perez functions at the zenith the sky is normalized by
+    normalization: Vector4<f32>,
+}
+
+impl GpuSkyConstants {
+    fn new(sky: &PreethamSky) -> Self {
+        let coefficients = sky.perez_coefficients();
+        let perez = [0, 1, 2, 3, 4].map(|index| {
+            Vector4::new(
+                coefficients[0].0[index],
+                coefficients[1].0[index],
+                coefficients[2].0[index],
+                0.0,
+            )
+        });
+
+        let zenith = sky.zenith();
+        let sun_direction = sky.sun_direction();
+        let normalization = sky.perez_normalization();
+
+        Self {
+            perez,
+            zenith: Vector4::new(zenith[0], zenith[1], zenith[2], sky.luminance_scale()),
+            sun_direction: sun_direction.push(0.0),
+            normalization: Vector4::new(normalization[0], normalization[1], normalization[2], 0.0),
+        }
+    }
+}
+
+/// Analytic sky drawn as background, with an environment generated from the same model for the ambient PBR term.
+/// The environment is generated on the Cpu for new sun positions and uploaded into the same cube map, which keeps its
+/// bindless slot for the lifetime of the pass.
+pub struct SkyPass {
+    technique: Arc<RenderTechnique>,
+    descriptor_set: Arc<DescriptorSet>,
+    /// Shared with the render pass registered to the graph
+    constants: Arc<RwLock<GpuSkyConstants>>,
+
+    sky: PreethamSky,
+    environment_image: Option<Handle<Image>>,
+    irradiance_sh: [Vector4<f32>; 9],
+    /// Most recently used environments at the front
+    environment_cache: VecDeque<(SkyEnvironmentKey, SkyEnvironment)>,
+    /// Set when the sky changed and the environment needs to be uploaded
+    dirty: bool,
+}
+
+impl SkyPass {
+    pub fn new(
+        renderer: &Renderer,
+        render_graph: &Graph,
+        sun_direction: Vector3<f32>,
+        turbidity: f32,
+        scene_uniform_buffer: Handle<Buffer>,
+    ) -> Result<Self> {
+        let technique = renderer
+            .create_technique_from_file(SKY_TECHNIQUE_FILE_PATH, render_graph)
+            .context("Failed to load sky technique")?;
+
+        let descriptor_set_layout =
+            technique.pass(0).graphics_pipeline.descriptor_set_layouts()[0].clone();
+        let descriptor_set = renderer.create_descriptor_set(
+            DescriptorSetDesc::new(descriptor_set_layout)
+                .add_buffer_resource_named("scene_constants", scene_uniform_buffer),
+        )?;
+
+        let sky = PreethamSky::new(sun_direction, turbidity, DEFAULT_SKY_LUMINANCE_SCALE);
+        let constants = Arc::new(RwLock::new(GpuSkyConstants::new(&sky)));
+
+        Ok(Self {
+            technique,
+            descriptor_set,
+            constants,
+            sky,
+            environment_image: None,
+            irradiance_sh: [Vector4::zeros(); 9],
+            environment_cache: VecDeque::new(),
+            dirty: true,
+        })
+    }
+
+    pub fn sky(&self) -> &PreethamSky {
+        &self.sky
+    }
+
+    /// Moves the sun, the environment is updated by the next `update`
+    pub fn set_sun(&mut self, sun_direction: Vector3<f32>, turbidity: f32) {
+        let sky = PreethamSky::new(sun_direction, turbidity, self.sky.luminance_scale());
+        if sky_environment_key(&sky) == sky_environment_key(&self.sky) {
+            return;
+        }
+
+        self.sky = sky;
+        *self.constants.write() = GpuSkyConstants::new(&self.sky);
+        self.dirty = true;
+    }
+
+    /// Uploads the environment of the current sky if it changed since the last update, generating it unless it is
+    /// cached
+    pub fn update(&mut self, renderer: &mut Renderer) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let key = sky_environment_key(&self.sky);
+        let environment = match self
+            .environment_cache
+            .iter()
+            .position(|(cached_key, _)| *cached_key == key)
+        {
+            Some(index) => self.environment_cache.remove(index).unwrap().1,
+            None => SkyEnvironment::generate(&self.sky, SKY_ENVIRONMENT_SIZE),
+        };
+
+        match &self.environment_image {
+            Some(image) => {
+                renderer.update_image_with_mips_from_data(image, &environment.specular)?
+            }
+            None => {
+                let image = renderer.create_image_with_mips_from_data(
+                    ImageDesc::new(environment.size, environment.size, 1)
+                        .set_format(vk::Format::R32G32B32A32_SFLOAT)
+                        .set_mip_level_count(environment.mip_count)
+                        .set_cube_map(1),
+                    &environment.specular,
+                )?;
+                self.environment_image = Some(image);
+            }
+        }
+
+        self.irradiance_sh = environment.irradiance_sh;
+        self.environment_cache.push_front((key, environment));
+        self.environment_cache.truncate(SKY_ENVIRONMENT_CACHE_SIZE);
+        self.dirty = false;
+
+        Ok(())
+    }
+
+    /// Prefiltered specular cube map, roughness increases linearly over its mip levels. Its bindless index refers to
+    /// the cube map array of the bindless set.
+    pub fn environment_image(&self) -> Option<&Handle<Image>> {
+        self.environment_image.as_ref()
+    }
+
+    /// Cosine convolved irradiance as band 2 spherical harmonics
+    pub fn irradiance_sh(&self) -> &[Vector4<f32>; 9] {
+        &self.irradiance_sh
+    }
+
+    pub fn create_render_pass(&self) -> Box<dyn RenderPass> {
+        Box::new(SkyRenderPass {
+            technique: self.technique.clone(),
+            descriptor_set: self.descriptor_set.clone(),
+            constants: self.constants.clone(),
+        })
+    }
+}
+
+struct SkyRenderPass {
+    technique: Arc<RenderTechnique>,
+    descriptor_set: Arc<DescriptorSet>,
+    constants: Arc<RwLock<GpuSkyConstants>>,
+}
+
+impl RenderPass for SkyRenderPass {
+    fn render(&self, command_buffer: &CommandBuffer) -> Result<()> {
+        let graphics_pipeline = self.technique.pass(0).graphics_pipeline;
+
+        command_buffer.bind_graphics_pipeline(&graphics_pipeline);
+        command_buffer.bind_descriptor_set(&self.descriptor_set, graphics_pipeline.raw_layout(), 0);
+        command_buffer.push_constants(
+            graphics_pipeline.raw_layout(),
+            vk::ShaderStageFlags::FRAGMENT,
+            &*self.constants.read(),
+        );
+        command_buffer.draw(3, 1, 0, 0);
+
+        Ok(())
+    }
+
+    fn post_render(&self, _command_buffer: &CommandBuffer, _graph: &Graph) -> Result<()> {
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "Sky render pass"
+    }
+}
@@ -0,0 +1,454 @@
+use std::{mem::size_of, sync::Arc};
+
+use anyhow::{anyhow, Context, Result};
+use parking_lot::RwLock;
+
+use rikka_core::{
+    nalgebra::{Matrix4, Vector3, Vector4},
+    vk,
+};
+use rikka_gpu::{buffer::*, command_buffer::CommandBuffer, descriptor_set::*};
+use rikka_graph::{graph::Graph, types::*};
+
+use crate::{renderer::*, scene};
+
+/// Technique drawing the terrain patches, descriptor set 0 bindings: "scene_constants" and "terrain" uniform buffers.
+/// Vertex binding 0 contains interleaved `GpuTerrainVertex` data.
+pub const TERRAIN_TECHNIQUE_FILE_PATH: &str = "data/terrain.json";
+
+/// Render graph node the terrain pass is registered to
+pub const TERRAIN_PASS_NAME: &str = "terrain_pass";
+
+/// Grid of normalized heights, sampled with bilinear filtering.
+pub struct Heightmap {
+    width: u32,
+    height: u32,
+    heights: Vec<f32>,
+}
+
+impl Heightmap {
+    pub fn new(width: u32, height: u32, heights: Vec<f32>) -> Result<Self> {
+        if width < 2 || height < 2 {
+            return Err(anyhow!(
+                "Heightmap needs at least 2x2 samples, got {}x{}",
+                width,
+                height
+            ));
+        }
+        if heights.len() != (width * height) as usize {
+            return Err(anyhow!(
+                "Heightmap of {}x{} samples has {} heights",
+                width,
+                height,
+                heights.len()
+            ));
+        }
+
+        Ok(Self {
+            width,
+            height,
+            heights,
+        })
+    }
+
+    /// Loads a grayscale image, 16 bit images keep their full precision
+    pub fn load_from_file(file_name: &str) -> Result<Self> {
+        let image = image::open(file_name)
+            .with_context(|| format!("Failed to load heightmap {}", file_name))?
+            .into_luma16();
+
+        let heights = image
+            .pixels()
+            .map(|pixel| pixel.0[0] as f32 / u16::MAX as f32)
+            .collect();
+
+        Self::new(image.width(), image.height(), heights)
+    }
+
+    /// Creates a heightmap from a single channel 8 bit image, e.g. `procedural::white_noise`
+    pub fn from_r8(width: u32, height: u32, data: &[u8]) -> Result<Self> {
+        let heights = data
+            .iter()
+            .map(|value| *value as f32 / u8::MAX as f32)
+            .collect();
+        Self::new(width, height, heights)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn texel(&self, x: u32, y: u32) -> f32 {
+        self.heights[(y.min(self.height - 1) * self.width + x.min(self.width - 1)) as usize]
+    }
+
+    /// Bilinearly filtered height at the normalized coordinates, clamped to the edges
+    pub fn sample(&self, u: f32, v: f32) -> f32 {
+        let x = u.clamp(0.0, 1.0) * (self.width - 1) as f32;
+        let y = v.clamp(0.0, 1.0) * (self.height - 1) as f32;
+        let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+        let (fx, fy) = (x.fract(), y.fract());
+
+        let top = self.texel(x0, y0) * (1.0 - fx) + self.texel(x0 + 1, y0) * fx;
+        let bottom = self.texel(x0, y0 + 1) * (1.0 - fx) + self.texel(x0 + 1, y0 + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+pub struct TerrainDesc {
+    /// World space extent along x and z, the terrain is centered at the origin
+    pub size: f32,
+    pub height_scale: f32,
+    /// Patches along each side
+    pub patch_count: u32,
+    /// Quads along each side of a full detail patch, a power of two
+    pub patch_resolution: u32,
+    /// Each level halves the patch resolution
+    pub lod_count: u32,
+    /// Patches closer than this distance use full detail, the distance doubles for every LOD level
+    pub lod_distance: f32,
+}
+
+impl TerrainDesc {
+    pub fn new() -> Self {
+        Self {
+            size: 256.0,
+            height_scale: 32.0,
+            patch_count: 16,
+            patch_resolution: 32,
+            lod_count: 4,
+            lod_distance: 32.0,
+        }
+    }
+
+    pub fn set_size(mut self, size: f32) -> Self {
+        self.size = size;
+        self
+    }
+
+    pub fn set_height_scale(mut self, height_scale: f32) -> Self {
+        self.height_scale = height_scale;
+        self
+    }
+
+    pub fn set_patch_count(mut self, patch_count: u32) -> Self {
+        self.patch_count = patch_count;
+        self
+    }
+
+    pub fn set_patch_resolution(mut self, patch_resolution: u32) -> Self {
+        self.patch_resolution = patch_resolution;
+        self
+    }
+
+    pub fn set_lod_count(mut self, lod_count: u32) -> Self {
+        self.lod_count = lod_count;
+        self
+    }
+
+    pub fn set_lod_distance(mut self, lod_distance: f32) -> Self {
+        self.lod_distance = lod_distance;
+        self
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.patch_count == 0 {
+            return Err(anyhow!("Terrain needs at least one patch"));
+        }
+        if !self.patch_resolution.is_power_of_two() {
+            return Err(anyhow!(
+                "Terrain patch resolution {} is not a power of two",
+                self.patch_resolution
+            ));
+        }
+        let coarsest_lod_step = self
+            .lod_count
+            .checked_sub(1)
+            .and_then(|shift| 1_u32.checked_shl(shift));
+        if coarsest_lod_step.map_or(true, |step| step > self.patch_resolution) {
+            return Err(anyhow!(
+                "Terrain LOD count {} is invalid for a patch resolution of {}",
+                self.lod_count,
+                self.patch_resolution
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct GpuTerrainVertex {
+    pub position: [f32; 3],
+    pub normal: [f32; 3],
+    pub tex_coords: [f32; 2],
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GpuTerrainData {
+    model: Matrix4<f32>,
+    base_color_factor: Vector4<f32>,
+}
+
+/// Range of the index buffer drawing a patch at one LOD level
+#[derive(Clone, Copy)]
+struct PatchLodRange {
+    index_offset: u32,
+    index_count: u32,
+}
+
+struct TerrainPatch {
+    bounds: scene::Aabb,
+    lod_ranges: Vec<PatchLodRange>,
+}
+
+/// Heightmap terrain split into patches, each patch selects its LOD level from the distance to the camera.
+/// The geometry of all LOD levels is generated on the CPU when the terrain is created.
+// XXX: Generate the patches with tessellation or compute shaders
+// XXX: Neighbouring patches with different LOD levels can show cracks, add skirts or stitch the patch edges
+pub struct TerrainPass {
+    technique: Arc<RenderTechnique>,
+    descriptor_set: Arc<DescriptorSet>,
+    vertex_buffer: Handle<Buffer>,
+    index_buffer: Handle<Buffer>,
+
+    patches: Arc<Vec<TerrainPatch>>,
+    /// Shared with the render pass registered to the graph
+    selected_lods: Arc<RwLock<Vec<u32>>>,
+
+    bounds: scene::Aabb,
+    lod_distance: f32,
+}
+
+impl TerrainPass {
+    pub fn new(
+        renderer: &mut Renderer,
+        render_graph: &Graph,
+        heightmap: &Heightmap,
+        desc: TerrainDesc,
+        scene_uniform_buffer: Handle<Buffer>,
+    ) -> Result<Self> {
+        desc.validate()?;
+
+        let technique = renderer
+            .create_technique_from_file(TERRAIN_TECHNIQUE_FILE_PATH, render_graph)
+            .context("Failed to load terrain technique")?;
+
+        let vertices = Self::generate_vertices(heightmap, &desc);
+        let (indices, patches) = Self::generate_patches(&vertices, &desc);
+
+        let mut bounds = scene::Aabb::empty();
+        for patch in &patches {
+            bounds.extend(&patch.bounds);
+        }
+
+        let vertex_buffer = renderer.create_buffer_from_data(
+            BufferDesc::new().set_usage_flags(vk::BufferUsageFlags::VERTEX_BUFFER),
+            &vertices,
+        )?;
+        let index_buffer = renderer.create_buffer_from_data(
+            BufferDesc::new().set_usage_flags(vk::BufferUsageFlags::INDEX_BUFFER),
+            &indices,
+        )?;
+
+        let terrain_buffer = renderer.create_buffer(
+            BufferDesc::new()
+                .set_size(size_of::<GpuTerrainData>() as _)
+                .set_usage_flags(vk::BufferUsageFlags::UNIFORM_BUFFER)
+                .set_device_only(false),
+        )?;
+        terrain_buffer.copy_data_to_buffer(&[GpuTerrainData {
+            model: Matrix4::identity(),
+            base_color_factor: Vector4::new(1.0, 1.0, 1.0, 1.0),
+        }])?;
+
+        let descriptor_set_layout =
+            technique.pass(0).graphics_pipeline.descriptor_set_layouts()[0].clone();
+        let descriptor_set = renderer.create_descriptor_set(
+            DescriptorSetDesc::new(descriptor_set_layout)
+                .add_buffer_resource_named("scene_constants", scene_uniform_buffer)
+                .add_buffer_resource_named("terrain", terrain_buffer),
+        )?;
+
+        log::info!(
+            "Created terrain with {} vertices and {} patches",
+            vertices.len(),
+            patches.len()
+        );
+
+        let selected_lods = Arc::new(RwLock::new(vec![0; patches.len()]));
+
+        Ok(Self {
+            technique,
+            descriptor_set,
+            vertex_buffer,
+            index_buffer,
+            patches: Arc::new(patches),
+            selected_lods,
+            bounds,
+            lod_distance: desc.lod_distance,
+        })
+    }
+
+    /// Full detail vertex grid shared by all patches and LOD levels
+    fn generate_vertices(heightmap: &Heightmap, desc: &TerrainDesc) -> Vec<GpuTerrainVertex> {
+        let resolution = desc.patch_count * desc.patch_resolution;
+        let step = 1.0 / resolution as f32;
+        let quad_size = desc.size * step;
+        let height_at =
+            |x: u32, z: u32| heightmap.sample(x as f32 * step, z as f32 * step) * desc.height_scale;
+
+        let mut vertices = Vec::with_capacity(((resolution + 1) * (resolution + 1)) as usize);
+        for z in 0..=resolution {
+            for x in 0..=resolution {
+                let height = height_at(x, z);
+
+                // Central differences, one sided at the edges
+                let (left, right) = (x.saturating_sub(1), (x + 1).min(resolution));
+                let (back, front) = (z.saturating_sub(1), (z + 1).min(resolution));
+                let dx = (height_at(right, z) - height_at(left, z))
+                    / ((right - left) as f32 * quad_size);
+                let dz = (height_at(x, front) - height_at(x, back))
+                    / ((front - back) as f32 * quad_size);
+                let normal = Vector3::new(-dx, 1.0, -dz).normalize();
+
+                vertices.push(GpuTerrainVertex {
+                    position: [
+                        (x as f32 * step - 0.5) * desc.size,
+                        height,
+                        (z as f32 * step - 0.5) * desc.size,
+                    ],
+                    normal: [normal.x, normal.y, normal.z],
+                    tex_coords: [x as f32 * step, z as f32 * step],
+                });
+            }
+        }
+
+        vertices
+    }
+
+    /// Indices of every patch at every LOD level, a LOD level skips every other vertex of the previous level
+    fn generate_patches(
+        vertices: &[GpuTerrainVertex],
+        desc: &TerrainDesc,
+    ) -> (Vec<u32>, Vec<TerrainPatch>) {
+        let row_length = desc.patch_count * desc.patch_resolution + 1;
+        let mut indices = Vec::new();
+        let mut patches = Vec::with_capacity((desc.patch_count * desc.patch_count) as usize);
+
+        for patch_z in 0..desc.patch_count {
+            for patch_x in 0..desc.patch_count {
+                let origin_x = patch_x * desc.patch_resolution;
+                let origin_z = patch_z * desc.patch_resolution;
+
+                let mut bounds = scene::Aabb::empty();
+                for z in origin_z..=origin_z + desc.patch_resolution {
+                    for x in origin_x..=origin_x + desc.patch_resolution {
+                        let position = vertices[(z * row_length + x) as usize].position;
+                        bounds.extend_point(&Vector3::from(position));
+                    }
+                }
+
+                let mut lod_ranges = Vec::with_capacity(desc.lod_count as usize);
+                for lod in 0..desc.lod_count {
+                    let stride = 1 << lod;
+                    let index_offset = indices.len() as u32;
+
+                    for z in (origin_z..origin_z + desc.patch_resolution).step_by(stride) {
+                        for x in (origin_x..origin_x + desc.patch_resolution).step_by(stride) {
+                            let stride = stride as u32;
+                            let top_left = z * row_length + x;
+                            let top_right = top_left + stride;
+                            let bottom_left = top_left + stride * row_length;
+                            let bottom_right = bottom_left + stride;
+
+                            indices.extend_from_slice(&[
+                                top_left,
+                                bottom_left,
+                                top_right,
+                                top_right,
+                                bottom_left,
+                                bottom_right,
+                            ]);
+                        }
+                    }
+
+                    lod_ranges.push(PatchLodRange {
+                        index_offset,
+                        index_count: indices.len() as u32 - index_offset,
+                    });
+                }
+
+                patches.push(TerrainPatch { bounds, lod_ranges });
+            }
+        }
+
+        (indices, patches)
+    }
+
+    /// Selects the LOD level of every patch from its distance to `eye_position`
+    pub fn update_lods(&self, eye_position: &Vector3<f32>) {
+        let mut selected_lods = self.selected_lods.write();
+        for (patch, selected_lod) in self.patches.iter().zip(selected_lods.iter_mut()) {
+            let distance = patch.bounds.distance_to_point(eye_position);
+            let lod = (distance / self.lod_distance).max(1.0).log2().floor() as u32;
+            *selected_lod = lod.min(patch.lod_ranges.len() as u32 - 1);
+        }
+    }
+
+    pub fn bounds(&self) -> scene::Aabb {
+        self.bounds
+    }
+
+    pub fn create_render_pass(&self) -> Box<dyn RenderPass> {
+        Box::new(TerrainRenderPass {
+            technique: self.technique.clone(),
+            descriptor_set: self.descriptor_set.clone(),
+            vertex_buffer: self.vertex_buffer.clone(),
+            index_buffer: self.index_buffer.clone(),
+            patches: self.patches.clone(),
+            selected_lods: self.selected_lods.clone(),
+        })
+    }
+}
+
+struct TerrainRenderPass {
+    technique: Arc<RenderTechnique>,
+    descriptor_set: Arc<DescriptorSet>,
+    vertex_buffer: Handle<Buffer>,
+    index_buffer: Handle<Buffer>,
+    patches: Arc<Vec<TerrainPatch>>,
+    selected_lods: Arc<RwLock<Vec<u32>>>,
+}
+
+impl RenderPass for TerrainRenderPass {
+    fn render(&self, command_buffer: &CommandBuffer) -> Result<()> {
+        let graphics_pipeline = self.technique.pass(0).graphics_pipeline;
+
+        command_buffer.bind_graphics_pipeline(&graphics_pipeline);
+        command_buffer.bind_descriptor_set(&self.descriptor_set, graphics_pipeline.raw_layout(), 0);
+        command_buffer.bind_vertex_buffer(&self.vertex_buffer, 0, 0);
+        command_buffer.bind_index_buffer(&self.index_buffer, 0, vk::IndexType::UINT32);
+
+        let selected_lods = self.selected_lods.read();
+        for (patch, lod) in self.patches.iter().zip(selected_lods.iter()) {
+            let range = &patch.lod_ranges[*lod as usize];
+            command_buffer.draw_indexed(range.index_count, 1, range.index_offset, 0, 0);
+        }
+
+        Ok(())
+    }
+
+    fn post_render(&self, _command_buffer: &CommandBuffer, _graph: &Graph) -> Result<()> {
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "Terrain render pass"
+    }
+}
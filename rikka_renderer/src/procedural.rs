@@ -0,0 +1,128 @@
+/// Xorshift generator, procedural textures only need cheap deterministic noise
+struct Rng(u32);
+
+impl Rng {
+    fn new(seed: u32) -> Self {
+        // Zero is a fixed point of xorshift
+        Self(seed.max(1))
+    }
+
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+}
+
+/// RGBA8 pixels of a single color
+pub fn solid_color(width: u32, height: u32, color: [u8; 4]) -> Vec<u8> {
+    color.repeat((width * height) as usize)
+}
+
+/// RGBA8 checkerboard with square cells of `cell_size` pixels
+pub fn checkerboard(width: u32, height: u32, cell_size: u32, colors: [[u8; 4]; 2]) -> Vec<u8> {
+    let cell_size = cell_size.max(1);
+    (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x / cell_size + y / cell_size) % 2))
+        .flat_map(|cell| colors[cell as usize])
+        .collect()
+}
+
+/// R8 uniformly distributed white noise
+pub fn white_noise(width: u32, height: u32, seed: u32) -> Vec<u8> {
+    let mut rng = Rng::new(seed);
+    (0..width * height)
+        .map(|_| (rng.next() >> 24) as u8)
+        .collect()
+}
+
+/// Tileable R8 blue noise generated with the void and cluster method, meant for small dither textures such as
+/// 64x64 since generation is quadratic in the pixel count
+pub fn blue_noise(size: u32, seed: u32) -> Vec<u8> {
+    const SIGMA: f32 = 1.5;
+    const INITIAL_DENSITY: f32 = 0.1;
+
+    let size = size.max(2) as usize;
+    let pixel_count = size * size;
+
+    // Gaussian energy contributed by a pixel at a toroidal offset
+    let kernel = (0..pixel_count)
+        .map(|offset| {
+            let wrap = |d: usize| d.min(size - d) as f32;
+            let (dx, dy) = (wrap(offset % size), wrap(offset / size));
+            (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp()
+        })
+        .collect::<Vec<_>>();
+    let kernel_offset = |from: usize, to: usize| {
+        let dx = (to % size + size - from % size) % size;
+        let dy = (to / size + size - from / size) % size;
+        dy * size + dx
+    };
+
+    let mut pattern = vec![false; pixel_count];
+    let mut energy = vec![0.0_f32; pixel_count];
+    let mut toggle = |pattern: &mut Vec<bool>, energy: &mut Vec<f32>, pixel: usize| {
+        pattern[pixel] = !pattern[pixel];
+        let sign = if pattern[pixel] { 1.0 } else { -1.0 };
+        for (other, other_energy) in energy.iter_mut().enumerate() {
+            *other_energy += sign * kernel[kernel_offset(pixel, other)];
+        }
+    };
+    // Tightest cluster is the set pixel with the highest energy, largest void the unset pixel with the lowest
+    let tightest_cluster = |pattern: &[bool], energy: &[f32]| {
+        (0..pixel_count)
+            .filter(|pixel| pattern[*pixel])
+            .max_by(|a, b| energy[*a].total_cmp(&energy[*b]))
+    };
+    let largest_void = |pattern: &[bool], energy: &[f32]| {
+        (0..pixel_count)
+            .filter(|pixel| !pattern[*pixel])
+            .min_by(|a, b| energy[*a].total_cmp(&energy[*b]))
+    };
+
+    let mut rng = Rng::new(seed);
+    let initial_count = ((pixel_count as f32 * INITIAL_DENSITY) as usize).max(1);
+    let mut set_count = 0;
+    while set_count < initial_count {
+        let pixel = rng.next() as usize % pixel_count;
+        if !pattern[pixel] {
+            toggle(&mut pattern, &mut energy, pixel);
+            set_count += 1;
+        }
+    }
+
+    // Spread the initial pattern until moving the tightest cluster does not change it anymore
+    loop {
+        let cluster = tightest_cluster(&pattern, &energy).unwrap();
+        toggle(&mut pattern, &mut energy, cluster);
+        let void = largest_void(&pattern, &energy).unwrap();
+        toggle(&mut pattern, &mut energy, void);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0; pixel_count];
+
+    // Rank the initial pattern by removing its tightest clusters
+    let (initial_pattern, initial_energy) = (pattern.clone(), energy.clone());
+    for rank in (0..initial_count).rev() {
+        let cluster = tightest_cluster(&pattern, &energy).unwrap();
+        toggle(&mut pattern, &mut energy, cluster);
+        ranks[cluster] = rank;
+    }
+
+    // Rank the remaining pixels by filling the largest voids
+    let (mut pattern, mut energy) = (initial_pattern, initial_energy);
+    for rank in initial_count..pixel_count {
+        let void = largest_void(&pattern, &energy).unwrap();
+        toggle(&mut pattern, &mut energy, void);
+        ranks[void] = rank;
+    }
+
+    ranks
+        .into_iter()
+        .map(|rank| (rank * 256 / pixel_count) as u8)
+        .collect()
+}
@@ -1,44 +1,174 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::{Context, Result};
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 
 use rikka_core::vk;
 use rikka_gpu::{
     buffer::*, command_buffer::*, descriptor_set::*, gpu::Gpu, image::*, pipeline::*, sampler::*,
+    types::TransferFunction,
 };
 use rikka_graph::graph::Graph;
 
-use crate::loader;
+use crate::{loader, procedural, schedule::ComputeSchedule, stats::FrameStats};
 
 pub use rikka_gpu::escape::Handle;
 
-pub struct RenderTechniqueDesc {
+#[derive(Clone)]
+struct RenderTechniquePassDesc {
+    permutation_defines: Vec<String>,
+    /// Indexed by the bitmask of enabled permutation defines
     graphics_pipelines: Vec<GraphicsPipelineDesc>,
+}
+
+pub struct RenderTechniqueDesc {
+    passes: Vec<RenderTechniquePassDesc>,
     name: String,
 }
 
 impl RenderTechniqueDesc {
     pub fn new(name: String) -> Self {
         RenderTechniqueDesc {
-            graphics_pipelines: Vec::new(),
+            passes: Vec::new(),
             name,
         }
     }
 
     pub fn add_graphics_pipeline(mut self, graphics_pipeline: GraphicsPipelineDesc) -> Self {
-        self.graphics_pipelines.push(graphics_pipeline);
+        self.passes.push(RenderTechniquePassDesc {
+            permutation_defines: Vec::new(),
+            graphics_pipelines: vec![graphics_pipeline],
+        });
+        self
+    }
+
+    fn shader_file_names(&self) -> Vec<String> {
+        self.passes
+            .iter()
+            .flat_map(|pass| &pass.graphics_pipelines)
+            .flat_map(|graphics_pipeline| &graphics_pipeline.shader_state.stages)
+            .filter_map(|stage| stage.file_name.clone())
+            .collect()
+    }
+
+    /// `graphics_pipelines` are indexed by the bitmask of enabled `permutation_defines`
+    pub fn add_graphics_pipeline_permutations(
+        mut self,
+        permutation_defines: Vec<String>,
+        graphics_pipelines: Vec<GraphicsPipelineDesc>,
+    ) -> Self {
+        assert_eq!(graphics_pipelines.len(), 1 << permutation_defines.len());
+        self.passes.push(RenderTechniquePassDesc {
+            permutation_defines,
+            graphics_pipelines,
+        });
         self
     }
 }
 
+#[derive(Clone)]
 pub struct RenderTechniquePass {
     // XXX: Properly set struct member visiblity for this crate
+    /// Pipeline with none of the permutation defines enabled
     pub graphics_pipeline: Handle<GraphicsPipeline>,
+    permutation_defines: Vec<String>,
+    permutations: Vec<Handle<GraphicsPipeline>>,
+}
+
+impl RenderTechniquePass {
+    /// Returns the pipeline variant compiled with the given defines, defines that are not permutation defines of this pass are ignored
+    pub fn graphics_pipeline_variant(&self, defines: &[&str]) -> &Handle<GraphicsPipeline> {
+        let permutation = self
+            .permutation_defines
+            .iter()
+            .enumerate()
+            .filter(|(_, define)| defines.contains(&define.as_str()))
+            .fold(0, |permutation, (index, _)| permutation | (1 << index));
+
+        &self.permutations[permutation]
+    }
+
+    pub fn permutation_defines(&self) -> &[String] {
+        &self.permutation_defines
+    }
 }
 
 pub struct RenderTechnique {
-    pub passes: Vec<RenderTechniquePass>,
+    // Swapped when the technique is reloaded
+    passes: RwLock<Vec<RenderTechniquePass>>,
+    /// Kept to create polygon mode variants of the pipelines
+    pass_descs: RwLock<Vec<RenderTechniquePassDesc>>,
+    /// LINE polygon mode variants of `passes`, created on first use and dropped when the technique is reloaded
+    wireframe_passes: RwLock<Option<Vec<RenderTechniquePass>>>,
+    wireframe: AtomicBool,
+    /// Primitive topology variants of `passes` for meshes that are not triangle lists
+    topology_passes: RwLock<HashMap<vk::PrimitiveTopology, Vec<RenderTechniquePass>>>,
+}
+
+impl RenderTechnique {
+    fn new(passes: Vec<RenderTechniquePass>, pass_descs: Vec<RenderTechniquePassDesc>) -> Self {
+        Self {
+            passes: RwLock::new(passes),
+            pass_descs: RwLock::new(pass_descs),
+            wireframe_passes: RwLock::new(None),
+            wireframe: AtomicBool::new(false),
+            topology_passes: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the wireframe variant of the pass if wireframe is enabled for this technique
+    pub fn pass(&self, index: usize) -> RenderTechniquePass {
+        if self.wireframe() {
+            if let Some(wireframe_passes) = self.wireframe_passes.read().as_ref() {
+                return wireframe_passes[index].clone();
+            }
+        }
+        self.passes.read()[index].clone()
+    }
+
+    /// Returns the variant of the pass created by `Renderer::create_technique_topology_variant`, falls back to
+    /// `pass` for triangle lists or topologies without a variant
+    pub fn pass_with_topology(
+        &self,
+        index: usize,
+        primitive_topology: vk::PrimitiveTopology,
+    ) -> RenderTechniquePass {
+        if primitive_topology != vk::PrimitiveTopology::TRIANGLE_LIST {
+            if let Some(passes) = self.topology_passes.read().get(&primitive_topology) {
+                return passes[index].clone();
+            }
+        }
+        self.pass(index)
+    }
+
+    pub fn pass_count(&self) -> usize {
+        self.passes.read().len()
+    }
+
+    fn replace_passes(
+        &self,
+        passes: Vec<RenderTechniquePass>,
+        pass_descs: Vec<RenderTechniquePassDesc>,
+    ) {
+        *self.passes.write() = passes;
+        *self.pass_descs.write() = pass_descs;
+        *self.wireframe_passes.write() = None;
+    }
+
+    fn topology_variants(&self) -> Vec<vk::PrimitiveTopology> {
+        self.topology_passes.read().keys().copied().collect()
+    }
+
+    pub fn wireframe(&self) -> bool {
+        self.wireframe.load(Ordering::Relaxed)
+    }
 }
 
 pub struct MaterialDesc {
@@ -69,6 +199,11 @@ pub struct Material {
 pub struct Renderer {
     gpu: Gpu,
     render_techniques: RwLock<HashMap<String, Arc<RenderTechnique>>>,
+    technique_file_watcher: Mutex<loader::technique::TechniqueFileWatcher>,
+    /// Stats of the last completed frame
+    frame_stats: FrameStats,
+    /// Stats of the frame that is currently recorded
+    pending_frame_stats: FrameStats,
 }
 
 impl Renderer {
@@ -76,6 +211,9 @@ impl Renderer {
         Renderer {
             gpu,
             render_techniques: RwLock::new(HashMap::new()),
+            technique_file_watcher: Mutex::new(loader::technique::TechniqueFileWatcher::new()),
+            frame_stats: FrameStats::default(),
+            pending_frame_stats: FrameStats::default(),
         }
     }
 
@@ -87,29 +225,53 @@ impl Renderer {
         &mut self.gpu
     }
 
-    pub fn begin_frame(&mut self) -> Result<()> {
+    /// Returns false if the frame should be skipped, eg. when the window is minimized.
+    pub fn begin_frame(&mut self) -> Result<bool> {
         self.gpu.new_frame()?;
-        if let Err(_) = self.gpu.swapchain_acquire_next_image() {
-            self.gpu.recreate_swapchain()?;
-            self.gpu.advance_frame_counters();
-        }
-
-        Ok(())
+        self.pending_frame_stats
+            .set_gpu_timestamps(self.gpu.gpu_timestamps());
+        Ok(self.gpu.swapchain_acquire_next_image()?)
     }
 
     pub fn end_frame(&mut self) -> Result<()> {
+        let submit_start = Instant::now();
+
         self.gpu.submit_queued_graphics_command_buffers()?;
 
-        self.gpu.present().unwrap_or_else(|_| {
-            self.gpu.wait_idle();
-            false
-        });
+        if !self.gpu.present()? {
+            log::info!("Swapchain out of date after presentation");
+        }
+
+        self.pending_frame_stats.cpu_submit_ms = submit_start.elapsed().as_secs_f32() * 1000.0;
+        self.frame_stats = std::mem::take(&mut self.pending_frame_stats);
 
         Ok(())
     }
 
+    /// Cpu timings measured by the caller for the frame that is currently recorded
+    pub fn set_cpu_frame_timings(&mut self, update_ms: f32, record_ms: f32) {
+        self.pending_frame_stats.cpu_update_ms = update_ms;
+        self.pending_frame_stats.cpu_record_ms = record_ms;
+    }
+
+    pub fn frame_stats(&self) -> &FrameStats {
+        &self.frame_stats
+    }
+
+    pub fn invalidate_swapchain(&mut self) {
+        self.gpu.invalidate_swapchain();
+    }
+
     pub fn set_present_mode(&mut self, present_mode: vk::PresentModeKHR) -> Result<()> {
-        self.gpu.set_present_mode(present_mode)
+        Ok(self.gpu.set_present_mode(present_mode)?)
+    }
+
+    pub fn present_mode(&self) -> vk::PresentModeKHR {
+        self.gpu.present_mode()
+    }
+
+    pub fn supported_present_modes(&self) -> Result<Vec<vk::PresentModeKHR>> {
+        Ok(self.gpu.supported_present_modes()?)
     }
 
     pub fn aspect_ratio(&self) -> f32 {
@@ -121,31 +283,137 @@ impl Renderer {
         self.gpu.swapchain_extent()
     }
 
+    pub fn is_headless(&self) -> bool {
+        self.gpu.is_headless()
+    }
+
+    /// Reads back the last rendered frame when running headless and saves it to an image file.
+    pub fn save_output_image(&mut self, file_name: &str) -> Result<()> {
+        let extent = self.gpu.swapchain_extent();
+        let data = self.gpu.read_output_image()?;
+
+        ::image::save_buffer(
+            file_name,
+            &data,
+            extent.width,
+            extent.height,
+            ::image::ColorType::Rgba8,
+        )
+        .context("Failed to save output image")?;
+
+        Ok(())
+    }
+
+    /// Transfer function the final output needs to be tonemapped/encoded for.
+    pub fn output_transfer_function(&self) -> TransferFunction {
+        self.gpu.swapchain_transfer_function()
+    }
+
     pub fn create_buffer(&self, desc: BufferDesc) -> Result<Handle<Buffer>> {
         Ok(self.gpu.create_buffer(desc)?)
     }
 
+    /// Creates a device local buffer sized to `data` and uploads `data` to it through a staging buffer. The size and
+    /// memory location of `desc` are overridden.
+    pub fn create_buffer_from_data<T: Copy>(
+        &self,
+        desc: BufferDesc,
+        data: &[T],
+    ) -> Result<Handle<Buffer>> {
+        let size = std::mem::size_of_val(data).max(1);
+
+        let staging_buffer =
+            self.create_buffer(BufferDesc::new().set_size(size as _).set_device_only(false))?;
+        staging_buffer.copy_data_to_buffer(data)?;
+
+        let buffer = self.create_buffer(desc.set_size(size as _).set_device_only(true))?;
+        self.gpu.copy_buffer(&staging_buffer, &buffer)?;
+
+        Ok(buffer)
+    }
+
     pub fn create_image(&mut self, desc: ImageDesc) -> Result<Handle<Image>> {
         Ok(self.gpu.create_image(desc)?)
     }
 
+    /// Creates a sampled image from tightly packed pixel data and registers it in the bindless array
+    pub fn create_image_from_data<T: Copy>(
+        &mut self,
+        desc: ImageDesc,
+        data: &[T],
+    ) -> Result<Handle<Image>> {
+        Ok(self.gpu.create_image_from_data(desc, data)?)
+    }
+
+    /// Same as `create_image_from_data`, with the data of all mip levels packed starting with the most detailed one
+    pub fn create_image_with_mips_from_data<T: Copy>(
+        &mut self,
+        desc: ImageDesc,
+        data: &[T],
+    ) -> Result<Handle<Image>> {
+        Ok(self.gpu.create_image_with_mips_from_data(desc, data)?)
+    }
+
+    /// Replaces the data of all mip levels of an image created by `create_image_with_mips_from_data`
+    pub fn update_image_with_mips_from_data<T: Copy>(
+        &self,
+        image: &Handle<Image>,
+        data: &[T],
+    ) -> Result<()> {
+        Ok(self.gpu.update_image_with_mips_from_data(image, data)?)
+    }
+
+    pub fn create_solid_color_image(&mut self, color: [u8; 4]) -> Result<Handle<Image>> {
+        self.create_image_from_data(
+            ImageDesc::new(1, 1, 1).set_format(vk::Format::R8G8B8A8_UNORM),
+            &procedural::solid_color(1, 1, color),
+        )
+    }
+
+    pub fn create_checkerboard_image(
+        &mut self,
+        size: u32,
+        cell_size: u32,
+        colors: [[u8; 4]; 2],
+    ) -> Result<Handle<Image>> {
+        self.create_image_from_data(
+            ImageDesc::new(size, size, 1).set_format(vk::Format::R8G8B8A8_UNORM),
+            &procedural::checkerboard(size, size, cell_size, colors),
+        )
+    }
+
+    /// Single channel white noise
+    pub fn create_noise_image(&mut self, size: u32, seed: u32) -> Result<Handle<Image>> {
+        self.create_image_from_data(
+            ImageDesc::new(size, size, 1).set_format(vk::Format::R8_UNORM),
+            &procedural::white_noise(size, size, seed),
+        )
+    }
+
+    /// Single channel tileable blue noise for dithering and temporal sampling
+    pub fn create_blue_noise_image(&mut self, size: u32, seed: u32) -> Result<Handle<Image>> {
+        self.create_image_from_data(
+            ImageDesc::new(size, size, 1).set_format(vk::Format::R8_UNORM),
+            &procedural::blue_noise(size, seed),
+        )
+    }
+
+    /// Creates an image writable from compute shaders through the bindless storage image array
+    pub fn create_storage_image(&mut self, desc: ImageDesc) -> Result<Handle<Image>> {
+        Ok(self.gpu.create_storage_image(desc)?)
+    }
+
+    pub fn create_image_view(&self, desc: ImageViewDesc) -> Result<Handle<ImageView>> {
+        Ok(self.gpu.create_image_view(desc)?)
+    }
+
     pub fn create_sampler(&self, desc: SamplerDesc) -> Result<Handle<Sampler>> {
         Ok(self.gpu.create_sampler(desc)?)
     }
 
     pub fn create_technique(&self, desc: RenderTechniqueDesc) -> Result<Arc<RenderTechnique>> {
-        let graphics_pipelines = desc
-            .graphics_pipelines
-            .into_iter()
-            .map(|graphics_pipeline_desc| self.gpu.create_graphics_pipeline(graphics_pipeline_desc))
-            .collect::<Result<Vec<_>>>()?;
-
-        let passes = graphics_pipelines
-            .into_iter()
-            .map(|graphics_pipeline| RenderTechniquePass { graphics_pipeline })
-            .collect::<Vec<_>>();
-
-        let technique = Arc::new(RenderTechnique { passes });
+        let passes = self.create_technique_passes(&desc.passes, None, None)?;
+        let technique = Arc::new(RenderTechnique::new(passes, desc.passes));
 
         self.render_techniques
             .write()
@@ -154,6 +422,153 @@ impl Renderer {
         Ok(technique)
     }
 
+    /// `polygon_mode` and `primitive_topology` override the state of all pipelines if set
+    fn create_technique_passes(
+        &self,
+        pass_descs: &[RenderTechniquePassDesc],
+        polygon_mode: Option<vk::PolygonMode>,
+        primitive_topology: Option<vk::PrimitiveTopology>,
+    ) -> Result<Vec<RenderTechniquePass>> {
+        pass_descs
+            .iter()
+            .map(|pass_desc| {
+                let permutations = pass_desc
+                    .graphics_pipelines
+                    .iter()
+                    .map(|graphics_pipeline_desc| {
+                        let mut graphics_pipeline_desc = graphics_pipeline_desc.clone();
+                        if let Some(polygon_mode) = polygon_mode {
+                            graphics_pipeline_desc.rasterization_state.polygon_mode = polygon_mode;
+                        }
+                        if let Some(primitive_topology) = primitive_topology {
+                            graphics_pipeline_desc.primitive_topology = primitive_topology;
+                        }
+                        Ok(self.gpu.create_graphics_pipeline(graphics_pipeline_desc)?)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+
+                Ok(RenderTechniquePass {
+                    graphics_pipeline: permutations[0].clone(),
+                    permutation_defines: pass_desc.permutation_defines.clone(),
+                    permutations,
+                })
+            })
+            .collect()
+    }
+
+    /// Swaps the pipelines of the technique for LINE polygon mode variants, the variants are created on first use and cached
+    pub fn set_technique_wireframe(
+        &self,
+        technique: &RenderTechnique,
+        enabled: bool,
+    ) -> Result<()> {
+        if enabled && technique.wireframe_passes.read().is_none() {
+            if !self.gpu.device_features().fill_mode_non_solid {
+                return Err(anyhow::anyhow!(
+                    "Wireframe rendering requires the fillModeNonSolid device feature"
+                ));
+            }
+
+            let wireframe_passes = self.create_technique_passes(
+                &technique.pass_descs.read(),
+                Some(vk::PolygonMode::LINE),
+                None,
+            )?;
+            *technique.wireframe_passes.write() = Some(wireframe_passes);
+        }
+
+        technique.wireframe.store(enabled, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Creates pipelines of the technique for meshes with another primitive topology, returned by
+    /// `RenderTechnique::pass_with_topology`. Does nothing if the variant already exists.
+    pub fn create_technique_topology_variant(
+        &self,
+        technique: &RenderTechnique,
+        primitive_topology: vk::PrimitiveTopology,
+    ) -> Result<()> {
+        if primitive_topology == vk::PrimitiveTopology::TRIANGLE_LIST
+            || technique
+                .topology_passes
+                .read()
+                .contains_key(&primitive_topology)
+        {
+            return Ok(());
+        }
+
+        let passes = self.create_technique_passes(
+            &technique.pass_descs.read(),
+            None,
+            Some(primitive_topology),
+        )?;
+        technique
+            .topology_passes
+            .write()
+            .insert(primitive_topology, passes);
+
+        Ok(())
+    }
+
+    /// Rebuilds techniques whose files were modified since they were loaded, the new pipelines are used from the next recorded frame.
+    /// Techniques that fail to reload keep their previous pipelines. Returns the file names of the reloaded techniques.
+    pub fn reload_modified_techniques(&self, render_graph: &Graph) -> Vec<String> {
+        let modified_techniques = self.technique_file_watcher.lock().poll_modified();
+        let mut reloaded_techniques = Vec::new();
+
+        for (file_name, technique) in modified_techniques {
+            log::info!("Reloading render technique {}", file_name);
+
+            let passes = loader::technique::parse_from_file(&file_name, self, render_graph)
+                .and_then(|desc| {
+                    // Shader sources may have changed along with the technique
+                    self.gpu.invalidate_shader_states(&desc.shader_file_names());
+                    let passes = self.create_technique_passes(&desc.passes, None, None)?;
+                    Ok((passes, desc.passes))
+                });
+
+            match passes {
+                Ok((passes, pass_descs)) => {
+                    // Old pipelines are destroyed by the Gpu once the frames in flight using them completed
+                    let topology_variants = technique.topology_variants();
+                    technique.replace_passes(passes, pass_descs);
+                    technique.topology_passes.write().clear();
+
+                    for primitive_topology in topology_variants {
+                        if let Err(err) =
+                            self.create_technique_topology_variant(&technique, primitive_topology)
+                        {
+                            log::error!(
+                                "Failed to recreate {:?} pipelines of {}: {:?}",
+                                primitive_topology,
+                                file_name,
+                                err
+                            );
+                        }
+                    }
+
+                    if technique.wireframe() {
+                        if let Err(err) = self.set_technique_wireframe(&technique, true) {
+                            log::error!(
+                                "Failed to recreate wireframe pipelines of {}: {:?}",
+                                file_name,
+                                err
+                            );
+                        }
+                    }
+
+                    reloaded_techniques.push(file_name);
+                }
+                Err(err) => {
+                    log::error!("Failed to reload render technique {}: {:?}", file_name, err)
+                }
+            }
+        }
+
+        reloaded_techniques
+    }
+
     pub fn create_technique_from_file(
         &self,
         file_name: &str,
@@ -162,7 +577,12 @@ impl Renderer {
         let desc = loader::technique::parse_from_file(file_name, self, render_graph)
             .context("Failed to parse render technique file")?;
 
-        self.create_technique(desc)
+        let technique = self.create_technique(desc)?;
+        self.technique_file_watcher
+            .lock()
+            .watch(file_name, &technique);
+
+        Ok(technique)
     }
 
     pub fn get_render_technique(&self, name: &str) -> Result<Arc<RenderTechnique>> {
@@ -187,17 +607,45 @@ impl Renderer {
     }
 
     pub fn command_buffer(&mut self, thread_index: u32) -> Result<Arc<CommandBuffer>> {
-        self.gpu.current_command_buffer(thread_index)
+        Ok(self.gpu.current_command_buffer(thread_index)?)
     }
 
     pub fn queue_command_buffer(&mut self, command_buffer: Arc<CommandBuffer>) {
+        self.pending_frame_stats
+            .add_draw_stats(command_buffer.draw_stats());
         self.gpu.queue_graphics_command_buffer(command_buffer);
     }
 
-    /// XXX: Resource OBRM/RAII is not completely "safe" as they can be destroyed when used.
-    ///      Need a resource system tracker in the Gpu for this, or at least have a simple sender/receiver to delay
-    ///      object destruction until the end of the current frame
-    ///      Currently we just wait idle before dropping any resources but this needs to be removed
+    /// Fails if the Gpu does not support async compute
+    pub fn compute_command_buffer(&self) -> Result<Arc<CommandBuffer>> {
+        Ok(self.gpu.current_compute_command_buffer()?)
+    }
+
+    pub fn queue_compute_command_buffer(&mut self, command_buffer: Arc<CommandBuffer>) {
+        self.gpu.queue_compute_command_buffer(command_buffer);
+    }
+
+    pub fn async_compute_supported(&self) -> bool {
+        self.gpu.async_compute_supported()
+    }
+
+    /// Schedule of the compute workloads of the frame that is currently recorded
+    pub fn set_compute_schedule(&mut self, compute_schedule: ComputeSchedule) {
+        self.pending_frame_stats.compute_schedule = compute_schedule;
+    }
+
+    /// Graphics timeline value signaled once the work of the current frame completes
+    pub fn signal_value_after_submit(&self) -> u64 {
+        self.gpu.signal_value_after_submit()
+    }
+
+    /// Waits for the graphics timeline to reach `value`, returns false on timeout
+    pub fn wait_for_value(&self, value: u64, timeout: Duration) -> Result<bool> {
+        Ok(self.gpu.wait_for_value(value, timeout)?)
+    }
+
+    /// Dropped resources are destroyed once the frames in flight completed, waiting is only needed before
+    /// `Gpu::force_cleanup` or when resources are modified in place
     pub fn wait_idle(&self) {
         self.gpu.wait_idle();
     }
@@ -1,6 +1,8 @@
+use std::collections::HashMap;
+
 use anyhow::Result;
 
-use rikka_core::nalgebra::Matrix4;
+use rikka_core::nalgebra::{Matrix4, Point3, Vector3};
 
 pub const INVALID_INDEX: usize = usize::MAX;
 const MAX_SCENE_LEVEL: usize = 32;
@@ -38,12 +40,26 @@ impl Default for Hierarchy {
     }
 }
 
+/// Name, tags and visibility of a scene graph node
+#[derive(Clone, Debug, Default)]
+pub struct NodeMetadata {
+    /// Name of the source scene node, names are not unique
+    pub name: Option<String>,
+    pub tags: Vec<String>,
+    /// Hides the node with all its children
+    pub hidden: bool,
+}
+
 /// Scene transformation graph.
 pub struct Graph {
     pub local_matrices: Vec<Matrix4<f32>>,
     pub global_matrices: Vec<Matrix4<f32>>,
     pub nodes_hierarchy: Vec<Hierarchy>,
     pub changed_nodes: [Vec<usize>; MAX_SCENE_LEVEL],
+
+    nodes_metadata: Vec<NodeMetadata>,
+    /// Nodes with the name in ascending order
+    name_to_nodes: HashMap<String, Vec<usize>>,
 }
 
 impl Graph {
@@ -53,6 +69,8 @@ impl Graph {
             global_matrices: Vec::new(),
             nodes_hierarchy: Vec::new(),
             changed_nodes: Default::default(),
+            nodes_metadata: Vec::new(),
+            name_to_nodes: HashMap::new(),
         }
     }
 
@@ -62,15 +80,21 @@ impl Graph {
             global_matrices: vec![Matrix4::identity(); num_nodes],
             nodes_hierarchy: vec![Hierarchy::default(); num_nodes],
             changed_nodes: Default::default(),
+            nodes_metadata: vec![NodeMetadata::default(); num_nodes],
+            name_to_nodes: HashMap::new(),
         }
     }
 
+    pub fn node_count(&self) -> usize {
+        self.nodes_hierarchy.len()
+    }
+
     pub fn calculate_transforms(&mut self) -> Result<()> {
         let mut num_changed_nodes = 0;
         for level in 0..MAX_SCENE_LEVEL {
             num_changed_nodes += self.changed_nodes[level].len();
         }
-        log::info!("Scene graph number of changed nodes: {}", num_changed_nodes);
+        log::trace!("Scene graph number of changed nodes: {}", num_changed_nodes);
 
         for level in 0..MAX_SCENE_LEVEL {
             // if !self.changed_nodes[level].is_empty() {
@@ -129,6 +153,7 @@ impl Graph {
             level,
             ..Default::default()
         });
+        self.nodes_metadata.push(NodeMetadata::default());
 
         self.set_child_and_sibling_indices(node, parent);
 
@@ -148,6 +173,103 @@ impl Graph {
         self.set_child_and_sibling_indices(node, parent);
     }
 
+    pub fn metadata(&self, node: usize) -> &NodeMetadata {
+        &self.nodes_metadata[node]
+    }
+
+    pub fn name(&self, node: usize) -> Option<&str> {
+        self.nodes_metadata[node].name.as_deref()
+    }
+
+    pub fn set_name(&mut self, node: usize, name: Option<&str>) {
+        if let Some(old_name) = self.nodes_metadata[node].name.take() {
+            if let Some(nodes) = self.name_to_nodes.get_mut(&old_name) {
+                nodes.retain(|&named_node| named_node != node);
+                if nodes.is_empty() {
+                    self.name_to_nodes.remove(&old_name);
+                }
+            }
+        }
+
+        if let Some(name) = name {
+            let nodes = self.name_to_nodes.entry(name.to_string()).or_default();
+            let position = nodes.partition_point(|&named_node| named_node < node);
+            nodes.insert(position, node);
+        }
+        self.nodes_metadata[node].name = name.map(String::from);
+    }
+
+    /// Node with the lowest index named `name`
+    pub fn find_node(&self, name: &str) -> Option<usize> {
+        self.find_nodes(name).first().copied()
+    }
+
+    /// All nodes named `name` in ascending order
+    pub fn find_nodes(&self, name: &str) -> &[usize] {
+        self.name_to_nodes
+            .get(name)
+            .map_or(&[], |nodes| nodes.as_slice())
+    }
+
+    /// (node, name) of all named nodes in ascending node order
+    pub fn named_nodes(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.nodes_metadata
+            .iter()
+            .enumerate()
+            .filter_map(|(node, metadata)| Some((node, metadata.name.as_deref()?)))
+    }
+
+    /// Mesh visibility is not updated, use `SceneRenderer::set_node_visible` outside of the scene renderer
+    pub(crate) fn set_visible(&mut self, node: usize, visible: bool) {
+        self.nodes_metadata[node].hidden = !visible;
+    }
+
+    /// False if the node or one of its parents is hidden
+    pub fn visible_in_hierarchy(&self, node: usize) -> bool {
+        let mut current_node = node;
+        while current_node != INVALID_INDEX {
+            if self.nodes_metadata[current_node].hidden {
+                return false;
+            }
+            current_node = self.nodes_hierarchy[current_node].parent;
+        }
+        true
+    }
+
+    /// Returns false if the node already has the tag
+    pub fn add_tag(&mut self, node: usize, tag: &str) -> bool {
+        if self.has_tag(node, tag) {
+            return false;
+        }
+
+        self.nodes_metadata[node].tags.push(tag.to_string());
+        true
+    }
+
+    /// Returns false if the node does not have the tag
+    pub fn remove_tag(&mut self, node: usize, tag: &str) -> bool {
+        let tags = &mut self.nodes_metadata[node].tags;
+        let tag_count = tags.len();
+        tags.retain(|node_tag| node_tag != tag);
+        tags.len() != tag_count
+    }
+
+    pub fn has_tag(&self, node: usize, tag: &str) -> bool {
+        self.nodes_metadata[node]
+            .tags
+            .iter()
+            .any(|node_tag| node_tag == tag)
+    }
+
+    /// Nodes with `tag` in ascending order
+    pub fn nodes_with_tag<'a>(&'a self, tag: &'a str) -> impl Iterator<Item = usize> + 'a {
+        self.nodes_metadata
+            .iter()
+            .enumerate()
+            .filter(move |(_, metadata)| metadata.tags.iter().any(|node_tag| node_tag == tag))
+            .map(|(node, _)| node)
+    }
+
     /// Set parent's child and sibling indices
     fn set_child_and_sibling_indices(&mut self, node: usize, parent: usize) {
         self.nodes_hierarchy[node].next_sibling = INVALID_INDEX;
@@ -174,3 +296,549 @@ impl Graph {
         }
     }
 }
+
+/// Point light of the scene.
+// XXX: The scene renderer shades a single light
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PointLight {
+    pub position: Vector3<f32>,
+    pub range: f32,
+    pub intensity: f32,
+}
+
+/// Axis aligned bounding box.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl Aabb {
+    pub fn new(min: Vector3<f32>, max: Vector3<f32>) -> Self {
+        Self { min, max }
+    }
+
+    /// Bounds that contain nothing, extending it with any point yields that point
+    pub fn empty() -> Self {
+        Self {
+            min: Vector3::repeat(f32::MAX),
+            max: Vector3::repeat(f32::MIN),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn extend_point(&mut self, point: &Vector3<f32>) {
+        self.min = self.min.inf(point);
+        self.max = self.max.sup(point);
+    }
+
+    pub fn extend(&mut self, other: &Aabb) {
+        if other.is_empty() {
+            return;
+        }
+        self.extend_point(&other.min);
+        self.extend_point(&other.max);
+    }
+
+    /// Bounds of all 8 transformed corners
+    pub fn transform(&self, matrix: &Matrix4<f32>) -> Aabb {
+        let mut aabb = Aabb::empty();
+        if self.is_empty() {
+            return aabb;
+        }
+
+        for corner in 0..8 {
+            let point = Point3::new(
+                if corner & 1 == 0 {
+                    self.min.x
+                } else {
+                    self.max.x
+                },
+                if corner & 2 == 0 {
+                    self.min.y
+                } else {
+                    self.max.y
+                },
+                if corner & 4 == 0 {
+                    self.min.z
+                } else {
+                    self.max.z
+                },
+            );
+            aabb.extend_point(&matrix.transform_point(&point).coords);
+        }
+
+        aabb
+    }
+
+    pub fn center(&self) -> Vector3<f32> {
+        (self.min + self.max) * 0.5
+    }
+
+    /// Radius of the bounding sphere around `center`
+    pub fn radius(&self) -> f32 {
+        (self.max - self.min).norm() * 0.5
+    }
+
+    /// Zero if the point is inside
+    pub fn distance_to_point(&self, point: &Vector3<f32>) -> f32 {
+        let closest_point = point.sup(&self.min).inf(&self.max);
+        (point - closest_point).norm()
+    }
+}
+
+/// Ray with a normalized direction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ray {
+    pub origin: Vector3<f32>,
+    pub direction: Vector3<f32>,
+}
+
+impl Ray {
+    pub fn new(origin: Vector3<f32>, direction: Vector3<f32>) -> Self {
+        Self {
+            origin,
+            direction: direction.normalize(),
+        }
+    }
+
+    pub fn at(&self, distance: f32) -> Vector3<f32> {
+        self.origin + self.direction * distance
+    }
+}
+
+impl Aabb {
+    /// Distance along the ray to the entry point, zero if the origin is inside.
+    /// Returns None if the ray misses or enters further than `max_distance`.
+    pub fn ray_intersection(&self, ray: &Ray, max_distance: f32) -> Option<f32> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let mut t_min = 0.0f32;
+        let mut t_max = max_distance;
+        for axis in 0..3 {
+            let inverse_direction = 1.0 / ray.direction[axis];
+            let mut t0 = (self.min[axis] - ray.origin[axis]) * inverse_direction;
+            let mut t1 = (self.max[axis] - ray.origin[axis]) * inverse_direction;
+            if inverse_direction < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+
+            t_min = t_min.max(t0);
+            t_max = t_max.min(t1);
+            if t_max < t_min {
+                return None;
+            }
+        }
+
+        Some(t_min)
+    }
+}
+
+/// Distance along the ray to a two-sided triangle.
+pub fn ray_triangle_intersection(ray: &Ray, triangle: &[Vector3<f32>; 3]) -> Option<f32> {
+    let edge0 = triangle[1] - triangle[0];
+    let edge1 = triangle[2] - triangle[0];
+
+    let p = ray.direction.cross(&edge1);
+    let determinant = edge0.dot(&p);
+    if determinant.abs() < f32::EPSILON {
+        return None;
+    }
+    let inverse_determinant = 1.0 / determinant;
+
+    let s = ray.origin - triangle[0];
+    let u = s.dot(&p) * inverse_determinant;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+
+    let q = s.cross(&edge0);
+    let v = ray.direction.dot(&q) * inverse_determinant;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let distance = edge1.dot(&q) * inverse_determinant;
+    (distance >= 0.0).then_some(distance)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RayHit {
+    /// Index of the bounds or triangle the Bvh was built from
+    pub primitive_index: usize,
+    pub distance: f32,
+}
+
+const MAX_BVH_LEAF_PRIMITIVES: usize = 4;
+
+#[derive(Clone, Copy)]
+struct BvhNode {
+    bounds: Aabb,
+    /// Index of the first child for interior nodes, the second child directly follows it.
+    /// Offset into the primitive indices for leaves.
+    first: usize,
+    /// Zero for interior nodes
+    primitive_count: usize,
+}
+
+/// Bounding volume hierarchy for CPU ray casts, built over bounding boxes or the triangles of a mesh.
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    primitive_indices: Vec<usize>,
+    primitive_bounds: Vec<Aabb>,
+    /// Empty for Bvhs built over bounding boxes, hits are then reported at the box entry distance
+    triangles: Vec<[Vector3<f32>; 3]>,
+}
+
+impl Bvh {
+    pub fn from_bounds(bounds: Vec<Aabb>) -> Self {
+        Self::build(bounds, Vec::new())
+    }
+
+    /// Primitive indices of hits are triangle indices, i.e. the index of the first vertex index divided by 3
+    pub fn from_triangles(positions: &[Vector3<f32>], indices: &[u32]) -> Self {
+        let triangles = indices
+            .chunks_exact(3)
+            .map(|triangle| {
+                [
+                    positions[triangle[0] as usize],
+                    positions[triangle[1] as usize],
+                    positions[triangle[2] as usize],
+                ]
+            })
+            .collect::<Vec<_>>();
+        let bounds = triangles
+            .iter()
+            .map(|triangle| {
+                let mut bounds = Aabb::empty();
+                triangle
+                    .iter()
+                    .for_each(|vertex| bounds.extend_point(vertex));
+                bounds
+            })
+            .collect();
+
+        Self::build(bounds, triangles)
+    }
+
+    fn build(primitive_bounds: Vec<Aabb>, triangles: Vec<[Vector3<f32>; 3]>) -> Self {
+        let mut bvh = Self {
+            nodes: Vec::new(),
+            primitive_indices: (0..primitive_bounds.len()).collect(),
+            primitive_bounds,
+            triangles,
+        };
+        if bvh.primitive_bounds.is_empty() {
+            return bvh;
+        }
+
+        bvh.nodes.push(BvhNode {
+            bounds: Aabb::empty(),
+            first: 0,
+            primitive_count: bvh.primitive_indices.len(),
+        });
+
+        let mut stack = vec![0];
+        while let Some(node_index) = stack.pop() {
+            if let Some(first_child) = bvh.subdivide(node_index) {
+                stack.push(first_child);
+                stack.push(first_child + 1);
+            }
+        }
+
+        bvh
+    }
+
+    /// Computes the node bounds and splits it at the median centroid of its longest centroid axis.
+    /// Returns the index of the first child if the node was split.
+    fn subdivide(&mut self, node_index: usize) -> Option<usize> {
+        let BvhNode {
+            first,
+            primitive_count,
+            ..
+        } = self.nodes[node_index];
+        let primitives = &mut self.primitive_indices[first..first + primitive_count];
+
+        let mut bounds = Aabb::empty();
+        let mut centroid_bounds = Aabb::empty();
+        for primitive in primitives.iter() {
+            let primitive_bounds = &self.primitive_bounds[*primitive];
+            bounds.extend(primitive_bounds);
+            if !primitive_bounds.is_empty() {
+                centroid_bounds.extend_point(&primitive_bounds.center());
+            }
+        }
+        self.nodes[node_index].bounds = bounds;
+
+        if primitive_count <= MAX_BVH_LEAF_PRIMITIVES || centroid_bounds.is_empty() {
+            return None;
+        }
+
+        let extent = centroid_bounds.max - centroid_bounds.min;
+        let axis = extent.imax();
+        if extent[axis] <= 0.0 {
+            return None;
+        }
+
+        let primitive_bounds = &self.primitive_bounds;
+        let split = primitive_count / 2;
+        primitives.select_nth_unstable_by(split, |a, b| {
+            primitive_bounds[*a].center()[axis].total_cmp(&primitive_bounds[*b].center()[axis])
+        });
+
+        let first_child = self.nodes.len();
+        self.nodes.push(BvhNode {
+            bounds: Aabb::empty(),
+            first,
+            primitive_count: split,
+        });
+        self.nodes.push(BvhNode {
+            bounds: Aabb::empty(),
+            first: first + split,
+            primitive_count: primitive_count - split,
+        });
+        self.nodes[node_index].first = first_child;
+        self.nodes[node_index].primitive_count = 0;
+
+        Some(first_child)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes.first().map_or(Aabb::empty(), |root| root.bounds)
+    }
+
+    /// Closest hit along the ray starting at `origin`, `direction` does not have to be normalized
+    pub fn ray_cast(&self, origin: Vector3<f32>, direction: Vector3<f32>) -> Option<RayHit> {
+        let ray = Ray::new(origin, direction);
+        self.ray_cast_with(
+            &ray,
+            f32::MAX,
+            |primitive_index, bounds_distance| match self.triangles.get(primitive_index) {
+                Some(triangle) => ray_triangle_intersection(&ray, triangle),
+                None => Some(bounds_distance),
+            },
+        )
+    }
+
+    /// Closest hit within `max_distance` with a custom primitive test, `intersect` receives the primitive index and
+    /// the distance to its bounds and returns the hit distance.
+    pub fn ray_cast_with<F>(&self, ray: &Ray, max_distance: f32, mut intersect: F) -> Option<RayHit>
+    where
+        F: FnMut(usize, f32) -> Option<f32>,
+    {
+        let mut closest_hit: Option<RayHit> = None;
+        let mut closest_distance = max_distance;
+
+        let mut stack = Vec::new();
+        if !self.is_empty() {
+            stack.push(0);
+        }
+
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if node
+                .bounds
+                .ray_intersection(ray, closest_distance)
+                .is_none()
+            {
+                continue;
+            }
+
+            if node.primitive_count == 0 {
+                // Visit the nearer child first so farther subtrees are culled by the closer hit
+                let near = node.first;
+                let far = node.first + 1;
+                let near_distance = self.nodes[near]
+                    .bounds
+                    .ray_intersection(ray, closest_distance);
+                let far_distance = self.nodes[far]
+                    .bounds
+                    .ray_intersection(ray, closest_distance);
+                match (near_distance, far_distance) {
+                    (Some(near_distance), Some(far_distance)) if far_distance < near_distance => {
+                        stack.push(near);
+                        stack.push(far);
+                    }
+                    (Some(_), Some(_)) => {
+                        stack.push(far);
+                        stack.push(near);
+                    }
+                    (Some(_), None) => stack.push(near),
+                    (None, Some(_)) => stack.push(far),
+                    (None, None) => {}
+                }
+                continue;
+            }
+
+            for primitive_index in
+                &self.primitive_indices[node.first..node.first + node.primitive_count]
+            {
+                let bounds_distance = match self.primitive_bounds[*primitive_index]
+                    .ray_intersection(ray, closest_distance)
+                {
+                    Some(distance) => distance,
+                    None => continue,
+                };
+
+                if let Some(distance) = intersect(*primitive_index, bounds_distance) {
+                    if distance <= closest_distance {
+                        closest_distance = distance;
+                        closest_hit = Some(RayHit {
+                            primitive_index: *primitive_index,
+                            distance,
+                        });
+                    }
+                }
+            }
+        }
+
+        closest_hit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_box(center: Vector3<f32>) -> Aabb {
+        let half_extent = Vector3::new(0.5, 0.5, 0.5);
+        Aabb::new(center - half_extent, center + half_extent)
+    }
+
+    /// Boxes along the x axis at x = 0, 2, 4, ...
+    fn boxes_along_x(count: usize) -> Vec<Aabb> {
+        (0..count)
+            .map(|index| unit_box(Vector3::new(index as f32 * 2.0, 0.0, 0.0)))
+            .collect()
+    }
+
+    /// Two triangles per cell of a `size` x `size` grid in the z = 0 plane
+    fn grid(size: u32) -> (Vec<Vector3<f32>>, Vec<u32>) {
+        let mut positions = Vec::new();
+        for y in 0..=size {
+            for x in 0..=size {
+                positions.push(Vector3::new(x as f32, y as f32, 0.0));
+            }
+        }
+
+        let mut indices = Vec::new();
+        for y in 0..size {
+            for x in 0..size {
+                let corner = y * (size + 1) + x;
+                indices.extend_from_slice(&[corner, corner + 1, corner + size + 1]);
+                indices.extend_from_slice(&[corner + 1, corner + size + 2, corner + size + 1]);
+            }
+        }
+
+        (positions, indices)
+    }
+
+    #[test]
+    fn test_bvh_empty() {
+        let bvh = Bvh::from_bounds(Vec::new());
+        assert!(bvh.is_empty());
+        assert!(bvh.bounds().is_empty());
+        assert_eq!(
+            bvh.ray_cast(Vector3::zeros(), Vector3::new(1.0, 0.0, 0.0)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_bvh_ray_cast_closest_bounds() {
+        let bounds = boxes_along_x(32);
+        let bvh = Bvh::from_bounds(bounds.clone());
+
+        let mut expected_bounds = Aabb::empty();
+        bounds
+            .iter()
+            .for_each(|bounds| expected_bounds.extend(bounds));
+        assert_eq!(bvh.bounds(), expected_bounds);
+
+        // From the left the first box is hit, from the right the last one
+        let hit = bvh
+            .ray_cast(Vector3::new(-10.0, 0.0, 0.0), Vector3::new(2.0, 0.0, 0.0))
+            .unwrap();
+        assert_eq!(hit.primitive_index, 0);
+        assert!((hit.distance - 9.5).abs() < 1e-5);
+
+        let hit = bvh
+            .ray_cast(Vector3::new(100.0, 0.0, 0.0), Vector3::new(-1.0, 0.0, 0.0))
+            .unwrap();
+        assert_eq!(hit.primitive_index, 31);
+        assert!((hit.distance - 37.5).abs() < 1e-5);
+
+        // Origins inside a box hit it at distance zero
+        let hit = bvh
+            .ray_cast(Vector3::new(20.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0))
+            .unwrap();
+        assert_eq!(hit.primitive_index, 10);
+        assert_eq!(hit.distance, 0.0);
+
+        assert_eq!(
+            bvh.ray_cast(Vector3::new(0.0, 2.0, 0.0), Vector3::new(1.0, 0.0, 0.0)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_bvh_ray_cast_with_filter() {
+        let bvh = Bvh::from_bounds(boxes_along_x(8));
+        let ray = Ray::new(Vector3::new(-10.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0));
+
+        let hit = bvh
+            .ray_cast_with(&ray, f32::MAX, |primitive_index, bounds_distance| {
+                (primitive_index >= 3).then_some(bounds_distance)
+            })
+            .unwrap();
+        assert_eq!(hit.primitive_index, 3);
+
+        // Boxes further than the maximum distance are not hit
+        assert_eq!(
+            bvh.ray_cast_with(&ray, 5.0, |_, bounds_distance| Some(bounds_distance)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_bvh_ray_cast_triangles() {
+        let size = 16;
+        let (positions, indices) = grid(size);
+        let bvh = Bvh::from_triangles(&positions, &indices);
+
+        for (x, y) in [(0.25, 0.25), (0.75, 0.75), (7.2, 3.1), (15.9, 15.5)] {
+            let hit = bvh
+                .ray_cast(Vector3::new(x, y, 5.0), Vector3::new(0.0, 0.0, -1.0))
+                .unwrap();
+            assert!((hit.distance - 5.0).abs() < 1e-5);
+
+            let triangle = hit.primitive_index * 3;
+            let triangle = [
+                positions[indices[triangle] as usize],
+                positions[indices[triangle + 1] as usize],
+                positions[indices[triangle + 2] as usize],
+            ];
+            let ray = Ray::new(Vector3::new(x, y, 5.0), Vector3::new(0.0, 0.0, -1.0));
+            assert!(ray_triangle_intersection(&ray, &triangle).is_some());
+        }
+
+        // Parallel to the grid and outside of it
+        assert_eq!(
+            bvh.ray_cast(Vector3::new(0.5, 0.5, 1.0), Vector3::new(1.0, 0.0, 0.0)),
+            None
+        );
+        assert_eq!(
+            bvh.ray_cast(Vector3::new(20.0, 20.0, 5.0), Vector3::new(0.0, 0.0, -1.0)),
+            None
+        );
+    }
+}
@@ -0,0 +1,337 @@
+use std::{collections::HashMap, mem::size_of, sync::Arc};
+
+use anyhow::{Context, Result};
+
+use rikka_core::{
+    nalgebra::{Matrix4, Vector3, Vector4},
+    vk,
+};
+use rikka_gpu::{buffer::*, escape::Handle, image::*, sampler::*};
+
+use crate::{
+    loader::{asynchronous::*, cooked::*, streaming::TextureStreamer},
+    renderer::*,
+    scene,
+    scene_renderer::{gltf::*, material::*, mesh::*, meshlet::*},
+};
+
+fn cooked_texture_image(
+    texture_ref: &Option<CookedTextureRef>,
+    gpu_images: &[Handle<Image>],
+    gpu_samplers: &[Handle<Sampler>],
+) -> Option<Handle<Image>> {
+    let texture_ref = texture_ref.as_ref()?;
+    let image = gpu_images[texture_ref.texture_index as usize].clone();
+
+    if texture_ref.sampler_index != INVALID_COOKED_INDEX {
+        image.set_linked_sampler(gpu_samplers[texture_ref.sampler_index as usize].clone());
+    }
+
+    Some(image)
+}
+
+/// Color spaces of the cooked textures based on the material slots that reference them
+fn cooked_texture_color_spaces(scene: &CookedScene) -> Vec<TextureColorSpace> {
+    let references = scene.materials.iter().flat_map(|material| {
+        [
+            (&material.diffuse_texture, TextureColorSpace::Srgb),
+            (
+                &material.metallic_roughness_texture,
+                TextureColorSpace::Linear,
+            ),
+            (&material.normal_texture, TextureColorSpace::Linear),
+            (&material.occlusion_texture, TextureColorSpace::Linear),
+        ]
+        .into_iter()
+        .filter_map(|(texture_ref, color_space)| {
+            texture_ref
+                .as_ref()
+                .map(|texture_ref| (texture_ref.texture_index as usize, color_space))
+        })
+    });
+
+    TextureColorSpace::classify(references, scene.textures.len())
+}
+
+/// Copies `count` elements at a byte offset out of the merged cooked buffer
+fn read_cooked_buffer<T: Copy>(buffer: &[u8], offset: u32, count: u32) -> Vec<T> {
+    let data = &buffer[offset as usize..][..count as usize * std::mem::size_of::<T>()];
+    (0..count as usize)
+        .map(|index| unsafe { std::ptr::read_unaligned((data.as_ptr() as *const T).add(index)) })
+        .collect()
+}
+
+/// Adds the pre-built meshlets of a cooked primitive and generates its LOD levels
+fn add_cooked_primitive_meshlets_and_lods(
+    renderer: &mut Renderer,
+    scene: &CookedScene,
+    primitive: &CookedPrimitive,
+    mesh: &mut Mesh,
+    mesh_index: u32,
+    scene_meshlets: &mut SceneMeshlets,
+) -> Result<()> {
+    let cooked_meshlets =
+        &scene.meshlets[primitive.meshlet_offset as usize..][..primitive.meshlet_count as usize];
+
+    // Rebase the meshlets onto the data of this primitive only
+    let (vertex_base, triangle_base) = cooked_meshlets.first().map_or((0, 0), |meshlet| {
+        (meshlet.vertex_offset, meshlet.triangle_offset)
+    });
+    let (vertex_end, triangle_end) = cooked_meshlets.last().map_or((0, 0), |meshlet| {
+        (
+            meshlet.vertex_offset + meshlet.vertex_count,
+            meshlet.triangle_offset + meshlet.triangle_count * 3,
+        )
+    });
+    let meshlets = Meshlets {
+        meshlets: cooked_meshlets
+            .iter()
+            .map(|meshlet| Meshlet {
+                vertex_offset: meshlet.vertex_offset - vertex_base,
+                triangle_offset: meshlet.triangle_offset - triangle_base,
+                vertex_count: meshlet.vertex_count,
+                triangle_count: meshlet.triangle_count,
+                center: Vector3::from(meshlet.center),
+                radius: meshlet.radius,
+            })
+            .collect(),
+        vertices: scene.meshlet_vertices[vertex_base as usize..vertex_end as usize].to_vec(),
+        triangles: scene.meshlet_triangles[triangle_base as usize..triangle_end as usize].to_vec(),
+    };
+
+    let positions = read_cooked_buffer::<[f32; 3]>(
+        &scene.buffer,
+        primitive.position_offset,
+        primitive.vertex_count,
+    );
+    let normals = read_cooked_buffer::<[f32; 3]>(
+        &scene.buffer,
+        primitive.normal_offset,
+        primitive.vertex_count,
+    );
+    let tangents = if primitive.tangent_offset != INVALID_COOKED_INDEX {
+        read_cooked_buffer::<[f32; 4]>(
+            &scene.buffer,
+            primitive.tangent_offset,
+            primitive.vertex_count,
+        )
+    } else {
+        Vec::new()
+    };
+    let tex_coords = read_cooked_buffer::<[f32; 2]>(
+        &scene.buffer,
+        primitive.tex_coords_offset,
+        primitive.vertex_count,
+    );
+
+    let first_index = scene_meshlets.indices.len() as u32;
+    mesh.meshlet_vertex_offset = scene_meshlets.vertex_positions.len() as u32;
+    mesh.meshlet_offset = scene_meshlets.add_mesh(
+        mesh_index,
+        &MeshletVertexAttributes {
+            positions: &positions,
+            normals: &normals,
+            tangents: &tangents,
+            tex_coords: &tex_coords,
+        },
+        &meshlets,
+    );
+    mesh.meshlet_count = primitive.meshlet_count;
+    mesh.meshlet_first_index = first_index;
+    mesh.meshlet_index_count = scene_meshlets.indices.len() as u32 - first_index;
+
+    let indices = if primitive.index_size == 2 {
+        read_cooked_buffer::<u16>(&scene.buffer, primitive.index_offset, primitive.index_count)
+            .into_iter()
+            .map(u32::from)
+            .collect::<Vec<_>>()
+    } else {
+        read_cooked_buffer::<u32>(&scene.buffer, primitive.index_offset, primitive.index_count)
+    };
+    GltfScene::create_mesh_lods(
+        renderer,
+        mesh,
+        mesh_index,
+        &indices,
+        &positions,
+        scene_meshlets,
+    )
+}
+
+impl GltfScene {
+    /// Creates the Gpu resources of a cooked scene, all geometry is uploaded in a single buffer and textures are
+    /// streamed in by `async_loader`
+    pub fn new_from_cooked_data(
+        renderer: &mut Renderer,
+        scene_data: CookedSceneData,
+        render_technique: &Arc<RenderTechnique>,
+        async_loader: &mut AsynchronousLoader,
+        texture_streamer: &mut TextureStreamer,
+    ) -> Result<Self> {
+        let CookedSceneData {
+            root_path_buf,
+            scene,
+        } = scene_data;
+
+        let color_spaces = cooked_texture_color_spaces(&scene);
+        let mut gpu_images = Vec::with_capacity(scene.textures.len());
+        for (texture, color_space) in scene.textures.iter().zip(color_spaces) {
+            let texture_path = root_path_buf.join(&texture.file_name);
+            gpu_images.push(Self::create_image(
+                renderer,
+                texture_path
+                    .to_str()
+                    .context("Invalid cooked texture path")?,
+                color_space,
+                async_loader,
+                texture_streamer,
+            )?);
+        }
+
+        let mut gpu_samplers = Vec::with_capacity(scene.samplers.len());
+        for sampler in &scene.samplers {
+            gpu_samplers.push(
+                renderer.create_sampler(
+                    SamplerDesc::new()
+                        .set_min_filter(vk::Filter::from_raw(sampler.min_filter))
+                        .set_mag_filter(vk::Filter::from_raw(sampler.mag_filter)),
+                )?,
+            );
+        }
+
+        let gpu_buffer = Self::create_geometry_buffer(renderer, &scene.buffer)?;
+
+        // Nodes are stored in level order so parents are always added first
+        let mut scene_graph = scene::Graph::new();
+        for node in &scene.nodes {
+            let parent = if node.parent == INVALID_COOKED_INDEX {
+                scene::INVALID_INDEX
+            } else {
+                node.parent as usize
+            };
+            let node_index = scene_graph.add_node(parent, node.level as usize);
+            scene_graph.set_name(node_index, node.name.as_deref());
+        }
+
+        let mut meshes = Vec::new();
+        let mut pbr_materials: HashMap<u32, Arc<PBRMaterial>> = HashMap::new();
+        let mut scene_meshlets = SceneMeshlets::default();
+        for (node_index, node) in scene.nodes.iter().enumerate() {
+            scene_graph
+                .set_local_matrix(node_index, Matrix4::from_column_slice(&node.local_matrix));
+
+            if node.mesh_index == INVALID_COOKED_INDEX {
+                continue;
+            }
+
+            for primitive in &scene.meshes[node.mesh_index as usize].primitives {
+                let pbr_material = match pbr_materials.get(&primitive.material_index) {
+                    Some(pbr_material) => pbr_material.clone(),
+                    None => {
+                        let cooked_material = &scene.materials[primitive.material_index as usize];
+
+                        let mut pbr_material =
+                            Self::create_default_pbr_material(renderer, render_technique.clone())?;
+                        pbr_material.draw_flags =
+                            DrawFlags::from_bits_truncate(cooked_material.draw_flags);
+                        pbr_material.alpha_cutoff = cooked_material.alpha_cutoff;
+                        pbr_material.base_color_factor =
+                            Vector4::from(cooked_material.base_color_factor);
+                        pbr_material.metallic_roughness_occlusion_factor =
+                            Vector4::from(cooked_material.metallic_roughness_occlusion_factor);
+                        pbr_material.diffuse_image = cooked_texture_image(
+                            &cooked_material.diffuse_texture,
+                            &gpu_images,
+                            &gpu_samplers,
+                        );
+                        pbr_material.metallic_roughness_image = cooked_texture_image(
+                            &cooked_material.metallic_roughness_texture,
+                            &gpu_images,
+                            &gpu_samplers,
+                        );
+                        pbr_material.normal_image = cooked_texture_image(
+                            &cooked_material.normal_texture,
+                            &gpu_images,
+                            &gpu_samplers,
+                        );
+                        pbr_material.occlusion_image = cooked_texture_image(
+                            &cooked_material.occlusion_texture,
+                            &gpu_images,
+                            &gpu_samplers,
+                        );
+
+                        let pbr_material = Arc::new(pbr_material);
+                        pbr_materials.insert(primitive.material_index, pbr_material.clone());
+                        pbr_material
+                    }
+                };
+
+                let mut mesh = Mesh::new_with_pbr_material(pbr_material);
+
+                mesh.position_buffer = Some(gpu_buffer.clone());
+                mesh.position_offset = primitive.position_offset;
+                // Cooked positions are tightly packed
+                mesh.position_stride = size_of::<[f32; 3]>() as u32;
+                mesh.tex_coords_buffer = Some(gpu_buffer.clone());
+                mesh.tex_coords_offset = primitive.tex_coords_offset;
+                mesh.normal_buffer = Some(gpu_buffer.clone());
+                mesh.normal_offset = primitive.normal_offset;
+                if primitive.tangent_offset != INVALID_COOKED_INDEX {
+                    mesh.tangent_buffer = Some(gpu_buffer.clone());
+                    mesh.tangent_offset = primitive.tangent_offset;
+                }
+                if primitive.tex_coords_1_offset != INVALID_COOKED_INDEX {
+                    mesh.tex_coords_1_buffer = Some(gpu_buffer.clone());
+                    mesh.tex_coords_1_offset = primitive.tex_coords_1_offset;
+                    mesh.vertex_flags |= DrawFlags::HAS_TEXCOORDS_1;
+                }
+                if primitive.color_offset != INVALID_COOKED_INDEX {
+                    mesh.color_buffer = Some(gpu_buffer.clone());
+                    mesh.color_offset = primitive.color_offset;
+                    mesh.vertex_flags |= DrawFlags::HAS_COLORS;
+                }
+                mesh.index_buffer = Some(gpu_buffer.clone());
+                mesh.index_offset = primitive.index_offset;
+                mesh.index_type = if primitive.index_size == 2 {
+                    vk::IndexType::UINT16
+                } else {
+                    vk::IndexType::UINT32
+                };
+
+                mesh.vertex_count = primitive.vertex_count;
+                mesh.primitive_count = primitive.index_count;
+                add_cooked_primitive_meshlets_and_lods(
+                    renderer,
+                    &scene,
+                    primitive,
+                    &mut mesh,
+                    meshes.len() as u32,
+                    &mut scene_meshlets,
+                )?;
+                mesh.bounds = scene::Aabb::new(
+                    Vector3::from(primitive.bounds_min),
+                    Vector3::from(primitive.bounds_max),
+                );
+                mesh.scene_graph_node_index = node_index;
+
+                meshes.push(mesh);
+            }
+        }
+
+        log::info!(
+            "Created cooked scene with {} nodes, {} meshes and {} textures",
+            scene.nodes.len(),
+            meshes.len(),
+            gpu_images.len()
+        );
+
+        // XXX: Animations and skins are not cooked
+        Ok(Self {
+            meshes,
+            scene_graph,
+            meshlets: scene_meshlets,
+            animations: Vec::new(),
+            skins: Vec::new(),
+        })
+    }
+}
@@ -0,0 +1,120 @@
+use std::sync::Arc;
+
+use rikka_core::nalgebra::Vector3;
+use rikka_gpu::{
+    buffer::Buffer, command_buffer::CommandBuffer, descriptor_set::DescriptorSet,
+    pipeline::GraphicsPipeline,
+};
+
+use crate::{renderer::*, scene_renderer::mesh::*};
+
+/// State changes of a recorded draw list
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DrawListStats {
+    pub draw_count: u32,
+    pub pipeline_binds: u32,
+    /// Material changes between consecutive draws, materials are indexed without rebinding descriptor sets
+    pub material_changes: u32,
+}
+
+struct MeshDraw {
+    mesh: Arc<Mesh>,
+    graphics_pipeline: Handle<GraphicsPipeline>,
+    material_index: u32,
+    /// Squared view distance of the mesh bounds center
+    depth: f32,
+}
+
+/// Collects mesh draws and sorts them by pipeline, then material, then depth so state is only bound when it changes.
+/// Opaque draws are sorted front to back within a material to reduce overdraw.
+pub struct DrawListBuilder {
+    view_position: Vector3<f32>,
+    draws: Vec<MeshDraw>,
+}
+
+impl DrawListBuilder {
+    pub fn new(view_position: Vector3<f32>) -> Self {
+        Self {
+            view_position,
+            draws: Vec::new(),
+        }
+    }
+
+    pub fn add_mesh(&mut self, mesh: &Arc<Mesh>, graphics_pipeline: Handle<GraphicsPipeline>) {
+        let center = mesh
+            .global_model()
+            .transform_point(&mesh.bounds.center().into());
+
+        self.draws.push(MeshDraw {
+            mesh: mesh.clone(),
+            graphics_pipeline,
+            material_index: mesh.pbr_material.material_index(),
+            depth: (center.coords - self.view_position).norm_squared(),
+        });
+    }
+
+    pub fn build(mut self) -> DrawList {
+        self.draws.sort_by(|a, b| {
+            a.graphics_pipeline
+                .raw()
+                .cmp(&b.graphics_pipeline.raw())
+                .then_with(|| a.material_index.cmp(&b.material_index))
+                .then_with(|| a.depth.total_cmp(&b.depth))
+        });
+
+        DrawList { draws: self.draws }
+    }
+}
+
+/// Sorted mesh draws
+pub struct DrawList {
+    draws: Vec<MeshDraw>,
+}
+
+impl DrawList {
+    /// Records all draws, the pipeline with the material storage and bindless descriptor sets is only bound when it
+    /// differs from the previous draw
+    pub fn record(
+        &self,
+        command_buffer: &CommandBuffer,
+        material_descriptor_set: &DescriptorSet,
+        bindless_descriptor_set: &DescriptorSet,
+        zero_buffer: &Buffer,
+    ) -> DrawListStats {
+        let mut stats = DrawListStats::default();
+
+        let mut bound_pipeline = None;
+        let mut material_index = None;
+        for draw in &self.draws {
+            let graphics_pipeline = &draw.graphics_pipeline;
+
+            if bound_pipeline != Some(graphics_pipeline.raw()) {
+                command_buffer.bind_graphics_pipeline(graphics_pipeline);
+                // Pipelines of different techniques may not have compatible layouts
+                command_buffer.bind_descriptor_set(
+                    material_descriptor_set,
+                    graphics_pipeline.raw_layout(),
+                    0,
+                );
+                command_buffer.bind_descriptor_set(
+                    bindless_descriptor_set,
+                    graphics_pipeline.raw_layout(),
+                    1,
+                );
+                bound_pipeline = Some(graphics_pipeline.raw());
+                stats.pipeline_binds += 1;
+            }
+
+            if material_index != Some(draw.material_index) {
+                material_index = Some(draw.material_index);
+                stats.material_changes += 1;
+            }
+
+            draw.mesh
+                .draw(command_buffer, graphics_pipeline, zero_buffer);
+            stats.draw_count += 1;
+        }
+
+        stats
+    }
+}
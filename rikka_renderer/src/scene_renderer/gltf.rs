@@ -1,39 +1,148 @@
-use std::{collections::VecDeque, mem::size_of, path::PathBuf, sync::Arc, time::Instant};
+use std::{
+    collections::{HashMap, VecDeque},
+    mem::size_of,
+    path::PathBuf,
+    sync::Arc,
+    time::Instant,
+};
 
 use anyhow::{anyhow, Context, Result};
-use ddsfile::DxgiFormat;
-use gltf::{material::AlphaMode, Gltf};
+use gltf::{animation::util::ReadOutputs, material::AlphaMode, Gltf};
 
 use rikka_core::{
     nalgebra::{Matrix4, Vector3, Vector4},
     vk,
 };
-use rikka_gpu::{buffer::*, descriptor_set::*, escape::Handle, gpu::Gpu, image::*, sampler::*};
+use rikka_gpu::{
+    buffer::*, command_buffer::CopyBatch, escape::Handle, gpu::Gpu, image::*, sampler::*,
+};
 
 use crate::{
-    loader::asynchronous::*,
+    animation::*,
+    loader::{
+        asynchronous::*, basis, dds, hdr, scene::SceneLoadProgress, streaming::TextureStreamer,
+    },
     renderer::*,
     scene,
-    scene_renderer::{material::*, mesh::*},
+    scene_renderer::{
+        gpu_types::GpuSkinVertex, material::*, mesh::*, mesh_optimizer::*, meshlet::*,
+        tangent::generate_tangents,
+    },
 };
 
 pub struct GltfScene {
     pub meshes: Vec<Mesh>,
     pub scene_graph: scene::Graph,
+    pub meshlets: SceneMeshlets,
+    pub animations: Vec<AnimationClip>,
+    pub skins: Vec<Skin>,
+}
+
+/// Cpu side vertex and index data of a triangle list primitive, reordered for vertex cache and vertex fetch
+/// efficiency
+struct PrimitiveGeometry {
+    positions: Vec<[f32; 3]>,
+    normals: Vec<[f32; 3]>,
+    /// Empty if the primitive has no tangents
+    tangents: Vec<[f32; 4]>,
+    /// Empty if the primitive has no texture coordinates
+    tex_coords: Vec<[f32; 2]>,
+    indices: Vec<u32>,
+    /// Old to new vertex index, unreferenced vertices map to u32::MAX
+    remap: Vec<u32>,
 }
 
-fn dxgi_format_to_vulkan_format(dxgi_format: DxgiFormat) -> vk::Format {
-    match dxgi_format {
-        DxgiFormat::BC1_UNorm => vk::Format::BC1_RGBA_UNORM_BLOCK,
-        DxgiFormat::BC1_UNorm_sRGB => vk::Format::BC1_RGBA_SRGB_BLOCK,
-        DxgiFormat::BC3_UNorm => vk::Format::BC3_UNORM_BLOCK,
-        DxgiFormat::BC3_UNorm_sRGB => vk::Format::BC3_SRGB_BLOCK,
-        DxgiFormat::BC5_UNorm => vk::Format::BC5_UNORM_BLOCK,
-        _ => todo!(),
+/// Cpu side glTF data, loaded without Gpu access so it can be done on a background thread
+pub struct GltfSceneData {
+    root_path_buf: PathBuf,
+    gltf_file: Gltf,
+    buffers_data: Vec<Vec<u8>>,
+}
+
+impl GltfSceneData {
+    pub fn new_from_file(
+        file_name: &str,
+        mut progress: impl FnMut(SceneLoadProgress),
+    ) -> Result<Self> {
+        let mut root_path_buf = PathBuf::from(file_name);
+        // XXX: Assume asset paths are exactly on the same directory from the `.gLTF` file
+        //      Handle this more gracefully
+        root_path_buf.pop();
+
+        progress(SceneLoadProgress::Parsing);
+        let mut gltf_file = Gltf::open(file_name)?;
+
+        let gltf_blob = gltf_file.blob.take();
+        let buffers_data = Self::load_buffers_data(
+            &root_path_buf,
+            gltf_file.buffers(),
+            gltf_blob,
+            &mut progress,
+        )?;
+
+        Ok(Self {
+            root_path_buf,
+            gltf_file,
+            buffers_data,
+        })
+    }
+
+    fn load_buffers_data(
+        root_path_buf: &PathBuf,
+        buffers: gltf::iter::Buffers,
+        blob: Option<Vec<u8>>,
+        progress: &mut impl FnMut(SceneLoadProgress),
+    ) -> Result<Vec<Vec<u8>>> {
+        let total = buffers.len();
+        let mut buffers_data = Vec::with_capacity(total);
+        let mut blob_index = None;
+
+        log::info!("Gltf buffers length: {}", total);
+
+        for buffer in buffers {
+            progress(SceneLoadProgress::Buffers {
+                loaded: buffers_data.len(),
+                total,
+            });
+
+            let data = match buffer.source() {
+                gltf::buffer::Source::Bin => {
+                    blob_index = Some(buffer.index());
+                    Vec::<u8>::new()
+                }
+                gltf::buffer::Source::Uri(uri) => {
+                    let mut uri_path = root_path_buf.clone();
+                    uri_path.push(uri);
+
+                    let binary_data = std::fs::read(uri_path).context("Failed to read gltf uri")?;
+                    binary_data
+                }
+            };
+
+            buffers_data.push(data);
+        }
+
+        if let Some(blob_index) = blob_index {
+            buffers_data[blob_index] = blob.expect("Global blob not found");
+        }
+
+        Ok(buffers_data)
+    }
+
+    pub(crate) fn root_path_buf(&self) -> &PathBuf {
+        &self.root_path_buf
+    }
+
+    pub(crate) fn gltf_file(&self) -> &Gltf {
+        &self.gltf_file
+    }
+
+    pub(crate) fn buffers_data(&self) -> &[Vec<u8>] {
+        &self.buffers_data
     }
 }
 
-fn gltf_min_filter_to_vulkan_filter(gltf_filter: gltf::texture::MinFilter) -> vk::Filter {
+pub(crate) fn gltf_min_filter_to_vulkan_filter(gltf_filter: gltf::texture::MinFilter) -> vk::Filter {
     match gltf_filter {
         gltf::texture::MinFilter::Linear
         | gltf::texture::MinFilter::LinearMipmapLinear
@@ -45,69 +154,165 @@ fn gltf_min_filter_to_vulkan_filter(gltf_filter: gltf::texture::MinFilter) -> vk
     }
 }
 
-fn gltf_mag_filter_to_vulkan_filter(gltf_filter: gltf::texture::MagFilter) -> vk::Filter {
+pub(crate) fn gltf_mag_filter_to_vulkan_filter(gltf_filter: gltf::texture::MagFilter) -> vk::Filter {
     match gltf_filter {
         gltf::texture::MagFilter::Linear => vk::Filter::LINEAR,
         gltf::texture::MagFilter::Nearest => vk::Filter::NEAREST,
     }
 }
 
+/// Color space a texture is sampled in, determined by the material slots referencing it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextureColorSpace {
+    /// Base color and emissive textures
+    Srgb,
+    /// Normal, occlusion and metallic roughness textures
+    Linear,
+    /// Referenced by both sRGB and linear material slots
+    Mixed,
+}
+
+impl TextureColorSpace {
+    fn combine(self, other: Self) -> Self {
+        if self == other {
+            self
+        } else {
+            Self::Mixed
+        }
+    }
+
+    /// Combines the color spaces of all references to a texture, unreferenced textures are linear
+    pub fn classify(
+        references: impl IntoIterator<Item = (usize, Self)>,
+        count: usize,
+    ) -> Vec<Self> {
+        let mut color_spaces: Vec<Option<Self>> = vec![None; count];
+        for (index, color_space) in references {
+            let current = &mut color_spaces[index];
+            *current = Some(current.map_or(color_space, |current| current.combine(color_space)));
+        }
+
+        color_spaces
+            .into_iter()
+            .map(|color_space| color_space.unwrap_or(Self::Linear))
+            .collect()
+    }
+
+    /// Picks the sRGB variant of the format for sRGB textures. Mixed textures keep the linear format and are
+    /// created with a mutable format, the Gpu creates an additional sRGB view with its own bindless index for them.
+    pub fn apply(self, image_desc: ImageDesc) -> ImageDesc {
+        let srgb_format = match format_to_srgb(image_desc.format) {
+            Some(srgb_format) => srgb_format,
+            None => return image_desc,
+        };
+
+        match self {
+            Self::Srgb => image_desc.set_format(srgb_format),
+            Self::Linear => image_desc,
+            Self::Mixed => {
+                let flags = image_desc.flags | vk::ImageCreateFlags::MUTABLE_FORMAT;
+                image_desc.set_flags(flags)
+            }
+        }
+    }
+}
+
+/// Color spaces of the glTF images based on the material slots that reference them
+fn gltf_image_color_spaces(gltf_file: &Gltf) -> Vec<TextureColorSpace> {
+    let mut references = Vec::new();
+    for material in gltf_file.materials() {
+        let pbr = material.pbr_metallic_roughness();
+
+        let srgb_textures = [
+            pbr.base_color_texture().map(|info| info.texture()),
+            material.emissive_texture().map(|info| info.texture()),
+        ];
+        let linear_textures = [
+            pbr.metallic_roughness_texture().map(|info| info.texture()),
+            material.normal_texture().map(|info| info.texture()),
+            material.occlusion_texture().map(|info| info.texture()),
+        ];
+
+        references.extend(
+            srgb_textures
+                .into_iter()
+                .flatten()
+                .map(|texture| (texture.source().index(), TextureColorSpace::Srgb)),
+        );
+        references.extend(
+            linear_textures
+                .into_iter()
+                .flatten()
+                .map(|texture| (texture.source().index(), TextureColorSpace::Linear)),
+        );
+    }
+
+    TextureColorSpace::classify(references, gltf_file.images().len())
+}
+
+/// Line loops have no Vulkan topology, they are drawn as line strips with the loop closed in the index buffer
+fn primitive_topology(mode: gltf::mesh::Mode) -> vk::PrimitiveTopology {
+    match mode {
+        gltf::mesh::Mode::Points => vk::PrimitiveTopology::POINT_LIST,
+        gltf::mesh::Mode::Lines => vk::PrimitiveTopology::LINE_LIST,
+        gltf::mesh::Mode::LineLoop | gltf::mesh::Mode::LineStrip => {
+            vk::PrimitiveTopology::LINE_STRIP
+        }
+        gltf::mesh::Mode::Triangles => vk::PrimitiveTopology::TRIANGLE_LIST,
+        gltf::mesh::Mode::TriangleStrip => vk::PrimitiveTopology::TRIANGLE_STRIP,
+        gltf::mesh::Mode::TriangleFan => vk::PrimitiveTopology::TRIANGLE_FAN,
+    }
+}
+
 impl GltfScene {
-    fn create_image(
+    pub(crate) fn create_image(
         renderer: &mut Renderer,
         file_name: &str,
+        color_space: TextureColorSpace,
         // XXX: Use a channel for this
         async_loader: &mut AsynchronousLoader,
+        texture_streamer: &mut TextureStreamer,
     ) -> Result<Handle<Image>> {
         let mut data = std::io::Cursor::new(std::fs::read(file_name)?);
         let mut image_desc = ImageDesc::new(0, 0, 0);
 
         // XXX: How slow is this read?
-        if let Ok(dds) = ddsfile::Dds::read(&mut data) {
-            let mut vulkan_format = vk::Format::UNDEFINED;
-
-            if let Some(format) = dds.get_dxgi_format() {
-                vulkan_format = dxgi_format_to_vulkan_format(format);
-            } else if let Some(format) = dds.get_d3d_format() {
-                todo!()
-            }
-
-            image_desc = ImageDesc::new(dds.get_width(), dds.get_height(), 1)
-                .set_format(vulkan_format)
-                .set_usage_flags(vk::ImageUsageFlags::SAMPLED);
+        if basis::is_basis_file(file_name) {
+            let format = basis::transcode_format(renderer.gpu());
+            image_desc = basis::image_desc(data.get_ref(), format)?;
+        } else if hdr::is_hdr_file(file_name) {
+            image_desc = hdr::image_desc(file_name)?;
+        } else if let Ok(dds) = ddsfile::Dds::read(&mut data) {
+            image_desc = dds::image_desc(&dds)?;
         } else {
             let reader = image::io::Reader::open(file_name)?;
 
-            // XXX: Use proper format instead of always converting to R8G8B8A_UNORM?
-            // let format = reader.format()?;
+            // Images are always converted to 8 bit RGBA, sRGB textures use the sRGB variant
+            // XXX: Mips of sRGB textures are downsampled without linearizing
             let format = vk::Format::R8G8B8A8_UNORM;
 
             let (width, height) = reader.into_dimensions()?;
 
+            // Mips are generated by the asynchronous loader
             image_desc = ImageDesc::new(width, height, 1)
                 .set_format(format)
-                .set_usage_flags(vk::ImageUsageFlags::SAMPLED);
+                .set_usage_flags(vk::ImageUsageFlags::SAMPLED)
+                .set_mip_level_count(mip_chain_length(width, height));
         }
 
-        let image = renderer.create_image(image_desc)?;
-        // XXX: Do this internally in the Gpu
-        renderer
-            .gpu_mut()
-            .add_bindless_image_update(rikka_gpu::types::ImageResourceUpdate {
-                frame: 0,
-                image: Some(image.clone()),
-                sampler: None,
-            });
-        async_loader.request_image_file_load(file_name, image.clone());
-        Ok(image)
+        let image_desc = color_space.apply(image_desc);
+
+        texture_streamer.add_texture(renderer.gpu_mut(), async_loader, file_name, image_desc)
     }
 
     fn load_images(
         renderer: &mut Renderer,
         root_path_buf: &PathBuf,
         images: gltf::iter::Images,
+        color_spaces: &[TextureColorSpace],
         // XXX: Use a channel for this
         async_loader: &mut AsynchronousLoader,
+        texture_streamer: &mut TextureStreamer,
     ) -> Result<Vec<Handle<Image>>> {
         let mut gpu_images = Vec::with_capacity(images.len());
 
@@ -118,7 +323,13 @@ impl GltfScene {
                 gltf::image::Source::Uri { uri, .. } => {
                     let mut uri_path = root_path_buf.clone();
                     uri_path.push(uri);
-                    Self::create_image(renderer, uri_path.to_str().unwrap(), async_loader)
+                    Self::create_image(
+                        renderer,
+                        uri_path.to_str().unwrap(),
+                        color_spaces[image.index()],
+                        async_loader,
+                        texture_streamer,
+                    )
                 }
                 gltf::image::Source::View { view, .. } => {
                     panic!("glTF image loading from view not implemented!");
@@ -161,42 +372,8 @@ impl GltfScene {
         Ok(gpu_samplers)
     }
 
-    fn load_buffers_data(
-        root_path_buf: &PathBuf,
-        buffers: gltf::iter::Buffers,
-        blob: Option<Vec<u8>>,
-    ) -> Result<Vec<Vec<u8>>> {
-        let mut buffers_data = Vec::with_capacity(buffers.len());
-        let mut blob_index = None;
-
-        log::info!("Gltf buffers length: {}", buffers.len());
-
-        for buffer in buffers {
-            let data = match buffer.source() {
-                gltf::buffer::Source::Bin => {
-                    blob_index = Some(buffer.index());
-                    Vec::<u8>::new()
-                }
-                gltf::buffer::Source::Uri(uri) => {
-                    let mut uri_path = root_path_buf.clone();
-                    uri_path.push(uri);
-
-                    let binary_data = std::fs::read(uri_path).context("Failed to read gltf uri")?;
-                    binary_data
-                }
-            };
-
-            buffers_data.push(data);
-        }
-
-        if let Some(blob_index) = blob_index {
-            buffers_data[blob_index] = blob.expect("Global blob not found");
-        }
-
-        Ok(buffers_data)
-    }
-
-    /// Creates Gpu buffers based on buffer views
+    /// Creates Gpu buffers based on buffer views, all views are uploaded from one staging buffer with a single batch
+    /// of copies
     fn load_buffer_views(
         renderer: &mut Renderer,
         buffer_views: gltf::iter::Views,
@@ -206,74 +383,83 @@ impl GltfScene {
 
         log::info!("Buffer views length {}", buffer_views.len());
 
+        let mut staging_data = Vec::new();
+        let mut view_offsets = Vec::with_capacity(buffer_views.len());
         for buffer_view in buffer_views {
             let length = buffer_view.length();
             let range_start = buffer_view.offset();
             let range_end = range_start + length;
 
-            let data = &buffers_data[buffer_view.buffer().index()][range_start..range_end];
-
-            let staging_buffer = renderer.create_buffer(
-                BufferDesc::new()
-                    .set_size(length as _)
-                    .set_device_only(false),
-            )?;
-            staging_buffer.copy_data_to_buffer(data)?;
+            view_offsets.push(staging_data.len());
+            staging_data.extend_from_slice(
+                &buffers_data[buffer_view.buffer().index()][range_start..range_end],
+            );
+            gpu_buffers.push(Self::create_device_geometry_buffer(renderer, length)?);
+        }
 
-            let gpu_buffer = renderer.create_buffer(
-                BufferDesc::new()
-                    .set_size(length as _)
-                    .set_usage_flags(
-                        vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::INDEX_BUFFER,
-                    )
-                    .set_device_only(true),
-            )?;
-            renderer
-                .gpu_mut()
-                .copy_buffer(&staging_buffer, &gpu_buffer)?;
+        if gpu_buffers.is_empty() {
+            return Ok(gpu_buffers);
+        }
 
-            gpu_buffers.push(gpu_buffer);
+        let staging_buffer = renderer.create_buffer(
+            BufferDesc::new()
+                .set_size(staging_data.len() as _)
+                .set_device_only(false),
+        )?;
+        staging_buffer.copy_data_to_buffer(&staging_data)?;
+
+        let mut copy_batch = CopyBatch::new();
+        for (gpu_buffer, view_offset) in gpu_buffers.iter().zip(view_offsets) {
+            copy_batch = copy_batch.add_buffer_region(
+                &staging_buffer,
+                gpu_buffer,
+                gpu_buffer.size() as u64,
+                view_offset as u64,
+                0,
+            );
         }
 
+        let mut copy_buffers = gpu_buffers.iter().collect::<Vec<_>>();
+        copy_buffers.push(&staging_buffer);
+        renderer.gpu().copy_batch(copy_batch, &copy_buffers)?;
+
         Ok(gpu_buffers)
     }
 
-    fn create_default_pbr_material(
+    /// Creates a device local vertex and index buffer with `data`
+    pub(crate) fn create_geometry_buffer<T: Copy>(
+        renderer: &Renderer,
+        data: &[T],
+    ) -> Result<Handle<Buffer>> {
+        renderer.create_buffer_from_data(Self::geometry_buffer_desc(renderer), data)
+    }
+
+    fn create_device_geometry_buffer(renderer: &Renderer, size: usize) -> Result<Handle<Buffer>> {
+        renderer.create_buffer(Self::geometry_buffer_desc(renderer).set_size(size as _))
+    }
+
+    fn geometry_buffer_desc(renderer: &Renderer) -> BufferDesc {
+        let mut usage_flags =
+            vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::INDEX_BUFFER;
+        // Allow building acceleration structures directly from the vertex/index data
+        if renderer.gpu().ray_tracing_supported() {
+            usage_flags |= vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR
+                | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS;
+        }
+
+        BufferDesc::new()
+            .set_usage_flags(usage_flags)
+            .set_device_only(true)
+    }
+
+    pub(crate) fn create_default_pbr_material(
         renderer: &Renderer,
         render_technique: Arc<RenderTechnique>,
-        uniform_buffer: Handle<Buffer>,
     ) -> Result<PBRMaterial> {
-        let material_desc =
-            MaterialDesc::new(0, render_technique.clone(), String::from("pbr_lighting"));
+        let material_desc = MaterialDesc::new(0, render_technique, String::from("pbr_lighting"));
         let material = renderer.create_material(material_desc)?;
 
-        // XXX: Use dynamic uniform buffer?
-        let material_buffer_desc = BufferDesc::new()
-            .set_usage_flags(vk::BufferUsageFlags::UNIFORM_BUFFER)
-            .set_size(size_of::<GpuMeshData>() as _)
-            .set_device_only(false);
-        // log::info!("Material buffer size: {}", material_buffer_desc.size);
-        let material_buffer = renderer.create_buffer(material_buffer_desc)?;
-
-        // let mesh_data = GpuMeshData {
-        //     diffuse_texture_index: 3,
-        //     metallic_roughness_texture_index: 3,
-        //     normal_texture_index: 3,
-        //     base_color_factor: Vector4::new(0.67, 0.67, 0.67, 1.0),
-        // };
-        // material_buffer.copy_data_to_buffer(&[mesh_data])?;
-
-        // XXX: Use accessprs fpr a lot of the structs instead of public mbembers
-        let descriptor_set_layout = render_technique.passes[0]
-            .graphics_pipeline
-            .descriptor_set_layouts()[0]
-            .clone();
-        let descriptor_set_desc = DescriptorSetDesc::new(descriptor_set_layout)
-            .add_buffer_resource(uniform_buffer, 0)
-            .add_buffer_resource(material_buffer.clone(), 1);
-        let descriptor_set = renderer.create_descriptor_set(descriptor_set_desc)?;
-
-        Ok(PBRMaterial::new(material, material_buffer, descriptor_set))
+        Ok(PBRMaterial::new(material))
     }
 
     fn get_material_texture_image(
@@ -301,10 +487,8 @@ impl GltfScene {
         gpu_samplers: &Vec<Handle<Sampler>>,
         renderer: &Renderer,
         render_technique: Arc<RenderTechnique>,
-        uniform_buffer: Handle<Buffer>,
     ) -> Result<PBRMaterial> {
-        let mut pbr_material =
-            Self::create_default_pbr_material(renderer, render_technique, uniform_buffer)?;
+        let mut pbr_material = Self::create_default_pbr_material(renderer, render_technique)?;
 
         // Alpha mode
         match gltf_material.alpha_mode() {
@@ -346,6 +530,9 @@ impl GltfScene {
             pbr_material.occlusion_image = Some(image);
 
             pbr_material.metallic_roughness_occlusion_factor.z = occlusion_info.strength();
+            if occlusion_info.tex_coord() == 1 {
+                pbr_material.draw_flags |= DrawFlags::OCCLUSION_TEXCOORDS_1;
+            }
         } else {
             // log::warn!(
             //     "Material {} has no occlusion texture",
@@ -396,36 +583,283 @@ impl GltfScene {
         Ok(pbr_material)
     }
 
-    pub fn new_from_file(
+    /// Generates tangents of a triangle list primitive without a tangents accessor
+    fn generate_primitive_tangents(
+        primitive: &gltf::Primitive,
+        buffers_data: &[Vec<u8>],
+    ) -> Result<Option<Vec<[f32; 4]>>> {
+        let reader =
+            primitive.reader(|buffer| buffers_data.get(buffer.index()).map(Vec::as_slice));
+
+        let positions = reader
+            .read_positions()
+            .context("glTF positions accessor does not exist!")?
+            .collect::<Vec<_>>();
+        let normals = reader
+            .read_normals()
+            .context("glTF normals accessor does not exist!")?
+            .collect::<Vec<_>>();
+        let tex_coords = match reader.read_tex_coords(0) {
+            Some(tex_coords) => tex_coords.into_f32().collect::<Vec<_>>(),
+            None => return Ok(None),
+        };
+        let indices = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect::<Vec<_>>(),
+            None => (0..positions.len() as u32).collect(),
+        };
+
+        Ok(generate_tangents(
+            &indices,
+            &positions,
+            &normals,
+            &tex_coords,
+        ))
+    }
+
+    /// Reorders the triangles and vertices of a triangle list primitive with meshopt. The reordered attributes
+    /// replace the buffer view geometry of the mesh, `generated_tangents` are used if the primitive has no tangents
+    /// accessor.
+    fn optimize_primitive_geometry(
         renderer: &mut Renderer,
-        file_name: &str,
-        uniform_buffer: &Handle<Buffer>,
+        primitive: &gltf::Primitive,
+        buffers_data: &[Vec<u8>],
+        generated_tangents: Option<Vec<[f32; 4]>>,
+        mesh: &mut Mesh,
+    ) -> Result<PrimitiveGeometry> {
+        let reader =
+            primitive.reader(|buffer| buffers_data.get(buffer.index()).map(Vec::as_slice));
+
+        let positions = reader
+            .read_positions()
+            .context("glTF positions accessor does not exist!")?
+            .collect::<Vec<_>>();
+        let vertex_count = positions.len();
+        let normals = reader
+            .read_normals()
+            .context("glTF normals accessor does not exist!")?
+            .collect::<Vec<_>>();
+        let tangents = match (reader.read_tangents(), generated_tangents) {
+            (Some(tangents), _) => tangents.collect(),
+            (None, Some(generated_tangents)) => generated_tangents,
+            (None, None) => Vec::new(),
+        };
+        let tex_coords = reader
+            .read_tex_coords(0)
+            .map_or(Vec::new(), |tex_coords| tex_coords.into_f32().collect());
+        let tex_coords_1 = reader
+            .read_tex_coords(1)
+            .map(|tex_coords| tex_coords.into_f32().collect::<Vec<_>>());
+        let colors = reader
+            .read_colors(0)
+            .map(|colors| colors.into_rgba_f32().collect::<Vec<_>>());
+        let indices = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect::<Vec<_>>(),
+            None => (0..vertex_count as u32).collect(),
+        };
+
+        let mut indices = optimize_vertex_cache(&indices, vertex_count);
+        let (remapped_vertex_count, remap) =
+            optimize_vertex_fetch_remap(&mut indices, vertex_count);
+
+        let positions = remap_vertex_attribute(&positions, &remap, remapped_vertex_count);
+        mesh.position_buffer = Some(Self::create_geometry_buffer(renderer, &positions)?);
+        mesh.position_offset = 0;
+        mesh.position_stride = size_of::<[f32; 3]>() as u32;
+        mesh.vertex_count = remapped_vertex_count as _;
+
+        let normals = remap_vertex_attribute(&normals, &remap, remapped_vertex_count);
+        mesh.normal_buffer = Some(Self::create_geometry_buffer(renderer, &normals)?);
+        mesh.normal_offset = 0;
+
+        let mut tangents = tangents;
+        if !tangents.is_empty() {
+            tangents = remap_vertex_attribute(&tangents, &remap, remapped_vertex_count);
+            mesh.tangent_buffer = Some(Self::create_geometry_buffer(renderer, &tangents)?);
+            mesh.tangent_offset = 0;
+        }
+
+        let mut tex_coords = tex_coords;
+        if !tex_coords.is_empty() {
+            tex_coords = remap_vertex_attribute(&tex_coords, &remap, remapped_vertex_count);
+            mesh.tex_coords_buffer = Some(Self::create_geometry_buffer(renderer, &tex_coords)?);
+            mesh.tex_coords_offset = 0;
+        }
+
+        if let Some(tex_coords_1) = tex_coords_1 {
+            let tex_coords_1 = remap_vertex_attribute(&tex_coords_1, &remap, remapped_vertex_count);
+            mesh.tex_coords_1_buffer = Some(Self::create_geometry_buffer(renderer, &tex_coords_1)?);
+            mesh.tex_coords_1_offset = 0;
+        }
+
+        if let Some(colors) = colors {
+            let colors = remap_vertex_attribute(&colors, &remap, remapped_vertex_count);
+            mesh.color_buffer = Some(Self::create_geometry_buffer(renderer, &colors)?);
+            mesh.color_offset = 0;
+        }
+
+        let (index_buffer, index_type) =
+            Self::create_index_buffer(renderer, &indices, remapped_vertex_count)?;
+        mesh.index_buffer = Some(index_buffer);
+        mesh.index_offset = 0;
+        mesh.index_type = index_type;
+        mesh.primitive_count = indices.len() as _;
+
+        Ok(PrimitiveGeometry {
+            positions,
+            normals,
+            tangents,
+            tex_coords,
+            indices,
+            remap,
+        })
+    }
+
+    /// Builds the meshlets and LOD levels of a primitive from its optimized Cpu side vertex and index data
+    fn build_primitive_meshlets_and_lods(
+        renderer: &mut Renderer,
+        geometry: &PrimitiveGeometry,
+        mesh: &mut Mesh,
+        mesh_index: u32,
+        scene_meshlets: &mut SceneMeshlets,
+    ) -> Result<()> {
+        let meshlets = build_meshlets(&geometry.indices, &geometry.positions);
+        let first_index = scene_meshlets.indices.len() as u32;
+        mesh.meshlet_vertex_offset = scene_meshlets.vertex_positions.len() as u32;
+        mesh.meshlet_offset = scene_meshlets.add_mesh(
+            mesh_index,
+            &MeshletVertexAttributes {
+                positions: &geometry.positions,
+                normals: &geometry.normals,
+                tangents: &geometry.tangents,
+                tex_coords: &geometry.tex_coords,
+            },
+            &meshlets,
+        );
+        mesh.meshlet_count = meshlets.meshlets.len() as u32;
+        mesh.meshlet_first_index = first_index;
+        mesh.meshlet_index_count = scene_meshlets.indices.len() as u32 - first_index;
+
+        Self::create_mesh_lods(
+            renderer,
+            mesh,
+            mesh_index,
+            &geometry.indices,
+            &geometry.positions,
+            scene_meshlets,
+        )
+    }
+
+    /// Creates a 16 bit index buffer if all vertices can be indexed by it, 32 bit otherwise
+    fn create_index_buffer(
+        renderer: &mut Renderer,
+        indices: &[u32],
+        vertex_count: usize,
+    ) -> Result<(Handle<Buffer>, vk::IndexType)> {
+        if vertex_count <= u16::MAX as usize + 1 {
+            let indices = indices
+                .iter()
+                .map(|index| *index as u16)
+                .collect::<Vec<_>>();
+            Ok((
+                Self::create_geometry_buffer(renderer, &indices)?,
+                vk::IndexType::UINT16,
+            ))
+        } else {
+            Ok((
+                Self::create_geometry_buffer(renderer, indices)?,
+                vk::IndexType::UINT32,
+            ))
+        }
+    }
+
+    /// Generates simplified LOD levels of a mesh, all levels share one index buffer. The meshlets of every level
+    /// are appended to `scene_meshlets` and index the vertices of the full detail meshlets.
+    pub(crate) fn create_mesh_lods(
+        renderer: &mut Renderer,
+        mesh: &mut Mesh,
+        mesh_index: u32,
+        indices: &[u32],
+        positions: &[[f32; 3]],
+        scene_meshlets: &mut SceneMeshlets,
+    ) -> Result<()> {
+        let lods = generate_lods(indices, positions, MAX_MESH_LODS);
+        if lods.is_empty() {
+            return Ok(());
+        }
+
+        let lod_indices = lods
+            .iter()
+            .flat_map(|lod| lod.indices.iter().copied())
+            .collect::<Vec<_>>();
+        let (index_buffer, index_type) =
+            Self::create_index_buffer(renderer, &lod_indices, positions.len())?;
+        let index_size = match index_type {
+            vk::IndexType::UINT16 => size_of::<u16>(),
+            _ => size_of::<u32>(),
+        };
+
+        let mut index_offset = 0;
+        for lod in lods {
+            let meshlets = build_meshlets(&lod.indices, positions);
+            let meshlet_first_index = scene_meshlets.indices.len() as u32;
+            let meshlet_offset = scene_meshlets.add_meshlets(
+                mesh_index,
+                mesh.meshlet_vertex_offset,
+                positions,
+                &meshlets,
+            );
+
+            mesh.lods.push(MeshLod {
+                index_buffer: index_buffer.clone(),
+                index_offset: (index_offset * index_size) as u32,
+                index_count: lod.indices.len() as u32,
+                index_type,
+                error: lod.error,
+                meshlet_offset,
+                meshlet_count: meshlets.meshlets.len() as u32,
+                meshlet_first_index,
+                meshlet_index_count: scene_meshlets.indices.len() as u32 - meshlet_first_index,
+            });
+            index_offset += lod.indices.len();
+        }
+
+        Ok(())
+    }
+
+    /// Creates the Gpu resources of a loaded scene, textures are streamed in by `async_loader`
+    pub fn new_from_data(
+        renderer: &mut Renderer,
+        scene_data: GltfSceneData,
         render_technique: &Arc<RenderTechnique>,
-        // XXX: Use a channel for this
         async_loader: &mut AsynchronousLoader,
+        texture_streamer: &mut TextureStreamer,
     ) -> Result<Self> {
-        let mut root_path_buf = PathBuf::from(file_name);
-        // XXX: Assume asset paths are exactly on the same directory from the `.gLTF` file
-        //      Handle this more gracefully
-        root_path_buf.pop();
-
-        let mut gltf_file = Gltf::open(file_name)?;
-
-        let gpu_images =
-            Self::load_images(renderer, &root_path_buf, gltf_file.images(), async_loader)?;
+        let GltfSceneData {
+            root_path_buf,
+            gltf_file,
+            buffers_data,
+        } = scene_data;
+
+        let gpu_images = Self::load_images(
+            renderer,
+            &root_path_buf,
+            gltf_file.images(),
+            &gltf_image_color_spaces(&gltf_file),
+            async_loader,
+            texture_streamer,
+        )?;
 
         let gpu_samplers = Self::load_samplers(renderer, gltf_file.samplers())?;
 
-        let gltf_blob = gltf_file.blob.take();
-        let buffers_data =
-            GltfScene::load_buffers_data(&root_path_buf, gltf_file.buffers(), gltf_blob)?;
-
         log::info!("Buffers data length {}", buffers_data[0].len());
 
         let gpu_buffers = Self::load_buffer_views(renderer, gltf_file.views(), &buffers_data)?;
 
         let gltf_meshes = gltf_file.meshes();
         let mut meshes = Vec::with_capacity(gltf_meshes.len());
+        // Primitives referencing the same glTF material share one material buffer and descriptor set,
+        // None is the default material
+        let mut pbr_materials: HashMap<Option<usize>, Arc<PBRMaterial>> = HashMap::new();
 
         log::info!("Meshes count: {}", gltf_meshes.len());
 
@@ -437,6 +871,7 @@ impl GltfScene {
         log::debug!("gLTF number of nodes: {}", gltf_nodes.len());
 
         let mut scene_graph = scene::Graph::with_num_nodes(gltf_nodes.len());
+        let mut scene_meshlets = SceneMeshlets::default();
 
         // Set scene graph hierarchy with level order traversal/BFS
         let root_scene = gltf_file.default_scene().unwrap();
@@ -455,6 +890,7 @@ impl GltfScene {
             // Find to set this now as all nodes are traversed in a BFS manner
             let transform_matrix = Matrix4::from(node.transform().matrix());
             scene_graph.set_local_matrix(node.index(), transform_matrix);
+            scene_graph.set_name(node.index(), node.name());
 
             // log::trace!(
             //     "Processing scene node {}, local transform {:#?}, level {}",
@@ -479,38 +915,104 @@ impl GltfScene {
 
             let gltf_mesh = node.mesh().unwrap();
             for primitive in gltf_mesh.primitives() {
-                let pbr_material = Self::create_pbr_material(
-                    primitive.material(),
-                    &gpu_images,
-                    &gpu_samplers,
-                    renderer,
-                    render_technique.clone(),
-                    uniform_buffer.clone(),
-                )?;
+                let gltf_material = primitive.material();
+                let pbr_material = match pbr_materials.get(&gltf_material.index()) {
+                    Some(pbr_material) => pbr_material.clone(),
+                    None => {
+                        let pbr_material = Arc::new(Self::create_pbr_material(
+                            gltf_material.clone(),
+                            &gpu_images,
+                            &gpu_samplers,
+                            renderer,
+                            render_technique.clone(),
+                        )?);
+                        pbr_materials.insert(gltf_material.index(), pbr_material.clone());
+                        pbr_material
+                    }
+                };
 
                 let mut mesh = Mesh::new_with_pbr_material(pbr_material);
 
-                if primitive.mode() != gltf::mesh::Mode::Triangles {
-                    return Err(anyhow!(
-                        "glTF primitive mode is not TRIANGLES, only TRIANGLES is supported"
-                    ));
+                mesh.topology = primitive_topology(primitive.mode());
+                if mesh.topology != vk::PrimitiveTopology::TRIANGLE_LIST {
+                    renderer.create_technique_topology_variant(render_technique, mesh.topology)?;
                 }
 
                 if let Some(positions_accessor) = primitive.get(&gltf::Semantic::Positions) {
                     let buffer_view = positions_accessor.view().unwrap();
                     mesh.position_buffer = Some(gpu_buffers[buffer_view.index()].clone());
                     mesh.position_offset = positions_accessor.offset() as _;
+                    mesh.position_stride =
+                        buffer_view.stride().unwrap_or(positions_accessor.size()) as _;
+                    mesh.vertex_count = positions_accessor.count() as _;
+
+                    // glTF requires min/max for position accessors
+                    let bounding_box = primitive.bounding_box();
+                    mesh.bounds = scene::Aabb::new(
+                        Vector3::from(bounding_box.min),
+                        Vector3::from(bounding_box.max),
+                    );
                 } else {
                     return Err(anyhow!("glTF positions accessor does not exist!"));
                 }
 
-                if let Some(indices_accessor) = primitive.indices() {
+                // Line loops are drawn as line strips that repeat the first index at the end
+                let line_loop = primitive.mode() == gltf::mesh::Mode::LineLoop;
+
+                if let Some(indices_accessor) = primitive.indices().filter(|_| !line_loop) {
                     let buffer_view = indices_accessor.view().unwrap();
                     mesh.index_buffer = Some(gpu_buffers[buffer_view.index()].clone());
                     mesh.index_offset = indices_accessor.offset() as _;
                     mesh.primitive_count = indices_accessor.count() as _;
+                    mesh.index_type = match indices_accessor.data_type() {
+                        gltf::accessor::DataType::U8 => vk::IndexType::UINT8_EXT,
+                        gltf::accessor::DataType::U16 => vk::IndexType::UINT16,
+                        gltf::accessor::DataType::U32 => vk::IndexType::UINT32,
+                        data_type => {
+                            return Err(anyhow!(
+                                "glTF index component type {:?} is not supported",
+                                data_type
+                            ));
+                        }
+                    };
+
+                    // Acceleration structures cannot be built from 8 bit indices either
+                    if mesh.index_type == vk::IndexType::UINT8_EXT
+                        && (!renderer.gpu().device_features().index_type_uint8
+                            || renderer.gpu().ray_tracing_supported())
+                    {
+                        let reader = primitive
+                            .reader(|buffer| buffers_data.get(buffer.index()).map(Vec::as_slice));
+                        let indices = reader
+                            .read_indices()
+                            .context("glTF indices accessor does not exist!")?
+                            .into_u32()
+                            .map(|index| index as u16)
+                            .collect::<Vec<_>>();
+
+                        mesh.index_buffer = Some(Self::create_geometry_buffer(renderer, &indices)?);
+                        mesh.index_offset = 0;
+                        mesh.index_type = vk::IndexType::UINT16;
+                    }
                 } else {
-                    return Err(anyhow!("glTF indices accessor does not exist!"));
+                    let reader = primitive
+                        .reader(|buffer| buffers_data.get(buffer.index()).map(Vec::as_slice));
+                    let mut indices = match reader.read_indices() {
+                        Some(indices) => indices.into_u32().collect::<Vec<_>>(),
+                        None => (0..mesh.vertex_count).collect(),
+                    };
+                    if line_loop {
+                        if let Some(first_index) = indices.first().copied() {
+                            indices.push(first_index);
+                        }
+                    }
+
+                    let (index_buffer, index_type) =
+                        Self::create_index_buffer(renderer, &indices, mesh.vertex_count as _)?;
+                    mesh.index_buffer = Some(index_buffer);
+                    mesh.index_offset = 0;
+                    mesh.index_type = index_type;
+                    mesh.primitive_count = indices.len() as _;
                 }
 
                 if let Some(tex_coords_accessor) = primitive.get(&gltf::Semantic::TexCoords(0)) {
@@ -525,6 +1027,38 @@ impl GltfScene {
                     // ));
                 }
 
+                if let Some(tex_coords_1_accessor) = primitive.get(&gltf::Semantic::TexCoords(1)) {
+                    if tex_coords_1_accessor.data_type() == gltf::accessor::DataType::F32 {
+                        let buffer_view = tex_coords_1_accessor.view().unwrap();
+                        mesh.tex_coords_1_buffer = Some(gpu_buffers[buffer_view.index()].clone());
+                        mesh.tex_coords_1_offset = tex_coords_1_accessor.offset() as _;
+                    } else {
+                        // Normalized integer coordinates are converted to match the vertex input format
+                        let tex_coords = primitive
+                            .reader(|buffer| buffers_data.get(buffer.index()).map(Vec::as_slice))
+                            .read_tex_coords(1)
+                            .context("glTF texture coordinates 1 accessor does not exist!")?
+                            .into_f32()
+                            .collect::<Vec<_>>();
+                        mesh.tex_coords_1_buffer =
+                            Some(Self::create_geometry_buffer(renderer, &tex_coords)?);
+                        mesh.tex_coords_1_offset = 0;
+                    }
+                    mesh.vertex_flags |= DrawFlags::HAS_TEXCOORDS_1;
+                }
+
+                // Colors can be RGB or RGBA with float or normalized integer components, always upload them as
+                // RGBA floats
+                if let Some(colors) = primitive
+                    .reader(|buffer| buffers_data.get(buffer.index()).map(Vec::as_slice))
+                    .read_colors(0)
+                {
+                    let colors = colors.into_rgba_f32().collect::<Vec<_>>();
+                    mesh.color_buffer = Some(Self::create_geometry_buffer(renderer, &colors)?);
+                    mesh.color_offset = 0;
+                    mesh.vertex_flags |= DrawFlags::HAS_COLORS;
+                }
+
                 if let Some(normals_accessor) = primitive.get(&gltf::Semantic::Normals) {
                     let buffer_view = normals_accessor.view().unwrap();
                     mesh.normal_buffer = Some(gpu_buffers[buffer_view.index()].clone());
@@ -533,16 +1067,62 @@ impl GltfScene {
                     return Err(anyhow!("glTF normals accessor does not exist!"));
                 }
 
+                let mut generated_tangents = None;
                 if let Some(tangents_accessor) = primitive.get(&gltf::Semantic::Tangents) {
                     let buffer_view = tangents_accessor.view().unwrap();
                     mesh.tangent_buffer = Some(gpu_buffers[buffer_view.index()].clone());
                     mesh.tangent_offset = tangents_accessor.offset() as _;
-                } else {
-                    // log::info!("Does not contain tangents! index {}", primitive.index());
+                } else if mesh.pbr_material.normal_image.is_some()
+                    && mesh.topology == vk::PrimitiveTopology::TRIANGLE_LIST
+                {
+                    // Normal mapping needs a tangent frame, glTF expects mikktspace tangents when they are omitted.
+                    // The tangent buffer is created with the optimized geometry below.
+                    generated_tangents =
+                        Self::generate_primitive_tangents(&primitive, &buffers_data)?;
+                    if generated_tangents.is_none() {
+                        log::warn!(
+                            "Failed to generate tangents of mesh {} primitive {}",
+                            gltf_mesh.index(),
+                            primitive.index()
+                        );
+                    }
                 }
 
                 mesh.scene_graph_node_index = node.index();
 
+                // Triangle lists are drawn from their reordered geometry, other topologies from the buffer views
+                let geometry = if mesh.topology == vk::PrimitiveTopology::TRIANGLE_LIST {
+                    Some(Self::optimize_primitive_geometry(
+                        renderer,
+                        &primitive,
+                        &buffers_data,
+                        generated_tangents,
+                        &mut mesh,
+                    )?)
+                } else {
+                    None
+                };
+
+                if let Some(skin) = node.skin() {
+                    mesh.skin = Self::create_primitive_skin(
+                        renderer,
+                        &primitive,
+                        &buffers_data,
+                        skin.index(),
+                        geometry.as_ref().map(|geometry| geometry.remap.as_slice()),
+                    )?;
+                }
+
+                if let Some(geometry) = &geometry {
+                    Self::build_primitive_meshlets_and_lods(
+                        renderer,
+                        geometry,
+                        &mut mesh,
+                        meshes.len() as u32,
+                        &mut scene_meshlets,
+                    )?;
+                }
+
                 // log::trace!(
                 //     "Processing scene mesh node {}, local transform {:#?}, level {}",
                 //     node.name().unwrap_or("no node name"),
@@ -556,9 +1136,173 @@ impl GltfScene {
             }
         }
 
+        log::info!(
+            "Created {} meshes sharing {} materials",
+            meshes.len(),
+            pbr_materials.len()
+        );
+        log::info!(
+            "Built {} meshlets with {} vertices",
+            scene_meshlets.meshlets.len(),
+            scene_meshlets.vertex_positions.len()
+        );
+
+        let animations = Self::load_animations(&gltf_file, &buffers_data);
+        log::info!("Loaded {} animations", animations.len());
+        let skins = Self::load_skins(&gltf_file, &buffers_data);
+        log::info!("Loaded {} skins", skins.len());
+
         Ok(Self {
             meshes,
             scene_graph,
+            meshlets: scene_meshlets,
+            animations,
+            skins,
         })
     }
+
+    /// Skin vertices of a primitive of a skinned node, None if the primitive has no joints or weights. `remap` is
+    /// the vertex remap of the optimized primitive geometry.
+    fn create_primitive_skin(
+        renderer: &mut Renderer,
+        primitive: &gltf::Primitive,
+        buffers_data: &[Vec<u8>],
+        skin_index: usize,
+        remap: Option<&[u32]>,
+    ) -> Result<Option<MeshSkin>> {
+        let reader =
+            primitive.reader(|buffer| buffers_data.get(buffer.index()).map(Vec::as_slice));
+        let (joints, weights) = match (reader.read_joints(0), reader.read_weights(0)) {
+            (Some(joints), Some(weights)) => (joints.into_u16(), weights.into_f32()),
+            _ => {
+                log::warn!(
+                    "Skinned primitive {} does not have joints and weights",
+                    primitive.index()
+                );
+                return Ok(None);
+            }
+        };
+
+        let positions = reader
+            .read_positions()
+            .context("glTF positions accessor does not exist!")?;
+        let normals = reader
+            .read_normals()
+            .context("glTF normals accessor does not exist!")?;
+
+        let vertices = positions
+            .zip(normals)
+            .zip(joints.zip(weights))
+            .map(|((position, normal), (joints, weights))| GpuSkinVertex {
+                position: Vector3::from(position).push(1.0),
+                normal: Vector3::from(normal).push(0.0),
+                joints: Vector4::from(joints.map(u32::from)),
+                weights: Vector4::from(weights),
+            })
+            .collect::<Vec<_>>();
+        let vertices = match remap {
+            Some(remap) => {
+                // Unreferenced vertices are dropped, the remapped vertices fit into the original ones
+                let mut remapped = vertices.clone();
+                for (old_index, new_index) in remap.iter().enumerate() {
+                    if *new_index != u32::MAX {
+                        remapped[*new_index as usize] = vertices[old_index];
+                    }
+                }
+                remapped.truncate(remap.iter().filter(|index| **index != u32::MAX).count());
+                remapped
+            }
+            None => vertices,
+        };
+
+        Ok(Some(MeshSkin::new(renderer, skin_index, &vertices)?))
+    }
+
+    /// Skins without inverse bind matrices use identity matrices
+    fn load_skins(gltf_file: &Gltf, buffers_data: &[Vec<u8>]) -> Vec<Skin> {
+        gltf_file
+            .skins()
+            .map(|skin| {
+                let joints = skin.joints().map(|joint| joint.index()).collect::<Vec<_>>();
+                let inverse_bind_matrices = match skin
+                    .reader(|buffer| buffers_data.get(buffer.index()).map(Vec::as_slice))
+                    .read_inverse_bind_matrices()
+                {
+                    Some(matrices) => matrices.map(Matrix4::from).collect(),
+                    None => vec![Matrix4::identity(); joints.len()],
+                };
+
+                Skin {
+                    name: skin.name().map(String::from),
+                    joints,
+                    inverse_bind_matrices,
+                }
+            })
+            .collect()
+    }
+
+    /// Node transform animations, channels of morph target weights and channels with invalid keyframes are skipped
+    fn load_animations(gltf_file: &Gltf, buffers_data: &[Vec<u8>]) -> Vec<AnimationClip> {
+        gltf_file
+            .animations()
+            .map(|animation| {
+                let channels = animation
+                    .channels()
+                    .filter_map(|channel| {
+                        let reader = channel
+                            .reader(|buffer| buffers_data.get(buffer.index()).map(Vec::as_slice));
+                        let times = reader.read_inputs()?.collect::<Vec<_>>();
+
+                        let (property, values) = match reader.read_outputs()? {
+                            ReadOutputs::Translations(translations) => (
+                                ChannelProperty::Translation,
+                                translations
+                                    .map(|[x, y, z]| Vector4::new(x, y, z, 0.0))
+                                    .collect::<Vec<_>>(),
+                            ),
+                            ReadOutputs::Rotations(rotations) => (
+                                ChannelProperty::Rotation,
+                                rotations.into_f32().map(Vector4::from).collect(),
+                            ),
+                            ReadOutputs::Scales(scales) => (
+                                ChannelProperty::Scale,
+                                scales.map(|[x, y, z]| Vector4::new(x, y, z, 0.0)).collect(),
+                            ),
+                            ReadOutputs::MorphTargetWeights(_) => return None,
+                        };
+
+                        let interpolation = match channel.sampler().interpolation() {
+                            gltf::animation::Interpolation::Step => Interpolation::Step,
+                            gltf::animation::Interpolation::Linear => Interpolation::Linear,
+                            gltf::animation::Interpolation::CubicSpline => {
+                                Interpolation::CubicSpline
+                            }
+                        };
+                        let values_per_keyframe = match interpolation {
+                            Interpolation::CubicSpline => 3,
+                            _ => 1,
+                        };
+                        if times.is_empty() || values.len() != times.len() * values_per_keyframe {
+                            log::warn!(
+                                "Skipping animation channel with {} keyframes and {} values",
+                                times.len(),
+                                values.len()
+                            );
+                            return None;
+                        }
+
+                        Some(AnimationChannel {
+                            node: channel.target().node().index(),
+                            property,
+                            interpolation,
+                            times,
+                            values,
+                        })
+                    })
+                    .collect();
+
+                AnimationClip::new(animation.name().map(String::from), channels)
+            })
+            .collect()
+    }
 }
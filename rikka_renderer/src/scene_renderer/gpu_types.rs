@@ -37,10 +37,44 @@ pub struct GpuMeshInstanceData {
     pub inverse_model: Matrix4<f32>,
 
     pub mesh_index: u32,
+    pub material_index: u32,
+    pub object_id: u32,
+    /// `GpuCullMesh` of the selected LOD level
+    pub cull_mesh_index: u32,
+}
 
-    _pad0: u32,
-    _pad1: u32,
-    _pad2: u32,
+impl GpuMeshInstanceData {
+    pub fn new(
+        model: Matrix4<f32>,
+        mesh_index: u32,
+        material_index: u32,
+        object_id: u32,
+        cull_mesh_index: u32,
+    ) -> Self {
+        Self {
+            model,
+            inverse_model: model.try_inverse().unwrap_or_else(Matrix4::identity),
+            mesh_index,
+            material_index,
+            object_id,
+            cull_mesh_index,
+        }
+    }
+}
+
+/// Object space bounding sphere and geometry ranges of a mesh LOD level, read by the culling pass
+#[derive(Copy, Clone)]
+#[repr(C, align(16))]
+pub struct GpuCullMesh {
+    pub center: Vector3<f32>,
+    pub radius: f32,
+
+    /// Index range in the scene meshlet index buffer
+    pub first_index: u32,
+    pub index_count: u32,
+
+    pub meshlet_offset: u32,
+    pub meshlet_count: u32,
 }
 
 #[derive(Copy, Clone)]
@@ -82,6 +116,25 @@ pub struct GpuMeshletVertexPosition {
     _pad0: u32,
 }
 
+impl GpuMeshletVertexPosition {
+    pub fn new(position: Vector3<f32>) -> Self {
+        Self { position, _pad0: 0 }
+    }
+}
+
+/// Bind pose vertex of a skinned mesh read by the skinning pass
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct GpuSkinVertex {
+    /// w unused
+    pub position: Vector4<f32>,
+    /// w unused
+    pub normal: Vector4<f32>,
+    /// Elements of the joint matrices of the mesh skin
+    pub joints: Vector4<u32>,
+    pub weights: Vector4<f32>,
+}
+
 #[derive(Copy, Clone)]
 #[repr(C, align(16))]
 pub struct GpuMeshlet {
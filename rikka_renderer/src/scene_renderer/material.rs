@@ -1,9 +1,24 @@
-use std::sync::Arc;
+use std::{
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Result;
+use parking_lot::RwLock;
 
 use rikka_core::nalgebra::Vector4;
-use rikka_gpu::{buffer::Buffer, descriptor_set::DescriptorSet, image::Image};
+use rikka_gpu::{constants::INVALID_BINDLESS_TEXTURE_INDEX, image::Image};
+
+use crate::{
+    renderer::*,
+    scene_renderer::{material_storage::MaterialStorage, mesh::Mesh},
+};
 
-use crate::renderer::*;
+/// Material index of materials without a slot in the material storage buffer
+pub const INVALID_MATERIAL_INDEX: u32 = u32::MAX;
 
 pub const INVALID_FLOAT_VALUE: f32 = f32::MAX;
 
@@ -19,14 +34,63 @@ bitflags::bitflags! {
         const HAS_JOINTS = 0x40;
         const HAS_WEIGHTS = 0x80;
         const ALPHA_DITHER = 0x100;
+        const HAS_TEXCOORDS_1 = 0x200;
+        const HAS_COLORS = 0x400;
+        /// Occlusion texture is sampled with the second texture coordinate set
+        const OCCLUSION_TEXCOORDS_1 = 0x800;
     }
 }
 
+/// Texture slots of a PBR material
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaterialTextureSlot {
+    Diffuse,
+    MetallicRoughness,
+    Normal,
+    Occlusion,
+}
+
+/// Material parameters, one element of the "materials" storage buffer
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct GpuMaterialData {
+    pub base_color_factor: Vector4<f32>,
+    /// x - metallic, y - roughness, z - occlusion strength
+    pub metallic_roughness_occlusion_factor: Vector4<f32>,
+
+    pub diffuse_texture_index: u32,
+    pub metallic_roughness_texture_index: u32,
+    pub normal_texture_index: u32,
+    pub occlusion_texture_index: u32,
+
+    /// `DrawFlags` of the material
+    pub flags: u32,
+    pub alpha_cutoff: f32,
+
+    _pad0: u32,
+    _pad1: u32,
+}
+
+fn texture_index(image: &Option<Handle<Image>>) -> u32 {
+    image
+        .as_ref()
+        .map(|image| image.bindless_index())
+        .unwrap_or(INVALID_BINDLESS_TEXTURE_INDEX)
+}
+
+/// Base color textures are sampled through the sRGB view of textures also used as linear data
+fn srgb_texture_index(image: &Option<Handle<Image>>) -> u32 {
+    image
+        .as_ref()
+        .map(|image| image.srgb_bindless_index())
+        .unwrap_or(INVALID_BINDLESS_TEXTURE_INDEX)
+}
+
+/// Material as loaded from the scene, shared by all meshes using it
 pub struct PBRMaterial {
     pub material: Arc<Material>,
-    /// Also used to store Mesh data
-    pub material_buffer: Handle<Buffer>,
-    pub descriptor_set: Arc<DescriptorSet>,
+    /// Slot in the material storage buffer, assigned when the scene is created
+    material_index: AtomicU32,
 
     pub diffuse_image: Option<Handle<Image>>,
     pub metallic_roughness_image: Option<Handle<Image>>,
@@ -36,19 +100,27 @@ pub struct PBRMaterial {
     pub base_color_factor: Vector4<f32>,
     pub metallic_roughness_occlusion_factor: Vector4<f32>,
     pub alpha_cutoff: f32,
+    /// Flags of the material only, vertex attribute flags are stored per mesh
     pub draw_flags: DrawFlags,
 }
 
 impl PBRMaterial {
-    pub fn new(
-        material: Arc<Material>,
-        material_buffer: Handle<Buffer>,
-        descriptor_set: Arc<DescriptorSet>,
-    ) -> Self {
+    /// Technique permutation defines matching this material
+    pub fn permutation_defines(&self) -> Vec<&'static str> {
+        let mut defines = Vec::new();
+        if self.normal_image.is_some() {
+            defines.push("USE_NORMAL_MAP");
+        }
+        if self.draw_flags.contains(DrawFlags::ALPHA_MASK) {
+            defines.push("ALPHA_MASK");
+        }
+        defines
+    }
+
+    pub fn new(material: Arc<Material>) -> Self {
         Self {
             material,
-            material_buffer,
-            descriptor_set,
+            material_index: AtomicU32::new(INVALID_MATERIAL_INDEX),
             diffuse_image: None,
             metallic_roughness_image: None,
             normal_image: None,
@@ -59,4 +131,232 @@ impl PBRMaterial {
             draw_flags: DrawFlags::NONE,
         }
     }
+
+    pub fn material_index(&self) -> u32 {
+        self.material_index.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_material_index(&self, material_index: u32) {
+        self.material_index.store(material_index, Ordering::Relaxed);
+    }
+
+    pub fn create_gpu_data(&self) -> GpuMaterialData {
+        GpuMaterialData {
+            base_color_factor: self.base_color_factor,
+            metallic_roughness_occlusion_factor: self.metallic_roughness_occlusion_factor,
+            diffuse_texture_index: srgb_texture_index(&self.diffuse_image),
+            metallic_roughness_texture_index: texture_index(&self.metallic_roughness_image),
+            normal_texture_index: texture_index(&self.normal_image),
+            occlusion_texture_index: texture_index(&self.occlusion_image),
+            flags: self.draw_flags.bits(),
+            alpha_cutoff: self.alpha_cutoff,
+            _pad0: 0,
+            _pad1: 0,
+        }
+    }
+}
+
+/// Parameters changed at runtime, None keeps the value of the shared material
+#[derive(Clone, Default)]
+struct MaterialOverrides {
+    base_color_factor: Option<Vector4<f32>>,
+    metallic_factor: Option<f32>,
+    roughness_factor: Option<f32>,
+    diffuse_image: Option<Option<Handle<Image>>>,
+    metallic_roughness_image: Option<Option<Handle<Image>>>,
+    normal_image: Option<Option<Handle<Image>>>,
+    occlusion_image: Option<Option<Handle<Image>>>,
+}
+
+impl MaterialOverrides {
+    fn is_empty(&self) -> bool {
+        self.base_color_factor.is_none()
+            && self.metallic_factor.is_none()
+            && self.roughness_factor.is_none()
+            && self.diffuse_image.is_none()
+            && self.metallic_roughness_image.is_none()
+            && self.normal_image.is_none()
+            && self.occlusion_image.is_none()
+    }
+}
+
+/// Per mesh use of a shared `PBRMaterial`. Meshes index the material storage slot of the shared material until a
+/// parameter is overridden, the instance then gets its own slot.
+pub struct MaterialInstance {
+    material: Arc<PBRMaterial>,
+
+    overrides: RwLock<MaterialOverrides>,
+    /// Allocated by the scene renderer for the first override
+    instance_index: AtomicU32,
+    /// Set when a parameter changed and the material buffer needs to be uploaded again
+    dirty: AtomicBool,
+}
+
+impl MaterialInstance {
+    pub fn new(material: Arc<PBRMaterial>) -> Self {
+        Self {
+            material,
+            overrides: RwLock::new(MaterialOverrides::default()),
+            instance_index: AtomicU32::new(INVALID_MATERIAL_INDEX),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    pub fn shared(&self) -> &Arc<PBRMaterial> {
+        &self.material
+    }
+
+    /// Whether any parameter of the shared material is overridden
+    pub fn overridden(&self) -> bool {
+        !self.overrides.read().is_empty()
+    }
+
+    /// Slot of the material storage buffer the mesh is drawn with
+    pub fn material_index(&self) -> u32 {
+        let instance_index = self.instance_index.load(Ordering::Relaxed);
+        if instance_index != INVALID_MATERIAL_INDEX && self.overridden() {
+            instance_index
+        } else {
+            self.material.material_index()
+        }
+    }
+
+    pub fn base_color(&self) -> Vector4<f32> {
+        self.overrides
+            .read()
+            .base_color_factor
+            .unwrap_or(self.material.base_color_factor)
+    }
+
+    /// x - metallic, y - roughness, z - occlusion strength
+    pub fn metallic_roughness_occlusion(&self) -> Vector4<f32> {
+        let overrides = self.overrides.read();
+        let mut factor = self.material.metallic_roughness_occlusion_factor;
+        factor.x = overrides.metallic_factor.unwrap_or(factor.x);
+        factor.y = overrides.roughness_factor.unwrap_or(factor.y);
+        factor
+    }
+
+    /// Texture bound to `slot`, including runtime changes
+    pub fn texture(&self, slot: MaterialTextureSlot) -> Option<Handle<Image>> {
+        let overrides = self.overrides.read();
+        let (image, texture_override) = match slot {
+            MaterialTextureSlot::Diffuse => {
+                (&self.material.diffuse_image, &overrides.diffuse_image)
+            }
+            MaterialTextureSlot::MetallicRoughness => (
+                &self.material.metallic_roughness_image,
+                &overrides.metallic_roughness_image,
+            ),
+            MaterialTextureSlot::Normal => (&self.material.normal_image, &overrides.normal_image),
+            MaterialTextureSlot::Occlusion => {
+                (&self.material.occlusion_image, &overrides.occlusion_image)
+            }
+        };
+        texture_override.as_ref().unwrap_or(image).clone()
+    }
+
+    pub fn set_base_color(&self, base_color_factor: Vector4<f32>) {
+        self.overrides.write().base_color_factor = Some(base_color_factor);
+        self.mark_dirty();
+    }
+
+    pub fn set_metallic(&self, metallic_factor: f32) {
+        self.overrides.write().metallic_factor = Some(metallic_factor.clamp(0.0, 1.0));
+        self.mark_dirty();
+    }
+
+    pub fn set_roughness(&self, roughness_factor: f32) {
+        self.overrides.write().roughness_factor = Some(roughness_factor.clamp(0.0, 1.0));
+        self.mark_dirty();
+    }
+
+    /// The image needs to be registered in the bindless array, None unbinds the texture of the slot.
+    // XXX: Permutation defines are not updated, setting a normal map on a material loaded without one has no effect
+    pub fn set_texture(&self, slot: MaterialTextureSlot, image: Option<Handle<Image>>) {
+        let mut overrides = self.overrides.write();
+        let texture_override = match slot {
+            MaterialTextureSlot::Diffuse => &mut overrides.diffuse_image,
+            MaterialTextureSlot::MetallicRoughness => &mut overrides.metallic_roughness_image,
+            MaterialTextureSlot::Normal => &mut overrides.normal_image,
+            MaterialTextureSlot::Occlusion => &mut overrides.occlusion_image,
+        };
+        *texture_override = Some(image);
+        drop(overrides);
+
+        self.mark_dirty();
+    }
+
+    /// Drops all overrides, the mesh is drawn with the shared material again
+    pub fn reset(&self) {
+        *self.overrides.write() = MaterialOverrides::default();
+        self.mark_dirty();
+    }
+
+    pub fn create_gpu_data(&self) -> GpuMaterialData {
+        let mut gpu_data = self.material.create_gpu_data();
+        gpu_data.base_color_factor = self.base_color();
+        gpu_data.metallic_roughness_occlusion_factor = self.metallic_roughness_occlusion();
+        gpu_data.diffuse_texture_index =
+            srgb_texture_index(&self.texture(MaterialTextureSlot::Diffuse));
+        gpu_data.metallic_roughness_texture_index =
+            texture_index(&self.texture(MaterialTextureSlot::MetallicRoughness));
+        gpu_data.normal_texture_index = texture_index(&self.texture(MaterialTextureSlot::Normal));
+        gpu_data.occlusion_texture_index =
+            texture_index(&self.texture(MaterialTextureSlot::Occlusion));
+        gpu_data
+    }
+
+    /// Uploads the overridden parameters if they changed since the last call, allocating the instance slot on first use
+    pub(crate) fn update_gpu_data(&self, material_storage: &mut MaterialStorage) -> Result<()> {
+        if !self.dirty.swap(false, Ordering::Relaxed) || !self.overridden() {
+            return Ok(());
+        }
+
+        let mut instance_index = self.instance_index.load(Ordering::Relaxed);
+        if instance_index == INVALID_MATERIAL_INDEX {
+            instance_index = material_storage.allocate()?;
+            self.instance_index.store(instance_index, Ordering::Relaxed);
+        }
+
+        material_storage.write(instance_index, &self.create_gpu_data())
+    }
+
+    fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Deref for MaterialInstance {
+    type Target = PBRMaterial;
+
+    fn deref(&self) -> &Self::Target {
+        &self.material
+    }
+}
+
+/// Editable material of a scene mesh. Parameter changes only affect this mesh and are uploaded by the scene renderer
+/// at the start of the next frame.
+#[derive(Clone)]
+pub struct MaterialHandle {
+    mesh: Arc<Mesh>,
+}
+
+impl MaterialHandle {
+    pub(crate) fn new(mesh: Arc<Mesh>) -> Self {
+        Self { mesh }
+    }
+
+    /// Scene graph node of the mesh using the material
+    pub fn scene_graph_node_index(&self) -> usize {
+        self.mesh.scene_graph_node_index
+    }
+}
+
+impl Deref for MaterialHandle {
+    type Target = MaterialInstance;
+
+    fn deref(&self) -> &Self::Target {
+        &self.mesh.pbr_material
+    }
 }
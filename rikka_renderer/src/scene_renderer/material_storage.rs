@@ -0,0 +1,143 @@
+use std::{collections::HashMap, mem::size_of, sync::Arc};
+
+use anyhow::Result;
+
+use rikka_core::vk;
+use rikka_gpu::{buffer::*, descriptor_set::*};
+
+use crate::{
+    renderer::*,
+    scene_renderer::{material::*, mesh::Mesh},
+};
+
+/// Slots reserved for material instances with overridden parameters
+pub const MAX_MATERIAL_INSTANCE_OVERRIDES: u32 = 256;
+
+/// All scene materials in one storage buffer. Meshes push the index of their material, so every mesh of a technique is
+/// drawn with the same descriptor set 0 with bindings "scene_constants" uniform buffer and "materials" storage buffer.
+pub struct MaterialStorage {
+    buffer: Handle<Buffer>,
+    descriptor_set: Arc<DescriptorSet>,
+    capacity: u32,
+    count: u32,
+}
+
+impl MaterialStorage {
+    /// Assigns a slot to every shared material of `meshes` and uploads its parameters
+    pub fn new(
+        renderer: &Renderer,
+        meshes: &[Arc<Mesh>],
+        render_technique: &RenderTechnique,
+        scene_uniform_buffer: Handle<Buffer>,
+    ) -> Result<Self> {
+        let mut materials = Vec::new();
+        let mut material_indices = HashMap::new();
+        for mesh in meshes {
+            let pbr_material = mesh.pbr_material.shared();
+            material_indices
+                .entry(Arc::as_ptr(pbr_material))
+                .or_insert_with(|| {
+                    materials.push(pbr_material.clone());
+                    materials.len() as u32 - 1
+                });
+        }
+
+        let capacity = materials.len() as u32 + MAX_MATERIAL_INSTANCE_OVERRIDES;
+        let buffer = renderer.create_buffer(
+            BufferDesc::new()
+                .set_usage_flags(vk::BufferUsageFlags::STORAGE_BUFFER)
+                .set_size(capacity * size_of::<GpuMaterialData>() as u32)
+                .set_device_only(false),
+        )?;
+
+        let descriptor_set =
+            Self::create_descriptor_set(renderer, render_technique, scene_uniform_buffer, &buffer)?;
+
+        let gpu_data = materials
+            .iter()
+            .enumerate()
+            .map(|(material_index, pbr_material)| {
+                pbr_material.set_material_index(material_index as u32);
+                pbr_material.create_gpu_data()
+            })
+            .collect::<Vec<_>>();
+        buffer.copy_data_to_buffer(&gpu_data)?;
+
+        log::info!(
+            "Created material storage with {} materials for {} meshes",
+            materials.len(),
+            meshes.len()
+        );
+
+        Ok(Self {
+            buffer,
+            descriptor_set,
+            capacity,
+            count: materials.len() as u32,
+        })
+    }
+
+    pub fn descriptor_set(&self) -> &Arc<DescriptorSet> {
+        &self.descriptor_set
+    }
+
+    /// Descriptor set 0 of `render_technique` for a view with its own scene constants, eg. an additional viewport
+    pub fn create_view_descriptor_set(
+        &self,
+        renderer: &Renderer,
+        render_technique: &RenderTechnique,
+        scene_uniform_buffer: Handle<Buffer>,
+    ) -> Result<Arc<DescriptorSet>> {
+        Self::create_descriptor_set(
+            renderer,
+            render_technique,
+            scene_uniform_buffer,
+            &self.buffer,
+        )
+    }
+
+    /// Descriptor set 0 of `render_technique` with `buffer` bound as the "materials" storage buffer
+    pub(crate) fn create_descriptor_set(
+        renderer: &Renderer,
+        render_technique: &RenderTechnique,
+        scene_uniform_buffer: Handle<Buffer>,
+        buffer: &Handle<Buffer>,
+    ) -> Result<Arc<DescriptorSet>> {
+        let descriptor_set_layout = render_technique
+            .pass(0)
+            .graphics_pipeline
+            .descriptor_set_layouts()[0]
+            .clone();
+        renderer.create_descriptor_set(
+            DescriptorSetDesc::new(descriptor_set_layout)
+                .add_buffer_resource_named("scene_constants", scene_uniform_buffer)
+                .add_buffer_resource_named("materials", buffer.clone()),
+        )
+    }
+
+    /// Storage buffer of `GpuMaterialData` indexed by `material_index`
+    pub fn buffer(&self) -> &Handle<Buffer> {
+        &self.buffer
+    }
+
+    /// Allocates a slot for a material instance
+    // XXX: The storage buffer does not grow and slots are never released until the scene is unloaded
+    pub fn allocate(&mut self) -> Result<u32> {
+        if self.count == self.capacity {
+            return Err(anyhow::anyhow!(
+                "Material storage is full, {} materials are allocated",
+                self.capacity
+            ));
+        }
+
+        self.count += 1;
+        Ok(self.count - 1)
+    }
+
+    pub fn write(&self, material_index: u32, gpu_data: &GpuMaterialData) -> Result<()> {
+        Ok(self.buffer.copy_data_to_buffer_with_offset(
+            std::slice::from_ref(gpu_data),
+            material_index as usize * size_of::<GpuMaterialData>(),
+        )?)
+    }
+}
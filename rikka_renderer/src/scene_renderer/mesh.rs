@@ -1,113 +1,335 @@
-use std::sync::Arc;
+use std::{
+    mem::size_of,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Result;
+use parking_lot::RwLock;
 
 use rikka_core::{
     nalgebra::{Matrix4, Vector4},
     vk,
 };
 use rikka_gpu::{
-    buffer::Buffer, command_buffer::CommandBuffer, constants::INVALID_BINDLESS_TEXTURE_INDEX,
-    image::Image, pipeline::GraphicsPipeline,
+    buffer::*, command_buffer::CommandBuffer, constants::MAX_FRAMES, pipeline::GraphicsPipeline,
 };
 
-use crate::{renderer::*, scene, scene_renderer::material::*};
+use crate::{
+    renderer::*,
+    scene,
+    scene_renderer::{
+        gpu_types::{GpuCullMesh, GpuSkinVertex},
+        material::*,
+        picking::object_id_from_node_index,
+    },
+};
+
+/// Simplified level of detail, indexes the vertex buffers of the full detail mesh
+pub struct MeshLod {
+    pub index_buffer: Handle<Buffer>,
+    pub index_offset: u32,
+    pub index_count: u32,
+    pub index_type: vk::IndexType,
+    /// Object space simplification error
+    pub error: f32,
+
+    /// Meshlets of the level in the scene meshlet buffers, drawn by the mesh shading and indirect draw paths
+    pub meshlet_offset: u32,
+    pub meshlet_count: u32,
+    /// Range of the level triangles in `SceneMeshlets::indices`
+    pub meshlet_first_index: u32,
+    pub meshlet_index_count: u32,
+}
+
+/// Maximum number of simplified LOD levels generated per mesh
+pub const MAX_MESH_LODS: usize = 4;
+
+const INVALID_SKINNED_FRAME: u32 = u32::MAX;
+
+/// Vertices of a mesh deformed by a skin, skinned once per frame by the skinning pass
+pub struct MeshSkin {
+    /// Element of the scene skins
+    pub skin_index: usize,
+    /// Bind pose vertices with their joints and weights
+    pub vertex_buffer: Handle<Buffer>,
+    /// Tightly packed positions and normals written by the skinning pass, one per frame in flight
+    pub skinned_position_buffers: [Handle<Buffer>; MAX_FRAMES as usize],
+    pub skinned_normal_buffers: [Handle<Buffer>; MAX_FRAMES as usize],
+    /// Frame whose skinned vertices are bound by draws, the source vertices are drawn until the first skinning
+    skinned_frame: AtomicU32,
+}
+
+impl MeshSkin {
+    pub fn new(
+        renderer: &mut Renderer,
+        skin_index: usize,
+        vertices: &[GpuSkinVertex],
+    ) -> Result<Self> {
+        // Skinning can run on the async compute queue
+        let vertex_buffer = renderer.create_buffer_from_data(
+            BufferDesc::new()
+                .set_usage_flags(vk::BufferUsageFlags::STORAGE_BUFFER)
+                .set_async_compute(true),
+            vertices,
+        )?;
+
+        let create_skinned_buffer = || {
+            renderer.create_buffer(
+                BufferDesc::new()
+                    .set_size((vertices.len() * size_of::<[f32; 3]>()) as _)
+                    .set_usage_flags(
+                        vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::STORAGE_BUFFER,
+                    )
+                    .set_device_only(true)
+                    .set_async_compute(true),
+            )
+        };
+
+        Ok(Self {
+            skin_index,
+            vertex_buffer,
+            skinned_position_buffers: [create_skinned_buffer()?, create_skinned_buffer()?],
+            skinned_normal_buffers: [create_skinned_buffer()?, create_skinned_buffer()?],
+            skinned_frame: AtomicU32::new(INVALID_SKINNED_FRAME),
+        })
+    }
+
+    pub fn skinned_frame(&self) -> Option<usize> {
+        match self.skinned_frame.load(Ordering::Relaxed) {
+            INVALID_SKINNED_FRAME => None,
+            frame => Some(frame as usize),
+        }
+    }
+
+    /// Set by the skinning pass once the skinned vertices of `frame` are recorded
+    pub fn set_skinned_frame(&self, frame: Option<usize>) {
+        self.skinned_frame.store(
+            frame.map_or(INVALID_SKINNED_FRAME, |frame| frame as u32),
+            Ordering::Relaxed,
+        );
+    }
+}
 
 pub struct Mesh {
-    pub pbr_material: PBRMaterial,
+    pub pbr_material: MaterialInstance,
+    /// Vertex attribute `DrawFlags`, combined with the flags of the material
+    pub vertex_flags: DrawFlags,
 
     pub position_buffer: Option<Handle<Buffer>>,
     pub tex_coords_buffer: Option<Handle<Buffer>>,
     pub normal_buffer: Option<Handle<Buffer>>,
     pub tangent_buffer: Option<Handle<Buffer>>,
+    /// Second texture coordinate set, used by occlusion and light maps
+    pub tex_coords_1_buffer: Option<Handle<Buffer>>,
+    /// RGBA float vertex colors
+    pub color_buffer: Option<Handle<Buffer>>,
     pub index_buffer: Option<Handle<Buffer>>,
 
     pub primitive_count: u32,
+    pub vertex_count: u32,
 
     pub position_offset: u32,
+    /// Bytes between consecutive positions, larger than a vec3 for interleaved vertex data
+    pub position_stride: u32,
     pub tex_coords_offset: u32,
     pub normal_offset: u32,
     pub tangent_offset: u32,
+    pub tex_coords_1_offset: u32,
+    pub color_offset: u32,
 
     pub index_offset: u32,
     pub index_type: vk::IndexType,
+    /// Meshes that are not triangle lists are drawn with the topology variants of their technique and have no
+    /// meshlets or LODs
+    pub topology: vk::PrimitiveTopology,
 
     pub meshlet_offset: u32,
     pub meshlet_count: u32,
+    /// Range of the mesh triangles in `SceneMeshlets::indices`
+    pub meshlet_first_index: u32,
+    pub meshlet_index_count: u32,
+    /// First vertex of the mesh in `SceneMeshlets::vertex_positions`
+    pub meshlet_vertex_offset: u32,
     pub gpu_mesh_index: u32,
 
     pub scene_graph_node_index: usize,
+    /// Bounds in mesh local space
+    pub bounds: scene::Aabb,
+
+    /// Coarser levels of detail, LOD 0 is the full detail mesh
+    pub lods: Vec<MeshLod>,
+    /// Set for meshes deformed by a skin, bounds and LODs are computed from the bind pose
+    pub skin: Option<MeshSkin>,
+    /// Selected each frame by the scene renderer
+    selected_lod: AtomicU32,
+    /// Cleared when the scene graph node or one of its parents is hidden, hidden meshes are not drawn
+    visible: AtomicBool,
+    /// Pushed as constants when the mesh is drawn
+    gpu_data: RwLock<GpuMeshData>,
 }
 
 impl Mesh {
-    pub fn new_with_pbr_material(pbr_material: PBRMaterial) -> Self {
+    pub fn new_with_pbr_material(pbr_material: Arc<PBRMaterial>) -> Self {
         Self {
-            pbr_material,
+            pbr_material: MaterialInstance::new(pbr_material),
+            vertex_flags: DrawFlags::NONE,
             position_buffer: None,
             tex_coords_buffer: None,
             normal_buffer: None,
             tangent_buffer: None,
+            tex_coords_1_buffer: None,
+            color_buffer: None,
             index_buffer: None,
             primitive_count: 0,
+            vertex_count: 0,
             position_offset: 0,
+            position_stride: size_of::<[f32; 3]>() as u32,
             tex_coords_offset: 0,
             normal_offset: 0,
             tangent_offset: 0,
+            tex_coords_1_offset: 0,
+            color_offset: 0,
             index_offset: 0,
             index_type: vk::IndexType::UINT16,
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
             meshlet_offset: u32::MAX,
             meshlet_count: u32::MAX,
+            meshlet_first_index: 0,
+            meshlet_index_count: 0,
+            meshlet_vertex_offset: u32::MAX,
             gpu_mesh_index: u32::MAX,
             scene_graph_node_index: scene::INVALID_INDEX,
+            bounds: scene::Aabb::empty(),
+            lods: Vec::new(),
+            skin: None,
+            selected_lod: AtomicU32::new(0),
+            visible: AtomicBool::new(true),
+            gpu_data: RwLock::new(GpuMeshData::new()),
         }
     }
 
-    fn get_texture_index(image_handle: &Option<Handle<Image>>) -> u32 {
-        if let Some(image) = image_handle {
-            image.bindless_index()
-        } else {
-            INVALID_BINDLESS_TEXTURE_INDEX
+    pub fn lod_count(&self) -> usize {
+        self.lods.len() + 1
+    }
+
+    pub fn selected_lod(&self) -> usize {
+        self.selected_lod.load(Ordering::Relaxed) as usize
+    }
+
+    /// Returns whether the selected LOD level changed
+    pub fn set_selected_lod(&self, lod: usize) -> bool {
+        let lod = lod.min(self.lods.len()) as u32;
+        self.selected_lod.swap(lod, Ordering::Relaxed) != lod
+    }
+
+    /// Bounds and meshlet ranges of a LOD level read by the culling pass
+    pub fn gpu_cull_mesh(&self, lod: usize) -> GpuCullMesh {
+        let (first_index, index_count, meshlet_offset, meshlet_count) = match lod {
+            0 => (
+                self.meshlet_first_index,
+                self.meshlet_index_count,
+                self.meshlet_offset,
+                self.meshlet_count,
+            ),
+            lod => {
+                let lod = &self.lods[lod - 1];
+                (
+                    lod.meshlet_first_index,
+                    lod.meshlet_index_count,
+                    lod.meshlet_offset,
+                    lod.meshlet_count,
+                )
+            }
+        };
+
+        GpuCullMesh {
+            center: self.bounds.center(),
+            radius: self.bounds.radius(),
+            first_index,
+            index_count,
+            meshlet_offset,
+            meshlet_count,
         }
     }
 
+    /// Object space error of a LOD level
+    pub fn lod_error(&self, lod: usize) -> f32 {
+        match lod {
+            0 => 0.0,
+            _ => self.lods[lod - 1].error,
+        }
+    }
+
+    /// Technique permutation defines matching the material and vertex attributes of this mesh
+    pub fn permutation_defines(&self) -> Vec<&'static str> {
+        let mut defines = self.pbr_material.permutation_defines();
+        if self.vertex_flags.contains(DrawFlags::HAS_TEXCOORDS_1) {
+            defines.push("USE_TEXCOORDS_1");
+        }
+        if self.vertex_flags.contains(DrawFlags::HAS_COLORS) {
+            defines.push("USE_VERTEX_COLORS");
+        }
+        defines
+    }
+
     pub fn create_gpu_data(&self) -> GpuMeshData {
         GpuMeshData {
-            global_model: Matrix4::identity(),
-            global_inverse_model: Matrix4::identity(),
-            base_color_factor: self.pbr_material.base_color_factor,
-            diffuse_texture_index: Self::get_texture_index(&self.pbr_material.diffuse_image),
-            metallic_roughness_texture_index: Self::get_texture_index(
-                &self.pbr_material.metallic_roughness_image,
-            ),
-            normal_texture_index: Self::get_texture_index(&self.pbr_material.normal_image),
-            occlusion_texture_index: Self::get_texture_index(&self.pbr_material.occlusion_image),
-            // metallic_roughness_occlusion_factor: self
-            //     .pbr_material
-            //     .metallic_roughness_occlusion_factor,
-            // alpha_cutoff: self.pbr_material.alpha_cutoff,
-            // flags: self.pbr_material.draw_flags.bits(),
+            flags: (self.pbr_material.draw_flags | self.vertex_flags).bits(),
+            object_id: object_id_from_node_index(self.scene_graph_node_index),
+            ..GpuMeshData::new()
         }
     }
 
+    /// Sets the data pushed by the following draws
+    pub fn set_gpu_data(&self, gpu_data: GpuMeshData) {
+        *self.gpu_data.write() = gpu_data;
+    }
+
+    /// Only changes the color tint of the data pushed by the following draws
+    pub fn set_color_tint(&self, color_tint: Vector4<f32>) {
+        self.gpu_data.write().color_tint = color_tint;
+    }
+
+    /// World matrix set by the last `set_gpu_data`
+    pub fn global_model(&self) -> Matrix4<f32> {
+        self.gpu_data.read().global_model
+    }
+
+    /// Draws the selected LOD, the material storage descriptor set has to be bound to set 0.
+    /// Skinned meshes are drawn with the vertices written by the last skinning pass.
     pub fn draw(
         &self,
         command_buffer: &CommandBuffer,
         graphics_pipeline: &GraphicsPipeline,
         zero_buffer: &Buffer,
     ) {
-        command_buffer.bind_vertex_buffer(
-            self.position_buffer.as_ref().unwrap(),
-            0,
-            self.position_offset as _,
-        );
+        let skinned_frame = self
+            .skin
+            .as_ref()
+            .and_then(|skin| skin.skinned_frame().map(|frame| (skin, frame)));
+        if let Some((skin, frame)) = skinned_frame {
+            command_buffer.bind_vertex_buffer(&skin.skinned_position_buffers[frame], 0, 0);
+            command_buffer.bind_vertex_buffer(&skin.skinned_normal_buffers[frame], 2, 0);
+        } else {
+            command_buffer.bind_vertex_buffer(
+                self.position_buffer.as_ref().unwrap(),
+                0,
+                self.position_offset as _,
+            );
+            command_buffer.bind_vertex_buffer(
+                self.normal_buffer.as_ref().unwrap(),
+                2,
+                self.normal_offset as _,
+            );
+        }
         command_buffer.bind_vertex_buffer(
             self.tex_coords_buffer.as_ref().unwrap(),
             1,
             self.tex_coords_offset as _,
         );
-        command_buffer.bind_vertex_buffer(
-            self.normal_buffer.as_ref().unwrap(),
-            2,
-            self.normal_offset as _,
-        );
 
         // XXX: From where should we access the zero buffer?
         if let Some(tangent_buffer) = &self.tangent_buffer {
@@ -115,18 +337,50 @@ impl Mesh {
         } else {
             command_buffer.bind_vertex_buffer(&zero_buffer, 3, 0);
         }
+        if let Some(tex_coords_1_buffer) = &self.tex_coords_1_buffer {
+            command_buffer.bind_vertex_buffer(
+                tex_coords_1_buffer,
+                4,
+                self.tex_coords_1_offset as _,
+            );
+        } else {
+            command_buffer.bind_vertex_buffer(&zero_buffer, 4, 0);
+        }
+        if let Some(color_buffer) = &self.color_buffer {
+            command_buffer.bind_vertex_buffer(color_buffer, 5, self.color_offset as _);
+        } else {
+            command_buffer.bind_vertex_buffer(&zero_buffer, 5, 0);
+        }
 
-        command_buffer
-            .bind_index_buffer(self.index_buffer.as_ref().unwrap(), self.index_offset as _);
+        let (index_buffer, index_offset, index_count, index_type) = match self.selected_lod() {
+            0 => (
+                self.index_buffer.as_ref().unwrap(),
+                self.index_offset,
+                self.primitive_count,
+                self.index_type,
+            ),
+            lod => {
+                let lod = &self.lods[lod - 1];
+                (
+                    &lod.index_buffer,
+                    lod.index_offset,
+                    lod.index_count,
+                    lod.index_type,
+                )
+            }
+        };
+        command_buffer.bind_index_buffer(index_buffer, index_offset as _, index_type);
 
-        // XXX: From where should we access the graphics pipeline layout?
-        command_buffer.bind_descriptor_set(
-            &self.pbr_material.descriptor_set,
+        // Material overrides can change the material index between frames
+        let mut gpu_data = *self.gpu_data.read();
+        gpu_data.material_index = self.pbr_material.material_index();
+        command_buffer.push_constants(
             graphics_pipeline.raw_layout(),
-            0,
+            vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+            &gpu_data,
         );
 
-        command_buffer.draw_indexed(self.primitive_count, 1, 0, 0, 0);
+        command_buffer.draw_indexed(index_count, 1, 0, 0, 0);
     }
 
     pub fn transparent(&self) -> bool {
@@ -134,6 +388,14 @@ impl Mesh {
             .draw_flags
             .contains(DrawFlags::TRANSPARENT)
     }
+
+    pub fn visible(&self) -> bool {
+        self.visible.load(Ordering::Relaxed)
+    }
+
+    pub fn set_visible(&self, visible: bool) {
+        self.visible.store(visible, Ordering::Relaxed);
+    }
 }
 
 #[derive(Clone)]
@@ -184,27 +446,38 @@ impl MeshInstanceDraw {
     }
 }
 
-/// Mesh material data
+/// Per mesh data, pushed as constants. Material parameters are fetched from the material storage buffer.
 #[derive(Clone, Copy)]
 #[repr(C)]
 pub struct GpuMeshData {
     pub global_model: Matrix4<f32>,
-    pub global_inverse_model: Matrix4<f32>,
+    /// Multiplied with the material base color, used by debug views
+    pub color_tint: Vector4<f32>,
 
-    pub base_color_factor: Vector4<f32>,
+    /// `DrawFlags` of the material and vertex attributes
+    pub flags: u32,
+    /// Written to the object ID attachment, see `picking::object_id_from_node_index`
+    pub object_id: u32,
+    /// Element of the "materials" storage buffer
+    pub material_index: u32,
 
-    pub diffuse_texture_index: u32,
-    pub metallic_roughness_texture_index: u32,
-    pub normal_texture_index: u32,
-    pub occlusion_texture_index: u32,
-    // pub metallic_roughness_occlusion_factor: Vector4<f32>,
-    // pub alpha_cutoff: f32,
-    // pub flags: u32,
+    _pad0: u32,
 }
 
 impl GpuMeshData {
+    pub fn new() -> Self {
+        Self {
+            global_model: Matrix4::identity(),
+            color_tint: Vector4::new(1.0, 1.0, 1.0, 1.0),
+            flags: 0,
+            object_id: 0,
+            material_index: INVALID_MATERIAL_INDEX,
+            _pad0: 0,
+        }
+    }
+
+    /// Normals are transformed with the inverse transpose computed in the shader
     pub fn set_matrices_from_scene_graph(&mut self, mesh: &Mesh, scene_graph: &scene::Graph) {
         self.global_model = scene_graph.global_matrices[mesh.scene_graph_node_index];
-        self.global_inverse_model = self.global_model.try_inverse().unwrap();
     }
 }
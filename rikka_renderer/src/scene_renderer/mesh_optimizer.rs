@@ -0,0 +1,286 @@
+use rikka_core::nalgebra::Vector3;
+
+/// Post-transform vertex cache size used for statistics, close to recent Gpus
+pub const VERTEX_CACHE_SIZE: usize = 16;
+
+/// Average cache miss ratio, transformed vertices per triangle with a FIFO cache. Lower is better, 0.5 is optimal
+pub fn average_cache_miss_ratio(indices: &[u32], vertex_count: usize) -> f32 {
+    if indices.len() < 3 {
+        return 0.0;
+    }
+
+    let mut cache_timestamps = vec![0usize; vertex_count];
+    let mut timestamp = VERTEX_CACHE_SIZE + 1;
+    let mut misses = 0;
+
+    for index in indices {
+        let cache_timestamp = &mut cache_timestamps[*index as usize];
+        if timestamp - *cache_timestamp > VERTEX_CACHE_SIZE {
+            *cache_timestamp = timestamp;
+            timestamp += 1;
+            misses += 1;
+        }
+    }
+
+    misses as f32 / (indices.len() / 3) as f32
+}
+
+#[derive(Clone, Copy)]
+struct OptimizerVertex([f32; 3]);
+
+impl meshopt_rs::Position for OptimizerVertex {
+    fn pos(&self) -> [f32; 3] {
+        self.0
+    }
+}
+
+fn optimizer_vertices(positions: &[[f32; 3]]) -> Vec<OptimizerVertex> {
+    positions
+        .iter()
+        .map(|position| OptimizerVertex(*position))
+        .collect()
+}
+
+/// Reorders triangles for post-transform vertex cache efficiency with meshopt
+pub fn optimize_vertex_cache(indices: &[u32], vertex_count: usize) -> Vec<u32> {
+    let mut optimized_indices = vec![0; indices.len()];
+    meshopt_rs::vertex::cache::optimize_vertex_cache(&mut optimized_indices, indices, vertex_count);
+    optimized_indices
+}
+
+/// Reorders triangles of a cache optimized index buffer with meshopt to reduce overdraw. Triangle clusters may only
+/// be reordered if the cache miss ratio stays below `threshold` times the input ratio.
+pub fn optimize_overdraw(indices: &[u32], positions: &[[f32; 3]], threshold: f32) -> Vec<u32> {
+    let mut optimized_indices = vec![0; indices.len()];
+    meshopt_rs::overdraw::optimize_overdraw(
+        &mut optimized_indices,
+        indices,
+        &optimizer_vertices(positions),
+        threshold,
+    );
+    optimized_indices
+}
+
+/// Remaps vertices in the order they are first referenced with meshopt to improve vertex fetch locality, `indices`
+/// are rewritten in place. Returns the new vertex count and the old to new vertex remap table, unreferenced vertices
+/// map to u32::MAX.
+pub fn optimize_vertex_fetch_remap(indices: &mut [u32], vertex_count: usize) -> (usize, Vec<u32>) {
+    let mut remap = vec![u32::MAX; vertex_count];
+    let remapped_vertex_count =
+        meshopt_rs::vertex::fetch::optimize_vertex_fetch_remap(&mut remap, indices);
+
+    for index in indices.iter_mut() {
+        *index = remap[*index as usize];
+    }
+
+    (remapped_vertex_count, remap)
+}
+
+/// Applies a remap table from `optimize_vertex_fetch_remap` to a vertex attribute
+pub fn remap_vertex_attribute<T: Copy + Default>(
+    attribute: &[T],
+    remap: &[u32],
+    vertex_count: usize,
+) -> Vec<T> {
+    let mut remapped = vec![T::default(); vertex_count];
+    for (old_index, new_index) in remap.iter().enumerate() {
+        if *new_index != u32::MAX {
+            remapped[*new_index as usize] = attribute[old_index];
+        }
+    }
+    remapped
+}
+
+/// Stop generating LOD levels once a level removes less than this fraction of the triangles
+const MIN_LOD_TRIANGLE_REDUCTION: f32 = 0.1;
+const MIN_LOD_TRIANGLE_COUNT: usize = 64;
+
+/// Error of the first LOD level relative to the mesh extent, doubled for every following level
+const LOD_BASE_TARGET_ERROR: f32 = 0.01;
+const LOD_MAX_TARGET_ERROR: f32 = 0.5;
+
+/// Simplified index buffer referencing the vertices of the full detail mesh
+pub struct SimplifiedIndices {
+    pub indices: Vec<u32>,
+    /// Upper bound of the object space distance between the simplified and the full detail surface
+    pub error: f32,
+}
+
+/// Generates up to `max_lod_count` coarser LOD levels with meshopt's edge collapse simplifier, ordered from the most
+/// to the least detailed. Every level halves the triangle count of the previous one unless the error bound is hit.
+pub fn generate_lods(
+    indices: &[u32],
+    positions: &[[f32; 3]],
+    max_lod_count: usize,
+) -> Vec<SimplifiedIndices> {
+    let mut min = Vector3::repeat(f32::MAX);
+    let mut max = Vector3::repeat(f32::MIN);
+    for position in positions {
+        let position = Vector3::from(*position);
+        min = min.inf(&position);
+        max = max.sup(&position);
+    }
+    let extent = (max - min).max().max(0.0);
+
+    let vertices = optimizer_vertices(positions);
+
+    let mut lods: Vec<SimplifiedIndices> = Vec::new();
+    let mut target_error = LOD_BASE_TARGET_ERROR;
+
+    while lods.len() < max_lod_count && target_error <= LOD_MAX_TARGET_ERROR {
+        let triangle_count = lods.last().map_or(indices.len(), |lod| lod.indices.len()) / 3;
+        if triangle_count <= MIN_LOD_TRIANGLE_COUNT {
+            break;
+        }
+
+        // Levels are simplified from the full detail mesh so their errors do not accumulate
+        let mut simplified_indices = vec![0; indices.len()];
+        let index_count = meshopt_rs::simplify::simplify(
+            &mut simplified_indices,
+            indices,
+            &vertices,
+            triangle_count / 2 * 3,
+            target_error,
+        );
+        simplified_indices.truncate(index_count);
+        let error = target_error * extent;
+        target_error *= 2.0;
+
+        let lod_triangle_count = simplified_indices.len() / 3;
+        if lod_triangle_count == 0 {
+            break;
+        }
+        if (lod_triangle_count as f32) > triangle_count as f32 * (1.0 - MIN_LOD_TRIANGLE_REDUCTION)
+        {
+            continue;
+        }
+
+        lods.push(SimplifiedIndices {
+            indices: simplified_indices,
+            error,
+        });
+    }
+
+    lods
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Triangulated `size` x `size` quad grid in the XY plane, triangles in a scattered order
+    fn grid(size: u32) -> (Vec<u32>, Vec<[f32; 3]>) {
+        let positions = (0..=size)
+            .flat_map(|y| (0..=size).map(move |x| [x as f32, y as f32, 0.0]))
+            .collect::<Vec<_>>();
+
+        let mut triangles = Vec::new();
+        for y in 0..size {
+            for x in 0..size {
+                let corner = y * (size + 1) + x;
+                triangles.push([corner, corner + 1, corner + size + 2]);
+                triangles.push([corner, corner + size + 2, corner + size + 1]);
+            }
+        }
+        // Interleave both halves so the input order has little locality
+        let (first, second) = triangles.split_at(triangles.len() / 2);
+        let indices = first
+            .iter()
+            .zip(second.iter().rev())
+            .flat_map(|(a, b)| a.iter().chain(b.iter()).copied())
+            .collect::<Vec<_>>();
+
+        (indices, positions)
+    }
+
+    fn sorted_triangles(indices: &[u32], vertices: &[[f32; 3]]) -> Vec<[[u32; 3]; 3]> {
+        let mut triangles = indices
+            .chunks_exact(3)
+            .map(|triangle| {
+                let mut corners =
+                    [0, 1, 2].map(|corner| vertices[triangle[corner] as usize].map(|c| c as u32));
+                // Rotate the smallest corner first, the winding stays the same
+                let first = (0..3).min_by_key(|corner| corners[*corner]).unwrap();
+                corners.rotate_left(first);
+                corners
+            })
+            .collect::<Vec<_>>();
+        triangles.sort();
+        triangles
+    }
+
+    #[test]
+    fn test_optimize_vertex_cache() {
+        let (indices, positions) = grid(16);
+
+        let optimized = optimize_vertex_cache(&indices, positions.len());
+
+        assert_eq!(
+            sorted_triangles(&optimized, &positions),
+            sorted_triangles(&indices, &positions)
+        );
+        assert!(
+            average_cache_miss_ratio(&optimized, positions.len())
+                < average_cache_miss_ratio(&indices, positions.len())
+        );
+    }
+
+    #[test]
+    fn test_optimize_vertex_fetch_remap() {
+        let (indices, positions) = grid(4);
+        // Vertex 0 is left unreferenced
+        let mut indices = indices
+            .chunks_exact(3)
+            .filter(|triangle| !triangle.contains(&0))
+            .flatten()
+            .copied()
+            .collect::<Vec<_>>();
+        let original_indices = indices.clone();
+
+        let (vertex_count, remap) = optimize_vertex_fetch_remap(&mut indices, positions.len());
+        let remapped_positions = remap_vertex_attribute(&positions, &remap, vertex_count);
+
+        assert_eq!(vertex_count, positions.len() - 1);
+        assert_eq!(remap[0], u32::MAX);
+        assert_eq!(
+            sorted_triangles(&indices, &remapped_positions),
+            sorted_triangles(&original_indices, &positions)
+        );
+
+        let mut next_vertex = 0;
+        for index in &indices {
+            assert!(*index <= next_vertex);
+            if *index == next_vertex {
+                next_vertex += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn test_build_lods() {
+        let (indices, positions) = grid(32);
+
+        let lods = generate_lods(&indices, &positions, 4);
+
+        assert!(!lods.is_empty());
+        let mut triangle_count = indices.len() / 3;
+        let mut error = 0.0;
+        for lod in &lods {
+            assert!(lod.indices.len() / 3 < triangle_count);
+            assert!(lod.error > error);
+            assert!(lod
+                .indices
+                .iter()
+                .all(|index| (*index as usize) < positions.len()));
+            triangle_count = lod.indices.len() / 3;
+            error = lod.error;
+        }
+    }
+
+    #[test]
+    fn test_build_lods_small_mesh() {
+        let (indices, positions) = grid(4);
+
+        assert!(generate_lods(&indices, &positions, 4).is_empty());
+    }
+}
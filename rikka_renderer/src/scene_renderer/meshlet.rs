@@ -1,6 +1,349 @@
-use rikka_core::nalgebra::{Vector2, Vector3, Vector4};
+use anyhow::Result;
+
+use rikka_core::{
+    nalgebra::{Vector2, Vector3, Vector4},
+    vk,
+};
+use rikka_gpu::buffer::*;
+
+use crate::{renderer::*, scene_renderer::gpu_types::*};
+
+/// Meshlet limits matching the mesh shader workgroup output sizes
+pub const MAX_MESHLET_VERTICES: usize = 64;
+pub const MAX_MESHLET_TRIANGLES: usize = 124;
 
 pub struct MeshletToMeshIndex {
     pub mesh_index: u32,
     pub primitive_index: u32,
 }
+
+#[derive(Clone, Copy, Debug)]
+pub struct Meshlet {
+    /// Offset into `Meshlets::vertices`
+    pub vertex_offset: u32,
+    /// Offset into `Meshlets::triangles`, in bytes
+    pub triangle_offset: u32,
+    pub vertex_count: u32,
+    pub triangle_count: u32,
+
+    pub center: Vector3<f32>,
+    pub radius: f32,
+}
+
+/// Meshlets of a single indexed triangle list
+#[derive(Default)]
+pub struct Meshlets {
+    pub meshlets: Vec<Meshlet>,
+    /// Mesh vertex indices referenced by the meshlets
+    pub vertices: Vec<u32>,
+    /// Meshlet local vertex indices, 3 per triangle
+    pub triangles: Vec<u8>,
+}
+
+/// Greedily splits a triangle list into meshlets in index order.
+/// XXX: Use meshopt's spatial clustering, index order only gives good meshlets for cache optimized index buffers
+pub fn build_meshlets(indices: &[u32], positions: &[[f32; 3]]) -> Meshlets {
+    let mut meshlets = Meshlets::default();
+
+    // Meshlet local index of every mesh vertex, u8::MAX when not part of the current meshlet
+    let mut local_indices = vec![u8::MAX; positions.len()];
+    let mut current = Meshlet {
+        vertex_offset: 0,
+        triangle_offset: 0,
+        vertex_count: 0,
+        triangle_count: 0,
+        center: Vector3::zeros(),
+        radius: 0.0,
+    };
+
+    for triangle in indices.chunks_exact(3) {
+        let new_vertices = triangle
+            .iter()
+            .filter(|index| local_indices[**index as usize] == u8::MAX)
+            .count();
+
+        if current.vertex_count as usize + new_vertices > MAX_MESHLET_VERTICES
+            || current.triangle_count as usize + 1 > MAX_MESHLET_TRIANGLES
+        {
+            finish_meshlet(&mut meshlets, &mut current, &mut local_indices, positions);
+        }
+
+        for index in triangle {
+            let local_index = &mut local_indices[*index as usize];
+            if *local_index == u8::MAX {
+                *local_index = current.vertex_count as u8;
+                meshlets.vertices.push(*index);
+                current.vertex_count += 1;
+            }
+            meshlets.triangles.push(*local_index);
+        }
+        current.triangle_count += 1;
+    }
+
+    if current.triangle_count > 0 {
+        finish_meshlet(&mut meshlets, &mut current, &mut local_indices, positions);
+    }
+
+    meshlets
+}
+
+fn finish_meshlet(
+    meshlets: &mut Meshlets,
+    current: &mut Meshlet,
+    local_indices: &mut [u8],
+    positions: &[[f32; 3]],
+) {
+    let vertices = &meshlets.vertices[current.vertex_offset as usize..];
+    for index in vertices {
+        local_indices[*index as usize] = u8::MAX;
+    }
+
+    // Bounding sphere around the vertex centroid
+    let center = vertices
+        .iter()
+        .map(|index| Vector3::from(positions[*index as usize]))
+        .sum::<Vector3<f32>>()
+        / vertices.len().max(1) as f32;
+    current.center = center;
+    current.radius = vertices
+        .iter()
+        .map(|index| (Vector3::from(positions[*index as usize]) - center).norm())
+        .fold(0.0, f32::max);
+
+    meshlets.meshlets.push(*current);
+
+    current.vertex_offset = meshlets.vertices.len() as u32;
+    current.triangle_offset = meshlets.triangles.len() as u32;
+    current.vertex_count = 0;
+    current.triangle_count = 0;
+}
+
+/// Vertex attributes of a single mesh, all slices have the same length
+pub struct MeshletVertexAttributes<'a> {
+    pub positions: &'a [[f32; 3]],
+    pub normals: &'a [[f32; 3]],
+    /// Empty if the mesh has no tangents
+    pub tangents: &'a [[f32; 4]],
+    /// Empty if the mesh has no texture coordinates
+    pub tex_coords: &'a [[f32; 2]],
+}
+
+/// Meshlet data of all scene meshes in the layout of the meshlet storage buffers
+#[derive(Default)]
+pub struct SceneMeshlets {
+    pub meshlets: Vec<GpuMeshlet>,
+    pub vertex_positions: Vec<GpuMeshletVertexPosition>,
+    pub vertex_data: Vec<GpuMeshletVertexData>,
+    /// Per meshlet, scene vertex indices followed by the local triangle indices packed 4 per u32
+    pub data: Vec<u32>,
+    /// Scene vertex indices of all meshlet triangles, drawn by the indirect draw path without mesh shaders
+    pub indices: Vec<u32>,
+}
+
+fn pack_unorm8(value: f32) -> u8 {
+    ((value * 0.5 + 0.5).clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+fn pack_snorm8(value: f32) -> i8 {
+    (value.clamp(-1.0, 1.0) * 127.0).round() as i8
+}
+
+/// Round towards zero conversion, texture coordinates do not need exact rounding
+fn f32_to_f16_bits(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    let mantissa = bits & 0x7f_ffff;
+
+    if exponent <= 0 {
+        sign
+    } else if exponent >= 0x1f {
+        sign | 0x7c00
+    } else {
+        sign | ((exponent as u16) << 10) | (mantissa >> 13) as u16
+    }
+}
+
+/// Normal cone of the meshlet triangles for backface cone culling, the cutoff is 1 when the cone is too wide to cull
+fn meshlet_cone(
+    meshlet: &Meshlet,
+    vertices: &[u32],
+    triangles: &[u8],
+    positions: &[[f32; 3]],
+) -> (Vector3<f32>, f32) {
+    let triangle_normals = triangles[meshlet.triangle_offset as usize..]
+        .chunks_exact(3)
+        .take(meshlet.triangle_count as usize)
+        .filter_map(|triangle| {
+            let position = |local_index: u8| {
+                Vector3::from(
+                    positions[vertices[meshlet.vertex_offset as usize + local_index as usize]
+                        as usize],
+                )
+            };
+            let (a, b, c) = (
+                position(triangle[0]),
+                position(triangle[1]),
+                position(triangle[2]),
+            );
+            (b - a).cross(&(c - a)).try_normalize(f32::EPSILON)
+        })
+        .collect::<Vec<_>>();
+
+    let axis = match triangle_normals
+        .iter()
+        .sum::<Vector3<f32>>()
+        .try_normalize(f32::EPSILON)
+    {
+        Some(axis) => axis,
+        None => return (Vector3::zeros(), 1.0),
+    };
+
+    let min_dot = triangle_normals
+        .iter()
+        .map(|normal| normal.dot(&axis))
+        .fold(1.0, f32::min);
+    if min_dot <= 0.1 {
+        return (axis, 1.0);
+    }
+
+    (axis, (1.0 - min_dot * min_dot).sqrt())
+}
+
+impl SceneMeshlets {
+    /// Appends the meshlets of a mesh, returns the offset of its first meshlet
+    pub fn add_mesh(
+        &mut self,
+        mesh_index: u32,
+        attributes: &MeshletVertexAttributes,
+        meshlets: &Meshlets,
+    ) -> u32 {
+        let vertex_base = self.vertex_positions.len() as u32;
+
+        for (vertex_index, position) in attributes.positions.iter().enumerate() {
+            let normal = attributes.normals[vertex_index];
+            let tangent = attributes
+                .tangents
+                .get(vertex_index)
+                .copied()
+                .unwrap_or([0.0; 4]);
+            let tex_coords = attributes
+                .tex_coords
+                .get(vertex_index)
+                .copied()
+                .unwrap_or([0.0; 2]);
+
+            self.vertex_positions
+                .push(GpuMeshletVertexPosition::new(Vector3::from(*position)));
+            self.vertex_data.push(GpuMeshletVertexData {
+                normal: Vector4::new(
+                    pack_unorm8(normal[0]),
+                    pack_unorm8(normal[1]),
+                    pack_unorm8(normal[2]),
+                    0,
+                ),
+                tangent: Vector4::new(
+                    pack_unorm8(tangent[0]),
+                    pack_unorm8(tangent[1]),
+                    pack_unorm8(tangent[2]),
+                    pack_unorm8(tangent[3]),
+                ),
+                tex_coords: Vector2::new(
+                    f32_to_f16_bits(tex_coords[0]),
+                    f32_to_f16_bits(tex_coords[1]),
+                ),
+            });
+        }
+
+        self.add_meshlets(mesh_index, vertex_base, attributes.positions, meshlets)
+    }
+
+    /// Appends meshlets indexing the vertices of a mesh added at `vertex_base`, eg. the meshlets of a LOD level.
+    /// Returns the offset of the first meshlet.
+    pub fn add_meshlets(
+        &mut self,
+        mesh_index: u32,
+        vertex_base: u32,
+        positions: &[[f32; 3]],
+        meshlets: &Meshlets,
+    ) -> u32 {
+        let meshlet_offset = self.meshlets.len() as u32;
+
+        for meshlet in &meshlets.meshlets {
+            let (cone_axis, cone_cutoff) =
+                meshlet_cone(meshlet, &meshlets.vertices, &meshlets.triangles, positions);
+
+            self.meshlets.push(GpuMeshlet {
+                center: meshlet.center,
+                radius: meshlet.radius,
+                cone_axis: cone_axis.map(pack_snorm8),
+                cone_cutoff: pack_snorm8(cone_cutoff),
+                data_offset: self.data.len() as u32,
+                mesh_index,
+                vertex_count: meshlet.vertex_count as u8,
+                triangle_count: meshlet.triangle_count as u8,
+            });
+
+            let vertices = &meshlets.vertices[meshlet.vertex_offset as usize..]
+                [..meshlet.vertex_count as usize];
+            self.data
+                .extend(vertices.iter().map(|index| vertex_base + index));
+
+            let triangles = &meshlets.triangles[meshlet.triangle_offset as usize..]
+                [..meshlet.triangle_count as usize * 3];
+            self.indices.extend(
+                triangles
+                    .iter()
+                    .map(|local_index| vertex_base + vertices[*local_index as usize]),
+            );
+            self.data.extend(triangles.chunks(4).map(|indices| {
+                indices
+                    .iter()
+                    .enumerate()
+                    .fold(0, |packed, (index, local_index)| {
+                        packed | (*local_index as u32) << (index * 8)
+                    })
+            }));
+        }
+
+        meshlet_offset
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.meshlets.is_empty()
+    }
+}
+
+/// Storage buffers read by the mesh shading and indirect draw passes
+pub struct MeshletStorageBuffers {
+    pub meshlets: Handle<Buffer>,
+    pub vertex_positions: Handle<Buffer>,
+    pub vertex_data: Handle<Buffer>,
+    pub data: Handle<Buffer>,
+    /// 32 bit index buffer of `SceneMeshlets::indices`
+    pub indices: Handle<Buffer>,
+}
+
+fn create_storage_buffer<T: Copy>(renderer: &Renderer, data: &[T]) -> Result<Handle<Buffer>> {
+    renderer.create_buffer_from_data(
+        BufferDesc::new().set_usage_flags(vk::BufferUsageFlags::STORAGE_BUFFER),
+        data,
+    )
+}
+
+impl MeshletStorageBuffers {
+    pub fn new(renderer: &mut Renderer, scene_meshlets: &SceneMeshlets) -> Result<Self> {
+        Ok(Self {
+            meshlets: create_storage_buffer(renderer, &scene_meshlets.meshlets)?,
+            vertex_positions: create_storage_buffer(renderer, &scene_meshlets.vertex_positions)?,
+            vertex_data: create_storage_buffer(renderer, &scene_meshlets.vertex_data)?,
+            data: create_storage_buffer(renderer, &scene_meshlets.data)?,
+            indices: renderer.create_buffer_from_data(
+                BufferDesc::new().set_usage_flags(
+                    vk::BufferUsageFlags::INDEX_BUFFER | vk::BufferUsageFlags::STORAGE_BUFFER,
+                ),
+                &scene_meshlets.indices,
+            )?,
+        })
+    }
+}
@@ -1,8 +1,14 @@
 pub mod scene_renderer;
+pub mod viewport;
 
+pub(crate) mod cooked;
+pub(crate) mod draw_list;
+pub(crate) mod gltf;
 pub(crate) mod gpu_types;
 pub(crate) mod material;
+pub(crate) mod material_storage;
 pub(crate) mod mesh;
+pub(crate) mod mesh_optimizer;
 pub(crate) mod meshlet;
-
-mod gltf;
+pub(crate) mod picking;
+pub(crate) mod tangent;
@@ -0,0 +1,174 @@
+use anyhow::Result;
+
+use rikka_core::vk;
+use rikka_gpu::{
+    barriers::*, buffer::*, command_buffer::CommandBuffer, escape::Handle, image::Image,
+};
+
+use crate::{renderer::*, scene};
+
+/// Render graph attachment the geometry pass writes the object IDs of the meshes to
+pub const OBJECT_ID_RESOURCE_NAME: &str = "object_ids";
+
+/// Object ID of pixels not covered by a mesh, the attachment is cleared to zero
+pub const INVALID_OBJECT_ID: u32 = 0;
+
+/// Object IDs are the scene graph node index offset by one so the cleared attachment reads as no object
+pub fn object_id_from_node_index(node_index: usize) -> u32 {
+    if node_index == scene::INVALID_INDEX {
+        INVALID_OBJECT_ID
+    } else {
+        node_index as u32 + 1
+    }
+}
+
+pub fn node_index_from_object_id(object_id: u32) -> Option<usize> {
+    if object_id == INVALID_OBJECT_ID {
+        None
+    } else {
+        Some(object_id as usize - 1)
+    }
+}
+
+struct PickReadback {
+    buffer: Handle<Buffer>,
+    /// Graphics timeline value signaled by the frame that recorded the copy, None if the readback is free
+    signal_value: Option<u64>,
+}
+
+/// Reads back single texels of the object ID attachment without stalling the Gpu.
+/// Two readback buffers are cycled so a new request can be recorded while the previous copy is in flight.
+pub struct ObjectPicker {
+    readbacks: [PickReadback; 2],
+    next_readback: usize,
+    /// Pixel to be copied by the next rendered frame
+    requested_position: Option<(u32, u32)>,
+    /// Result of the most recently completed readback
+    picked_node_index: Option<usize>,
+    last_completed_value: u64,
+}
+
+impl ObjectPicker {
+    pub fn new(renderer: &Renderer) -> Result<Self> {
+        let create_readback = || -> Result<PickReadback> {
+            let buffer = renderer.create_buffer(
+                BufferDesc::new()
+                    .set_size(std::mem::size_of::<u32>() as _)
+                    .set_device_only(false)
+                    .set_readback(true),
+            )?;
+            Ok(PickReadback {
+                buffer,
+                signal_value: None,
+            })
+        };
+
+        Ok(Self {
+            readbacks: [create_readback()?, create_readback()?],
+            next_readback: 0,
+            requested_position: None,
+            picked_node_index: None,
+            last_completed_value: 0,
+        })
+    }
+
+    /// Requests a readback of the pixel at (x, y), replacing a request that was not recorded yet
+    pub fn request(&mut self, x: u32, y: u32) {
+        self.requested_position = Some((x, y));
+    }
+
+    /// Scene graph node index under the most recently completed request
+    pub fn picked_node_index(&self) -> Option<usize> {
+        self.picked_node_index
+    }
+
+    /// Drops pending requests and results, readbacks in flight are ignored once they complete
+    pub fn clear(&mut self) {
+        self.requested_position = None;
+        self.picked_node_index = None;
+        for readback in &mut self.readbacks {
+            readback.signal_value = None;
+        }
+    }
+
+    /// Resolves readbacks of frames the Gpu finished, results of newer frames replace older ones
+    pub fn resolve(&mut self, completed_value: u64) -> Result<()> {
+        for readback in &mut self.readbacks {
+            let signal_value = match readback.signal_value {
+                Some(signal_value) if signal_value <= completed_value => signal_value,
+                _ => continue,
+            };
+            readback.signal_value = None;
+
+            if signal_value < self.last_completed_value {
+                continue;
+            }
+            self.last_completed_value = signal_value;
+
+            let object_id = readback.buffer.read_data_from_buffer::<u32>(1)?[0];
+            self.picked_node_index = node_index_from_object_id(object_id);
+        }
+
+        Ok(())
+    }
+
+    /// Records the copy of the requested pixel, skipped if both readbacks are still in flight.
+    /// `object_ids` has to be in `state`, it is returned to `state` after the copy.
+    pub fn record(
+        &mut self,
+        command_buffer: &CommandBuffer,
+        object_ids: &Image,
+        state: ResourceState,
+        signal_value: u64,
+    ) {
+        let (x, y) = match self.requested_position {
+            Some(position) => position,
+            None => return,
+        };
+
+        let readback = &mut self.readbacks[self.next_readback];
+        if readback.signal_value.is_some() {
+            return;
+        }
+
+        let extent = object_ids.extent();
+        if x >= extent.width || y >= extent.height {
+            self.requested_position = None;
+            return;
+        }
+
+        if state != ResourceState::COPY_SOURCE {
+            command_buffer.pipeline_barrier(Barriers::new().add_image(
+                object_ids,
+                state,
+                ResourceState::COPY_SOURCE,
+            ));
+        }
+        command_buffer.copy_image_region_to_buffer(
+            object_ids,
+            &readback.buffer,
+            0,
+            vk::Offset3D {
+                x: x as i32,
+                y: y as i32,
+                z: 0,
+            },
+            vk::Extent3D {
+                width: 1,
+                height: 1,
+                depth: 1,
+            },
+        );
+        if state != ResourceState::COPY_SOURCE {
+            command_buffer.pipeline_barrier(Barriers::new().add_image(
+                object_ids,
+                ResourceState::COPY_SOURCE,
+                state,
+            ));
+        }
+
+        readback.signal_value = Some(signal_value);
+        self.next_readback = (self.next_readback + 1) % self.readbacks.len();
+        self.requested_position = None;
+    }
+}
@@ -1,32 +1,51 @@
-use std::{mem::size_of, sync::Arc};
+use std::{mem::size_of, sync::Arc, time::Instant};
 
 use anyhow::{Context, Result};
 use parking_lot::RwLock;
 use serde_derive::{Deserialize, Serialize};
 
 use rikka_core::{
-    nalgebra::{Matrix4, Vector4},
+    nalgebra::{Matrix4, Vector3, Vector4},
     vk,
 };
 use rikka_gpu::{
-    barriers::*, buffer::*, constants::MAX_FRAMES, descriptor_set::*, gpu::Gpu, image::Image,
+    barriers::*,
+    buffer::*,
+    command_buffer::CommandBuffer,
+    constants::{INVALID_BINDLESS_TEXTURE_INDEX, MAX_FRAMES},
+    descriptor_set::*,
+    gpu::Gpu,
+    image::Image,
+    profiling,
     types::*,
 };
-use rikka_graph::graph::Graph;
+use rikka_graph::{graph::Graph, types::FrameContext};
 
 use crate::{
-    loader::asynchronous::AsynchronousLoader,
-    pass::simple_pbr::*,
+    animation::{Animator, Skin},
+    camera::Camera,
+    capture,
+    loader::{asynchronous::AsynchronousLoader, native::*, scene::*, streaming::*},
+    pass::{
+        auto_exposure::*, depth_of_field::*, fsr::*, heatmap::*, indirect_draw::*, overlay::*,
+        particles::*, ray_traced_shadows::*, simple_pbr::*, skinning::*, sky::*, terrain::*,
+    },
     renderer::*,
-    scene,
-    scene_renderer::{gltf::*, mesh::*, meshlet::*},
+    scene::{self, PointLight},
+    scene_renderer::{
+        draw_list::DrawListStats, gltf::*, material::MaterialHandle,
+        material_storage::MaterialStorage, mesh::*, meshlet::*, picking::*, viewport::*,
+    },
+    schedule::*,
+    sky::PreethamSky,
 };
 
 #[derive(Serialize, Deserialize)]
 pub struct FilePathsConfig {
     pub render_graph_file_path: String,
     pub render_techniques_file_paths: Vec<String>,
-    pub gtlf_model_file_path: String,
+    /// Loaded synchronously when set, the scene starts empty otherwise
+    pub gtlf_model_file_path: Option<String>,
 }
 
 pub struct Config<'a> {
@@ -41,6 +60,101 @@ impl RenderTechniqeFilePaths {
     const FULLSCREEN: &str = "data/fullscreen.json";
     const SIMPLE_PBR: &str = "data/simple_pbr.json";
     const DEFERRED_MESH_SHADER: &str = "data/deferred_mesh_shader.json";
+    const DEFERRED_MESH_SHADER_EXT: &str = "data/deferred_mesh_shader_ext.json";
+    const FULLSCREEN_DEBUG: &str = "data/fullscreen_debug.json";
+}
+
+/// Visualization of a single render graph channel, decoded by the fullscreen debug shader
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum DebugView {
+    Final = 0,
+    Albedo = 1,
+    Normals = 2,
+    Roughness = 3,
+    Metalness = 4,
+    Occlusion = 5,
+    Depth = 6,
+    MeshletIds = 7,
+    Overdraw = 8,
+    /// Final image with the mesh base colors tinted by their selected LOD level
+    LodLevels = 9,
+    /// Fragments shaded per pixel, counted with atomics by the scene fragment shaders
+    OverdrawHeatmap = 10,
+    LuminanceHeatmap = 11,
+}
+
+impl DebugView {
+    pub const ALL: [DebugView; 12] = [
+        Self::Final,
+        Self::Albedo,
+        Self::Normals,
+        Self::Roughness,
+        Self::Metalness,
+        Self::Occlusion,
+        Self::Depth,
+        Self::MeshletIds,
+        Self::Overdraw,
+        Self::LodLevels,
+        Self::OverdrawHeatmap,
+        Self::LuminanceHeatmap,
+    ];
+
+    /// Render graph resource sampled by the debug shader, depth is taken from the scene depth attachment
+    fn graph_resource_name(&self) -> Option<&'static str> {
+        match self {
+            Self::Final
+            | Self::Depth
+            | Self::LodLevels
+            | Self::OverdrawHeatmap
+            | Self::LuminanceHeatmap => None,
+            Self::Albedo => Some("gbuffer_albedo"),
+            Self::Normals => Some("gbuffer_normals"),
+            // Occlusion, roughness and metalness are packed in the RGB channels of a single attachment
+            Self::Roughness | Self::Metalness | Self::Occlusion => {
+                Some("gbuffer_occlusion_roughness_metalness")
+            }
+            Self::MeshletIds => Some("meshlet_ids"),
+            Self::Overdraw => Some("overdraw"),
+        }
+    }
+
+    /// Heatmap computed by the debug heatmap pass instead of sampling a graph resource
+    fn heatmap_source(&self) -> Option<HeatmapSource> {
+        match self {
+            Self::OverdrawHeatmap => Some(HeatmapSource::Overdraw),
+            Self::LuminanceHeatmap => Some(HeatmapSource::Luminance),
+            _ => None,
+        }
+    }
+}
+
+/// Base color tints of the LOD debug view, from the full detail mesh to the coarsest level
+const LOD_TINT_COLORS: [[f32; 4]; MAX_MESH_LODS + 1] = [
+    [1.0, 1.0, 1.0, 1.0],
+    [0.2, 1.0, 0.2, 1.0],
+    [0.2, 0.4, 1.0, 1.0],
+    [1.0, 1.0, 0.2, 1.0],
+    [1.0, 0.2, 0.2, 1.0],
+];
+
+/// Projected LOD error in pixels below which a coarser LOD level is selected
+pub const DEFAULT_LOD_ERROR_THRESHOLD: f32 = 1.0;
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GpuDebugViewConstants {
+    image_index: u32,
+    debug_view: u32,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GpuTonemapConstants {
+    /// Linear scale of the final image before tonemapping
+    exposure: f32,
+    /// `TransferFunction` the tonemapped image is encoded with for the swapchain color space
+    transfer_function: u32,
 }
 
 #[derive(Clone, Copy)]
@@ -54,6 +168,18 @@ pub struct GpuSceneUniformData {
     pub light_position: Vector4<f32>,
     pub light_range: f32,
     pub light_intensity: f32,
+    _pad0: [f32; 2],
+
+    /// Cosine convolved environment irradiance as band 2 spherical harmonics, zero without an environment
+    pub environment_irradiance_sh: [Vector4<f32>; 9],
+    /// Index of the prefiltered specular environment in the bindless cube map array
+    pub environment_specular_index: u32,
+    pub environment_specular_mip_count: u32,
+    /// Bindless storage index of the overdraw counts, invalid when overdraw is not counted
+    pub debug_overdraw_storage_index: u32,
+    /// Bindless index of the ray traced shadow mask, sampled by the lighting at the screen position of the fragment.
+    /// Invalid without ray traced shadows.
+    pub shadow_mask_index: u32,
 }
 impl GpuSceneUniformData {
     pub fn new() -> Self {
@@ -64,6 +190,12 @@ impl GpuSceneUniformData {
             light_position: Vector4::new(-1.5, 2.5, -0.5, 1.0),
             light_range: 0.0,
             light_intensity: 0.0,
+            _pad0: [0.0; 2],
+            environment_irradiance_sh: [Vector4::zeros(); 9],
+            environment_specular_index: INVALID_BINDLESS_TEXTURE_INDEX,
+            environment_specular_mip_count: 0,
+            debug_overdraw_storage_index: INVALID_BINDLESS_TEXTURE_INDEX,
+            shadow_mask_index: INVALID_BINDLESS_TEXTURE_INDEX,
         }
     }
 }
@@ -113,11 +245,18 @@ impl RenderContext {
 pub struct SceneRenderer {
     renderer: Renderer,
     render_graph: Graph,
+    render_graph_extent: vk::Extent2D,
 
     scene_graph: scene::Graph,
+    /// Animation clips of the current scene
+    animator: Animator,
+    /// Skins of the current scene, indexed by `MeshSkin::skin_index`
+    skins: Vec<Skin>,
 
     // Mesh data
     meshes: Vec<Arc<Mesh>>,
+    /// Materials of `meshes`, None without a scene
+    material_storage: Option<MaterialStorage>,
     // mesh_instances: Vec<MeshInstance>,
     // gltf_mesh_to_mesh_offset: Vec<u32>,
 
@@ -130,8 +269,9 @@ pub struct SceneRenderer {
     // mesh_draw_counts: GpuMeshDrawCounts,
 
     // Per-frame scene data
-    // XXX: Remove this `pub` and add accessors
-    pub scene_uniform_data: GpuSceneUniformData,
+    /// View and projection are written from `camera` every frame
+    scene_uniform_data: GpuSceneUniformData,
+    camera: Camera,
 
     // Gpu buffers
     scene_uniform_buffer: Handle<Buffer>,
@@ -139,11 +279,8 @@ pub struct SceneRenderer {
     // meshes_storage_buffer: Handle<Buffer>,
     // mesh_bounds_storage_buffer: Handle<Buffer>,
     // mesh_instances_storage_buffer: Handle<Buffer>,
-
-    // meshlets_storage_buffer: Handle<Buffer>,
-    // meshlets_vertex_positions_storage_buffer: Handle<Buffer>,
-    // meshlets_vertex_data_storage_buffer: Handle<Buffer>,
-    // meshlets_data_storage_buffer: Handle<Buffer>,
+    /// Built from the scene meshes on load, None for an empty scene
+    meshlet_storage_buffers: Option<MeshletStorageBuffers>,
 
     // // Gpu indirect data
     // mesh_task_indirect_count_early_storage_buffer: Vec<Handle<Buffer>>,
@@ -159,6 +296,18 @@ pub struct SceneRenderer {
     // Fullscreen pass
     fullscreen_technique: Arc<RenderTechnique>,
     final_image: Handle<Image>,
+    /// Graph resource displayed instead of the final image for debugging
+    debug_view_resource: Option<String>,
+    debug_view: DebugView,
+    /// Loaded on first use of a debug view
+    fullscreen_debug_technique: Option<Arc<RenderTechnique>>,
+    /// Only created while a heatmap debug view is shown
+    heatmap_pass: Option<HeatmapPass>,
+    auto_exposure: Option<AutoExposure>,
+    /// Only created when depth of field is enabled on a render graph with a depth of field pass
+    depth_of_field_pass: Option<DepthOfFieldPass>,
+    /// Only created when upscaling is enabled on a render graph with the FSR passes
+    fsr_pass: Option<FsrUpscalePass>,
 
     // Render passes
     // pbr_lighting_pass: PBRLightingPass,
@@ -168,15 +317,60 @@ pub struct SceneRenderer {
     // One-pass PBR
     simple_pbr_pass: SimplePbrPass,
     simple_pbr_render_technique: Arc<RenderTechnique>,
+
+    /// Replaces the simple PBR render pass when enabled and the scene has meshlets
+    indirect_draw_pass: Option<IndirectDrawPass>,
+    indirect_draw: bool,
+
+    /// Only created when enabled on a ray tracing capable Gpu
+    ray_traced_shadows_pass: Option<RayTracedShadowsPass>,
+
+    /// Only created for scenes with skinned meshes
+    skinning_pass: Option<SkinningPass>,
+
+    /// Queues requested for the skinning, particle and culling work, resolved every frame
+    compute_schedule_policy: ComputeSchedulePolicy,
+
+    /// glTF or cooked file of the current scene, None without a scene
+    scene_file_name: Option<String>,
+    /// Scene being loaded in the background by `begin_scene_load`
+    scene_load: Option<SceneLoad>,
+    /// Changes restored once the scene being loaded is created when a native scene is loaded
+    scene_load_native_scene: Option<NativeScene>,
+    /// Ray traced shadows are rebuilt for the loaded scene if they were enabled before the load
+    scene_load_ray_traced_shadows: bool,
+
+    texture_streamer: TextureStreamer,
+
+    /// Screen space error in pixels allowed when selecting mesh LOD levels
+    lod_error_threshold: f32,
+
+    object_picker: ObjectPicker,
+
+    /// Built over the world space mesh bounds when a scene is created
+    scene_bvh: scene::Bvh,
+
+    /// Only created when a terrain is loaded into a render graph with a terrain pass
+    terrain_pass: Option<TerrainPass>,
+
+    /// Only created when emitters are set on a render graph with the particle passes
+    particle_system: Option<ParticleSystem>,
+
+    /// Created on first use
+    overlay: Option<Overlay>,
+
+    /// Only created when a sky is set on a render graph with a sky pass
+    sky_pass: Option<SkyPass>,
+
+    /// Region of the output image the main view is composited to
+    main_viewport_region: ViewportRegion,
+    /// Additional views, composited over the main view in order
+    viewports: Vec<Viewport>,
 }
 
 impl SceneRenderer {
-    pub fn new(
-        mut renderer: Renderer,
-        mut render_graph: Graph,
-        async_loader: &mut AsynchronousLoader,
-        gltf_file_name: &str,
-    ) -> Result<Self> {
+    /// Starts with an empty scene
+    pub fn new(mut renderer: Renderer, mut render_graph: Graph) -> Result<Self> {
         // Get final image to be copied to the swapchain from the render graph
         let final_image_graph_resource = render_graph
             // .access_node_by_name(FINAL_IMAGE_NODE_NAME)
@@ -191,11 +385,12 @@ impl SceneRenderer {
         let fullscreen_technique = renderer
             .create_technique_from_file(RenderTechniqeFilePaths::FULLSCREEN, &render_graph)?;
 
-        // Setup per-frame uniform buffer
+        // Setup per-frame uniform buffer, also read by the particle simulation on the async compute queue
         let scene_uniform_buffer_desc = BufferDesc::new()
             .set_size(size_of::<GpuSceneUniformData>() as _)
             .set_device_only(false)
-            .set_usage_flags(vk::BufferUsageFlags::UNIFORM_BUFFER);
+            .set_usage_flags(vk::BufferUsageFlags::UNIFORM_BUFFER)
+            .set_async_compute(true);
         let scene_uniform_buffer = renderer.create_buffer(scene_uniform_buffer_desc)?;
 
         let scene_uniform_data = GpuSceneUniformData::new();
@@ -205,30 +400,15 @@ impl SceneRenderer {
         let simple_pbr_render_technique = renderer
             .create_technique_from_file(RenderTechniqeFilePaths::SIMPLE_PBR, &render_graph)?;
 
-        // Load glTF scene
-        log::trace!("Loading gltf file {}...", gltf_file_name);
-        let gltf_scene = GltfScene::new_from_file(
-            &mut renderer,
-            gltf_file_name,
-            &scene_uniform_buffer,
-            &simple_pbr_render_technique,
-            async_loader,
-        )?;
-        log::trace!("Successfully loaded gltf file {}", gltf_file_name);
-
-        let meshes = gltf_scene
-            .meshes
-            .into_iter()
-            .map(Arc::new)
-            .collect::<Vec<_>>();
-        let scene_graph = gltf_scene.scene_graph;
-
         // Create render passes
+        let meshes = Vec::new();
+        let scene_graph = scene::Graph::new();
         let simple_pbr_pass = SimplePbrPass::new(
             &renderer,
             &render_graph,
             &meshes,
             renderer.gpu().bindless_descriptor_set().clone(),
+            None,
         )?;
 
         // Register render passes
@@ -253,30 +433,83 @@ impl SceneRenderer {
             ResourceState::SHADER_RESOURCE,
         )?;
 
-        // Test load mesh shader pipeline
-        let mut deferred_mesh_shader_graph =
-            rikka_graph::parser::parse_from_file("data/graphs/deferred_mesh_shader_graph.json")
-                .context("Failed to load deferred mesh shader render graph")?;
-        deferred_mesh_shader_graph.compile(renderer.gpu_mut())?;
+        // Test load mesh shader pipeline, shader variants differ per mesh shader extension
+        let deferred_mesh_shader_technique_path = match renderer.gpu().device_features().mesh_shader
+        {
+            Some(MeshShaderExtension::Ext) => {
+                Some(RenderTechniqeFilePaths::DEFERRED_MESH_SHADER_EXT)
+            }
+            Some(MeshShaderExtension::Nv) => Some(RenderTechniqeFilePaths::DEFERRED_MESH_SHADER),
+            None => {
+                log::warn!("Mesh shaders are not supported, skipping mesh shader technique");
+                None
+            }
+        };
+
+        if let Some(technique_path) = deferred_mesh_shader_technique_path {
+            let mut deferred_mesh_shader_graph =
+                rikka_graph::parser::parse_from_file("data/graphs/deferred_mesh_shader_graph.json")
+                    .context("Failed to load deferred mesh shader render graph")?;
+            deferred_mesh_shader_graph.compile(renderer.gpu_mut())?;
+
+            let _deferred_mesh_shader_technique = renderer
+                .create_technique_from_file(technique_path, &deferred_mesh_shader_graph)
+                .context("Failed to load deferred mesh shader technique")?;
+        }
 
-        let _deferred_mesh_shader_technique = renderer
-            .create_technique_from_file(
-                RenderTechniqeFilePaths::DEFERRED_MESH_SHADER,
-                &deferred_mesh_shader_graph,
-            )
-            .context("Failed to load deferred mesh shader technique")?;
+        // Gpus without mesh shaders cull and draw the scene meshes with indirect draws by default
+        let device_features = renderer.gpu().device_features();
+        let indirect_draw =
+            device_features.mesh_shader.is_none() && device_features.draw_indirect_count;
+
+        let render_graph_extent = renderer.extent();
+        let mut camera = Camera::default();
+        camera.set_extent(render_graph_extent.width, render_graph_extent.height);
+        let object_picker = ObjectPicker::new(&renderer)?;
 
         Ok(Self {
             renderer,
             render_graph,
+            render_graph_extent,
             meshes,
+            material_storage: None,
             scene_graph,
+            animator: Animator::default(),
+            skins: Vec::new(),
             final_image,
+            debug_view_resource: None,
+            debug_view: DebugView::Final,
+            fullscreen_debug_technique: None,
+            heatmap_pass: None,
+            auto_exposure: None,
+            depth_of_field_pass: None,
+            fsr_pass: None,
             scene_uniform_buffer,
             scene_uniform_data,
+            camera,
             fullscreen_technique,
             simple_pbr_render_technique,
             simple_pbr_pass,
+            indirect_draw_pass: None,
+            indirect_draw,
+            ray_traced_shadows_pass: None,
+            skinning_pass: None,
+            compute_schedule_policy: ComputeSchedulePolicy::default(),
+            meshlet_storage_buffers: None,
+            scene_file_name: None,
+            scene_load: None,
+            scene_load_native_scene: None,
+            scene_load_ray_traced_shadows: false,
+            texture_streamer: TextureStreamer::new(DEFAULT_TEXTURE_BUDGET),
+            lod_error_threshold: DEFAULT_LOD_ERROR_THRESHOLD,
+            object_picker,
+            scene_bvh: scene::Bvh::from_bounds(Vec::new()),
+            terrain_pass: None,
+            particle_system: None,
+            overlay: None,
+            sky_pass: None,
+            main_viewport_region: ViewportRegion::FULL,
+            viewports: Vec::new(),
         })
     }
 
@@ -288,12 +521,18 @@ impl SceneRenderer {
         )?;
         render_graph.compile(renderer.gpu_mut())?;
 
-        Self::new(
-            renderer,
-            render_graph,
-            config.async_loader,
-            config.file_paths_config.gtlf_model_file_path.as_str(),
-        )
+        for technique_file_path in &config.file_paths_config.render_techniques_file_paths {
+            renderer
+                .create_technique_from_file(technique_file_path, &render_graph)
+                .with_context(|| format!("Failed to load technique {}", technique_file_path))?;
+        }
+
+        let mut scene_renderer = Self::new(renderer, render_graph)?;
+        if let Some(gltf_file_name) = &config.file_paths_config.gtlf_model_file_path {
+            scene_renderer.load_scene(gltf_file_name, config.async_loader)?;
+        }
+
+        Ok(scene_renderer)
     }
 
     pub fn upload_data_to_gpu(&mut self) -> Result<()> {
@@ -303,102 +542,1956 @@ impl SceneRenderer {
             mesh_data.set_matrices_from_scene_graph(mesh, &self.scene_graph);
             // mesh_data.global_model = Matrix4::identity();
             // mesh_data.global_model = Matrix4::new_scaling(0.1) * mesh_data.global_model;
-            mesh.pbr_material
-                .material_buffer
-                .copy_data_to_buffer(&[mesh_data])?;
+            mesh.set_gpu_data(mesh_data);
+        }
+        if self.debug_view == DebugView::LodLevels {
+            self.set_lod_tints(true);
         }
 
         Ok(())
     }
 
-    pub fn render(&mut self) -> Result<()> {
-        // XXX: This call is useless because the uniform buffers that contain the model matrix will not be updated. Handle this nicer?
-        // self.scene_graph.calculate_transforms()?;
+    /// Uploads the material instances changed since the last frame
+    fn upload_dirty_materials(&mut self) -> Result<()> {
+        if let Some(material_storage) = &mut self.material_storage {
+            for mesh in &self.meshes {
+                mesh.pbr_material.update_gpu_data(material_storage)?;
+            }
+        }
 
-        self.scene_uniform_buffer
-            .copy_data_to_buffer(&[self.scene_uniform_data])?;
+        Ok(())
+    }
+
+    /// Material of the scene mesh `mesh_index`, None if the index is out of range
+    pub fn material(&self, mesh_index: usize) -> Option<MaterialHandle> {
+        self.meshes
+            .get(mesh_index)
+            .map(|mesh| MaterialHandle::new(mesh.clone()))
+    }
 
-        self.renderer.begin_frame()?;
+    /// Materials of all meshes of a scene graph node, e.g. the node returned by `pick`
+    pub fn node_materials(&self, scene_graph_node_index: usize) -> Vec<MaterialHandle> {
+        self.meshes
+            .iter()
+            .filter(|mesh| mesh.scene_graph_node_index == scene_graph_node_index)
+            .map(|mesh| MaterialHandle::new(mesh.clone()))
+            .collect()
+    }
 
-        let command_buffer = self.renderer.command_buffer(0)?;
-        command_buffer.begin()?;
-        let swapchain = self.renderer.gpu().swapchain();
+    /// Releases all scene meshes with their buffers, textures and material descriptor sets, rendering an empty scene
+    /// until the next `load_scene`
+    pub fn unload_scene(&mut self) -> Result<()> {
+        self.scene_file_name = None;
+        self.scene_load = None;
+        self.scene_load_native_scene = None;
+
+        // Scene resources may still be used by frames in flight
+        self.renderer.wait_idle();
+
+        // Acceleration structures reference the mesh buffers
+        self.ray_traced_shadows_pass = None;
+
+        self.texture_streamer.clear();
+        self.skinning_pass = None;
+        self.skins.clear();
+        self.meshlet_storage_buffers = None;
+        self.meshes.clear();
+        self.material_storage = None;
+        self.scene_graph = scene::Graph::new();
+        self.animator = Animator::default();
+        self.object_picker.clear();
+        self.scene_bvh = scene::Bvh::from_bounds(Vec::new());
+        self.rebuild_scene_passes()?;
+
+        // Release the Gpu memory before anything new is loaded
+        self.renderer.gpu().force_cleanup();
 
-        let barriers = Barriers::new().add_image(
-            &self.final_image,
-            ResourceState::SHADER_RESOURCE,
-            ResourceState::RENDER_TARGET,
+        Ok(())
+    }
+
+    /// Replaces the current scene with a glTF, cooked or native scene file, its textures are streamed in by
+    /// `async_loader`. The scene is left empty if loading fails.
+    pub fn load_scene(
+        &mut self,
+        gltf_file_name: &str,
+        async_loader: &mut AsynchronousLoader,
+    ) -> Result<()> {
+        let ray_traced_shadows = self.ray_traced_shadows_enabled();
+        self.unload_scene()?;
+
+        let native_scene = Self::read_native_scene(gltf_file_name)?;
+        let source_file_name = native_scene
+            .as_ref()
+            .map_or(gltf_file_name, |native_scene| {
+                native_scene.source_file_name.as_str()
+            });
+
+        log::trace!("Loading gltf file {}...", source_file_name);
+        let scene_data = SceneData::new_from_file(source_file_name, |_| {})?;
+        self.create_scene(
+            scene_data,
+            async_loader,
+            ray_traced_shadows,
+            native_scene.as_ref(),
+        )?;
+        self.scene_file_name = Some(source_file_name.to_string());
+        log::trace!("Successfully loaded gltf file {}", source_file_name);
+
+        Ok(())
+    }
+
+    /// Replaces the current scene with a glTF, cooked or native scene file that is loaded on a background thread,
+    /// `update_scene_load` creates the scene once the file has been loaded
+    pub fn begin_scene_load(&mut self, gltf_file_name: &str) -> Result<()> {
+        let ray_traced_shadows = self.ray_traced_shadows_enabled();
+        self.unload_scene()?;
+
+        // Native scenes are small, only their source scene is loaded in the background
+        let native_scene = Self::read_native_scene(gltf_file_name)?;
+        let source_file_name = native_scene
+            .as_ref()
+            .map_or(gltf_file_name, |native_scene| {
+                native_scene.source_file_name.as_str()
+            });
+
+        self.scene_load = Some(SceneLoad::new(source_file_name)?);
+        self.scene_load_native_scene = native_scene;
+        self.scene_load_ray_traced_shadows = ray_traced_shadows;
+
+        Ok(())
+    }
+
+    /// Called every frame while a scene is loading. Returns true on the frame the loaded scene is created, its
+    /// textures keep streaming in afterwards. The scene is left empty if loading fails.
+    pub fn update_scene_load(&mut self, async_loader: &mut AsynchronousLoader) -> Result<bool> {
+        let mut scene_load = match self.scene_load.take() {
+            Some(scene_load) => scene_load,
+            None => return Ok(false),
+        };
+
+        // XXX: Textures count as loaded once decoded, the Gpu upload may still be pending
+        if let SceneLoadProgress::Textures { total, .. } = *scene_load.progress() {
+            let pending = async_loader.pending_image_file_loads().min(total);
+            if pending > 0 {
+                scene_load.set_progress(SceneLoadProgress::Textures {
+                    loaded: total - pending,
+                    total,
+                });
+                self.scene_load = Some(scene_load);
+            }
+            return Ok(false);
+        }
+
+        let scene_data = match scene_load.poll() {
+            Some(scene_data) => scene_data
+                .with_context(|| format!("Failed to load gltf file {}", scene_load.file_name()))?,
+            None => {
+                self.scene_load = Some(scene_load);
+                return Ok(false);
+            }
+        };
+
+        let pending_textures = async_loader.pending_image_file_loads();
+        let native_scene = self.scene_load_native_scene.take();
+        self.create_scene(
+            scene_data,
+            async_loader,
+            self.scene_load_ray_traced_shadows,
+            native_scene.as_ref(),
+        )?;
+        self.scene_file_name = Some(scene_load.file_name().to_string());
+        log::trace!("Successfully loaded gltf file {}", scene_load.file_name());
+
+        let total = async_loader
+            .pending_image_file_loads()
+            .saturating_sub(pending_textures);
+        scene_load.set_progress(SceneLoadProgress::Textures { loaded: 0, total });
+        self.scene_load = Some(scene_load);
+
+        Ok(true)
+    }
+
+    /// None when no scene is being loaded
+    pub fn scene_load_progress(&self) -> Option<&SceneLoadProgress> {
+        self.scene_load.as_ref().map(SceneLoad::progress)
+    }
+
+    /// Saves the node transforms and tags, material changes, light and camera of the current scene to a native scene
+    /// file referencing the glTF or cooked file the scene was loaded from
+    // XXX: Texture changes of materials are not saved
+    pub fn save_scene(&self, file_name: &str) -> Result<()> {
+        let source_file_name = self
+            .scene_file_name
+            .as_ref()
+            .context("No scene is loaded")?;
+
+        let nodes = self
+            .scene_graph
+            .nodes_hierarchy
+            .iter()
+            .zip(&self.scene_graph.local_matrices)
+            .enumerate()
+            .map(|(node_index, (hierarchy, local_matrix))| NativeNode {
+                parent: if hierarchy.parent == scene::INVALID_INDEX {
+                    INVALID_NATIVE_INDEX
+                } else {
+                    hierarchy.parent as u32
+                },
+                level: hierarchy.level as u32,
+                local_matrix: std::array::from_fn(|index| local_matrix[index]),
+                tags: self.scene_graph.metadata(node_index).tags.clone(),
+                hidden: self.scene_graph.metadata(node_index).hidden,
+            })
+            .collect();
+
+        let meshes = self
+            .meshes
+            .iter()
+            .map(|mesh| {
+                let material = &mesh.pbr_material;
+                NativeMesh {
+                    node: mesh.scene_graph_node_index as u32,
+                    material: material.overridden().then(|| {
+                        let metallic_roughness_occlusion = material.metallic_roughness_occlusion();
+                        NativeMaterial {
+                            base_color_factor: material.base_color().into(),
+                            metallic_factor: metallic_roughness_occlusion.x,
+                            roughness_factor: metallic_roughness_occlusion.y,
+                        }
+                    }),
+                }
+            })
+            .collect();
+
+        let light = self.light();
+        let native_scene = NativeScene {
+            version: NATIVE_SCENE_VERSION,
+            source_file_name: source_file_name.clone(),
+            nodes,
+            meshes,
+            light: NativeLight {
+                position: light.position.into(),
+                range: light.range,
+                intensity: light.intensity,
+            },
+            camera: NativeCamera {
+                position: (*self.camera.position()).into(),
+                forward: (*self.camera.forward()).into(),
+                projection: *self.camera.projection(),
+                exposure: self.camera.exposure(),
+            },
+        };
+        native_scene.write_to_file(file_name)?;
+
+        log::info!("Saved scene {} to {}", source_file_name, file_name);
+
+        Ok(())
+    }
+
+    /// glTF or cooked file the current scene was loaded from, None without a scene
+    pub fn scene_file_name(&self) -> Option<&str> {
+        self.scene_file_name.as_deref()
+    }
+
+    /// Returns None if `file_name` is not a native scene file
+    fn read_native_scene(file_name: &str) -> Result<Option<NativeScene>> {
+        if !is_native_scene_file(file_name) {
+            return Ok(None);
+        }
+
+        Ok(Some(NativeScene::new_from_file(file_name)?))
+    }
+
+    /// Restores the changes of a native scene on the scene created from its source file, the scene graph has to be
+    /// unchanged since the native scene was saved
+    fn apply_native_scene(&mut self, native_scene: &NativeScene) -> Result<()> {
+        let nodes_hierarchy = &self.scene_graph.nodes_hierarchy;
+        let nodes_match = native_scene.nodes.len() == nodes_hierarchy.len()
+            && native_scene
+                .nodes
+                .iter()
+                .zip(nodes_hierarchy)
+                .all(|(node, hierarchy)| {
+                    let parent = if node.parent == INVALID_NATIVE_INDEX {
+                        scene::INVALID_INDEX
+                    } else {
+                        node.parent as usize
+                    };
+                    parent == hierarchy.parent && node.level as usize == hierarchy.level
+                });
+        let meshes_match = native_scene.meshes.len() == self.meshes.len()
+            && native_scene
+                .meshes
+                .iter()
+                .zip(&self.meshes)
+                .all(|(native_mesh, mesh)| {
+                    native_mesh.node as usize == mesh.scene_graph_node_index
+                });
+        if !nodes_match || !meshes_match {
+            return Err(anyhow::anyhow!(
+                "Native scene does not match its source scene {}",
+                native_scene.source_file_name
+            ));
+        }
+
+        for (node_index, node) in native_scene.nodes.iter().enumerate() {
+            self.scene_graph
+                .set_local_matrix(node_index, Matrix4::from_column_slice(&node.local_matrix));
+            for tag in &node.tags {
+                self.scene_graph.add_tag(node_index, tag);
+            }
+            self.scene_graph.set_visible(node_index, !node.hidden);
+        }
+        self.update_mesh_visibility();
+
+        for (native_mesh, mesh) in native_scene.meshes.iter().zip(&self.meshes) {
+            if let Some(material) = &native_mesh.material {
+                mesh.pbr_material
+                    .set_base_color(Vector4::from(material.base_color_factor));
+                mesh.pbr_material.set_metallic(material.metallic_factor);
+                mesh.pbr_material.set_roughness(material.roughness_factor);
+            }
+        }
+
+        self.set_light(PointLight {
+            position: Vector3::from(native_scene.light.position),
+            range: native_scene.light.range,
+            intensity: native_scene.light.intensity,
+        });
+
+        let camera = &native_scene.camera;
+        self.camera.set_projection(camera.projection);
+        self.camera.set_transform(
+            Vector3::from(camera.position),
+            Vector3::from(camera.forward),
         );
-        command_buffer.pipeline_barrier(barriers);
+        self.camera.set_exposure(camera.exposure);
 
-        self.render_graph.render(&command_buffer)?;
+        Ok(())
+    }
 
-        let barriers = Barriers::new()
-            .add_image(
-                &self.final_image,
-                ResourceState::RENDER_TARGET,
-                ResourceState::SHADER_RESOURCE,
-            )
-            .add_image(
-                swapchain.current_image(),
-                ResourceState::UNDEFINED,
-                ResourceState::RENDER_TARGET,
-            );
-        command_buffer.pipeline_barrier(barriers);
+    /// Advances the playing animation clips and writes the animated node transforms, should be called once per frame
+    /// before `render`
+    // XXX: Acceleration structures of ray traced shadows are not rebuilt
+    pub fn update_animations(&mut self, delta_time: f32) -> Result<()> {
+        if self.animator.is_empty() {
+            return Ok(());
+        }
 
-        {
-            let color_attachment = RenderColorAttachment::new()
-                .set_clear_value(vk::ClearColorValue {
-                    float32: [1.0, 1.0, 1.0, 1.0],
+        self.animator.update(delta_time);
+        if !self.animator.apply(&mut self.scene_graph) {
+            return Ok(());
+        }
+        self.upload_data_to_gpu()?;
+        self.scene_bvh = scene::Bvh::from_bounds(self.world_mesh_bounds());
+
+        Ok(())
+    }
+
+    pub fn animator(&self) -> &Animator {
+        &self.animator
+    }
+
+    pub fn skins(&self) -> &[Skin] {
+        &self.skins
+    }
+
+    /// Meshes skinned by the compute skinning pass, 0 for scenes without skins
+    pub fn skinned_mesh_count(&self) -> usize {
+        self.skinning_pass
+            .as_ref()
+            .map_or(0, |skinning_pass| skinning_pass.skinned_mesh_count())
+    }
+
+    pub fn compute_schedule_policy(&self) -> ComputeSchedulePolicy {
+        self.compute_schedule_policy
+    }
+
+    /// Takes effect on the next frame, the resolved schedule is reported in the frame stats
+    pub fn set_compute_schedule_policy(&mut self, compute_schedule_policy: ComputeSchedulePolicy) {
+        self.compute_schedule_policy = compute_schedule_policy;
+    }
+
+    /// Queues of the compute workloads with work in the current scene. Workloads fall back to the graphics queue
+    /// without a dedicated compute queue family or when they cannot overlap the previous frame.
+    pub fn resolve_compute_schedule(&self) -> ComputeSchedule {
+        let async_compute_supported = self.renderer.async_compute_supported();
+
+        let workloads = ComputeWorkload::ALL
+            .into_iter()
+            .filter_map(|workload| {
+                let unsupported_reason = match workload {
+                    ComputeWorkload::Skinning => {
+                        let skinning_pass = self.skinning_pass.as_ref()?;
+                        skinning_pass
+                            .writes_meshlet_vertices()
+                            .then_some("skinning writes the meshlet vertices shared by all frames")
+                    }
+                    ComputeWorkload::Particles => {
+                        self.particle_system.as_ref()?;
+                        None
+                    }
+                    ComputeWorkload::Culling => {
+                        self.indirect_draw_pass.as_ref()?;
+                        None
+                    }
+                };
+
+                let fallback_reason = match self.compute_schedule_policy.queue(workload) {
+                    ComputeQueue::Graphics => None,
+                    ComputeQueue::AsyncCompute if !async_compute_supported => {
+                        Some("Gpu has no dedicated compute queue family")
+                    }
+                    ComputeQueue::AsyncCompute => unsupported_reason,
+                };
+                let queue = match fallback_reason {
+                    Some(_) => ComputeQueue::Graphics,
+                    None => self.compute_schedule_policy.queue(workload),
+                };
+
+                Some(ScheduledWorkload {
+                    workload,
+                    queue,
+                    fallback_reason,
                 })
-                .set_operation(RenderPassOperation::Clear)
-                .set_image_view(swapchain.current_image_view())
-                .set_image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+            })
+            .collect();
 
-            let rendering_state =
-                RenderingState::new(swapchain.extent().width, swapchain.extent().height)
-                    .add_color_attachment(color_attachment);
-            command_buffer.begin_rendering(rendering_state);
+        ComputeSchedule { workloads }
+    }
 
-            let fullscreen_graphics_pipeline =
-                &self.fullscreen_technique.passes[0].graphics_pipeline;
-            command_buffer.bind_graphics_pipeline(fullscreen_graphics_pipeline);
-            command_buffer.bind_descriptor_set(
-                self.renderer.gpu().bindless_descriptor_set().as_ref(),
-                fullscreen_graphics_pipeline.raw_layout(),
-                0,
+    /// Playback of the scene animation clips, applied by `update_animations`
+    pub fn animator_mut(&mut self) -> &mut Animator {
+        &mut self.animator
+    }
+
+    /// Node transforms changed through the graph are uploaded by `upload_data_to_gpu`
+    pub fn scene_graph(&self) -> &scene::Graph {
+        &self.scene_graph
+    }
+
+    pub fn scene_graph_mut(&mut self) -> &mut scene::Graph {
+        &mut self.scene_graph
+    }
+
+    /// Hides or shows a scene graph node with its children without unloading their meshes
+    // XXX: Hidden meshes still cast ray traced shadows
+    pub fn set_node_visible(&mut self, scene_graph_node_index: usize, visible: bool) -> Result<()> {
+        if scene_graph_node_index >= self.scene_graph.node_count() {
+            return Err(anyhow::anyhow!(
+                "Scene graph node {} does not exist",
+                scene_graph_node_index
+            ));
+        }
+
+        self.scene_graph
+            .set_visible(scene_graph_node_index, visible);
+        self.update_mesh_visibility();
+
+        Ok(())
+    }
+
+    /// Hides or shows all scene graph nodes named `name`
+    pub fn set_node_visible_by_name(&mut self, name: &str, visible: bool) -> Result<()> {
+        let nodes = self.scene_graph.find_nodes(name).to_vec();
+        if nodes.is_empty() {
+            return Err(anyhow::anyhow!("Scene graph node {} does not exist", name));
+        }
+
+        for node in nodes {
+            self.scene_graph.set_visible(node, visible);
+        }
+        self.update_mesh_visibility();
+
+        Ok(())
+    }
+
+    /// Whether the node and all its parents are visible, false if the index is out of range
+    pub fn node_visible(&self, scene_graph_node_index: usize) -> bool {
+        scene_graph_node_index < self.scene_graph.node_count()
+            && self
+                .scene_graph
+                .visible_in_hierarchy(scene_graph_node_index)
+    }
+
+    fn update_mesh_visibility(&self) {
+        for mesh in &self.meshes {
+            mesh.set_visible(
+                self.scene_graph
+                    .visible_in_hierarchy(mesh.scene_graph_node_index),
             );
+        }
+    }
 
-            // XXX: Set scissor, viewport?
+    /// Local transform of a scene graph node relative to its parent, None if the index is out of range
+    pub fn node_local_matrix(&self, scene_graph_node_index: usize) -> Option<&Matrix4<f32>> {
+        self.scene_graph.local_matrices.get(scene_graph_node_index)
+    }
 
-            // Set final image bindless index as the instance count parameter
-            command_buffer.draw(3, 1, 0, self.final_image.bindless_index());
+    /// Moves a scene graph node with its children, e.g. the node returned by `pick`
+    // XXX: Acceleration structures of ray traced shadows are not rebuilt
+    pub fn set_node_local_matrix(
+        &mut self,
+        scene_graph_node_index: usize,
+        local_matrix: Matrix4<f32>,
+    ) -> Result<()> {
+        if scene_graph_node_index >= self.scene_graph.local_matrices.len() {
+            return Err(anyhow::anyhow!(
+                "Scene graph node {} does not exist",
+                scene_graph_node_index
+            ));
+        }
 
-            command_buffer.end_rendering();
+        self.scene_graph
+            .set_local_matrix(scene_graph_node_index, local_matrix);
+        self.upload_data_to_gpu()?;
+        self.scene_bvh = scene::Bvh::from_bounds(self.world_mesh_bounds());
+
+        Ok(())
+    }
+
+    pub fn set_light(&mut self, light: PointLight) {
+        self.scene_uniform_data.light_position =
+            Vector4::new(light.position.x, light.position.y, light.position.z, 1.0);
+        self.scene_uniform_data.light_range = light.range;
+        self.scene_uniform_data.light_intensity = light.intensity;
+    }
+
+    pub fn light(&self) -> PointLight {
+        PointLight {
+            position: self.scene_uniform_data.light_position.xyz(),
+            range: self.scene_uniform_data.light_range,
+            intensity: self.scene_uniform_data.light_intensity,
         }
+    }
 
-        let barriers = Barriers::new().add_image(
-            swapchain.current_image(),
-            ResourceState::RENDER_TARGET,
-            ResourceState::PRESENT,
+    fn create_scene(
+        &mut self,
+        scene_data: SceneData,
+        async_loader: &mut AsynchronousLoader,
+        ray_traced_shadows: bool,
+        native_scene: Option<&NativeScene>,
+    ) -> Result<()> {
+        let gltf_scene = match scene_data {
+            SceneData::Gltf(scene_data) => GltfScene::new_from_data(
+                &mut self.renderer,
+                scene_data,
+                &self.simple_pbr_render_technique,
+                async_loader,
+                &mut self.texture_streamer,
+            )?,
+            SceneData::Cooked(scene_data) => GltfScene::new_from_cooked_data(
+                &mut self.renderer,
+                scene_data,
+                &self.simple_pbr_render_technique,
+                async_loader,
+                &mut self.texture_streamer,
+            )?,
+        };
+
+        self.meshes = gltf_scene
+            .meshes
+            .into_iter()
+            .map(Arc::new)
+            .collect::<Vec<_>>();
+        self.scene_graph = gltf_scene.scene_graph;
+        self.material_storage = Some(MaterialStorage::new(
+            &self.renderer,
+            &self.meshes,
+            &self.simple_pbr_render_technique,
+            self.scene_uniform_buffer.clone(),
+        )?);
+
+        if !gltf_scene.meshlets.is_empty() {
+            self.meshlet_storage_buffers = Some(MeshletStorageBuffers::new(
+                &mut self.renderer,
+                &gltf_scene.meshlets,
+            )?);
+        }
+        self.skins = gltf_scene.skins;
+        if self.meshes.iter().any(|mesh| mesh.skin.is_some()) {
+            self.skinning_pass = Some(SkinningPass::new(
+                &self.renderer,
+                &self.meshes,
+                &self.skins,
+                self.meshlet_storage_buffers.as_ref(),
+            )?);
+        }
+
+        // Restored before the passes are built so they see the restored transforms and visibility
+        if let Some(native_scene) = native_scene {
+            if let Err(error) = self.apply_native_scene(native_scene) {
+                self.unload_scene()?;
+                return Err(error);
+            }
+        }
+        self.rebuild_scene_passes()?;
+
+        // Rest poses include the transforms restored from the native scene
+        self.animator = Animator::new(gltf_scene.animations, &self.scene_graph);
+
+        self.upload_data_to_gpu()?;
+        self.scene_bvh = scene::Bvh::from_bounds(self.world_mesh_bounds());
+
+        self.set_ray_traced_shadows(ray_traced_shadows)
+    }
+
+    /// Recreates the draw lists of passes rendering the scene meshes
+    fn rebuild_scene_passes(&mut self) -> Result<()> {
+        for viewport in &mut self.viewports {
+            viewport.rebuild_scene_pass(
+                &self.renderer,
+                &self.meshes,
+                self.material_storage.as_ref(),
+                &self.simple_pbr_render_technique,
+            )?;
+        }
+
+        self.simple_pbr_pass = SimplePbrPass::new(
+            &self.renderer,
+            &self.render_graph,
+            &self.meshes,
+            self.renderer.gpu().bindless_descriptor_set().clone(),
+            self.material_storage
+                .as_ref()
+                .map(|material_storage| material_storage.descriptor_set().clone()),
+        )?;
+
+        self.indirect_draw_pass = None;
+        if let (true, Some(meshlet_storage_buffers), Some(material_storage)) = (
+            self.indirect_draw,
+            &self.meshlet_storage_buffers,
+            &self.material_storage,
+        ) {
+            let indirect_draw_pass = IndirectDrawPass::new(
+                &self.renderer,
+                &self.render_graph,
+                &self.meshes,
+                meshlet_storage_buffers,
+                material_storage,
+                self.scene_uniform_buffer.clone(),
+            )?;
+            self.render_graph
+                .register_render_pass("simple_pbr_pass", indirect_draw_pass.create_render_pass())?;
+            self.indirect_draw_pass = Some(indirect_draw_pass);
+            return Ok(());
+        }
+
+        self.render_graph
+            .register_render_pass("simple_pbr_pass", self.simple_pbr_pass.create_render_pass())
+    }
+
+    /// Draws the opaque scene meshes with Gpu culling and a single indexed multi-draw instead of one draw per mesh,
+    /// fails if the Gpu does not support `drawIndirectCount`. Enabled by default on Gpus without mesh shaders.
+    pub fn set_indirect_draw(&mut self, enabled: bool) -> Result<()> {
+        if enabled && !self.renderer.gpu().device_features().draw_indirect_count {
+            return Err(anyhow::anyhow!(
+                "Indirect draws require the drawIndirectCount device feature"
+            ));
+        }
+        if enabled == self.indirect_draw {
+            return Ok(());
+        }
+
+        self.renderer.wait_idle();
+
+        self.indirect_draw = enabled;
+        self.rebuild_scene_passes()
+    }
+
+    pub fn indirect_draw_enabled(&self) -> bool {
+        self.indirect_draw
+    }
+
+    /// World space bounds of all scene meshes, requires transforms computed by `upload_data_to_gpu`
+    pub fn scene_bounds(&self) -> scene::Aabb {
+        let mut bounds = scene::Aabb::empty();
+        for mesh_bounds in self.world_mesh_bounds() {
+            bounds.extend(&mesh_bounds);
+        }
+        if let Some(terrain_pass) = &self.terrain_pass {
+            bounds.extend(&terrain_pass.bounds());
+        }
+        bounds
+    }
+
+    fn world_mesh_bounds(&self) -> Vec<scene::Aabb> {
+        self.meshes
+            .iter()
+            .map(|mesh| {
+                mesh.bounds
+                    .transform(&self.scene_graph.global_matrices[mesh.scene_graph_node_index])
+            })
+            .collect()
+    }
+
+    /// Casts a ray against the world space mesh bounds on the CPU, returns the scene graph node index of the visible
+    /// mesh whose bounds are entered first with the distance to its bounds. Use `pick` for pixel exact hits.
+    // XXX: Triangles are not kept on the CPU, hits are only as precise as the mesh bounds
+    pub fn ray_cast_mesh_bounds(
+        &self,
+        origin: Vector3<f32>,
+        direction: Vector3<f32>,
+    ) -> Option<(usize, f32)> {
+        let ray = scene::Ray::new(origin, direction);
+        self.scene_bvh
+            .ray_cast_with(&ray, f32::MAX, |mesh_index, bounds_distance| {
+                self.meshes[mesh_index].visible().then_some(bounds_distance)
+            })
+            .map(|hit| {
+                (
+                    self.meshes[hit.primitive_index].scene_graph_node_index,
+                    hit.distance,
+                )
+            })
+    }
+
+    /// Texture memory budget in bytes, the low mip levels loaded with the scene are not counted
+    pub fn set_texture_budget(&mut self, budget: usize) {
+        self.texture_streamer.set_budget(budget);
+    }
+
+    /// Streams texture mip levels in and out for the current view, should be called once per frame before `render`
+    pub fn update_texture_streaming(&mut self, async_loader: &mut AsynchronousLoader) {
+        let _span = profiling::span("SceneRenderer::update_texture_streaming");
+
+        let eye_position = *self.camera.position();
+        let pixels_per_unit = self.camera.pixels_per_unit();
+
+        self.texture_streamer.update(
+            self.renderer.gpu_mut(),
+            async_loader,
+            &self.meshes,
+            &self.scene_graph.global_matrices,
+            &StreamingView {
+                eye_position,
+                pixels_per_unit,
+            },
         );
-        command_buffer.pipeline_barrier(barriers);
+    }
 
-        command_buffer.end()?;
+    /// Screen space error in pixels allowed for mesh LOD levels, 0 always renders the full detail meshes
+    pub fn set_lod_error_threshold(&mut self, pixels: f32) {
+        self.lod_error_threshold = pixels.max(0.0);
+    }
 
-        self.renderer.queue_command_buffer(command_buffer);
+    pub fn lod_error_threshold(&self) -> f32 {
+        self.lod_error_threshold
+    }
 
-        self.renderer
-            .gpu_mut()
-            .update_image_transitions(0)
-            .expect("Failed to update Gpu image transitions");
+    /// Selects the coarsest LOD level of every mesh whose projected error stays within the threshold
+    // XXX: Projected errors and texture streaming assume a perspective falloff with distance, orthographic views
+    // select coarser levels than needed far from the camera
+    fn update_lod_selection(&mut self) -> Result<()> {
+        let _span = profiling::span("SceneRenderer::update_lod_selection");
 
-        self.renderer.end_frame()?;
+        let eye_position = *self.camera.position();
+        let pixels_per_unit = self.camera.pixels_per_unit();
+
+        for mesh in &self.meshes {
+            let global_matrix = &self.scene_graph.global_matrices[mesh.scene_graph_node_index];
+            let distance = mesh
+                .bounds
+                .transform(global_matrix)
+                .distance_to_point(&eye_position)
+                .max(f32::EPSILON);
+            // Object space errors are scaled by the largest axis scale of the node
+            let scale = (0..3)
+                .map(|column| global_matrix.fixed_view::<3, 1>(0, column).norm())
+                .fold(0.0, f32::max);
+
+            let mut lod = 0;
+            while lod + 1 < mesh.lod_count()
+                && mesh.lod_error(lod + 1) * scale / distance * pixels_per_unit
+                    <= self.lod_error_threshold
+            {
+                lod += 1;
+            }
+            if mesh.set_selected_lod(lod) && self.debug_view == DebugView::LodLevels {
+                mesh.set_color_tint(Vector4::from(LOD_TINT_COLORS[lod]));
+            }
+        }
 
         Ok(())
     }
 
-    pub fn wait_idle(&self) {
+    /// Tints the base colors of the meshes by their selected LOD level, or restores the untinted base colors
+    fn set_lod_tints(&self, tinted: bool) {
+        for mesh in &self.meshes {
+            let color_tint = if tinted {
+                LOD_TINT_COLORS[mesh.selected_lod()]
+            } else {
+                [1.0; 4]
+            };
+            mesh.set_color_tint(Vector4::from(color_tint));
+        }
+    }
+
+    pub fn render(&mut self) -> Result<()> {
+        let update_start = Instant::now();
+        let update_span = profiling::span("SceneRenderer::update");
+
+        self.update_camera();
+        self.upload_dirty_materials()?;
+        self.update_lod_selection()?;
+        self.simple_pbr_pass
+            .set_view_position(*self.camera.position());
+
+        if let Some(terrain_pass) = &self.terrain_pass {
+            terrain_pass.update_lods(self.camera.position());
+        }
+
+        let completed_value = self.renderer.gpu().completed_value()?;
+        self.object_picker.resolve(completed_value)?;
+
+        // XXX: This call is useless because the uniform buffers that contain the model matrix will not be updated. Handle this nicer?
+        // self.scene_graph.calculate_transforms()?;
+
+        if let Some(sky_pass) = &mut self.sky_pass {
+            sky_pass.update(&mut self.renderer)?;
+            if let Some(environment_image) = sky_pass.environment_image() {
+                self.scene_uniform_data.environment_irradiance_sh = *sky_pass.irradiance_sh();
+                self.scene_uniform_data.environment_specular_index =
+                    environment_image.bindless_index();
+                self.scene_uniform_data.environment_specular_mip_count =
+                    environment_image.mip_levels();
+            }
+        }
+
+        self.scene_uniform_data.debug_overdraw_storage_index = match &self.heatmap_pass {
+            Some(heatmap_pass) if self.debug_view == DebugView::OverdrawHeatmap => {
+                heatmap_pass.overdraw_storage_index()
+            }
+            _ => INVALID_BINDLESS_TEXTURE_INDEX,
+        };
+        // XXX: The shadow mask is traced after the render graph, lighting samples the mask of the previous frame
+        self.scene_uniform_data.shadow_mask_index = self
+            .ray_traced_shadows_pass
+            .as_ref()
+            .map_or(INVALID_BINDLESS_TEXTURE_INDEX, |pass| {
+                pass.shadow_mask_image().bindless_index()
+            });
+
+        self.scene_uniform_buffer
+            .copy_data_to_buffer(&[self.scene_uniform_data])?;
+        for viewport in &self.viewports {
+            viewport.update(&self.scene_uniform_data)?;
+        }
+
+        for file_name in self.renderer.reload_modified_techniques(&self.render_graph) {
+            self.render_graph.on_technique_reloaded(&file_name)?;
+        }
+        self.resize_render_graph()?;
+        let output_extent = self.renderer.extent();
+        for viewport in &mut self.viewports {
+            viewport.resize(&mut self.renderer, output_extent)?;
+        }
+
+        let update_ms = update_start.elapsed().as_secs_f32() * 1000.0;
+        drop(update_span);
+
+        if !self.renderer.begin_frame()? {
+            return Ok(());
+        }
+
+        let record_start = Instant::now();
+        let record_span = profiling::span("SceneRenderer::record");
+
+        let frame_context = FrameContext {
+            frame_index: self.renderer.gpu().current_frame_index(),
+            absolute_frame_index: self.renderer.gpu().absolute_frame_index(),
+            extent: self.render_graph_extent,
+        };
+        self.render_graph.prepare(&frame_context)?;
+        for viewport in &mut self.viewports {
+            viewport.prepare(&frame_context)?;
+        }
+
+        if let Some(auto_exposure) = &mut self.auto_exposure {
+            auto_exposure.update(frame_context.frame_index as usize)?;
+        }
+        if let Some(skinning_pass) = &self.skinning_pass {
+            skinning_pass.update(
+                frame_context.frame_index as usize,
+                &self.skins,
+                &self.scene_graph,
+            )?;
+        }
+        if let Some(particle_system) = &mut self.particle_system {
+            particle_system.update(&frame_context)?;
+        }
+
+        let command_buffer = self.renderer.command_buffer(0)?;
+        command_buffer.begin()?;
+
+        // Async compute work is submitted before the graphics work of the frame, which waits on it
+        let compute_schedule = self.resolve_compute_schedule();
+        let compute_command_buffer = if compute_schedule.has_async_compute_work() {
+            let compute_command_buffer = self.renderer.compute_command_buffer()?;
+            compute_command_buffer.begin()?;
+            Some(compute_command_buffer)
+        } else {
+            None
+        };
+        let workload_command_buffer =
+            |workload| match (compute_schedule.queue(workload), &compute_command_buffer) {
+                (Some(ComputeQueue::AsyncCompute), Some(compute_command_buffer)) => {
+                    (compute_command_buffer, ComputeQueue::AsyncCompute)
+                }
+                _ => (&command_buffer, ComputeQueue::Graphics),
+            };
+
+        let gpu = self.renderer.gpu();
+        let output_image = gpu.output_image();
+        let output_extent = gpu.swapchain_extent();
+
+        let barriers = Barriers::new().add_image(
+            &self.final_image,
+            ResourceState::SHADER_RESOURCE,
+            ResourceState::RENDER_TARGET,
+        );
+        command_buffer.pipeline_barrier(barriers);
+
+        if let Some(heatmap_pass) = &self.heatmap_pass {
+            if self.debug_view == DebugView::OverdrawHeatmap {
+                heatmap_pass.clear_overdraw(&command_buffer)?;
+            }
+        }
+
+        // Skinned vertices are shared by all passes drawing the scene meshes, including the additional viewports
+        if let Some(skinning_pass) = &self.skinning_pass {
+            let (command_buffer, queue) = workload_command_buffer(ComputeWorkload::Skinning);
+            command_buffer.push_timestamp_scope("skinning");
+            skinning_pass.record(command_buffer, frame_context.frame_index as usize, queue);
+            command_buffer.pop_timestamp_scope();
+        }
+
+        if let Some(particle_system) = &self.particle_system {
+            let (command_buffer, queue) = workload_command_buffer(ComputeWorkload::Particles);
+            command_buffer.push_timestamp_scope("particles");
+            particle_system.record_simulation(command_buffer, queue)?;
+            command_buffer.pop_timestamp_scope();
+        }
+
+        if let Some(indirect_draw_pass) = &self.indirect_draw_pass {
+            let (command_buffer, queue) = workload_command_buffer(ComputeWorkload::Culling);
+            command_buffer.push_timestamp_scope("mesh_culling");
+            indirect_draw_pass.record_culling(
+                command_buffer,
+                frame_context.frame_index as usize,
+                &self.camera,
+                queue,
+            )?;
+            command_buffer.pop_timestamp_scope();
+        }
+
+        self.render_graph.render(&command_buffer)?;
+
+        if let Some(ray_traced_shadows_pass) = &self.ray_traced_shadows_pass {
+            command_buffer.push_timestamp_scope("ray_traced_shadows");
+            ray_traced_shadows_pass.render(&command_buffer);
+            command_buffer.pop_timestamp_scope();
+        }
+
+        for viewport in &self.viewports {
+            viewport.record(&command_buffer)?;
+        }
+
+        if let Ok(resource) = self
+            .render_graph
+            .access_resource_by_name(OBJECT_ID_RESOURCE_NAME)
+        {
+            let object_ids = resource.gpu_image()?;
+            let state = self
+                .render_graph
+                .resource_final_state(OBJECT_ID_RESOURCE_NAME)?;
+            self.object_picker.record(
+                &command_buffer,
+                &object_ids,
+                state,
+                self.renderer.signal_value_after_submit(),
+            );
+        }
+
+        let mut barriers = Barriers::new()
+            .add_compute_image(
+                &self.final_image,
+                ResourceState::RENDER_TARGET,
+                ResourceState::SHADER_RESOURCE,
+            )
+            .add_image(
+                output_image,
+                ResourceState::UNDEFINED,
+                ResourceState::RENDER_TARGET,
+            );
+
+        // Debug view resource needs to be readable by the fullscreen pass
+        let debug_view = match &self.debug_view_resource {
+            Some(name) => {
+                let image = self
+                    .render_graph
+                    .access_resource_by_name(name)?
+                    .gpu_image()?;
+                let state = self.render_graph.resource_final_state(name)?;
+                Some((image, state))
+            }
+            None => None,
+        };
+        if let Some((image, state)) = &debug_view {
+            if *state != ResourceState::SHADER_RESOURCE {
+                barriers = barriers.add_image(image, *state, ResourceState::SHADER_RESOURCE);
+            }
+        }
+        command_buffer.pipeline_barrier(barriers);
+
+        if let Some(auto_exposure) = &mut self.auto_exposure {
+            command_buffer.push_timestamp_scope("auto_exposure");
+            auto_exposure.record(&command_buffer, frame_context.frame_index as usize)?;
+            command_buffer.pop_timestamp_scope();
+        }
+
+        if let (Some(heatmap_pass), Some(source)) =
+            (&self.heatmap_pass, self.debug_view.heatmap_source())
+        {
+            let max_value = match source {
+                HeatmapSource::Overdraw => DEFAULT_OVERDRAW_HEATMAP_MAX,
+                HeatmapSource::Luminance => DEFAULT_LUMINANCE_HEATMAP_STOPS,
+            };
+            command_buffer.push_timestamp_scope("debug_heatmap");
+            heatmap_pass.record(&command_buffer, source, max_value);
+            command_buffer.pop_timestamp_scope();
+        }
+
+        {
+            command_buffer.push_timestamp_scope("fullscreen");
+
+            let color_attachment = RenderColorAttachment::new()
+                .set_clear_value(vk::ClearColorValue {
+                    float32: [1.0, 1.0, 1.0, 1.0],
+                })
+                .set_operation(RenderPassOperation::Clear)
+                .set_image_view(output_image.raw_view())
+                .set_image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+
+            let rendering_state = RenderingState::new(output_extent.width, output_extent.height)
+                .add_color_attachment(color_attachment);
+            command_buffer.begin_rendering(rendering_state.clone());
+            set_fullscreen_viewport(
+                &command_buffer,
+                self.main_viewport_region.rect(output_extent),
+            );
+
+            let debug_technique = match self.debug_view {
+                DebugView::Final | DebugView::LodLevels => None,
+                _ => self.fullscreen_debug_technique.as_ref(),
+            };
+            let fullscreen_graphics_pipeline = debug_technique
+                .unwrap_or(&self.fullscreen_technique)
+                .pass(0)
+                .graphics_pipeline;
+            command_buffer.bind_graphics_pipeline(&fullscreen_graphics_pipeline);
+            command_buffer.bind_descriptor_set(
+                self.renderer.gpu().bindless_descriptor_set().as_ref(),
+                fullscreen_graphics_pipeline.raw_layout(),
+                0,
+            );
+
+            // Set final image bindless index as the instance count parameter
+            let fullscreen_image_index = match (&debug_view, &self.heatmap_pass) {
+                (Some((image, _)), _) => image.bindless_index(),
+                (None, Some(heatmap_pass)) => heatmap_pass.heatmap_image().bindless_index(),
+                (None, None) => self.final_image.bindless_index(),
+            };
+            if debug_technique.is_some() {
+                command_buffer.push_constants(
+                    fullscreen_graphics_pipeline.raw_layout(),
+                    vk::ShaderStageFlags::FRAGMENT,
+                    &GpuDebugViewConstants {
+                        image_index: fullscreen_image_index,
+                        debug_view: self.debug_view as u32,
+                    },
+                );
+            } else {
+                command_buffer.push_constants(
+                    fullscreen_graphics_pipeline.raw_layout(),
+                    vk::ShaderStageFlags::FRAGMENT,
+                    &GpuTonemapConstants {
+                        exposure: self.exposure(),
+                        transfer_function: self.renderer.output_transfer_function() as u32,
+                    },
+                );
+            }
+            command_buffer.draw(3, 1, 0, fullscreen_image_index);
+
+            // Debug views are only shown in the main view
+            if !self.viewports.is_empty() {
+                let fullscreen_graphics_pipeline =
+                    &self.fullscreen_technique.pass(0).graphics_pipeline;
+                command_buffer.bind_graphics_pipeline(fullscreen_graphics_pipeline);
+                command_buffer.bind_descriptor_set(
+                    self.renderer.gpu().bindless_descriptor_set().as_ref(),
+                    fullscreen_graphics_pipeline.raw_layout(),
+                    0,
+                );
+                command_buffer.push_constants(
+                    fullscreen_graphics_pipeline.raw_layout(),
+                    vk::ShaderStageFlags::FRAGMENT,
+                    &GpuTonemapConstants {
+                        exposure: self.exposure(),
+                        transfer_function: self.renderer.output_transfer_function() as u32,
+                    },
+                );
+            }
+            for viewport in &self.viewports {
+                set_fullscreen_viewport(&command_buffer, viewport.region.rect(output_extent));
+                command_buffer.draw(3, 1, 0, viewport.final_image().bindless_index());
+            }
+
+            command_buffer.set_viewport_and_scissor(&rendering_state);
+
+            if let Some(overlay) = &mut self.overlay {
+                overlay.render(
+                    &self.renderer,
+                    &command_buffer,
+                    frame_context.frame_index as usize,
+                    output_extent,
+                )?;
+            }
+
+            command_buffer.end_rendering();
+
+            command_buffer.pop_timestamp_scope();
+        }
+
+        let mut barriers = Barriers::new().add_image(
+            output_image,
+            ResourceState::RENDER_TARGET,
+            gpu.output_image_final_state(),
+        );
+
+        // Keep the graph resource in the state the graph expects it to be in
+        if let Some((image, state)) = &debug_view {
+            if *state != ResourceState::SHADER_RESOURCE {
+                barriers = barriers.add_image(image, ResourceState::SHADER_RESOURCE, *state);
+            }
+        }
+        command_buffer.pipeline_barrier(barriers);
+
+        command_buffer.end()?;
+
+        if let Some(compute_command_buffer) = compute_command_buffer {
+            compute_command_buffer.end()?;
+            self.renderer
+                .queue_compute_command_buffer(compute_command_buffer);
+        }
+        self.renderer.queue_command_buffer(command_buffer);
+        self.renderer.set_compute_schedule(compute_schedule);
+
+        let record_ms = record_start.elapsed().as_secs_f32() * 1000.0;
+        drop(record_span);
+        self.renderer.set_cpu_frame_timings(update_ms, record_ms);
+
+        self.renderer
+            .gpu_mut()
+            .update_image_transitions(0)
+            .expect("Failed to update Gpu image transitions");
+
+        self.renderer.end_frame()?;
+
+        self.render_graph.advance_history()?;
+        for viewport in &mut self.viewports {
+            viewport.advance_history()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn wait_idle(&self) {
         self.renderer.gpu().wait_idle();
     }
+
+    /// Displays a render graph image resource instead of the final image, None resets to the final image.
+    pub fn set_debug_view_resource(&mut self, name: Option<&str>) -> Result<()> {
+        self.bind_debug_view_resource(name)?;
+        self.heatmap_pass = None;
+        if self.debug_view == DebugView::LodLevels {
+            self.set_lod_tints(false);
+        }
+        self.debug_view = DebugView::Final;
+
+        Ok(())
+    }
+
+    /// Blurs the scene color by the circle of confusion of the camera before tonemapping, None disables it.
+    /// Fails if the render graph does not contain a depth of field pass.
+    pub fn set_depth_of_field(&mut self, settings: Option<DepthOfFieldSettings>) -> Result<()> {
+        match (settings, &mut self.depth_of_field_pass) {
+            (Some(settings), Some(depth_of_field_pass)) => {
+                depth_of_field_pass.set_settings(settings);
+                return Ok(());
+            }
+            (None, None) => return Ok(()),
+            _ => {}
+        }
+
+        // The final image is switched while the previous one may still be read by frames in flight
+        self.renderer.wait_idle();
+
+        match settings {
+            Some(settings) => {
+                let depth_of_field_pass = DepthOfFieldPass::new(
+                    &self.renderer,
+                    &self.render_graph,
+                    settings,
+                    self.scene_uniform_buffer.clone(),
+                )?;
+                self.render_graph.register_render_pass(
+                    DEPTH_OF_FIELD_PASS_NAME,
+                    depth_of_field_pass.create_render_pass(),
+                )?;
+                self.depth_of_field_pass = Some(depth_of_field_pass);
+            }
+            None => {
+                self.render_graph
+                    .unregister_render_pass(DEPTH_OF_FIELD_PASS_NAME)?;
+                self.depth_of_field_pass = None;
+            }
+        }
+
+        self.update_final_image()
+    }
+
+    pub fn depth_of_field(&self) -> Option<&DepthOfFieldSettings> {
+        self.depth_of_field_pass
+            .as_ref()
+            .map(|depth_of_field_pass| depth_of_field_pass.settings())
+    }
+
+    /// Upscales the reduced resolution scene color with FSR 1 before tonemapping, `sharpness` is in stops with 0 being
+    /// the sharpest and None disables upscaling. The render resolution is set by the `resolution_scale` of the graph
+    /// attachments. Fails if the render graph does not contain the FSR passes.
+    pub fn set_upscaling(&mut self, sharpness: Option<f32>) -> Result<()> {
+        match (sharpness, &mut self.fsr_pass) {
+            (Some(sharpness), Some(fsr_pass)) => {
+                fsr_pass.set_sharpness(sharpness);
+                return Ok(());
+            }
+            (None, None) => return Ok(()),
+            _ => {}
+        }
+
+        // The final image is switched while the previous one may still be read by frames in flight
+        self.renderer.wait_idle();
+
+        match sharpness {
+            Some(sharpness) => {
+                let fsr_pass = FsrUpscalePass::new(&self.renderer, &self.render_graph, sharpness)?;
+                self.render_graph
+                    .register_render_pass(FSR_EASU_PASS_NAME, fsr_pass.create_easu_render_pass())?;
+                self.render_graph
+                    .register_render_pass(FSR_RCAS_PASS_NAME, fsr_pass.create_rcas_render_pass())?;
+                self.fsr_pass = Some(fsr_pass);
+            }
+            None => {
+                self.render_graph
+                    .unregister_render_pass(FSR_EASU_PASS_NAME)?;
+                self.render_graph
+                    .unregister_render_pass(FSR_RCAS_PASS_NAME)?;
+                self.fsr_pass = None;
+            }
+        }
+
+        self.update_final_image()
+    }
+
+    pub fn upscaling_enabled(&self) -> bool {
+        self.fsr_pass.is_some()
+    }
+
+    /// Adapts the exposure of the final image to its luminance histogram, None returns to the manual exposure
+    pub fn set_auto_exposure(&mut self, desc: Option<AutoExposureDesc>) -> Result<()> {
+        match (desc, &mut self.auto_exposure) {
+            (Some(desc), Some(auto_exposure)) => auto_exposure.set_desc(desc),
+            (Some(desc), None) => {
+                self.auto_exposure = Some(AutoExposure::new(
+                    &self.renderer,
+                    self.final_image.clone(),
+                    desc,
+                )?);
+            }
+            (None, _) => {
+                // The histogram buffers may still be used by frames in flight
+                self.renderer.wait_idle();
+                self.auto_exposure = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn auto_exposure_enabled(&self) -> bool {
+        self.auto_exposure.is_some()
+    }
+
+    /// Exposure used by the tonemapping of the next frame, the camera exposure unless auto exposure is enabled
+    pub fn exposure(&self) -> f32 {
+        match &self.auto_exposure {
+            Some(auto_exposure) => auto_exposure.exposure(),
+            None => self.camera.exposure(),
+        }
+    }
+
+    pub fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    /// The aspect ratio is kept in sync with the main view by the scene renderer
+    pub fn camera_mut(&mut self) -> &mut Camera {
+        &mut self.camera
+    }
+
+    /// Fits the camera to the main view and writes its matrices to the scene constants
+    fn update_camera(&mut self) {
+        let extent = self
+            .main_viewport_region
+            .rect(self.renderer.extent())
+            .extent;
+        self.camera.set_extent(extent.width, extent.height);
+
+        let eye_position = self.camera.position();
+        self.scene_uniform_data.view = *self.camera.view();
+        self.scene_uniform_data.projection = *self.camera.projection_matrix();
+        self.scene_uniform_data.eye_position =
+            Vector4::new(eye_position.x, eye_position.y, eye_position.z, 1.0);
+    }
+
+    /// Renders a single channel of the scene through the fullscreen debug shader, `DebugView::Final` resets to the final image.
+    pub fn set_debug_view(&mut self, debug_view: DebugView) -> Result<()> {
+        // Restore the untinted base colors
+        if self.debug_view == DebugView::LodLevels && debug_view != DebugView::LodLevels {
+            self.set_lod_tints(false);
+        }
+
+        if debug_view == DebugView::Final || debug_view == DebugView::LodLevels {
+            self.bind_debug_view_resource(None)?;
+            self.heatmap_pass = None;
+            self.debug_view = debug_view;
+            if debug_view == DebugView::LodLevels {
+                self.set_lod_tints(true);
+            }
+            return Ok(());
+        }
+
+        if debug_view.heatmap_source().is_some() {
+            let outdated = self.heatmap_pass.as_ref().map_or(true, |heatmap_pass| {
+                heatmap_pass.scene_color().raw() != self.final_image.raw()
+            });
+            if outdated {
+                self.heatmap_pass = Some(HeatmapPass::new(
+                    &mut self.renderer,
+                    self.final_image.clone(),
+                )?);
+            }
+            self.load_fullscreen_debug_technique()?;
+            self.bind_debug_view_resource(None)?;
+            self.debug_view = debug_view;
+            return Ok(());
+        }
+        self.heatmap_pass = None;
+
+        let name = match debug_view.graph_resource_name() {
+            Some(name) => name.to_string(),
+            None => self.scene_depth_resource_name()?,
+        };
+        let image = self
+            .render_graph
+            .access_resource_by_name(&name)
+            .with_context(|| {
+                format!(
+                    "Debug view {:?} requires graph resource {}",
+                    debug_view, name
+                )
+            })?
+            .gpu_image()?;
+        if image.has_stencil() {
+            return Err(anyhow::anyhow!(
+                "Depth stencil resource {} cannot be sampled by the debug view",
+                name
+            ));
+        }
+
+        self.load_fullscreen_debug_technique()?;
+
+        self.renderer
+            .gpu_mut()
+            .add_bindless_image_update(ImageResourceUpdate {
+                frame: 0,
+                image: Some(image),
+                sampler: None,
+            });
+        self.debug_view_resource = Some(name);
+        self.debug_view = debug_view;
+
+        Ok(())
+    }
+
+    pub fn debug_view(&self) -> DebugView {
+        self.debug_view
+    }
+
+    fn load_fullscreen_debug_technique(&mut self) -> Result<()> {
+        if self.fullscreen_debug_technique.is_none() {
+            self.fullscreen_debug_technique = Some(
+                self.renderer
+                    .create_technique_from_file(
+                        RenderTechniqeFilePaths::FULLSCREEN_DEBUG,
+                        &self.render_graph,
+                    )
+                    .context("Failed to load fullscreen debug technique")?,
+            );
+        }
+        Ok(())
+    }
+
+    fn scene_depth_resource_name(&self) -> Result<String> {
+        self.render_graph
+            .access_node_by_name("simple_pbr_pass")?
+            .outputs
+            .iter()
+            .filter_map(|handle| self.render_graph.access_resource_by_handle(*handle).ok())
+            .find(|resource| {
+                resource
+                    .gpu_image()
+                    .map_or(false, |image| image.has_depth())
+            })
+            .map(|resource| resource.name.clone())
+            .context("Render graph does not contain a scene depth attachment")
+    }
+
+    fn bind_debug_view_resource(&mut self, name: Option<&str>) -> Result<()> {
+        if let Some(name) = name {
+            let image = self
+                .render_graph
+                .access_resource_by_name(name)?
+                .gpu_image()?;
+
+            // Depth attachments are not created as sampled images
+            if image.has_depth() {
+                return Err(anyhow::anyhow!(
+                    "Depth resource {} cannot be used as a debug view",
+                    name
+                ));
+            }
+
+            self.renderer
+                .gpu_mut()
+                .add_bindless_image_update(ImageResourceUpdate {
+                    frame: 0,
+                    image: Some(image),
+                    sampler: None,
+                });
+        }
+
+        self.debug_view_resource = name.map(str::to_string);
+
+        Ok(())
+    }
+
+    /// Reloads the render graph from its file, keeping registered render passes and unchanged attachments
+    pub fn reload_render_graph(&mut self) -> Result<()> {
+        self.renderer.wait_idle();
+
+        let diff = self
+            .render_graph
+            .reload_from_file(self.renderer.gpu_mut())
+            .context("Failed to reload render graph")?;
+        if diff.is_empty() {
+            return Ok(());
+        }
+
+        // XXX: Techniques are not rebuilt, attachment format changes require reloading the technique files
+        self.update_graph_resources()
+    }
+
+    /// Enables or disables a render graph pass and recompiles the graph.
+    /// Fails and keeps the current graph if another enabled pass depends on the pass.
+    pub fn set_render_pass_enabled(&mut self, name: &str, enabled: bool) -> Result<()> {
+        // XXX: The final image is produced by the PBR pass, make the graph output node configurable
+        if name == "simple_pbr_pass" && !enabled {
+            return Err(anyhow::anyhow!(
+                "Render pass {} produces the final image and cannot be disabled",
+                name
+            ));
+        }
+
+        self.renderer.wait_idle();
+
+        self.render_graph
+            .set_render_pass_enabled(self.renderer.gpu_mut(), name, enabled)?;
+
+        self.update_graph_resources()
+    }
+
+    pub fn render_pass_enabled(&self, name: &str) -> Result<bool> {
+        self.render_graph.render_pass_enabled(name)
+    }
+
+    /// Render graph pass names with their enabled state, in execution order
+    pub fn render_pass_states(&self) -> Result<Vec<(String, bool)>> {
+        self.render_graph.render_pass_states()
+    }
+
+    /// Recreates relative resolution graph attachments when the main view extent changes
+    fn resize_render_graph(&mut self) -> Result<()> {
+        let extent = self
+            .main_viewport_region
+            .rect(self.renderer.extent())
+            .extent;
+        if extent == self.render_graph_extent {
+            return Ok(());
+        }
+
+        self.renderer.wait_idle();
+
+        let resized =
+            self.render_graph
+                .on_resize(self.renderer.gpu_mut(), extent.width, extent.height)?;
+        self.render_graph_extent = extent;
+
+        if !resized.is_empty() {
+            log::info!("Resized render graph attachments: {:?}", resized);
+            self.update_graph_resources()?;
+        }
+
+        Ok(())
+    }
+
+    /// Updates state that references render graph images after the graph images were recreated
+    fn update_graph_resources(&mut self) -> Result<()> {
+        if let Some(depth_of_field_pass) = &mut self.depth_of_field_pass {
+            depth_of_field_pass.update_graph_resources(&self.renderer, &self.render_graph)?;
+        }
+        if let Some(fsr_pass) = &mut self.fsr_pass {
+            fsr_pass.update_graph_resources(&self.renderer, &self.render_graph)?;
+        }
+
+        self.update_final_image()?;
+
+        if self.debug_view != DebugView::Final && self.debug_view != DebugView::LodLevels {
+            if self.set_debug_view(self.debug_view).is_err() {
+                self.set_debug_view(DebugView::Final)?;
+            }
+        } else if let Some(name) = self.debug_view_resource.clone() {
+            if self.set_debug_view_resource(Some(&name)).is_err() {
+                self.set_debug_view_resource(None)?;
+            }
+        }
+
+        // The ray traced shadows pass references the scene depth attachment
+        if self.ray_traced_shadows_enabled() {
+            self.set_ray_traced_shadows(false)?;
+            self.set_ray_traced_shadows(true)?;
+        }
+
+        Ok(())
+    }
+
+    /// Picks up the graph image that is tonemapped, the upscaled or depth of field output replaces the scene color
+    /// when enabled
+    fn update_final_image(&mut self) -> Result<()> {
+        let final_image_graph_resource = if self.fsr_pass.is_some() {
+            self.render_graph
+                .access_node_by_name(FSR_RCAS_PASS_NAME)?
+                .outputs[0]
+        } else if self.depth_of_field_pass.is_some() {
+            self.render_graph
+                .access_node_by_name(DEPTH_OF_FIELD_PASS_NAME)?
+                .outputs[0]
+        } else {
+            self.render_graph
+                .access_node_by_name("simple_pbr_pass")
+                .context("Failed to retrieve render graph final node")?
+                .outputs[1]
+        };
+        let final_image = self
+            .render_graph
+            .access_resource_by_handle(final_image_graph_resource)?
+            .gpu_image()?;
+
+        if final_image.raw() != self.final_image.raw() {
+            self.renderer
+                .gpu_mut()
+                .add_bindless_image_update(ImageResourceUpdate {
+                    frame: 0,
+                    image: Some(final_image.clone()),
+                    sampler: None,
+                });
+            self.renderer.gpu().transition_image_layout(
+                &final_image,
+                ResourceState::UNDEFINED,
+                ResourceState::SHADER_RESOURCE,
+            )?;
+            if let Some(auto_exposure) = &mut self.auto_exposure {
+                auto_exposure.set_hdr_image(&self.renderer, final_image.clone())?;
+            }
+            self.final_image = final_image;
+        }
+
+        Ok(())
+    }
+
+    /// Builds the scene acceleration structures on first use, fails if the Gpu cannot ray trace
+    pub fn set_ray_traced_shadows(&mut self, enabled: bool) -> Result<()> {
+        if !enabled {
+            self.ray_traced_shadows_pass = None;
+            return Ok(());
+        }
+        if self.ray_traced_shadows_pass.is_some() {
+            return Ok(());
+        }
+
+        let depth_image = self
+            .render_graph
+            .access_resource_by_name(SCENE_DEPTH_RESOURCE_NAME)
+            .and_then(|resource| resource.gpu_image())
+            .ok()
+            .filter(|image| image.has_depth())
+            .with_context(|| {
+                format!(
+                    "Render graph does not contain the {} depth attachment",
+                    SCENE_DEPTH_RESOURCE_NAME
+                )
+            })?;
+
+        // Acceleration structures are built with blocking submissions
+        self.renderer.wait_idle();
+
+        self.ray_traced_shadows_pass = Some(RayTracedShadowsPass::new(
+            &mut self.renderer,
+            &self.meshes,
+            &self.scene_graph,
+            depth_image,
+            self.scene_uniform_buffer.clone(),
+        )?);
+
+        Ok(())
+    }
+
+    /// Replaces the current terrain, fails if the render graph does not contain a terrain pass
+    pub fn load_terrain(&mut self, heightmap: &Heightmap, desc: TerrainDesc) -> Result<()> {
+        self.render_graph
+            .access_node_by_name(TERRAIN_PASS_NAME)
+            .context("Render graph does not contain a terrain pass")?;
+
+        // The previous terrain may still be used by frames in flight
+        self.renderer.wait_idle();
+
+        let terrain_pass = TerrainPass::new(
+            &mut self.renderer,
+            &self.render_graph,
+            heightmap,
+            desc,
+            self.scene_uniform_buffer.clone(),
+        )?;
+        self.render_graph
+            .register_render_pass(TERRAIN_PASS_NAME, terrain_pass.create_render_pass())?;
+        self.terrain_pass = Some(terrain_pass);
+
+        Ok(())
+    }
+
+    pub fn unload_terrain(&mut self) -> Result<()> {
+        if self.terrain_pass.is_none() {
+            return Ok(());
+        }
+
+        self.renderer.wait_idle();
+
+        self.render_graph
+            .unregister_render_pass(TERRAIN_PASS_NAME)?;
+        self.terrain_pass = None;
+
+        Ok(())
+    }
+
+    pub fn terrain_loaded(&self) -> bool {
+        self.terrain_pass.is_some()
+    }
+
+    /// Draws an analytic sky and uses it as the ambient environment, replacing the current sky.
+    /// Fails if the render graph does not contain a sky pass.
+    pub fn set_sky(&mut self, sun_direction: Vector3<f32>, turbidity: f32) -> Result<()> {
+        if let Some(sky_pass) = &mut self.sky_pass {
+            sky_pass.set_sun(sun_direction, turbidity);
+            return Ok(());
+        }
+
+        self.render_graph
+            .access_node_by_name(SKY_PASS_NAME)
+            .context("Render graph does not contain a sky pass")?;
+
+        let sky_pass = SkyPass::new(
+            &self.renderer,
+            &self.render_graph,
+            sun_direction,
+            turbidity,
+            self.scene_uniform_buffer.clone(),
+        )?;
+        self.render_graph
+            .register_render_pass(SKY_PASS_NAME, sky_pass.create_render_pass())?;
+        self.sky_pass = Some(sky_pass);
+
+        Ok(())
+    }
+
+    pub fn clear_sky(&mut self) -> Result<()> {
+        if self.sky_pass.is_none() {
+            return Ok(());
+        }
+
+        self.renderer.wait_idle();
+
+        self.render_graph.unregister_render_pass(SKY_PASS_NAME)?;
+        self.sky_pass = None;
+
+        self.scene_uniform_data.environment_irradiance_sh = [Vector4::zeros(); 9];
+        self.scene_uniform_data.environment_specular_index = INVALID_BINDLESS_TEXTURE_INDEX;
+        self.scene_uniform_data.environment_specular_mip_count = 0;
+
+        Ok(())
+    }
+
+    pub fn sky(&self) -> Option<&PreethamSky> {
+        self.sky_pass.as_ref().map(|sky_pass| sky_pass.sky())
+    }
+
+    /// 2D overlay drawn on top of the output image, quads added to it are drawn by the next `render`
+    pub fn overlay(&mut self) -> Result<&mut Overlay> {
+        if self.overlay.is_none() {
+            self.overlay = Some(Overlay::new(&self.renderer, &self.render_graph)?);
+        }
+        Ok(self.overlay.as_mut().unwrap())
+    }
+
+    /// Replaces the particle system with one simulating `emitters`, fails if the render graph does not contain the
+    /// particle render pass
+    pub fn set_particle_emitters(&mut self, emitters: Vec<EmitterDesc>) -> Result<()> {
+        self.render_graph
+            .access_node_by_name(PARTICLE_RENDER_PASS_NAME)
+            .with_context(|| {
+                format!(
+                    "Render graph does not contain pass {}",
+                    PARTICLE_RENDER_PASS_NAME
+                )
+            })?;
+
+        // The previous particle buffers may still be used by frames in flight
+        self.renderer.wait_idle();
+
+        let particle_system = ParticleSystem::new(
+            &mut self.renderer,
+            &self.render_graph,
+            emitters,
+            self.scene_uniform_buffer.clone(),
+        )?;
+        self.render_graph.register_render_pass(
+            PARTICLE_RENDER_PASS_NAME,
+            particle_system.create_render_pass(),
+        )?;
+        self.particle_system = Some(particle_system);
+
+        Ok(())
+    }
+
+    pub fn clear_particle_emitters(&mut self) -> Result<()> {
+        if self.particle_system.is_none() {
+            return Ok(());
+        }
+
+        self.renderer.wait_idle();
+
+        self.render_graph
+            .unregister_render_pass(PARTICLE_RENDER_PASS_NAME)?;
+        self.particle_system = None;
+
+        Ok(())
+    }
+
+    pub fn particle_system(&self) -> Option<&ParticleSystem> {
+        self.particle_system.as_ref()
+    }
+
+    /// Renders the scene meshes with LINE polygon mode pipelines
+    pub fn set_wireframe(&mut self, enabled: bool) -> Result<()> {
+        self.renderer
+            .set_technique_wireframe(&self.simple_pbr_render_technique, enabled)
+    }
+
+    pub fn wireframe(&self) -> bool {
+        self.simple_pbr_render_technique.wireframe()
+    }
+
+    pub fn ray_traced_shadows_enabled(&self) -> bool {
+        self.ray_traced_shadows_pass.is_some()
+    }
+
+    pub fn ray_traced_shadow_mask(&self) -> Option<&Handle<Image>> {
+        self.ray_traced_shadows_pass
+            .as_ref()
+            .map(|pass| pass.shadow_mask_image())
+    }
+
+    pub fn debug_view_resource(&self) -> Option<&str> {
+        self.debug_view_resource.as_deref()
+    }
+
+    pub fn graph_attachment_names(&self) -> Result<Vec<String>> {
+        self.render_graph.attachment_names()
+    }
+
+    /// Saves a render graph image resource of the last rendered frame to disk.
+    pub fn capture_graph_resource(&mut self, name: &str, file_name: &str) -> Result<()> {
+        let capture = self
+            .render_graph
+            .capture_resource(self.renderer.gpu_mut(), name)?;
+        capture::save_image_capture(&capture, file_name)
+    }
+
+    /// Requests the scene graph node under the pixel (x, y) of the render graph extent. The object ID is read back
+    /// asynchronously by a later frame, returns the node of the most recently completed request.
+    /// Fails if the render graph does not contain an object ID attachment.
+    pub fn pick(&mut self, x: u32, y: u32) -> Result<Option<usize>> {
+        if self
+            .render_graph
+            .access_resource_by_name(OBJECT_ID_RESOURCE_NAME)
+            .is_err()
+        {
+            return Err(anyhow::anyhow!(
+                "Render graph does not contain the {} attachment",
+                OBJECT_ID_RESOURCE_NAME
+            ));
+        }
+
+        self.object_picker.request(x, y);
+
+        Ok(self.object_picker.picked_node_index())
+    }
+
+    /// Draws and state changes of the opaque scene meshes recorded by the last frame
+    pub fn draw_list_stats(&self) -> DrawListStats {
+        self.simple_pbr_pass.draw_list_stats()
+    }
+
+    pub fn meshlet_storage_buffers(&self) -> Option<&MeshletStorageBuffers> {
+        self.meshlet_storage_buffers.as_ref()
+    }
+
+    /// Renders the scene from `camera` into its own instance of the render graph, composited into `region` of the
+    /// output image over the main view. Graph resources are not shared with the main view.
+    pub fn add_viewport(
+        &mut self,
+        name: &str,
+        region: ViewportRegion,
+        camera: ViewportCamera,
+    ) -> Result<()> {
+        if self.viewports.iter().any(|viewport| viewport.name == name) {
+            return Err(anyhow::anyhow!("Viewport {} already exists", name));
+        }
+
+        let render_graph_file_name = self
+            .render_graph
+            .source_file_name()
+            .context("Viewports require a render graph loaded from a file")?
+            .to_string();
+        let mut viewport = Viewport::new(
+            &mut self.renderer,
+            &render_graph_file_name,
+            name,
+            region,
+            camera,
+        )?;
+        viewport.rebuild_scene_pass(
+            &self.renderer,
+            &self.meshes,
+            self.material_storage.as_ref(),
+            &self.simple_pbr_render_technique,
+        )?;
+
+        log::info!("Added viewport {} with region {:?}", name, region);
+        self.viewports.push(viewport);
+
+        Ok(())
+    }
+
+    pub fn remove_viewport(&mut self, name: &str) -> Result<()> {
+        let position = self.viewport_position(name)?;
+
+        // The viewport graph resources may still be used by frames in flight
+        self.renderer.wait_idle();
+        self.viewports.remove(position);
+
+        Ok(())
+    }
+
+    pub fn set_viewport_camera(&mut self, name: &str, camera: ViewportCamera) -> Result<()> {
+        let position = self.viewport_position(name)?;
+        self.viewports[position].camera = camera;
+        Ok(())
+    }
+
+    /// The viewport render graph is resized before the next frame
+    pub fn set_viewport_region(&mut self, name: &str, region: ViewportRegion) -> Result<()> {
+        let position = self.viewport_position(name)?;
+        self.viewports[position].region = region;
+        Ok(())
+    }
+
+    pub fn viewport_names(&self) -> Vec<&str> {
+        self.viewports
+            .iter()
+            .map(|viewport| viewport.name.as_str())
+            .collect()
+    }
+
+    /// Region of the output image the main view is composited to, the main render graph is resized before the next
+    /// frame. The camera aspect ratio follows the region.
+    // XXX: Picking coordinates are relative to the main view
+    pub fn set_main_viewport_region(&mut self, region: ViewportRegion) {
+        self.main_viewport_region = region;
+    }
+
+    pub fn main_viewport_region(&self) -> ViewportRegion {
+        self.main_viewport_region
+    }
+
+    fn viewport_position(&self, name: &str) -> Result<usize> {
+        self.viewports
+            .iter()
+            .position(|viewport| viewport.name == name)
+            .ok_or_else(|| anyhow::anyhow!("Viewport {} does not exist", name))
+    }
+
+    pub fn renderer(&self) -> &Renderer {
+        &self.renderer
+    }
+
+    pub fn renderer_mut(&mut self) -> &mut Renderer {
+        &mut self.renderer
+    }
+}
+
+/// Limits the fullscreen pass to `rect` of the output image, the fullscreen triangle covers the whole viewport
+fn set_fullscreen_viewport(command_buffer: &CommandBuffer, rect: vk::Rect2D) {
+    command_buffer.set_viewport(
+        rect.offset.x as f32,
+        rect.offset.y as f32,
+        rect.extent.width as f32,
+        rect.extent.height as f32,
+    );
+    command_buffer.set_scissor(
+        rect.offset.x,
+        rect.offset.y,
+        rect.extent.width,
+        rect.extent.height,
+    );
 }
@@ -0,0 +1,81 @@
+use rikka_core::nalgebra::Vector3;
+
+/// Indexed triangle list adapter for mikktspace, tangents are written per vertex
+struct TangentGeometry<'a> {
+    indices: &'a [u32],
+    positions: &'a [[f32; 3]],
+    normals: &'a [[f32; 3]],
+    tex_coords: &'a [[f32; 2]],
+    tangents: Vec<[f32; 4]>,
+}
+
+impl<'a> TangentGeometry<'a> {
+    fn vertex_index(&self, face: usize, vert: usize) -> usize {
+        self.indices[face * 3 + vert] as usize
+    }
+}
+
+impl<'a> mikktspace::Geometry for TangentGeometry<'a> {
+    fn num_faces(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        3
+    }
+
+    fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.positions[self.vertex_index(face, vert)]
+    }
+
+    fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.normals[self.vertex_index(face, vert)]
+    }
+
+    fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+        self.tex_coords[self.vertex_index(face, vert)]
+    }
+
+    // XXX: Vertices shared by faces with different tangent frames are not split, the last written tangent wins
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+        let vertex_index = self.vertex_index(face, vert);
+        self.tangents[vertex_index] = tangent;
+    }
+}
+
+/// Generates mikktspace tangents of an indexed triangle list, the w component stores the bitangent sign.
+/// Returns None if the tangent space could not be generated.
+pub fn generate_tangents(
+    indices: &[u32],
+    positions: &[[f32; 3]],
+    normals: &[[f32; 3]],
+    tex_coords: &[[f32; 2]],
+) -> Option<Vec<[f32; 4]>> {
+    if indices.len() < 3 || tex_coords.len() != positions.len() {
+        return None;
+    }
+
+    let mut geometry = TangentGeometry {
+        indices,
+        positions,
+        normals,
+        tex_coords,
+        tangents: vec![[1.0, 0.0, 0.0, 1.0]; positions.len()],
+    };
+
+    if !mikktspace::generate_tangents(&mut geometry) {
+        return None;
+    }
+
+    // Degenerate texture coordinates can produce tangents parallel to the normal
+    for (tangent, normal) in geometry.tangents.iter_mut().zip(normals) {
+        let normal = Vector3::from(*normal);
+        let orthogonal = Vector3::new(tangent[0], tangent[1], tangent[2]);
+        let orthogonal = orthogonal - normal * normal.dot(&orthogonal);
+        if let Some(orthogonal) = orthogonal.try_normalize(f32::EPSILON) {
+            *tangent = [orthogonal.x, orthogonal.y, orthogonal.z, tangent[3]];
+        }
+    }
+
+    Some(geometry.tangents)
+}
@@ -0,0 +1,362 @@
+use std::{mem::size_of, sync::Arc};
+
+use anyhow::{Context, Result};
+
+use rikka_core::{
+    nalgebra::{Matrix4, Vector3, Vector4},
+    vk,
+};
+use rikka_gpu::{barriers::*, buffer::*, command_buffer::CommandBuffer, image::Image, types::*};
+use rikka_graph::{graph::Graph, types::FrameContext};
+
+use crate::{
+    camera::Camera,
+    pass::simple_pbr::SimplePbrPass,
+    renderer::*,
+    scene_renderer::{
+        material_storage::MaterialStorage, mesh::Mesh, scene_renderer::GpuSceneUniformData,
+    },
+};
+
+/// Render graph node that produces the image composited into the viewport region
+pub const VIEWPORT_FINAL_NODE_NAME: &str = "simple_pbr_pass";
+/// Output of `VIEWPORT_FINAL_NODE_NAME` composited into the viewport region
+pub const VIEWPORT_FINAL_IMAGE_NAME: &str = "final";
+
+/// Normalized region of the output image, (0, 0) is the top left corner
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ViewportRegion {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl ViewportRegion {
+    pub const FULL: Self = Self {
+        x: 0.0,
+        y: 0.0,
+        width: 1.0,
+        height: 1.0,
+    };
+
+    pub fn new(x: f32, y: f32, width: f32, height: f32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Pixels of the region in an image of `extent`, clamped to the image and always at least 1x1
+    pub fn rect(&self, extent: vk::Extent2D) -> vk::Rect2D {
+        // The offset is kept inside the image so a region starting on the right or bottom edge still covers a pixel
+        let x = ((self.x.clamp(0.0, 1.0) * extent.width as f32) as u32)
+            .min(extent.width.saturating_sub(1));
+        let y = ((self.y.clamp(0.0, 1.0) * extent.height as f32) as u32)
+            .min(extent.height.saturating_sub(1));
+        let width = ((self.width * extent.width as f32) as u32)
+            .min(extent.width - x)
+            .max(1);
+        let height = ((self.height * extent.height as f32) as u32)
+            .min(extent.height - y)
+            .max(1);
+
+        vk::Rect2D {
+            offset: vk::Offset2D {
+                x: x as i32,
+                y: y as i32,
+            },
+            extent: vk::Extent2D { width, height },
+        }
+    }
+}
+
+/// Camera of an additional viewport, the projection needs to match the aspect ratio of the viewport region
+#[derive(Clone, Copy, Debug)]
+pub struct ViewportCamera {
+    pub view: Matrix4<f32>,
+    pub projection: Matrix4<f32>,
+    pub eye_position: Vector3<f32>,
+}
+
+impl From<&Camera> for ViewportCamera {
+    fn from(camera: &Camera) -> Self {
+        Self {
+            view: *camera.view(),
+            projection: *camera.projection_matrix(),
+            eye_position: *camera.position(),
+        }
+    }
+}
+
+/// Scene rendered from an additional camera into its own instance of the render graph. The fullscreen pass composites
+/// its final image into `region` of the output image.
+// XXX: Only the simple PBR pass is registered, terrain, sky, particles and post processing are main view only
+// XXX: Mesh LODs are selected for the main camera
+pub(crate) struct Viewport {
+    pub(crate) name: String,
+    pub(crate) region: ViewportRegion,
+    pub(crate) camera: ViewportCamera,
+
+    render_graph: Graph,
+    render_graph_extent: vk::Extent2D,
+
+    scene_uniform_buffer: Handle<Buffer>,
+    simple_pbr_pass: SimplePbrPass,
+    final_image: Handle<Image>,
+}
+
+impl Viewport {
+    pub(crate) fn new(
+        renderer: &mut Renderer,
+        render_graph_file_name: &str,
+        name: &str,
+        region: ViewportRegion,
+        camera: ViewportCamera,
+    ) -> Result<Self> {
+        let mut render_graph = rikka_graph::parser::parse_from_file(render_graph_file_name)
+            .with_context(|| format!("Failed to load render graph of viewport {}", name))?;
+        render_graph.compile(renderer.gpu_mut())?;
+
+        let render_graph_extent = region.rect(renderer.extent()).extent;
+        render_graph.on_resize(
+            renderer.gpu_mut(),
+            render_graph_extent.width,
+            render_graph_extent.height,
+        )?;
+
+        let scene_uniform_buffer = renderer.create_buffer(
+            BufferDesc::new()
+                .set_size(size_of::<GpuSceneUniformData>() as _)
+                .set_device_only(false)
+                .set_usage_flags(vk::BufferUsageFlags::UNIFORM_BUFFER),
+        )?;
+
+        let simple_pbr_pass = SimplePbrPass::new(
+            renderer,
+            &render_graph,
+            &[],
+            renderer.gpu().bindless_descriptor_set().clone(),
+            None,
+        )?;
+        render_graph.register_render_pass(
+            VIEWPORT_FINAL_NODE_NAME,
+            simple_pbr_pass.create_render_pass(),
+        )?;
+
+        let final_image = Self::graph_final_image(&render_graph)?;
+        Self::bind_final_image(renderer, &final_image)?;
+
+        Ok(Self {
+            name: name.to_string(),
+            region,
+            camera,
+            render_graph,
+            render_graph_extent,
+            scene_uniform_buffer,
+            simple_pbr_pass,
+            final_image,
+        })
+    }
+
+    /// Recreates the scene draw pass for the current scene meshes
+    pub(crate) fn rebuild_scene_pass(
+        &mut self,
+        renderer: &Renderer,
+        meshes: &[Arc<Mesh>],
+        material_storage: Option<&MaterialStorage>,
+        render_technique: &RenderTechnique,
+    ) -> Result<()> {
+        let material_descriptor_set = match material_storage {
+            Some(material_storage) => Some(material_storage.create_view_descriptor_set(
+                renderer,
+                render_technique,
+                self.scene_uniform_buffer.clone(),
+            )?),
+            None => None,
+        };
+
+        self.simple_pbr_pass = SimplePbrPass::new(
+            renderer,
+            &self.render_graph,
+            meshes,
+            renderer.gpu().bindless_descriptor_set().clone(),
+            material_descriptor_set,
+        )?;
+        self.render_graph.register_render_pass(
+            VIEWPORT_FINAL_NODE_NAME,
+            self.simple_pbr_pass.create_render_pass(),
+        )
+    }
+
+    /// Uploads the scene constants of the main view with the camera of this viewport
+    pub(crate) fn update(&self, scene_uniform_data: &GpuSceneUniformData) -> Result<()> {
+        let mut scene_uniform_data = *scene_uniform_data;
+        scene_uniform_data.view = self.camera.view;
+        scene_uniform_data.projection = self.camera.projection;
+        scene_uniform_data.eye_position = Vector4::new(
+            self.camera.eye_position.x,
+            self.camera.eye_position.y,
+            self.camera.eye_position.z,
+            1.0,
+        );
+        self.scene_uniform_buffer
+            .copy_data_to_buffer(&[scene_uniform_data])?;
+
+        self.simple_pbr_pass
+            .set_view_position(self.camera.eye_position);
+
+        Ok(())
+    }
+
+    /// Resizes the render graph to the region of an output image of `output_extent`
+    pub(crate) fn resize(
+        &mut self,
+        renderer: &mut Renderer,
+        output_extent: vk::Extent2D,
+    ) -> Result<()> {
+        let extent = self.region.rect(output_extent).extent;
+        if extent == self.render_graph_extent {
+            return Ok(());
+        }
+
+        renderer.wait_idle();
+
+        self.render_graph
+            .on_resize(renderer.gpu_mut(), extent.width, extent.height)?;
+        self.render_graph_extent = extent;
+
+        let final_image = Self::graph_final_image(&self.render_graph)?;
+        if final_image.raw() != self.final_image.raw() {
+            Self::bind_final_image(renderer, &final_image)?;
+            self.final_image = final_image;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn prepare(&mut self, frame_context: &FrameContext) -> Result<()> {
+        self.render_graph.prepare(&FrameContext {
+            extent: self.render_graph_extent,
+            ..*frame_context
+        })
+    }
+
+    /// Leaves the final image readable by the fullscreen pass
+    pub(crate) fn record(&self, command_buffer: &CommandBuffer) -> Result<()> {
+        command_buffer.push_timestamp_scope(&self.name);
+
+        command_buffer.pipeline_barrier(Barriers::new().add_image(
+            &self.final_image,
+            ResourceState::SHADER_RESOURCE,
+            ResourceState::RENDER_TARGET,
+        ));
+
+        self.render_graph.render(command_buffer)?;
+
+        command_buffer.pipeline_barrier(Barriers::new().add_compute_image(
+            &self.final_image,
+            ResourceState::RENDER_TARGET,
+            ResourceState::SHADER_RESOURCE,
+        ));
+
+        command_buffer.pop_timestamp_scope();
+
+        Ok(())
+    }
+
+    pub(crate) fn advance_history(&mut self) -> Result<()> {
+        self.render_graph.advance_history()
+    }
+
+    pub(crate) fn final_image(&self) -> &Handle<Image> {
+        &self.final_image
+    }
+
+    fn graph_final_image(render_graph: &Graph) -> Result<Handle<Image>> {
+        let node = render_graph
+            .access_node_by_name(VIEWPORT_FINAL_NODE_NAME)
+            .context("Failed to retrieve render graph final node")?;
+
+        for output in &node.outputs {
+            let resource = render_graph.access_resource_by_handle(*output)?;
+            if resource.name == VIEWPORT_FINAL_IMAGE_NAME {
+                return resource.gpu_image();
+            }
+        }
+
+        Err(anyhow::anyhow!(
+            "Render graph node {} has no output {}",
+            VIEWPORT_FINAL_NODE_NAME,
+            VIEWPORT_FINAL_IMAGE_NAME
+        ))
+    }
+
+    /// Makes the final image sampleable by the fullscreen pass through its bindless index
+    fn bind_final_image(renderer: &mut Renderer, final_image: &Handle<Image>) -> Result<()> {
+        renderer
+            .gpu_mut()
+            .add_bindless_image_update(ImageResourceUpdate {
+                frame: 0,
+                image: Some(final_image.clone()),
+                sampler: None,
+            });
+        renderer.gpu_mut().update_bindless_images();
+
+        renderer.gpu().transition_image_layout(
+            final_image,
+            ResourceState::UNDEFINED,
+            ResourceState::SHADER_RESOURCE,
+        )?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXTENT: vk::Extent2D = vk::Extent2D {
+        width: 1280,
+        height: 720,
+    };
+
+    #[test]
+    fn test_region_rect_clamped() {
+        let rect = ViewportRegion::new(0.75, 0.5, 0.5, 1.0).rect(EXTENT);
+        assert_eq!(rect.offset, vk::Offset2D { x: 960, y: 360 });
+        assert_eq!(
+            rect.extent,
+            vk::Extent2D {
+                width: 320,
+                height: 360
+            }
+        );
+    }
+
+    #[test]
+    fn test_region_rect_not_empty() {
+        for region in [
+            ViewportRegion::new(1.0, 1.0, 0.5, 0.5),
+            ViewportRegion::new(0.5, 0.5, 0.0, 0.0),
+            ViewportRegion::new(0.0, 0.0, -1.0, -1.0),
+        ] {
+            let rect = region.rect(EXTENT);
+            assert!(rect.extent.width >= 1 && rect.extent.height >= 1);
+            assert!(rect.offset.x as u32 + rect.extent.width <= EXTENT.width);
+            assert!(rect.offset.y as u32 + rect.extent.height <= EXTENT.height);
+        }
+
+        let rect = ViewportRegion::FULL.rect(vk::Extent2D::default());
+        assert_eq!(
+            rect.extent,
+            vk::Extent2D {
+                width: 1,
+                height: 1
+            }
+        );
+    }
+}
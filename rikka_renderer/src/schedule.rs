@@ -0,0 +1,136 @@
+use std::fmt;
+
+/// Compute work recorded by the scene renderer every frame that can be placed on the async compute queue
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ComputeWorkload {
+    Skinning,
+    Particles,
+    Culling,
+}
+
+impl ComputeWorkload {
+    pub const ALL: [ComputeWorkload; 3] = [Self::Skinning, Self::Particles, Self::Culling];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Skinning => "skinning",
+            Self::Particles => "particles",
+            Self::Culling => "culling",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ComputeQueue {
+    /// Recorded into the graphics command buffer of the frame
+    Graphics,
+    /// Submitted before the graphics work of the frame, overlaps the graphics work of the previous frame
+    AsyncCompute,
+}
+
+/// Queues requested for the compute workloads. Requests for the async compute queue fall back to the graphics queue
+/// when the Gpu has no dedicated compute queue family or the workload cannot run on it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ComputeSchedulePolicy {
+    skinning: ComputeQueue,
+    particles: ComputeQueue,
+    culling: ComputeQueue,
+}
+
+impl ComputeSchedulePolicy {
+    /// All workloads on the graphics queue
+    pub fn new() -> Self {
+        Self {
+            skinning: ComputeQueue::Graphics,
+            particles: ComputeQueue::Graphics,
+            culling: ComputeQueue::Graphics,
+        }
+    }
+
+    /// All workloads on the async compute queue where possible
+    pub fn async_compute() -> Self {
+        Self {
+            skinning: ComputeQueue::AsyncCompute,
+            particles: ComputeQueue::AsyncCompute,
+            culling: ComputeQueue::AsyncCompute,
+        }
+    }
+
+    pub fn set_queue(mut self, workload: ComputeWorkload, queue: ComputeQueue) -> Self {
+        match workload {
+            ComputeWorkload::Skinning => self.skinning = queue,
+            ComputeWorkload::Particles => self.particles = queue,
+            ComputeWorkload::Culling => self.culling = queue,
+        }
+        self
+    }
+
+    pub fn queue(&self, workload: ComputeWorkload) -> ComputeQueue {
+        match workload {
+            ComputeWorkload::Skinning => self.skinning,
+            ComputeWorkload::Particles => self.particles,
+            ComputeWorkload::Culling => self.culling,
+        }
+    }
+}
+
+impl Default for ComputeSchedulePolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScheduledWorkload {
+    pub workload: ComputeWorkload,
+    pub queue: ComputeQueue,
+    /// Why the async compute queue requested by the policy was not used
+    pub fallback_reason: Option<&'static str>,
+}
+
+/// Queues the compute workloads of a frame were recorded on, workloads without work in the scene are left out
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ComputeSchedule {
+    pub workloads: Vec<ScheduledWorkload>,
+}
+
+impl ComputeSchedule {
+    /// None if the workload had no work in the frame
+    pub fn queue(&self, workload: ComputeWorkload) -> Option<ComputeQueue> {
+        self.workloads
+            .iter()
+            .find(|scheduled| scheduled.workload == workload)
+            .map(|scheduled| scheduled.queue)
+    }
+
+    pub fn has_async_compute_work(&self) -> bool {
+        self.workloads
+            .iter()
+            .any(|scheduled| scheduled.queue == ComputeQueue::AsyncCompute)
+    }
+}
+
+impl fmt::Display for ComputeSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.workloads.is_empty() {
+            return write!(f, "no compute");
+        }
+
+        for (index, scheduled) in self.workloads.iter().enumerate() {
+            if index > 0 {
+                write!(f, " ")?;
+            }
+
+            let queue = match scheduled.queue {
+                ComputeQueue::Graphics => "gfx",
+                ComputeQueue::AsyncCompute => "async",
+            };
+            write!(f, "{} {}", scheduled.workload.name(), queue)?;
+            if scheduled.fallback_reason.is_some() {
+                write!(f, " (fallback)")?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,291 @@
+use std::f32::consts::PI;
+
+use rikka_core::nalgebra::{Vector3, Vector4};
+
+/// Perez distribution coefficients A to E of one channel of the Preetham model
+#[derive(Clone, Copy, Debug)]
+pub struct PerezCoefficients(pub [f32; 5]);
+
+impl PerezCoefficients {
+    /// Relative radiance for a view direction at zenith angle `theta` and angle `gamma` to the sun
+    fn evaluate(&self, cos_theta: f32, gamma: f32) -> f32 {
+        let [a, b, c, d, e] = self.0;
+        let cos_gamma = gamma.cos();
+        (1.0 + a * (b / cos_theta.max(0.01)).exp())
+            * (1.0 + c * (d * gamma).exp() + e * cos_gamma * cos_gamma)
+    }
+}
+
+/// Analytic clear sky model of Preetham, Shirley and Smits, "A Practical Analytic Model for Daylight".
+/// Directions are in world space with +y up, the sky below the horizon repeats the horizon.
+#[derive(Clone, Debug)]
+pub struct PreethamSky {
+    sun_direction: Vector3<f32>,
+    turbidity: f32,
+    /// Luminance is computed in kcd/m2 and scaled to renderer units
+    luminance_scale: f32,
+
+    /// Perez coefficients of the luminance Y and the chromaticities x, y
+    perez: [PerezCoefficients; 3],
+    /// Zenith luminance and chromaticities
+    zenith: [f32; 3],
+}
+
+pub const DEFAULT_SKY_TURBIDITY: f32 = 2.5;
+pub const DEFAULT_SKY_LUMINANCE_SCALE: f32 = 0.04;
+
+impl PreethamSky {
+    /// `turbidity` is clamped to [1.7, 10] where the model fits the measured data
+    pub fn new(sun_direction: Vector3<f32>, turbidity: f32, luminance_scale: f32) -> Self {
+        let sun_direction = sun_direction
+            .try_normalize(f32::EPSILON)
+            .unwrap_or_else(Vector3::y);
+        let t = turbidity.clamp(1.7, 10.0);
+
+        let perez = [
+            PerezCoefficients([
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ]),
+            PerezCoefficients([
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ]),
+            PerezCoefficients([
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ]),
+        ];
+
+        // The sun is kept slightly above the horizon, the zenith luminance fit diverges below it
+        let theta_sun = sun_direction.y.clamp(0.01, 1.0).acos();
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_sun);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+
+        let theta = [theta_sun.powi(3), theta_sun.powi(2), theta_sun, 1.0];
+        let chromaticity = |matrix: [[f32; 4]; 3]| {
+            let row = |row: [f32; 4]| row.iter().zip(theta).map(|(m, t)| m * t).sum::<f32>();
+            t * t * row(matrix[0]) + t * row(matrix[1]) + row(matrix[2])
+        };
+        let zenith_x = chromaticity([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let zenith_y = chromaticity([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+
+        Self {
+            sun_direction,
+            turbidity: t,
+            luminance_scale,
+            perez,
+            zenith: [zenith_luminance.max(0.0), zenith_x, zenith_y],
+        }
+    }
+
+    pub fn sun_direction(&self) -> Vector3<f32> {
+        self.sun_direction
+    }
+
+    pub fn turbidity(&self) -> f32 {
+        self.turbidity
+    }
+
+    pub fn luminance_scale(&self) -> f32 {
+        self.luminance_scale
+    }
+
+    pub fn perez_coefficients(&self) -> &[PerezCoefficients; 3] {
+        &self.perez
+    }
+
+    pub fn zenith(&self) -> [f32; 3] {
+        self.zenith
+    }
+
+    /// Perez functions of the zenith, which the distribution of every channel is normalized by
+    pub fn perez_normalization(&self) -> [f32; 3] {
+        let theta_sun = self.sun_direction.y.clamp(0.01, 1.0).acos();
+        [0, 1, 2].map(|channel| self.perez[channel].evaluate(1.0, theta_sun))
+    }
+
+    /// Linear sRGB radiance seen along `direction`
+    pub fn radiance(&self, direction: &Vector3<f32>) -> Vector3<f32> {
+        let direction = direction.normalize();
+        let cos_theta = direction.y.max(0.0);
+        let gamma = direction.dot(&self.sun_direction).clamp(-1.0, 1.0).acos();
+
+        let normalization = self.perez_normalization();
+        let yxy = [0, 1, 2].map(|channel| {
+            self.zenith[channel] * self.perez[channel].evaluate(cos_theta, gamma)
+                / normalization[channel]
+        });
+
+        let luminance = yxy[0] * self.luminance_scale;
+        let (x, y) = (yxy[1], yxy[2].max(f32::EPSILON));
+        let cie_x = x * luminance / y;
+        let cie_z = (1.0 - x - y) * luminance / y;
+
+        Vector3::new(
+            3.2406 * cie_x - 1.5372 * luminance - 0.4986 * cie_z,
+            -0.9689 * cie_x + 1.8758 * luminance + 0.0415 * cie_z,
+            0.0557 * cie_x - 0.2040 * luminance + 1.0570 * cie_z,
+        )
+        .map(|channel| channel.max(0.0))
+    }
+}
+
+/// Direction through the center of a cube map texel, faces are ordered +x, -x, +y, -y, +z, -z
+pub fn cube_map_direction(face: u32, x: u32, y: u32, size: u32) -> Vector3<f32> {
+    let s = 2.0 * (x as f32 + 0.5) / size as f32 - 1.0;
+    let t = 2.0 * (y as f32 + 0.5) / size as f32 - 1.0;
+
+    let direction = match face {
+        0 => Vector3::new(1.0, -t, -s),
+        1 => Vector3::new(-1.0, -t, s),
+        2 => Vector3::new(s, 1.0, t),
+        3 => Vector3::new(s, -1.0, -t),
+        4 => Vector3::new(s, -t, 1.0),
+        _ => Vector3::new(-s, -t, -1.0),
+    };
+    direction.normalize()
+}
+
+/// Approximate solid angle covered by a cube map texel
+fn cube_map_texel_solid_angle(x: u32, y: u32, size: u32) -> f32 {
+    let s = 2.0 * (x as f32 + 0.5) / size as f32 - 1.0;
+    let t = 2.0 * (y as f32 + 0.5) / size as f32 - 1.0;
+    let texel_size = 2.0 / size as f32;
+    texel_size * texel_size / (1.0 + s * s + t * t).powf(1.5)
+}
+
+/// Real spherical harmonics basis up to band 2
+fn sh9_basis(direction: &Vector3<f32>) -> [f32; 9] {
+    let (x, y, z) = (direction.x, direction.y, direction.z);
+    [
+        0.282095,
+        0.488603 * y,
+        0.488603 * z,
+        0.488603 * x,
+        1.092548 * x * y,
+        1.092548 * y * z,
+        0.315392 * (3.0 * z * z - 1.0),
+        1.092548 * x * z,
+        0.546274 * (x * x - y * y),
+    ]
+}
+
+/// Cube map radiance with its irradiance and prefiltered specular reflections
+pub struct SkyEnvironment {
+    pub size: u32,
+    pub mip_count: u32,
+    /// RGBA32F texels of all mips starting with the most detailed one, each mip stores its 6 faces consecutively.
+    /// Mip levels above 0 are prefiltered for increasing roughness, linearly from 0 to 1.
+    pub specular: Vec<[f32; 4]>,
+    /// Irradiance as band 2 spherical harmonics already convolved with the cosine lobe, w is unused.
+    /// Divide by pi for the Lambertian diffuse term.
+    pub irradiance_sh: [Vector4<f32>; 9],
+}
+
+impl SkyEnvironment {
+    /// Evaluates the sky into a cube map of `size` texels per side, `size` is expected to be a small power of two
+    pub fn generate(sky: &PreethamSky, size: u32) -> Self {
+        let size = size.max(1);
+        let mip_count = 32 - size.leading_zeros();
+
+        let mut directions = Vec::with_capacity((6 * size * size) as usize);
+        let mut solid_angles = Vec::with_capacity(directions.capacity());
+        let mut radiance = Vec::with_capacity(directions.capacity());
+        for face in 0..6 {
+            for y in 0..size {
+                for x in 0..size {
+                    let direction = cube_map_direction(face, x, y, size);
+                    radiance.push(sky.radiance(&direction));
+                    directions.push(direction);
+                    solid_angles.push(cube_map_texel_solid_angle(x, y, size));
+                }
+            }
+        }
+
+        // Project the radiance and convolve it with the clamped cosine lobe
+        let mut irradiance_sh = [Vector4::zeros(); 9];
+        for ((direction, solid_angle), radiance) in
+            directions.iter().zip(&solid_angles).zip(&radiance)
+        {
+            for (coefficient, basis) in irradiance_sh.iter_mut().zip(sh9_basis(direction)) {
+                *coefficient += (radiance * basis * solid_angle).push(0.0);
+            }
+        }
+        const BAND_CONVOLUTION: [f32; 9] = [
+            PI,
+            2.0 * PI / 3.0,
+            2.0 * PI / 3.0,
+            2.0 * PI / 3.0,
+            PI / 4.0,
+            PI / 4.0,
+            PI / 4.0,
+            PI / 4.0,
+            PI / 4.0,
+        ];
+        for (coefficient, convolution) in irradiance_sh.iter_mut().zip(BAND_CONVOLUTION) {
+            *coefficient *= convolution;
+        }
+
+        let mut specular = radiance
+            .iter()
+            .map(|radiance| [radiance.x, radiance.y, radiance.z, 1.0])
+            .collect::<Vec<_>>();
+        for mip in 1..mip_count {
+            let mip_size = (size >> mip).max(1);
+            let roughness = mip as f32 / (mip_count - 1) as f32;
+            // Phong exponent approximating the GGX lobe of the roughness
+            let alpha = roughness * roughness;
+            let exponent = (2.0 / (alpha * alpha).max(1e-4) - 2.0).max(1.0);
+
+            for face in 0..6 {
+                for y in 0..mip_size {
+                    for x in 0..mip_size {
+                        let normal = cube_map_direction(face, x, y, mip_size);
+
+                        let mut sum = Vector3::zeros();
+                        let mut weight_sum = 0.0;
+                        for ((direction, solid_angle), radiance) in
+                            directions.iter().zip(&solid_angles).zip(&radiance)
+                        {
+                            let cos = normal.dot(direction);
+                            if cos <= 0.0 {
+                                continue;
+                            }
+                            let weight = cos.powf(exponent) * solid_angle;
+                            sum += radiance * weight;
+                            weight_sum += weight;
+                        }
+
+                        let texel = sum / weight_sum.max(f32::EPSILON);
+                        specular.push([texel.x, texel.y, texel.z, 1.0]);
+                    }
+                }
+            }
+        }
+
+        Self {
+            size,
+            mip_count,
+            specular,
+            irradiance_sh,
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use rikka_gpu::{command_buffer::DrawStats, query::GpuTimestamp};
+
+use crate::schedule::ComputeSchedule;
+
+/// Timings and draw counts of a single frame, Cpu times are in milliseconds
+#[derive(Clone, Debug, Default)]
+pub struct FrameStats {
+    /// Scene and resource updates before recording
+    pub cpu_update_ms: f32,
+    pub cpu_record_ms: f32,
+    /// Queue submission and presentation
+    pub cpu_submit_ms: f32,
+
+    pub draw_calls: u32,
+    pub triangles: u64,
+
+    /// Gpu timestamp scopes lag behind the Cpu stats by the number of frames in flight
+    pub gpu_timestamps: Vec<GpuTimestamp>,
+    /// Sum of the top level timestamp scopes
+    pub gpu_frame_ms: f32,
+
+    /// Queues the compute workloads were recorded on
+    pub compute_schedule: ComputeSchedule,
+}
+
+impl FrameStats {
+    pub fn cpu_frame_ms(&self) -> f32 {
+        self.cpu_update_ms + self.cpu_record_ms + self.cpu_submit_ms
+    }
+
+    pub(crate) fn add_draw_stats(&mut self, draw_stats: DrawStats) {
+        self.draw_calls += draw_stats.draw_calls;
+        self.triangles += draw_stats.triangles;
+    }
+
+    pub(crate) fn set_gpu_timestamps(&mut self, gpu_timestamps: &[GpuTimestamp]) {
+        self.gpu_timestamps = gpu_timestamps.to_vec();
+        self.gpu_frame_ms = gpu_timestamps
+            .iter()
+            .filter(|timestamp| timestamp.depth == 0)
+            .map(|timestamp| timestamp.duration_ms)
+            .sum();
+    }
+
+    /// Single line summary
+    pub fn summary(&self) -> String {
+        format!(
+            "cpu {:.2}ms (update {:.2} record {:.2} submit {:.2}) | gpu {:.2}ms | {} draws {} tris | {}",
+            self.cpu_frame_ms(),
+            self.cpu_update_ms,
+            self.cpu_record_ms,
+            self.cpu_submit_ms,
+            self.gpu_frame_ms,
+            self.draw_calls,
+            self.triangles,
+            self.compute_schedule
+        )
+    }
+}
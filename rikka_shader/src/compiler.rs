@@ -1,7 +1,9 @@
 use std::{
+    collections::hash_map::DefaultHasher,
     fs::{self, File},
+    hash::{Hash, Hasher},
     io::Write,
-    path::Path,
+    path::{Path, PathBuf},
     process::Command,
 };
 
@@ -12,13 +14,52 @@ use crate::types::*;
 const GLSL_VERSION_DIRECTIVE: &str = "#version 460 core";
 const SHADER_INCLUDE_PRAGMA: &str = "#pragma RIKKA_REQUIRE";
 
+pub const DEFAULT_SHADER_CACHE_DIRECTORY: &str = "shader_cache";
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq)]
+pub enum ShaderCompilerBackend {
+    /// Shells out to the glslangValidator binary, which needs to be in PATH
+    GlslangValidatorCli,
+    /// Compiles in process through shaderc
+    #[cfg(feature = "shaderc")]
+    Shaderc,
+}
+
+impl Default for ShaderCompilerBackend {
+    fn default() -> Self {
+        #[cfg(feature = "shaderc")]
+        return Self::Shaderc;
+        #[cfg(not(feature = "shaderc"))]
+        return Self::GlslangValidatorCli;
+    }
+}
+
+/// Original file and line(1-based) of a line in the include processed shader source
+#[derive(Clone, Debug)]
+pub struct SourceLocation {
+    pub file_name: String,
+    pub line: usize,
+}
+
 pub fn read_shader_binary_file(file_name: &str) -> Result<ShaderData> {
     let bytes = fs::read(file_name)?;
     Ok(ShaderData { bytes })
 }
 
 pub fn process_includes(content: &str, base_path: &str, output: &mut String) -> Result<()> {
-    for line in content.lines() {
+    let mut line_map = Vec::new();
+    process_includes_with_line_map(content, "", base_path, output, &mut line_map)
+}
+
+/// Same as `process_includes`, additionally records the source location of every output line
+pub fn process_includes_with_line_map(
+    content: &str,
+    file_name: &str,
+    base_path: &str,
+    output: &mut String,
+    line_map: &mut Vec<SourceLocation>,
+) -> Result<()> {
+    for (line_index, line) in content.lines().enumerate() {
         let trimmed_line = line.trim();
 
         if trimmed_line.starts_with(SHADER_INCLUDE_PRAGMA) {
@@ -26,15 +67,27 @@ pub fn process_includes(content: &str, base_path: &str, output: &mut String) ->
             let end_index = trimmed_line.rfind(')').unwrap_or(start_index);
             let include_path = &trimmed_line[start_index + 1..end_index];
 
-            let include_content = fs::read_to_string(format!("{}/{}", base_path, include_path))?;
+            let include_file_name = format!("{}/{}", base_path, include_path);
+            let include_content = fs::read_to_string(&include_file_name)
+                .with_context(|| format!("Failed to read shader include {}", include_file_name))?;
 
-            process_includes(include_content.as_str(), base_path, output)?
+            process_includes_with_line_map(
+                include_content.as_str(),
+                include_file_name.as_str(),
+                base_path,
+                output,
+                line_map,
+            )?
         } else if trimmed_line == GLSL_VERSION_DIRECTIVE {
             // XXX: Handle error case where version is different
             continue;
         } else {
             output.push_str(line);
             output.push('\n');
+            line_map.push(SourceLocation {
+                file_name: file_name.to_owned(),
+                line: line_index + 1,
+            });
         }
     }
 
@@ -48,6 +101,14 @@ pub fn read_shader_source_file(file_name: &str) -> Result<String> {
 }
 
 pub fn read_shader_source_file_with_includes(file_name: &str) -> Result<String> {
+    let (final_shader_source, _) = read_shader_source_file_with_line_map(file_name)?;
+    Ok(final_shader_source)
+}
+
+/// Reads and processes includes of a shader source file, returning the final source and the source location of each of its lines
+pub fn read_shader_source_file_with_line_map(
+    file_name: &str,
+) -> Result<(String, Vec<SourceLocation>)> {
     let input_base_path = Path::new(file_name)
         .parent()
         .unwrap_or_else(|| Path::new(""))
@@ -57,21 +118,171 @@ pub fn read_shader_source_file_with_includes(file_name: &str) -> Result<String>
     let initial_shader_source = read_shader_source_file(file_name)?;
 
     let mut final_shader_source = String::from(GLSL_VERSION_DIRECTIVE);
-    process_includes(
+    final_shader_source.push('\n');
+    let mut line_map = vec![SourceLocation {
+        file_name: file_name.to_owned(),
+        line: 1,
+    }];
+
+    process_includes_with_line_map(
         initial_shader_source.as_str(),
+        file_name,
         input_base_path,
         &mut final_shader_source,
+        &mut line_map,
     )?;
 
-    Ok(final_shader_source)
+    Ok((final_shader_source, line_map))
+}
+
+/// Rewrites `<name>:<line>:` locations in compiler messages to the original file and line
+pub fn map_compile_errors_to_source(
+    messages: &str,
+    compiled_name: &str,
+    line_map: &[SourceLocation],
+) -> String {
+    let prefix = format!("{}:", compiled_name);
+
+    messages
+        .lines()
+        .map(|message| {
+            // Messages are formatted as `[ERROR: ]<name>:<line>: <message>`
+            let location = message.find(&prefix).and_then(|start| {
+                let line_start = start + prefix.len();
+                let line_end = message[line_start..].find(':')? + line_start;
+                let line = message[line_start..line_end].trim().parse::<usize>().ok()?;
+                Some((start, line_end, line))
+            });
+
+            match location {
+                Some((start, line_end, line)) if line > 0 && line <= line_map.len() => {
+                    let source_location = &line_map[line - 1];
+                    format!(
+                        "{}{}:{}{}",
+                        &message[..start],
+                        source_location.file_name,
+                        source_location.line,
+                        &message[line_end..]
+                    )
+                }
+                _ => message.to_owned(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn compile_shader(
+    source_file_name: &str,
+    destination_file_name: &str,
+    shader_type: ShaderStageType,
+    defines: &[ShaderDefine],
+    backend: ShaderCompilerBackend,
+) -> Result<ShaderData> {
+    match backend {
+        ShaderCompilerBackend::GlslangValidatorCli => compile_shader_through_glslangvalidator_cli(
+            source_file_name,
+            destination_file_name,
+            shader_type,
+            defines,
+        ),
+        #[cfg(feature = "shaderc")]
+        ShaderCompilerBackend::Shaderc => compile_shader_through_shaderc(
+            source_file_name,
+            destination_file_name,
+            shader_type,
+            defines,
+        ),
+    }
+}
+
+#[cfg(feature = "shaderc")]
+fn shaderc_shader_kind(shader_type: ShaderStageType) -> shaderc::ShaderKind {
+    match shader_type {
+        ShaderStageType::Vertex => shaderc::ShaderKind::Vertex,
+        ShaderStageType::Fragment => shaderc::ShaderKind::Fragment,
+        ShaderStageType::Geometry => shaderc::ShaderKind::Geometry,
+        ShaderStageType::Compute => shaderc::ShaderKind::Compute,
+        ShaderStageType::Mesh => shaderc::ShaderKind::Mesh,
+        ShaderStageType::Task => shaderc::ShaderKind::Task,
+        ShaderStageType::RayGeneration => shaderc::ShaderKind::RayGeneration,
+        ShaderStageType::Miss => shaderc::ShaderKind::Miss,
+        ShaderStageType::ClosestHit => shaderc::ShaderKind::ClosestHit,
+        ShaderStageType::AnyHit => shaderc::ShaderKind::AnyHit,
+        ShaderStageType::Intersection => shaderc::ShaderKind::Intersection,
+    }
+}
+
+/// Compiles in process and also writes the SPIR-V binary to the destination file
+#[cfg(feature = "shaderc")]
+pub fn compile_shader_through_shaderc(
+    source_file_name: &str,
+    destination_file_name: &str,
+    shader_type: ShaderStageType,
+    defines: &[ShaderDefine],
+) -> Result<ShaderData> {
+    let (shader_source, line_map) = read_shader_source_file_with_line_map(source_file_name)?;
+
+    let compiler = shaderc::Compiler::new()
+        .ok_or_else(|| anyhow::anyhow!("Failed to create shaderc compiler"))?;
+    let mut options = shaderc::CompileOptions::new()
+        .ok_or_else(|| anyhow::anyhow!("Failed to create shaderc compile options"))?;
+    options.set_target_env(
+        shaderc::TargetEnv::Vulkan,
+        shaderc::EnvVersion::Vulkan1_3 as u32,
+    );
+    options.set_source_language(shaderc::SourceLanguage::GLSL);
+    options.add_macro_definition(shader_type.to_glslang_stage_defines().as_str(), None);
+    for define in defines {
+        options.add_macro_definition(define.name.as_str(), define.value.as_deref());
+    }
+
+    let artifact = match compiler.compile_into_spirv(
+        shader_source.as_str(),
+        shaderc_shader_kind(shader_type),
+        source_file_name,
+        "main",
+        Some(&options),
+    ) {
+        Ok(artifact) => artifact,
+        Err(shaderc::Error::CompilationError(_, messages)) => {
+            let messages = map_compile_errors_to_source(&messages, source_file_name, &line_map);
+            log::error!("shaderc returned error:\n{}", messages);
+
+            return Err(anyhow::anyhow!(
+                "Failed to compile shader {} through shaderc:\n{}",
+                source_file_name,
+                messages
+            ));
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    if artifact.get_num_warnings() > 0 {
+        log::warn!(
+            "shaderc warnings:\n{}",
+            map_compile_errors_to_source(
+                &artifact.get_warning_messages(),
+                source_file_name,
+                &line_map
+            )
+        );
+    }
+
+    let bytes = artifact.as_binary_u8().to_vec();
+    fs::write(destination_file_name, &bytes)
+        .context("Failed to write compiled shader binary file")?;
+
+    Ok(ShaderData { bytes })
 }
 
 pub fn compile_shader_through_glslangvalidator_cli(
     source_file_name: &str,
     destination_file_name: &str,
     shader_type: ShaderStageType,
+    defines: &[ShaderDefine],
 ) -> Result<ShaderData> {
-    let shader_source = read_shader_source_file_with_includes(source_file_name)?;
+    let (shader_source, line_map) = read_shader_source_file_with_line_map(source_file_name)?;
 
     let temp_file_name = "temp_shader";
     {
@@ -92,6 +303,11 @@ pub fn compile_shader_through_glslangvalidator_cli(
         .args(["-o", destination_file_name])
         .args(["-S", shader_type.to_glslang_compiler_extension().as_str()])
         .args(["--D", shader_type.to_glslang_stage_defines().as_str()])
+        .args(
+            defines
+                .iter()
+                .map(|define| format!("-D{}", define.to_command_line_string())),
+        )
         .output()?;
 
     fs::remove_file(temp_file_name).context("Failed to remove temp shader source file")?;
@@ -100,18 +316,172 @@ pub fn compile_shader_through_glslangvalidator_cli(
         let shader_data = read_shader_binary_file(destination_file_name)?;
         Ok(shader_data)
     } else {
-        // log::error!(
-        //     "glslangValidator returned error: {:?}",
-        //     command_output.stderr
-        // );
-
-        println!(
-            "glslangValidator returned error: {:?}",
-            String::from_utf8(command_output.stdout)
+        let messages = map_compile_errors_to_source(
+            &String::from_utf8_lossy(&command_output.stdout),
+            temp_file_name,
+            &line_map,
         );
+        log::error!("glslangValidator returned error:\n{}", messages);
 
         Err(anyhow::anyhow!(
-            "Failed to compile shader through glslangvalidator!"
+            "Failed to compile shader {} through glslangvalidator:\n{}",
+            source_file_name,
+            messages
+        ))
+    }
+}
+
+/// On disk cache of compiled SPIR-V binaries, keyed by the hash of the include processed source, stage and backend
+#[derive(Clone, Debug)]
+pub struct ShaderCache {
+    directory: PathBuf,
+}
+
+impl Default for ShaderCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_SHADER_CACHE_DIRECTORY)
+    }
+}
+
+impl ShaderCache {
+    pub fn new(directory: impl AsRef<Path>) -> Self {
+        Self {
+            directory: directory.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Returns the cached binary if the shader source(including includes) did not change and was not modified after the
+    /// binary was written, otherwise compiles and caches it
+    pub fn compile(
+        &self,
+        source_file_name: &str,
+        destination_file_name: &str,
+        shader_type: ShaderStageType,
+        defines: &[ShaderDefine],
+        backend: ShaderCompilerBackend,
+    ) -> Result<ShaderData> {
+        let (shader_source, line_map) = read_shader_source_file_with_line_map(source_file_name)?;
+        let cache_file_path = self.cache_file_path(
+            source_file_name,
+            Self::hash_key(&shader_source, shader_type, defines, backend),
+        );
+
+        if Self::is_newer_than_sources(&cache_file_path, &line_map) {
+            if let Ok(bytes) = fs::read(&cache_file_path) {
+                log::trace!("Using cached shader binary for {}", source_file_name);
+                return Ok(ShaderData { bytes });
+            }
+        }
+
+        let shader_data = compile_shader(
+            source_file_name,
+            destination_file_name,
+            shader_type,
+            defines,
+            backend,
+        )?;
+
+        fs::create_dir_all(&self.directory).context("Failed to create shader cache directory")?;
+        if let Err(err) = fs::write(&cache_file_path, &shader_data.bytes) {
+            log::warn!(
+                "Failed to write shader cache file {:?}: {}",
+                cache_file_path,
+                err
+            );
+        }
+
+        Ok(shader_data)
+    }
+
+    /// Removes all cached binaries of a shader source file
+    pub fn invalidate(&self, source_file_name: &str) -> Result<()> {
+        let prefix = Self::cache_file_prefix(source_file_name);
+        self.remove_entries(|file_name| file_name.starts_with(&prefix))
+    }
+
+    /// Removes all cached binaries
+    pub fn clear(&self) -> Result<()> {
+        self.remove_entries(|_| true)
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    fn remove_entries(&self, predicate: impl Fn(&str) -> bool) -> Result<()> {
+        if !self.directory.exists() {
+            return Ok(());
+        }
+
+        for entry in fs::read_dir(&self.directory)? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+
+            if file_name.ends_with(".spv") && predicate(&file_name) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the cached binary was written after the last modification of the shader source and its includes
+    fn is_newer_than_sources(cache_file_path: &Path, line_map: &[SourceLocation]) -> bool {
+        let modified = |path: &Path| {
+            fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        };
+
+        let cache_modified = match modified(cache_file_path) {
+            Some(cache_modified) => cache_modified,
+            None => return false,
+        };
+
+        let mut source_file_names = line_map
+            .iter()
+            .map(|location| location.file_name.as_str())
+            .collect::<Vec<_>>();
+        source_file_names.sort_unstable();
+        source_file_names.dedup();
+
+        source_file_names.iter().all(|file_name| {
+            modified(Path::new(file_name))
+                .map_or(false, |source_modified| source_modified <= cache_modified)
+        })
+    }
+
+    // XXX: DefaultHasher is not guaranteed to be stable across Rust versions, which only causes cache misses
+    fn hash_key(
+        shader_source: &str,
+        shader_type: ShaderStageType,
+        defines: &[ShaderDefine],
+        backend: ShaderCompilerBackend,
+    ) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        shader_source.hash(&mut hasher);
+        shader_type
+            .to_glslang_compiler_extension()
+            .hash(&mut hasher);
+        defines.hash(&mut hasher);
+        backend.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn cache_file_prefix(source_file_name: &str) -> String {
+        let sanitized_name = source_file_name
+            .chars()
+            .map(|c| if c.is_alphanumeric() { c } else { '_' })
+            .collect::<String>();
+        format!("{}-", sanitized_name)
+    }
+
+    fn cache_file_path(&self, source_file_name: &str, hash: u64) -> PathBuf {
+        self.directory.join(format!(
+            "{}{:016x}.spv",
+            Self::cache_file_prefix(source_file_name),
+            hash
         ))
     }
 }
@@ -15,6 +15,29 @@ mod tests {
     // println!("{}", final_shader_source);
     // }
 
+    #[test]
+    fn test_map_compile_errors_to_source() {
+        let line_map = vec![
+            SourceLocation {
+                file_name: String::from("main.glsl"),
+                line: 1,
+            },
+            SourceLocation {
+                file_name: String::from("scene.glsl"),
+                line: 7,
+            },
+        ];
+
+        let messages =
+            "ERROR: temp_shader:2: 'foo' : undeclared identifier\ntemp_shader:9: out of range";
+        let mapped = map_compile_errors_to_source(messages, "temp_shader", &line_map);
+
+        assert_eq!(
+            mapped,
+            "ERROR: scene.glsl:7: 'foo' : undeclared identifier\ntemp_shader:9: out of range"
+        );
+    }
+
     #[test]
     fn test_compile_shaders_with_includes() {
         // let source_file_name = "../shaders/gbuffer.mesh.glsl";
@@ -50,6 +73,7 @@ mod tests {
             source_file_name,
             destination_file_name,
             ShaderStageType::Vertex,
+            &[],
         )
         .unwrap();
     }
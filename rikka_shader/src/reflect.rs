@@ -78,7 +78,7 @@ impl ReflectInto<vk::DescriptorType> for ReflectDescriptorType {
             UniformBufferDynamic => Ok(DescriptorType::UNIFORM_BUFFER_DYNAMIC),
             StorageBufferDynamic => Ok(DescriptorType::STORAGE_BUFFER_DYNAMIC),
             InputAttachment => Ok(DescriptorType::INPUT_ATTACHMENT),
-            AccelerationStructureKHR => Ok(DescriptorType::ACCELERATION_STRUCTURE_NV),
+            AccelerationStructureKHR => Ok(DescriptorType::ACCELERATION_STRUCTURE_KHR),
             Undefined => Err(anyhow::anyhow!("Undfined descriptor type!")),
         }
     }
@@ -93,6 +93,20 @@ pub fn reflect_spirv_data(spirv_data: &[u8]) -> Result<ShaderReflection> {
         let descriptor_sets = descriptor_sets
             .into_iter()
             .map(|set| {
+                // Blocks without an instance name are referred to by their block type name
+                let binding_names = set
+                    .bindings
+                    .iter()
+                    .filter_map(|binding| {
+                        let name = if binding.name.is_empty() {
+                            binding.type_description.as_ref()?.type_name.clone()
+                        } else {
+                            binding.name.clone()
+                        };
+                        (!name.is_empty()).then(|| (name, binding.binding))
+                    })
+                    .collect::<Vec<_>>();
+
                 let bindings = set
                     .bindings
                     .into_iter()
@@ -112,11 +126,25 @@ pub fn reflect_spirv_data(spirv_data: &[u8]) -> Result<ShaderReflection> {
                     bindings: bindings?,
                     index: set.set,
                     shader_stages,
+                    binding_names,
                 })
             })
             .collect::<Result<Vec<_>>>()?;
 
-        Ok(ShaderReflection { descriptor_sets })
+        let push_constant_blocks = module
+            .enumerate_push_constant_blocks(None)
+            .map_err(|e| anyhow::anyhow!("Failed to reflect push constant blocks: {}", e))?
+            .into_iter()
+            .map(|block| PushConstantBlock {
+                shader_stages,
+                size: block.offset + block.size,
+            })
+            .collect();
+
+        Ok(ShaderReflection {
+            descriptor_sets,
+            push_constant_blocks,
+        })
     } else {
         Err(anyhow::anyhow!("Failed to load spirv data"))
     }
@@ -124,8 +152,20 @@ pub fn reflect_spirv_data(spirv_data: &[u8]) -> Result<ShaderReflection> {
 
 pub fn merge_reflections(parse_results: &[ShaderReflection]) -> Result<ShaderReflection> {
     let mut merged_sets = Vec::new();
+    let mut push_constant_blocks: Vec<PushConstantBlock> = Vec::new();
 
     for parse_result in parse_results {
+        // Blocks of the same size are assumed to be one range shared by the stages
+        for block in &parse_result.push_constant_blocks {
+            match push_constant_blocks
+                .iter_mut()
+                .find(|existing| existing.size == block.size)
+            {
+                Some(existing) => existing.shader_stages |= block.shader_stages,
+                None => push_constant_blocks.push(*block),
+            }
+        }
+
         let descriptor_sets = &parse_result.descriptor_sets;
         for (n, set) in descriptor_sets.iter().enumerate() {
             match merged_sets
@@ -141,6 +181,7 @@ pub fn merge_reflections(parse_results: &[ShaderReflection]) -> Result<ShaderRef
 
                     let mut new_set = set.clone();
                     new_set.shader_stages |= existing_set.shader_stages;
+                    merge_binding_names(&mut new_set, &existing_set.binding_names)?;
                     // Descriptor binding stage flags is set on a per-set level now rather than per-binding
                     // XXX: Handle this to make it per-binding
                     for binding in new_set.bindings.iter_mut() {
@@ -149,6 +190,7 @@ pub fn merge_reflections(parse_results: &[ShaderReflection]) -> Result<ShaderRef
                     merged_sets[n] = new_set;
                 }
                 Some(SetEquality::Equal) | Some(SetEquality::SubsetOf) => {
+                    merge_binding_names(&mut merged_sets[n], &set.binding_names)?;
                     for binding in merged_sets[n].bindings.iter_mut() {
                         binding.shader_stage_flags |= set.shader_stages;
                     }
@@ -159,9 +201,31 @@ pub fn merge_reflections(parse_results: &[ShaderReflection]) -> Result<ShaderRef
 
     Ok(ShaderReflection {
         descriptor_sets: merged_sets,
+        push_constant_blocks,
     })
 }
 
+/// Stages may name the same binding differently, a name referring to different bindings is an error
+fn merge_binding_names(set: &mut DescriptorSet, binding_names: &[(String, u32)]) -> Result<()> {
+    for (name, binding_index) in binding_names {
+        match set.binding_index_by_name(name) {
+            Some(existing_index) if existing_index != *binding_index => {
+                return Err(anyhow::anyhow!(
+                    "Shader binding name {} refers to both binding {} and {} of set {}",
+                    name,
+                    existing_index,
+                    binding_index,
+                    set.index
+                ));
+            }
+            Some(_) => {}
+            None => set.binding_names.push((name.clone(), *binding_index)),
+        }
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
 enum SetEquality {
     Equal,
@@ -1,10 +1,10 @@
-use std::hash::Hash;
+use std::{collections::HashMap, hash::Hash};
 
 use serde_derive::{Deserialize, Serialize};
 
 use rikka_core::vk;
 
-#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub enum ShaderStageType {
     Vertex,
     Fragment,
@@ -12,6 +12,11 @@ pub enum ShaderStageType {
     Compute,
     Mesh,
     Task,
+    RayGeneration,
+    Miss,
+    ClosestHit,
+    AnyHit,
+    Intersection,
 }
 
 impl ShaderStageType {
@@ -24,6 +29,11 @@ impl ShaderStageType {
             Self::Compute => String::from("comp"),
             Self::Mesh => String::from("mesh"),
             Self::Task => String::from("task"),
+            Self::RayGeneration => String::from("rgen"),
+            Self::Miss => String::from("rmiss"),
+            Self::ClosestHit => String::from("rchit"),
+            Self::AnyHit => String::from("rahit"),
+            Self::Intersection => String::from("rint"),
         }
     }
 
@@ -35,6 +45,11 @@ impl ShaderStageType {
             Self::Compute => String::from("COMPUTE"),
             Self::Mesh => String::from("MESH"),
             Self::Task => String::from("TASK"),
+            Self::RayGeneration => String::from("RAY_GENERATION"),
+            Self::Miss => String::from("MISS"),
+            Self::ClosestHit => String::from("CLOSEST_HIT"),
+            Self::AnyHit => String::from("ANY_HIT"),
+            Self::Intersection => String::from("INTERSECTION"),
         }
     }
 
@@ -48,6 +63,36 @@ impl ShaderStageType {
             Self::Compute => ShaderStageFlags::COMPUTE,
             Self::Mesh => ShaderStageFlags::MESH_NV,
             Self::Task => ShaderStageFlags::TASK_NV,
+            Self::RayGeneration => ShaderStageFlags::RAYGEN_KHR,
+            Self::Miss => ShaderStageFlags::MISS_KHR,
+            Self::ClosestHit => ShaderStageFlags::CLOSEST_HIT_KHR,
+            Self::AnyHit => ShaderStageFlags::ANY_HIT_KHR,
+            Self::Intersection => ShaderStageFlags::INTERSECTION_KHR,
+        }
+    }
+}
+
+/// Preprocessor define passed to the shader compiler, eg. `USE_NORMAL_MAP` or `MAX_LIGHTS=4`
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ShaderDefine {
+    pub name: String,
+    #[serde(default)]
+    pub value: Option<String>,
+}
+
+impl ShaderDefine {
+    pub fn new(name: &str, value: Option<&str>) -> Self {
+        Self {
+            name: name.to_owned(),
+            value: value.map(str::to_owned),
+        }
+    }
+
+    /// Formatted as `NAME` or `NAME=VALUE`
+    pub fn to_command_line_string(&self) -> String {
+        match &self.value {
+            Some(value) => format!("{}={}", self.name, value),
+            None => self.name.clone(),
         }
     }
 }
@@ -85,9 +130,61 @@ pub struct DescriptorSet {
     pub bindings: Vec<DescriptorBinding>,
     pub index: u32,
     pub shader_stages: vk::ShaderStageFlags,
+    /// Shader variable name to binding index of the reflected bindings
+    pub binding_names: Vec<(String, u32)>,
+}
+
+impl DescriptorSet {
+    pub fn binding_index_by_name(&self, name: &str) -> Option<u32> {
+        self.binding_names
+            .iter()
+            .find(|(binding_name, _)| binding_name == name)
+            .map(|(_, index)| *index)
+    }
+}
+
+/// Push constant block used by shader stages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PushConstantBlock {
+    pub shader_stages: vk::ShaderStageFlags,
+    /// Bytes from the start of the push constant range to the end of the last member
+    pub size: u32,
 }
 
 #[derive(Debug)]
 pub struct ShaderReflection {
     pub descriptor_sets: Vec<DescriptorSet>,
+    pub push_constant_blocks: Vec<PushConstantBlock>,
+}
+
+impl ShaderReflection {
+    /// Returns the (set, binding) indices of a shader variable
+    pub fn binding_by_name(&self, name: &str) -> Option<(u32, u32)> {
+        self.descriptor_sets.iter().find_map(|set| {
+            set.binding_index_by_name(name)
+                .map(|binding_index| (set.index, binding_index))
+        })
+    }
+
+    /// Push constant block used by `shader_stage`, None if the stage does not use push constants
+    pub fn push_constant_block(
+        &self,
+        shader_stage: vk::ShaderStageFlags,
+    ) -> Option<&PushConstantBlock> {
+        self.push_constant_blocks
+            .iter()
+            .find(|block| block.shader_stages.contains(shader_stage))
+    }
+
+    /// Shader variable name to (set, binding) indices of all reflected bindings
+    pub fn binding_locations(&self) -> HashMap<String, (u32, u32)> {
+        self.descriptor_sets
+            .iter()
+            .flat_map(|set| {
+                set.binding_names
+                    .iter()
+                    .map(move |(name, binding_index)| (name.clone(), (set.index, *binding_index)))
+            })
+            .collect()
+    }
 }